@@ -0,0 +1,54 @@
+use criterion::{Criterion, criterion_group, criterion_main};
+use isu::*;
+use std::collections::{HashMap, HashSet};
+use std::hint::black_box;
+
+/// Builds a grammar and domain for the system side of the travel dialogue.
+fn travel_grammar() -> (SimpleGenGrammar, Domain) {
+    let preds1 = HashMap::from([
+        ("price".to_string(), "int".to_string()),
+        ("dest_city".to_string(), "city".to_string()),
+        ("depart_city".to_string(), "city".to_string()),
+        ("depart_day".to_string(), "day".to_string()),
+    ]);
+    let sorts = HashMap::from([
+        ("city".to_string(), HashSet::from(["paris".to_string(), "berlin".to_string()])),
+        ("day".to_string(), HashSet::from(["today".to_string(), "tomorrow".to_string()])),
+    ]);
+    let domain = Domain::new(HashSet::from(["return".to_string()]), preds1, sorts);
+    let mut grammar = SimpleGenGrammar::new();
+    grammar.add_form("Ask('?x.dest_city(x)')", "Where do you want to go?");
+    grammar.add_form("Ask('?x.depart_city(x)')", "From where are you leaving?");
+    grammar.add_form("Ask('?return()')", "Do you need a return ticket?");
+    grammar.add_answer_form("price", "The price from {depart_city} to {dest_city} is {} euros");
+    (grammar, domain)
+}
+
+/// Generates the system turns of a long simulated dialogue, cycling through
+/// the moves of a travel booking.
+fn long_dialogue(c: &mut Criterion) {
+    let (grammar, domain) = travel_grammar();
+    let turns: Vec<Vec<String>> = [
+        vec!["Greet()"],
+        vec!["icm:acc*pos", "Ask('?x.dest_city(x)')"],
+        vec!["icm:acc*pos", "Ask('?x.depart_city(x)')"],
+        vec!["icm:acc*pos", "Ask('?x.depart_day(x)')"],
+        vec!["Ask('?return()')"],
+        vec!["Answer(price(232), [depart_city(berlin), dest_city(paris)])"],
+        vec!["Quit()"],
+    ]
+    .iter()
+    .map(|turn| turn.iter().map(|mv| mv.to_string()).collect())
+    .collect();
+    c.bench_function("generate long dialogue", |b| {
+        b.iter(|| {
+            let ctx = InterpretationContext::new(&domain);
+            for turn in turns.iter().cycle().take(1000) {
+                black_box(grammar.generate(turn, &ctx));
+            }
+        })
+    });
+}
+
+criterion_group!(benches, long_dialogue);
+criterion_main!(benches);
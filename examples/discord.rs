@@ -0,0 +1,21 @@
+use isu::*;
+
+#[path = "travel.rs"]
+#[allow(dead_code)]
+mod travel;
+
+// Main function to run the travel dialogue system as a Discord bot
+/// Entry point for the Discord bot, taking the token of the bot from the
+/// DISCORD_TOKEN environment variable and the channel it talks in from
+/// DISCORD_CHANNEL. Each user of the channel has their own dialogue.
+fn main() {
+    let (Ok(token), Ok(channel)) = (std::env::var("DISCORD_TOKEN"), std::env::var("DISCORD_CHANNEL")) else {
+        eprintln!("Set DISCORD_TOKEN to the token of the bot and DISCORD_CHANNEL to the id of its channel");
+        std::process::exit(1);
+    };
+    let mut sessions = SessionManager::new(travel::travel_controller);
+    let mut bot = DiscordBot::new(&token, &channel);
+
+    println!("Starting IBIS Travel Dialogue System as a Discord bot...");
+    bot.run(&CurlApi::new(), &mut sessions);
+}
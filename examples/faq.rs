@@ -0,0 +1,100 @@
+use isu::*;
+use std::collections::{HashMap, HashSet};
+
+/// A knowledge base holding the answer to each frequently asked question.
+struct FaqDB {
+    facts: HashMap<String, String>, // Answer by predicate, e.g. "wifi()" or "parking(free)"
+}
+
+/// Implements the Database trait for FaqDB.
+/// Each question is answered with the fact stored for its predicate.
+impl Database for FaqDB {
+    fn try_consult_db(&self, question: &Question, _context: &TSet<Prop>) -> Result<Prop, String> {
+        let pred = question.pred().unwrap_or_default();
+        let fact = self.facts.get(pred).cloned().unwrap_or(format!("-{}", pred));
+        Prop::new(&fact)
+    }
+
+    fn aggregate(&self, _aggregate: Aggregate, _field: &str, _context: &TSet<Prop>) -> Option<Ind> {
+        None
+    }
+}
+
+/// Builds the FAQ dialogue system reading user input from a handler.
+/// Every question has a single-step plan that consults the knowledge base,
+/// so the user can ask one question after the other.
+/// # Arguments
+/// * `input_handler` - The source of user input.
+pub fn faq_controller(input_handler: Box<dyn InputHandler>) -> IBISController {
+    // Initialize zero-place predicates
+    let preds0 = HashSet::from(["wifi".to_string(), "pets".to_string()]);
+
+    // Initialize one-place predicates with their sorts
+    let preds1 = HashMap::from([
+        ("opening_hours".to_string(), "hours".to_string()),
+        ("parking".to_string(), "fee".to_string()),
+    ]);
+
+    // Initialize sorts and their individuals
+    let sorts = HashMap::from([
+        (
+            "hours".to_string(),
+            HashSet::from(["weekdays".to_string(), "daily".to_string()]),
+        ),
+        (
+            "fee".to_string(),
+            HashSet::from(["free".to_string(), "paid".to_string()]),
+        ),
+    ]);
+
+    // Create the domain with a plan per question
+    let mut domain = Domain::new(preds0, preds1, sorts);
+    for question in ["?x.opening_hours(x)", "?x.parking(x)", "?wifi()", "?pets()"] {
+        domain.add_plan(Question::new(question).unwrap(), vec![format!("ConsultDB('{}')", question)]);
+    }
+
+    // Initialize the knowledge base
+    let database = FaqDB {
+        facts: HashMap::from([
+            ("opening_hours".to_string(), "opening_hours(daily)".to_string()),
+            ("parking".to_string(), "parking(free)".to_string()),
+            ("wifi".to_string(), "wifi()".to_string()),
+        ]),
+    };
+
+    // Initialize the grammar
+    let mut grammar = SimpleGenGrammar::new();
+    grammar.add_answer_form("opening_hours", "We are open {} from 9 to 17");
+    grammar.add_answer_form("parking", "Parking is {} for guests");
+    grammar.add_answer_form("wifi()", "Yes, there is wifi throughout the building");
+    grammar.add_answer_form("-pets()", "Sorry, pets are not allowed");
+
+    IBISController::with_input_handler(domain, database, grammar, input_handler)
+}
+
+/// Scripts the user side of the demo dialogue, checking each system response.
+pub fn faq_script() -> ScriptedInputHandler {
+    ScriptedInputHandler::new()
+        .expect("Hello.")
+        .say("?x.opening_hours(x)")
+        .expect("We are open daily from 9 to 17.")
+        .say("?wifi()")
+        .expect("Yes, there is wifi throughout the building.")
+        .say("?pets()")
+        .expect("Sorry, pets are not allowed.")
+        .say("?x.parking(x)")
+        .expect("Parking is free for guests.")
+        .say("quit")
+}
+
+// Main function to demonstrate the FAQ dialogue system
+/// Entry point for the FAQ dialogue system.
+fn main() {
+    let mut ibis = faq_controller(Box::new(faq_script()));
+
+    println!("Starting IBIS FAQ Dialogue System (Demo Mode)...");
+    println!();
+
+    // Run the demo
+    ibis.run();
+}
@@ -0,0 +1,110 @@
+use isu::*;
+use std::collections::{HashMap, HashSet};
+
+/// A home automation backend that switches devices when consulted.
+struct HomeDB;
+
+/// Implements the Database trait for HomeDB.
+/// Consulting "?x.switch(x)" performs the action described by the
+/// commitments and answers with the resulting setting.
+impl Database for HomeDB {
+    fn try_consult_db(&self, _question: &Question, context: &TSet<Prop>) -> Result<Prop, String> {
+        let value = |pred: &str| {
+            context
+                .iter()
+                .find(|prop| prop.pred() == pred)
+                .and_then(|prop| prop.ind())
+                .map_or("unknown".to_string(), |ind| ind.to_string())
+        };
+        let setting = value("setting");
+        println!("[home] switching {} in the {} {}", value("device"), value("room"), setting);
+        Prop::new(&format!("switch({})", setting))
+    }
+
+    fn aggregate(&self, _aggregate: Aggregate, _field: &str, _context: &TSet<Prop>) -> Option<Ind> {
+        None
+    }
+}
+
+/// Builds the home automation dialogue system reading user input from a handler.
+/// The plan collects the parameters of an action with Findout constructs and
+/// carries it out with ConsultDB.
+/// # Arguments
+/// * `input_handler` - The source of user input.
+pub fn home_controller(input_handler: Box<dyn InputHandler>) -> IBISController {
+    // Initialize one-place predicates with their sorts
+    let preds1 = HashMap::from([
+        ("switch".to_string(), "setting".to_string()),
+        ("device".to_string(), "device".to_string()),
+        ("room".to_string(), "room".to_string()),
+        ("setting".to_string(), "setting".to_string()),
+    ]);
+
+    // Initialize sorts and their individuals
+    let sorts = HashMap::from([
+        (
+            "device".to_string(),
+            HashSet::from(["lights".to_string(), "heating".to_string(), "radio".to_string()]),
+        ),
+        (
+            "room".to_string(),
+            HashSet::from(["kitchen".to_string(), "bedroom".to_string()]),
+        ),
+        (
+            "setting".to_string(),
+            HashSet::from(["on".to_string(), "off".to_string()]),
+        ),
+    ]);
+
+    // Create the domain
+    let mut domain = Domain::new(HashSet::new(), preds1, sorts);
+
+    // Define a plan for switching a device
+    let plan = vec![
+        "Findout('?x.device(x)')".to_string(),
+        "Findout('?x.room(x)')".to_string(),
+        "Findout('?x.setting(x)')".to_string(),
+        "ConsultDB('?x.switch(x)')".to_string(),
+    ];
+    domain.add_plan(Question::new("?x.switch(x)").unwrap(), plan);
+
+    // Register the words users may use for the predicates
+    domain.add_alias("in the", "room").unwrap();
+    domain.add_alias("turn", "setting").unwrap();
+
+    // Initialize the grammar
+    let mut grammar = SimpleGenGrammar::new();
+    grammar.add_form("Ask('?x.device(x)')", "What should I switch?");
+    grammar.add_form("Ask('?x.room(x)')", "In which room?");
+    grammar.add_form("Ask('?x.setting(x)')", "On or off?");
+    grammar.add_answer_form("switch", "OK, the {device} in the {room} is {}");
+
+    IBISController::with_input_handler(domain, HomeDB, grammar, input_handler)
+}
+
+/// Scripts the user side of the demo dialogue, checking each system response.
+pub fn home_script() -> ScriptedInputHandler {
+    ScriptedInputHandler::new()
+        .expect("Hello.")
+        .say("?x.switch(x)")
+        .expect("What should I switch?")
+        .say("heating")
+        .expect("In which room?")
+        .say("in the bedroom")
+        .expect("On or off?")
+        .say("turn on")
+        .expect("OK, the heating in the bedroom is on.")
+        .say("quit")
+}
+
+// Main function to demonstrate the home automation dialogue system
+/// Entry point for the home automation dialogue system.
+fn main() {
+    let mut ibis = home_controller(Box::new(home_script()));
+
+    println!("Starting IBIS Home Automation Dialogue System (Demo Mode)...");
+    println!();
+
+    // Run the demo
+    ibis.run();
+}
@@ -0,0 +1,135 @@
+use isu::*;
+use std::collections::{HashMap, HashSet};
+
+/// A booking backend holding the restaurants serving each cuisine.
+struct RestaurantDB {
+    restaurants: HashMap<String, String>, // Restaurant by cuisine
+}
+
+/// Implements the Database trait for RestaurantDB.
+/// Consulting "?x.booking(x)" books a table at the restaurant serving the
+/// chosen cuisine and answers with its name.
+impl Database for RestaurantDB {
+    fn try_consult_db(&self, _question: &Question, context: &TSet<Prop>) -> Result<Prop, String> {
+        let restaurant = context
+            .iter()
+            .find(|prop| prop.pred() == "cuisine")
+            .and_then(|prop| prop.ind())
+            .and_then(|cuisine| self.restaurants.get(&cuisine.to_string()))
+            .map_or("none", |name| name.as_str());
+        Prop::new(&format!("booking({})", restaurant))
+    }
+
+    fn aggregate(&self, _aggregate: Aggregate, _field: &str, _context: &TSet<Prop>) -> Option<Ind> {
+        None
+    }
+}
+
+/// Builds the restaurant booking dialogue system reading user input from a handler.
+/// The plan raises an optional question with Raise and branches on its answer
+/// with If before booking with ConsultDB.
+/// # Arguments
+/// * `input_handler` - The source of user input.
+pub fn restaurant_controller(input_handler: Box<dyn InputHandler>) -> IBISController {
+    // Initialize zero-place predicates
+    let preds0 = HashSet::from(["outdoor".to_string(), "heater".to_string()]);
+
+    // Initialize one-place predicates with their sorts
+    let preds1 = HashMap::from([
+        ("booking".to_string(), "restaurant".to_string()),
+        ("cuisine".to_string(), "cuisine".to_string()),
+        ("day".to_string(), "day".to_string()),
+        ("meal".to_string(), "meal".to_string()),
+    ]);
+
+    // Initialize sorts and their individuals
+    let sorts = HashMap::from([
+        (
+            "restaurant".to_string(),
+            HashSet::from(["trattoria".to_string(), "baan_thai".to_string()]),
+        ),
+        (
+            "cuisine".to_string(),
+            HashSet::from(["italian".to_string(), "thai".to_string()]),
+        ),
+        (
+            "day".to_string(),
+            HashSet::from(["today".to_string(), "tomorrow".to_string()]),
+        ),
+        (
+            "meal".to_string(),
+            HashSet::from(["lunch".to_string(), "dinner".to_string()]),
+        ),
+    ]);
+
+    // Create the domain
+    let mut domain = Domain::new(preds0, preds1, sorts);
+
+    // Define a plan for booking a table
+    let plan = vec![
+        "Findout('?x.cuisine(x)')".to_string(),
+        "Findout('?x.day(x)')".to_string(),
+        "Findout('?x.meal(x)')".to_string(),
+        "Raise('?outdoor()')".to_string(),
+        "If('?outdoor()', ['Findout(?heater())'], [])".to_string(),
+        "ConsultDB('?x.booking(x)')".to_string(),
+    ];
+    domain.add_plan(Question::new("?x.booking(x)").unwrap(), plan);
+
+    // Register the words users may use for the predicates
+    domain.add_alias("outside", "outdoor").unwrap();
+    domain.add_alias("for", "meal").unwrap();
+
+    // Initialize the booking database
+    let database = RestaurantDB {
+        restaurants: HashMap::from([
+            ("italian".to_string(), "trattoria".to_string()),
+            ("thai".to_string(), "baan_thai".to_string()),
+        ]),
+    };
+
+    // Initialize the grammar
+    let mut grammar = SimpleGenGrammar::new();
+    grammar.add_form("Ask('?x.cuisine(x)')", "What would you like to eat?");
+    grammar.add_form("Ask('?x.day(x)')", "For which day?");
+    grammar.add_form("Ask('?x.meal(x)')", "Lunch or dinner?");
+    grammar.add_form("Ask('?outdoor()')", "Would you like to sit outside?");
+    grammar.add_form("Ask('?heater()')", "Shall we put a heater at your table?");
+    grammar.add_answer_form("booking", "Your table at {} is booked for {meal} {day}");
+
+    IBISController::with_input_handler(domain, database, grammar, input_handler)
+}
+
+/// Scripts the user side of the demo dialogue, checking each system response.
+/// The branch shows how a script can follow whichever question the system asks.
+pub fn restaurant_script() -> ScriptedInputHandler {
+    ScriptedInputHandler::new()
+        .expect("Hello.")
+        .say("?x.booking(x)")
+        .expect("What would you like to eat?")
+        .say("thai")
+        .expect("For which day?")
+        .say("tomorrow")
+        .expect("Lunch or dinner?")
+        .say("for dinner")
+        .expect("Would you like to sit outside?")
+        .say("yes")
+        .branch(vec![
+            ("heater", vec![ScriptStep::Say("no".to_string())]),
+            ("", vec![ScriptStep::Say("quit".to_string())]),
+        ])
+        .expect("Your table at baan_thai is booked for dinner tomorrow.")
+        .say("quit")
+}
+
+// Main function to demonstrate the restaurant booking dialogue system
+/// Entry point for the restaurant booking dialogue system.
+fn main() {
+    let mut ibis = restaurant_controller(Box::new(restaurant_script()));
+
+    println!("Starting IBIS Restaurant Booking Dialogue System (Demo Mode)...");
+    println!();
+
+    // Run the demo
+    ibis.run();
+}
@@ -0,0 +1,20 @@
+use isu::*;
+
+#[path = "travel.rs"]
+#[allow(dead_code)]
+mod travel;
+
+// Main function to run the travel dialogue system as a Telegram bot
+/// Entry point for the Telegram bot, taking the token of the bot from the
+/// TELEGRAM_TOKEN environment variable. Each chat has its own dialogue.
+fn main() {
+    let Ok(token) = std::env::var("TELEGRAM_TOKEN") else {
+        eprintln!("Set TELEGRAM_TOKEN to the token of the bot");
+        std::process::exit(1);
+    };
+    let mut sessions = SessionManager::new(travel::travel_controller);
+    let mut bot = TelegramBot::new(&token);
+
+    println!("Starting IBIS Travel Dialogue System as a Telegram bot...");
+    bot.run(&CurlApi::new(), &mut sessions);
+}
@@ -1,9 +1,10 @@
 use isu::*;
 use std::collections::{HashMap, HashSet};
 
-// Main function to demonstrate the travel dialogue system
-/// Entry point for the travel dialogue system.
-fn main() {
+/// Builds the travel dialogue system reading user input from a handler.
+/// # Arguments
+/// * `input_handler` - The source of user input.
+pub fn travel_controller(input_handler: Box<dyn InputHandler>) -> IBISController {
     // Initialize zero-place predicates
     let preds0 = HashSet::from(["return".to_string(), "need-visa".to_string()]);
     
@@ -54,20 +55,16 @@ fn main() {
     ];
     domain.add_plan(Question::new("?x.price(x)").unwrap(), plan);
 
-    // Initialize the travel database
-    let mut database = TravelDB::new();
-    database.add_entry(HashMap::from([
-        ("price".to_string(), "232".to_string()),
-        ("from".to_string(), "berlin".to_string()),
-        ("to".to_string(), "paris".to_string()),
-        ("day".to_string(), "today".to_string()),
-    ]));
-    database.add_entry(HashMap::from([
-        ("price".to_string(), "345".to_string()),
-        ("from".to_string(), "paris".to_string()),
-        ("to".to_string(), "london".to_string()),
-        ("day".to_string(), "today".to_string()),
-    ]));
+    // Register the words users may use for the predicates
+    domain.add_alias("to", "dest_city").unwrap();
+    domain.add_alias("going to", "dest_city").unwrap();
+    domain.add_alias("from", "depart_city").unwrap();
+    domain.add_alias("return ticket", "return").unwrap();
+
+    // Load the travel database, selecting flights by their cities and day
+    let flights = concat!(env!("CARGO_MANIFEST_DIR"), "/examples/data/flights.csv");
+    let keys = ["depart_city=from", "dest_city=to", "depart_day=day"];
+    let database = FileDatabase::from_csv(flights, &keys).unwrap();
 
     // Initialize the grammar
     let mut grammar = SimpleGenGrammar::new();
@@ -78,23 +75,42 @@ fn main() {
     grammar.add_form("Ask('?x.return_day(x)')", "When do you want to return?");
     grammar.add_form("Ask('?x.class(x)')", "First or second class?");
     grammar.add_form("Ask('?return()')", "Do you want a return ticket?");
+    grammar.add_unit("euros", "price");
+    grammar.add_answer_form("price", "The price from {depart_city} to {dest_city} {depart_day} is {}");
+
+    IBISController::with_input_handler(domain, database, grammar, input_handler)
+}
+
+/// Scripts the user side of the demo dialogue, checking each system response.
+pub fn travel_script() -> ScriptedInputHandler {
+    ScriptedInputHandler::new()
+        .expect("Hello.")
+        .say("?x.price(x)")
+        .expect("How do you want to travel?")
+        .say("under 300 euros")
+        .expect("How do you want to travel?")
+        .say("train")
+        .expect("Where do you want to go?")
+        .say("I want to go to paris")
+        .expect("From where are you leaving?")
+        .say("from berlin")
+        .expect("When do you want to leave?")
+        .say("today")
+        .expect("First or second class?")
+        .say("first")
+        .expect("Do you want a return ticket?")
+        .say("yes")
+        .expect("When do you want to return?")
+        .say("tomorrow")
+        .expect("The price from berlin to paris today is 232.")
+        .say("quit")
+}
+
+// Main function to demonstrate the travel dialogue system
+/// Entry point for the travel dialogue system.
+fn main() {
+    let mut ibis = travel_controller(Box::new(travel_script()));
 
-    // Create demo inputs for non-interactive testing
-    let demo_inputs = vec![
-        "I want to go to paris".to_string(),
-        "train".to_string(),
-        "berlin".to_string(),
-        "today".to_string(),
-        "first".to_string(),
-        "yes".to_string(),
-        "tomorrow".to_string(),
-        "quit".to_string(),
-    ];
-    
-    // Create the IBIS controller with demo input handler
-    let demo_handler = isu::DemoInputHandler::new(demo_inputs);
-    let mut ibis = isu::IBISController::with_input_handler(domain, database, grammar, Box::new(demo_handler));
-    
     println!("Starting IBIS Travel Dialogue System (Demo Mode)...");
     println!("Simulating user interaction with predefined inputs:");
     println!();
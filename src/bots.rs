@@ -0,0 +1,405 @@
+//! Adapters connecting a SessionManager to the bot APIs of messaging services.
+
+use crate::SessionManager;
+use serde_json::Value;
+use std::io::Write;
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::Duration;
+
+/// Time waited after a failed poll, doubled for each further failure in a row.
+const FIRST_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Longest time waited after failed polls.
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// A request to the HTTP API of a messaging service.
+pub struct BotRequest {
+    pub method: String, // The HTTP method (e.g., "POST")
+    pub url: String, // The endpoint, with its query
+    pub headers: Vec<(String, String)>, // Headers besides the content type (e.g., authorization)
+    pub body: Option<Value>, // The JSON body, if any
+}
+
+impl BotRequest {
+    /// Creates a GET request without headers.
+    /// # Arguments
+    /// * `url` - The endpoint.
+    pub fn get(url: &str) -> Self {
+        BotRequest { method: "GET".to_string(), url: url.to_string(), headers: Vec::new(), body: None }
+    }
+
+    /// Creates a POST request with a JSON body and without headers.
+    /// # Arguments
+    /// * `url` - The endpoint.
+    /// * `body` - The body.
+    pub fn post(url: &str, body: Value) -> Self {
+        BotRequest { method: "POST".to_string(), url: url.to_string(), headers: Vec::new(), body: Some(body) }
+    }
+
+    /// Adds a header.
+    /// # Arguments
+    /// * `name` - The header name (e.g., "Authorization").
+    /// * `value` - The header value.
+    pub fn with_header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+}
+
+/// Sends requests to the API of a messaging service, returning the JSON
+/// responses. Applications may implement it with their own HTTP client.
+pub trait BotApi {
+    /// Sends a request and returns the JSON response.
+    /// # Arguments
+    /// * `request` - The request.
+    fn send(&self, request: &BotRequest) -> Result<Value, String>;
+}
+
+/// Sends requests with the `curl` executable. The URL, headers and body are
+/// passed in a config on its standard input rather than as arguments, where
+/// other users of the machine could read the token of the bot.
+pub struct CurlApi {
+    command: String, // The curl executable
+}
+
+impl CurlApi {
+    /// Sends requests with the `curl` found on the PATH.
+    pub fn new() -> Self {
+        Self::with_command("curl")
+    }
+
+    /// Sends requests with a given executable taking the options of curl.
+    /// # Arguments
+    /// * `command` - The executable.
+    pub fn with_command(command: &str) -> Self {
+        CurlApi { command: command.to_string() }
+    }
+}
+
+impl Default for CurlApi {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Quotes a value for a curl config, escaping backslashes, quotes and line breaks.
+/// # Arguments
+/// * `value` - The value (e.g., "Authorization: Bot abc").
+fn config_value(value: &str) -> String {
+    let escaped = value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+        .replace('\r', "\\r")
+        .replace('\t', "\\t");
+    format!("\"{}\"", escaped)
+}
+
+impl BotApi for CurlApi {
+    fn send(&self, request: &BotRequest) -> Result<Value, String> {
+        let mut config = format!("request = {}\nurl = {}\n", config_value(&request.method), config_value(&request.url));
+        for (name, value) in &request.headers {
+            config.push_str(&format!("header = {}\n", config_value(&format!("{}: {}", name, value))));
+        }
+        if let Some(body) = &request.body {
+            config.push_str("header = \"Content-Type: application/json\"\n");
+            config.push_str(&format!("data-binary = {}\n", config_value(&body.to_string())));
+        }
+        let mut child = Command::new(&self.command)
+            .args(["--silent", "--show-error", "--config", "-"])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|e| format!("Could not run {}: {}", self.command, e))?;
+        let mut stdin = child.stdin.take().ok_or(format!("{} has no input", self.command))?;
+        stdin.write_all(config.as_bytes()).map_err(|e| format!("{}: {}", self.command, e))?;
+        drop(stdin);
+        let output = child.wait_with_output().map_err(|e| format!("{}: {}", self.command, e))?;
+        if !output.status.success() {
+            return Err(format!("{}: {}", self.command, String::from_utf8_lossy(&output.stderr).trim()));
+        }
+        serde_json::from_slice(&output.stdout).map_err(|e| format!("Invalid response from {}: {}", request.url, e))
+    }
+}
+
+/// Logs the error of a failed poll and returns the time to wait before the
+/// next one, or None once a poll succeeds.
+/// # Arguments
+/// * `service` - The name of the messaging service (e.g., "Telegram").
+/// * `result` - The result of the poll.
+/// * `failures` - The failed polls in a row, updated with the result.
+fn back_off(service: &str, result: Result<usize, String>, failures: &mut u32) -> Option<Duration> {
+    let Err(e) = result else {
+        *failures = 0;
+        return None;
+    };
+    let delay = FIRST_BACKOFF.saturating_mul(2u32.saturating_pow(*failures)).min(MAX_BACKOFF);
+    *failures = failures.saturating_add(1);
+    eprintln!("{} poll failed, retrying in {}s: {}", service, delay.as_secs(), e);
+    Some(delay)
+}
+
+/// A Telegram bot taking a turn of the session of a chat for each text
+/// message, found by long polling. Sessions are named "telegram:" followed
+/// by the chat id.
+pub struct TelegramBot {
+    token: String, // The token of the bot
+    offset: i64, // Id of the next update to fetch
+    poll_timeout: u64, // Seconds a poll waits for new messages
+}
+
+impl TelegramBot {
+    /// Creates a bot polling for 30 seconds at a time.
+    /// # Arguments
+    /// * `token` - The token of the bot, given by BotFather.
+    pub fn new(token: &str) -> Self {
+        TelegramBot { token: token.to_string(), offset: 0, poll_timeout: 30 }
+    }
+
+    /// Sets the time a poll waits for new messages.
+    /// # Arguments
+    /// * `seconds` - The time, 0 to return at once.
+    pub fn with_poll_timeout(mut self, seconds: u64) -> Self {
+        self.poll_timeout = seconds;
+        self
+    }
+
+    /// Returns the endpoint of a method of the Bot API.
+    /// # Arguments
+    /// * `method` - The method, with its query (e.g., "sendMessage").
+    fn url(&self, method: &str) -> String {
+        format!("https://api.telegram.org/bot{}/{}", self.token, method)
+    }
+
+    /// Fetches the messages sent since the last poll, takes the turn of each
+    /// in the session of its chat and sends the replies back. Returns the
+    /// number of messages handled.
+    /// # Arguments
+    /// * `api` - The sender of requests.
+    /// * `sessions` - The sessions of the chats.
+    pub fn poll(&mut self, api: &dyn BotApi, sessions: &mut SessionManager) -> Result<usize, String> {
+        let query = format!("getUpdates?offset={}&timeout={}", self.offset, self.poll_timeout);
+        let response = api.send(&BotRequest::get(&self.url(&query)))?;
+        if response["ok"] != true {
+            return Err(format!("Telegram: {}", response["description"]));
+        }
+        let mut handled = 0;
+        for update in response["result"].as_array().into_iter().flatten() {
+            self.offset = self.offset.max(update["update_id"].as_i64().unwrap_or_default() + 1);
+            let message = &update["message"];
+            let (Some(text), Some(chat)) = (message["text"].as_str(), message["chat"]["id"].as_i64()) else {
+                continue;
+            };
+            for reply in sessions.handle(&format!("telegram:{}", chat), text) {
+                let body = serde_json::json!({ "chat_id": chat, "text": reply });
+                api.send(&BotRequest::post(&self.url("sendMessage"), body))?;
+            }
+            handled += 1;
+        }
+        Ok(handled)
+    }
+
+    /// Polls for messages forever. A failed poll is logged to the standard
+    /// error and retried after a delay, doubled for each failure in a row
+    /// up to a minute.
+    /// # Arguments
+    /// * `api` - The sender of requests.
+    /// * `sessions` - The sessions of the chats.
+    pub fn run(&mut self, api: &dyn BotApi, sessions: &mut SessionManager) {
+        let mut failures = 0;
+        loop {
+            let result = self.poll(api, sessions);
+            if let Some(delay) = back_off("Telegram", result, &mut failures) {
+                thread::sleep(delay);
+            }
+        }
+    }
+}
+
+/// A Discord bot taking a turn of the session of a user for each message in
+/// a channel, found by polling the channel. Messages of bots, itself
+/// included, are ignored, as are the messages sent before the first poll.
+/// Sessions are named "discord:" followed by the channel and user ids.
+pub struct DiscordBot {
+    token: String, // The token of the bot
+    channel: String, // Id of the channel the bot talks in
+    last: Option<String>, // Id of the last message seen
+    interval: Duration, // Time between polls when running
+}
+
+impl DiscordBot {
+    /// Creates a bot polling a channel every two seconds.
+    /// # Arguments
+    /// * `token` - The token of the bot, from the developer portal.
+    /// * `channel` - The id of the channel.
+    pub fn new(token: &str, channel: &str) -> Self {
+        DiscordBot {
+            token: token.to_string(),
+            channel: channel.to_string(),
+            last: None,
+            interval: Duration::from_secs(2),
+        }
+    }
+
+    /// Sets the time between polls when running.
+    /// # Arguments
+    /// * `interval` - The time.
+    pub fn with_interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// Returns a request to the messages endpoint of the channel, authorized as the bot.
+    /// # Arguments
+    /// * `request` - The request, with the query of the endpoint as its URL.
+    fn authorize(&self, mut request: BotRequest) -> BotRequest {
+        request.url = format!("https://discord.com/api/v10/channels/{}/messages{}", self.channel, request.url);
+        request.with_header("Authorization", &format!("Bot {}", self.token))
+    }
+
+    /// Fetches the messages sent to the channel since the last poll, takes
+    /// the turn of each in the session of its author and sends the replies
+    /// back. Returns the number of messages handled.
+    /// # Arguments
+    /// * `api` - The sender of requests.
+    /// * `sessions` - The sessions of the users.
+    pub fn poll(&mut self, api: &dyn BotApi, sessions: &mut SessionManager) -> Result<usize, String> {
+        let query = match &self.last {
+            Some(last) => format!("?limit=100&after={}", last),
+            None => "?limit=1".to_string(),
+        };
+        let response = api.send(&self.authorize(BotRequest::get(&query)))?;
+        let Some(messages) = response.as_array() else {
+            return Err(format!("Discord: {}", response["message"]));
+        };
+        let first_poll = self.last.is_none();
+        // Messages come newest first
+        let mut handled = 0;
+        for message in messages.iter().rev() {
+            self.last = message["id"].as_str().map(str::to_string).or(self.last.take());
+            let (Some(text), Some(author)) = (message["content"].as_str(), message["author"]["id"].as_str()) else {
+                continue;
+            };
+            if first_poll || message["author"]["bot"] == true || text.is_empty() {
+                continue;
+            }
+            for reply in sessions.handle(&format!("discord:{}:{}", self.channel, author), text) {
+                let body = serde_json::json!({ "content": reply });
+                api.send(&self.authorize(BotRequest::post("", body)))?;
+            }
+            handled += 1;
+        }
+        if first_poll && self.last.is_none() {
+            self.last = Some("0".to_string());
+        }
+        Ok(handled)
+    }
+
+    /// Polls for messages forever. A failed poll is logged to the standard
+    /// error and retried after a delay, doubled for each failure in a row
+    /// up to a minute, but never shorter than the interval.
+    /// # Arguments
+    /// * `api` - The sender of requests.
+    /// * `sessions` - The sessions of the users.
+    pub fn run(&mut self, api: &dyn BotApi, sessions: &mut SessionManager) {
+        let mut failures = 0;
+        loop {
+            let result = self.poll(api, sessions);
+            let delay = back_off("Discord", result, &mut failures).unwrap_or_default();
+            thread::sleep(delay.max(self.interval));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::travel_session;
+    use std::cell::RefCell;
+    use std::collections::VecDeque;
+
+    #[test]
+    fn test_bot_adapters() {
+        /// An API answering with canned responses and recording the requests.
+        struct FakeApi {
+            responses: RefCell<VecDeque<serde_json::Value>>, // Responses to the next polls
+            sent: RefCell<Vec<(String, String, serde_json::Value)>>, // Method, URL and body of each request
+        }
+        impl BotApi for FakeApi {
+            fn send(&self, request: &BotRequest) -> Result<serde_json::Value, String> {
+                let body = request.body.clone().unwrap_or_default();
+                self.sent.borrow_mut().push((request.method.clone(), request.url.clone(), body));
+                match request.method.as_str() {
+                    "GET" => self.responses.borrow_mut().pop_front().ok_or("No response".to_string()),
+                    _ => Ok(serde_json::json!({ "ok": true })),
+                }
+            }
+        }
+
+        let api = FakeApi {
+            responses: RefCell::new(VecDeque::from([serde_json::json!({ "ok": true, "result": [
+                { "update_id": 7, "message": { "chat": { "id": 42 }, "text": "?x.price(x)" } },
+                { "update_id": 8, "edited_message": { "chat": { "id": 42 }, "text": "paris" } },
+            ]})])),
+            sent: RefCell::new(Vec::new()),
+        };
+        let mut sessions = SessionManager::new(travel_session);
+        let mut telegram = TelegramBot::new("token");
+        assert_eq!(telegram.poll(&api, &mut sessions), Ok(1));
+        assert!(telegram.poll(&api, &mut sessions).is_err());
+        let sent = api.sent.borrow();
+        assert_eq!(sent[0].1, "https://api.telegram.org/bottoken/getUpdates?offset=0&timeout=30");
+        assert_eq!(sent[1].2, serde_json::json!({ "chat_id": 42, "text": "Hello." }));
+        assert_eq!(sent[2].2, serde_json::json!({ "chat_id": 42, "text": "Which city?" }));
+        assert_eq!(sent[3].1, "https://api.telegram.org/bottoken/getUpdates?offset=9&timeout=30");
+        assert_eq!(sessions.sessions(), ["telegram:42"]);
+
+        let api = FakeApi {
+            responses: RefCell::new(VecDeque::from([
+                serde_json::json!([{ "id": "100", "content": "old", "author": { "id": "5" } }]),
+                serde_json::json!([
+                    { "id": "102", "content": "Which city?", "author": { "id": "1", "bot": true } },
+                    { "id": "101", "content": "?x.price(x)", "author": { "id": "5" } },
+                ]),
+            ])),
+            sent: RefCell::new(Vec::new()),
+        };
+        let mut discord = DiscordBot::new("token", "9");
+        assert_eq!(discord.poll(&api, &mut sessions), Ok(0));
+        assert_eq!(discord.poll(&api, &mut sessions), Ok(1));
+        let sent = api.sent.borrow();
+        assert_eq!(sent[1].1, "https://discord.com/api/v10/channels/9/messages?limit=100&after=100");
+        assert_eq!(sent[2].2, serde_json::json!({ "content": "Hello." }));
+        assert_eq!(sessions.sessions(), ["discord:9:5", "telegram:42"]);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_curl_api() {
+        use std::os::unix::fs::PermissionsExt;
+        // A stand-in for curl recording its arguments and config
+        let dir = std::env::temp_dir().join(format!("isu-curl-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let curl = dir.join("curl");
+        let script = format!(
+            "#!/bin/sh\necho \"$@\" > {0}/args\ncat > {0}/config\necho '{{\"ok\": true}}'\n",
+            dir.display()
+        );
+        std::fs::write(&curl, script).unwrap();
+        std::fs::set_permissions(&curl, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        let api = CurlApi::with_command(curl.to_str().unwrap());
+        let request = BotRequest::post("https://discord.com/api", serde_json::json!({ "content": "a \"b\"\nc" }))
+            .with_header("Authorization", "Bot secret");
+        assert_eq!(api.send(&request), Ok(serde_json::json!({ "ok": true })));
+        let args = std::fs::read_to_string(dir.join("args")).unwrap();
+        assert_eq!(args.trim(), "--silent --show-error --config -");
+        let config = std::fs::read_to_string(dir.join("config")).unwrap();
+        assert!(config.contains("url = \"https://discord.com/api\"\n"));
+        assert!(config.contains("header = \"Authorization: Bot secret\"\n"));
+        assert!(config.contains(r#"data-binary = "{\"content\":\"a \\\"b\\\"\\nc\"}""#));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,131 @@
+//! Functions building the preconditions of rules, e.g.
+//! `qud_top(is_whq("dest_city")) & in_plan(findout("depart_day"))`.
+
+use crate::{Condition, Pattern, PatternKind, Prop};
+
+/// Holds when the topmost question under discussion matches a pattern.
+/// # Arguments
+/// * `pattern` - The pattern (e.g., `is_whq("dest_city")`).
+pub fn qud_top(pattern: Pattern) -> Condition {
+    Condition::QudTop(pattern)
+}
+
+/// Holds when a question under discussion matches a pattern.
+/// # Arguments
+/// * `pattern` - The pattern.
+pub fn in_qud(pattern: Pattern) -> Condition {
+    Condition::InQud(pattern)
+}
+
+/// Holds when the next plan construct matches a pattern.
+/// # Arguments
+/// * `pattern` - The pattern (e.g., `findout("depart_day")`).
+pub fn plan_top(pattern: Pattern) -> Condition {
+    Condition::PlanTop(pattern)
+}
+
+/// Holds when a plan construct matches a pattern.
+/// # Arguments
+/// * `pattern` - The pattern.
+pub fn in_plan(pattern: Pattern) -> Condition {
+    Condition::InPlan(pattern)
+}
+
+/// Holds when a proposition is a shared commitment.
+/// # Arguments
+/// * `prop` - The proposition.
+pub fn committed(prop: Prop) -> Condition {
+    Condition::Committed(prop)
+}
+
+/// Holds when a proposition is a private belief.
+/// # Arguments
+/// * `prop` - The proposition.
+pub fn believed(prop: Prop) -> Condition {
+    Condition::Believed(prop)
+}
+
+/// Matches wh-questions about a predicate.
+/// # Arguments
+/// * `pred` - The predicate (e.g., "dest_city").
+pub fn is_whq(pred: &str) -> Pattern {
+    Pattern { kind: PatternKind::WhQ, pred: pred.to_string() }
+}
+
+/// Matches yes/no-questions about a predicate.
+/// # Arguments
+/// * `pred` - The predicate (e.g., "return").
+pub fn is_ynq(pred: &str) -> Pattern {
+    Pattern { kind: PatternKind::YNQ, pred: pred.to_string() }
+}
+
+/// Matches Findout constructs for questions about a predicate.
+/// # Arguments
+/// * `pred` - The predicate.
+pub fn findout(pred: &str) -> Pattern {
+    Pattern { kind: PatternKind::Findout, pred: pred.to_string() }
+}
+
+/// Matches Raise constructs for questions about a predicate.
+/// # Arguments
+/// * `pred` - The predicate.
+pub fn raise(pred: &str) -> Pattern {
+    Pattern { kind: PatternKind::Raise, pred: pred.to_string() }
+}
+
+/// Matches ConsultDB constructs for questions about a predicate.
+/// # Arguments
+/// * `pred` - The predicate.
+pub fn consult_db(pred: &str) -> Pattern {
+    Pattern { kind: PatternKind::ConsultDB, pred: pred.to_string() }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::travel_controller;
+    use crate::{DialogueManager, Domain, Question, Rule, TSet};
+
+    #[test]
+    fn test_rule_preconditions() {
+        let condition = qud_top(is_whq("dest_city")) & in_plan(findout("depart_day"))
+            | !committed(Prop::new("return()").unwrap());
+        let text = "qud_top(is_whq(dest_city)) & in_plan(findout(depart_day)) | !committed(return())";
+        assert_eq!(condition.to_string(), text);
+        assert!(Condition::new(text).unwrap() == condition);
+        assert!(Condition::new("!(believed(vip()) | plan_top(raise(return)))").is_ok());
+        assert!(Condition::new("qud_top(is_altq(dest_city))").is_err());
+        assert!(findout("depart_day").matches("Findout('?x.depart_day(x)')"));
+        assert!(findout("depart_day").matches("Findout(?y.depart_day(y))"));
+        assert!(!findout("depart").matches("Findout('?x.depart_day(x)')"));
+        assert!(!raise("depart_day").matches("Findout('?x.depart_day(x)')"));
+        assert!(!is_ynq("depart_day").matches("?x.depart_day(x)"));
+        assert!(is_whq("price").matches("?x.min:price(x)<300"));
+        assert!(is_ynq("return").matches("?-return()") && !is_whq("return").matches("?return()"));
+        assert!(consult_db("price").matches("ConsultDB('?x.price(x)')"));
+        assert!(!is_ynq("dest_city").matches("{ ?dest_city(paris) | ?dest_city(berlin) }"));
+        assert!(Pattern::new("findout(depart_day)").unwrap() == findout("depart_day"));
+
+        let mut controller = travel_controller(&[]);
+        let rule = Rule::when(qud_top(is_whq("price")) & in_plan(findout("depart_day")), "asking-days()").unwrap();
+        assert_eq!(
+            rule.to_string(),
+            "when qud_top(is_whq(price)) & in_plan(findout(depart_day)) => asking-days()"
+        );
+        controller.domain_mut().add_rule(rule).unwrap();
+        let rule = Rule::new(&["asking-days()"], "weekend()").unwrap().with_precondition(!in_qud(is_ynq("return")));
+        controller.domain_mut().add_rule(rule).unwrap();
+        let json = controller.domain.to_json();
+        assert_eq!(json["rules"][1], "asking-days() when !in_qud(is_ynq(return)) => weekend()");
+        assert_eq!(Domain::from_json(&json).unwrap().to_json(), json);
+
+        controller.reset();
+        controller.is.qud.push("?x.price(x)".to_string()).unwrap();
+        controller.is.plan = controller.domain.get_plan(&Question::new("?x.price(x)").unwrap()).unwrap();
+        controller.update();
+        assert!(controller.is.bel.contains(&"asking-days()".to_string()));
+        assert!(controller.is.bel.contains(&"weekend()".to_string()));
+        let facts = TSet::new();
+        assert!(controller.domain.infer(&facts).is_empty()); // Preconditions need an information state
+    }
+}
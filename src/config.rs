@@ -0,0 +1,288 @@
+//! Configuration of controllers, layered from TOML files and environment variables.
+
+use crate::{Confirmation, Limits, Locale, Verbosity};
+use serde::Deserialize;
+use std::time::Duration;
+
+/// Environment variables read by IsuConfig::with_env, with the key of the
+/// configuration file each one overrides.
+const ENV_VARS: [(&str, &str); 16] = [
+    ("ISU_VERBOSITY", "verbosity"),
+    ("ISU_CONFIRMATION", "confirmation"),
+    ("ISU_CONFIRMATION_THRESHOLD", "confirmation_threshold"),
+    ("ISU_LOCALE", "locale"),
+    ("ISU_SEED", "seed"),
+    ("ISU_MAX_TURNS", "limits.max_turns"),
+    ("ISU_MAX_QUD_DEPTH", "limits.max_qud_depth"),
+    ("ISU_MAX_PLAN_DEPTH", "limits.max_plan_depth"),
+    ("ISU_MAX_AGENDA_SIZE", "limits.max_agenda_size"),
+    ("ISU_MAX_UPDATE_ITERATIONS", "limits.max_update_iterations"),
+    ("ISU_SILENCE_TIMEOUT", "timeouts.silence"),
+    ("ISU_MAX_REMINDERS", "timeouts.max_reminders"),
+    ("ISU_MAX_REASKS", "escalation.max_reasks"),
+    ("ISU_MAX_MISUNDERSTANDINGS", "escalation.max_misunderstandings"),
+    ("ISU_STREAMING", "features.streaming"),
+    ("ISU_ACKNOWLEDGEMENTS", "features.acknowledgements"),
+];
+
+/// The settings of a controller, applied with `IBISController::with_config`.
+/// Settings left unset keep the value of the controller.
+///
+/// Configurations are layered: each file or set of environment variables
+/// overrides the settings it has. A configuration file looks like:
+///
+/// ```toml
+/// verbosity = "QUIET"               # QUIET, STATE or TRACE
+/// confirmation = "LOW_CONFIDENCE"   # NEVER, LOW_CONFIDENCE or ALWAYS
+/// confirmation_threshold = 0.8      # from 0 to 1
+/// locale = "de"                     # en, de or sv
+/// seed = 42
+///
+/// [limits]
+/// max_turns = 50                    # also max_qud_depth, max_plan_depth, max_agenda_size, max_update_iterations
+///
+/// [timeouts]
+/// silence = 30.0                    # seconds
+/// max_reminders = 2
+///
+/// [escalation]
+/// max_reasks = 2
+/// max_misunderstandings = 3
+///
+/// [features]
+/// streaming = true
+/// acknowledgements = 0.3
+/// ```
+///
+/// and the environment variables are named after the keys, e.g.
+/// ISU_VERBOSITY, ISU_MAX_TURNS, ISU_SILENCE_TIMEOUT or ISU_STREAMING.
+#[derive(Clone, Debug, Default)]
+pub struct IsuConfig {
+    pub verbosity: Option<Verbosity>, // What the dialogue manager prints
+    pub confirmation: Option<Confirmation>, // When integrated user answers are confirmed
+    pub confirmation_threshold: Option<f64>, // Confidence below which answers are confirmed under LOW_CONFIDENCE
+    pub locale: Option<Locale>, // How values are formatted in outputs
+    pub seed: Option<u64>, // Seed of the random numbers of the sessions
+    pub limits: Option<Limits>, // Safeguards against unbounded dialogues
+    pub silence_timeout: Option<Duration>, // Time the user may stay silent before being reminded
+    pub max_reminders: Option<usize>, // Reminders given before a silent session is closed
+    pub max_reasks: Option<usize>, // Times a question may be asked again before escalating
+    pub max_misunderstandings: Option<usize>, // Inputs in a row not understood before escalating
+    pub acknowledgements: Option<f64>, // Share of user answers acknowledged
+    pub streaming: Option<bool>, // Whether utterances are presented sentence by sentence
+}
+
+/// The settings of a configuration file or of the environment.
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct ConfigLayer {
+    verbosity: Option<Verbosity>, // What the dialogue manager prints
+    confirmation: Option<Confirmation>, // When answers are confirmed
+    confirmation_threshold: Option<f64>, // Confidence below which answers are confirmed
+    locale: Option<String>, // Name of the locale (e.g., "de")
+    seed: Option<u64>, // Seed of the random numbers
+    limits: LimitsLayer, // The [limits] section
+    timeouts: TimeoutsLayer, // The [timeouts] section
+    escalation: EscalationLayer, // The [escalation] section
+    features: FeaturesLayer, // The [features] section
+}
+
+/// The [limits] section of a configuration.
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct LimitsLayer {
+    max_turns: Option<usize>, // User inputs per session
+    max_qud_depth: Option<usize>, // Questions under discussion
+    max_plan_depth: Option<usize>, // Constructs in the plan
+    max_agenda_size: Option<usize>, // Actions on the agenda
+    max_update_iterations: Option<usize>, // Rounds of the update rules per turn
+}
+
+/// The [timeouts] section of a configuration.
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct TimeoutsLayer {
+    silence: Option<f64>, // Seconds the user may stay silent
+    max_reminders: Option<usize>, // Reminders before a silent session is closed
+}
+
+/// The [escalation] section of a configuration.
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct EscalationLayer {
+    max_reasks: Option<usize>, // Times a question may be asked again
+    max_misunderstandings: Option<usize>, // Inputs in a row not understood
+}
+
+/// The [features] section of a configuration.
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct FeaturesLayer {
+    streaming: Option<bool>, // Whether utterances are streamed
+    acknowledgements: Option<f64>, // Share of user answers acknowledged
+}
+
+impl IsuConfig {
+    /// Creates a configuration leaving every setting unset.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Loads the configuration of a file, if any, overridden by the
+    /// environment variables.
+    /// # Arguments
+    /// * `path` - The path of the TOML file, if any (e.g., "isu.toml").
+    pub fn load(path: Option<&str>) -> Result<Self, String> {
+        let config = match path {
+            Some(path) => Self::new().with_file(path)?,
+            None => Self::new(),
+        };
+        config.with_env()
+    }
+
+    /// Overrides the settings with those of a TOML document.
+    /// # Arguments
+    /// * `toml` - The document.
+    pub fn with_toml(mut self, toml: &str) -> Result<Self, String> {
+        let layer = toml::from_str(toml).map_err(|e| format!("Invalid configuration: {}", e))?;
+        self.apply(layer)?;
+        Ok(self)
+    }
+
+    /// Overrides the settings with those of a TOML file.
+    /// # Arguments
+    /// * `path` - The path of the file.
+    pub fn with_file(self, path: &str) -> Result<Self, String> {
+        let toml = std::fs::read_to_string(path).map_err(|e| format!("Could not read {}: {}", path, e))?;
+        self.with_toml(&toml).map_err(|e| format!("{}: {}", path, e))
+    }
+
+    /// Overrides the settings with those of the ISU_ environment variables.
+    pub fn with_env(self) -> Result<Self, String> {
+        self.with_vars(std::env::vars())
+    }
+
+    /// Overrides the settings with those of variables named like the
+    /// environment variables, e.g. read from a .env file. Other variables are ignored.
+    /// # Arguments
+    /// * `vars` - The variables with their values (e.g., ("ISU_MAX_TURNS", "50")).
+    pub fn with_vars<I, K, V>(mut self, vars: I) -> Result<Self, String>
+    where
+        I: IntoIterator<Item = (K, V)>,
+        K: AsRef<str>,
+        V: AsRef<str>,
+    {
+        let mut document = toml::Table::new();
+        for (name, value) in vars {
+            let Some((_, key)) = ENV_VARS.iter().find(|(var, _)| *var == name.as_ref()) else {
+                continue;
+            };
+            // Values are TOML values, or else strings
+            let value = match format!("value = {}", value.as_ref()).parse::<toml::Table>() {
+                Ok(mut table) => table.remove("value"),
+                Err(_) => None,
+            }
+            .unwrap_or_else(|| toml::Value::String(value.as_ref().to_string()));
+            let (section, key) = key.split_once('.').map_or((None, *key), |(section, key)| (Some(section), key));
+            let table = match section {
+                Some(section) => document
+                    .entry(section)
+                    .or_insert_with(|| toml::Value::Table(toml::Table::new()))
+                    .as_table_mut()
+                    .ok_or(format!("Invalid section {}", section))?,
+                None => &mut document,
+            };
+            table.insert(key.to_string(), value);
+        }
+        let layer = toml::Value::Table(document)
+            .try_into()
+            .map_err(|e| format!("Invalid configuration variable: {}", e))?;
+        self.apply(layer)?;
+        Ok(self)
+    }
+
+    /// Overrides the settings with those of a layer.
+    /// # Arguments
+    /// * `layer` - The settings of a file or of the environment.
+    fn apply(&mut self, layer: ConfigLayer) -> Result<(), String> {
+        self.verbosity = layer.verbosity.or(self.verbosity.take());
+        self.confirmation = layer.confirmation.or(self.confirmation.take());
+        if let Some(threshold) = layer.confirmation_threshold {
+            if !(0.0..=1.0).contains(&threshold) {
+                return Err(format!("Invalid confirmation threshold: {}", threshold));
+            }
+            self.confirmation_threshold = Some(threshold);
+        }
+        if let Some(name) = layer.locale {
+            self.locale = Some(match name.as_str() {
+                "en" | "en_US" => Locale::new(),
+                "de" | "de_DE" => Locale::german(),
+                "sv" | "sv_SE" => Locale::swedish(),
+                _ => return Err(format!("Unknown locale: {}", name)),
+            });
+        }
+        self.seed = layer.seed.or(self.seed);
+        let LimitsLayer { max_turns, max_qud_depth, max_plan_depth, max_agenda_size, max_update_iterations } =
+            layer.limits;
+        let set = [max_turns, max_qud_depth, max_plan_depth, max_agenda_size, max_update_iterations];
+        if set.iter().any(Option::is_some) {
+            let mut limits = self.limits.take().unwrap_or_default();
+            limits.max_turns = max_turns.or(limits.max_turns);
+            limits.max_qud_depth = max_qud_depth.or(limits.max_qud_depth);
+            limits.max_plan_depth = max_plan_depth.or(limits.max_plan_depth);
+            limits.max_agenda_size = max_agenda_size.or(limits.max_agenda_size);
+            limits.max_update_iterations = max_update_iterations.or(limits.max_update_iterations);
+            self.limits = Some(limits);
+        }
+        if let Some(seconds) = layer.timeouts.silence {
+            let timeout = Duration::try_from_secs_f64(seconds).map_err(|_| format!("Invalid timeout: {}", seconds))?;
+            self.silence_timeout = Some(timeout);
+        }
+        self.max_reminders = layer.timeouts.max_reminders.or(self.max_reminders);
+        self.max_reasks = layer.escalation.max_reasks.or(self.max_reasks);
+        self.max_misunderstandings = layer.escalation.max_misunderstandings.or(self.max_misunderstandings);
+        self.streaming = layer.features.streaming.or(self.streaming);
+        self.acknowledgements = layer.features.acknowledgements.or(self.acknowledgements);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::travel_controller;
+    use crate::ScriptedInputHandler;
+
+    #[test]
+    fn test_config() {
+        let toml = "verbosity = \"QUIET\"\nconfirmation = \"ALWAYS\"\n\
+                    [limits]\nmax_turns = 5\n[timeouts]\nsilence = 1.5";
+        let config = IsuConfig::new().with_toml(toml).unwrap();
+        assert_eq!(config.limits.as_ref().unwrap().max_turns, Some(5));
+        assert_eq!(config.silence_timeout, Some(Duration::from_millis(1500)));
+
+        // Environment variables override the file, other variables are ignored
+        let vars =
+            [("ISU_MAX_QUD_DEPTH", "3"), ("ISU_CONFIRMATION", "NEVER"), ("HOME", "/root"), ("ISU_STREAMING", "true")];
+        let layered = config.clone().with_vars(vars).unwrap();
+        assert_eq!(layered.limits.as_ref().unwrap().max_turns, Some(5));
+        assert_eq!(layered.limits.as_ref().unwrap().max_qud_depth, Some(3));
+        assert_eq!(layered.confirmation, Some(Confirmation::NEVER));
+        assert_eq!(layered.streaming, Some(true));
+        assert!(IsuConfig::new().with_toml("verbosity = \"LOUD\"").is_err());
+        assert!(IsuConfig::new().with_toml("[limits]\nmax_turnz = 5").is_err());
+        assert!(IsuConfig::new().with_vars([("ISU_MAX_TURNS", "many")]).is_err());
+        assert!(IsuConfig::new().with_vars([("ISU_LOCALE", "xx")]).is_err());
+        assert!(IsuConfig::new().with_toml("confirmation_threshold = 1.5").is_err());
+        assert!(IsuConfig::new().with_vars([("ISU_CONFIRMATION_THRESHOLD", "-0.2")]).is_err());
+
+        // Settings apply on their own, e.g. reminders without a timeout
+        let reminders = IsuConfig::new().with_toml("[timeouts]\nmax_reminders = 2").unwrap();
+        assert_eq!(travel_controller(&[]).with_config(&reminders).max_reminders, 2);
+
+        // Controllers confirm every answer when configured to
+        let mut controller = travel_controller(&[]).with_config(&config);
+        let result = controller.run_corpus(vec![ScriptedInputHandler::new().say("?x.price(x)").say("paris")]);
+        assert_eq!(result[0].transcript[4].2, "Okay, paris. Which city?");
+    }
+}
@@ -0,0 +1,465 @@
+//! Finite-state abstraction of the conversations a domain allows, for review.
+
+use crate::{Domain, PlanItem, Question, fill_placeholders, placeholders};
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::Arc;
+
+/// A state of a DialogueFlow: the start of the dialogue, the system asking a
+/// question of a plan, or the end of a plan.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct FlowState {
+    pub id: String, // Name of the state, unique in the flow (e.g., "s3")
+    pub plan: Option<String>, // The question whose plan the state belongs to, None for the start state
+    pub question: Option<String>, // The question the system asks, None for the start and the final states
+}
+
+/// A transition of a DialogueFlow, taken when the user asks a question or
+/// gives an answer while the conditions hold.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct FlowTransition {
+    pub source: String, // Id of the state left
+    pub target: String, // Id of the state entered
+    pub event: String, // The question asked (e.g., "?x.price(x)") or the answer given (e.g., "dest_city(paris)")
+    pub conditions: Vec<String>, // Commitments deciding the If constructs on the way (e.g., "-return()")
+}
+
+/// The conversation flows a domain allows, as a finite-state machine for
+/// compliance and UX reviews: the questions the system asks are the states
+/// and the answers of the user the transitions between them.
+///
+/// From the start state, asking a question with a plan enters the state of
+/// the first question of the plan. Each answer to a question leads to the
+/// state of the next question, or to the final state of the plan once it has
+/// no more questions. Questions over enumerated sorts have a transition per
+/// individual; other questions have a single transition, named after the
+/// question itself, taken by any answer. The branches of If constructs become
+/// transitions guarded by the commitment deciding them. Subplans are followed,
+/// while the recoveries of OnFailure constructs are left out. Plan templates
+/// contribute a plan per question they stand for, with their placeholders
+/// filled with individuals the domain admits in the trigger.
+pub struct DialogueFlow {
+    pub name: Option<String>, // Name of the domain, if any
+    pub states: Vec<FlowState>, // The states, the start state first
+    pub transitions: Vec<FlowTransition>, // The transitions, in the order of the plans
+}
+
+/// A transition waiting for the state it enters.
+struct Pending {
+    source: String, // Id of the state left
+    event: String, // The question asked or the answer given
+    conditions: Vec<String>, // Commitments the transition requires
+}
+
+impl DialogueFlow {
+    /// Builds the flow of the plans of a domain, those of its plan templates
+    /// included, taken in the order of their questions.
+    /// # Arguments
+    /// * `domain` - The domain.
+    pub fn from_domain(domain: &Domain) -> Self {
+        let mut flow = DialogueFlow {
+            name: domain.name().map(str::to_string),
+            states: vec![FlowState { id: "start".to_string(), plan: None, question: None }],
+            transitions: Vec::new(),
+        };
+        let mut plans: BTreeMap<&str, Arc<[String]>> =
+            domain.plans.iter().map(|(trigger, plan)| (trigger.as_str(), plan.clone())).collect();
+        let instances: Vec<String> = domain.plan_templates.keys().flat_map(|t| template_triggers(domain, t)).collect();
+        for trigger in &instances {
+            // The plan of the question is that of the template taking precedence
+            if let Some(plan) = domain.plan_constructs(trigger) {
+                plans.entry(trigger).or_insert(plan);
+            }
+        }
+        for (trigger, plan) in plans {
+            let start = Pending { source: "start".to_string(), event: trigger.to_string(), conditions: Vec::new() };
+            let pending = flow.walk(domain, trigger, &plan, vec![start], &mut HashSet::new());
+            let id = flow.add_state(trigger, None);
+            flow.connect(pending, &id);
+        }
+        flow
+    }
+
+    /// Adds the states of the questions of a plan, returning the transitions
+    /// leaving the last of them.
+    /// # Arguments
+    /// * `domain` - The domain.
+    /// * `trigger` - The question of the plan.
+    /// * `plan` - The constructs, in order.
+    /// * `pending` - The transitions entering the first question.
+    /// * `called` - The subplans being followed, which are not entered again.
+    fn walk(
+        &mut self,
+        domain: &Domain,
+        trigger: &str,
+        plan: &[String],
+        mut pending: Vec<Pending>,
+        called: &mut HashSet<String>,
+    ) -> Vec<Pending> {
+        for item in plan {
+            let Ok(item) = PlanItem::new(item) else {
+                continue;
+            };
+            match item {
+                PlanItem::Findout(findout) => pending = self.ask(domain, trigger, &findout.content, pending),
+                PlanItem::Raise(raise) => pending = self.ask(domain, trigger, &raise.content, pending),
+                PlanItem::If(cond) => {
+                    let mut branches = Vec::new();
+                    for (branch, holds) in [(&cond.iftrue, true), (&cond.iffalse, false)] {
+                        let guard = condition(&cond.cond, holds);
+                        let guarded = pending
+                            .iter()
+                            .filter(|transition| transition.event != negation(&guard))
+                            .map(|transition| {
+                                let mut conditions = transition.conditions.clone();
+                                if transition.event != guard && !conditions.contains(&guard) {
+                                    conditions.push(guard.clone());
+                                }
+                                let (source, event) = (transition.source.clone(), transition.event.clone());
+                                Pending { source, event, conditions }
+                            })
+                            .filter(|transition| !transition.conditions.contains(&negation(&guard)))
+                            .collect();
+                        branches.extend(self.walk(domain, trigger, branch, guarded, called));
+                    }
+                    pending = branches;
+                }
+                PlanItem::CallPlan(call) if !called.contains(&call.name) => {
+                    if let Some(subplan) = domain.subplans.get(&call.name) {
+                        called.insert(call.name.clone());
+                        pending = self.walk(domain, trigger, subplan, pending, called);
+                        called.remove(&call.name);
+                    }
+                }
+                _ => {}
+            }
+        }
+        pending
+    }
+
+    /// Adds the state asking a question, entered by the pending transitions,
+    /// and returns the transitions of its answers.
+    /// # Arguments
+    /// * `domain` - The domain.
+    /// * `trigger` - The question of the plan.
+    /// * `question` - The question asked.
+    /// * `pending` - The transitions entering the state.
+    fn ask(&mut self, domain: &Domain, trigger: &str, question: &Question, pending: Vec<Pending>) -> Vec<Pending> {
+        let id = self.add_state(trigger, Some(question.to_string()));
+        self.connect(pending, &id);
+        answers(domain, question)
+            .into_iter()
+            .map(|event| Pending { source: id.clone(), event, conditions: Vec::new() })
+            .collect()
+    }
+
+    /// Adds a state of a plan and returns its id.
+    /// # Arguments
+    /// * `trigger` - The question of the plan.
+    /// * `question` - The question asked in the state, None for the final state.
+    fn add_state(&mut self, trigger: &str, question: Option<String>) -> String {
+        let id = format!("s{}", self.states.len());
+        self.states.push(FlowState { id: id.clone(), plan: Some(trigger.to_string()), question });
+        id
+    }
+
+    /// Adds pending transitions entering a state.
+    /// # Arguments
+    /// * `pending` - The transitions.
+    /// * `target` - The id of the state.
+    fn connect(&mut self, pending: Vec<Pending>, target: &str) {
+        self.transitions.extend(pending.into_iter().map(|transition| FlowTransition {
+            source: transition.source,
+            target: target.to_string(),
+            event: transition.event,
+            conditions: transition.conditions,
+        }));
+    }
+
+    /// Returns the states that are the end of a plan.
+    pub fn final_states(&self) -> Vec<&FlowState> {
+        self.states.iter().filter(|state| state.plan.is_some() && state.question.is_none()).collect()
+    }
+
+    /// Returns the flow as a JSON object with the fields "name", "initial",
+    /// "states" (with their "id", "plan" and "question") and "transitions"
+    /// (with their "source", "target", "event" and "conditions").
+    pub fn to_json(&self) -> Value {
+        let states: Vec<Value> = self
+            .states
+            .iter()
+            .map(|state| serde_json::json!({ "id": state.id, "plan": state.plan, "question": state.question }))
+            .collect();
+        let transitions: Vec<Value> = self
+            .transitions
+            .iter()
+            .map(|transition| {
+                serde_json::json!({
+                    "source": transition.source,
+                    "target": transition.target,
+                    "event": transition.event,
+                    "conditions": transition.conditions,
+                })
+            })
+            .collect();
+        serde_json::json!({ "name": self.name, "initial": "start", "states": states, "transitions": transitions })
+    }
+
+    /// Returns the flow as an SCXML document with the ECMAScript data model.
+    /// The question of each state is logged on entry. Events are named after
+    /// the words of the question asked or the answer given, joined with dots
+    /// (e.g., "dest_city.paris" for "dest_city(paris)" and "not.return" for
+    /// "-return()"). Conditions test the array "commitments" of the data
+    /// model, which the host keeps up to date with the commitments made.
+    pub fn to_scxml(&self) -> String {
+        let name = self.name.as_ref().map(|name| format!(" name=\"{}\"", escape(name))).unwrap_or_default();
+        let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+        xml.push_str(&format!(
+            "<scxml xmlns=\"http://www.w3.org/2005/07/scxml\" version=\"1.0\" datamodel=\"ecmascript\" \
+             initial=\"start\"{}>\n",
+            name
+        ));
+        xml.push_str("  <datamodel><data id=\"commitments\" expr=\"[]\"/></datamodel>\n");
+        for state in &self.states {
+            let element = if self.final_states().contains(&state) { "final" } else { "state" };
+            let transitions: Vec<&FlowTransition> = self.transitions.iter().filter(|t| t.source == state.id).collect();
+            if state.question.is_none() && transitions.is_empty() {
+                xml.push_str(&format!("  <{} id=\"{}\"/>\n", element, state.id));
+                continue;
+            }
+            xml.push_str(&format!("  <{} id=\"{}\">\n", element, state.id));
+            if let Some(question) = &state.question {
+                xml.push_str(&format!("    <onentry><log label=\"ask\" expr=\"'{}'\"/></onentry>\n", escape(question)));
+            }
+            for transition in transitions {
+                let tests: Vec<String> = transition
+                    .conditions
+                    .iter()
+                    .map(|condition| format!("commitments.indexOf('{}') >= 0", condition))
+                    .collect();
+                let cond = match tests.is_empty() {
+                    true => String::new(),
+                    false => format!(" cond=\"{}\"", escape(&tests.join(" && "))),
+                };
+                xml.push_str(&format!(
+                    "    <transition event=\"{}\"{} target=\"{}\"/>\n",
+                    event_name(&transition.event),
+                    cond,
+                    transition.target
+                ));
+            }
+            xml.push_str(&format!("  </{}>\n", element));
+        }
+        xml.push_str("</scxml>\n");
+        xml
+    }
+}
+
+/// Returns the questions a plan template stands for: its trigger with each
+/// placeholder filled with an individual of the domain, wherever the domain
+/// admits the propositions of the question filled in.
+/// # Arguments
+/// * `domain` - The domain.
+/// * `template` - The trigger of the template (e.g., "?book_trip({transport})").
+fn template_triggers(domain: &Domain, template: &str) -> Vec<String> {
+    let mut inds: Vec<&String> = domain.sorts.iter().flat_map(|(_, inds)| inds).collect();
+    inds.sort();
+    inds.dedup();
+    let mut assignments: Vec<HashMap<String, String>> = vec![HashMap::new()];
+    for param in placeholders(template) {
+        let mut extended = Vec::new();
+        for values in &assignments {
+            for ind in &inds {
+                let mut values = values.clone();
+                values.insert(param.clone(), ind.to_string());
+                extended.push(values);
+            }
+        }
+        assignments = extended;
+    }
+    assignments
+        .iter()
+        .map(|values| fill_placeholders(template, values))
+        .filter(|trigger| {
+            Question::new(trigger).is_ok_and(|question| match &question {
+                Question::WhQ(whq) => domain.preds1.contains_key(&whq.pred.to_string()),
+                Question::YNQ(ynq) => domain.admits(&ynq.prop),
+                Question::AltQ(altq) => altq.ynqs.iter().all(|ynq| domain.admits(&ynq.prop)),
+            })
+        })
+        .collect()
+}
+
+/// Returns the answers of a question taking distinct transitions: an answer
+/// per individual of an enumerated sort, a polarity per yes/no question and
+/// an alternative per alternative question. Questions over sorts that are
+/// not enumerated are answered by the question itself, standing for any answer.
+/// # Arguments
+/// * `domain` - The domain.
+/// * `question` - The question.
+fn answers(domain: &Domain, question: &Question) -> Vec<String> {
+    match question {
+        Question::WhQ(whq) => {
+            let pred = whq.pred.to_string();
+            let sort = domain.preds1.get(&pred);
+            let enumerated = sort.filter(|sort| !domain.is_open_sort(sort) && !domain.validators.contains_key(*sort));
+            let mut inds: Vec<&String> =
+                enumerated.and_then(|sort| domain.sorts.get(sort)).into_iter().flatten().collect();
+            if inds.is_empty() {
+                return vec![question.to_string()];
+            }
+            inds.sort();
+            inds.into_iter().map(|ind| format!("{}({})", pred, ind)).collect()
+        }
+        Question::YNQ(ynq) => vec![ynq.prop.to_string(), negation(&ynq.prop.to_string())],
+        Question::AltQ(altq) => altq.ynqs.iter().map(|ynq| ynq.prop.to_string()).collect(),
+    }
+}
+
+/// Returns the commitment under which a branch of an If is taken, e.g.
+/// "return()" or "-return()" for "?return()".
+/// # Arguments
+/// * `question` - The condition of the If.
+/// * `holds` - Whether the branch is taken when the condition holds.
+fn condition(question: &Question, holds: bool) -> String {
+    let holding = match question {
+        Question::YNQ(ynq) => ynq.prop.to_string(),
+        _ => question.to_string(),
+    };
+    if holds { holding } else { negation(&holding) }
+}
+
+/// Returns the negation of a commitment (e.g., "-return()" for "return()" and back).
+/// # Arguments
+/// * `prop` - The commitment.
+fn negation(prop: &str) -> String {
+    match prop.strip_prefix('-') {
+        Some(positive) => positive.to_string(),
+        None => format!("-{}", prop),
+    }
+}
+
+/// Returns the SCXML event name of a question or an answer: its words joined
+/// with dots, after "not" for a negation (e.g., "not.return" for "-return()").
+/// # Arguments
+/// * `event` - The question or the answer (e.g., "dest_city(paris)").
+fn event_name(event: &str) -> String {
+    let (negated, event) = match event.strip_prefix('-') {
+        Some(positive) => (true, positive),
+        None => (false, event),
+    };
+    let words = event.split(|c: char| !c.is_alphanumeric() && c != '_').filter(|word| !word.is_empty());
+    (negated.then_some("not")).into_iter().chain(words).collect::<Vec<_>>().join(".")
+}
+
+/// Escapes the characters of text that are special in XML attributes.
+/// # Arguments
+/// * `text` - The text (e.g., "fish & chips").
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::travel_domain;
+
+    #[test]
+    fn test_dialogue_flow() {
+        let mut domain = travel_domain();
+        domain.set_name("travel");
+        let flow = DialogueFlow::from_domain(&domain);
+        let questions: Vec<Option<&str>> = flow.states.iter().map(|state| state.question.as_deref()).collect();
+        assert_eq!(
+            questions,
+            [
+                None,
+                Some("?x.dest_city(x)"),
+                Some("?x.depart_city(x)"),
+                Some("?x.depart_day(x)"),
+                Some("?return()"),
+                Some("?x.dest_city(x)"),
+                None,
+            ]
+        );
+        assert_eq!(flow.final_states().len(), 1);
+        let transition = |source: &str, event: &str| {
+            flow.transitions
+                .iter()
+                .find(|t| t.source == source && t.event == event)
+                .map(|t| (t.target.as_str(), t.conditions.clone()))
+        };
+        assert_eq!(transition("start", "?x.price(x)"), Some(("s1", Vec::new())));
+        assert_eq!(transition("s1", "dest_city(berlin)"), Some(("s2", Vec::new())));
+        assert_eq!(transition("s1", "dest_city(paris)"), Some(("s2", Vec::new())));
+        // The answer to the condition decides the branch of the If
+        assert_eq!(transition("s4", "return()"), Some(("s5", Vec::new())));
+        assert_eq!(transition("s4", "-return()"), Some(("s6", Vec::new())));
+        assert_eq!(flow.transitions.len(), 10);
+
+        // Conditions decided elsewhere guard the branches
+        let mut domain = travel_domain();
+        domain.add_plan(
+            Question::new("?x.hotel(x)").unwrap(),
+            vec!["If('?member()', ['Findout(?x.depart_day(x))'], [])".to_string()],
+        );
+        let flow = DialogueFlow::from_domain(&domain);
+        let guarded: Vec<(&str, &[String])> = flow
+            .transitions
+            .iter()
+            .filter(|t| t.event == "?x.hotel(x)" || t.source == "s1")
+            .map(|t| (t.target.as_str(), &t.conditions[..]))
+            .collect();
+        assert_eq!(guarded[0], ("s1", &["member()".to_string()][..]));
+        assert_eq!(guarded[1], ("s2", &[][..]));
+        assert_eq!(guarded[2], ("s2", &["-member()".to_string()][..]));
+
+        let json = flow.to_json();
+        assert_eq!(json["initial"], "start");
+        assert_eq!(json["transitions"][0]["conditions"][0], "member()");
+        let scxml = flow.to_scxml();
+        let cond = "cond=\"commitments.indexOf(&apos;member()&apos;) &gt;= 0\"";
+        assert!(scxml.contains(&format!("<transition event=\"x.hotel.x\" {} target=\"s1\"/>", cond)));
+        let scxml = DialogueFlow::from_domain(&travel_domain()).to_scxml();
+        let root = "<scxml xmlns=\"http://www.w3.org/2005/07/scxml\" version=\"1.0\" datamodel=\"ecmascript\"";
+        assert!(scxml.contains(&format!("{} initial=\"start\">", root)));
+        assert!(scxml.contains("<onentry><log label=\"ask\" expr=\"'?return()'\"/></onentry>"));
+        assert!(scxml.contains("<transition event=\"dest_city.paris\" target=\"s2\"/>"));
+        assert!(scxml.contains("<transition event=\"not.return\" target=\"s6\"/>"));
+        assert!(scxml.contains("<final id=\"s6\"/>"));
+
+        // Plan templates contribute a plan per question the domain admits
+        let preds1 = HashMap::from([
+            ("book_trip".to_string(), "means".to_string()),
+            ("plane_class".to_string(), "class".to_string()),
+            ("train_class".to_string(), "class".to_string()),
+        ]);
+        let sorts = HashMap::from([
+            ("means".to_string(), HashSet::from(["plane".to_string(), "train".to_string()])),
+            ("class".to_string(), HashSet::from(["first".to_string()])),
+        ]);
+        let mut domain = Domain::new(HashSet::new(), preds1, sorts);
+        let template = vec!["Findout('?x.{transport}_class(x)')".to_string()];
+        domain.add_plan_template("?book_trip({transport})", template).unwrap();
+        let flow = DialogueFlow::from_domain(&domain);
+        let triggers: Vec<&str> =
+            flow.transitions.iter().filter(|t| t.source == "start").map(|t| t.event.as_str()).collect();
+        assert_eq!(triggers, ["?book_trip(plane)", "?book_trip(train)"]);
+        assert_eq!(flow.states[3].question.as_deref(), Some("?x.train_class(x)"));
+
+        // A subplan called twice in a row is followed both times
+        let mut domain = travel_domain();
+        domain.add_subplan("ask_day", vec!["Findout('?x.depart_day(x)')".to_string()]).unwrap();
+        domain.add_plan(
+            Question::new("?x.hotel(x)").unwrap(),
+            vec!["CallPlan('ask_day')".to_string(), "CallPlan('ask_day')".to_string()],
+        );
+        let flow = DialogueFlow::from_domain(&domain);
+        let asked = flow.states.iter().filter(|state| state.plan.as_deref() == Some("?x.hotel(x)"));
+        assert_eq!(asked.filter(|state| state.question.is_some()).count(), 2);
+    }
+}
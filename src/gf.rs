@@ -0,0 +1,237 @@
+//! Grammatical Framework (GF) grammars, run by the `gf` shell.
+
+use crate::{Grammar, InterpretationContext, Move, TSet};
+use std::cell::RefCell;
+use std::io::{BufRead, BufReader, Write};
+use std::process::{Child, ChildStdin, ChildStdout, Command, Stdio};
+
+/// Line the shell is asked to print after each command, marking the end of its output.
+const END_MARKER: &str = "__isu_end__";
+
+/// Beginnings of the messages the shell prints instead of a parse.
+const PARSE_FAILURES: [&str; 2] = ["The parser failed", "The sentence is not complete"];
+
+/// Callback notified of the errors of the shell
+type ErrorHandler = Box<dyn Fn(&str)>;
+
+/// A running `gf` shell with a compiled grammar loaded.
+struct GfShell {
+    child: Child, // The shell process
+    stdin: ChildStdin, // Commands sent to the shell
+    stdout: BufReader<ChildStdout>, // Output of the shell
+}
+
+impl GfShell {
+    /// Runs a command and returns the lines it printed.
+    /// # Arguments
+    /// * `command` - The shell command (e.g., "parse -lang=TravelEng \"paris\"").
+    fn run(&mut self, command: &str) -> Result<Vec<String>, String> {
+        writeln!(self.stdin, "{}\nps \"{}\"", command, END_MARKER).map_err(|e| format!("GF shell: {}", e))?;
+        self.stdin.flush().map_err(|e| format!("GF shell: {}", e))?;
+        let mut lines = Vec::new();
+        loop {
+            let mut line = String::new();
+            match self.stdout.read_line(&mut line) {
+                Ok(0) => return Err("GF shell exited".to_string()),
+                Ok(_) if line.trim() == END_MARKER => return Ok(lines),
+                Ok(_) if !line.trim().is_empty() => lines.push(line.trim().to_string()),
+                Ok(_) => {}
+                Err(e) => return Err(format!("GF shell: {}", e)),
+            }
+        }
+    }
+}
+
+impl Drop for GfShell {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+/// A grammar compiled with GF into a PGF file, for parsing and linearization
+/// in any of its concrete syntaxes. Besides the natural language, the grammar
+/// has a semantic concrete syntax that linearizes each tree as the moves it
+/// stands for, with punctuation as separate tokens (e.g., `Ask ( ' ? x .
+/// price ( x ) ' )`) and several moves separated by `;`. User input is parsed
+/// in the language and linearized in the semantics; moves are parsed in the
+/// semantics and linearized in the language. Moves the grammar does not cover
+/// are output as they are. Errors of the shell are passed to the handler
+/// set with on_error, and the input or move is then treated as not covered.
+pub struct GfGrammar {
+    shell: RefCell<GfShell>, // The shell running the grammar
+    language: String, // Concrete syntax of the language (e.g., "TravelEng")
+    semantics: String, // Concrete syntax of the moves (e.g., "TravelSem")
+    error_handler: Option<ErrorHandler>, // Host hook receiving the errors of the shell
+}
+
+impl GfGrammar {
+    /// Loads a compiled grammar into a new `gf` shell found on the PATH.
+    /// # Arguments
+    /// * `pgf` - The path of the PGF file.
+    /// * `language` - The concrete syntax of the language.
+    /// * `semantics` - The concrete syntax of the moves.
+    pub fn new(pgf: &str, language: &str, semantics: &str) -> Result<Self, String> {
+        Self::with_command("gf", pgf, language, semantics)
+    }
+
+    /// Loads a compiled grammar into a new shell run by a given executable.
+    /// # Arguments
+    /// * `command` - The `gf` executable.
+    /// * `pgf` - The path of the PGF file.
+    /// * `language` - The concrete syntax of the language.
+    /// * `semantics` - The concrete syntax of the moves.
+    pub fn with_command(command: &str, pgf: &str, language: &str, semantics: &str) -> Result<Self, String> {
+        let mut child = Command::new(command)
+            .args(["--run", pgf])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .map_err(|e| format!("Could not run {}: {}", command, e))?;
+        let stdin = child.stdin.take().ok_or("GF shell has no input")?;
+        let stdout = BufReader::new(child.stdout.take().ok_or("GF shell has no output")?);
+        let mut shell = GfShell { child, stdin, stdout };
+        // The shell answers once the grammar is loaded, or exits if it cannot be
+        shell.run("").map_err(|e| format!("Could not load {}: {}", pgf, e))?;
+        Ok(GfGrammar {
+            shell: RefCell::new(shell),
+            language: language.to_string(),
+            semantics: semantics.to_string(),
+            error_handler: None,
+        })
+    }
+
+    /// Sets the handler receiving the errors of the shell.
+    /// # Arguments
+    /// * `handler` - The handler, called with the error message.
+    pub fn on_error<F>(&mut self, handler: F)
+    where
+        F: Fn(&str) + 'static,
+    {
+        self.error_handler = Some(Box::new(handler));
+    }
+
+    /// Translates a string from one concrete syntax into another through its
+    /// first parse, if any. Strings with control characters are rejected, as
+    /// a line break would end the command and start another one.
+    /// # Arguments
+    /// * `text` - The string, as tokens separated by spaces.
+    /// * `from` - The concrete syntax to parse with.
+    /// * `to` - The concrete syntax to linearize with.
+    pub fn translate(&self, text: &str, from: &str, to: &str) -> Result<Option<String>, String> {
+        if text.chars().any(char::is_control) {
+            return Err(format!("Control character in GF input: {:?}", text));
+        }
+        let text = text.replace('\\', "\\\\").replace('"', "\\\"");
+        let command = format!("parse -lang={} \"{}\" | linearize -lang={}", from, text, to);
+        let Some(output) = self.shell.borrow_mut().run(&command)?.into_iter().next() else {
+            return Ok(None);
+        };
+        Ok((!PARSE_FAILURES.iter().any(|failure| output.starts_with(failure))).then_some(output))
+    }
+
+    /// Translates a string like translate, passing an error to the error handler.
+    /// # Arguments
+    /// * `text` - The string, as tokens separated by spaces.
+    /// * `from` - The concrete syntax to parse with.
+    /// * `to` - The concrete syntax to linearize with.
+    fn translate_or_report(&self, text: &str, from: &str, to: &str) -> Option<String> {
+        self.translate(text, from, to).unwrap_or_else(|e| {
+            if let Some(handler) = &self.error_handler {
+                handler(&e);
+            }
+            None
+        })
+    }
+}
+
+/// Splits the punctuation of a move into separate tokens.
+/// # Arguments
+/// * `move_str` - The move (e.g., "Ask('?x.price(x)')").
+fn tokenize_move(move_str: &str) -> String {
+    let mut tokens = String::new();
+    for c in move_str.chars().filter(|c| !c.is_whitespace()) {
+        if c.is_alphanumeric() || c == '_' {
+            tokens.push(c);
+        } else {
+            tokens.extend([' ', c, ' ']);
+        }
+    }
+    tokens.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+impl Grammar for GfGrammar {
+    fn generate(&self, moves: &[String], _ctx: &InterpretationContext) -> String {
+        moves
+            .iter()
+            .map(|m| self.translate_or_report(&tokenize_move(m), &self.semantics, &self.language).unwrap_or(m.clone()))
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    fn interpret(&self, input: &str, _ctx: &InterpretationContext) -> Option<TSet<String>> {
+        // Line breaks and tabs in the input separate tokens like spaces
+        let input = input.split_whitespace().collect::<Vec<_>>().join(" ");
+        let semantics = self.translate_or_report(&input, &self.language, &self.semantics)?;
+        semantics
+            .split(';')
+            .map(|m| Move::new(&m.split_whitespace().collect::<String>()).ok().map(|m| m.to_string()))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::travel_controller;
+    use std::rc::Rc;
+
+    #[test]
+    #[cfg(unix)]
+    fn test_gf_grammar() {
+        use std::os::unix::fs::PermissionsExt;
+        // A stand-in for the gf shell knowing a few translations of a travel grammar
+        let script = r#"#!/bin/sh
+[ -f "$2" ] || exit 1
+while read -r line; do
+    case "$line" in
+        'ps "'*) echo "$line" | cut -d '"' -f 2 ;;
+        *'"to paris"'*) echo "Answer ( ' paris ' )" ;;
+        *'"hello , paris"'*) echo "Greet ( ) ; Answer ( ' paris ' )" ;;
+        *"\"Ask ( ' ? x . dest_city ( x ) ' )\""*) echo "where do you want to go ?" ;;
+        *"\"Answer ( price ( 232 ) , [ dest_city ( paris ) ] )\""*) echo "it costs 232" ;;
+        parse*) echo "The parser failed at token 1" ;;
+    esac
+done
+"#;
+        let dir = std::env::temp_dir().join(format!("isu-gf-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (gf, pgf) = (dir.join("gf"), dir.join("Travel.pgf"));
+        std::fs::write(&gf, script).unwrap();
+        std::fs::set_permissions(&gf, std::fs::Permissions::from_mode(0o755)).unwrap();
+        std::fs::write(&pgf, "").unwrap();
+        let gf = gf.to_str().unwrap();
+
+        let mut grammar = GfGrammar::with_command(gf, pgf.to_str().unwrap(), "TravelEng", "TravelSem").unwrap();
+        let errors = Rc::new(RefCell::new(Vec::new()));
+        let reported = errors.clone();
+        grammar.on_error(move |e| reported.borrow_mut().push(e.to_string()));
+        let domain = travel_controller(&[]).domain;
+        let ctx = InterpretationContext::new(&domain);
+        let moves = grammar.interpret("to paris", &ctx).unwrap();
+        assert!(moves.contains(&"Answer(paris)".to_string()));
+        assert_eq!(grammar.interpret("hello , paris", &ctx).unwrap().len(), 2);
+        assert!(grammar.interpret("to london", &ctx).is_none());
+        let moves = ["Ask('?x.dest_city(x)')".to_string(), "Answer(price(232), [dest_city(paris)])".to_string()];
+        assert_eq!(grammar.generate(&moves, &ctx), "where do you want to go ? it costs 232");
+        assert_eq!(grammar.generate(&["Quit()".to_string()], &ctx), "Quit()");
+        // A line break cannot end the parse command and start another one
+        assert!(grammar.translate("paris\"\n! rm -rf x", "TravelEng", "TravelSem").is_err());
+        assert!(grammar.interpret("to\nparis", &ctx).unwrap().contains(&"Answer(paris)".to_string()));
+        assert!(grammar.interpret("to paris\u{7}", &ctx).is_none());
+        assert_eq!(errors.borrow().len(), 1);
+        assert!(GfGrammar::with_command(gf, "Missing.pgf", "TravelEng", "TravelSem").is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
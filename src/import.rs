@@ -0,0 +1,605 @@
+//! Import of domain definitions written for other dialogue frameworks.
+
+use crate::{Domain, Question, SimpleGenGrammar};
+use roxmltree::{Document, Node, ParsingOptions};
+use serde_json::Value;
+use std::collections::{HashMap, HashSet};
+
+/// A domain converted from the definitions of another dialogue framework,
+/// with the grammar asking its questions.
+///
+/// Each intent or form collecting slots becomes a zero-place predicate whose
+/// question (e.g., "?book_flight()") has a plan finding out the required
+/// slots in order, to be fulfilled by the application with
+/// `IBISController::on_complete`. Each slot becomes a one-place predicate of
+/// the sort of its entity, whose values are the individuals of the sort;
+/// system entities and free-text slots become open sorts. The words before
+/// an annotated entity in the training phrases become predicate aliases
+/// (e.g., "fly to" for dest_city), and the prompts of the slots become the
+/// forms asking their questions. Intents without slots and entity synonyms
+/// are not imported.
+pub struct ImportedDomain {
+    pub domain: Domain, // The predicates, sorts, plans and aliases
+    pub grammar: SimpleGenGrammar, // Grammar with a form asking each question of the plans
+}
+
+impl ImportedDomain {
+    /// Converts the intents and entities of a Dialogflow agent export. Intents
+    /// carry their training phrases in "userSays" and entities their values in
+    /// "entries", as in the files of the export or merged from the separate
+    /// `_usersays_` and `_entries_` files by from_dialogflow_dir.
+    /// # Arguments
+    /// * `intents` - The intent objects (e.g., the contents of intents/book_flight.json).
+    /// * `entities` - The entity objects (e.g., the contents of entities/city.json).
+    pub fn from_dialogflow(intents: &[Value], entities: &[Value]) -> Result<Self, String> {
+        let mut converter = Converter::default();
+        for entity in entities {
+            let sort = identifier(entity["name"].as_str().ok_or(format!("Entity without a name: {}", entity))?);
+            let values = converter.sorts.entry(sort).or_default();
+            for entry in entity["entries"].as_array().into_iter().flatten() {
+                values.extend(entry["value"].as_str().map(identifier));
+            }
+        }
+        for intent in intents {
+            let name = identifier(intent["name"].as_str().ok_or(format!("Intent without a name: {}", intent))?);
+            let mut plan = Vec::new();
+            let responses = intent["responses"].as_array().into_iter().flatten();
+            for parameter in responses.flat_map(|response| response["parameters"].as_array().into_iter().flatten()) {
+                let (Some(slot), Some(data_type)) = (parameter["name"].as_str(), parameter["dataType"].as_str()) else {
+                    return Err(format!("Invalid parameter of intent {}: {}", name, parameter));
+                };
+                let slot = identifier(slot);
+                let data_type = data_type.trim_start_matches('@');
+                match data_type.strip_prefix("sys.") {
+                    Some(system) => converter.add_slot(&slot, &identifier(system), true)?,
+                    None => converter.add_slot(&slot, &identifier(data_type), false)?,
+                }
+                if parameter["required"].as_bool() == Some(true) {
+                    plan.push(slot.clone());
+                }
+                if let Some(prompt) = parameter["prompts"][0]["value"].as_str() {
+                    converter.prompts.push((slot, prompt.to_string()));
+                }
+            }
+            converter.add_plan(&name, plan);
+            for phrase in intent["userSays"].as_array().into_iter().flatten() {
+                let mut carrier = String::new();
+                for part in phrase["data"].as_array().into_iter().flatten() {
+                    let text = part["text"].as_str().unwrap_or_default();
+                    match part["alias"].as_str() {
+                        Some(slot) => converter.add_alias(&std::mem::take(&mut carrier), &identifier(slot)),
+                        None => carrier = text.to_string(),
+                    }
+                }
+            }
+        }
+        converter.finish()
+    }
+
+    /// Converts a Dialogflow agent export unpacked in a directory, reading the
+    /// files of its intents/ and entities/ subdirectories.
+    /// # Arguments
+    /// * `path` - The directory of the export.
+    pub fn from_dialogflow_dir(path: &str) -> Result<Self, String> {
+        let read_dir = |dir: &str| -> Result<Vec<(String, Value)>, String> {
+            let dir = format!("{}/{}", path, dir);
+            let entries = std::fs::read_dir(&dir).map_err(|e| format!("Could not read {}: {}", dir, e))?;
+            let mut files = Vec::new();
+            for entry in entries {
+                let file = entry.map_err(|e| format!("Could not read {}: {}", dir, e))?.path();
+                let Some(stem) = file.file_stem().and_then(|stem| stem.to_str()) else {
+                    continue;
+                };
+                if file.extension().is_some_and(|extension| extension == "json") {
+                    let contents = std::fs::read_to_string(&file)
+                        .map_err(|e| format!("Could not read {}: {}", file.display(), e))?;
+                    let json = serde_json::from_str(&contents)
+                        .map_err(|e| format!("Invalid export file {}: {}", file.display(), e))?;
+                    files.push((stem.to_string(), json));
+                }
+            }
+            files.sort_by(|(a, _), (b, _)| a.cmp(b));
+            Ok(files)
+        };
+        // Training phrases and entries are kept in files named after their
+        // intent or entity followed by the language, e.g. city_entries_en.json
+        let merge = |files: Vec<(String, Value)>, infix: &str, field: &str| -> Vec<Value> {
+            let (extras, mut objects): (Vec<_>, Vec<_>) =
+                files.into_iter().partition(|(stem, _)| stem.contains(infix));
+            for (stem, extra) in extras {
+                let owner = &stem[..stem.rfind(infix).unwrap_or_default()];
+                if let Some((_, object)) = objects.iter_mut().find(|(name, _)| name == owner)
+                    && let (Some(list), Some(extra)) = (object.as_object_mut(), extra.as_array())
+                {
+                    let merged = list.entry(field).or_insert(Value::Array(Vec::new()));
+                    merged.as_array_mut().into_iter().for_each(|merged| merged.extend(extra.iter().cloned()));
+                }
+            }
+            objects.into_iter().map(|(_, object)| object).collect()
+        };
+        let intents = merge(read_dir("intents")?, "_usersays_", "userSays");
+        let entities = merge(read_dir("entities")?, "_entries_", "entries");
+        Self::from_dialogflow(&intents, &entities)
+    }
+
+    /// Converts a Rasa domain.yml, with the training examples of an nlu.yml
+    /// for the aliases. Each form becomes a plan for the question named after
+    /// it without the "_form" suffix (e.g., "?flight()" for flight_form).
+    /// Boolean slots become zero-place predicates, categorical slots take the
+    /// sort of the entity they are filled from and other slots an open sort.
+    /// The utter_ask_ responses of the slots become the forms asking them.
+    /// # Arguments
+    /// * `domain_yml` - The contents of domain.yml.
+    /// * `nlu_yml` - The contents of nlu.yml, if any.
+    pub fn from_rasa(domain_yml: &str, nlu_yml: Option<&str>) -> Result<Self, String> {
+        let rasa = parse_yaml(domain_yml)?;
+        let mut converter = Converter::default();
+        // Slot filled by each entity and role of the annotations
+        let mut fillers: HashMap<(String, Option<String>), String> = HashMap::new();
+        for (slot, definition) in rasa["slots"].as_object().into_iter().flatten() {
+            let mut entity = None;
+            for mapping in definition["mappings"].as_array().into_iter().flatten() {
+                if let Some(name) = mapping["entity"].as_str() {
+                    let role = mapping["role"].as_str().map(str::to_string);
+                    fillers.insert((name.to_string(), role), slot.clone());
+                    entity.get_or_insert(identifier(name));
+                }
+            }
+            fillers.entry((slot.clone(), None)).or_insert(slot.clone());
+            let sort = entity.unwrap_or(identifier(slot));
+            match definition["type"].as_str().unwrap_or("any") {
+                "bool" => {
+                    converter.preds0.insert(identifier(slot));
+                }
+                "categorical" => {
+                    converter.add_slot(&identifier(slot), &sort, false)?;
+                    let values = converter.sorts.entry(sort).or_default();
+                    for value in definition["values"].as_array().into_iter().flatten() {
+                        values.extend(scalar_text(value).map(|value| identifier(&value)));
+                    }
+                }
+                _ => converter.add_slot(&identifier(slot), &sort, true)?,
+            }
+        }
+        for (form, definition) in rasa["forms"].as_object().into_iter().flatten() {
+            let required = &definition["required_slots"];
+            let slots: Vec<String> = match required.as_object() {
+                Some(slots) => slots.keys().cloned().collect(),
+                None => required.as_array().into_iter().flatten().filter_map(scalar_text).collect(),
+            };
+            let mut plan = Vec::new();
+            for slot in slots {
+                if rasa["slots"].get(&slot).is_none() {
+                    return Err(format!("Unknown slot {} in form {}", slot, form));
+                }
+                plan.push(identifier(&slot));
+            }
+            converter.add_plan(&identifier(form.strip_suffix("_form").unwrap_or(form)), plan);
+        }
+        let responses = rasa["responses"].as_object();
+        for slot in rasa["slots"].as_object().into_iter().flat_map(|slots| slots.keys()) {
+            let prompt = rasa["forms"]
+                .as_object()
+                .into_iter()
+                .flat_map(|forms| forms.keys())
+                .map(|form| format!("utter_ask_{}_{}", form, slot))
+                .chain([format!("utter_ask_{}", slot)])
+                .find_map(|name| responses?.get(&name)?[0]["text"].as_str());
+            if let Some(prompt) = prompt {
+                converter.prompts.push((identifier(slot), prompt.to_string()));
+            }
+        }
+        if let Some(nlu_yml) = nlu_yml {
+            let nlu = parse_yaml(nlu_yml)?;
+            for intent in nlu["nlu"].as_array().into_iter().flatten() {
+                let examples = intent["examples"].as_str().unwrap_or_default();
+                for example in examples.lines().filter_map(|line| line.trim().strip_prefix("- ")) {
+                    for (carrier, entity, role) in annotations(example) {
+                        let slot = fillers
+                            .get(&(entity.clone(), role))
+                            .or(fillers.get(&(entity, None)));
+                        if let Some(slot) = slot {
+                            converter.add_alias(&carrier, &identifier(slot));
+                        }
+                    }
+                }
+            }
+        }
+        converter.finish()
+    }
+
+    /// Converts a Rasa project, reading domain.yml and, if present, data/nlu.yml.
+    /// # Arguments
+    /// * `path` - The directory of the project.
+    pub fn from_rasa_dir(path: &str) -> Result<Self, String> {
+        let domain_path = format!("{}/domain.yml", path);
+        let domain_yml = std::fs::read_to_string(&domain_path)
+            .map_err(|e| format!("Could not read {}: {}", domain_path, e))?;
+        let nlu_yml = std::fs::read_to_string(format!("{}/data/nlu.yml", path)).ok();
+        Self::from_rasa(&domain_yml, nlu_yml.as_deref())
+    }
+
+    /// Converts the forms of a VoiceXML document. Each form becomes a plan for
+    /// the question named after its id (e.g., "?flight()") finding out its
+    /// fields in document order, which the user may then fill in any order
+    /// and several at a time. Boolean fields become zero-place predicates and
+    /// fields of the other builtin types take an open sort named after the
+    /// type. Other fields take the sort named after the root rule of their
+    /// inline SRGS grammar, or after the field, with the individuals of its
+    /// options and grammar items; fields with only an external grammar take
+    /// an open sort. The first prompt of a field becomes the form asking it.
+    /// # Arguments
+    /// * `xml` - The VoiceXML document.
+    pub fn from_voicexml(xml: &str) -> Result<Self, String> {
+        let options = ParsingOptions { allow_dtd: true, ..ParsingOptions::default() };
+        let document = Document::parse_with_options(xml, options).map_err(|e| format!("Invalid XML: {}", e))?;
+        let vxml = document.root_element();
+        if vxml.tag_name().name() != "vxml" {
+            return Err(format!("Expected a vxml document, found <{}>", vxml.tag_name().name()));
+        }
+        let mut converter = Converter::default();
+        for form in children(vxml, "form") {
+            let id = form.attribute("id").ok_or("Form without an id")?;
+            let mut plan = Vec::new();
+            for field in children(form, "field") {
+                let name = field.attribute("name").ok_or(format!("Field without a name in form {}", id))?;
+                let name = identifier(name);
+                match field.attribute("type").map(|kind| kind.split('?').next().unwrap_or_default()) {
+                    Some("boolean") => {
+                        converter.preds0.insert(name.clone());
+                    }
+                    Some(builtin) => converter.add_slot(&name, &identifier(builtin), true)?,
+                    None => {
+                        let grammars: Vec<Node> = descendants(field, "grammar").collect();
+                        let root = grammars.iter().find_map(|grammar| grammar.attribute("root"));
+                        let sort = identifier(root.unwrap_or(&name));
+                        let options = children(field, "option").map(|option| match option.attribute("value") {
+                            Some(value) => value.to_string(),
+                            None => text(option),
+                        });
+                        let items = grammars.iter().flat_map(|grammar| descendants(*grammar, "item"));
+                        let items = items.filter(|item| descendants(*item, "item").next().is_none()).map(text);
+                        let values: HashSet<String> = options.chain(items).map(|value| identifier(&value)).collect();
+                        converter.add_slot(&name, &sort, values.is_empty())?;
+                        converter.sorts.entry(sort).or_default().extend(values);
+                    }
+                }
+                if let Some(prompt) = children(field, "prompt").next() {
+                    converter.prompts.push((name.clone(), text(prompt)));
+                }
+                plan.push(name);
+            }
+            converter.add_plan(&identifier(id), plan);
+        }
+        converter.finish()
+    }
+
+    /// Converts the forms of a VoiceXML file in the format of from_voicexml.
+    /// # Arguments
+    /// * `path` - The path of the file.
+    pub fn from_voicexml_file(path: &str) -> Result<Self, String> {
+        let xml = std::fs::read_to_string(path).map_err(|e| format!("Could not read {}: {}", path, e))?;
+        Self::from_voicexml(&xml)
+    }
+}
+
+/// Definitions collected from another framework, turned into a domain once complete.
+#[derive(Default)]
+struct Converter {
+    preds0: HashSet<String>, // Zero-place predicates, one per plan and boolean slot
+    preds1: HashMap<String, String>, // Sort of each slot
+    sorts: HashMap<String, HashSet<String>>, // Individuals of each sort
+    open_sorts: HashSet<String>, // Sorts accepting any value
+    plans: Vec<(String, Vec<String>)>, // Slots found out by the plan of each predicate, in order
+    prompts: Vec<(String, String)>, // Prompt asking each slot
+    aliases: HashMap<String, String>, // Slot named by each carrier phrase, the first one found
+}
+
+impl Converter {
+    /// Records a slot as a one-place predicate.
+    /// # Arguments
+    /// * `slot` - The slot (e.g., "dest_city").
+    /// * `sort` - The sort of its values (e.g., "city").
+    /// * `open` - Whether the sort accepts any value.
+    fn add_slot(&mut self, slot: &str, sort: &str, open: bool) -> Result<(), String> {
+        match self.preds1.get(slot) {
+            Some(known) if known != sort => return Err(format!("Slot {} has the sorts {} and {}", slot, known, sort)),
+            _ => self.preds1.insert(slot.to_string(), sort.to_string()),
+        };
+        self.sorts.entry(sort.to_string()).or_default();
+        if open {
+            self.open_sorts.insert(sort.to_string());
+        }
+        Ok(())
+    }
+
+    /// Records a plan finding out slots, unless there are none.
+    /// # Arguments
+    /// * `pred` - The zero-place predicate of the plan (e.g., "book_flight").
+    /// * `slots` - The slots, in order.
+    fn add_plan(&mut self, pred: &str, slots: Vec<String>) {
+        if !slots.is_empty() {
+            self.preds0.insert(pred.to_string());
+            self.plans.push((pred.to_string(), slots));
+        }
+    }
+
+    /// Records the last words of the text before an annotated value as an
+    /// alias of its slot, e.g. "fly to" from "I want to fly to".
+    /// # Arguments
+    /// * `text` - The text before the value.
+    /// * `slot` - The slot of the value.
+    fn add_alias(&mut self, text: &str, slot: &str) {
+        let words: Vec<String> = text.split_whitespace().map(str::to_lowercase).collect();
+        let carrier = words[words.len().saturating_sub(2)..].join(" ");
+        if !carrier.is_empty() {
+            self.aliases.entry(carrier).or_insert(slot.to_string());
+        }
+    }
+
+    /// Returns the question finding out a slot.
+    /// # Arguments
+    /// * `slot` - The slot.
+    fn question(&self, slot: &str) -> String {
+        if self.preds0.contains(slot) {
+            format!("?{}()", slot)
+        } else {
+            format!("?x.{}(x)", slot)
+        }
+    }
+
+    /// Builds the domain and the grammar.
+    fn finish(self) -> Result<ImportedDomain, String> {
+        let mut kinds: HashMap<&str, &str> = HashMap::new();
+        for (sort, inds) in &self.sorts {
+            for ind in inds {
+                if let Some(kind) = kinds.insert(ind, sort) {
+                    return Err(format!("{} is of the sorts {} and {}", ind, kind, sort));
+                }
+            }
+        }
+        let mut domain = Domain::new(self.preds0.clone(), self.preds1.clone(), self.sorts.clone());
+        for sort in &self.open_sorts {
+            domain.add_open_sort(sort);
+        }
+        for (pred, slots) in &self.plans {
+            let plan = slots
+                .iter()
+                .map(|slot| format!("Findout('{}')", self.question(slot)))
+                .collect();
+            domain.add_plan(Question::new(&format!("?{}()", pred))?, plan);
+        }
+        for (alias, slot) in &self.aliases {
+            if self.preds1.contains_key(slot) {
+                domain.add_alias(alias, slot)?;
+            }
+        }
+        let mut grammar = SimpleGenGrammar::new();
+        for (slot, prompt) in &self.prompts {
+            grammar.add_form(&format!("Ask('{}')", self.question(slot)), prompt);
+        }
+        Ok(ImportedDomain { domain, grammar })
+    }
+}
+
+/// Turns a name from another framework into a predicate, sort or individual,
+/// e.g. "New York" into "new_york".
+/// # Arguments
+/// * `name` - The name.
+fn identifier(name: &str) -> String {
+    let name: String = name
+        .trim()
+        .chars()
+        .map(|c| if c.is_alphanumeric() { c.to_ascii_lowercase() } else { '_' })
+        .collect();
+    name.trim_matches('_').to_string()
+}
+
+/// Returns the text of a scalar, whether a string, number or boolean.
+/// # Arguments
+/// * `value` - The value.
+fn scalar_text(value: &Value) -> Option<String> {
+    match value {
+        Value::String(text) => Some(text.clone()),
+        Value::Number(_) | Value::Bool(_) => Some(value.to_string()),
+        _ => None,
+    }
+}
+
+/// Returns the annotated entities of a Rasa training example with the text
+/// before each, e.g. ("fly to", "city", None) from "fly to [paris](city)".
+/// Both the short form and the JSON form with a role are read.
+/// # Arguments
+/// * `example` - The example.
+fn annotations(example: &str) -> Vec<(String, String, Option<String>)> {
+    let mut found = Vec::new();
+    let mut rest = example;
+    while let Some(start) = rest.find('[') {
+        let carrier = &rest[..start];
+        let Some(end) = rest[start..].find(']').map(|end| start + end) else {
+            break;
+        };
+        let after = &rest[end + 1..];
+        let (entity, role, consumed) = if let Some(short) = after.strip_prefix('(') {
+            let Some(close) = short.find(')') else {
+                break;
+            };
+            (short[..close].to_string(), None, close + 2)
+        } else if after.starts_with('{') {
+            let Some(close) = after.find('}') else {
+                break;
+            };
+            let json: Value = serde_json::from_str(&after[..=close]).unwrap_or_default();
+            let Some(entity) = json["entity"].as_str() else {
+                break;
+            };
+            (entity.to_string(), json["role"].as_str().map(str::to_string), close + 1)
+        } else {
+            rest = after;
+            continue;
+        };
+        found.push((carrier.to_string(), entity, role));
+        rest = &after[consumed..];
+    }
+    found
+}
+
+/// Parses a YAML document into JSON.
+/// # Arguments
+/// * `text` - The YAML document.
+fn parse_yaml(text: &str) -> Result<Value, String> {
+    serde_yaml::from_str(text).map_err(|e| format!("Invalid YAML: {}", e))
+}
+
+/// Returns the child elements of an XML element with a tag name.
+/// # Arguments
+/// * `element` - The element.
+/// * `name` - The tag name, without its namespace (e.g., "field").
+fn children<'a, 'input>(element: Node<'a, 'input>, name: &'a str) -> impl Iterator<Item = Node<'a, 'input>> {
+    element.children().filter(move |child| child.is_element() && child.tag_name().name() == name)
+}
+
+/// Returns the elements with a tag name below an XML element, in document order.
+/// # Arguments
+/// * `element` - The element.
+/// * `name` - The tag name, without its namespace (e.g., "item").
+fn descendants<'a, 'input>(element: Node<'a, 'input>, name: &'a str) -> impl Iterator<Item = Node<'a, 'input>> {
+    element
+        .descendants()
+        .skip(1)
+        .filter(move |node| node.is_element() && node.tag_name().name() == name)
+}
+
+/// Returns the text of an XML element and the elements below it, with
+/// whitespace collapsed.
+/// # Arguments
+/// * `element` - The element.
+fn text(element: Node) -> String {
+    let parts: Vec<&str> = element.descendants().filter_map(|node| node.text().filter(|_| node.is_text())).collect();
+    parts.join(" ").split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Grammar, InterpretationContext};
+
+    #[test]
+    fn test_domain_import() {
+        let intents = [serde_json::json!({
+            "name": "Book Flight",
+            "responses": [{"parameters": [
+                {"name": "dest_city", "dataType": "@city", "required": true,
+                 "prompts": [{"lang": "en", "value": "Where do you want to go?"}]},
+                {"name": "passengers", "dataType": "@sys.number", "required": true},
+            ]}],
+            "userSays": [{"data": [{"text": "I want to fly to "}, {"text": "paris", "alias": "dest_city"}]}],
+        })];
+        let entities = [serde_json::json!({
+            "name": "city",
+            "entries": [{"value": "Paris", "synonyms": ["paris"]}, {"value": "New York", "synonyms": []}],
+        })];
+        let imported = ImportedDomain::from_dialogflow(&intents, &entities).unwrap();
+        let domain = &imported.domain;
+        let plan = domain.get_plan(&Question::new("?book_flight()").unwrap()).unwrap();
+        assert_eq!(
+            plan.iter_rev().cloned().collect::<Vec<_>>(),
+            ["Findout('?x.dest_city(x)')", "Findout('?x.passengers(x)')"]
+        );
+        assert!(domain.has_sort("new_york", "city"));
+        assert!(domain.is_open_sort("number"));
+        assert_eq!(domain.interpret_alias("fly to paris").unwrap().to_string(), "dest_city(paris)");
+        let ctx = InterpretationContext::new(domain);
+        let ask = imported.grammar.generate(&["Ask('?x.dest_city(x)')".to_string()], &ctx);
+        assert_eq!(ask, "Where do you want to go?");
+
+        let domain_yml = r#"
+version: "3.1"
+slots:
+  dest_city:
+    type: categorical
+    values: [paris, berlin]
+    mappings:
+    - type: from_entity
+      entity: city
+      role: destination
+  return:
+    type: bool
+    mappings:
+    - type: from_intent  # Filled by affirm or deny
+      intent: affirm
+      value: true
+forms:
+  flight_form:
+    required_slots:
+      - dest_city
+      - return
+responses:
+  utter_ask_dest_city:
+  - text: "Where to?"
+"#;
+        let nlu_yml = r#"
+nlu:
+- intent: book
+  examples: |
+    - I am going to [paris]{"entity": "city", "role": "destination"}
+"#;
+        let imported = ImportedDomain::from_rasa(domain_yml, Some(nlu_yml)).unwrap();
+        let domain = &imported.domain;
+        let plan = domain.get_plan(&Question::new("?flight()").unwrap()).unwrap();
+        assert_eq!(
+            plan.iter_rev().cloned().collect::<Vec<_>>(),
+            ["Findout('?x.dest_city(x)')", "Findout('?return()')"]
+        );
+        assert!(domain.has_sort("berlin", "city"));
+        assert_eq!(domain.interpret_alias("going to berlin").unwrap().to_string(), "dest_city(berlin)");
+        let ctx = InterpretationContext::new(domain);
+        assert_eq!(imported.grammar.generate(&["Ask('?x.dest_city(x)')".to_string()], &ctx), "Where to?");
+        assert!(ImportedDomain::from_rasa("forms:\n  f:\n    required_slots: [missing]\n", None).is_err());
+    }
+
+    #[test]
+    fn test_voicexml_import() {
+        let vxml = r#"<?xml version="1.0" encoding="UTF-8"?>
+<vxml version="2.1" xmlns="http://www.w3.org/2001/vxml">
+  <!-- Flight booking -->
+  <form id="flight">
+    <field name="dest_city">
+      <prompt>Where do you want to go?</prompt>
+      <grammar type="application/srgs+xml" root="city" version="1.0">
+        <rule id="city"><one-of><item>paris</item><item>berlin</item></one-of></rule>
+      </grammar>
+    </field>
+    <field name="seat">
+      <prompt>Window &amp; aisle are free.</prompt>
+      <option value="window">by the window</option>
+      <option>aisle</option>
+    </field>
+    <field name="passengers" type="digits?maxlength=1"/>
+    <field name="return" type="boolean"><prompt>Do you need a return trip?</prompt></field>
+    <filled><submit next="book.jsp"/></filled>
+  </form>
+</vxml>"#;
+        let imported = ImportedDomain::from_voicexml(vxml).unwrap();
+        let domain = &imported.domain;
+        let plan = domain.get_plan(&Question::new("?flight()").unwrap()).unwrap();
+        assert_eq!(
+            plan.iter_rev().cloned().collect::<Vec<_>>(),
+            [
+                "Findout('?x.dest_city(x)')",
+                "Findout('?x.seat(x)')",
+                "Findout('?x.passengers(x)')",
+                "Findout('?return()')"
+            ]
+        );
+        assert!(domain.has_sort("berlin", "city"));
+        assert!(domain.has_sort("window", "seat") && domain.has_sort("aisle", "seat"));
+        assert!(domain.is_open_sort("digits"));
+        let ctx = InterpretationContext::new(domain);
+        let ask = |question: &str| imported.grammar.generate(&[format!("Ask('{}')", question)], &ctx);
+        assert_eq!(ask("?x.seat(x)"), "Window & aisle are free.");
+        assert_eq!(ask("?return()"), "Do you need a return trip?");
+        assert!(ImportedDomain::from_voicexml("<vxml><form id='f'><field/></form></vxml>").is_err());
+        assert!(ImportedDomain::from_voicexml("<vxml><form id='f'></vxml>").is_err());
+    }
+}
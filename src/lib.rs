@@ -2,14 +2,61 @@
 //! The library can be use for Issue-Based Dialogue Management and 
 //! Conversational Agent Architecture.
 
-use std::collections::{HashMap, HashSet, VecDeque};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
 use std::fmt;
 use std::io::{self, Write};
+use std::path::Path;
 use std::hash::Hash;
 use std::any::Any;
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+#[cfg(feature = "readline")]
+mod readline;
+#[cfg(feature = "readline")]
+pub use readline::RustylineInputHandler;
+#[cfg(feature = "metrics")]
+mod metrics;
+#[cfg(feature = "metrics")]
+pub use metrics::DialogueMetrics;
+#[cfg(feature = "gf")]
+mod gf;
+#[cfg(feature = "gf")]
+pub use gf::GfGrammar;
+#[cfg(feature = "regex")]
+mod regex_grammar;
+#[cfg(feature = "regex")]
+pub use regex_grammar::RegexGrammar;
+#[cfg(feature = "bots")]
+mod bots;
+#[cfg(feature = "bots")]
+pub use bots::{BotApi, BotRequest, CurlApi, DiscordBot, TelegramBot};
+#[cfg(feature = "import")]
+mod import;
+#[cfg(feature = "import")]
+pub use import::ImportedDomain;
+mod social;
+pub use social::SocialMoves;
+mod config;
+pub use config::IsuConfig;
+mod flow;
+pub use flow::{DialogueFlow, FlowState, FlowTransition};
+pub mod conditions;
 
 // Input handling traits and implementations
 
+/// The outcome of waiting for user input
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum InputEvent {
+    Line(String), // The user said something
+    Silence, // The user said nothing within the time allowed
+    Closed, // The input has ended
+}
+
 /// Trait for input handling abstraction
 pub trait InputHandler {
     /// Attempts to read a line of input
@@ -18,20 +65,82 @@ pub trait InputHandler {
     
     /// Returns true if input is available
     fn has_input(&self) -> bool;
+
+    /// Receives each system utterance, for handlers that react to the system
+    fn observe_output(&mut self, _output: &str) {}
+
+    /// Waits at most the given time for a line of input.
+    /// Handlers that cannot time out wait for the next line.
+    fn read_line_timeout(&mut self, _timeout: Duration) -> InputEvent {
+        match self.read_line() {
+            Some(line) => InputEvent::Line(line),
+            None => InputEvent::Closed,
+        }
+    }
 }
 
 /// Standard input handler that blocks for user input
-pub struct StandardInputHandler;
+pub struct StandardInputHandler {
+    prompt: String, // Prompt printed before reading a line
+    echo: bool, // Whether to print the line read (useful for piped input)
+    eof_message: String, // Message printed at the end of input
+}
+
+impl StandardInputHandler {
+    /// Creates a new StandardInputHandler with the "U> " prompt.
+    pub fn new() -> Self {
+        Self {
+            prompt: "U> ".to_string(),
+            echo: false,
+            eof_message: "EOF".to_string(),
+        }
+    }
+
+    /// Sets the prompt printed before reading a line.
+    /// # Arguments
+    /// * `prompt` - The prompt.
+    pub fn with_prompt(mut self, prompt: &str) -> Self {
+        self.prompt = prompt.to_string();
+        self
+    }
+
+    /// Sets whether the line read is printed again.
+    /// # Arguments
+    /// * `echo` - True to echo input.
+    pub fn with_echo(mut self, echo: bool) -> Self {
+        self.echo = echo;
+        self
+    }
+
+    /// Sets the message printed at the end of input.
+    /// # Arguments
+    /// * `message` - The message.
+    pub fn with_eof_message(mut self, message: &str) -> Self {
+        self.eof_message = message.to_string();
+        self
+    }
+}
+
+impl Default for StandardInputHandler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
 impl InputHandler for StandardInputHandler {
     fn read_line(&mut self) -> Option<String> {
-        print!("U> ");
+        print!("{}", self.prompt);
         io::stdout().flush().unwrap();
         let mut input = String::new();
         match io::stdin().read_line(&mut input) {
-            Ok(_) => Some(input.trim().to_string()),
-            Err(_) => {
-                println!("EOF");
+            Ok(n) if n > 0 => {
+                if self.echo {
+                    println!("{}", input.trim());
+                }
+                Some(input.trim().to_string())
+            }
+            _ => {
+                println!("{}", self.eof_message);
                 None
             }
         }
@@ -45,25 +154,55 @@ impl InputHandler for StandardInputHandler {
 /// Demo input handler with predefined inputs
 pub struct DemoInputHandler {
     inputs: VecDeque<String>,
-    current_index: usize,
+    prompt: String, // Prompt shown before each simulated input
+    echo: bool, // Whether to show the simulated input
+    eof_message: String, // Message printed when the inputs run out
 }
 
 impl DemoInputHandler {
     pub fn new(inputs: Vec<String>) -> Self {
         Self {
             inputs: inputs.into(),
-            current_index: 0,
+            prompt: "U> ".to_string(),
+            echo: true,
+            eof_message: "Demo completed - no more inputs".to_string(),
         }
     }
+
+    /// Sets the prompt shown before each simulated input.
+    /// # Arguments
+    /// * `prompt` - The prompt.
+    pub fn with_prompt(mut self, prompt: &str) -> Self {
+        self.prompt = prompt.to_string();
+        self
+    }
+
+    /// Sets whether the simulated input is shown.
+    /// # Arguments
+    /// * `echo` - True to show input.
+    pub fn with_echo(mut self, echo: bool) -> Self {
+        self.echo = echo;
+        self
+    }
+
+    /// Sets the message printed when the inputs run out.
+    /// # Arguments
+    /// * `message` - The message.
+    pub fn with_eof_message(mut self, message: &str) -> Self {
+        self.eof_message = message.to_string();
+        self
+    }
 }
 
 impl InputHandler for DemoInputHandler {
     fn read_line(&mut self) -> Option<String> {
         if let Some(input) = self.inputs.pop_front() {
-            println!("U> {}", input); // Show simulated user input
+            if self.echo {
+                println!("{}{}", self.prompt, input); // Show simulated user input
+            }
             Some(input)
         } else {
-            println!("Demo completed - no more inputs");
+            println!("{}", self.eof_message);
             None
         }
     }
@@ -73,2422 +212,15132 @@ impl InputHandler for DemoInputHandler {
     }
 }
 
-// Helper functions
-
-/// Checks if a given type can be treated as a sequence.
-/// Note: Simplified to always return true due to Rust's type system constraints.
-/// Modify based on specific type requirements.
-fn is_sequence<T>(seq: &T) -> bool {
-    // This is a simplified version assuming we work with specific types.
-    true // Modify based on specific needs
+/// A step of a dialogue script
+#[derive(Clone, PartialEq, Debug)]
+pub enum ScriptStep {
+    Say(String), // Provide a line of user input
+    Expect(String), // Assert the latest system utterance
+    Delay(Duration), // Pause before continuing
+    Branch(Vec<(String, Vec<ScriptStep>)>), // Continue with the steps of the first pattern found in the latest system utterance
+    Silence, // Let the time allowed for input pass without saying anything
 }
 
-// Value struct
-
-/// A generic container for values with constraints on allowed values or type checks.
-/// Represents a single value with optional validation rules.
-struct Value<T: Clone + PartialEq + Eq + Hash> {
-    value: Option<T>, // The stored value, if any
-    allowed_values: HashSet<T>, // Set of permitted values
-    type_constraint: Option<Box<dyn Fn(&T) -> bool>>, // Optional type checking function
+/// Scripted input handler that feeds user input, checks the system
+/// utterances against expectations and branches on them, so that example
+/// dialogues double as end-to-end tests
+pub struct ScriptedInputHandler {
+    steps: VecDeque<ScriptStep>, // Remaining steps of the script
+    last_output: Option<String>, // Latest system utterance
+    strict: bool, // Whether a failed expectation panics
+    mismatches: Vec<String>, // Failed expectations
+    transcript: Vec<(Speaker, String)>, // Utterances exchanged so far
+    prompt: String, // Prompt shown before each scripted input
+    echo: bool, // Whether to show the scripted input
+    eof_message: String, // Message printed when the script runs out
 }
 
-impl<T: Clone + PartialEq + Eq + Hash> Clone for Value<T> {
-    fn clone(&self) -> Self {
-        Value {
-            value: self.value.clone(),
-            allowed_values: self.allowed_values.clone(),
-            type_constraint: None, // Cannot clone function pointers
+impl ScriptedInputHandler {
+    /// Creates a new, empty ScriptedInputHandler.
+    pub fn new() -> Self {
+        Self {
+            steps: VecDeque::new(),
+            last_output: None,
+            strict: true,
+            mismatches: Vec::new(),
+            transcript: Vec::new(),
+            prompt: "U> ".to_string(),
+            echo: true,
+            eof_message: "Script completed - no more inputs".to_string(),
         }
     }
-}
 
-/// Implementation of methods for the Value struct.
-impl<T: Clone + PartialEq + Eq + Hash + fmt::Display> Value<T> {
-    /// Creates a new Value with a set of allowed values.
+    /// Creates a new ScriptedInputHandler from a list of steps.
     /// # Arguments
-    /// * `allowed` - A HashSet of permitted values.
-    fn new_allowed(allowed: HashSet<T>) -> Self {
-        Value {
-            value: None,
-            allowed_values: allowed,
-            type_constraint: None,
+    /// * `steps` - The steps of the script.
+    pub fn from_steps(steps: Vec<ScriptStep>) -> Self {
+        Self {
+            steps: steps.into(),
+            ..Self::new()
         }
     }
 
-    /// Creates a new Value with a type constraint function.
+    /// Appends a line of user input to the script.
     /// # Arguments
-    /// * `type_check` - A function to validate the type of values.
-    fn new_type<F>(type_check: F) -> Self
-    where
-        F: Fn(&T) -> bool + 'static,
-    {
-        Value {
-            value: None,
-            allowed_values: HashSet::new(),
-            type_constraint: Some(Box::new(type_check)),
-        }
+    /// * `input` - The user input.
+    pub fn say(mut self, input: &str) -> Self {
+        self.steps.push_back(ScriptStep::Say(input.to_string()));
+        self
     }
 
-    /// Sets the value after validating against constraints.
-    /// Returns an error if the value is not allowed or fails the type check.
+    /// Appends the system utterance expected at this point of the script.
     /// # Arguments
-    /// * `value` - The value to set.
-    fn set(&mut self, value: T) -> Result<(), String> {
-        if !self.allowed_values.is_empty() && !self.allowed_values.contains(&value) {
-            return Err(format!("{} is not among allowed values", value));
-        }
-        if let Some(check) = &self.type_constraint {
-            if !check(&value) {
-                return Err(format!("{} does not match type constraint", value));
-            }
-        }
-        self.value = Some(value);
-        Ok(())
-    }
-
-    /// Retrieves the stored value, if any.
-    fn get(&self) -> Option<&T> {
-        self.value.as_ref()
+    /// * `output` - The expected system utterance.
+    pub fn expect(mut self, output: &str) -> Self {
+        self.steps.push_back(ScriptStep::Expect(output.to_string()));
+        self
     }
 
-    /// Clears the stored value.
-    fn clear(&mut self) {
-        self.value = None;
+    /// Appends a pause to the script.
+    /// # Arguments
+    /// * `duration` - The length of the pause.
+    pub fn delay(mut self, duration: Duration) -> Self {
+        self.steps.push_back(ScriptStep::Delay(duration));
+        self
     }
-}
 
-/// Formats the Value for display, showing the stored value or an empty marker.
-impl<T: Clone + PartialEq + Eq + Hash + fmt::Display> fmt::Display for Value<T> {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match &self.value {
-            Some(v) => write!(f, "<{}>", v),
-            None => write!(f, "<>"),
-        }
+    /// Appends a turn in which the user stays silent to the script.
+    pub fn silence(mut self) -> Self {
+        self.steps.push_back(ScriptStep::Silence);
+        self
     }
-}
-
-// Record struct
-
-/// A key-value store with type checking for fields.
-struct Record {
-    typedict: HashMap<String, Box<dyn Fn(&dyn Any) -> bool>>, // Type checking functions for fields
-    fields: HashMap<String, Box<dyn Any>>, // Stored field values
-}
 
-impl Clone for Record {
-    fn clone(&self) -> Self {
-        Record {
-            typedict: HashMap::new(), // Cannot clone function pointers
-            fields: HashMap::new(), // Cannot clone Any trait objects safely
-        }
+    /// Appends a branch on the latest system utterance to the script.
+    /// # Arguments
+    /// * `branches` - Pairs of a pattern and the steps to continue with when the
+    ///   utterance contains it. The empty pattern always matches.
+    pub fn branch(mut self, branches: Vec<(&str, Vec<ScriptStep>)>) -> Self {
+        let branches = branches.into_iter().map(|(p, steps)| (p.to_string(), steps)).collect();
+        self.steps.push_back(ScriptStep::Branch(branches));
+        self
     }
-}
 
-/// Implementation of methods for the Record struct.
-impl Record {
-    /// Creates a new Record with initial fields and inferred type checks.
+    /// Sets whether a failed expectation panics or is only recorded.
     /// # Arguments
-    /// * `fields` - Initial key-value pairs.
-    fn new(fields: HashMap<String, Box<dyn Any>>) -> Self {
-        let mut typedict: HashMap<String, Box<dyn Fn(&dyn Any) -> bool>> = HashMap::new();
-        for (key, value) in &fields {
-            let type_id = value.type_id();
-            typedict.insert(key.clone(), Box::new(move |v: &dyn Any| v.type_id() == type_id) as Box<dyn Fn(&dyn Any) -> bool>);
-        }
-        Record { typedict, fields }
+    /// * `strict` - True to panic.
+    pub fn with_strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
     }
 
-    /// Returns a HashMap of field keys to their values.
-    fn as_dict(&self) -> HashMap<String, &dyn Any> {
-        self.fields.iter().map(|(k, v)| (k.clone(), v.as_ref())).collect()
+    /// Sets the prompt shown before each scripted input.
+    /// # Arguments
+    /// * `prompt` - The prompt.
+    pub fn with_prompt(mut self, prompt: &str) -> Self {
+        self.prompt = prompt.to_string();
+        self
     }
 
-    /// Checks if a value matches the expected type for a given key.
+    /// Sets whether the scripted input is shown.
     /// # Arguments
-    /// * `key` - The field key to check.
-    /// * `value` - Optional value to type check.
-    fn typecheck(&self, key: &str, value: Option<&dyn Any>) -> Result<(), String> {
-        if let Some(type_fn) = self.typedict.get(key) {
-            if let Some(val) = value {
-                if !type_fn(val) {
-                    return Err(format!("{} is not of expected type", key));
-                }
-            }
-            Ok(())
-        } else {
-            Err(format!("{} is not a valid key", key))
-        }
+    /// * `echo` - True to show input.
+    pub fn with_echo(mut self, echo: bool) -> Self {
+        self.echo = echo;
+        self
     }
 
-    /// Retrieves a field value by key after type checking.
+    /// Sets the message printed when the script runs out.
     /// # Arguments
-    /// * `key` - The field key.
-    fn get(&self, key: &str) -> Option<&dyn Any> {
-        self.typecheck(key, None).ok()?;
-        self.fields.get(key).map(|v| v.as_ref())
+    /// * `message` - The message.
+    pub fn with_eof_message(mut self, message: &str) -> Self {
+        self.eof_message = message.to_string();
+        self
     }
 
-    /// Sets a field value after type checking.
-    /// # Arguments
-    /// * `key` - The field key.
-    /// * `value` - The value to set.
-    fn set(&mut self, key: &str, value: Box<dyn Any>) -> Result<(), String> {
-        self.typecheck(key, Some(value.as_ref()))?;
-        self.fields.insert(key.to_string(), value);
-        Ok(())
+    /// Returns the failed expectations recorded so far.
+    pub fn mismatches(&self) -> &[String] {
+        &self.mismatches
     }
 
-    /// Removes a field by key after type checking.
-    /// # Arguments
-    /// * `key` - The field key to remove.
-    fn delete(&mut self, key: &str) -> Result<(), String> {
-        self.typecheck(key, None)?;
-        self.fields.remove(key);
-        Ok(())
+    /// Returns the utterances of the user and the system exchanged so far.
+    pub fn transcript(&self) -> &[(Speaker, String)] {
+        &self.transcript
     }
 
-    /// Formats the Record as a string with a given prefix and indent.
-    /// # Arguments
-    /// * `prefix` - Prefix for each line.
-    /// * `indent` - Indentation string (unused in this implementation).
-    fn pformat(&self, prefix: &str, indent: &str) -> String {
-        let mut result = String::new();
-        for (key, value) in self.as_dict() {
-            if !result.is_empty() {
-                result.push('\n');
-            }
-            result.push_str(prefix);
-            result.push_str(&key);
-            result.push_str(": ");
-            // Simplified: assumes value can be formatted as string
-            result.push_str(&format!("{:?}", value));
+    /// Checks the latest system utterance against an expected one.
+    fn check(&mut self, expected: &str) {
+        if self.last_output.as_deref() == Some(expected) {
+            return;
         }
-        result
+        let mismatch = format!(
+            "expected system output {:?}, got {:?}",
+            expected,
+            self.last_output.as_deref().unwrap_or("[---]")
+        );
+        if self.strict {
+            panic!("{}", mismatch);
+        }
+        self.mismatches.push(mismatch);
     }
 }
 
-/// Formats the Record for display as a semicolon-separated list of key-value pairs.
-impl fmt::Display for Record {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let items: Vec<String> = self.as_dict().iter().map(|(k, v)| format!("{} = {:?}", k, v)).collect();
-        write!(f, "{{{}}}", items.join("; "))
+impl Default for ScriptedInputHandler {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
-// Stack struct
+impl From<Vec<String>> for ScriptedInputHandler {
+    /// Creates a script saying each input in turn, e.g. from a recorded user log.
+    fn from(inputs: Vec<String>) -> Self {
+        Self::from_steps(inputs.into_iter().map(ScriptStep::Say).collect())
+    }
+}
 
-/// A generic stack with optional type constraints.
-struct Stack<T: Clone> {
-    elements: Vec<T>, // The stack's elements
-    type_constraint: Option<Box<dyn Fn(&T) -> bool>>, // Optional type checking function
+impl From<Vec<ScriptStep>> for ScriptedInputHandler {
+    fn from(steps: Vec<ScriptStep>) -> Self {
+        Self::from_steps(steps)
+    }
 }
 
-impl<T: Clone> Clone for Stack<T> {
-    fn clone(&self) -> Self {
-        Stack {
-            elements: self.elements.clone(),
-            type_constraint: None, // Cannot clone function pointers
+impl ScriptedInputHandler {
+    /// Plays the script up to the next user turn.
+    /// # Arguments
+    /// * `timeout` - Whether silent turns can be reported; otherwise they are skipped.
+    fn next_event(&mut self, timeout: bool) -> InputEvent {
+        while let Some(step) = self.steps.pop_front() {
+            match step {
+                ScriptStep::Say(input) => {
+                    if self.echo {
+                        println!("{}{}", self.prompt, input); // Show scripted user input
+                    }
+                    self.transcript.push((Speaker::USR, input.clone()));
+                    return InputEvent::Line(input);
+                }
+                ScriptStep::Silence if timeout => {
+                    if self.echo {
+                        println!("{}...", self.prompt); // Show the silent turn
+                    }
+                    return InputEvent::Silence;
+                }
+                ScriptStep::Silence => {}
+                ScriptStep::Expect(expected) => self.check(&expected),
+                ScriptStep::Delay(duration) => thread::sleep(duration),
+                ScriptStep::Branch(branches) => {
+                    let output = self.last_output.as_deref().unwrap_or("");
+                    if let Some((_, steps)) = branches.into_iter().find(|(p, _)| output.contains(p.as_str())) {
+                        for step in steps.into_iter().rev() {
+                            self.steps.push_front(step);
+                        }
+                    }
+                }
+            }
         }
+        println!("{}", self.eof_message);
+        InputEvent::Closed
     }
 }
 
-/// Implementation of methods for the Stack struct.
-impl<T: Clone + PartialEq + fmt::Display> Stack<T> {
-    /// Creates a new empty Stack.
-    fn new() -> Self {
-        Stack {
-            elements: Vec::new(),
-            type_constraint: None,
+impl InputHandler for ScriptedInputHandler {
+    fn read_line(&mut self) -> Option<String> {
+        match self.next_event(false) {
+            InputEvent::Line(line) => Some(line),
+            _ => None,
         }
     }
 
-    /// Creates a new Stack with a type constraint.
-    /// # Arguments
-    /// * `type_check` - A function to validate pushed values.
-    fn with_type<F>(type_check: F) -> Self
-    where
-        F: Fn(&T) -> bool + 'static,
-    {
-        Stack {
-            elements: Vec::new(),
-            type_constraint: Some(Box::new(type_check)),
-        }
+    fn read_line_timeout(&mut self, _timeout: Duration) -> InputEvent {
+        self.next_event(true)
     }
 
-    /// Returns a reference to the top element of the stack.
-    fn top(&self) -> Result<&T, String> {
-        self.elements.last().ok_or("Stack is empty".to_string())
+    fn has_input(&self) -> bool {
+        !self.steps.is_empty()
     }
 
-    /// Removes and returns the top element of the stack.
-    fn pop(&mut self) -> Result<T, String> {
-        self.elements.pop().ok_or("Stack is empty".to_string())
+    fn observe_output(&mut self, output: &str) {
+        self.last_output = Some(output.to_string());
+        self.transcript.push((Speaker::SYS, output.to_string()));
     }
+}
 
-    /// Pushes a value onto the stack after type checking.
-    /// # Arguments
-    /// * `value` - The value to push.
-    fn push(&mut self, value: T) -> Result<(), String> {
-        if let Some(check) = &self.type_constraint {
-            if !check(&value) {
-                return Err(format!("{} does not match type constraint", value));
-            }
-        }
-        self.elements.push(value);
-        Ok(())
+/// Scripted input handler shared with the controller while a corpus runs
+struct SharedScript(Rc<RefCell<ScriptedInputHandler>>);
+
+impl InputHandler for SharedScript {
+    fn read_line(&mut self) -> Option<String> {
+        self.0.borrow_mut().read_line()
     }
 
-    /// Clears all elements from the stack.
-    fn clear(&mut self) {
-        self.elements.clear();
+    fn has_input(&self) -> bool {
+        self.0.borrow().has_input()
     }
 
-    /// Returns the number of elements in the stack.
-    fn len(&self) -> usize {
-        self.elements.len()
+    fn observe_output(&mut self, output: &str) {
+        self.0.borrow_mut().observe_output(output);
     }
-}
 
-/// Formats the Stack for display, showing elements in reverse order.
-impl<T: Clone + fmt::Display> fmt::Display for Stack<T> {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let reversed: Vec<String> = self.elements.iter().rev().map(|e| e.to_string()).collect();
-        write!(f, "<[ {} <]", reversed.join(", "))
+    fn read_line_timeout(&mut self, timeout: Duration) -> InputEvent {
+        self.0.borrow_mut().read_line_timeout(timeout)
     }
 }
 
-// StackSet struct
-
-/// A stack-based set ensuring unique elements with LIFO order.
-#[derive(Clone)]
-struct StackSet<T: Clone + PartialEq + Eq + Hash> {
-    stack: Stack<T>, // Underlying stack for storage
+/// The outcome of one scripted dialogue of a corpus
+#[derive(Clone, PartialEq, Debug)]
+pub struct DialogueResult {
+    pub transcript: Vec<(usize, Speaker, String)>, // Utterances of the user and the system with their turn
+    pub mismatches: Vec<String>, // Failed expectations
+    pub commitments: Vec<String>, // Shared commitments at the end, sorted
+    pub completed: bool, // Whether the whole script was played
+    pub limit_exceeded: Option<LimitExceeded>, // The limit that ended the dialogue, if any
+    pub user: Option<String>, // The user the dialogue was held with, if identified
+    pub variants: Vec<(String, String)>, // Variants of the experiments the dialogue was held in, by experiment
 }
 
-/// Implementation of methods for the StackSet struct.
-impl<T: Clone + PartialEq + Eq + Hash + fmt::Display> StackSet<T> {
-    /// Creates a new empty StackSet.
-    fn new() -> Self {
-        StackSet { stack: Stack::new() }
+impl DialogueResult {
+    /// Returns true if the whole script was played and all expectations held.
+    pub fn passed(&self) -> bool {
+        self.completed && self.mismatches.is_empty()
     }
 
-    /// Creates a new StackSet with a type constraint.
-    /// # Arguments
-    /// * `type_check` - A function to validate elements.
-    fn with_type<F>(type_check: F) -> Self
-    where
-        F: Fn(&T) -> bool + 'static,
-    {
-        StackSet {
-            stack: Stack::with_type(type_check),
+    /// Returns the canonical text of the dialogue: the variants of its
+    /// experiments, if any, then one line per utterance, prefixed with its
+    /// turn, followed by the sorted shared commitments.
+    pub fn to_transcript(&self) -> String {
+        let mut text = String::new();
+        if !self.variants.is_empty() {
+            let variants: Vec<String> = self.variants.iter().map(|(e, v)| format!("{}={}", e, v)).collect();
+            text.push_str(&format!("VARIANTS: {}\n", variants.join(", ")));
+        }
+        for (turn, speaker, utterance) in &self.transcript {
+            let prompt = if *speaker == Speaker::USR { "U> " } else { "S> " };
+            text.push_str(&format!("[{}] {}{}\n", turn, prompt, utterance));
         }
+        text.push_str(&format!("COM: {}\n", self.commitments.join(", ")));
+        text
     }
+}
 
-    /// Checks if a value is present in the StackSet.
-    /// # Arguments
-    /// * `value` - The value to check.
-    fn contains(&self, value: &T) -> bool {
-        self.stack.elements.contains(value)
-    }
+/// Environment variable that makes check_golden (re)write golden files
+pub const BLESS_ENV: &str = "ISU_BLESS";
 
-    /// Pushes a value, removing any existing instance to maintain uniqueness.
-    /// # Arguments
-    /// * `value` - The value to push.
-    fn push(&mut self, value: T) -> Result<(), String> {
-        if self.contains(&value) {
-            self.stack.elements.retain(|x| x != &value);
+/// Compares a transcript with the golden file at a path. When the ISU_BLESS
+/// environment variable is set, the golden file is written instead.
+/// # Arguments
+/// * `path` - The golden file.
+/// * `transcript` - The transcript of the dialogue, e.g. from DialogueResult::to_transcript.
+pub fn check_golden(path: impl AsRef<Path>, transcript: &str) -> Result<(), String> {
+    let path = path.as_ref();
+    if std::env::var_os(BLESS_ENV).is_some() {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir).map_err(|e| e.to_string())?;
         }
-        self.stack.push(value)
+        return std::fs::write(path, transcript).map_err(|e| e.to_string());
     }
+    let golden = std::fs::read_to_string(path)
+        .map_err(|e| format!("Cannot read golden file {}: {} (set {}=1 to create it)", path.display(), e, BLESS_ENV))?;
+    if golden == transcript {
+        return Ok(());
+    }
+    let diff: Vec<String> = golden
+        .lines()
+        .map(Some)
+        .chain(std::iter::repeat(None))
+        .zip(transcript.lines().map(Some).chain(std::iter::repeat(None)))
+        .take_while(|(g, t)| g.is_some() || t.is_some())
+        .filter(|(g, t)| g != t)
+        .map(|(g, t)| format!("- {}\n+ {}", g.unwrap_or(""), t.unwrap_or("")))
+        .collect();
+    Err(format!(
+        "Transcript differs from {} (set {}=1 to bless):\n{}",
+        path.display(),
+        BLESS_ENV,
+        diff.join("\n")
+    ))
 }
 
-/// Formats the StackSet for display, showing the underlying stack.
-impl<T: Clone + PartialEq + Eq + Hash + fmt::Display> fmt::Display for StackSet<T> {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "<{{ {} <}}", self.stack.to_string())
+// Output handling traits and implementations
+
+/// Trait for output handling abstraction
+pub trait OutputHandler {
+    /// Presents a system utterance
+    fn write_output(&mut self, output: &str);
+
+    /// Presents one sentence of an utterance as soon as it is generated, when
+    /// the controller streams its output. A handler that is not ready for more
+    /// applies back-pressure by blocking until it is. By default the sentence
+    /// is presented as an utterance of its own.
+    fn write_chunk(&mut self, chunk: &str) {
+        self.write_output(chunk);
     }
+
+    /// Marks the end of a streamed utterance.
+    fn end_output(&mut self) {}
 }
 
-// TSet struct
+/// Standard output handler that prints system utterances to stdout
+pub struct StandardOutputHandler {
+    prompt: String, // Prefix printed before each utterance
+}
 
-/// A typed set with optional type constraints for elements.
-struct TSet<T: Clone + PartialEq + Eq + Hash> {
-    elements: HashSet<T>, // The set of elements
-    type_constraint: Option<Box<dyn Fn(&T) -> bool>>, // Optional type checking function
+impl StandardOutputHandler {
+    /// Creates a new StandardOutputHandler with the "S> " prompt.
+    pub fn new() -> Self {
+        Self {
+            prompt: "S> ".to_string(),
+        }
+    }
+
+    /// Sets the prefix printed before each utterance.
+    /// # Arguments
+    /// * `prompt` - The prefix.
+    pub fn with_prompt(mut self, prompt: &str) -> Self {
+        self.prompt = prompt.to_string();
+        self
+    }
 }
 
-impl<T: Clone + PartialEq + Eq + Hash> Clone for TSet<T> {
-    fn clone(&self) -> Self {
-        TSet {
-            elements: self.elements.clone(),
-            type_constraint: None, // Cannot clone function pointers
-        }
+impl Default for StandardOutputHandler {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
-/// Implementation of methods for the TSet struct.
-impl<T: Clone + PartialEq + Eq + Hash + fmt::Display> TSet<T> {
-    /// Creates a new empty TSet.
-    fn new() -> Self {
-        TSet {
-            elements: HashSet::new(),
-            type_constraint: None,
-        }
+impl OutputHandler for StandardOutputHandler {
+    fn write_output(&mut self, output: &str) {
+        println!("{}{}", self.prompt, output);
+        println!();
     }
+}
 
-    /// Creates a new TSet with a type constraint.
-    /// # Arguments
-    /// * `type_check` - A function to validate elements.
-    fn with_type<F>(type_check: F) -> Self
-    where
-        F: Fn(&T) -> bool + 'static,
-    {
-        TSet {
-            elements: HashSet::new(),
-            type_constraint: Some(Box::new(type_check)),
-        }
+// Text filters
+
+/// Trait for filters rewriting user input before interpretation or system
+/// output before it is presented (e.g., to mask profanity or personal data)
+pub trait TextFilter {
+    /// Returns the filtered text
+    fn filter(&self, text: &str) -> String;
+}
+
+/// Any function from text to text can be used as a filter
+impl<F> TextFilter for F
+where
+    F: Fn(&str) -> String,
+{
+    fn filter(&self, text: &str) -> String {
+        self(text)
     }
+}
 
-    /// Adds an element to the TSet after type checking.
+/// Filter redacting obvious personal data: email addresses and long numbers
+/// such as phone, card or account numbers
+pub struct PiiRedactor {
+    min_digits: usize, // Digits a number needs to be redacted
+}
+
+impl PiiRedactor {
+    /// Creates a new PiiRedactor redacting numbers of 7 or more digits.
+    pub fn new() -> Self {
+        Self { min_digits: 7 }
+    }
+
+    /// Sets the digits a number needs to be redacted.
     /// # Arguments
-    /// * `value` - The value to add.
-    fn add(&mut self, value: T) -> Result<(), String> {
-        if let Some(check) = &self.type_constraint {
-            if !check(&value) {
-                return Err(format!("{} does not match type constraint", value));
+    /// * `min_digits` - The number of digits, ignoring separators.
+    pub fn with_min_digits(mut self, min_digits: usize) -> Self {
+        self.min_digits = min_digits;
+        self
+    }
+
+    /// Replaces words that look like email addresses with "[email]".
+    fn redact_emails(text: &str) -> String {
+        text.split(' ')
+            .map(|word| {
+                let trimmed = word.trim_end_matches(|c: char| c.is_ascii_punctuation());
+                let is_email = trimmed.split_once('@').is_some_and(|(local, domain)| {
+                    !local.is_empty()
+                        && domain.contains('.')
+                        && !domain.starts_with('.')
+                        && !domain.ends_with('.')
+                });
+                if is_email {
+                    format!("[email]{}", &word[trimmed.len()..])
+                } else {
+                    word.to_string()
+                }
+            })
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    /// Replaces numbers with enough digits, possibly written with spaces,
+    /// dashes or parentheses, with "[number]".
+    fn redact_numbers(&self, text: &str) -> String {
+        let chars: Vec<char> = text.chars().collect();
+        let mut result = String::new();
+        let mut i = 0;
+        while i < chars.len() {
+            if !(chars[i].is_ascii_digit() || chars[i] == '+') {
+                result.push(chars[i]);
+                i += 1;
+                continue;
+            }
+            let mut end = i;
+            while end < chars.len() && (chars[end].is_ascii_digit() || " -()+".contains(chars[end])) {
+                end += 1;
             }
+            while end > i && !chars[end - 1].is_ascii_digit() {
+                end -= 1;
+            }
+            let digits = chars[i..end].iter().filter(|c| c.is_ascii_digit()).count();
+            if digits >= self.min_digits {
+                result.push_str("[number]");
+            } else {
+                result.extend(&chars[i..end.max(i + 1)]);
+            }
+            i = end.max(i + 1);
         }
-        self.elements.insert(value);
-        Ok(())
+        result
     }
+}
 
-    /// Clears all elements from the TSet.
-    fn clear(&mut self) {
-        self.elements.clear();
+impl Default for PiiRedactor {
+    fn default() -> Self {
+        Self::new()
     }
+}
 
-    /// Returns the number of elements in the TSet.
-    fn len(&self) -> usize {
-        self.elements.len()
+impl TextFilter for PiiRedactor {
+    fn filter(&self, text: &str) -> String {
+        self.redact_numbers(&Self::redact_emails(text))
     }
+}
 
-    /// Checks if a value is present in the TSet.
-    /// # Arguments
-    /// * `value` - The value to check.
-    fn contains(&self, value: &T) -> bool {
-        self.elements.contains(value)
-    }
+// Tokenizers
+
+/// Trait for tokenizers splitting user input into tokens. The controller
+/// passes the tokens, joined by single spaces, to whichever grammar
+/// interprets the input, so that all grammars see input the same way
+pub trait Tokenizer {
+    /// Returns the tokens of the text
+    fn tokenize(&self, text: &str) -> Vec<String>;
 }
 
-/// Formats the TSet for display as a comma-separated list of elements.
-impl<T: Clone + PartialEq + Eq + Hash + fmt::Display> fmt::Display for TSet<T> {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let elements: Vec<String> = self.elements.iter().map(|e| e.to_string()).collect();
-        write!(f, "{{{}}}", elements.join(", "))
+/// Any function from text to tokens can be used as a tokenizer
+impl<F> Tokenizer for F
+where
+    F: Fn(&str) -> Vec<String>,
+{
+    fn tokenize(&self, text: &str) -> Vec<String> {
+        self(text)
     }
 }
 
-// Enum creation macro
+/// Tokenizer splitting text on whitespace, leaving punctuation attached to
+/// the words. This is the default, and keeps formal input such as
+/// "?x.price(x)" intact
+pub struct WhitespaceTokenizer;
 
-/// Macro to create an enum with string parsing and display capabilities.
-/// # Arguments
-/// * `$name` - The name of the enum.
-/// * `$($variant),+` - The variants of the enum.
-macro_rules! create_enum {
-    ($name:ident, $($variant:ident),+) => {
-        /// An enumeration with named variants.
-        #[derive(Clone, PartialEq, Eq, Debug, Hash)]
-        enum $name {
-            $($variant),+
-        }
+impl Tokenizer for WhitespaceTokenizer {
+    fn tokenize(&self, text: &str) -> Vec<String> {
+        text.split_whitespace().map(str::to_string).collect()
+    }
+}
 
-        impl $name {
-            /// Creates an enum variant from a string name.
-            /// # Arguments
-            /// * `name` - The string name of the variant.
-            fn new(name: &str) -> Option<Self> {
-                match name {
-                    $(stringify!($variant) => Some($name::$variant),)+
-                    _ => None,
-                }
-            }
-        }
+/// Tokenizer splitting text on whitespace and stripping the punctuation
+/// around words, so that "Paris, please!" becomes "Paris" and "please".
+/// Punctuation inside words and a leading question mark are kept, so formal
+/// input such as "?x.price(x)" and "o'clock" stay intact
+pub struct PunctuationTokenizer;
 
-        /// Formats the enum for display.
-        impl fmt::Display for $name {
-            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-                match self {
-                    $($name::$variant => write!(f, "{}", stringify!($variant)),)+
-                }
-            }
-        }
-    };
+impl Tokenizer for PunctuationTokenizer {
+    fn tokenize(&self, text: &str) -> Vec<String> {
+        text.split_whitespace()
+            .map(|word| {
+                word.trim_start_matches(|c: char| ",;:!.\"¡¿".contains(c))
+                    .trim_end_matches(|c: char| ",;:!.?\"".contains(c))
+            })
+            .filter(|word| !word.is_empty())
+            .map(str::to_string)
+            .collect()
+    }
 }
 
-// Define Speaker and ProgramState enums
-create_enum!(Speaker, USR, SYS);
-create_enum!(ProgramState, RUN, QUIT);
-
-// Semantic types
+/// Tokenizer splitting text into words at the Unicode word boundaries
+/// (UAX #29) and dropping the punctuation between them. Formal input such as
+/// "?x.price(x)" is not kept intact
+#[cfg(feature = "unicode")]
+pub struct UnicodeTokenizer;
 
-/// Trait for types that can be type-checked within a Domain.
-trait Type: fmt::Display {
-    /// Checks if the type is valid within the given Domain context.
-    /// # Arguments
-    /// * `context` - The Domain to check against.
-    fn typecheck(&self, context: &Domain) -> Result<(), String>;
+#[cfg(feature = "unicode")]
+impl Tokenizer for UnicodeTokenizer {
+    fn tokenize(&self, text: &str) -> Vec<String> {
+        use unicode_segmentation::UnicodeSegmentation;
+        text.unicode_words().map(str::to_string).collect()
+    }
 }
 
-/// Represents an atomic string with validation rules.
-#[derive(Clone, PartialEq, Eq, Hash)]
-struct Atomic {
-    content: String, // The atomic string value
+// Channel-based handlers
+
+/// Input handler receiving user input from another thread over a channel
+pub struct ChannelInputHandler {
+    receiver: mpsc::Receiver<String>, // Source of user input
+    closed: bool, // Whether all senders have hung up
 }
 
-/// Implementation of methods for the Atomic struct.
-impl Atomic {
-    /// Creates a new Atomic value with validation.
+impl ChannelInputHandler {
+    /// Creates a new ChannelInputHandler reading from a receiver.
     /// # Arguments
-    /// * `atom` - The string to validate and store.
-    fn new(atom: &str) -> Result<Self, String> {
-        if atom.is_empty() || atom == "yes" || atom == "no" {
-            return Err("Invalid atom".to_string());
-        }
-        if !atom.chars().next().unwrap_or(' ').is_alphabetic() {
-            return Err("Atom must start with a letter".to_string());
-        }
-        if !atom.chars().all(|c| c.is_alphanumeric() || c == '_' || c == '-' || c == '+' || c == ':') {
-            return Err("Invalid characters in atom".to_string());
-        }
-        Ok(Atomic { content: atom.to_string() })
+    /// * `receiver` - The receiving end of the input channel.
+    pub fn new(receiver: mpsc::Receiver<String>) -> Self {
+        Self { receiver, closed: false }
     }
-}
 
-/// Formats the Atomic value for display.
-impl fmt::Display for Atomic {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}", self.content)
+    /// Creates a new input channel, returning the sender for the host application.
+    pub fn channel() -> (mpsc::Sender<String>, Self) {
+        let (sender, receiver) = mpsc::channel();
+        (sender, Self::new(receiver))
     }
 }
 
-/// Represents an individual in the domain, wrapping an Atomic value.
-#[derive(Clone, PartialEq, Eq, Hash)]
-struct Ind(Atomic);
+impl InputHandler for ChannelInputHandler {
+    fn read_line(&mut self) -> Option<String> {
+        let input = self.receiver.recv().ok();
+        self.closed = input.is_none();
+        input
+    }
 
-/// Implementation of methods for the Ind struct.
-impl Ind {
-    /// Creates a new Ind from a string.
-    /// # Arguments
-    /// * `atom` - The string to create an Atomic value from.
-    fn new(atom: &str) -> Result<Self, String> {
-        Ok(Ind(Atomic::new(atom)?))
+    fn has_input(&self) -> bool {
+        !self.closed // Blocks in read_line until input arrives or the channel closes
     }
-}
 
-/// Implements type checking for Ind against a Domain.
-impl Type for Ind {
-    fn typecheck(&self, context: &Domain) -> Result<(), String> {
-        if context.inds.contains_key(&self.0.content) {
-            Ok(())
-        } else {
-            Err(format!("{} not in context individuals", self.0.content))
+    fn read_line_timeout(&mut self, timeout: Duration) -> InputEvent {
+        match self.receiver.recv_timeout(timeout) {
+            Ok(line) => InputEvent::Line(line),
+            Err(mpsc::RecvTimeoutError::Timeout) => InputEvent::Silence,
+            Err(mpsc::RecvTimeoutError::Disconnected) => {
+                self.closed = true;
+                InputEvent::Closed
+            }
         }
     }
 }
 
-/// Formats the Ind for display.
-impl fmt::Display for Ind {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}", self.0)
-    }
+/// Output handler sending system utterances to another thread over a channel
+pub struct ChannelOutputHandler {
+    sender: mpsc::Sender<String>, // Destination of system utterances
 }
 
-/// Represents a zero-place predicate.
-#[derive(Clone, PartialEq, Eq, Hash)]
-struct Pred0(Atomic);
-
-/// Implementation of methods for the Pred0 struct.
-impl Pred0 {
-    /// Creates a new Pred0 from a string.
+impl ChannelOutputHandler {
+    /// Creates a new ChannelOutputHandler writing to a sender.
     /// # Arguments
-    /// * `atom` - The string to create an Atomic value from.
-    fn new(atom: &str) -> Result<Self, String> {
-        Ok(Pred0(Atomic::new(atom)?))
+    /// * `sender` - The sending end of the output channel.
+    pub fn new(sender: mpsc::Sender<String>) -> Self {
+        Self { sender }
     }
-}
 
-/// Implements type checking for Pred0 against a Domain.
-impl Type for Pred0 {
-    fn typecheck(&self, context: &Domain) -> Result<(), String> {
-        if context.preds0.contains(&self.0.content) {
-            Ok(())
-        } else {
-            Err(format!("{} not in context 0-place predicates", self.0.content))
-        }
+    /// Creates a new output channel, returning the receiver for the host application.
+    pub fn channel() -> (Self, mpsc::Receiver<String>) {
+        let (sender, receiver) = mpsc::channel();
+        (Self::new(sender), receiver)
     }
 }
 
-/// Formats the Pred0 for display.
-impl fmt::Display for Pred0 {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}", self.0)
+impl OutputHandler for ChannelOutputHandler {
+    fn write_output(&mut self, output: &str) {
+        // A host that stopped listening is not an error for the dialogue
+        self.sender.send(output.to_string()).ok();
     }
 }
 
-/// Represents a one-place predicate.
-#[derive(Clone, PartialEq, Eq, Hash)]
-struct Pred1(Atomic);
+/// A part of a streamed utterance.
+#[derive(Clone, PartialEq, Debug)]
+pub enum OutputChunk {
+    Sentence(String), // The next sentence of the utterance
+    End, // The utterance is complete
+}
 
-/// Implementation of methods for the Pred1 struct.
-impl Pred1 {
-    /// Creates a new Pred1 from a string.
+/// Output handler streaming system utterances sentence by sentence to another
+/// thread over a bounded channel. When the host falls behind by more sentences
+/// than the channel holds, the dialogue waits for it to catch up.
+pub struct StreamingOutputHandler {
+    sender: mpsc::SyncSender<OutputChunk>, // Destination of the sentences
+}
+
+impl StreamingOutputHandler {
+    /// Creates a new StreamingOutputHandler writing to a sender.
     /// # Arguments
-    /// * `atom` - The string to create an Atomic value from.
-    fn new(atom: &str) -> Result<Self, String> {
-        Ok(Pred1(Atomic::new(atom)?))
+    /// * `sender` - The sending end of a bounded output channel.
+    pub fn new(sender: mpsc::SyncSender<OutputChunk>) -> Self {
+        Self { sender }
     }
 
-    /// Applies the predicate to an individual to create a proposition.
+    /// Creates a new bounded output channel, returning the receiver for the host application.
     /// # Arguments
-    /// * `ind` - The individual to apply the predicate to.
-    fn apply(&self, ind: &Ind) -> Result<Prop, Box<dyn std::error::Error>> {
-        Ok(Prop {
-            pred: Pred0::new(&self.0.content)?,
-            ind: Some(ind.clone()),
-            yes: true,
-        })
+    /// * `capacity` - The sentences the host may fall behind by.
+    pub fn channel(capacity: usize) -> (Self, mpsc::Receiver<OutputChunk>) {
+        let (sender, receiver) = mpsc::sync_channel(capacity);
+        (Self::new(sender), receiver)
     }
 }
 
-/// Implements type checking for Pred1 against a Domain.
-impl Type for Pred1 {
-    fn typecheck(&self, context: &Domain) -> Result<(), String> {
-        if context.preds1.contains_key(&self.0.content) {
-            Ok(())
-        } else {
-            Err(format!("{} not in context 1-place predicates", self.0.content))
-        }
+impl OutputHandler for StreamingOutputHandler {
+    fn write_output(&mut self, output: &str) {
+        self.write_chunk(output);
+        self.end_output();
     }
-}
 
-/// Formats the Pred1 for display.
-impl fmt::Display for Pred1 {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}", self.0)
+    fn write_chunk(&mut self, chunk: &str) {
+        // Blocks while the channel is full; a host that stopped listening is not an error
+        self.sender.send(OutputChunk::Sentence(chunk.to_string())).ok();
+    }
+
+    fn end_output(&mut self) {
+        self.sender.send(OutputChunk::End).ok();
     }
 }
 
-/// Represents a sort (category) for individuals, wrapping a Pred1.
-#[derive(Clone)]
-struct Sort(Pred1);
+/// Input handler receiving user input from an async task over a tokio channel.
+/// The dialogue itself runs synchronously, e.g. inside `spawn_blocking`.
+#[cfg(feature = "async")]
+pub struct TokioChannelInputHandler {
+    receiver: tokio::sync::mpsc::UnboundedReceiver<String>, // Source of user input
+}
 
-/// Implementation of methods for the Sort struct.
-impl Sort {
-    /// Creates a new Sort from a string.
+#[cfg(feature = "async")]
+impl TokioChannelInputHandler {
+    /// Creates a new TokioChannelInputHandler reading from a receiver.
     /// # Arguments
-    /// * `atom` - The string to create a Pred1 from.
-    fn new(atom: &str) -> Result<Self, String> {
-        Ok(Sort(Pred1::new(atom)?))
+    /// * `receiver` - The receiving end of the input channel.
+    pub fn new(receiver: tokio::sync::mpsc::UnboundedReceiver<String>) -> Self {
+        Self { receiver }
     }
 }
 
-/// Implements type checking for Sort against a Domain.
-impl Type for Sort {
-    fn typecheck(&self, context: &Domain) -> Result<(), String> {
-        if context.sorts.contains_key(&self.0 .0.content) {
-            Ok(())
-        } else {
-            Err(format!("{} not in context sorts", self.0 .0.content))
-        }
+#[cfg(feature = "async")]
+impl InputHandler for TokioChannelInputHandler {
+    fn read_line(&mut self) -> Option<String> {
+        self.receiver.blocking_recv()
     }
-}
 
-/// Formats the Sort for display.
-impl fmt::Display for Sort {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}", self.0)
+    fn has_input(&self) -> bool {
+        !self.receiver.is_closed() || !self.receiver.is_empty()
     }
 }
 
-/// Represents a proposition, combining a predicate with an optional individual and polarity.
-#[derive(Clone, PartialEq, Eq, Hash)]
-struct Prop {
-    pred: Pred0, // The predicate
-    ind: Option<Ind>, // Optional individual
-    yes: bool, // Polarity (true for positive, false for negative)
+/// Output handler sending system utterances to an async task over a tokio channel
+#[cfg(feature = "async")]
+pub struct TokioChannelOutputHandler {
+    sender: tokio::sync::mpsc::UnboundedSender<String>, // Destination of system utterances
 }
 
-/// Implementation of methods for the Prop struct.
-impl Prop {
-    /// Creates a new Prop from a string, parsing polarity and arguments.
+#[cfg(feature = "async")]
+impl TokioChannelOutputHandler {
+    /// Creates a new TokioChannelOutputHandler writing to a sender.
     /// # Arguments
-    /// * `s` - The string to parse (e.g., "pred(ind)" or "-pred").
-    fn new(s: &str) -> Result<Self, String> {
-        let (yes, pred_str, ind_str) = if s.starts_with('-') {
-            (false, &s[1..], None::<&str>)
-        } else {
-            (true, s, None)
-        };
-        let (pred_str, ind_str) = if pred_str.ends_with(')') {
-            let parts: Vec<&str> = pred_str[..pred_str.len() - 1].split('(').collect();
-            if parts.len() == 2 {
-                (parts[0], Some(parts[1]))
-            } else {
-                (pred_str, None)
-            }
-        } else {
-            (pred_str, None)
-        };
-        let pred = if ind_str.is_some() {
-            Pred0::new(pred_str)? // Simplified: assuming Pred0 for now
-        } else {
-            Pred0::new(pred_str)?
-        };
-        let ind = ind_str.map(|s| Ind::new(s).unwrap());
-        Ok(Prop { pred, ind, yes })
+    /// * `sender` - The sending end of the output channel.
+    pub fn new(sender: tokio::sync::mpsc::UnboundedSender<String>) -> Self {
+        Self { sender }
     }
 }
 
-/// Implements type checking for Prop against a Domain.
-impl Type for Prop {
-    fn typecheck(&self, context: &Domain) -> Result<(), String> {
-        self.pred.typecheck(context)?;
-        if let Some(ind) = &self.ind {
-            ind.typecheck(context)?;
-            if let Some(sort) = context.preds1.get(&self.pred.0.content) {
-                if context.inds.get(&ind.0.content) != Some(sort) {
-                    return Err("Sort mismatch".to_string());
-                }
-            }
-        }
-        Ok(())
+#[cfg(feature = "async")]
+impl OutputHandler for TokioChannelOutputHandler {
+    fn write_output(&mut self, output: &str) {
+        self.sender.send(output.to_string()).ok();
     }
 }
 
-/// Formats the Prop for display, including polarity and arguments.
-impl fmt::Display for Prop {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let prefix = if self.yes { "" } else { "-" };
-        let ind_str = self.ind.as_ref().map_or("", |ind| &ind.0.content);
-        write!(f, "{}{}({})", prefix, self.pred, ind_str)
-    }
-}
+// Helper functions
 
-/// Represents a short answer (e.g., "paris" or "-paris").
-#[derive(Clone)]
-struct ShortAns {
-    ind: Ind, // The individual
-    yes: bool, // Polarity
-}
+/// A shared type checking function used by the typed containers.
+/// Sharing it lets clones of a container keep its type constraint.
+type TypeCheck<T> = Rc<dyn Fn(&T) -> bool>;
 
-/// Implementation of methods for the ShortAns struct.
-impl ShortAns {
-    /// Creates a new ShortAns from a string, parsing polarity.
-    /// # Arguments
-    /// * `s` - The string to parse.
-    fn new(s: &str) -> Result<Self, String> {
-        let (yes, ind_str) = if s.starts_with('-') {
-            (false, &s[1..])
-        } else {
-            (true, s)
-        };
-        Ok(ShortAns {
-            ind: Ind::new(ind_str)?,
-            yes,
-        })
-    }
+/// A validator of the individuals of a sort, shared by the sessions of a domain.
+type SortValidator = Arc<dyn Fn(&str) -> bool + Send + Sync>;
+
+/// A shared observer called with the old and the new value when a Value changes.
+type Observer<T> = Rc<dyn Fn(Option<&T>, Option<&T>)>;
+
+/// Removes one pair of surrounding single quotes from a string, if present.
+/// # Arguments
+/// * `s` - The string to unquote.
+fn unquote(s: &str) -> &str {
+    let s = s.trim();
+    s.strip_prefix('\'')
+        .and_then(|inner| inner.strip_suffix('\''))
+        .unwrap_or(s)
 }
 
-/// Implements type checking for ShortAns against a Domain.
-impl Type for ShortAns {
-    fn typecheck(&self, context: &Domain) -> Result<(), String> {
-        self.ind.typecheck(context)
-    }
+/// Returns the raw argument list of a construct such as "Name(args)".
+/// # Arguments
+/// * `s` - The construct string.
+/// * `name` - The expected construct name.
+fn construct_body<'a>(s: &'a str, name: &str) -> Option<&'a str> {
+    s.trim().strip_prefix(name)?.strip_prefix('(')?.strip_suffix(')')
 }
 
-/// Formats the ShortAns for display.
-impl fmt::Display for ShortAns {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let prefix = if self.yes { "" } else { "-" };
-        write!(f, "{}{}", prefix, self.ind)
-    }
+/// Returns the single, unquoted argument of a construct such as "Name('arg')".
+/// # Arguments
+/// * `s` - The construct string.
+/// * `name` - The expected construct name.
+fn construct_arg<'a>(s: &'a str, name: &str) -> Option<&'a str> {
+    construct_body(s, name).map(unquote)
 }
 
-/// Represents a yes/no answer.
-#[derive(Clone)]
-struct YesNo {
-    yes: bool, // True for "yes", false for "no"
+/// Returns the items of a bracketed list such as "[a, b]".
+/// # Arguments
+/// * `s` - The list string.
+fn list_items(s: &str) -> Result<Vec<&str>, String> {
+    let items = s
+        .trim()
+        .strip_prefix('[')
+        .and_then(|a| a.strip_suffix(']'))
+        .ok_or(format!("Expected a list: {}", s))?;
+    Ok(split_top_level(items))
 }
 
-/// Implementation of methods for the YesNo struct.
-impl YesNo {
-    /// Creates a new YesNo from a string.
-    /// # Arguments
-    /// * `s` - The string ("yes" or "no").
-    fn new(s: &str) -> Result<Self, String> {
-        match s {
-            "yes" => Ok(YesNo { yes: true }),
-            "no" => Ok(YesNo { yes: false }),
-            _ => Err(format!("Invalid YesNo: {}", s)),
+/// Splits a string on commas that are not nested in brackets or parentheses.
+/// # Arguments
+/// * `s` - The string to split.
+fn split_top_level(s: &str) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut depth = 0;
+    let mut start = 0;
+    for (i, c) in s.char_indices() {
+        match c {
+            '(' | '[' => depth += 1,
+            ')' | ']' => depth -= 1,
+            ',' if depth == 0 => {
+                parts.push(s[start..i].trim());
+                start = i + 1;
+            }
+            _ => {}
         }
     }
-}
-
-/// Implements type checking for YesNo (always valid).
-impl Type for YesNo {
-    fn typecheck(&self, _context: &Domain) -> Result<(), String> {
-        Ok(())
+    let last = s[start..].trim();
+    if !last.is_empty() || !parts.is_empty() {
+        parts.push(last);
     }
+    parts
 }
 
-/// Formats the YesNo for display.
-impl fmt::Display for YesNo {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}", if self.yes { "yes" } else { "no" })
+/// Splits a string at an operator outside parentheses and brackets.
+/// # Arguments
+/// * `s` - The string to split (e.g., "a(x) & (b() | c())").
+/// * `op` - The operator (e.g., '&').
+fn split_operator(s: &str, op: char) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut depth = 0;
+    let mut start = 0;
+    for (i, c) in s.char_indices() {
+        match c {
+            '(' | '[' => depth += 1,
+            ')' | ']' => depth -= 1,
+            c if c == op && depth == 0 => {
+                parts.push(s[start..i].trim());
+                start = i + c.len_utf8();
+            }
+            _ => {}
+        }
     }
+    parts.push(s[start..].trim());
+    parts
 }
 
-/// Enum representing different types of answers.
-#[derive(Clone)]
-enum Ans {
-    Prop(Prop), // A proposition
-    ShortAns(ShortAns), // A short answer
-    YesNo(YesNo), // A yes/no answer
+/// Returns the 64-bit FNV-1a hash of a string, which is the same on every
+/// platform and release, unlike the hashers of the standard library.
+/// # Arguments
+/// * `s` - The string.
+fn fnv1a(s: &str) -> u64 {
+    s.bytes().fold(0xcbf29ce484222325u64, |hash, byte| (hash ^ byte as u64).wrapping_mul(0x100000001b3))
 }
 
-/// Implementation of methods for the Ans enum.
-impl Ans {
-    /// Creates a new Ans from a string, parsing the appropriate type.
-    /// # Arguments
-    /// * `s` - The string to parse.
-    fn new(s: &str) -> Result<Self, String> {
-        if s == "yes" || s == "no" {
-            Ok(Ans::YesNo(YesNo::new(s)?))
-        } else if !s.contains('(') && !s.contains(')') {
-            Ok(Ans::ShortAns(ShortAns::new(s)?))
-        } else if s.contains('(') && s.ends_with(')') {
-            Ok(Ans::Prop(Prop::new(s)?))
-        } else {
-            Err(format!("Could not parse answer: {}", s))
+/// Checks if a string matches a pattern in which "*" stands for any text.
+/// # Arguments
+/// * `pattern` - The pattern (e.g., "Answer(price(*))").
+/// * `s` - The string (e.g., "Answer(price(232))").
+fn glob_match(pattern: &str, s: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let Some(mut rest) = s.strip_prefix(first) else {
+        return false;
+    };
+    let parts: Vec<&str> = parts.collect();
+    let Some((last, middle)) = parts.split_last() else {
+        return rest.is_empty();
+    };
+    for part in middle {
+        match rest.find(part) {
+            Some(i) => rest = &rest[i + part.len()..],
+            None => return false,
         }
     }
+    rest.len() >= last.len() && rest.ends_with(last)
 }
 
-/// Implements type checking for Ans against a Domain.
-impl Type for Ans {
-    fn typecheck(&self, context: &Domain) -> Result<(), String> {
-        match self {
-            Ans::Prop(p) => p.typecheck(context),
-            Ans::ShortAns(s) => s.typecheck(context),
-            Ans::YesNo(y) => y.typecheck(context),
+/// Renames the predicates applied in a string form (e.g., "Findout('?x.temp(x)')"),
+/// leaving individuals and plan constructors untouched.
+/// # Arguments
+/// * `s` - The string form of a proposition, question or plan construct.
+/// * `names` - The new name of each renamed predicate.
+fn rename_preds(s: &str, names: &HashMap<String, String>) -> String {
+    let mut out = String::new();
+    let mut rest = s;
+    while let Some(start) = rest.find(|c: char| c.is_alphabetic()) {
+        out.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let end = rest
+            .find(|c: char| !(c.is_alphanumeric() || c == '_' || c == '-' || c == '+'))
+            .unwrap_or(rest.len());
+        let word = &rest[..end];
+        match names.get(word) {
+            Some(name) if rest[end..].starts_with('(') => out.push_str(name),
+            _ => out.push_str(word),
         }
+        rest = &rest[end..];
     }
+    out.push_str(rest);
+    out
 }
 
-/// Formats the Ans for display.
-impl fmt::Display for Ans {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match self {
-            Ans::Prop(p) => write!(f, "{}", p),
-            Ans::ShortAns(s) => write!(f, "{}", s),
-            Ans::YesNo(y) => write!(f, "{}", y),
-        }
-    }
-}
+// Random numbers
 
-/// Represents a "wh" question (e.g., "?x.pred(x)").
-#[derive(Clone)]
-struct WhQ {
-    pred: Pred1, // The predicate
+/// A small seedable pseudo-random number generator (SplitMix64). The
+/// controller owns one and passes it to the parts of the system that need
+/// randomness, so a whole dialogue can be replayed from its seed.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Rng {
+    seed: u64, // Seed the generator started from
+    state: u64, // Current state
 }
 
-/// Implementation of methods for the WhQ struct.
-impl WhQ {
-    /// Creates a new WhQ from a string, parsing the predicate.
+impl Rng {
+    /// Creates a new Rng from a seed.
     /// # Arguments
-    /// * `pred` - The predicate string (e.g., "?x.pred(x)" or "pred").
-    fn new(pred: &str) -> Result<Self, String> {
-        let pred = if pred.starts_with("?x.") && pred.ends_with("(x)") {
-            &pred[3..pred.len() - 3]
-        } else {
-            pred
-        };
-        Ok(WhQ {
-            pred: Pred1::new(pred)?,
-        })
+    /// * `seed` - The seed; equal seeds give equal sequences.
+    pub fn new(seed: u64) -> Self {
+        Self { seed, state: seed }
     }
-}
 
-/// Implements type checking for WhQ against a Domain.
-impl Type for WhQ {
-    fn typecheck(&self, context: &Domain) -> Result<(), String> {
-        self.pred.typecheck(context)
+    /// Creates a new Rng seeded from the clock.
+    pub fn from_time() -> Self {
+        let nanos = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |d| d.as_nanos() as u64);
+        Self::new(nanos)
     }
-}
 
-/// Formats the WhQ for display.
-impl fmt::Display for WhQ {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "?x.{} (x)", self.pred)
+    /// Returns the seed the generator started from.
+    pub fn seed(&self) -> u64 {
+        self.seed
     }
-}
 
-/// Represents a yes/no question.
-#[derive(Clone)]
-struct YNQ {
-    prop: Prop, // The proposition
-}
+    /// Restarts the sequence from the seed.
+    pub fn reseed(&mut self) {
+        self.state = self.seed;
+    }
 
-/// Implementation of methods for the YNQ struct.
-impl YNQ {
-    /// Creates a new YNQ from a string.
+    /// Returns the next number of the sequence.
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+
+    /// Returns a number below a bound, or 0 if the bound is 0.
     /// # Arguments
-    /// * `prop` - The proposition string (e.g., "?pred(ind)").
-    fn new(prop: &str) -> Result<Self, String> {
-        let prop = if prop.starts_with('?') { &prop[1..] } else { prop };
-        Ok(YNQ {
-            prop: Prop::new(prop)?,
-        })
+    /// * `bound` - The exclusive upper bound.
+    pub fn below(&mut self, bound: usize) -> usize {
+        if bound == 0 {
+            return 0;
+        }
+        (self.next_u64() % bound as u64) as usize
     }
-}
 
-/// Implements type checking for YNQ against a Domain.
-impl Type for YNQ {
-    fn typecheck(&self, context: &Domain) -> Result<(), String> {
-        self.prop.typecheck(context)
+    /// Returns true with a probability.
+    /// # Arguments
+    /// * `probability` - The probability, between 0 and 1.
+    pub fn chance(&mut self, probability: f64) -> bool {
+        ((self.next_u64() >> 11) as f64 / (1u64 << 53) as f64) < probability
     }
-}
 
-/// Formats the YNQ for display.
-impl fmt::Display for YNQ {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "?{}", self.prop)
+    /// Returns a random element of a slice, or None if it is empty.
+    /// # Arguments
+    /// * `items` - The elements to choose from.
+    pub fn choose<'a, T>(&mut self, items: &'a [T]) -> Option<&'a T> {
+        if items.is_empty() {
+            return None;
+        }
+        items.get(self.below(items.len()))
     }
 }
 
-/// Represents an alternative question (multiple yes/no questions).
-#[derive(Clone)]
-struct AltQ {
-    ynqs: Vec<YNQ>, // List of yes/no questions
+// Value struct
+
+/// A generic container for values with constraints on allowed values or type checks.
+/// Represents a single value with optional validation rules.
+pub struct Value<T: Clone + PartialEq + Eq + Hash> {
+    value: Option<T>, // The stored value, if any
+    allowed_values: HashSet<T>, // Set of permitted values
+    type_constraint: Option<TypeCheck<T>>, // Optional type checking function
+    observer: Option<Observer<T>>, // Optional change observer
 }
 
-/// Implementation of methods for the AltQ struct.
-impl AltQ {
-    /// Creates a new AltQ from a list of YNQs.
-    /// # Arguments
-    /// * `ynqs` - The list of yes/no questions.
-    fn new(ynqs: Vec<YNQ>) -> Self {
-        AltQ { ynqs }
+impl<T: Clone + PartialEq + Eq + Hash> Clone for Value<T> {
+    fn clone(&self) -> Self {
+        Value {
+            value: self.value.clone(),
+            allowed_values: self.allowed_values.clone(),
+            type_constraint: self.type_constraint.clone(),
+            observer: self.observer.clone(),
+        }
     }
 }
 
-/// Implements type checking for AltQ against a Domain.
-impl Type for AltQ {
-    fn typecheck(&self, context: &Domain) -> Result<(), String> {
-        for ynq in &self.ynqs {
-            ynq.typecheck(context)?;
+/// Implementation of methods for the Value struct.
+impl<T: Clone + PartialEq + Eq + Hash> Value<T> {
+    /// Creates a new Value with a set of allowed values.
+    /// # Arguments
+    /// * `allowed` - A HashSet of permitted values.
+    pub fn new_allowed(allowed: HashSet<T>) -> Self {
+        Value {
+            value: None,
+            allowed_values: allowed,
+            type_constraint: None,
+            observer: None,
+        }
+    }
+
+    /// Creates a new Value with a type constraint function.
+    /// # Arguments
+    /// * `type_check` - A function to validate the type of values.
+    pub fn new_type<F>(type_check: F) -> Self
+    where
+        F: Fn(&T) -> bool + 'static,
+    {
+        Value {
+            value: None,
+            allowed_values: HashSet::new(),
+            type_constraint: Some(Rc::new(type_check)),
+            observer: None,
+        }
+    }
+
+    /// Sets the value after validating against constraints.
+    /// Returns an error naming the value if it is not allowed or fails the type check.
+    /// # Arguments
+    /// * `value` - The value to set.
+    pub fn set(&mut self, value: T) -> Result<(), String>
+    where
+        T: fmt::Debug,
+    {
+        if !self.allowed_values.is_empty() && !self.allowed_values.contains(&value) {
+            return Err(format!("Value {:?} is not among allowed values", value));
         }
+        if let Some(check) = &self.type_constraint
+            && !check(&value)
+        {
+            return Err(format!("Value {:?} does not match type constraint", value));
+        }
+        self.replace(Some(value));
         Ok(())
     }
-}
 
-/// Formats the AltQ for display.
-impl fmt::Display for AltQ {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let ynq_strs: Vec<String> = self.ynqs.iter().map(|y| y.to_string()).collect();
-        write!(f, "{{ {} }}", ynq_strs.join(" | "))
+    /// Retrieves the stored value, if any.
+    pub fn get(&self) -> Option<&T> {
+        self.value.as_ref()
     }
-}
 
-/// Enum representing different types of questions.
-#[derive(Clone)]
-pub enum Question {
-    WhQ(WhQ), // Wh-question
-    YNQ(YNQ), // Yes/no question
-    AltQ(AltQ), // Alternative question
-}
+    /// Clears the stored value.
+    pub fn clear(&mut self) {
+        self.replace(None);
+    }
 
-/// Implementation of methods for the Question enum.
-impl Question {
-    /// Creates a new Question from a string.
+    /// Sets an observer called with the old and the new value whenever the
+    /// stored value changes. Setting an equal value does not notify it.
     /// # Arguments
-    /// * `s` - The string to parse.
-    pub fn new(s: &str) -> Result<Self, String> {
-        if s.starts_with("?x.") && s.ends_with("(x)") {
-            Ok(Question::WhQ(WhQ::new(&s[3..s.len() - 3])?))
-        } else if s.starts_with('?') {
-            Ok(Question::YNQ(YNQ::new(&s[1..])?))
-        } else {
-            Err(format!("Could not parse question: {}", s))
+    /// * `observer` - The function to call on changes.
+    pub fn on_change<F>(&mut self, observer: F)
+    where
+        F: Fn(Option<&T>, Option<&T>) + 'static,
+    {
+        self.observer = Some(Rc::new(observer));
+    }
+
+    /// Stores a new value and notifies the observer if it changed.
+    /// # Arguments
+    /// * `value` - The new value.
+    fn replace(&mut self, value: Option<T>) {
+        let old = std::mem::replace(&mut self.value, value);
+        if let Some(observer) = &self.observer
+            && old != self.value
+        {
+            observer(old.as_ref(), self.value.as_ref());
         }
     }
 }
 
-/// Implements type checking for Question against a Domain.
-impl Type for Question {
-    fn typecheck(&self, context: &Domain) -> Result<(), String> {
-        match self {
-            Question::WhQ(w) => w.typecheck(context),
-            Question::YNQ(y) => y.typecheck(context),
-            Question::AltQ(a) => a.typecheck(context),
+/// Formats the Value for display, showing the stored value or an empty marker.
+impl<T: Clone + PartialEq + Eq + Hash + fmt::Display> fmt::Display for Value<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.value {
+            Some(v) => write!(f, "<{}>", v),
+            None => write!(f, "<>"),
         }
     }
 }
 
-/// Formats the Question for display.
-impl fmt::Display for Question {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match self {
-            Question::WhQ(w) => write!(f, "{}", w),
-            Question::YNQ(y) => write!(f, "{}", y),
-            Question::AltQ(a) => write!(f, "{}", a),
-        }
+impl<T: Clone + PartialEq + Eq + Hash> Default for Value<T> {
+    fn default() -> Self {
+        Self::new_allowed(HashSet::new())
     }
 }
 
-// Dialogue moves
-
-/// Represents a greeting dialogue move.
-#[derive(Clone)]
-struct Greet;
-
-/// Implements type checking for Greet (always valid).
-impl Type for Greet {
-    fn typecheck(&self, _context: &Domain) -> Result<(), String> {
-        Ok(())
+/// Compares the stored values, ignoring constraints and observers.
+impl<T: Clone + PartialEq + Eq + Hash> PartialEq for Value<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.value == other.value
     }
 }
 
-/// Formats the Greet for display.
-impl fmt::Display for Greet {
+impl<T: Clone + PartialEq + Eq + Hash> Eq for Value<T> {}
+
+/// Formats the stored value for debugging, e.g. `Value(Some(3))`.
+impl<T: Clone + PartialEq + Eq + Hash + fmt::Debug> fmt::Debug for Value<T> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "Greet()")
+        f.debug_tuple("Value").field(&self.value).finish()
     }
 }
 
-/// Represents a quit dialogue move.
-#[derive(Clone)]
-struct Quit;
+/// Consumes the Value into an iterator over the stored value, if any.
+impl<T: Clone + PartialEq + Eq + Hash> IntoIterator for Value<T> {
+    type Item = T;
+    type IntoIter = std::option::IntoIter<T>;
 
-/// Implements type checking for Quit (always valid).
-impl Type for Quit {
-    fn typecheck(&self, _context: &Domain) -> Result<(), String> {
-        Ok(())
+    fn into_iter(self) -> Self::IntoIter {
+        self.value.into_iter()
     }
 }
 
-/// Formats the Quit for display.
-impl fmt::Display for Quit {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "Quit()")
+/// Iterates over the stored value of a borrowed Value, if any.
+impl<'a, T: Clone + PartialEq + Eq + Hash> IntoIterator for &'a Value<T> {
+    type Item = &'a T;
+    type IntoIter = std::option::Iter<'a, T>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.value.iter()
     }
 }
 
-/// Represents an ask dialogue move.
-#[derive(Clone)]
-struct Ask {
-    content: Question, // The question being asked
-}
+// Record struct
 
-/// Implementation of methods for the Ask struct.
-impl Ask {
-    /// Creates a new Ask move.
-    /// # Arguments
-    /// * `content` - The question to ask.
-    fn new(content: Question) -> Self {
-        Ask { content }
-    }
+/// A key-value store with type checking for fields.
+pub struct Record {
+    typedict: HashMap<String, TypeCheck<dyn Any>>, // Type checking functions for fields
+    fields: HashMap<String, Box<dyn Any>>, // Stored field values
 }
 
-/// Implements type checking for Ask against a Domain.
-impl Type for Ask {
-    fn typecheck(&self, context: &Domain) -> Result<(), String> {
-        self.content.typecheck(context)
+impl Clone for Record {
+    fn clone(&self) -> Self {
+        Record {
+            typedict: HashMap::new(), // Cannot clone function pointers
+            fields: HashMap::new(), // Cannot clone Any trait objects safely
+        }
     }
 }
 
-/// Formats the Ask for display.
-impl fmt::Display for Ask {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "Ask('{}')", self.content)
+/// Implementation of methods for the Record struct.
+impl Record {
+    /// Creates a new Record with initial fields and inferred type checks.
+    /// # Arguments
+    /// * `fields` - Initial key-value pairs.
+    pub fn new(fields: HashMap<String, Box<dyn Any>>) -> Self {
+        let mut typedict: HashMap<String, TypeCheck<dyn Any>> = HashMap::new();
+        for (key, value) in &fields {
+            let type_id = (**value).type_id();
+            typedict.insert(key.clone(), Rc::new(move |v: &dyn Any| v.type_id() == type_id) as TypeCheck<dyn Any>);
+        }
+        Record { typedict, fields }
     }
-}
 
-/// Represents an answer dialogue move.
-#[derive(Clone)]
-struct Answer {
-    content: Ans, // The answer content
-}
+    /// Returns a HashMap of field keys to their values.
+    pub fn as_dict(&self) -> HashMap<String, &dyn Any> {
+        self.fields.iter().map(|(k, v)| (k.clone(), v.as_ref())).collect()
+    }
 
-/// Implementation of methods for the Answer struct.
-impl Answer {
-    /// Creates a new Answer move.
+    /// Checks if a value matches the expected type for a given key.
     /// # Arguments
-    /// * `content` - The answer content.
-    fn new(content: Ans) -> Self {
-        Answer { content }
+    /// * `key` - The field key to check.
+    /// * `value` - Optional value to type check.
+    pub fn typecheck(&self, key: &str, value: Option<&dyn Any>) -> Result<(), String> {
+        if let Some(type_fn) = self.typedict.get(key) {
+            if let Some(val) = value
+                && !type_fn(val)
+            {
+                return Err(format!("{} is not of expected type", key));
+            }
+            Ok(())
+        } else {
+            Err(format!("{} is not a valid key", key))
+        }
     }
-}
 
-/// Implements type checking for Answer against a Domain.
-impl Type for Answer {
-    fn typecheck(&self, context: &Domain) -> Result<(), String> {
-        self.content.typecheck(context)
+    /// Retrieves a field value by key after type checking.
+    /// # Arguments
+    /// * `key` - The field key.
+    pub fn get(&self, key: &str) -> Option<&dyn Any> {
+        self.typecheck(key, None).ok()?;
+        self.fields.get(key).map(|v| v.as_ref())
     }
-}
 
-/// Formats the Answer for display.
-impl fmt::Display for Answer {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "Answer({})", self.content)
+    /// Sets a field value after type checking.
+    /// # Arguments
+    /// * `key` - The field key.
+    /// * `value` - The value to set.
+    pub fn set(&mut self, key: &str, value: Box<dyn Any>) -> Result<(), String> {
+        self.typecheck(key, Some(value.as_ref()))?;
+        self.fields.insert(key.to_string(), value);
+        Ok(())
     }
-}
 
-/// Represents an Information State Update Control Mechanism (ICM).
-#[derive(Clone)]
-struct ICM {
-    level: String, // The ICM level (e.g., "per" for perception)
-    polarity: String, // The polarity (e.g., "pos" or "neg")
-    icm_content: Option<String>, // Optional content for the ICM
-}
-
-/// Implementation of methods for the ICM struct.
-impl ICM {
-    /// Creates a new ICM.
+    /// Removes a field by key after type checking.
     /// # Arguments
-    /// * `level` - The ICM level.
-    /// * `polarity` - The polarity.
-    /// * `icm_content` - Optional content.
-    fn new(level: &str, polarity: &str, icm_content: Option<String>) -> Self {
-        ICM {
-            level: level.to_string(),
-            polarity: polarity.to_string(),
-            icm_content,
-        }
+    /// * `key` - The field key to remove.
+    pub fn delete(&mut self, key: &str) -> Result<(), String> {
+        self.typecheck(key, None)?;
+        self.fields.remove(key);
+        Ok(())
     }
-}
 
-/// Implements type checking for ICM (always valid).
-impl Type for ICM {
-    fn typecheck(&self, _context: &Domain) -> Result<(), String> {
-        Ok(())
+    /// Returns the field keys in alphabetical order.
+    pub fn keys(&self) -> Vec<&str> {
+        let mut keys: Vec<&str> = self.fields.keys().map(|k| k.as_str()).collect();
+        keys.sort();
+        keys
     }
-}
 
-/// Formats the ICM for display.
-impl fmt::Display for ICM {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let mut s = format!("icm:{}*{}", self.level, self.polarity);
-        if let Some(content) = &self.icm_content {
-            s.push_str(&format!(":'{}'", content));
-        }
-        write!(f, "{}", s)
+    /// Returns an iterator over the fields and their values in alphabetical order of the keys.
+    pub fn iter(&self) -> std::vec::IntoIter<(&str, &dyn Any)> {
+        let mut fields: Vec<(&str, &dyn Any)> = self.fields.iter().map(|(k, v)| (k.as_str(), v.as_ref())).collect();
+        fields.sort_by_key(|(k, _)| *k);
+        fields.into_iter()
     }
-}
 
-// Plan constructors
+    /// Retrieves a field value by key as a given type, e.g.
+    /// `record.get_as::<String>("name")`. Returns None if the field is
+    /// missing or of another type.
+    /// # Arguments
+    /// * `key` - The field key.
+    pub fn get_as<T: 'static>(&self, key: &str) -> Option<&T> {
+        self.get(key)?.downcast_ref()
+    }
 
-/// Represents a respond plan constructor.
-#[derive(Clone)]
-struct Respond {
-    content: Question, // The question to respond to
-}
+    /// Sets a field value of a given type after type checking, boxing it.
+    /// # Arguments
+    /// * `key` - The field key.
+    /// * `value` - The value to set.
+    pub fn set_checked<T: 'static>(&mut self, key: &str, value: T) -> Result<(), String> {
+        self.set(key, Box::new(value))
+    }
 
-/// Implementation of methods for the Respond struct.
-impl Respond {
-    /// Creates a new Respond plan.
+    /// Formats the Record as a string with a given prefix and indent, one
+    /// field per line. Nested Records are formatted on the following lines
+    /// with one more indent; values of types that cannot be displayed are
+    /// shown as `<opaque>`.
     /// # Arguments
-    /// * `content` - The question to respond to.
-    fn new(content: Question) -> Self {
-        Respond { content }
+    /// * `prefix` - Prefix for each line.
+    /// * `indent` - Indentation added for each level of nesting.
+    pub fn pformat(&self, prefix: &str, indent: &str) -> String {
+        let mut lines = Vec::new();
+        for (key, value) in self.iter() {
+            match value.downcast_ref::<Record>() {
+                Some(record) => {
+                    lines.push(format!("{}{}:", prefix, key));
+                    let nested = record.pformat(&format!("{}{}", prefix, indent), indent);
+                    if !nested.is_empty() {
+                        lines.push(nested);
+                    }
+                }
+                None => lines.push(format!("{}{}: {}", prefix, key, format_field(value))),
+            }
+        }
+        lines.join("\n")
     }
 }
 
-/// Implements type checking for Respond against a Domain.
-impl Type for Respond {
-    fn typecheck(&self, context: &Domain) -> Result<(), String> {
-        self.content.typecheck(context)
+/// Formats a field value of a Record if it is of a type known to be
+/// displayable: strings, numbers, booleans, Records, individuals,
+/// propositions, questions, moves and containers of them. Other values are
+/// shown as `<opaque>`.
+/// # Arguments
+/// * `value` - The field value.
+fn format_field(value: &dyn Any) -> String {
+    macro_rules! display {
+        ($($ty:ty),*) => {
+            $(
+                if let Some(value) = value.downcast_ref::<$ty>() {
+                    return value.to_string();
+                }
+            )*
+        };
+    }
+    macro_rules! display_containers {
+        ($($ty:ty),*) => {
+            display!($(Stack<$ty>, StackSet<$ty>, TSet<$ty>, Value<$ty>),*);
+        };
     }
+    display!(Record, String, &'static str, bool, char, i32, i64, u32, u64, usize, f64, Ind, Prop, Question, Move);
+    display_containers!(String, i32, i64, Ind, Prop);
+    display!(Stack<Question>, Stack<Move>);
+    "<opaque>".to_string()
 }
 
-/// Formats the Respond for display.
-impl fmt::Display for Respond {
+/// Formats the Record for display as a semicolon-separated list of key-value pairs.
+impl fmt::Display for Record {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "Respond('{}')", self.content)
+        let items: Vec<String> = self.iter().map(|(k, v)| format!("{} = {}", k, format_field(v))).collect();
+        write!(f, "{{{}}}", items.join("; "))
     }
 }
 
-/// Represents a consult database plan constructor.
-#[derive(Clone)]
-pub struct ConsultDB {
-    content: Question, // The question to consult
-}
+// Stack struct
 
-/// Implementation of methods for the ConsultDB struct.
-impl ConsultDB {
-    /// Creates a new ConsultDB plan.
-    /// # Arguments
-    /// * `content` - The question to consult.
-    pub fn new(content: Question) -> Self {
-        ConsultDB { content }
-    }
+/// A generic stack with optional type constraints.
+pub struct Stack<T: Clone> {
+    elements: Vec<T>, // The stack's elements
+    type_constraint: Option<TypeCheck<T>>, // Optional type checking function
 }
 
-/// Implements type checking for ConsultDB against a Domain.
-impl Type for ConsultDB {
-    fn typecheck(&self, context: &Domain) -> Result<(), String> {
-        self.content.typecheck(context)
+impl<T: Clone> Clone for Stack<T> {
+    fn clone(&self) -> Self {
+        Stack {
+            elements: self.elements.clone(),
+            type_constraint: self.type_constraint.clone(),
+        }
     }
 }
 
-/// Formats the ConsultDB for display.
-impl fmt::Display for ConsultDB {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "ConsultDB('{}')", self.content)
+/// Implementation of methods for the Stack struct.
+impl<T: Clone + PartialEq> Stack<T> {
+    /// Creates a new empty Stack.
+    pub fn new() -> Self {
+        Stack {
+            elements: Vec::new(),
+            type_constraint: None,
+        }
     }
-}
 
-/// Represents a findout plan constructor.
-#[derive(Clone)]
-pub struct Findout {
-    content: Question, // The question to find out
-}
-
-/// Implementation of methods for the Findout struct.
-impl Findout {
-    /// Creates a new Findout plan.
+    /// Creates a new Stack with a type constraint.
     /// # Arguments
-    /// * `content` - The question to find out.
-    pub fn new(content: Question) -> Self {
-        Findout { content }
+    /// * `type_check` - A function to validate pushed values.
+    pub fn with_type<F>(type_check: F) -> Self
+    where
+        F: Fn(&T) -> bool + 'static,
+    {
+        Stack {
+            elements: Vec::new(),
+            type_constraint: Some(Rc::new(type_check)),
+        }
     }
-}
 
-/// Implements type checking for Findout against a Domain.
-impl Type for Findout {
-    fn typecheck(&self, context: &Domain) -> Result<(), String> {
-        self.content.typecheck(context)
+    /// Returns a reference to the top element of the stack.
+    pub fn top(&self) -> Result<&T, String> {
+        self.elements.last().ok_or("Stack is empty".to_string())
     }
-}
 
-/// Formats the Findout for display.
-impl fmt::Display for Findout {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "Findout('{}')", self.content)
+    /// Removes and returns the top element of the stack.
+    pub fn pop(&mut self) -> Result<T, String> {
+        self.elements.pop().ok_or("Stack is empty".to_string())
     }
-}
 
-/// Represents a raise plan constructor.
-#[derive(Clone)]
-struct Raise {
-    content: Question, // The question to raise
-}
+    /// Pushes a value onto the stack after type checking.
+    /// # Arguments
+    /// * `value` - The value to push.
+    pub fn push(&mut self, value: T) -> Result<(), String>
+    where
+        T: fmt::Debug,
+    {
+        if let Some(check) = &self.type_constraint
+            && !check(&value)
+        {
+            return Err(format!("Value {:?} does not match type constraint", value));
+        }
+        self.elements.push(value);
+        Ok(())
+    }
 
-/// Implementation of methods for the Raise struct.
-impl Raise {
-    /// Creates a new Raise plan.
+    /// Clears all elements from the stack.
+    pub fn clear(&mut self) {
+        self.elements.clear();
+    }
+
+    /// Returns the number of elements in the stack.
+    pub fn len(&self) -> usize {
+        self.elements.len()
+    }
+
+    /// Checks if the stack is empty.
+    pub fn is_empty(&self) -> bool {
+        self.elements.is_empty()
+    }
+
+    /// Checks if a value is present in the stack.
     /// # Arguments
-    /// * `content` - The question to raise.
-    fn new(content: Question) -> Self {
-        Raise { content }
+    /// * `value` - The value to check.
+    pub fn contains(&self, value: &T) -> bool {
+        self.elements.contains(value)
+    }
+
+    /// Returns a reference to the element `n` places below the top (0 is the top).
+    /// # Arguments
+    /// * `n` - The depth of the element.
+    pub fn peek_nth(&self, n: usize) -> Option<&T> {
+        self.elements.iter().rev().nth(n)
+    }
+
+    /// Removes the topmost occurrence of a value, returning whether it was present.
+    /// # Arguments
+    /// * `value` - The value to remove.
+    pub fn remove(&mut self, value: &T) -> bool {
+        match self.elements.iter().rposition(|x| x == value) {
+            Some(pos) => {
+                self.elements.remove(pos);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Returns an iterator over the elements from the bottom to the top.
+    pub fn iter(&self) -> std::slice::Iter<'_, T> {
+        self.elements.iter()
+    }
+
+    /// Returns an iterator over the elements from the top to the bottom.
+    pub fn iter_rev(&self) -> std::iter::Rev<std::slice::Iter<'_, T>> {
+        self.elements.iter().rev()
     }
 }
 
-/// Implements type checking for Raise against a Domain.
-impl Type for Raise {
-    fn typecheck(&self, context: &Domain) -> Result<(), String> {
-        self.content.typecheck(context)
+/// Creates a Stack from a vector whose last element becomes the top.
+impl<T: Clone> From<Vec<T>> for Stack<T> {
+    fn from(elements: Vec<T>) -> Self {
+        Stack {
+            elements,
+            type_constraint: None,
+        }
     }
 }
 
-/// Formats the Raise for display.
-impl fmt::Display for Raise {
+impl<T: Clone + PartialEq> Default for Stack<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Formats the Stack for display, showing elements in reverse order.
+impl<T: Clone + fmt::Display> fmt::Display for Stack<T> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "Raise('{}')", self.content)
+        let reversed: Vec<String> = self.elements.iter().rev().map(|e| e.to_string()).collect();
+        write!(f, "<[ {} <]", reversed.join(", "))
     }
 }
 
-/// Represents a conditional plan constructor.
-#[derive(Clone)]
-pub struct If {
-    cond: Question, // The condition question
-    iftrue: Vec<String>, // Plans if condition is true
-    iffalse: Vec<String>, // Plans if condition is false
+/// Creates an unconstrained Stack from an iterator whose last element becomes the top.
+impl<T: Clone> FromIterator<T> for Stack<T> {
+    fn from_iter<I: IntoIterator<Item = T>>(iter: I) -> Self {
+        Stack::from(iter.into_iter().collect::<Vec<_>>())
+    }
 }
 
-/// Implementation of methods for the If struct.
-impl If {
-    /// Creates a new If plan.
-    /// # Arguments
-    /// * `cond` - The condition question.
-    /// * `iftrue` - Plans to execute if true.
-    /// * `iffalse` - Plans to execute if false.
-    pub fn new(cond: Question, iftrue: Vec<String>, iffalse: Vec<String>) -> Self {
-        If { cond, iftrue, iffalse }
+/// Pushes the elements of an iterator in order. Values failing the type
+/// check are skipped; use push to handle them.
+impl<T: Clone + PartialEq> Extend<T> for Stack<T> {
+    fn extend<I: IntoIterator<Item = T>>(&mut self, iter: I) {
+        for value in iter {
+            if self.type_constraint.as_ref().is_none_or(|check| check(&value)) {
+                self.elements.push(value);
+            }
+        }
     }
 }
 
-/// Implements type checking for If against a Domain.
-impl Type for If {
-    fn typecheck(&self, context: &Domain) -> Result<(), String> {
-        self.cond.typecheck(context)?;
-        Ok(())
+/// Consumes the Stack into an iterator over its elements from the bottom to the top.
+impl<T: Clone> IntoIterator for Stack<T> {
+    type Item = T;
+    type IntoIter = std::vec::IntoIter<T>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.elements.into_iter()
     }
 }
 
-/// Formats the If for display.
-impl fmt::Display for If {
+/// Iterates over the elements of a borrowed Stack from the bottom to the top.
+impl<'a, T: Clone> IntoIterator for &'a Stack<T> {
+    type Item = &'a T;
+    type IntoIter = std::slice::Iter<'a, T>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.elements.iter()
+    }
+}
+
+/// Compares the elements and their order, ignoring type constraints.
+impl<T: Clone + PartialEq> PartialEq for Stack<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.elements == other.elements
+    }
+}
+
+impl<T: Clone + Eq> Eq for Stack<T> {}
+
+/// Formats the Stack for debugging as a list from the top to the bottom.
+impl<T: Clone + fmt::Debug> fmt::Debug for Stack<T> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let iftrue_str: Vec<String> = self.iftrue.clone();
-        let iffalse_str: Vec<String> = self.iffalse.clone();
-        write!(f, "If('{}', {}, {})", self.cond, iftrue_str.join(", "), iffalse_str.join(", "))
+        f.debug_list().entries(self.elements.iter().rev()).finish()
     }
 }
 
-/// Trait for plan constructors.
-pub trait PlanConstructor: Type + fmt::Display + Clone {}
+// StackSet struct
 
-impl PlanConstructor for Respond {}
-impl PlanConstructor for ConsultDB {}
-impl PlanConstructor for Findout {}
-impl PlanConstructor for Raise {}
-impl PlanConstructor for If {}
+/// A stack-based set ensuring unique elements with LIFO order.
+#[derive(Clone)]
+pub struct StackSet<T: Clone + PartialEq + Eq + Hash> {
+    stack: Stack<T>, // Underlying stack for storage
+}
 
-// Dialogue Manager
+/// Implementation of methods for the StackSet struct.
+impl<T: Clone + PartialEq + Eq + Hash> StackSet<T> {
+    /// Creates a new empty StackSet.
+    pub fn new() -> Self {
+        StackSet { stack: Stack::new() }
+    }
 
-/// Trait for managing dialogue flow and state.
-trait DialogueManager {
-    /// Logs a trace message.
+    /// Creates a new StackSet with a type constraint.
     /// # Arguments
-    /// * `message` - The message to log.
-    fn trace(&self, message: &str) {
-        println!("{{{}}}", message);
+    /// * `type_check` - A function to validate elements.
+    pub fn with_type<F>(type_check: F) -> Self
+    where
+        F: Fn(&T) -> bool + 'static,
+    {
+        StackSet {
+            stack: Stack::with_type(type_check),
+        }
     }
 
-    /// Runs the dialogue manager.
-    fn run(&mut self) {
-        self.reset();
-        self.control();
+    /// Checks if a value is present in the StackSet.
+    /// # Arguments
+    /// * `value` - The value to check.
+    pub fn contains(&self, value: &T) -> bool {
+        self.stack.contains(value)
     }
 
-    /// Resets the dialogue state.
-    fn reset(&mut self);
-
-    /// Controls the dialogue flow.
-    fn control(&mut self);
+    /// Pushes a value, removing any existing instance to maintain uniqueness.
+    /// # Arguments
+    /// * `value` - The value to push.
+    pub fn push(&mut self, value: T) -> Result<(), String>
+    where
+        T: fmt::Debug,
+    {
+        self.stack.remove(&value);
+        self.stack.push(value)
+    }
 
-    /// Prints the current dialogue state.
-    fn print_state(&self);
-}
+    /// Returns a reference to the top element of the StackSet.
+    pub fn top(&self) -> Result<&T, String> {
+        self.stack.top()
+    }
 
-/// Standard MIVS (Minimal Information State) for dialogue management.
-struct StandardMIVS {
-    input: Value<String>, // User input
-    latest_speaker: Value<Speaker>, // Latest speaker (USR or SYS)
-    latest_moves: TSet<String>, // Latest dialogue moves
-    next_moves: Stack<String>, // Next moves to perform
-    output: Value<String>, // System output
-    program_state: Value<ProgramState>, // Program state (RUN or QUIT)
-}
+    /// Removes and returns the top element of the StackSet.
+    pub fn pop(&mut self) -> Result<T, String> {
+        self.stack.pop()
+    }
 
-/// Implementation of methods for the StandardMIVS struct.
-impl StandardMIVS {
-    /// Initializes the MIVS state.
-    fn init_mivs(&mut self) {
-        self.input = Value::new_type(|_: &String| true);
-        self.latest_speaker = Value::new_allowed(HashSet::from([Speaker::USR, Speaker::SYS]));
-        self.latest_moves = TSet::new();
-        self.next_moves = Stack::new();
-        self.output = Value::new_type(|_: &String| true);
-        self.program_state = Value::new_allowed(HashSet::from([ProgramState::RUN, ProgramState::QUIT]));
-        self.program_state.set(ProgramState::RUN).unwrap();
+    /// Clears all elements from the StackSet.
+    pub fn clear(&mut self) {
+        self.stack.clear();
     }
 
-    /// Prints the MIVS state with a prefix.
-    /// # Arguments
-    /// * `prefix` - The prefix for each line.
-    fn print_mivs(&self, prefix: &str) {
-        println!("{}INPUT:          {}", prefix, self.input);
-        println!("{}LATEST_SPEAKER: {}", prefix, self.latest_speaker);
-        println!("{}LATEST_MOVES:   {}", prefix, self.latest_moves);
-        println!("{}NEXT_MOVES:     {}", prefix, self.next_moves);
-        println!("{}OUTPUT:         {}", prefix, self.output);
-        println!("{}PROGRAM_STATE:  {}", prefix, self.program_state);
+    /// Returns the number of elements in the StackSet.
+    pub fn len(&self) -> usize {
+        self.stack.len()
     }
-}
 
-// Grammar
+    /// Checks if the StackSet is empty.
+    pub fn is_empty(&self) -> bool {
+        self.stack.is_empty()
+    }
 
-/// Trait for generating and interpreting dialogue moves.
-trait Grammar {
-    /// Generates a string from a set of moves.
+    /// Returns a reference to the element `n` places below the top (0 is the top).
     /// # Arguments
-    /// * `moves` - The set of moves to generate.
-    fn generate(&self, moves: &TSet<String>) -> String;
+    /// * `n` - The depth of the element.
+    pub fn peek_nth(&self, n: usize) -> Option<&T> {
+        self.stack.peek_nth(n)
+    }
 
-    /// Interprets an input string into a set of moves.
+    /// Removes a value, returning whether it was present.
     /// # Arguments
-    /// * `input` - The input string to interpret.
-    fn interpret(&self, input: &str) -> Option<TSet<String>>;
+    /// * `value` - The value to remove.
+    pub fn remove(&mut self, value: &T) -> bool {
+        self.stack.remove(value)
+    }
+
+    /// Returns an iterator over the elements from the bottom to the top.
+    pub fn iter(&self) -> std::slice::Iter<'_, T> {
+        self.stack.iter()
+    }
+
+    /// Returns an iterator over the elements from the top to the bottom.
+    pub fn iter_rev(&self) -> std::iter::Rev<std::slice::Iter<'_, T>> {
+        self.stack.iter_rev()
+    }
 }
 
-/// A simple grammar for generating and interpreting dialogue moves.
-pub struct SimpleGenGrammar {
-    forms: HashMap<String, String>, // Mapping of move strings to output strings
+/// Creates a StackSet from a vector whose last element becomes the top.
+/// Of repeated values only the topmost occurrence is kept.
+impl<T: Clone + PartialEq + Eq + Hash> From<Vec<T>> for StackSet<T> {
+    fn from(elements: Vec<T>) -> Self {
+        let mut set = StackSet::new();
+        set.extend(elements);
+        set
+    }
 }
 
-/// Implementation of methods for the SimpleGenGrammar struct.
-impl SimpleGenGrammar {
-    /// Creates a new SimpleGenGrammar with default forms.
-    pub fn new() -> Self {
-        let mut grammar = SimpleGenGrammar {
-            forms: HashMap::new(),
-        };
-        grammar.add_form("Greet()", "Hello");
-        grammar.add_form("icm:neg*sem", "I don't understand");
-        grammar
+impl<T: Clone + PartialEq + Eq + Hash> Default for StackSet<T> {
+    fn default() -> Self {
+        Self::new()
     }
+}
 
-    /// Adds a form to the grammar.
-    /// # Arguments
-    /// * `move_str` - The move string.
-    /// * `output` - The corresponding output string.
-    pub fn add_form(&mut self, move_str: &str, output: &str) {
-        self.forms.insert(move_str.to_string(), output.to_string());
+/// Formats the StackSet for display, showing the underlying stack.
+impl<T: Clone + PartialEq + Eq + Hash + fmt::Display> fmt::Display for StackSet<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "<{{ {} <}}", self.stack)
     }
+}
 
-    /// Generates a string for a single move.
-    /// # Arguments
-    /// * `move` - The move to generate.
-    fn generate_move(&self, move_str: &str) -> String {
-        self.forms.get(move_str).cloned().unwrap_or_else(|| move_str.to_string())
+/// Creates an unconstrained StackSet from an iterator whose last element
+/// becomes the top. Of repeated values only the topmost occurrence is kept.
+impl<T: Clone + PartialEq + Eq + Hash> FromIterator<T> for StackSet<T> {
+    fn from_iter<I: IntoIterator<Item = T>>(iter: I) -> Self {
+        StackSet::from(iter.into_iter().collect::<Vec<_>>())
     }
+}
 
-    /// Joins phrases into a single string with punctuation.
-    /// # Arguments
-    /// * `phrases` - The phrases to join.
-    fn join_phrases(&self, phrases: &[String]) -> String {
-        let mut result = String::new();
-        for p in phrases {
-            if !result.is_empty() {
-                result.push(' ');
-            }
-            result.push_str(p);
-            if !p.ends_with('.') && !p.ends_with('?') && !p.ends_with('!') {
-                result.push('.');
+/// Pushes the elements of an iterator in order, moving repeated values to
+/// the top. Values failing the type check are skipped; use push to handle them.
+impl<T: Clone + PartialEq + Eq + Hash> Extend<T> for StackSet<T> {
+    fn extend<I: IntoIterator<Item = T>>(&mut self, iter: I) {
+        for value in iter {
+            if self.stack.type_constraint.as_ref().is_none_or(|check| check(&value)) {
+                self.stack.remove(&value);
+                self.stack.elements.push(value);
             }
         }
-        result
     }
 }
 
-/// Implements the Grammar trait for SimpleGenGrammar.
-impl Grammar for SimpleGenGrammar {
-    fn generate(&self, moves: &TSet<String>) -> String {
-        let phrases: Vec<String> = moves.elements.iter().map(|m| self.generate_move(m)).collect();
-        self.join_phrases(&phrases)
+/// Consumes the StackSet into an iterator over its elements from the bottom to the top.
+impl<T: Clone + PartialEq + Eq + Hash> IntoIterator for StackSet<T> {
+    type Item = T;
+    type IntoIter = std::vec::IntoIter<T>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.stack.into_iter()
     }
+}
 
-    fn interpret(&self, input: &str) -> Option<TSet<String>> {
-        let mut moves = TSet::new();
-        
-        // Handle special cases first
-        if input == "quit" || input == "exit" {
-            moves.add("Quit()".to_string()).ok();
-        }
-        // Try to parse as a question
-        else if let Ok(_question) = Question::new(input) {
-            moves.add(format!("Ask('{}')", input)).ok();
-        }
-        // Try to parse as an answer
-        else if let Ok(_answer) = Ans::new(input) {
-            moves.add(format!("Answer({})", input)).ok();
-        }
-        else {
-            return None;
-        }
-        
-        Some(moves)
+/// Iterates over the elements of a borrowed StackSet from the bottom to the top.
+impl<'a, T: Clone + PartialEq + Eq + Hash> IntoIterator for &'a StackSet<T> {
+    type Item = &'a T;
+    type IntoIter = std::slice::Iter<'a, T>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.stack.iter()
     }
+}
 
+/// Compares the elements and their order, ignoring type constraints.
+impl<T: Clone + PartialEq + Eq + Hash> PartialEq for StackSet<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.stack == other.stack
+    }
 }
 
+impl<T: Clone + PartialEq + Eq + Hash> Eq for StackSet<T> {}
 
-/// CFG Grammar Rule structure for parsing context-free grammar files
-#[derive(Debug, Clone)]
-struct CFGRule {
-    lhs: String,           // Left-hand side (e.g., "USR[sem=?s]")
-    rhs: Vec<String>,      // Right-hand side alternatives (e.g., ["ANSWER[sem=?s]", "ASK[sem=?s]"])
-    features: HashMap<String, String>, // Feature annotations (e.g., sem=?s, q=?q)
+/// Formats the StackSet for debugging as a list from the top to the bottom.
+impl<T: Clone + PartialEq + Eq + Hash + fmt::Debug> fmt::Debug for StackSet<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&self.stack, f)
+    }
 }
 
-/// CFG Grammar structure for parsing travel.fcfg files
-struct CFGGrammar {
-    rules: Vec<CFGRule>,
-    terminals: HashMap<String, Vec<String>>, // Terminal mappings (e.g., 'price' -> WHQ[q=price])
+// TSet struct
+
+/// A typed set with optional type constraints for elements.
+pub struct TSet<T: Clone + PartialEq + Eq + Hash> {
+    elements: HashSet<T>, // The set of elements
+    type_constraint: Option<TypeCheck<T>>, // Optional type checking function
 }
 
-impl CFGGrammar {
-    /// Creates a new empty CFG grammar
-    fn new() -> Self {
-        CFGGrammar {
-            rules: Vec::new(),
-            terminals: HashMap::new(),
+impl<T: Clone + PartialEq + Eq + Hash> Clone for TSet<T> {
+    fn clone(&self) -> Self {
+        TSet {
+            elements: self.elements.clone(),
+            type_constraint: self.type_constraint.clone(),
         }
     }
+}
 
-    /// Loads CFG rules from a file (basic implementation)
-    fn load_from_file(&mut self, _filepath: &str) -> Result<(), Box<dyn std::error::Error>> {
-        // Placeholder: In full implementation, this would parse travel.fcfg
-        // For now, add some basic rules based on travel.fcfg
-        
-        // Add basic rule: USR[sem=?s] -> ANSWER[sem=?s] | ASK[sem=?s]
-        self.rules.push(CFGRule {
-            lhs: "USR[sem=?s]".to_string(),
-            rhs: vec!["ANSWER[sem=?s]".to_string(), "ASK[sem=?s]".to_string()],
-            features: HashMap::from([("sem".to_string(), "?s".to_string())]),
-        });
-
-        // Add terminal mappings
-        self.terminals.insert("price".to_string(), vec!["WHQ[q=price]".to_string()]);
-        self.terminals.insert("plane".to_string(), vec!["CAT[cat=how, ind=plane]".to_string()]);
-        self.terminals.insert("train".to_string(), vec!["CAT[cat=how, ind=train]".to_string()]);
-        
-        Ok(())
-    }
-
-    /// Basic parsing of input using CFG rules (placeholder)
-    fn parse(&self, input: &str) -> Option<String> {
-        // Placeholder: Check terminals first
-        if let Some(categories) = self.terminals.get(input) {
-            return categories.first().cloned();
-        }
-        None
-    }
-}
-
-// Database
-
-/// Trait for consulting a database with questions.
-trait Database {
-    /// Consults the database with a question and context.
-    /// # Arguments
-    /// * `question` - The question to consult.
-    /// * `context` - The context propositions.
-    fn consult_db(&self, question: &Question, context: &TSet<Prop>) -> Prop;
-}
-
-/// A travel database storing entries as key-value maps.
-pub struct TravelDB {
-    entries: Vec<HashMap<String, String>>, // Database entries
-}
-
-/// Implementation of methods for the TravelDB struct.
-impl TravelDB {
-    /// Creates a new empty TravelDB.
+/// Implementation of methods for the TSet struct.
+impl<T: Clone + PartialEq + Eq + Hash> TSet<T> {
+    /// Creates a new empty TSet.
     pub fn new() -> Self {
-        TravelDB { entries: Vec::new() }
-    }
-
-    /// Adds an entry to the database.
-    /// # Arguments
-    /// * `entry` - The key-value map to add.
-    pub fn add_entry(&mut self, entry: HashMap<String, String>) {
-        self.entries.push(entry);
+        TSet {
+            elements: HashSet::new(),
+            type_constraint: None,
+        }
     }
 
-    /// Retrieves a context value for a predicate.
+    /// Creates a new TSet with a type constraint.
     /// # Arguments
-    /// * `context` - The context propositions.
-    /// * `pred` - The predicate to look up.
-    fn get_context(&self, context: &TSet<Prop>, pred: &str) -> Option<String> {
-        for prop in &context.elements {
-            if prop.pred.0.content == pred {
-                return prop.ind.as_ref().map(|ind| ind.0.content.clone());
-            }
+    /// * `type_check` - A function to validate elements.
+    pub fn with_type<F>(type_check: F) -> Self
+    where
+        F: Fn(&T) -> bool + 'static,
+    {
+        TSet {
+            elements: HashSet::new(),
+            type_constraint: Some(Rc::new(type_check)),
         }
-        None
     }
 
-    /// Looks up an entry by departure city, destination city, and day.
+    /// Adds an element to the TSet after type checking.
     /// # Arguments
-    /// * `depart_city` - Departure city.
-    /// * `dest_city` - Destination city.
-    /// * `day` - Departure day.
-    fn lookup_entry(&self, depart_city: &str, dest_city: &str, day: &str) -> Option<&HashMap<String, String>> {
-        for entry in &self.entries {
-            if entry.get("from") == Some(&depart_city.to_string())
-                && entry.get("to") == Some(&dest_city.to_string())
-                && entry.get("day") == Some(&day.to_string())
-            {
-                return Some(entry);
-            }
+    /// * `value` - The value to add.
+    pub fn add(&mut self, value: T) -> Result<(), String>
+    where
+        T: fmt::Debug,
+    {
+        if let Some(check) = &self.type_constraint
+            && !check(&value)
+        {
+            return Err(format!("Value {:?} does not match type constraint", value));
         }
-        None
+        self.elements.insert(value);
+        Ok(())
     }
-}
 
-/// Implements the Database trait for TravelDB.
-impl Database for TravelDB {
-    fn consult_db(&self, question: &Question, context: &TSet<Prop>) -> Prop {
-        let depart_city = self.get_context(context, "depart_city").unwrap_or_default();
-        let dest_city = self.get_context(context, "dest_city").unwrap_or_default();
-        let day = self.get_context(context, "depart_day").unwrap_or_default();
-        let entry = self.lookup_entry(&depart_city, &dest_city, &day).expect("Entry not found");
-        let price = entry.get("price").expect("Price not found");
-        Prop {
-            pred: Pred0::new("price").unwrap(),
-            ind: Some(Ind::new(price).unwrap()),
-            yes: true,
-        }
+    /// Clears all elements from the TSet.
+    pub fn clear(&mut self) {
+        self.elements.clear();
     }
-}
 
-// Domain
-
-/// Represents the domain knowledge, including predicates, sorts, and plans.
-pub struct Domain {
-    preds0: HashSet<String>, // Zero-place predicates
-    preds1: HashMap<String, String>, // One-place predicates with their sorts
-    sorts: HashMap<String, HashSet<String>>, // Sorts and their individuals
-    inds: HashMap<String, String>, // Individuals and their sorts
-    plans: HashMap<String, Vec<String>>, // Question-triggered plans
-}
+    /// Returns the number of elements in the TSet.
+    pub fn len(&self) -> usize {
+        self.elements.len()
+    }
 
-/// Implementation of methods for the Domain struct.
-impl Domain {
-    /// Creates a new Domain.
-    /// # Arguments
-    /// * `preds0` - Zero-place predicates.
-    /// * `preds1` - One-place predicates with their sorts.
-    /// * `sorts` - Sorts and their individuals.
-    pub fn new(
-        preds0: HashSet<String>,
-        preds1: HashMap<String, String>,
-        sorts: HashMap<String, HashSet<String>>,
-    ) -> Self {
-        let inds = sorts
-            .iter()
-            .flat_map(|(sort, inds)| inds.iter().map(move |ind| (ind.clone(), sort.clone())))
-            .collect();
-        Domain {
-            preds0,
-            preds1,
-            sorts,
-            inds,
-            plans: HashMap::new(),
-        }
+    /// Checks if the TSet is empty.
+    pub fn is_empty(&self) -> bool {
+        self.elements.is_empty()
     }
 
-    /// Adds a plan for a question.
+    /// Checks if a value is present in the TSet.
     /// # Arguments
-    /// * `trigger` - The question that triggers the plan.
-    /// * `plan` - The plan constructors to execute.
-    pub fn add_plan(&mut self, trigger: Question, plan: Vec<String>) {
-        self.plans.insert(trigger.to_string(), plan);
+    /// * `value` - The value to check.
+    pub fn contains(&self, value: &T) -> bool {
+        self.elements.contains(value)
     }
 
-    /// Checks if an answer is relevant to a question.
+    /// Removes a value, returning whether it was present.
     /// # Arguments
-    /// * `answer` - The answer to check.
-    /// * `question` - The question to check against.
-    fn relevant(&self, answer: &Ans, question: &Question) -> bool {
-        match (answer, question) {
-            (Ans::Prop(prop), Question::WhQ(whq)) => prop.pred.0.content == whq.pred.0.content,
-            (Ans::ShortAns(short), Question::WhQ(whq)) => {
-                let sort1 = self.inds.get(&short.ind.0.content);
-                let sort2 = self.preds1.get(&whq.pred.0.content);
-                sort1.is_some() && sort2.is_some() && sort1 == sort2
-            }
-            (Ans::YesNo(_), Question::YNQ(_)) => true,
-            (Ans::Prop(prop), Question::YNQ(ynq)) => prop == &ynq.prop,
-            (Ans::Prop(prop), Question::AltQ(altq)) => {
-                altq.ynqs.iter().any(|ynq| prop == &ynq.prop)
-            }
-            (Ans::YesNo(_), Question::AltQ(_)) => true,
-            _ => false,
-        }
+    /// * `value` - The value to remove.
+    pub fn remove(&mut self, value: &T) -> bool {
+        self.elements.remove(value)
     }
 
-    /// Checks if an answer resolves a question.
+    /// Keeps only the elements satisfying a predicate.
     /// # Arguments
-    /// * `answer` - The answer to check.
-    /// * `question` - The question to check against.
-    fn resolves(&self, answer: &Ans, question: &Question) -> bool {
-        if self.relevant(answer, question) {
-            match (answer, question) {
-                (Ans::YesNo(_), Question::YNQ(_)) => true,
-                (Ans::ShortAns(short), Question::WhQ(_)) => short.yes,
-                (Ans::Prop(prop), Question::WhQ(_)) => prop.yes,
-                _ => false,
-            }
-        } else {
-            false
-        }
+    /// * `keep` - The predicate deciding which elements to keep.
+    pub fn retain<F>(&mut self, keep: F)
+    where
+        F: FnMut(&T) -> bool,
+    {
+        self.elements.retain(keep);
     }
 
-    /// Combines a question and answer into a proposition.
+    /// Adds all values after type checking them. Nothing is added if any value
+    /// fails the type check.
     /// # Arguments
-    /// * `question` - The question.
-    /// * `answer` - The answer.
-    fn combine(&self, question: &Question, answer: &Ans) -> Result<Prop, Box<dyn std::error::Error>> {
-        assert!(self.relevant(answer, question));
-        match (question, answer) {
-            (Question::WhQ(whq), Ans::ShortAns(short)) => {
-                let mut prop = whq.pred.apply(&short.ind)?;
-                if !short.yes {
-                    prop.yes = false;
-                }
-                Ok(prop)
-            }
-            (Question::YNQ(ynq), Ans::YesNo(yesno)) => {
-                let mut prop = ynq.prop.clone();
-                if prop.yes != yesno.yes {
-                    prop.yes = !prop.yes;
-                }
-                Ok(prop)
-            }
-            _ => match answer {
-                Ans::Prop(p) => Ok(p.clone()),
-                _ => panic!("Invalid combination"),
-            },
+    /// * `values` - The values to add.
+    pub fn extend<I>(&mut self, values: I) -> Result<(), String>
+    where
+        I: IntoIterator<Item = T>,
+        T: fmt::Debug,
+    {
+        let values: Vec<T> = values.into_iter().collect();
+        if let Some(value) = self.type_constraint.as_ref().and_then(|check| values.iter().find(|value| !check(value))) {
+            return Err(format!("Value {:?} does not match type constraint", value));
         }
+        self.elements.extend(values);
+        Ok(())
     }
 
-    /// Retrieves the plan for a question.
+    /// Returns the union with another set, keeping this set's type constraint.
     /// # Arguments
-    /// * `question` - The question to get the plan for.
-    fn get_plan(&self, question: &Question) -> Option<Stack<String>> {
-        self.plans.get(&question.to_string()).map(|plan| {
-            let mut stack = Stack::new();
-            for construct in plan.iter().rev() {
-                stack.push(construct.clone()).unwrap();
-            }
-            stack
-        })
+    /// * `other` - The other set.
+    pub fn union(&self, other: &TSet<T>) -> Result<TSet<T>, String>
+    where
+        T: fmt::Debug,
+    {
+        let mut result = self.clone();
+        result.extend(other.iter().cloned())?;
+        Ok(result)
     }
-}
-
-// IBIS Information State
-
-/// Represents the Information-Based Inquiry System (IBIS) information state.
-struct IBISInfostate {
-    is: Record, // The record storing private and shared state
-}
 
-/// Implementation of methods for the IBISInfostate struct.
-impl IBISInfostate {
-    /// Initializes the information state with default fields.
-    fn init_is(&mut self) {
-        let mut fields = HashMap::new();
-        fields.insert("agenda".to_string(), Box::new(Stack::<String>::new()) as Box<dyn Any>);
-        fields.insert("plan".to_string(), Box::new(Stack::<String>::new()) as Box<dyn Any>);
-        fields.insert("bel".to_string(), Box::new(TSet::<String>::new()) as Box<dyn Any>);
-        fields.insert("com".to_string(), Box::new(TSet::<String>::new()) as Box<dyn Any>);
-        fields.insert("qud".to_string(), Box::new(StackSet::<String>::new()) as Box<dyn Any>);
-        self.is = Record::new(fields);
+    /// Returns the elements also present in another set, keeping this set's type constraint.
+    /// # Arguments
+    /// * `other` - The other set.
+    pub fn intersection(&self, other: &TSet<T>) -> TSet<T> {
+        let mut result = self.clone();
+        result.retain(|value| other.contains(value));
+        result
     }
 
-    /// Prints the information state with a prefix.
+    /// Returns the elements not present in another set, keeping this set's type constraint.
     /// # Arguments
-    /// * `prefix` - The prefix for each line.
-    fn print_is(&self, prefix: &str) {
-        println!("{}", self.is.pformat(prefix, "    "));
+    /// * `other` - The other set.
+    pub fn difference(&self, other: &TSet<T>) -> TSet<T> {
+        let mut result = self.clone();
+        result.retain(|value| !other.contains(value));
+        result
     }
-}
-
-// IBIS Controller
 
-/// Controls the IBIS dialogue system.
-pub struct IBISController {
-    is: IBISInfostate, // Information state
-    mivs: StandardMIVS, // Minimal information state
-    domain: Domain, // Domain knowledge
-    database: TravelDB, // Travel database
-    grammar: SimpleGenGrammar, // Grammar for generation and interpretation
-    input_handler: Box<dyn InputHandler>, // Input handling abstraction
+    /// Returns an iterator over the elements, in no particular order.
+    pub fn iter(&self) -> std::collections::hash_set::Iter<'_, T> {
+        self.elements.iter()
+    }
 }
 
-/// Implementation of methods for the IBISController struct.
-impl IBISController {
-    /// Creates a new IBISController.
-    /// # Arguments
-    /// * `domain` - The domain knowledge.
-    /// * `database` - The travel database.
-    /// * `grammar` - The grammar for dialogue.
-    pub fn new(domain: Domain, database: TravelDB, grammar: SimpleGenGrammar) -> Self {
-        Self::with_input_handler(domain, database, grammar, Box::new(StandardInputHandler))
-    }
-    
-    pub fn with_input_handler(domain: Domain, database: TravelDB, grammar: SimpleGenGrammar, input_handler: Box<dyn InputHandler>) -> Self {
-        IBISController {
-            is: IBISInfostate { is: Record::new(HashMap::new()) },
-            mivs: StandardMIVS {
-                input: Value::new_type(|_: &String| true),
-                latest_speaker: Value::new_allowed(HashSet::from([Speaker::USR, Speaker::SYS])),
-                latest_moves: TSet::new(),
-                next_moves: Stack::new(),
-                output: Value::new_type(|_: &String| true),
-                program_state: Value::new_allowed(HashSet::from([ProgramState::RUN, ProgramState::QUIT])),
-            },
-            domain,
-            database,
-            grammar,
-            input_handler,
+/// Creates an unconstrained TSet from an iterator.
+impl<T: Clone + PartialEq + Eq + Hash> FromIterator<T> for TSet<T> {
+    fn from_iter<I: IntoIterator<Item = T>>(iter: I) -> Self {
+        TSet {
+            elements: iter.into_iter().collect(),
+            type_constraint: None,
         }
     }
+}
+
+/// Consumes the TSet into an iterator over its elements.
+impl<T: Clone + PartialEq + Eq + Hash> IntoIterator for TSet<T> {
+    type Item = T;
+    type IntoIter = std::collections::hash_set::IntoIter<T>;
 
-    /// Selects the next moves (placeholder).
-    fn select(&mut self) {
-        // Placeholder: Implement selection logic
+    fn into_iter(self) -> Self::IntoIter {
+        self.elements.into_iter()
     }
+}
 
-    /// Generates output from the next moves.
-    fn generate(&mut self) {
-        // Convert stack to TSet for generation
-        let mut moves_set = TSet::new();
-        for element in &self.mivs.next_moves.elements {
-            moves_set.add(element.clone()).ok();
-        }
-        let output = self.grammar.generate(&moves_set);
-        self.mivs.output.set(output).unwrap();
+/// Iterates over the elements of a borrowed TSet.
+impl<'a, T: Clone + PartialEq + Eq + Hash> IntoIterator for &'a TSet<T> {
+    type Item = &'a T;
+    type IntoIter = std::collections::hash_set::Iter<'a, T>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.elements.iter()
     }
+}
 
-    /// Outputs the generated response.
-    fn output(&mut self) {
-        println!("S> {}", self.mivs.output.get().unwrap_or(&"[---]".to_string()));
-        println!();
-        self.mivs.latest_speaker.set(Speaker::SYS).unwrap();
-        self.mivs.latest_moves.clear();
-        for element in &self.mivs.next_moves.elements {
-            self.mivs.latest_moves.add(element.clone()).ok();
-        }
-        self.mivs.next_moves.clear();
+impl<T: Clone + PartialEq + Eq + Hash> Default for TSet<T> {
+    fn default() -> Self {
+        Self::new()
     }
+}
 
-    /// Reads user input.
-    fn input(&mut self) {
-        if let Some(input) = self.input_handler.read_line() {
-            self.mivs.input.set(input).unwrap();
-            self.mivs.latest_speaker.set(Speaker::USR).unwrap();
-        } else {
-            self.mivs.program_state.set(ProgramState::QUIT).unwrap();
-        }
+/// Formats the TSet for display as a comma-separated list of elements.
+impl<T: Clone + PartialEq + Eq + Hash + fmt::Display> fmt::Display for TSet<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let elements: Vec<String> = self.elements.iter().map(|e| e.to_string()).collect();
+        write!(f, "{{{}}}", elements.join(", "))
     }
+}
 
-    /// Interprets the user input into moves.
-    fn interpret(&mut self) {
-        self.mivs.latest_moves.clear();
-        if let Some(input) = self.mivs.input.get() {
-            if !input.is_empty() {
-                if let Some(moves) = self.grammar.interpret(input) {
-                    for move_str in &moves.elements {
-                        self.mivs.latest_moves.add(move_str.clone()).ok();
-                    }
-                } else {
-                    println!("Did not understand: {}", input);
-                }
+/// Adds the elements of an iterator. Values failing the type check are
+/// skipped; the inherent extend reports them instead.
+impl<T: Clone + PartialEq + Eq + Hash> Extend<T> for TSet<T> {
+    fn extend<I: IntoIterator<Item = T>>(&mut self, iter: I) {
+        for value in iter {
+            if self.type_constraint.as_ref().is_none_or(|check| check(&value)) {
+                self.elements.insert(value);
             }
         }
     }
+}
 
-    /// Updates the dialogue state (placeholder).
-    fn update(&mut self) {
-        // Placeholder: Implement update logic
+/// Compares the elements regardless of order, ignoring type constraints.
+impl<T: Clone + PartialEq + Eq + Hash> PartialEq for TSet<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.elements == other.elements
     }
 }
 
-/// Implements the DialogueManager trait for IBISController.
-impl DialogueManager for IBISController {
-    fn reset(&mut self) {
-        self.is.init_is();
-        self.mivs.init_mivs();
+impl<T: Clone + PartialEq + Eq + Hash> Eq for TSet<T> {}
+
+/// Formats the TSet for debugging as a set of its elements.
+impl<T: Clone + PartialEq + Eq + Hash + fmt::Debug> fmt::Debug for TSet<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_set().entries(self.elements.iter()).finish()
     }
+}
 
-    fn control(&mut self) {
-        self.mivs.next_moves.push("Greet()".to_string()).unwrap();
-        self.print_state();
-        while self.mivs.program_state.get() != Some(&ProgramState::QUIT) {
-            self.select();
-            if !self.mivs.next_moves.elements.is_empty() {
-                self.generate();
-                self.output();
-                self.update();
-                self.print_state();
+// Enum creation macro
+
+#[doc(hidden)]
+pub use serde as __serde;
+
+/// Macro to create a public enum of named variants that can be parsed,
+/// displayed, listed and serialized by name. Applications can use it for
+/// their own enumerations, e.g. `isu::create_enum!(Mood, HAPPY, ANGRY);`.
+/// # Arguments
+/// * `$meta` - Optional attributes of the enum, e.g. its doc comment.
+/// * `$name` - The name of the enum.
+/// * `$($variant),+` - The variants of the enum.
+#[macro_export]
+macro_rules! create_enum {
+    ($(#[$meta:meta])* $name:ident, $($variant:ident),+ $(,)?) => {
+        $(#[$meta])*
+        #[derive(Clone, PartialEq, Eq, Debug, Hash)]
+        #[allow(clippy::upper_case_acronyms)]
+        pub enum $name {
+            $($variant),+
+        }
+
+        impl $name {
+            /// All variants, in declaration order.
+            pub const VARIANTS: &'static [$name] = &[$($name::$variant),+];
+
+            /// Creates an enum variant from a string name.
+            /// # Arguments
+            /// * `name` - The string name of the variant.
+            pub fn new(name: &str) -> Option<Self> {
+                match name {
+                    $(stringify!($variant) => Some($name::$variant),)+
+                    _ => None,
+                }
+            }
+
+            /// Returns the name of the variant.
+            pub fn as_str(&self) -> &'static str {
+                match self {
+                    $($name::$variant => stringify!($variant),)+
+                }
+            }
+
+            /// Iterates over all variants, in declaration order.
+            pub fn iter() -> impl Iterator<Item = $name> {
+                Self::VARIANTS.iter().cloned()
+            }
+        }
+
+        /// Parses the enum from the name of a variant.
+        impl ::std::str::FromStr for $name {
+            type Err = String;
+
+            fn from_str(name: &str) -> Result<Self, String> {
+                Self::new(name).ok_or_else(|| format!("Unknown {}: {}", stringify!($name), name))
+            }
+        }
+
+        /// Formats the enum for display.
+        impl ::std::fmt::Display for $name {
+            fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+                write!(f, "{}", self.as_str())
+            }
+        }
+
+        /// Serializes the enum as the name of its variant.
+        impl $crate::__serde::Serialize for $name {
+            fn serialize<S: $crate::__serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                serializer.serialize_str(self.as_str())
+            }
+        }
+
+        /// Deserializes the enum from the name of a variant.
+        impl<'de> $crate::__serde::Deserialize<'de> for $name {
+            fn deserialize<D: $crate::__serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+                let name = <String as $crate::__serde::Deserialize>::deserialize(deserializer)?;
+                name.parse().map_err(<D::Error as $crate::__serde::de::Error>::custom)
+            }
+        }
+    };
+}
+
+// Define Speaker and ProgramState enums
+create_enum!(
+    /// The participant who made a move or has the floor.
+    Speaker, USR, SYS
+);
+create_enum!(
+    /// Whether the dialogue is running or has ended.
+    ProgramState, RUN, QUIT
+);
+
+// Semantic types
+
+/// Trait for types that can be type-checked within a Domain.
+pub trait Type: fmt::Display {
+    /// Checks if the type is valid within the given Domain context.
+    /// # Arguments
+    /// * `context` - The Domain to check against.
+    fn typecheck(&self, context: &Domain) -> Result<(), String>;
+}
+
+/// Represents an atomic string with validation rules.
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub struct Atomic {
+    content: String, // The atomic string value
+}
+
+/// Implementation of methods for the Atomic struct.
+impl Atomic {
+    /// Creates a new Atomic value with validation.
+    /// # Arguments
+    /// * `atom` - The string to validate and store.
+    pub fn new(atom: &str) -> Result<Self, String> {
+        if atom.is_empty() || atom == "yes" || atom == "no" {
+            return Err("Invalid atom".to_string());
+        }
+        if Self::is_number(atom) || Self::is_date(atom) {
+            return Ok(Atomic { content: atom.to_string() });
+        }
+        if !atom.chars().next().unwrap_or(' ').is_alphabetic() {
+            return Err("Atom must start with a letter".to_string());
+        }
+        if !atom.chars().all(|c| c.is_alphanumeric() || c == '_' || c == '-' || c == '+' || c == ':') {
+            return Err("Invalid characters in atom".to_string());
+        }
+        Ok(Atomic { content: atom.to_string() })
+    }
+
+    /// Checks if a string is a plain decimal number (e.g., "232" or "12.5").
+    /// # Arguments
+    /// * `s` - The string to check.
+    fn is_number(s: &str) -> bool {
+        s.chars().all(|c| c.is_ascii_digit() || c == '.') && s.parse::<f64>().is_ok()
+    }
+
+    /// Checks if a string is an ISO 8601 date of the Gregorian calendar (e.g., "2024-05-17").
+    /// # Arguments
+    /// * `s` - The string to check.
+    fn is_date(s: &str) -> bool {
+        let parts: Vec<&str> = s.split('-').collect();
+        let [year, month, day] = parts[..] else {
+            return false;
+        };
+        let digits = parts.iter().all(|part| part.chars().all(|c| c.is_ascii_digit()));
+        if !digits || year.len() != 4 || month.len() != 2 || day.len() != 2 {
+            return false;
+        }
+        let (Ok(year), Ok(month), Ok(day)) = (year.parse::<u32>(), month.parse::<u32>(), day.parse::<u32>()) else {
+            return false;
+        };
+        let leap = year % 4 == 0 && (year % 100 != 0 || year % 400 == 0);
+        let days = match month {
+            1 | 3 | 5 | 7 | 8 | 10 | 12 => 31,
+            4 | 6 | 9 | 11 => 30,
+            2 if leap => 29,
+            2 => 28,
+            _ => return false,
+        };
+        (1..=days).contains(&day)
+    }
+
+    /// Returns the numeric value of the atom, if it is a number.
+    pub fn as_number(&self) -> Option<f64> {
+        if Self::is_number(&self.content) {
+            self.content.parse().ok()
+        } else {
+            None
+        }
+    }
+}
+
+/// Serializes the Atomic value as its string.
+impl serde::Serialize for Atomic {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.content)
+    }
+}
+
+/// Formats the Atomic value for display.
+impl fmt::Display for Atomic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.content)
+    }
+}
+
+/// Represents an individual in the domain, wrapping an Atomic value.
+#[derive(Clone, PartialEq, Eq, Hash, serde::Serialize)]
+#[serde(transparent)]
+pub struct Ind(Atomic);
+
+/// Implementation of methods for the Ind struct.
+impl Ind {
+    /// Creates a new Ind from a string.
+    /// # Arguments
+    /// * `atom` - The string to create an Atomic value from.
+    pub fn new(atom: &str) -> Result<Self, String> {
+        Ok(Ind(Atomic::new(atom)?))
+    }
+}
+
+/// Implements type checking for Ind against a Domain.
+impl Type for Ind {
+    fn typecheck(&self, context: &Domain) -> Result<(), String> {
+        if context.is_individual(&self.0.content) {
+            Ok(())
+        } else {
+            Err(format!("{} not in context individuals", self.0.content))
+        }
+    }
+}
+
+/// Formats the Ind for display.
+impl fmt::Display for Ind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// Represents a zero-place predicate.
+#[derive(Clone, PartialEq, Eq, Hash, serde::Serialize)]
+#[serde(transparent)]
+pub struct Pred0(Atomic);
+
+/// Implementation of methods for the Pred0 struct.
+impl Pred0 {
+    /// Creates a new Pred0 from a string.
+    /// # Arguments
+    /// * `atom` - The string to create an Atomic value from.
+    pub fn new(atom: &str) -> Result<Self, String> {
+        Ok(Pred0(Atomic::new(atom)?))
+    }
+}
+
+/// Implements type checking for Pred0 against a Domain.
+impl Type for Pred0 {
+    fn typecheck(&self, context: &Domain) -> Result<(), String> {
+        if context.preds0.contains(&self.0.content) {
+            Ok(())
+        } else {
+            Err(format!("{} not in context 0-place predicates", self.0.content))
+        }
+    }
+}
+
+/// Formats the Pred0 for display.
+impl fmt::Display for Pred0 {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// Represents a one-place predicate.
+#[derive(Clone, PartialEq, Eq, Hash, serde::Serialize)]
+#[serde(transparent)]
+pub struct Pred1(Atomic);
+
+/// Implementation of methods for the Pred1 struct.
+impl Pred1 {
+    /// Creates a new Pred1 from a string.
+    /// # Arguments
+    /// * `atom` - The string to create an Atomic value from.
+    pub fn new(atom: &str) -> Result<Self, String> {
+        Ok(Pred1(Atomic::new(atom)?))
+    }
+
+    /// Applies the predicate to an individual to create a proposition.
+    /// # Arguments
+    /// * `ind` - The individual to apply the predicate to.
+    pub fn apply(&self, ind: &Ind) -> Result<Prop, Box<dyn std::error::Error>> {
+        Ok(Prop {
+            pred: Pred0::new(&self.0.content)?,
+            ind: Some(ind.clone()),
+            yes: true,
+            constraint: None,
+            aggregate: None,
+        })
+    }
+}
+
+/// Implements type checking for Pred1 against a Domain.
+impl Type for Pred1 {
+    fn typecheck(&self, context: &Domain) -> Result<(), String> {
+        if context.preds1.contains_key(&self.0.content) {
+            Ok(())
+        } else {
+            Err(format!("{} not in context 1-place predicates", self.0.content))
+        }
+    }
+}
+
+/// Formats the Pred1 for display.
+impl fmt::Display for Pred1 {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// Represents a sort (category) for individuals, wrapping a Pred1.
+#[derive(Clone)]
+pub struct Sort(Pred1);
+
+/// Implementation of methods for the Sort struct.
+impl Sort {
+    /// Creates a new Sort from a string.
+    /// # Arguments
+    /// * `atom` - The string to create a Pred1 from.
+    pub fn new(atom: &str) -> Result<Self, String> {
+        Ok(Sort(Pred1::new(atom)?))
+    }
+}
+
+/// Implements type checking for Sort against a Domain.
+impl Type for Sort {
+    fn typecheck(&self, context: &Domain) -> Result<(), String> {
+        if context.sorts.contains_key(&self.0 .0.content) {
+            Ok(())
+        } else {
+            Err(format!("{} not in context sorts", self.0 .0.content))
+        }
+    }
+}
+
+/// Formats the Sort for display.
+impl fmt::Display for Sort {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// Comparison operators usable in numeric constraints.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, serde::Serialize)]
+pub enum CmpOp {
+    #[serde(rename = "<")]
+    Lt, // Less than
+    #[serde(rename = "<=")]
+    Le, // Less than or equal
+    #[serde(rename = ">")]
+    Gt, // Greater than
+    #[serde(rename = ">=")]
+    Ge, // Greater than or equal
+}
+
+/// Implementation of methods for the CmpOp enum.
+impl CmpOp {
+    /// Returns the symbol of the operator (e.g., "<=").
+    pub fn symbol(&self) -> &'static str {
+        match self {
+            CmpOp::Lt => "<",
+            CmpOp::Le => "<=",
+            CmpOp::Gt => ">",
+            CmpOp::Ge => ">=",
+        }
+    }
+
+    /// Checks whether the comparison holds between two numbers.
+    /// # Arguments
+    /// * `lhs` - The left-hand operand.
+    /// * `rhs` - The right-hand operand.
+    pub fn holds(&self, lhs: f64, rhs: f64) -> bool {
+        match self {
+            CmpOp::Lt => lhs < rhs,
+            CmpOp::Le => lhs <= rhs,
+            CmpOp::Gt => lhs > rhs,
+            CmpOp::Ge => lhs >= rhs,
+        }
+    }
+}
+
+/// Formats the CmpOp for display.
+impl fmt::Display for CmpOp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.symbol())
+    }
+}
+
+/// Represents a numeric constraint on a value (e.g., "<300").
+#[derive(Clone, PartialEq, Eq, Hash, serde::Serialize)]
+pub struct Constraint {
+    op: CmpOp, // The comparison operator
+    bound: Ind, // The numeric bound
+}
+
+/// Implementation of methods for the Constraint struct.
+impl Constraint {
+    /// Creates a new Constraint from a string.
+    /// # Arguments
+    /// * `s` - The string to parse (e.g., "<300" or ">=50").
+    pub fn new(s: &str) -> Result<Self, String> {
+        let (op, bound) = if let Some(rest) = s.strip_prefix("<=") {
+            (CmpOp::Le, rest)
+        } else if let Some(rest) = s.strip_prefix(">=") {
+            (CmpOp::Ge, rest)
+        } else if let Some(rest) = s.strip_prefix('<') {
+            (CmpOp::Lt, rest)
+        } else if let Some(rest) = s.strip_prefix('>') {
+            (CmpOp::Gt, rest)
+        } else {
+            return Err(format!("Could not parse constraint: {}", s));
+        };
+        let bound = Ind::new(bound.trim())?;
+        if bound.0.as_number().is_none() {
+            return Err(format!("{} is not a numeric bound", bound));
+        }
+        Ok(Constraint { op, bound })
+    }
+
+    /// Checks whether a value satisfies the constraint.
+    /// Non-numeric values never satisfy a constraint.
+    /// # Arguments
+    /// * `value` - The value to check.
+    pub fn admits(&self, value: &Ind) -> bool {
+        match (value.0.as_number(), self.bound.0.as_number()) {
+            (Some(value), Some(bound)) => self.op.holds(value, bound),
+            _ => false,
+        }
+    }
+}
+
+/// Formats the Constraint for display.
+impl fmt::Display for Constraint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}{}", self.op, self.bound)
+    }
+}
+
+/// Splits a trailing constraint (e.g., "<300") off a string.
+/// # Arguments
+/// * `s` - The string to split.
+fn split_constraint(s: &str) -> Result<(&str, Option<Constraint>), String> {
+    match s.find(['<', '>']) {
+        Some(pos) => Ok((&s[..pos], Some(Constraint::new(&s[pos..])?))),
+        None => Ok((s, None)),
+    }
+}
+
+/// Aggregate operations over the values of a predicate (e.g., the cheapest price).
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Aggregate {
+    Min, // Smallest numeric value
+    Max, // Largest numeric value
+    Count, // Number of values
+}
+
+/// Implementation of methods for the Aggregate enum.
+impl Aggregate {
+    /// Creates a new Aggregate from its name.
+    /// # Arguments
+    /// * `name` - The name ("min", "max" or "count").
+    pub fn new(name: &str) -> Option<Self> {
+        match name {
+            "min" => Some(Aggregate::Min),
+            "max" => Some(Aggregate::Max),
+            "count" => Some(Aggregate::Count),
+            _ => None,
+        }
+    }
+
+    /// Returns the name of the aggregate (e.g., "min").
+    pub fn name(&self) -> &'static str {
+        match self {
+            Aggregate::Min => "min",
+            Aggregate::Max => "max",
+            Aggregate::Count => "count",
+        }
+    }
+
+    /// Applies the aggregate to a list of values.
+    /// Min and max ignore non-numeric values and yield nothing for an empty list.
+    /// # Arguments
+    /// * `values` - The values to aggregate.
+    pub fn apply(&self, values: &[Ind]) -> Option<Ind> {
+        let numbers = values.iter().filter_map(|v| v.0.as_number().map(|n| (n, v)));
+        match self {
+            Aggregate::Min => numbers.min_by(|a, b| a.0.total_cmp(&b.0)).map(|(_, v)| v.clone()),
+            Aggregate::Max => numbers.max_by(|a, b| a.0.total_cmp(&b.0)).map(|(_, v)| v.clone()),
+            Aggregate::Count => Ind::new(&values.len().to_string()).ok(),
+        }
+    }
+}
+
+/// Formats the Aggregate for display.
+impl fmt::Display for Aggregate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+/// Splits a leading aggregate (e.g., "min:") off a predicate string.
+/// # Arguments
+/// * `s` - The string to split.
+fn split_aggregate(s: &str) -> (Option<Aggregate>, &str) {
+    match s.split_once(':') {
+        Some((name, rest)) if Aggregate::new(name).is_some() => (Aggregate::new(name), rest),
+        _ => (None, s),
+    }
+}
+
+/// Represents a proposition, combining a predicate with an optional individual and polarity.
+/// A proposition may carry a numeric constraint: without an individual it restricts
+/// the predicate (e.g., "price()<300"), with one it is a result found under that
+/// restriction (e.g., "price(232)<300"). An aggregated proposition records how its
+/// value was computed (e.g., "min:price(232)").
+#[derive(Clone, PartialEq, Eq, Hash, serde::Serialize)]
+pub struct Prop {
+    pred: Pred0, // The predicate
+    ind: Option<Ind>, // Optional individual
+    #[serde(rename = "positive")]
+    yes: bool, // Polarity (true for positive, false for negative)
+    constraint: Option<Constraint>, // Optional numeric constraint
+    aggregate: Option<Aggregate>, // Optional aggregate the value was computed with
+}
+
+/// Implementation of methods for the Prop struct.
+impl Prop {
+    /// Creates a new Prop from a string, parsing polarity, arguments and constraint.
+    /// # Arguments
+    /// * `s` - The string to parse (e.g., "pred(ind)", "-pred", "pred()<300" or "min:pred(ind)").
+    pub fn new(s: &str) -> Result<Self, String> {
+        let (body, constraint) = split_constraint(s)?;
+        let (yes, body) = match body.strip_prefix('-') {
+            Some(rest) => (false, rest),
+            None => (true, body),
+        };
+        let (pred_str, ind_str) = match body.strip_suffix(')').and_then(|b| b.split_once('(')) {
+            Some((pred, ind)) => (pred, Some(ind)),
+            None => (body, None),
+        };
+        let (aggregate, pred_str) = split_aggregate(pred_str);
+        let pred = Pred0::new(pred_str)?;
+        let ind = match ind_str {
+            Some(ind) if !ind.is_empty() => Some(Ind::new(ind)?),
+            _ => None,
+        };
+        Ok(Prop { pred, ind, yes, constraint, aggregate })
+    }
+
+    /// Checks if the proposition only restricts its predicate (e.g., "price()<300").
+    pub fn is_constraint(&self) -> bool {
+        self.ind.is_none() && self.constraint.is_some()
+    }
+
+    /// Returns the name of the predicate.
+    pub fn pred(&self) -> &str {
+        &self.pred.0.content
+    }
+
+    /// Returns the individual, if any.
+    pub fn ind(&self) -> Option<&Ind> {
+        self.ind.as_ref()
+    }
+
+    /// Checks if the proposition is positive.
+    pub fn is_positive(&self) -> bool {
+        self.yes
+    }
+
+    /// Returns the proposition with the opposite polarity.
+    fn negated(&self) -> Prop {
+        Prop { yes: !self.yes, ..self.clone() }
+    }
+}
+
+/// Implements type checking for Prop against a Domain.
+impl Type for Prop {
+    fn typecheck(&self, context: &Domain) -> Result<(), String> {
+        self.pred.typecheck(context)?;
+        if let Some(ind) = &self.ind {
+            ind.typecheck(context)?;
+            if let Some(sort) = context.preds1.get(&self.pred.0.content)
+                && !context.has_sort(&ind.0.content, sort)
+            {
+                return Err("Sort mismatch".to_string());
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Formats the Prop for display, including polarity and arguments.
+impl fmt::Display for Prop {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let prefix = if self.yes { "" } else { "-" };
+        let ind_str = self.ind.as_ref().map_or("", |ind| &ind.0.content);
+        write!(f, "{}", prefix)?;
+        if let Some(aggregate) = &self.aggregate {
+            write!(f, "{}:", aggregate)?;
+        }
+        write!(f, "{}({})", self.pred, ind_str)?;
+        if let Some(constraint) = &self.constraint {
+            write!(f, "{}", constraint)?;
+        }
+        Ok(())
+    }
+}
+
+/// Formats the Prop for debugging, e.g. `Prop(price(232)<300)`.
+impl fmt::Debug for Prop {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Prop({})", self)
+    }
+}
+
+/// Represents a short answer (e.g., "paris" or "-paris").
+#[derive(Clone, serde::Serialize)]
+pub struct ShortAns {
+    ind: Ind, // The individual
+    #[serde(rename = "positive")]
+    yes: bool, // Polarity
+}
+
+/// Implementation of methods for the ShortAns struct.
+impl ShortAns {
+    /// Creates a new ShortAns from a string, parsing polarity.
+    /// # Arguments
+    /// * `s` - The string to parse.
+    pub fn new(s: &str) -> Result<Self, String> {
+        let (yes, ind_str) = match s.strip_prefix('-') {
+            Some(rest) => (false, rest),
+            None => (true, s),
+        };
+        Ok(ShortAns {
+            ind: Ind::new(ind_str)?,
+            yes,
+        })
+    }
+}
+
+/// Implements type checking for ShortAns against a Domain.
+impl Type for ShortAns {
+    fn typecheck(&self, context: &Domain) -> Result<(), String> {
+        self.ind.typecheck(context)
+    }
+}
+
+/// Formats the ShortAns for display.
+impl fmt::Display for ShortAns {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let prefix = if self.yes { "" } else { "-" };
+        write!(f, "{}{}", prefix, self.ind)
+    }
+}
+
+/// Represents a yes/no answer.
+#[derive(Clone, serde::Serialize)]
+pub struct YesNo {
+    yes: bool, // True for "yes", false for "no"
+}
+
+/// Implementation of methods for the YesNo struct.
+impl YesNo {
+    /// Creates a new YesNo from a string.
+    /// # Arguments
+    /// * `s` - The string ("yes" or "no").
+    pub fn new(s: &str) -> Result<Self, String> {
+        match s {
+            "yes" => Ok(YesNo { yes: true }),
+            "no" => Ok(YesNo { yes: false }),
+            _ => Err(format!("Invalid YesNo: {}", s)),
+        }
+    }
+}
+
+/// Implements type checking for YesNo (always valid).
+impl Type for YesNo {
+    fn typecheck(&self, _context: &Domain) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+/// Formats the YesNo for display.
+impl fmt::Display for YesNo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", if self.yes { "yes" } else { "no" })
+    }
+}
+
+/// Represents a conjunction of propositions giving several facts at once
+/// (e.g., "dest_city(paris) ∧ depart_day(today)").
+#[derive(Clone, serde::Serialize)]
+pub struct Conj {
+    #[serde(rename = "props")]
+    conjuncts: Vec<Prop>, // The propositions, in the order they were given
+}
+
+/// Implementation of methods for the Conj struct.
+impl Conj {
+    /// Creates a new Conj from a string, splitting it at "∧" or "&".
+    /// # Arguments
+    /// * `s` - The string to parse.
+    pub fn new(s: &str) -> Result<Self, String> {
+        let conjuncts: Vec<Prop> = s.split(['∧', '&']).map(|p| Prop::new(p.trim())).collect::<Result<_, _>>()?;
+        if conjuncts.len() < 2 {
+            return Err(format!("Not a conjunction: {}", s));
+        }
+        Ok(Conj { conjuncts })
+    }
+
+    /// Returns the propositions of the conjunction.
+    pub fn conjuncts(&self) -> &[Prop] {
+        &self.conjuncts
+    }
+}
+
+/// Implements type checking for Conj against a Domain.
+impl Type for Conj {
+    fn typecheck(&self, context: &Domain) -> Result<(), String> {
+        self.conjuncts.iter().try_for_each(|p| p.typecheck(context))
+    }
+}
+
+/// Formats the Conj for display.
+impl fmt::Display for Conj {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let conjuncts: Vec<String> = self.conjuncts.iter().map(|p| p.to_string()).collect();
+        write!(f, "{}", conjuncts.join(" ∧ "))
+    }
+}
+
+/// Enum representing different types of answers.
+#[derive(Clone, serde::Serialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Ans {
+    Prop(Prop), // A proposition
+    Conj(Conj), // Several propositions at once
+    #[serde(rename = "short")]
+    ShortAns(ShortAns), // A short answer
+    YesNo(YesNo), // A yes/no answer
+    Any, // "any": every answer will do, so the question puts no restriction on lookups
+    Unknown, // "unknown": the user cannot answer yet, so the question is deferred
+}
+
+/// The individual recording that a question was answered with "any".
+pub const ANY: &str = "any";
+
+/// Implementation of methods for the Ans enum.
+impl Ans {
+    /// Creates a new Ans from a string, parsing the appropriate type.
+    /// # Arguments
+    /// * `s` - The string to parse.
+    pub fn new(s: &str) -> Result<Self, String> {
+        if s == "yes" || s == "no" {
+            Ok(Ans::YesNo(YesNo::new(s)?))
+        } else if s == ANY {
+            Ok(Ans::Any)
+        } else if s == "unknown" {
+            Ok(Ans::Unknown)
+        } else if s.contains(['∧', '&']) {
+            Ok(Ans::Conj(Conj::new(s)?))
+        } else if s.contains(['<', '>']) {
+            Ok(Ans::Prop(Prop::new(s)?))
+        } else if !s.contains('(') && !s.contains(')') {
+            Ok(Ans::ShortAns(ShortAns::new(s)?))
+        } else if s.contains('(') && s.ends_with(')') {
+            Ok(Ans::Prop(Prop::new(s)?))
+        } else {
+            Err(format!("Could not parse answer: {}", s))
+        }
+    }
+}
+
+/// Implements type checking for Ans against a Domain.
+impl Type for Ans {
+    fn typecheck(&self, context: &Domain) -> Result<(), String> {
+        match self {
+            Ans::Prop(p) => p.typecheck(context),
+            Ans::Conj(c) => c.typecheck(context),
+            Ans::ShortAns(s) => s.typecheck(context),
+            Ans::YesNo(y) => y.typecheck(context),
+            Ans::Any | Ans::Unknown => Ok(()),
+        }
+    }
+}
+
+/// Formats the Ans for display.
+impl fmt::Display for Ans {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Ans::Prop(p) => write!(f, "{}", p),
+            Ans::Conj(c) => write!(f, "{}", c),
+            Ans::ShortAns(s) => write!(f, "{}", s),
+            Ans::YesNo(y) => write!(f, "{}", y),
+            Ans::Any => write!(f, "{}", ANY),
+            Ans::Unknown => write!(f, "unknown"),
+        }
+    }
+}
+
+/// Represents a "wh" question (e.g., "?x.pred(x)"), optionally restricting
+/// its answers with a numeric constraint (e.g., "?x.price(x)<300") or asking
+/// for an aggregate over them (e.g., "?x.min:price(x)"). The variable is
+/// explicit, and further conditions on it may be conjoined to the predicate
+/// (e.g., "?y.flight(y) ∧ price(y)<300").
+#[derive(Clone, serde::Serialize)]
+pub struct WhQ {
+    #[serde(rename = "variable")]
+    var: String, // The variable asked for (e.g., "x")
+    pred: Pred1, // The predicate
+    constraint: Option<Constraint>, // Optional restriction on answers
+    aggregate: Option<Aggregate>, // Optional aggregate over answers
+    conditions: Vec<Prop>, // Further conditions on the variable (e.g., "price(x)<300")
+}
+
+/// Checks whether a string can name a question variable (e.g., "x" or "flight2").
+/// # Arguments
+/// * `s` - The string to check.
+fn is_variable(s: &str) -> bool {
+    s.chars().next().is_some_and(|c| c.is_ascii_lowercase())
+        && s.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Implementation of methods for the WhQ struct.
+impl WhQ {
+    /// Creates a new WhQ from a string, parsing the variable, predicate,
+    /// constraint and conditions. Conditions are joined with "∧" or "&" and
+    /// constrain the variable (e.g., "price(y)<300"): those on the predicate
+    /// asked about restrict its answers, and those on other predicates the
+    /// database lookups answering the question.
+    /// # Arguments
+    /// * `pred` - The predicate string (e.g., "?x.pred(x)", "?x.pred(x)<300", "?x.min:pred(x)",
+    ///   "?y.flight(y) ∧ price(y)<300" or "pred").
+    pub fn new(pred: &str) -> Result<Self, String> {
+        let mut conjuncts = pred.split(['∧', '&']).map(str::trim);
+        let head = conjuncts.next().unwrap_or_default();
+        let (var, head) = match head.strip_prefix('?').and_then(|h| h.split_once('.')) {
+            Some((var, body)) if is_variable(var) => (var, body),
+            _ => ("x", head),
+        };
+        let (head, constraint) = split_constraint(head)?;
+        let head = head.strip_suffix(&format!("({})", var)).unwrap_or(head);
+        let (aggregate, head) = split_aggregate(head);
+        let conditions = conjuncts
+            .map(|conjunct| {
+                let prop = Prop::new(conjunct)?;
+                match &prop.ind {
+                    Some(ind) if ind.0.content != var => Err(format!("Condition is not about {}: {}", var, conjunct)),
+                    Some(_) if prop.yes && prop.constraint.is_some() && prop.aggregate.is_none() => Ok(prop),
+                    _ => Err(format!("Condition is not a constraint: {}", conjunct)),
+                }
+            })
+            .collect::<Result<_, String>>()?;
+        Ok(WhQ {
+            var: var.to_string(),
+            pred: Pred1::new(head)?,
+            constraint,
+            aggregate,
+            conditions,
+        })
+    }
+
+    /// Returns the question without constraint, aggregate or conditions, asking
+    /// for the variable "x" (e.g., "?x.price(x)").
+    fn base(&self) -> WhQ {
+        WhQ {
+            var: "x".to_string(),
+            pred: self.pred.clone(),
+            constraint: None,
+            aggregate: None,
+            conditions: Vec::new(),
+        }
+    }
+
+    /// Returns the constraints on the predicate asked about: its own and
+    /// those of the conditions on the same predicate.
+    fn constraints(&self) -> Vec<Constraint> {
+        let conditions = self
+            .conditions
+            .iter()
+            .filter(|prop| prop.pred.0 == self.pred.0)
+            .filter_map(|prop| prop.constraint.clone());
+        self.constraint.clone().into_iter().chain(conditions).collect()
+    }
+
+    /// Checks whether an individual is an admissible answer under the constraints.
+    /// # Arguments
+    /// * `ind` - The individual to check.
+    fn admits(&self, ind: &Ind) -> bool {
+        self.constraints().iter().all(|c| c.admits(ind))
+    }
+
+    /// Returns the constraints of the question as propositions restricting
+    /// their predicates (e.g., "price()<300" for "?x.flight(x) ∧ price(x)<300").
+    fn constraint_props(&self) -> Vec<Prop> {
+        let own = self.constraint.clone().map(|constraint| (Pred0(self.pred.0.clone()), constraint));
+        let conditions = self
+            .conditions
+            .iter()
+            .filter_map(|prop| Some((prop.pred.clone(), prop.constraint.clone()?)));
+        own.into_iter()
+            .chain(conditions)
+            .map(|(pred, constraint)| Prop { pred, ind: None, yes: true, constraint: Some(constraint), aggregate: None })
+            .collect()
+    }
+}
+
+/// Implements type checking for WhQ against a Domain.
+impl Type for WhQ {
+    fn typecheck(&self, context: &Domain) -> Result<(), String> {
+        self.pred.typecheck(context)?;
+        for condition in &self.conditions {
+            Pred1(condition.pred.0.clone()).typecheck(context)?;
+        }
+        Ok(())
+    }
+}
+
+/// Formats the WhQ for display.
+impl fmt::Display for WhQ {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "?{}.", self.var)?;
+        if let Some(aggregate) = &self.aggregate {
+            write!(f, "{}:", aggregate)?;
+        }
+        write!(f, "{}({})", self.pred, self.var)?;
+        if let Some(constraint) = &self.constraint {
+            write!(f, "{}", constraint)?;
+        }
+        for condition in &self.conditions {
+            write!(f, " ∧ {}", condition)?;
+        }
+        Ok(())
+    }
+}
+
+/// Represents a yes/no question.
+#[derive(Clone, serde::Serialize)]
+#[allow(clippy::upper_case_acronyms)]
+pub struct YNQ {
+    prop: Prop, // The proposition
+}
+
+/// Implementation of methods for the YNQ struct.
+impl YNQ {
+    /// Creates a new YNQ from a string.
+    /// # Arguments
+    /// * `prop` - The proposition string (e.g., "?pred(ind)").
+    pub fn new(prop: &str) -> Result<Self, String> {
+        let prop = prop.strip_prefix('?').unwrap_or(prop);
+        Ok(YNQ {
+            prop: Prop::new(prop)?,
+        })
+    }
+}
+
+/// Implements type checking for YNQ against a Domain.
+impl Type for YNQ {
+    fn typecheck(&self, context: &Domain) -> Result<(), String> {
+        self.prop.typecheck(context)
+    }
+}
+
+/// Formats the YNQ for display.
+impl fmt::Display for YNQ {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "?{}", self.prop)
+    }
+}
+
+/// Represents an alternative question (multiple yes/no questions).
+#[derive(Clone, serde::Serialize)]
+pub struct AltQ {
+    #[serde(rename = "props", serialize_with = "serialize_alternatives")]
+    ynqs: Vec<YNQ>, // List of yes/no questions
+}
+
+/// Implementation of methods for the AltQ struct.
+impl AltQ {
+    /// Creates a new AltQ from a list of YNQs.
+    /// # Arguments
+    /// * `ynqs` - The list of yes/no questions.
+    pub fn new(ynqs: Vec<YNQ>) -> Self {
+        AltQ { ynqs }
+    }
+}
+
+/// Serializes the alternatives of an AltQ as the list of their propositions.
+/// # Arguments
+/// * `ynqs` - The alternatives.
+/// * `serializer` - The serializer to write to.
+fn serialize_alternatives<S: serde::Serializer>(ynqs: &[YNQ], serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_seq(ynqs.iter().map(|ynq| &ynq.prop))
+}
+
+/// Implements type checking for AltQ against a Domain.
+impl Type for AltQ {
+    fn typecheck(&self, context: &Domain) -> Result<(), String> {
+        for ynq in &self.ynqs {
+            ynq.typecheck(context)?;
+        }
+        Ok(())
+    }
+}
+
+/// Formats the AltQ for display.
+impl fmt::Display for AltQ {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let ynq_strs: Vec<String> = self.ynqs.iter().map(|y| y.to_string()).collect();
+        write!(f, "{{ {} }}", ynq_strs.join(" | "))
+    }
+}
+
+/// Enum representing different types of questions.
+#[derive(Clone, serde::Serialize)]
+#[serde(tag = "type")]
+#[allow(clippy::upper_case_acronyms)]
+pub enum Question {
+    #[serde(rename = "wh")]
+    WhQ(WhQ), // Wh-question
+    #[serde(rename = "yn")]
+    YNQ(YNQ), // Yes/no question
+    #[serde(rename = "alt")]
+    AltQ(AltQ), // Alternative question
+}
+
+/// Implementation of methods for the Question enum.
+impl Question {
+    /// Creates a new Question from a string.
+    /// # Arguments
+    /// * `s` - The string to parse.
+    pub fn new(s: &str) -> Result<Self, String> {
+        let is_whq = s
+            .strip_prefix('?')
+            .and_then(|rest| rest.split_once('.'))
+            .is_some_and(|(var, _)| is_variable(var));
+        if is_whq {
+            Ok(Question::WhQ(WhQ::new(s)?))
+        } else if let Some(prop) = s.strip_prefix('?') {
+            Ok(Question::YNQ(YNQ::new(prop)?))
+        } else {
+            Err(format!("Could not parse question: {}", s))
+        }
+    }
+
+    /// Returns the name of the predicate asked about, if the question has a single one.
+    pub fn pred(&self) -> Option<&str> {
+        match self {
+            Question::WhQ(w) => Some(&w.pred.0.content),
+            Question::YNQ(y) => Some(y.prop.pred()),
+            Question::AltQ(_) => None,
+        }
+    }
+}
+
+/// Implements type checking for Question against a Domain.
+impl Type for Question {
+    fn typecheck(&self, context: &Domain) -> Result<(), String> {
+        match self {
+            Question::WhQ(w) => w.typecheck(context),
+            Question::YNQ(y) => y.typecheck(context),
+            Question::AltQ(a) => a.typecheck(context),
+        }
+    }
+}
+
+/// Formats the Question for display.
+impl fmt::Display for Question {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Question::WhQ(w) => write!(f, "{}", w),
+            Question::YNQ(y) => write!(f, "{}", y),
+            Question::AltQ(a) => write!(f, "{}", a),
+        }
+    }
+}
+
+// Unification
+
+/// The values a question's variables take in a proposition (e.g., x=paris).
+pub type Bindings = HashMap<String, Ind>;
+
+/// Matching of questions against propositions.
+impl Question {
+    /// Returns the variable of a wh-question (e.g., "x" in "?x.price(x)").
+    pub fn variable(&self) -> Option<&str> {
+        match self {
+            Question::WhQ(whq) => Some(&whq.var),
+            _ => None,
+        }
+    }
+
+    /// Unifies the question with a proposition of either polarity, returning
+    /// the bindings of its variables. A wh-question unifies with propositions
+    /// of its predicate and aggregate, binding its variable to their
+    /// individual if they have one (a constraint such as "price()<300" binds
+    /// nothing); a yes/no question unifies with its proposition and an
+    /// alternative question with one of its alternatives, binding nothing.
+    /// # Arguments
+    /// * `prop` - The proposition to match.
+    pub fn unify(&self, prop: &Prop) -> Option<Bindings> {
+        match self {
+            Question::WhQ(whq) => {
+                if prop.pred.0.content != whq.pred.0.content || prop.aggregate != whq.aggregate {
+                    return None;
+                }
+                let variable = self.variable()?.to_string();
+                Some(prop.ind.iter().map(|ind| (variable.clone(), ind.clone())).collect())
+            }
+            Question::YNQ(ynq) => (prop.pred == ynq.prop.pred && prop.ind == ynq.prop.ind).then(Bindings::new),
+            Question::AltQ(altq) => altq.ynqs.iter().any(|ynq| *prop == ynq.prop).then(Bindings::new),
+        }
+    }
+
+    /// Builds the positive proposition the question asks about under some
+    /// bindings (e.g., "price(232)" for "?x.price(x)" with x=232). Alternative
+    /// questions and unbound variables yield nothing.
+    /// # Arguments
+    /// * `bindings` - The values of the variables.
+    pub fn instantiate(&self, bindings: &Bindings) -> Option<Prop> {
+        match self {
+            Question::WhQ(whq) => {
+                let mut prop = whq.pred.apply(bindings.get(self.variable()?)?).ok()?;
+                prop.aggregate = whq.aggregate;
+                Some(prop)
+            }
+            Question::YNQ(ynq) => Some(Prop { yes: true, ..ynq.prop.clone() }),
+            Question::AltQ(_) => None,
+        }
+    }
+}
+
+// Dialogue moves
+
+/// Represents a greeting dialogue move.
+#[derive(Clone, serde::Serialize)]
+pub struct Greet;
+
+/// Implements type checking for Greet (always valid).
+impl Type for Greet {
+    fn typecheck(&self, _context: &Domain) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+/// Formats the Greet for display.
+impl fmt::Display for Greet {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Greet()")
+    }
+}
+
+/// Represents a quit dialogue move.
+#[derive(Clone, serde::Serialize)]
+pub struct Quit;
+
+/// Implements type checking for Quit (always valid).
+impl Type for Quit {
+    fn typecheck(&self, _context: &Domain) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+/// Formats the Quit for display.
+impl fmt::Display for Quit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Quit()")
+    }
+}
+
+/// Represents a move handing the dialogue over to a human agent.
+#[derive(Clone, serde::Serialize)]
+pub struct Escalate;
+
+/// Implements type checking for Escalate (always valid).
+impl Type for Escalate {
+    fn typecheck(&self, _context: &Domain) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+/// Formats the Escalate for display.
+impl fmt::Display for Escalate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Escalate()")
+    }
+}
+
+/// Represents a backchannel or acknowledgement ("okay", "mhm"), telling the
+/// other participant that what they said was heard without taking the floor.
+#[derive(Clone, serde::Serialize)]
+pub struct Acknowledge;
+
+/// Implements type checking for Acknowledge (always valid).
+impl Type for Acknowledge {
+    fn typecheck(&self, _context: &Domain) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+/// Formats the Acknowledge for display.
+impl fmt::Display for Acknowledge {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Acknowledge()")
+    }
+}
+
+/// Represents a dialogue move telling the other participant a fact it did not
+/// ask about (e.g., that a flight was cancelled).
+#[derive(Clone, serde::Serialize)]
+pub struct Inform {
+    #[serde(rename = "prop")]
+    content: Prop, // The fact being told
+}
+
+/// Implementation of methods for the Inform struct.
+impl Inform {
+    /// Creates a new Inform move.
+    /// # Arguments
+    /// * `content` - The fact to tell.
+    pub fn new(content: Prop) -> Self {
+        Inform { content }
+    }
+}
+
+/// Implements type checking for Inform against a Domain.
+impl Type for Inform {
+    fn typecheck(&self, context: &Domain) -> Result<(), String> {
+        self.content.typecheck(context)
+    }
+}
+
+/// Formats the Inform for display.
+impl fmt::Display for Inform {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Inform({})", self.content)
+    }
+}
+
+/// Represents a move of a kind registered by the domain, written with a
+/// "custom:" prefix (e.g., "custom:BookTicket(paris, today)").
+#[derive(Clone, PartialEq, Eq, Debug, serde::Serialize)]
+pub struct Custom {
+    name: String, // The name of the move kind, e.g. "BookTicket"
+    args: Vec<String>, // The arguments, in order
+}
+
+/// Implementation of methods for the Custom struct.
+impl Custom {
+    /// Creates a new Custom move.
+    /// # Arguments
+    /// * `name` - The name of the move kind, capitalized.
+    /// * `args` - The arguments.
+    pub fn new(name: &str, args: &[&str]) -> Result<Self, String> {
+        let valid = name.starts_with(|c: char| c.is_ascii_uppercase())
+            && name.chars().all(|c| c.is_alphanumeric() || c == '_');
+        if !valid {
+            return Err(format!("Invalid custom move name: {}", name));
+        }
+        Ok(Custom { name: name.to_string(), args: args.iter().map(|a| a.to_string()).collect() })
+    }
+
+    /// Parses a custom move from its string form, e.g. "custom:BookTicket(paris, today)".
+    /// # Arguments
+    /// * `s` - The string to parse.
+    fn parse(s: &str) -> Option<Self> {
+        let (name, rest) = s.strip_prefix("custom:")?.split_once('(')?;
+        let body = rest.strip_suffix(')')?;
+        let args: Vec<&str> = split_top_level(body).into_iter().map(unquote).filter(|a| !a.is_empty()).collect();
+        Custom::new(name, &args).ok()
+    }
+
+    /// Returns the name of the move kind.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns the arguments of the move.
+    pub fn args(&self) -> &[String] {
+        &self.args
+    }
+}
+
+/// Implements type checking for Custom against a Domain: the move kind must be registered.
+impl Type for Custom {
+    fn typecheck(&self, context: &Domain) -> Result<(), String> {
+        context
+            .custom_move(&self.name)
+            .map(|_| ())
+            .ok_or(format!("Unknown move kind: {}", self.name))
+    }
+}
+
+/// Formats the Custom move for display.
+impl fmt::Display for Custom {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "custom:{}({})", self.name, self.args.join(", "))
+    }
+}
+
+/// Represents an ask dialogue move.
+#[derive(Clone, serde::Serialize)]
+pub struct Ask {
+    #[serde(rename = "question")]
+    content: Question, // The question being asked
+}
+
+/// Implementation of methods for the Ask struct.
+impl Ask {
+    /// Creates a new Ask move.
+    /// # Arguments
+    /// * `content` - The question to ask.
+    pub fn new(content: Question) -> Self {
+        Ask { content }
+    }
+}
+
+/// Implements type checking for Ask against a Domain.
+impl Type for Ask {
+    fn typecheck(&self, context: &Domain) -> Result<(), String> {
+        self.content.typecheck(context)
+    }
+}
+
+/// Formats the Ask for display.
+impl fmt::Display for Ask {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Ask('{}')", self.content)
+    }
+}
+
+/// Represents an answer dialogue move.
+#[derive(Clone, serde::Serialize)]
+pub struct Answer {
+    #[serde(rename = "answer")]
+    content: Ans, // The answer content
+    support: Vec<Prop>, // Supporting propositions from the context
+}
+
+/// Implementation of methods for the Answer struct.
+impl Answer {
+    /// Creates a new Answer move.
+    /// # Arguments
+    /// * `content` - The answer content.
+    pub fn new(content: Ans) -> Self {
+        Answer { content, support: Vec::new() }
+    }
+
+    /// Creates a new Answer move echoing the propositions it is based on.
+    /// # Arguments
+    /// * `content` - The answer content.
+    /// * `support` - The supporting propositions (e.g., the departure and destination).
+    pub fn with_support(content: Ans, support: Vec<Prop>) -> Self {
+        Answer { content, support }
+    }
+}
+
+/// Implements type checking for Answer against a Domain.
+impl Type for Answer {
+    fn typecheck(&self, context: &Domain) -> Result<(), String> {
+        self.content.typecheck(context)
+    }
+}
+
+/// Formats the Answer for display.
+impl fmt::Display for Answer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.support.is_empty() {
+            return write!(f, "Answer({})", self.content);
+        }
+        let support: Vec<String> = self.support.iter().map(|p| p.to_string()).collect();
+        write!(f, "Answer({}, [{}])", self.content, support.join(", "))
+    }
+}
+
+/// Represents an Information State Update Control Mechanism (ICM).
+#[derive(Clone, serde::Serialize)]
+#[allow(clippy::upper_case_acronyms)]
+pub struct ICM {
+    level: String, // The ICM level (e.g., "per" for perception)
+    polarity: String, // The polarity (e.g., "pos" or "neg")
+    #[serde(rename = "content")]
+    icm_content: Option<String>, // Optional content for the ICM
+}
+
+/// Implementation of methods for the ICM struct.
+impl ICM {
+    /// Creates a new ICM.
+    /// # Arguments
+    /// * `level` - The ICM level.
+    /// * `polarity` - The polarity.
+    /// * `icm_content` - Optional content.
+    pub fn new(level: &str, polarity: &str, icm_content: Option<String>) -> Self {
+        ICM {
+            level: level.to_string(),
+            polarity: polarity.to_string(),
+            icm_content,
+        }
+    }
+}
+
+/// Implements type checking for ICM (always valid).
+impl Type for ICM {
+    fn typecheck(&self, _context: &Domain) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+/// Formats the ICM for display.
+impl fmt::Display for ICM {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut s = format!("icm:{}*{}", self.level, self.polarity);
+        if let Some(content) = &self.icm_content {
+            s.push_str(&format!(":'{}'", content));
+        }
+        write!(f, "{}", s)
+    }
+}
+
+/// Enum representing the dialogue moves exchanged between the participants.
+/// Serializes as a JSON object tagged with its "move" (e.g., "ask").
+#[derive(Clone, serde::Serialize)]
+#[serde(tag = "move", rename_all = "lowercase")]
+#[allow(clippy::upper_case_acronyms)]
+pub enum Move {
+    Greet(Greet), // A greeting
+    Quit(Quit), // A request to end the dialogue
+    Escalate(Escalate), // A handover to a human agent
+    Acknowledge(Acknowledge), // A backchannel or acknowledgement
+    Ask(Ask), // A question
+    Answer(Answer), // An answer
+    Inform(Inform), // An unsolicited fact
+    ICM(ICM), // A feedback move
+    Custom(Custom), // A move of a kind registered by the domain
+}
+
+/// Implementation of methods for the Move enum.
+impl Move {
+    /// Creates a new Move from its string form.
+    /// # Arguments
+    /// * `s` - The string to parse (e.g., "Ask('?x.how(x)')", "Answer(price(232), [dest_city(paris)])"
+    ///   or "icm:sem*neg").
+    pub fn new(s: &str) -> Result<Self, String> {
+        let s = s.trim();
+        if s == "Greet()" {
+            Ok(Move::Greet(Greet))
+        } else if s == "Quit()" {
+            Ok(Move::Quit(Quit))
+        } else if s == "Escalate()" {
+            Ok(Move::Escalate(Escalate))
+        } else if s == "Acknowledge()" {
+            Ok(Move::Acknowledge(Acknowledge))
+        } else if let Some(arg) = construct_arg(s, "Ask") {
+            Ok(Move::Ask(Ask::new(Question::new(arg)?)))
+        } else if let Some(body) = construct_body(s, "Answer") {
+            match split_top_level(body)[..] {
+                [content] => Ok(Move::Answer(Answer::new(Ans::new(unquote(content))?))),
+                [content, support] => {
+                    let support = list_items(support)?.into_iter().map(Prop::new).collect::<Result<_, _>>()?;
+                    Ok(Move::Answer(Answer::with_support(Ans::new(unquote(content))?, support)))
+                }
+                _ => Err(format!("Could not parse move: {}", s)),
+            }
+        } else if let Some(arg) = construct_arg(s, "Inform") {
+            Ok(Move::Inform(Inform::new(Prop::new(arg)?)))
+        } else if let Some(rest) = s.strip_prefix("icm:") {
+            let (kind, content) = match rest.split_once(':') {
+                Some((kind, content)) => (kind, Some(unquote(content).to_string())),
+                None => (rest, None),
+            };
+            let (level, polarity) = kind
+                .split_once('*')
+                .ok_or(format!("Could not parse ICM: {}", s))?;
+            Ok(Move::ICM(ICM::new(level, polarity, content)))
+        } else if let Some(custom) = Custom::parse(s) {
+            Ok(Move::Custom(custom))
+        } else {
+            Err(format!("Could not parse move: {}", s))
+        }
+    }
+}
+
+/// Implements type checking for Move against a Domain.
+impl Type for Move {
+    fn typecheck(&self, context: &Domain) -> Result<(), String> {
+        match self {
+            Move::Greet(g) => g.typecheck(context),
+            Move::Quit(q) => q.typecheck(context),
+            Move::Escalate(e) => e.typecheck(context),
+            Move::Acknowledge(a) => a.typecheck(context),
+            Move::Ask(a) => a.typecheck(context),
+            Move::Answer(a) => a.typecheck(context),
+            Move::Inform(i) => i.typecheck(context),
+            Move::ICM(i) => i.typecheck(context),
+            Move::Custom(c) => c.typecheck(context),
+        }
+    }
+}
+
+/// Formats the Move for display.
+impl fmt::Display for Move {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Move::Greet(g) => write!(f, "{}", g),
+            Move::Quit(q) => write!(f, "{}", q),
+            Move::Escalate(e) => write!(f, "{}", e),
+            Move::Acknowledge(a) => write!(f, "{}", a),
+            Move::Ask(a) => write!(f, "{}", a),
+            Move::Answer(a) => write!(f, "{}", a),
+            Move::Inform(i) => write!(f, "{}", i),
+            Move::ICM(i) => write!(f, "{}", i),
+            Move::Custom(c) => write!(f, "{}", c),
+        }
+    }
+}
+
+/// A dialogue move with the circumstances in which it was made.
+#[derive(Clone)]
+pub struct TaggedMove {
+    pub mv: Move, // The dialogue move
+    pub speaker: Speaker, // Who made it
+    pub turn_id: usize, // The turn it was made in, counting from 1
+    pub timestamp: SystemTime, // When it was made
+    pub confidence: f64, // How sure the interpretation is, from 0 to 1
+}
+
+/// Implementation of methods for the TaggedMove struct.
+impl TaggedMove {
+    /// Creates a new TaggedMove made now, with full confidence.
+    /// # Arguments
+    /// * `mv` - The dialogue move.
+    /// * `speaker` - Who made it.
+    /// * `turn_id` - The turn it was made in.
+    pub fn new(mv: Move, speaker: Speaker, turn_id: usize) -> Self {
+        TaggedMove {
+            mv,
+            speaker,
+            turn_id,
+            timestamp: SystemTime::now(),
+            confidence: 1.0,
+        }
+    }
+
+    /// Sets how sure the interpretation of the move is.
+    /// # Arguments
+    /// * `confidence` - The confidence, from 0 to 1.
+    pub fn with_confidence(mut self, confidence: f64) -> Self {
+        self.confidence = confidence;
+        self
+    }
+}
+
+/// Formats the TaggedMove for display.
+impl fmt::Display for TaggedMove {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.mv)
+    }
+}
+
+// Canonical forms
+
+/// Returns propositions sorted by their string form, without duplicates.
+/// # Arguments
+/// * `props` - The propositions to sort.
+fn sorted_props(props: &[Prop]) -> Vec<Prop> {
+    let mut props = props.to_vec();
+    props.sort_by_cached_key(|p| p.to_string());
+    props.dedup();
+    props
+}
+
+/// Normal forms of questions for logs, transcripts and comparisons.
+impl Question {
+    /// Returns the question with its unordered parts sorted: the conditions
+    /// of a wh-question and the alternatives of an alternative question.
+    pub fn canonical(&self) -> Question {
+        match self {
+            Question::WhQ(whq) => Question::WhQ(WhQ { conditions: sorted_props(&whq.conditions), ..whq.clone() }),
+            Question::YNQ(ynq) => Question::YNQ(ynq.clone()),
+            Question::AltQ(altq) => {
+                let mut ynqs = altq.ynqs.clone();
+                ynqs.sort_by_cached_key(|ynq| ynq.to_string());
+                ynqs.dedup_by(|a, b| a.prop == b.prop);
+                Question::AltQ(AltQ { ynqs })
+            }
+        }
+    }
+
+    /// Returns the canonical string of the question, which Question::new
+    /// parses back and which is the same for questions differing only in
+    /// the order of their conditions or alternatives.
+    pub fn to_canonical_string(&self) -> String {
+        self.canonical().to_string()
+    }
+}
+
+/// Normal forms of moves for logs, transcripts and comparisons.
+impl Move {
+    /// Returns the move with its unordered parts sorted: the question of an
+    /// ask, the conjuncts of a conjunctive answer and the support of an answer.
+    pub fn canonical(&self) -> Move {
+        match self {
+            Move::Ask(ask) => Move::Ask(Ask { content: ask.content.canonical() }),
+            Move::Answer(answer) => {
+                let content = match &answer.content {
+                    Ans::Conj(conj) => Ans::Conj(Conj { conjuncts: sorted_props(&conj.conjuncts) }),
+                    content => content.clone(),
+                };
+                Move::Answer(Answer { content, support: sorted_props(&answer.support) })
+            }
+            mv => mv.clone(),
+        }
+    }
+
+    /// Returns the canonical string of the move, which Move::new parses back
+    /// and which is the same for moves differing only in the order of their
+    /// unordered parts.
+    pub fn to_canonical_string(&self) -> String {
+        self.canonical().to_string()
+    }
+}
+
+// Plan constructors
+
+/// Represents a respond plan constructor.
+#[derive(Clone)]
+pub struct Respond {
+    content: Question, // The question to respond to
+}
+
+/// Implementation of methods for the Respond struct.
+impl Respond {
+    /// Creates a new Respond plan.
+    /// # Arguments
+    /// * `content` - The question to respond to.
+    pub fn new(content: Question) -> Self {
+        Respond { content }
+    }
+}
+
+/// Implements type checking for Respond against a Domain.
+impl Type for Respond {
+    fn typecheck(&self, context: &Domain) -> Result<(), String> {
+        self.content.typecheck(context)
+    }
+}
+
+/// Formats the Respond for display.
+impl fmt::Display for Respond {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Respond('{}')", self.content)
+    }
+}
+
+/// Represents a consult database plan constructor.
+#[derive(Clone)]
+pub struct ConsultDB {
+    content: Question, // The question to consult
+}
+
+/// Implementation of methods for the ConsultDB struct.
+impl ConsultDB {
+    /// Creates a new ConsultDB plan.
+    /// # Arguments
+    /// * `content` - The question to consult.
+    pub fn new(content: Question) -> Self {
+        ConsultDB { content }
+    }
+}
+
+/// Implements type checking for ConsultDB against a Domain.
+impl Type for ConsultDB {
+    fn typecheck(&self, context: &Domain) -> Result<(), String> {
+        self.content.typecheck(context)
+    }
+}
+
+/// Formats the ConsultDB for display.
+impl fmt::Display for ConsultDB {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "ConsultDB('{}')", self.content)
+    }
+}
+
+/// Represents a findout plan constructor.
+#[derive(Clone)]
+pub struct Findout {
+    content: Question, // The question to find out
+}
+
+/// Implementation of methods for the Findout struct.
+impl Findout {
+    /// Creates a new Findout plan.
+    /// # Arguments
+    /// * `content` - The question to find out.
+    pub fn new(content: Question) -> Self {
+        Findout { content }
+    }
+}
+
+/// Implements type checking for Findout against a Domain.
+impl Type for Findout {
+    fn typecheck(&self, context: &Domain) -> Result<(), String> {
+        self.content.typecheck(context)
+    }
+}
+
+/// Formats the Findout for display.
+impl fmt::Display for Findout {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Findout('{}')", self.content)
+    }
+}
+
+/// Represents a raise plan constructor.
+#[derive(Clone)]
+pub struct Raise {
+    content: Question, // The question to raise
+}
+
+/// Implementation of methods for the Raise struct.
+impl Raise {
+    /// Creates a new Raise plan.
+    /// # Arguments
+    /// * `content` - The question to raise.
+    pub fn new(content: Question) -> Self {
+        Raise { content }
+    }
+}
+
+/// Implements type checking for Raise against a Domain.
+impl Type for Raise {
+    fn typecheck(&self, context: &Domain) -> Result<(), String> {
+        self.content.typecheck(context)
+    }
+}
+
+/// Formats the Raise for display.
+impl fmt::Display for Raise {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Raise('{}')", self.content)
+    }
+}
+
+/// Represents a conditional plan constructor.
+#[derive(Clone)]
+pub struct If {
+    cond: Question, // The condition question
+    iftrue: Vec<String>, // Plans if condition is true
+    iffalse: Vec<String>, // Plans if condition is false
+}
+
+/// Implementation of methods for the If struct.
+impl If {
+    /// Creates a new If plan.
+    /// # Arguments
+    /// * `cond` - The condition question.
+    /// * `iftrue` - Plans to execute if true.
+    /// * `iffalse` - Plans to execute if false.
+    pub fn new(cond: Question, iftrue: Vec<String>, iffalse: Vec<String>) -> Self {
+        If { cond, iftrue, iffalse }
+    }
+}
+
+/// Implements type checking for If against a Domain.
+impl Type for If {
+    fn typecheck(&self, context: &Domain) -> Result<(), String> {
+        self.cond.typecheck(context)?;
+        Ok(())
+    }
+}
+
+/// Formats the If for display.
+impl fmt::Display for If {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "If('{}', [{}], [{}])", self.cond, self.iftrue.join(", "), self.iffalse.join(", "))
+    }
+}
+
+/// Represents a plan constructor calling a named subplan of the domain. The
+/// constructs of the subplan are carried out in its place, after which the
+/// calling plan goes on. Subplans may not call themselves, directly or
+/// through other subplans.
+///
+/// The findouts of a subplan are not local to the call: like those of any
+/// plan, they are resolved by the shared commitments, so an answer the user
+/// gave before the call (e.g., the card in "to paris with visa") is not asked
+/// for again, and the caller can consult the database with the answers the
+/// subplan collected. Scoping them to the call would undo both.
+#[derive(Clone)]
+pub struct CallPlan {
+    name: String, // The name of the subplan (e.g., "collect_payment")
+}
+
+/// Implementation of methods for the CallPlan struct.
+impl CallPlan {
+    /// Creates a new CallPlan plan.
+    /// # Arguments
+    /// * `name` - The name of the subplan.
+    pub fn new(name: &str) -> Result<Self, String> {
+        if name.is_empty() || !name.chars().all(|c| c.is_alphanumeric() || c == '_') {
+            return Err(format!("Invalid subplan name: {}", name));
+        }
+        Ok(CallPlan { name: name.to_string() })
+    }
+}
+
+/// Implements type checking for CallPlan against a Domain.
+impl Type for CallPlan {
+    fn typecheck(&self, context: &Domain) -> Result<(), String> {
+        if !context.subplans.contains_key(&self.name) {
+            return Err(format!("Unknown subplan {}", self.name));
+        }
+        Ok(())
+    }
+}
+
+/// Formats the CallPlan for display.
+impl fmt::Display for CallPlan {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "CallPlan('{}')", self.name)
+    }
+}
+
+/// Represents a plan constructor holding recovery constructs. It is skipped
+/// when the plan reaches it; when a construct before it in the plan fails
+/// (e.g., ConsultDB finds nothing), the constructs up to it are dropped and
+/// its recovery constructs carried out instead.
+#[derive(Clone)]
+pub struct OnFailure {
+    recovery: Vec<String>, // Plans to execute if a construct before it fails
+}
+
+/// Implementation of methods for the OnFailure struct.
+impl OnFailure {
+    /// Creates a new OnFailure plan.
+    /// # Arguments
+    /// * `recovery` - Plans to execute if a construct before it fails.
+    pub fn new(recovery: Vec<String>) -> Self {
+        OnFailure { recovery }
+    }
+}
+
+/// Implements type checking for OnFailure against a Domain.
+impl Type for OnFailure {
+    fn typecheck(&self, _context: &Domain) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+/// Formats the OnFailure for display.
+impl fmt::Display for OnFailure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "OnFailure([{}])", self.recovery.join(", "))
+    }
+}
+
+/// Trait for plan constructors.
+pub trait PlanConstructor: Type + fmt::Display + Clone {}
+
+impl PlanConstructor for Respond {}
+impl PlanConstructor for ConsultDB {}
+impl PlanConstructor for Findout {}
+impl PlanConstructor for Raise {}
+impl PlanConstructor for If {}
+impl PlanConstructor for CallPlan {}
+impl PlanConstructor for OnFailure {}
+
+/// Enum representing a plan constructor parsed from its string form.
+#[derive(Clone)]
+pub enum PlanItem {
+    Respond(Respond), // Respond to a question
+    ConsultDB(ConsultDB), // Consult the database
+    Findout(Findout), // Find out the answer to a question
+    Raise(Raise), // Raise a question
+    If(If), // Conditional plan
+    CallPlan(CallPlan), // Call a subplan
+    OnFailure(OnFailure), // Recover from a failing construct
+}
+
+/// Implementation of methods for the PlanItem enum.
+impl PlanItem {
+    /// Creates a new PlanItem from its string form.
+    /// # Arguments
+    /// * `s` - The string to parse (e.g., "Findout('?x.how(x)')",
+    ///   "If('?return()', ['Findout(?x.return_day(x))'], [])" or "CallPlan('collect_payment')").
+    pub fn new(s: &str) -> Result<Self, String> {
+        let s = s.trim();
+        if let Some(arg) = construct_arg(s, "Respond") {
+            Ok(PlanItem::Respond(Respond::new(Question::new(arg)?)))
+        } else if let Some(arg) = construct_arg(s, "ConsultDB") {
+            Ok(PlanItem::ConsultDB(ConsultDB::new(Question::new(arg)?)))
+        } else if let Some(arg) = construct_arg(s, "Findout") {
+            Ok(PlanItem::Findout(Findout::new(Question::new(arg)?)))
+        } else if let Some(arg) = construct_arg(s, "Raise") {
+            Ok(PlanItem::Raise(Raise::new(Question::new(arg)?)))
+        } else if let Some(body) = construct_body(s, "If") {
+            let args = split_top_level(body);
+            if args.len() != 3 {
+                return Err(format!("Could not parse plan construct: {}", s));
+            }
+            let branch = |arg: &str| -> Result<Vec<String>, String> {
+                Ok(list_items(arg)?.into_iter().map(|i| unquote(i).to_string()).collect())
+            };
+            Ok(PlanItem::If(If::new(Question::new(unquote(args[0]))?, branch(args[1])?, branch(args[2])?)))
+        } else if let Some(arg) = construct_arg(s, "CallPlan") {
+            Ok(PlanItem::CallPlan(CallPlan::new(arg)?))
+        } else if let Some(body) = construct_body(s, "OnFailure") {
+            let recovery = list_items(body)?.into_iter().map(|i| unquote(i).to_string()).collect();
+            Ok(PlanItem::OnFailure(OnFailure::new(recovery)))
+        } else {
+            Err(format!("Could not parse plan construct: {}", s))
+        }
+    }
+}
+
+/// An entry of the agenda: a dialogue move the system has decided to make, or
+/// a plan construct it is about to carry out.
+#[derive(Clone)]
+pub enum AgendaItem {
+    Move(Move), // A move waiting to be selected
+    Construct(PlanItem), // A plan construct being carried out
+}
+
+/// Implementation of methods for the AgendaItem enum.
+impl AgendaItem {
+    /// Creates a new AgendaItem from its string form.
+    /// # Arguments
+    /// * `s` - The string to parse (e.g., "icm:sem*neg" or "Findout('?x.how(x)')").
+    pub fn new(s: &str) -> Result<Self, String> {
+        match PlanItem::new(s) {
+            Ok(construct) => Ok(AgendaItem::Construct(construct)),
+            Err(_) => Move::new(s)
+                .map(AgendaItem::Move)
+                .map_err(|_| format!("Could not parse agenda item: {}", s)),
+        }
+    }
+}
+
+/// Formats the AgendaItem for display.
+impl fmt::Display for AgendaItem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AgendaItem::Move(mv) => write!(f, "{}", mv),
+            AgendaItem::Construct(construct) => write!(f, "{}", construct),
+        }
+    }
+}
+
+/// Parses the constructs of a plan, followed by those of the branches of its
+/// If constructs, of its recovery constructs and of the subplans it calls,
+/// each subplan once. Constructs that cannot be parsed are skipped.
+/// # Arguments
+/// * `plan` - The plan constructors, first to execute first.
+/// * `subplans` - The subplans of the domain by name.
+fn flatten_plan<I>(plan: I, subplans: &HashMap<String, Arc<[String]>>) -> Vec<PlanItem>
+where
+    I: IntoIterator<Item = String>,
+{
+    let mut items: VecDeque<String> = plan.into_iter().collect();
+    let mut flat = Vec::new();
+    let mut called = HashSet::new();
+    while let Some(item) = items.pop_front() {
+        if let Ok(item) = PlanItem::new(&item) {
+            match &item {
+                PlanItem::If(cond) => items.extend(cond.iftrue.iter().chain(&cond.iffalse).cloned()),
+                PlanItem::OnFailure(on_failure) => items.extend(on_failure.recovery.iter().cloned()),
+                PlanItem::CallPlan(call) if called.insert(call.name.clone()) => {
+                    items.extend(subplans.get(&call.name).into_iter().flat_map(|plan| plan.iter().cloned()));
+                }
+                _ => {}
+            }
+            flat.push(item);
+        }
+    }
+    flat
+}
+
+/// Returns the constructs a plan is expanded to with the branches they lead to
+/// (e.g., ("then", the constructs of the true branch) for an If). Only If,
+/// OnFailure and CallPlan constructs lead to others.
+/// # Arguments
+/// * `construct` - The plan construct.
+/// * `subplans` - The subplans of the domain by name.
+fn plan_branches(construct: &str, subplans: &HashMap<String, Arc<[String]>>) -> Vec<(&'static str, Vec<String>)> {
+    match PlanItem::new(construct) {
+        Ok(PlanItem::If(cond)) => vec![("then", cond.iftrue), ("else", cond.iffalse)],
+        Ok(PlanItem::OnFailure(on_failure)) => vec![("recover", on_failure.recovery)],
+        Ok(PlanItem::CallPlan(call)) => {
+            subplans.get(&call.name).map(|plan| ("call", plan.to_vec())).into_iter().collect()
+        }
+        _ => Vec::new(),
+    }
+}
+
+/// Lists the constructs of a plan with their positions, followed by those of
+/// the branches, recoveries and subplans each leads to. Constructs that cannot
+/// be parsed are skipped.
+/// # Arguments
+/// * `plan` - The plan constructs, first to execute first.
+/// * `subplans` - The subplans of the domain by name.
+/// * `prefix` - The position of the construct the plan belongs to, empty for a plan of the domain.
+/// * `positions` - The constructs found so far, with their positions (e.g., ["4", "then", "0"]).
+fn plan_positions(
+    plan: &[String],
+    subplans: &HashMap<String, Arc<[String]>>,
+    prefix: &[String],
+    positions: &mut Vec<(Vec<String>, String)>,
+) {
+    for (index, construct) in plan.iter().enumerate() {
+        let Ok(item) = PlanItem::new(construct) else {
+            continue;
+        };
+        let mut position = prefix.to_vec();
+        position.push(index.to_string());
+        positions.push((position.clone(), item.to_string()));
+        for (label, branch) in plan_branches(construct, subplans) {
+            let mut prefix = position.clone();
+            prefix.push(label.to_string());
+            plan_positions(&branch, subplans, &prefix, positions);
+        }
+    }
+}
+
+/// Returns the position in a plan of the next construct to carry out, given
+/// the constructs left: those following it in the plan, preceded by what
+/// remains of the branch, recovery or subplan the construct before them led to.
+/// # Arguments
+/// * `plan` - The plan constructs, first to execute first.
+/// * `remaining` - The constructs left to carry out, next first.
+/// * `subplans` - The subplans of the domain by name.
+fn plan_position(
+    plan: &[String],
+    remaining: &[String],
+    subplans: &HashMap<String, Arc<[String]>>,
+) -> Option<Vec<String>> {
+    for index in 0..plan.len() {
+        let tail = &plan[index..];
+        let Some(expanded) = remaining.len().checked_sub(tail.len()) else {
+            continue;
+        };
+        if remaining[expanded..] != *tail {
+            continue;
+        }
+        if expanded == 0 {
+            return Some(vec![index.to_string()]);
+        }
+        // The constructs before the tail come from the construct preceding it
+        let Some(parent) = index.checked_sub(1) else {
+            continue;
+        };
+        for (label, branch) in plan_branches(&plan[parent], subplans) {
+            if let Some(mut position) = plan_position(&branch, &remaining[..expanded], subplans) {
+                position.splice(0..0, [parent.to_string(), label.to_string()]);
+                return Some(position);
+            }
+        }
+    }
+    // Only constructs of what the last construct of the plan led to are left
+    let parent = plan.len().checked_sub(1)?;
+    plan_branches(&plan[parent], subplans).into_iter().find_map(|(label, branch)| {
+        let mut position = plan_position(&branch, remaining, subplans)?;
+        position.splice(0..0, [parent.to_string(), label.to_string()]);
+        Some(position)
+    })
+}
+
+/// Checks that none of the given subplans calls itself, directly or through
+/// other subplans.
+/// # Arguments
+/// * `subplans` - The subplans of the domain by name.
+/// * `names` - The names of the subplans to check.
+fn check_subplan_cycles<'a, I>(subplans: &HashMap<String, Arc<[String]>>, names: I) -> Result<(), String>
+where
+    I: IntoIterator<Item = &'a str>,
+{
+    for name in names {
+        let plan = subplans.get(name).into_iter().flat_map(|plan| plan.iter().cloned());
+        let recursive = flatten_plan(plan, subplans)
+            .iter()
+            .any(|item| matches!(item, PlanItem::CallPlan(call) if call.name == name));
+        if recursive {
+            return Err(format!("Subplan {} calls itself", name));
+        }
+    }
+    Ok(())
+}
+
+/// Formats the PlanItem for display.
+impl fmt::Display for PlanItem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PlanItem::Respond(r) => write!(f, "{}", r),
+            PlanItem::ConsultDB(c) => write!(f, "{}", c),
+            PlanItem::Findout(fo) => write!(f, "{}", fo),
+            PlanItem::Raise(r) => write!(f, "{}", r),
+            PlanItem::If(i) => write!(f, "{}", i),
+            PlanItem::CallPlan(c) => write!(f, "{}", c),
+            PlanItem::OnFailure(o) => write!(f, "{}", o),
+        }
+    }
+}
+
+// Dialogue Manager
+
+/// Trait for managing dialogue flow and state. A dialogue is a series of
+/// rounds in which the system and then the user take a turn, each turn
+/// passing through phases that implementations can override one by one:
+/// the system selects its next moves, generates and outputs them; the user
+/// input is read and interpreted; after each turn the information state is
+/// updated with the latest moves. Phases failing with an error end the
+/// dialogue; errors a manager can recover from should be handled within the
+/// phase. IBISController implements the trait with the issue-based approach.
+pub trait DialogueManager {
+    /// Logs a trace message.
+    /// # Arguments
+    /// * `message` - The message to log.
+    fn trace(&self, message: &str) {
+        println!("{{{}}}", message);
+    }
+
+    /// Runs the dialogue manager.
+    fn run(&mut self) -> Result<(), TurnError> {
+        self.reset();
+        self.control()
+    }
+
+    /// Resets the dialogue state.
+    fn reset(&mut self);
+
+    /// Returns whether the dialogue goes on, i.e. neither speaker has ended it.
+    fn is_running(&self) -> bool;
+
+    /// Selects the moves the system makes next from the information state.
+    /// Selecting no moves passes the turn to the user.
+    fn select(&mut self) -> Result<(), String>;
+
+    /// Generates the output of the selected moves.
+    fn generate(&mut self) -> Result<(), String>;
+
+    /// Outputs the generated text to the user and makes the selected moves
+    /// the latest moves of the system.
+    fn output(&mut self) -> Result<(), String>;
+
+    /// Reads the next user input. Ends the dialogue if there is no more input.
+    fn input(&mut self) -> Result<(), String>;
+
+    /// Interprets the user input into the latest moves of the user. Input
+    /// that is not understood leaves no latest moves.
+    fn interpret(&mut self);
+
+    /// Updates the information state with the latest moves of either speaker.
+    /// Errors are recovered within the update, e.g. by rolling back the state.
+    fn update(&mut self);
+
+    /// Takes one round of turns: the system's, then the user's. Returns
+    /// whether the dialogue goes on.
+    fn exchange(&mut self) -> Result<bool, String> {
+        if !self.is_running() {
+            return Ok(false);
+        }
+        self.select()?;
+        self.generate()?;
+        self.output()?;
+        self.update();
+        if !self.is_running() {
+            return Ok(false);
+        }
+        self.input()?;
+        self.interpret();
+        self.update();
+        Ok(true)
+    }
+
+    /// Reports an error ending the dialogue and returns it. Managers counting
+    /// turns override it to record the turn of the error.
+    /// # Arguments
+    /// * `message` - What went wrong.
+    fn report_error(&mut self, message: String) -> TurnError {
+        self.trace(&format!("turn_error: {}", message));
+        TurnError { turn: 0, message }
+    }
+
+    /// Controls the dialogue flow, returning the error that ended it, if any.
+    /// By default rounds of turns are taken until the dialogue ends.
+    fn control(&mut self) -> Result<(), TurnError> {
+        while self.exchange().map_err(|message| self.report_error(message))? {}
+        Ok(())
+    }
+
+    /// Prints the current dialogue state.
+    fn print_state(&self);
+}
+
+// Kernel
+
+/// A module of the dialogue system, run by the kernel when the control
+/// algorithm names it (e.g., the interpretation module). Modules work on the
+/// dialogue through the controller, typically through one of the phases of
+/// the DialogueManager trait, and may wrap or replace the standard ones.
+pub trait Module {
+    /// Runs the module. An error ends the dialogue.
+    /// # Arguments
+    /// * `controller` - The dialogue to work on.
+    fn run(&self, controller: &mut IBISController) -> Result<(), String>;
+}
+
+/// Any function working on the dialogue can be used as a module
+impl<F> Module for F
+where
+    F: Fn(&mut IBISController) -> Result<(), String>,
+{
+    fn run(&self, controller: &mut IBISController) -> Result<(), String> {
+        self(controller)
+    }
+}
+
+/// A condition the control algorithm can branch on.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ControlCondition {
+    NextMoves, // The system has selected moves to make
+    Ended, // The program state is QUIT
+    Paused, // The host application paused the dialogue
+    LimitExceeded, // A limit of the session was exceeded
+}
+
+/// A step of the control algorithm of a kernel.
+#[derive(Clone, PartialEq, Debug)]
+pub enum ControlStep {
+    Module(String), // Runs the module of the name
+    If(ControlCondition, Vec<ControlStep>), // Runs the steps if the condition holds
+    Repeat(Vec<ControlStep>), // Runs the steps over and over until a Stop
+    Stop, // Stops the control algorithm, ending or pausing the session
+}
+
+/// Coordinates the modules of a dialogue system with a declared control
+/// algorithm, as the kernel of TrindiKit does. The algorithm names the
+/// modules to run and when; modules can be replaced or rearranged without
+/// touching the controller.
+#[derive(Clone)]
+pub struct Kernel {
+    modules: HashMap<String, Rc<dyn Module>>, // Modules by name
+    algorithm: Vec<ControlStep>, // The control algorithm
+}
+
+impl Kernel {
+    /// Creates a kernel without modules and with an empty control algorithm.
+    pub fn new() -> Self {
+        Kernel {
+            modules: HashMap::new(),
+            algorithm: Vec::new(),
+        }
+    }
+
+    /// Creates the kernel of the IBIS controller. Its modules are "events"
+    /// (handles the events of the host application), "select", "generate",
+    /// "output", "input", "interpret", "update" (which also prints the
+    /// state), "limits" (ends the dialogue when a limit is exceeded),
+    /// "yield_turn" (gives the turn to the user) and "take_turn" (takes it
+    /// back once the user has spoken). Its algorithm,
+    /// STANDARD_CONTROL_ALGORITHM, repeats a round in which the system speaks
+    /// if it has selected anything to say, then the user, until the dialogue
+    /// ends, is paused or exceeds a limit.
+    pub fn standard() -> Self {
+        Kernel::new()
+            .with_module("events", |c: &mut IBISController| {
+                c.handle_system_events();
+                Ok(())
+            })
+            .with_module("select", |c: &mut IBISController| c.select())
+            .with_module("generate", |c: &mut IBISController| c.generate())
+            .with_module("output", |c: &mut IBISController| c.output())
+            .with_module("input", |c: &mut IBISController| c.input())
+            .with_module("interpret", |c: &mut IBISController| {
+                c.interpret();
+                Ok(())
+            })
+            .with_module("update", |c: &mut IBISController| {
+                c.update();
+                c.print_state();
+                Ok(())
+            })
+            .with_module("limits", |c: &mut IBISController| c.enforce_limits().map(|_| ()))
+            .with_module("yield_turn", |c: &mut IBISController| c.yield_turn())
+            .with_module("take_turn", |c: &mut IBISController| c.take_turn())
+            .with_algorithm(Kernel::parse_algorithm(STANDARD_CONTROL_ALGORITHM).unwrap())
+    }
+
+    /// Adds a module, replacing any module of the same name.
+    /// # Arguments
+    /// * `name` - The name the control algorithm refers to it by.
+    /// * `module` - The module.
+    pub fn with_module(mut self, name: &str, module: impl Module + 'static) -> Self {
+        self.modules.insert(name.to_string(), Rc::new(module));
+        self
+    }
+
+    /// Sets the control algorithm.
+    /// # Arguments
+    /// * `algorithm` - The steps, run in order.
+    pub fn with_algorithm(mut self, algorithm: Vec<ControlStep>) -> Self {
+        self.algorithm = algorithm;
+        self
+    }
+
+    /// Sets the control algorithm written in the control language, checking
+    /// that it only names modules of the kernel.
+    /// # Arguments
+    /// * `text` - The algorithm (e.g., "repeat [input, interpret, update] until program_state == quit").
+    pub fn with_algorithm_text(self, text: &str) -> Result<Self, String> {
+        let kernel = self.with_algorithm(Kernel::parse_algorithm(text)?);
+        kernel.validate()?;
+        Ok(kernel)
+    }
+
+    /// Sets the control algorithm written in the control language in a file,
+    /// so that it can be changed without recompiling.
+    /// # Arguments
+    /// * `path` - The path of the file.
+    pub fn with_algorithm_file(self, path: &str) -> Result<Self, String> {
+        let text = std::fs::read_to_string(path).map_err(|e| format!("Could not read {}: {}", path, e))?;
+        self.with_algorithm_text(&text)
+    }
+
+    /// Parses a control algorithm written in the control language: steps
+    /// separated by commas, each of them a module name, `stop`,
+    /// `if <condition> then [<steps>]` or `repeat [<steps>]`, optionally
+    /// followed by `until <condition>`. The conditions are
+    /// `next_moves nonempty`, `program_state == quit`, `paused` and
+    /// `limit_exceeded`.
+    /// # Arguments
+    /// * `text` - The algorithm.
+    pub fn parse_algorithm(text: &str) -> Result<Vec<ControlStep>, String> {
+        let mut tokens = Vec::new();
+        let mut chars = text.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '[' | ']' | ',' => tokens.push(c.to_string()),
+                '=' if chars.next_if_eq(&'=').is_some() => tokens.push("==".to_string()),
+                c if c.is_alphanumeric() || c == '_' => {
+                    let mut word = c.to_string();
+                    while let Some(c) = chars.next_if(|c| c.is_alphanumeric() || *c == '_') {
+                        word.push(c);
+                    }
+                    tokens.push(word);
+                }
+                c if c.is_whitespace() => {}
+                c => return Err(format!("Unexpected {} in control algorithm", c)),
+            }
+        }
+        let mut parser = ControlParser { tokens, pos: 0 };
+        let steps = parser.steps()?;
+        match parser.next() {
+            None => Ok(steps),
+            Some(token) => Err(format!("Unexpected {} in control algorithm", token)),
+        }
+    }
+
+    /// Returns the control algorithm.
+    pub fn algorithm(&self) -> &[ControlStep] {
+        &self.algorithm
+    }
+
+    /// Returns the names of the modules in alphabetical order.
+    pub fn modules(&self) -> Vec<&str> {
+        let mut names: Vec<&str> = self.modules.keys().map(|name| name.as_str()).collect();
+        names.sort();
+        names
+    }
+
+    /// Checks that the control algorithm only names known modules and that
+    /// every repeat can stop.
+    pub fn validate(&self) -> Result<(), String> {
+        fn stops(steps: &[ControlStep]) -> bool {
+            steps.iter().any(|step| match step {
+                ControlStep::Stop => true,
+                ControlStep::If(_, steps) | ControlStep::Repeat(steps) => stops(steps),
+                ControlStep::Module(_) => false,
+            })
+        }
+        fn check(kernel: &Kernel, steps: &[ControlStep]) -> Result<(), String> {
+            for step in steps {
+                match step {
+                    ControlStep::Module(name) if !kernel.modules.contains_key(name) => {
+                        return Err(format!("Unknown module {}", name));
+                    }
+                    ControlStep::Repeat(steps) if !stops(steps) => return Err("Repeat without a stop".to_string()),
+                    ControlStep::If(_, steps) | ControlStep::Repeat(steps) => check(kernel, steps)?,
+                    ControlStep::Module(_) | ControlStep::Stop => {}
+                }
+            }
+            Ok(())
+        }
+        check(self, &self.algorithm)
+    }
+}
+
+impl Default for Kernel {
+    fn default() -> Self {
+        Self::standard()
+    }
+}
+
+/// The control algorithm of the standard kernel, in the control language.
+pub const STANDARD_CONTROL_ALGORITHM: &str = "repeat [
+    if program_state == quit then [stop],
+    events,
+    if paused then [stop],
+    select,
+    if next_moves nonempty then [generate, output, update, limits],
+    if program_state == quit then [stop],
+    yield_turn,
+    input,
+    take_turn,
+    limits,
+    if limit_exceeded then [stop],
+    interpret,
+    update,
+    limits
+]";
+
+/// Words of the control language that cannot name modules.
+const CONTROL_KEYWORDS: [&str; 5] = ["if", "then", "repeat", "until", "stop"];
+
+/// Recursive descent parser of the control language.
+struct ControlParser {
+    tokens: Vec<String>, // Tokens of the algorithm
+    pos: usize, // Index of the next token
+}
+
+impl ControlParser {
+    /// Returns the next token, if any, and moves past it.
+    fn next(&mut self) -> Option<String> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    /// Returns the next token, if any, without moving past it.
+    fn peek(&self) -> Option<&str> {
+        self.tokens.get(self.pos).map(|token| token.as_str())
+    }
+
+    /// Moves past the next token, which must be the expected one.
+    /// # Arguments
+    /// * `expected` - The expected token.
+    fn expect(&mut self, expected: &str) -> Result<(), String> {
+        match self.next() {
+            Some(token) if token.eq_ignore_ascii_case(expected) => Ok(()),
+            token => Err(format!(
+                "Expected {} in control algorithm, found {}",
+                expected,
+                token.unwrap_or("the end".to_string())
+            )),
+        }
+    }
+
+    /// Parses steps separated by commas.
+    fn steps(&mut self) -> Result<Vec<ControlStep>, String> {
+        let mut steps = vec![self.step()?];
+        while self.peek() == Some(",") {
+            self.pos += 1;
+            steps.push(self.step()?);
+        }
+        Ok(steps)
+    }
+
+    /// Parses a bracketed list of steps.
+    fn block(&mut self) -> Result<Vec<ControlStep>, String> {
+        self.expect("[")?;
+        let steps = self.steps()?;
+        self.expect("]")?;
+        Ok(steps)
+    }
+
+    /// Parses a step.
+    fn step(&mut self) -> Result<ControlStep, String> {
+        let token = self.next().ok_or("Expected a step in control algorithm, found the end")?;
+        match token.to_lowercase().as_str() {
+            "stop" => Ok(ControlStep::Stop),
+            "if" => {
+                let condition = self.condition()?;
+                self.expect("then")?;
+                Ok(ControlStep::If(condition, self.block()?))
+            }
+            "repeat" => {
+                let mut steps = self.block()?;
+                if self.peek().is_some_and(|token| token.eq_ignore_ascii_case("until")) {
+                    self.pos += 1;
+                    steps.push(ControlStep::If(self.condition()?, vec![ControlStep::Stop]));
+                }
+                Ok(ControlStep::Repeat(steps))
+            }
+            word if CONTROL_KEYWORDS.contains(&word) || !word.chars().all(|c| c.is_alphanumeric() || c == '_') => {
+                Err(format!("Expected a step in control algorithm, found {}", token))
+            }
+            _ => Ok(ControlStep::Module(token)),
+        }
+    }
+
+    /// Parses a condition.
+    fn condition(&mut self) -> Result<ControlCondition, String> {
+        let token = self.next().unwrap_or_default();
+        match token.to_lowercase().as_str() {
+            "next_moves" => {
+                self.expect("nonempty")?;
+                Ok(ControlCondition::NextMoves)
+            }
+            "program_state" => {
+                self.expect("==")?;
+                self.expect("quit")?;
+                Ok(ControlCondition::Ended)
+            }
+            "paused" => Ok(ControlCondition::Paused),
+            "limit_exceeded" => Ok(ControlCondition::LimitExceeded),
+            _ => Err(format!("Unknown condition {} in control algorithm", token)),
+        }
+    }
+}
+
+/// Formats the ControlCondition in the control language (e.g., "next_moves nonempty").
+impl fmt::Display for ControlCondition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ControlCondition::NextMoves => write!(f, "next_moves nonempty"),
+            ControlCondition::Ended => write!(f, "program_state == quit"),
+            ControlCondition::Paused => write!(f, "paused"),
+            ControlCondition::LimitExceeded => write!(f, "limit_exceeded"),
+        }
+    }
+}
+
+/// Formats the ControlStep in the control language (e.g., "if paused then [stop]").
+impl fmt::Display for ControlStep {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let block = |steps: &[ControlStep]| steps.iter().map(|step| step.to_string()).collect::<Vec<_>>().join(", ");
+        match self {
+            ControlStep::Module(name) => write!(f, "{}", name),
+            ControlStep::If(condition, steps) => write!(f, "if {} then [{}]", condition, block(steps)),
+            ControlStep::Repeat(steps) => write!(f, "repeat [{}]", block(steps)),
+            ControlStep::Stop => write!(f, "stop"),
+        }
+    }
+}
+
+/// A change in the minimal information state that user interfaces may react to
+/// (e.g., disabling the input box while the system has the turn).
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum MivsEvent {
+    ProgramState(Option<ProgramState>), // The program state changed
+    LatestSpeaker(Option<Speaker>), // The turn passed to another speaker
+    Output(Option<String>), // The system produced a new output
+    Turn(Option<usize>), // A new turn started
+    TurnHolder(Option<Speaker>), // The turn passed to the participant expected to speak next
+    User(Option<String>), // The host application identified another user
+}
+
+/// Standard MIVS (Minimal Information State) for dialogue management.
+#[derive(Clone)]
+struct StandardMIVS {
+    input: Value<String>, // User input
+    latest_speaker: Value<Speaker>, // Latest speaker (USR or SYS)
+    latest_moves: Vec<TaggedMove>, // Latest dialogue moves, in order
+    next_moves: Stack<String>, // Next moves to perform
+    output: Value<String>, // System output
+    program_state: Value<ProgramState>, // Program state (RUN or QUIT)
+    turn: Value<usize>, // Current turn, counting from 1 and advancing whenever a speaker takes the floor
+    turn_holder: Value<Speaker>, // Participant holding the turn, expected to speak next
+    user: Value<String>, // Identity of the user, set by the host application and kept across sessions
+}
+
+/// Implementation of methods for the StandardMIVS struct.
+impl StandardMIVS {
+    /// Creates a new, empty MIVS state.
+    fn new() -> Self {
+        StandardMIVS {
+            input: Value::new_type(|_: &String| true),
+            latest_speaker: Value::new_allowed(Speaker::iter().collect()),
+            latest_moves: Vec::new(),
+            next_moves: Stack::new(),
+            output: Value::new_type(|_: &String| true),
+            program_state: Value::new_allowed(ProgramState::iter().collect()),
+            turn: Value::new_type(|turn: &usize| *turn > 0),
+            turn_holder: Value::new_allowed(Speaker::iter().collect()),
+            user: Value::new_type(|user: &String| !user.is_empty()),
+        }
+    }
+
+    /// Initializes the MIVS state. Values are cleared rather than replaced
+    /// so that change observers stay registered. The user is kept.
+    fn init_mivs(&mut self) {
+        self.input.clear();
+        self.latest_speaker.clear();
+        self.latest_moves.clear();
+        self.next_moves.clear();
+        self.output.clear();
+        self.program_state.set(ProgramState::RUN).unwrap();
+        self.turn.clear();
+        // The system opens the dialogue
+        self.turn_holder.set(Speaker::SYS).unwrap();
+    }
+
+    /// Starts a new turn and returns its number.
+    fn next_turn(&mut self) -> Result<usize, String> {
+        let turn = self.turn.get().map_or(1, |turn| turn + 1);
+        self.turn.set(turn)?;
+        Ok(turn)
+    }
+
+    /// Returns the latest moves as strings, in order.
+    fn latest_move_strings(&self) -> Vec<String> {
+        self.latest_moves.iter().map(|tagged| tagged.mv.to_string()).collect()
+    }
+
+    /// Prints the MIVS state with a prefix.
+    /// # Arguments
+    /// * `prefix` - The prefix for each line.
+    fn print_mivs(&self, prefix: &str) {
+        println!("{}INPUT:          {}", prefix, self.input);
+        println!("{}LATEST_SPEAKER: {}", prefix, self.latest_speaker);
+        println!("{}LATEST_MOVES:   {{{}}}", prefix, self.latest_move_strings().join(", "));
+        println!("{}NEXT_MOVES:     {}", prefix, self.next_moves);
+        println!("{}OUTPUT:         {}", prefix, self.output);
+        println!("{}PROGRAM_STATE:  {}", prefix, self.program_state);
+        println!("{}TURN:           {}", prefix, self.turn);
+        println!("{}TURN_HOLDER:    {}", prefix, self.turn_holder);
+        println!("{}USER:           {}", prefix, self.user);
+    }
+}
+
+// Localization
+
+create_enum!(
+    /// How the values of a predicate are formatted in outputs.
+    ValueFormat, NUMBER, CURRENCY, DATE
+);
+create_enum!(
+    /// The order of the day, month and year in formatted dates.
+    DateOrder, DMY, MDY, YMD
+);
+
+/// Conventions for formatting numbers, amounts and dates in outputs, so that
+/// "232" can be rendered as "€232" or "232 kr" without changing the domain.
+/// Values are formatted by the format declared for their predicate; in the
+/// domain, numbers are written "1234.5" and dates "2024-05-17".
+#[derive(Clone, Debug)]
+pub struct Locale {
+    decimal_separator: char, // Separator of the decimals
+    thousands_separator: Option<char>, // Separator of groups of thousands, if any
+    currency: String, // Currency symbol (e.g., "€" or "kr")
+    currency_first: bool, // Whether the symbol precedes the amount
+    date_order: DateOrder, // Order of the day, month and year
+    date_separator: char, // Separator of the day, month and year
+    formats: HashMap<String, ValueFormat>, // Format of the values of each predicate
+}
+
+/// Implementation of methods for the Locale struct.
+impl Locale {
+    /// Creates a locale for American English: "1,234.5", "$232" and "05/17/2024".
+    pub fn new() -> Self {
+        Locale {
+            decimal_separator: '.',
+            thousands_separator: Some(','),
+            currency: "$".to_string(),
+            currency_first: true,
+            date_order: DateOrder::MDY,
+            date_separator: '/',
+            formats: HashMap::new(),
+        }
+    }
+
+    /// Creates a locale for German: "1.234,5", "232 €" and "17.05.2024".
+    pub fn german() -> Self {
+        Locale::new()
+            .with_separators(',', Some('.'))
+            .with_currency("€", false)
+            .with_date_order(DateOrder::DMY, '.')
+    }
+
+    /// Creates a locale for Swedish: "1 234,5", "232 kr" and "2024-05-17".
+    pub fn swedish() -> Self {
+        Locale::new()
+            .with_separators(',', Some(' '))
+            .with_currency("kr", false)
+            .with_date_order(DateOrder::YMD, '-')
+    }
+
+    /// Sets the separators of numbers.
+    /// # Arguments
+    /// * `decimal` - The separator of the decimals.
+    /// * `thousands` - The separator of groups of thousands, if any.
+    pub fn with_separators(mut self, decimal: char, thousands: Option<char>) -> Self {
+        self.decimal_separator = decimal;
+        self.thousands_separator = thousands;
+        self
+    }
+
+    /// Sets the currency of amounts.
+    /// # Arguments
+    /// * `symbol` - The currency symbol.
+    /// * `first` - Whether the symbol precedes the amount ("€232") or follows it ("232 kr").
+    pub fn with_currency(mut self, symbol: &str, first: bool) -> Self {
+        self.currency = symbol.to_string();
+        self.currency_first = first;
+        self
+    }
+
+    /// Sets how dates are written.
+    /// # Arguments
+    /// * `order` - The order of the day, month and year.
+    /// * `separator` - The separator between them.
+    pub fn with_date_order(mut self, order: DateOrder, separator: char) -> Self {
+        self.date_order = order;
+        self.date_separator = separator;
+        self
+    }
+
+    /// Declares how the values of a predicate are formatted.
+    /// # Arguments
+    /// * `pred` - The predicate (e.g., "price").
+    /// * `format` - The format.
+    pub fn with_format(mut self, pred: &str, format: ValueFormat) -> Self {
+        self.formats.insert(pred.to_string(), format);
+        self
+    }
+
+    /// Formats a value of a predicate. Values of predicates without a format,
+    /// and values that are not numbers or dates as their format expects, are
+    /// returned as they are.
+    /// # Arguments
+    /// * `pred` - The predicate.
+    /// * `value` - The value (e.g., "1234.5" or "2024-05-17").
+    pub fn format(&self, pred: &str, value: &str) -> String {
+        match self.formats.get(pred) {
+            Some(ValueFormat::NUMBER) => self.format_number(value).unwrap_or(value.to_string()),
+            Some(ValueFormat::CURRENCY) => match self.format_number(value) {
+                Some(amount) if self.currency_first => format!("{}{}", self.currency, amount),
+                Some(amount) => format!("{} {}", amount, self.currency),
+                None => value.to_string(),
+            },
+            Some(ValueFormat::DATE) => self.format_date(value).unwrap_or(value.to_string()),
+            None => value.to_string(),
+        }
+    }
+
+    /// Formats a decimal number with the separators of the locale.
+    /// # Arguments
+    /// * `value` - The number (e.g., "1234.5").
+    fn format_number(&self, value: &str) -> Option<String> {
+        if !Atomic::is_number(value) {
+            return None;
+        }
+        let (integer, decimals) = value.split_once('.').unwrap_or((value, ""));
+        let mut output = String::new();
+        for (i, digit) in integer.chars().enumerate() {
+            if let Some(separator) = self.thousands_separator
+                && i > 0
+                && (integer.len() - i) % 3 == 0
+            {
+                output.push(separator);
+            }
+            output.push(digit);
+        }
+        if !decimals.is_empty() {
+            output.push(self.decimal_separator);
+            output.push_str(decimals);
+        }
+        Some(output)
+    }
+
+    /// Formats an ISO 8601 date in the order of the locale.
+    /// # Arguments
+    /// * `value` - The date (e.g., "2024-05-17").
+    fn format_date(&self, value: &str) -> Option<String> {
+        if !Atomic::is_date(value) {
+            return None;
+        }
+        let [year, month, day] = value.split('-').collect::<Vec<_>>()[..] else {
+            return None;
+        };
+        let parts = match self.date_order {
+            DateOrder::DMY => [day, month, year],
+            DateOrder::MDY => [month, day, year],
+            DateOrder::YMD => [year, month, day],
+        };
+        Some(parts.join(&self.date_separator.to_string()))
+    }
+}
+
+impl Default for Locale {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Grammar
+
+/// The dialogue context available to a grammar when interpreting and generating.
+pub struct InterpretationContext<'a> {
+    pub domain: &'a Domain, // Domain knowledge
+    pub qud: Vec<Question>, // Questions under discussion, topmost first
+    pub findouts: Vec<Question>, // Questions the active plan will find out, next first
+    pub locale: Option<&'a Locale>, // How values are formatted in outputs, if localized
+}
+
+/// Implementation of methods for the InterpretationContext struct.
+impl<'a> InterpretationContext<'a> {
+    /// Creates a new context without questions under discussion or plan.
+    /// # Arguments
+    /// * `domain` - The domain knowledge.
+    pub fn new(domain: &'a Domain) -> Self {
+        InterpretationContext {
+            domain,
+            qud: Vec::new(),
+            findouts: Vec::new(),
+            locale: None,
+        }
+    }
+
+    /// Formats a value of a predicate for output with the locale, if any.
+    /// # Arguments
+    /// * `pred` - The predicate.
+    /// * `value` - The value.
+    pub fn format_value(&self, pred: &str, value: &str) -> String {
+        match self.locale {
+            Some(locale) => locale.format(pred, value),
+            None => value.to_string(),
+        }
+    }
+
+    /// Returns the topmost question under discussion, if any.
+    pub fn qud_top(&self) -> Option<&Question> {
+        self.qud.first()
+    }
+
+    /// Binds a bare answer to the question it answers: the topmost question under
+    /// discussion if the answer is relevant to it, otherwise the only plan findout
+    /// a short answer is relevant to.
+    /// # Arguments
+    /// * `answer` - The bare answer (e.g., "today" or "yes").
+    pub fn bind(&self, answer: &Ans) -> Option<Prop> {
+        let que = match self.qud_top() {
+            Some(que)
+                if matches!((answer, que), (Ans::ShortAns(_), Question::WhQ(_)) | (Ans::YesNo(_), Question::YNQ(_)))
+                    && self.domain.relevant(answer, que) =>
+            {
+                que
+            }
+            _ => {
+                if !matches!(answer, Ans::ShortAns(_)) {
+                    return None;
+                }
+                let mut candidates = self
+                    .findouts
+                    .iter()
+                    .filter(|que| matches!(que, Question::WhQ(_)) && self.domain.relevant(answer, que));
+                let que = candidates.next()?;
+                if candidates.next().is_some() {
+                    return None;
+                }
+                que
+            }
+        };
+        self.domain.combine(que, answer).ok()
+    }
+
+    /// Scores a reading of an utterance against the dialogue context. Answers
+    /// score highest when they address the topmost question under discussion,
+    /// less when they address another open question or a plan findout, and
+    /// below zero when they address nothing. Questions score higher when the
+    /// domain has a plan for them, and quitting always scores high.
+    /// # Arguments
+    /// * `moves` - The moves of the reading.
+    pub fn score(&self, moves: &TSet<String>) -> i32 {
+        moves
+            .iter()
+            .map(|m| match Move::new(m) {
+                Ok(Move::Quit(_)) => 4,
+                Ok(Move::Ask(ask)) if self.domain.plans.contains_key(&ask.content.to_string()) => 2,
+                Ok(Move::Custom(custom)) if self.domain.custom_move(&custom.name).is_some() => 2,
+                Ok(Move::Answer(answer)) => {
+                    let relevant = |que: &Question| self.domain.relevant(&answer.content, que);
+                    if self.qud_top().is_some_and(relevant) {
+                        3
+                    } else if self.qud.iter().any(relevant) {
+                        2
+                    } else if self.findouts.iter().any(relevant) {
+                        1
+                    } else {
+                        -1
+                    }
+                }
+                _ => 0,
+            })
+            .sum()
+    }
+}
+
+/// A reading of an utterance with its score against the dialogue context.
+#[derive(Clone)]
+pub struct Interpretation {
+    pub moves: TSet<String>, // The moves of the reading
+    pub score: i32, // How well the reading fits the dialogue context
+}
+
+/// Trait for generating and interpreting dialogue moves.
+pub trait Grammar {
+    /// Generates a string from a list of moves, realizing them in order.
+    /// # Arguments
+    /// * `moves` - The moves to generate, first to utter first.
+    /// * `ctx` - The dialogue context.
+    fn generate(&self, moves: &[String], ctx: &InterpretationContext) -> String;
+
+    /// Interprets an input string into a set of moves.
+    /// # Arguments
+    /// * `input` - The input string to interpret.
+    /// * `ctx` - The dialogue context.
+    fn interpret(&self, input: &str, ctx: &InterpretationContext) -> Option<TSet<String>>;
+
+    /// Returns the name of the grammar, recorded with the user utterances it
+    /// interprets. Defaults to the name of its type (e.g., "SimpleGenGrammar").
+    fn name(&self) -> &str {
+        let name = std::any::type_name::<Self>();
+        name.rsplit("::").next().unwrap_or(name)
+    }
+
+    /// Interprets an input string into a set of moves, telling how confident
+    /// the grammar is in them. Defaults to the moves of interpret with full
+    /// confidence; grammars that can tell how sure they are override it.
+    /// # Arguments
+    /// * `input` - The input string to interpret.
+    /// * `ctx` - The dialogue context.
+    fn parse(&self, input: &str, ctx: &InterpretationContext) -> Option<Parse> {
+        self.interpret(input, ctx).map(|moves| Parse {
+            moves,
+            confidence: 1.0,
+            grammar: self.name().to_string(),
+        })
+    }
+
+    /// Returns the moves the grammar has a form for, sorted, e.g. to report
+    /// the forms a test corpus never generates. Defaults to none.
+    fn forms(&self) -> Vec<String> {
+        Vec::new()
+    }
+}
+
+/// A grammar shared by several controllers, e.g. the sessions of a server.
+impl<G: Grammar + ?Sized> Grammar for Arc<G> {
+    fn generate(&self, moves: &[String], ctx: &InterpretationContext) -> String {
+        (**self).generate(moves, ctx)
+    }
+
+    fn interpret(&self, input: &str, ctx: &InterpretationContext) -> Option<TSet<String>> {
+        (**self).interpret(input, ctx)
+    }
+
+    fn name(&self) -> &str {
+        (**self).name()
+    }
+
+    fn parse(&self, input: &str, ctx: &InterpretationContext) -> Option<Parse> {
+        (**self).parse(input, ctx)
+    }
+
+    fn forms(&self) -> Vec<String> {
+        (**self).forms()
+    }
+}
+
+/// The moves a grammar read in an input, with its confidence in them.
+#[derive(Clone)]
+pub struct Parse {
+    pub moves: TSet<String>, // The moves
+    pub confidence: f64, // How sure the grammar is, from 0 to 1
+    pub grammar: String, // Name of the grammar that read the moves
+}
+
+/// Phrases introducing a numeric constraint, with the comparison they express.
+const CONSTRAINT_PHRASES: [(&str, CmpOp); 10] = [
+    ("less than", CmpOp::Lt),
+    ("cheaper than", CmpOp::Lt),
+    ("under", CmpOp::Lt),
+    ("below", CmpOp::Lt),
+    ("at most", CmpOp::Le),
+    ("up to", CmpOp::Le),
+    ("more than", CmpOp::Gt),
+    ("over", CmpOp::Gt),
+    ("above", CmpOp::Gt),
+    ("at least", CmpOp::Ge),
+];
+
+/// Phrases telling that any answer will do or that the user does not know,
+/// with the answer they stand for. They match whole words of the input.
+const INDIFFERENCE_PHRASES: [(&str, &str); 6] = [
+    ("doesn't matter", ANY),
+    ("don't care", ANY),
+    ("anything", ANY),
+    ("don't know", "unknown"),
+    ("not sure", "unknown"),
+    ("no idea", "unknown"),
+];
+
+/// Backchannels of users telling they heard the system (e.g., "mhm"). When
+/// the topmost question under discussion is a yes/no question, such as a
+/// confirmation, they agree instead.
+const BACKCHANNEL_PHRASES: [&str; 8] = ["ok", "okay", "mhm", "uh huh", "right", "i see", "got it", "alright"];
+
+/// Entries a generation cache holds before it is emptied.
+const GENERATION_CACHE_SIZE: usize = 1024;
+
+/// Generated phrases and outputs kept by a grammar between turns. Phrases
+/// that depend on the domain (questions phrased after their sort and custom
+/// moves) or on the locale (answers generated with one) are not cached, and
+/// changing a form empties the cache.
+#[derive(Default)]
+struct GenerationCache {
+    phrases: HashMap<String, Arc<str>>, // Interned phrase generated for each move
+    outputs: HashMap<Vec<String>, String>, // Joined output generated for each sequence of moves
+}
+
+impl GenerationCache {
+    /// Empties the cache.
+    fn clear(&mut self) {
+        self.phrases.clear();
+        self.outputs.clear();
+    }
+}
+
+/// A simple grammar for generating and interpreting dialogue moves.
+pub struct SimpleGenGrammar {
+    forms: HashMap<String, String>, // Mapping of move strings to output strings
+    units: HashMap<String, String>, // Mapping of unit words to constrained predicates
+    constrained_forms: HashMap<CmpOp, String>, // Templates for answers found under a constraint, by operator
+    aggregate_phrases: Vec<(String, Aggregate, String)>, // Phrases asking for an aggregate over a predicate
+    aggregate_forms: HashMap<Aggregate, String>, // Templates for aggregate answers
+    answer_forms: HashMap<String, String>, // Templates for answers by predicate
+    yn_forms: HashMap<String, String>, // Templates for yes/no questions by predicate
+    cache: Mutex<GenerationCache>, // Phrases and outputs generated before, shared by the sessions using the grammar
+}
+
+/// Implementation of methods for the SimpleGenGrammar struct.
+impl SimpleGenGrammar {
+    /// Creates a new SimpleGenGrammar with default forms.
+    pub fn new() -> Self {
+        let mut grammar = SimpleGenGrammar {
+            forms: HashMap::new(),
+            units: HashMap::new(),
+            constrained_forms: HashMap::from([
+                (CmpOp::Lt, "The cheapest is {}".to_string()),
+                (CmpOp::Le, "The cheapest is {}".to_string()),
+                (CmpOp::Gt, "The lowest above {bound} is {}".to_string()),
+                (CmpOp::Ge, "The lowest from {bound} is {}".to_string()),
+            ]),
+            aggregate_phrases: Vec::new(),
+            aggregate_forms: HashMap::from([
+                (Aggregate::Min, "The lowest is {}".to_string()),
+                (Aggregate::Max, "The highest is {}".to_string()),
+                (Aggregate::Count, "There are {}".to_string()),
+            ]),
+            answer_forms: HashMap::new(),
+            yn_forms: HashMap::new(),
+            cache: Mutex::new(GenerationCache::default()),
+        };
+        grammar.add_form("Greet()", "Hello");
+        grammar.add_form("Acknowledge()", "Okay");
+        grammar.add_form("icm:sem*neg", "I don't understand");
+        grammar.add_form("icm:con*neg", "Are you still there?");
+        grammar.add_form("icm:acc*neg", "Sorry, I cannot help with that");
+        grammar.add_form("Quit()", "Goodbye");
+        grammar.add_form("Escalate()", "Let me put you through to a colleague");
+        grammar
+    }
+
+    /// Adds a form to the grammar.
+    /// # Arguments
+    /// * `move_str` - The move string.
+    /// * `output` - The corresponding output string.
+    pub fn add_form(&mut self, move_str: &str, output: &str) {
+        self.forms.insert(move_str.to_string(), output.to_string());
+        self.cache().clear();
+    }
+
+    /// Registers a unit word naming the predicate it constrains, so that
+    /// phrases such as "under 300 euros" are interpreted as "price()<300".
+    /// # Arguments
+    /// * `unit` - The unit word (e.g., "euros").
+    /// * `pred` - The constrained predicate (e.g., "price").
+    pub fn add_unit(&mut self, unit: &str, pred: &str) {
+        self.units.insert(unit.to_string(), pred.to_string());
+    }
+
+    /// Sets the template used for answers found under a constraint with an
+    /// operator, unless the predicate answered has an answer form. The answer
+    /// is the smallest value admitted, so under a lower bound it is not the
+    /// cheapest overall.
+    /// # Arguments
+    /// * `op` - The operator of the constraint.
+    /// * `form` - The template, where "{}" is replaced by the answer value
+    ///   and "{bound}" by the bound of the constraint.
+    pub fn set_constrained_form(&mut self, op: CmpOp, form: &str) {
+        self.constrained_forms.insert(op, form.to_string());
+        self.cache().clear();
+    }
+
+    /// Registers a phrase asking for an aggregate over a predicate, so that
+    /// e.g. "what is the cheapest flight" is interpreted as "?x.min:price(x)".
+    /// Phrases are tried in the order they were added.
+    /// # Arguments
+    /// * `phrase` - The phrase (e.g., "cheapest").
+    /// * `aggregate` - The aggregate it asks for.
+    /// * `pred` - The aggregated predicate (e.g., "price").
+    pub fn add_aggregate_phrase(&mut self, phrase: &str, aggregate: Aggregate, pred: &str) {
+        self.aggregate_phrases.push((phrase.to_string(), aggregate, pred.to_string()));
+    }
+
+    /// Sets the template used for answers computed with an aggregate.
+    /// # Arguments
+    /// * `aggregate` - The aggregate.
+    /// * `form` - The template, where "{}" is replaced by the answer value.
+    pub fn set_aggregate_form(&mut self, aggregate: Aggregate, form: &str) {
+        self.aggregate_forms.insert(aggregate, form.to_string());
+        self.cache().clear();
+    }
+
+    /// Adds a template for answers about a predicate. Besides "{}" for the
+    /// answer value, the template may name supporting predicates, e.g.
+    /// "The price from {depart_city} to {dest_city} is {}". It is used for
+    /// answers found under a constraint too, with "{bound}" replaced by the
+    /// bound of the constraint, instead of the constrained form. Answers to
+    /// yes/no questions are given forms by their proposition (e.g., "-pets()").
+    /// # Arguments
+    /// * `pred` - The predicate answered (e.g., "price"), or the proposition of a yes/no answer.
+    /// * `form` - The template.
+    pub fn add_answer_form(&mut self, pred: &str, form: &str) {
+        self.answer_forms.insert(pred.to_string(), form.to_string());
+        self.cache().clear();
+    }
+
+    /// Adds a template for yes/no questions about the value of a predicate,
+    /// with "{}" replaced by the value (e.g., "From {} as usual?" for depart_city).
+    /// # Arguments
+    /// * `pred` - The predicate asked about.
+    /// * `form` - The template.
+    pub fn add_yn_form(&mut self, pred: &str, form: &str) {
+        self.yn_forms.insert(pred.to_string(), form.to_string());
+        self.cache().clear();
+    }
+
+    /// Fills a template with an answer value and its supporting propositions,
+    /// formatted with the locale of the context.
+    /// # Arguments
+    /// * `form` - The template.
+    /// * `pred` - The predicate of the answer value.
+    /// * `ind` - The answer value replacing "{}".
+    /// * `support` - The propositions whose individuals replace "{pred}".
+    /// * `ctx` - The dialogue context.
+    fn fill_template(
+        &self,
+        form: &str,
+        pred: &str,
+        ind: &Ind,
+        support: &[Prop],
+        ctx: &InterpretationContext,
+    ) -> String {
+        let mut output = form.replace("{}", &ctx.format_value(pred, &ind.to_string()));
+        for prop in support {
+            if let Some(value) = &prop.ind {
+                let value = ctx.format_value(prop.pred(), &value.to_string());
+                output = output.replace(&format!("{{{}}}", prop.pred), &value);
+            }
+        }
+        output
+    }
+
+    /// Locks the generation cache. A panic while it was locked cannot leave
+    /// it inconsistent, so a poisoned lock is taken over.
+    fn cache(&self) -> MutexGuard<'_, GenerationCache> {
+        self.cache.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Generates the phrase for a single move, reusing the phrase generated
+    /// before when it does not depend on the domain.
+    /// # Arguments
+    /// * `move_str` - The move to generate.
+    /// * `ctx` - The dialogue context.
+    fn generate_phrase(&self, move_str: &str, ctx: &InterpretationContext) -> Arc<str> {
+        let localized = self.is_localized(move_str, ctx);
+        if !localized && let Some(phrase) = self.cache().phrases.get(move_str) {
+            return phrase.clone();
+        }
+        let phrase: Arc<str> = self.generate_move(move_str, ctx).into();
+        let depends_on_domain = matches!(Move::new(move_str), Ok(Move::Ask(_)) | Ok(Move::Custom(_)));
+        let cacheable = !localized && (self.forms.contains_key(move_str) || !depends_on_domain);
+        if cacheable {
+            let mut cache = self.cache();
+            if cache.phrases.len() >= GENERATION_CACHE_SIZE {
+                cache.phrases.clear();
+            }
+            cache.phrases.insert(move_str.to_string(), phrase.clone());
+        }
+        phrase
+    }
+
+    /// Checks if the phrase of a move depends on the locale of the context.
+    /// The cache is not keyed by locale, so such phrases are neither taken
+    /// from it nor put in it.
+    /// # Arguments
+    /// * `move_str` - The move.
+    /// * `ctx` - The dialogue context.
+    fn is_localized(&self, move_str: &str, ctx: &InterpretationContext) -> bool {
+        ctx.locale.is_some()
+            && !self.forms.contains_key(move_str)
+            && matches!(Move::new(move_str), Ok(Move::Answer(_)) | Ok(Move::Inform(_)))
+    }
+
+    /// Generates a string for a single move.
+    /// Questions without a form are phrased after the sort of their predicate
+    /// (e.g., "Which city?").
+    /// # Arguments
+    /// * `move` - The move to generate.
+    /// * `ctx` - The dialogue context.
+    fn generate_move(&self, move_str: &str, ctx: &InterpretationContext) -> String {
+        if let Some(form) = self.forms.get(move_str) {
+            return form.clone();
+        }
+        match Move::new(move_str) {
+            Ok(Move::Custom(custom)) => {
+                if let Some(kind) = ctx.domain.custom_move(&custom.name) {
+                    return kind.generate(&custom.args);
+                }
+            }
+            Ok(Move::Answer(answer)) => {
+                if let Ans::Prop(prop) = &answer.content
+                    && let Some(ind) = &prop.ind
+                {
+                    // Answer forms of the predicate take precedence over the forms of constrained answers
+                    let form = match prop.aggregate.and_then(|a| self.aggregate_forms.get(&a)) {
+                        Some(form) => Some(form.clone()),
+                        None => match &prop.constraint {
+                            Some(constraint) => self
+                                .answer_forms
+                                .get(&prop.pred.0.content)
+                                .or_else(|| self.constrained_forms.get(&constraint.op))
+                                .map(|form| form.replace("{bound}", &constraint.bound.to_string())),
+                            None => self.answer_forms.get(&prop.pred.0.content).cloned(),
+                        },
+                    };
+                    if let Some(form) = form {
+                        return self.fill_template(&form, prop.pred(), ind, &answer.support, ctx);
+                    }
+                } else if let Ans::Prop(prop) = &answer.content
+                    && let Some(form) = self.answer_forms.get(&prop.to_string())
+                {
+                    return form.clone();
+                }
+            }
+            Ok(Move::Inform(inform)) => {
+                let prop = &inform.content;
+                if let Some(form) = self.answer_forms.get(&prop.to_string()) {
+                    return form.clone();
+                } else if let Some(ind) = &prop.ind
+                    && let Some(form) = self.answer_forms.get(&prop.pred.0.content)
+                {
+                    return self.fill_template(form, prop.pred(), ind, &[], ctx);
+                }
+            }
+            Ok(Move::Ask(ask)) => {
+                if let Question::WhQ(whq) = &ask.content
+                    && let Some(sort) = ctx.domain.preds1.get(&whq.pred.0.content)
+                    && ctx.domain.sorts.contains_key(sort)
+                {
+                    return format!("Which {}?", sort);
+                } else if let Question::YNQ(ynq) = &ask.content
+                    && let Some(ind) = &ynq.prop.ind
+                    && let Some(form) = self.yn_forms.get(ynq.prop.pred())
+                {
+                    return self.fill_template(form, ynq.prop.pred(), ind, &[], ctx);
+                }
+            }
+            // Confirmations repeat the value understood
+            Ok(Move::ICM(icm)) if icm.level == "und" && icm.polarity == "pos" => {
+                if let Some(Ok(prop)) = icm.icm_content.as_deref().map(Prop::new) {
+                    let value = prop.ind.as_ref().map_or(prop.pred().to_string(), |ind| ind.to_string());
+                    return format!("Okay, {}", value);
+                }
+            }
+            // Answers given before are acknowledged with their value
+            Ok(Move::ICM(icm)) if icm.level == "acc" && icm.polarity == "pos" => {
+                if let Some(Ok(prop)) = icm.icm_content.as_deref().map(Prop::new) {
+                    let value = prop.ind.as_ref().map_or(prop.pred().to_string(), |ind| ind.to_string());
+                    return format!("I know, you already told me {}", value);
+                }
+            }
+            _ => {}
+        }
+        move_str.to_string()
+    }
+
+    /// Returns every reading of an input, best first. Readings are ranked by
+    /// their score against the context; ties go to the reading found first,
+    /// trying phrases such as "doesn't matter" or "don't know", constraint phrases, aggregate questions, questions, answers and
+    /// predicate aliases in that order. "quit" and "exit" only read as Quit(),
+    /// and backchannels (e.g., "okay") as Acknowledge(), or as "yes" while a
+    /// yes/no question is topmost under discussion.
+    /// # Arguments
+    /// * `input` - The input string to interpret.
+    /// * `ctx` - The dialogue context.
+    pub fn interpretations(&self, input: &str, ctx: &InterpretationContext) -> Vec<Interpretation> {
+        let mut readings: Vec<String> = Vec::new();
+        let backchannel = BACKCHANNEL_PHRASES.contains(&input.trim_end_matches(['.', '!']).to_lowercase().as_str());
+        let yes_no = matches!(ctx.qud.first(), Some(Question::YNQ(_)));
+        if input == "quit" || input == "exit" {
+            readings.push("Quit()".to_string());
+        } else if backchannel && !yes_no {
+            readings.push("Acknowledge()".to_string());
+        } else {
+            // Backchannels agree with a yes/no question
+            let input = if backchannel { "yes" } else { input };
+            if let Some((_, answer)) = INDIFFERENCE_PHRASES.iter().find(|(phrase, _)| contains_words(input, phrase)) {
+                readings.push(format!("Answer({})", answer));
+            }
+            if let Some(prop) = self.interpret_constraint(input) {
+                readings.push(format!("Answer({})", prop));
+            }
+            if let Some(question) = self.interpret_aggregate(input) {
+                readings.push(format!("Ask('{}')", question));
+            }
+            if Question::new(input).is_ok() {
+                readings.push(format!("Ask('{}')", input));
+            }
+            // Bare answers are bound to the context when possible
+            if let Ok(answer) = Ans::new(input) {
+                match ctx.bind(&answer) {
+                    Some(prop) => readings.push(format!("Answer({})", prop)),
+                    None => readings.push(format!("Answer({})", input)),
+                }
+            }
+            if let Some(prop) = ctx.domain.interpret_alias(input) {
+                readings.push(format!("Answer({})", prop));
+            }
+            for kind in ctx.domain.moves.values() {
+                if let Some(args) = kind.interpret(input) {
+                    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+                    if let Ok(custom) = Custom::new(kind.name(), &args) {
+                        readings.push(custom.to_string());
+                    }
+                }
+            }
+        }
+        let mut interpretations: Vec<Interpretation> = readings
+            .into_iter()
+            .map(|reading| {
+                let mut moves = TSet::new();
+                moves.add(reading).ok();
+                let score = ctx.score(&moves);
+                Interpretation { moves, score }
+            })
+            .collect();
+        interpretations.sort_by_key(|reading| std::cmp::Reverse(reading.score));
+        interpretations
+    }
+
+    /// Interprets an input containing an aggregate phrase as an aggregate question.
+    /// # Arguments
+    /// * `input` - The input string to interpret.
+    fn interpret_aggregate(&self, input: &str) -> Option<Question> {
+        let (_, aggregate, pred) = self
+            .aggregate_phrases
+            .iter()
+            .find(|(phrase, _, _)| input.contains(phrase.as_str()))?;
+        Question::new(&format!("?x.{}:{}(x)", aggregate, pred)).ok()
+    }
+
+    /// Interprets a phrase such as "under 300 euros" as a constraint proposition.
+    /// # Arguments
+    /// * `input` - The input string to interpret.
+    fn interpret_constraint(&self, input: &str) -> Option<Prop> {
+        let (op, rest) = CONSTRAINT_PHRASES.iter().find_map(|(phrase, op)| {
+            input.strip_prefix(phrase)?.strip_prefix(' ').map(|rest| (*op, rest))
+        })?;
+        let words: Vec<&str> = rest.split_whitespace().collect();
+        let [number, unit] = words[..] else {
+            return None;
+        };
+        let pred = self.units.get(unit)?;
+        Prop::new(&format!("{}(){}{}", pred, op, number)).ok()
+    }
+
+    /// Joins phrases into a single string with punctuation.
+    /// # Arguments
+    /// * `phrases` - The phrases to join.
+    fn join_phrases<S: AsRef<str>>(&self, phrases: &[S]) -> String {
+        let mut result = String::new();
+        for p in phrases {
+            let p = p.as_ref();
+            if !result.is_empty() {
+                result.push(' ');
+            }
+            result.push_str(p);
+            if !p.ends_with('.') && !p.ends_with('?') && !p.ends_with('!') {
+                result.push('.');
+            }
+        }
+        result
+    }
+}
+
+/// Implements the Grammar trait for SimpleGenGrammar.
+impl Grammar for SimpleGenGrammar {
+    fn generate(&self, moves: &[String], ctx: &InterpretationContext) -> String {
+        let localized = moves.iter().any(|m| self.is_localized(m, ctx));
+        if !localized && let Some(output) = self.cache().outputs.get(moves) {
+            return output.clone();
+        }
+        let phrases: Vec<Arc<str>> = moves.iter().map(|m| self.generate_phrase(m, ctx)).collect();
+        let output = self.join_phrases(&phrases);
+        // Outputs are only reused when all their phrases were
+        let mut cache = self.cache();
+        if !localized && moves.iter().all(|m| cache.phrases.contains_key(m)) {
+            if cache.outputs.len() >= GENERATION_CACHE_SIZE {
+                cache.outputs.clear();
+            }
+            cache.outputs.insert(moves.to_vec(), output.clone());
+        }
+        output
+    }
+
+    fn interpret(&self, input: &str, ctx: &InterpretationContext) -> Option<TSet<String>> {
+        self.interpretations(input, ctx).into_iter().next().map(|reading| reading.moves)
+    }
+
+    fn forms(&self) -> Vec<String> {
+        let mut forms: Vec<String> = self.forms.keys().cloned().collect();
+        forms.sort();
+        forms
+    }
+}
+
+impl Default for SimpleGenGrammar {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// How a grammar ensemble chooses among the parses of its grammars.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum EnsemblePolicy {
+    HighestConfidence, // Ask every grammar and take the most confident parse, the earliest on ties
+    FirstConfident(f64), // Take the first parse at least this confident, or else the most confident one
+}
+
+/// A grammar stacking several grammars, e.g. a CFG first, then regular
+/// expressions, then a language model as a fallback. Input is interpreted by
+/// the grammars according to the ensemble policy, and output is generated by
+/// the first grammar.
+pub struct GrammarEnsemble {
+    grammars: Vec<Box<dyn Grammar>>, // The grammars, in order
+    policy: EnsemblePolicy, // How the parse is chosen
+}
+
+impl GrammarEnsemble {
+    /// Creates an ensemble without grammars, taking the most confident parse.
+    pub fn new() -> Self {
+        GrammarEnsemble {
+            grammars: Vec::new(),
+            policy: EnsemblePolicy::HighestConfidence,
+        }
+    }
+
+    /// Adds a grammar after the ones added before.
+    /// # Arguments
+    /// * `grammar` - The grammar.
+    pub fn with_grammar(mut self, grammar: impl Grammar + 'static) -> Self {
+        self.grammars.push(Box::new(grammar));
+        self
+    }
+
+    /// Sets how the parse is chosen.
+    /// # Arguments
+    /// * `policy` - The policy.
+    pub fn with_policy(mut self, policy: EnsemblePolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+}
+
+impl Default for GrammarEnsemble {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Grammar for GrammarEnsemble {
+    fn generate(&self, moves: &[String], ctx: &InterpretationContext) -> String {
+        self.grammars.first().map(|grammar| grammar.generate(moves, ctx)).unwrap_or_default()
+    }
+
+    fn interpret(&self, input: &str, ctx: &InterpretationContext) -> Option<TSet<String>> {
+        self.parse(input, ctx).map(|parse| parse.moves)
+    }
+
+    fn name(&self) -> &str {
+        "GrammarEnsemble"
+    }
+
+    fn forms(&self) -> Vec<String> {
+        self.grammars.first().map(|grammar| grammar.forms()).unwrap_or_default()
+    }
+
+    fn parse(&self, input: &str, ctx: &InterpretationContext) -> Option<Parse> {
+        let mut best: Option<Parse> = None;
+        for grammar in &self.grammars {
+            let Some(parse) = grammar.parse(input, ctx) else {
+                continue;
+            };
+            if let EnsemblePolicy::FirstConfident(threshold) = self.policy
+                && parse.confidence >= threshold
+            {
+                return Some(parse);
+            }
+            if best.as_ref().is_none_or(|best| parse.confidence > best.confidence) {
+                best = Some(parse);
+            }
+        }
+        best
+    }
+}
+
+
+/// CFG Grammar Rule structure for parsing context-free grammar files
+#[derive(Debug, Clone)]
+#[allow(clippy::upper_case_acronyms)]
+pub struct CFGRule {
+    pub lhs: String,           // Left-hand side (e.g., "USR[sem=?s]")
+    pub rhs: Vec<String>,      // Right-hand side alternatives (e.g., ["ANSWER[sem=?s]", "ASK[sem=?s]"])
+    pub features: HashMap<String, String>, // Feature annotations (e.g., sem=?s, q=?q)
+}
+
+/// CFG Grammar structure for parsing travel.fcfg files
+#[allow(clippy::upper_case_acronyms)]
+pub struct CFGGrammar {
+    rules: Vec<CFGRule>,
+    terminals: HashMap<String, Vec<String>>, // Terminal mappings (e.g., 'price' -> WHQ[q=price])
+}
+
+impl CFGGrammar {
+    /// Creates a new empty CFG grammar
+    pub fn new() -> Self {
+        CFGGrammar {
+            rules: Vec::new(),
+            terminals: HashMap::new(),
+        }
+    }
+
+    /// Loads CFG rules from a file (basic implementation)
+    pub fn load_from_file(&mut self, _filepath: &str) -> Result<(), Box<dyn std::error::Error>> {
+        // Placeholder: In full implementation, this would parse travel.fcfg
+        // For now, add some basic rules based on travel.fcfg
+        
+        // Add basic rule: USR[sem=?s] -> ANSWER[sem=?s] | ASK[sem=?s]
+        self.rules.push(CFGRule {
+            lhs: "USR[sem=?s]".to_string(),
+            rhs: vec!["ANSWER[sem=?s]".to_string(), "ASK[sem=?s]".to_string()],
+            features: HashMap::from([("sem".to_string(), "?s".to_string())]),
+        });
+
+        // Add terminal mappings
+        self.terminals.insert("price".to_string(), vec!["WHQ[q=price]".to_string()]);
+        self.terminals.insert("plane".to_string(), vec!["CAT[cat=how, ind=plane]".to_string()]);
+        self.terminals.insert("train".to_string(), vec!["CAT[cat=how, ind=train]".to_string()]);
+        
+        Ok(())
+    }
+
+    /// Returns the loaded rules
+    pub fn rules(&self) -> &[CFGRule] {
+        &self.rules
+    }
+
+    /// Basic parsing of input using CFG rules (placeholder)
+    pub fn parse(&self, input: &str) -> Option<String> {
+        // Placeholder: Check terminals first
+        if let Some(categories) = self.terminals.get(input) {
+            return categories.first().cloned();
+        }
+        None
+    }
+}
+
+impl Default for CFGGrammar {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Database
+
+/// Trait for consulting a database with questions.
+pub trait Database {
+    /// Consults the database with a question and context, returning an error
+    /// instead of an answer when nothing matches, so that the plan can recover
+    /// (see OnFailure). Constraints on the answer are given by the question
+    /// itself (e.g., "?x.price(x)<300") and by constraint propositions in the context.
+    /// # Arguments
+    /// * `question` - The question to consult.
+    /// * `context` - The context propositions.
+    fn try_consult_db(&self, question: &Question, context: &TSet<Prop>) -> Result<Prop, String>;
+
+    /// Consults the database like try_consult_db, panicking when nothing matches.
+    /// # Arguments
+    /// * `question` - The question to consult.
+    /// * `context` - The context propositions.
+    #[deprecated(note = "use try_consult_db, which returns an error when nothing matches")]
+    fn consult_db(&self, question: &Question, context: &TSet<Prop>) -> Prop {
+        self.try_consult_db(question, context).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// Computes an aggregate over a field of the entries matching the context.
+    /// # Arguments
+    /// * `aggregate` - The aggregate to compute.
+    /// * `field` - The field to aggregate over (e.g., "price").
+    /// * `context` - The context propositions.
+    fn aggregate(&self, aggregate: Aggregate, field: &str, context: &TSet<Prop>) -> Option<Ind>;
+}
+
+/// A database shared by several controllers, e.g. the sessions of a server.
+impl<D: Database + ?Sized> Database for Arc<D> {
+    fn try_consult_db(&self, question: &Question, context: &TSet<Prop>) -> Result<Prop, String> {
+        (**self).try_consult_db(question, context)
+    }
+
+    fn aggregate(&self, aggregate: Aggregate, field: &str, context: &TSet<Prop>) -> Option<Ind> {
+        (**self).aggregate(aggregate, field, context)
+    }
+}
+
+/// A query planned from a database question and the commitments given
+/// with it. The predicate asked about is the output, whose values are read
+/// from the entries and kept when they satisfy the constraints; the other
+/// commitments to one-place predicates are filters selecting the entries.
+/// Databases without a field for a filter may leave it out.
+#[derive(Clone)]
+pub struct QueryPlan {
+    question: String, // The question answered (e.g., "?x.price(x)<300")
+    output: Pred0, // The predicate asked about (e.g., "price")
+    aggregate: Option<Aggregate>, // The aggregate asked for, if any
+    filters: Vec<(String, String)>, // Committed predicates and values, sorted (e.g., ("dest_city", "paris"))
+    constraints: Vec<Constraint>, // Constraints on the output values
+}
+
+impl QueryPlan {
+    /// Plans the query answering a wh-question. Other questions have no
+    /// predicate asked about and cannot be planned.
+    /// # Arguments
+    /// * `question` - The question (e.g., "?x.min:price(x)").
+    /// * `context` - The context propositions.
+    pub fn new(question: &Question, context: &TSet<Prop>) -> Result<Self, String> {
+        let Question::WhQ(whq) = question else {
+            return Err(format!("Cannot plan a query answering {}", question));
+        };
+        let mut plan = Self::for_field(&whq.pred.0.content, context)?;
+        plan.question = question.to_string();
+        plan.aggregate = whq.aggregate;
+        plan.constraints.splice(0..0, whq.constraints());
+        Ok(plan)
+    }
+
+    /// Plans a query of the values of a field, e.g. to compute an aggregate over them.
+    /// # Arguments
+    /// * `field` - The field, a predicate name (e.g., "price").
+    /// * `context` - The context propositions.
+    pub fn for_field(field: &str, context: &TSet<Prop>) -> Result<Self, String> {
+        let output = Pred0::new(field).map_err(|e| format!("Invalid field {}: {}", field, e))?;
+        let mut filters: Vec<(String, String)> = context
+            .iter()
+            .filter(|prop| prop.yes && !prop.is_constraint() && prop.aggregate.is_none() && prop.pred() != field)
+            .filter_map(|prop| Some((prop.pred().to_string(), prop.ind.as_ref()?.0.content.clone())))
+            .collect();
+        filters.sort();
+        let constraints = context
+            .iter()
+            .filter(|prop| prop.is_constraint() && prop.pred() == field)
+            .filter_map(|prop| prop.constraint.clone())
+            .collect();
+        let question = format!("?x.{}(x)", field);
+        Ok(QueryPlan { question, output, aggregate: None, filters, constraints })
+    }
+
+    /// Returns the predicate asked about.
+    pub fn output(&self) -> &str {
+        &self.output.0.content
+    }
+
+    /// Returns the committed predicates and values selecting the entries.
+    pub fn filters(&self) -> &[(String, String)] {
+        &self.filters
+    }
+
+    /// Returns the constraints the output values must satisfy.
+    pub fn constraints(&self) -> &[Constraint] {
+        &self.constraints
+    }
+
+    /// Tells whether an output value satisfies all constraints.
+    /// # Arguments
+    /// * `value` - The value.
+    pub fn admits(&self, value: &Ind) -> bool {
+        self.constraints.iter().all(|c| c.admits(value))
+    }
+
+    /// Answers the question with the output values of the selected entries
+    /// that satisfy the constraints. Without constraints the first value is
+    /// used; with constraints the smallest one is returned. Aggregate
+    /// questions (e.g., "?x.min:price(x)") are answered with the aggregate
+    /// over all values.
+    /// # Arguments
+    /// * `values` - The values.
+    pub fn answer(&self, values: Vec<Ind>) -> Result<Prop, String> {
+        let value = match self.aggregate {
+            Some(aggregate) => aggregate.apply(&values),
+            None if self.constraints.is_empty() => values.into_iter().next(),
+            None => Aggregate::Min.apply(&values),
+        };
+        Ok(Prop {
+            pred: self.output.clone(),
+            ind: Some(value.ok_or(format!("No entry answers {}", self.question))?),
+            yes: true,
+            constraint: self.constraints.first().cloned(),
+            aggregate: self.aggregate,
+        })
+    }
+}
+
+/// Returns the entries matching the filters of a query that have a column.
+/// An entry matches a filter when the column of its predicate has the
+/// committed value; a value of "any", committed or in the column, matches
+/// every value. Filters on predicates without a column are left out.
+/// # Arguments
+/// * `entries` - The entries, by column.
+/// * `filters` - The filters, with the column of each.
+fn filter_entries<'a>(
+    entries: &'a [HashMap<String, String>],
+    filters: &[(&str, &str)],
+) -> impl Iterator<Item = &'a HashMap<String, String>> {
+    let filters: Vec<(String, String)> = filters
+        .iter()
+        .filter(|(column, _)| entries.iter().any(|entry| entry.contains_key(*column)))
+        .map(|(column, value)| (column.to_string(), value.to_string()))
+        .collect();
+    entries.iter().filter(move |entry| {
+        filters.iter().all(|(column, value)| {
+            value == ANY || entry.get(column).is_some_and(|v| v == value || v == ANY)
+        })
+    })
+}
+
+/// A travel database storing entries as key-value maps. Entries are selected
+/// by the commitments given with a question, each compared with the column
+/// named after its predicate, or with the column set for it. The columns of
+/// depart_city, dest_city and depart_day are "from", "to" and "day".
+pub struct TravelDB {
+    entries: Vec<HashMap<String, String>>, // Database entries
+    columns: HashMap<String, String>, // Columns of the predicates not named after them
+}
+
+/// Implementation of methods for the TravelDB struct.
+impl TravelDB {
+    /// Creates a new empty TravelDB.
+    pub fn new() -> Self {
+        let columns = [("depart_city", "from"), ("dest_city", "to"), ("depart_day", "day")];
+        TravelDB {
+            entries: Vec::new(),
+            columns: columns.iter().map(|(pred, column)| (pred.to_string(), column.to_string())).collect(),
+        }
+    }
+
+    /// Sets the column compared with the commitments to a predicate.
+    /// # Arguments
+    /// * `pred` - The predicate (e.g., "class").
+    /// * `column` - The column (e.g., "cabin").
+    pub fn with_column(mut self, pred: &str, column: &str) -> Self {
+        self.columns.insert(pred.to_string(), column.to_string());
+        self
+    }
+
+    /// Adds an entry to the database.
+    /// # Arguments
+    /// * `entry` - The key-value map to add.
+    pub fn add_entry(&mut self, entry: HashMap<String, String>) {
+        self.entries.push(entry);
+    }
+
+    /// Looks up an entry by departure city, destination city, and day.
+    /// # Arguments
+    /// * `depart_city` - Departure city.
+    /// * `dest_city` - Destination city.
+    /// * `day` - Departure day.
+    pub fn lookup_entry(&self, depart_city: &str, dest_city: &str, day: &str) -> Option<&HashMap<String, String>> {
+        let filters = [("from", depart_city), ("to", dest_city), ("day", day)];
+        filter_entries(&self.entries, &filters).next()
+    }
+
+    /// Returns the output values of the entries selected by a query that
+    /// satisfy its constraints.
+    /// # Arguments
+    /// * `plan` - The query.
+    fn matching_values(&self, plan: &QueryPlan) -> Vec<Ind> {
+        let filters: Vec<(&str, &str)> = plan
+            .filters()
+            .iter()
+            .map(|(pred, value)| (self.columns.get(pred).unwrap_or(pred).as_str(), value.as_str()))
+            .collect();
+        filter_entries(&self.entries, &filters)
+            .filter_map(|entry| entry.get(plan.output()))
+            .filter_map(|value| Ind::new(value).ok())
+            .filter(|value| plan.admits(value))
+            .collect()
+    }
+}
+
+impl Default for TravelDB {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Implements the Database trait for TravelDB.
+/// Without constraints the first matching entry is used; with constraints
+/// the cheapest entry satisfying all of them is returned. Aggregate questions
+/// (e.g., "?x.min:price(x)") are answered with the aggregate over all matches.
+impl Database for TravelDB {
+    fn try_consult_db(&self, question: &Question, context: &TSet<Prop>) -> Result<Prop, String> {
+        let plan = QueryPlan::new(question, context)?;
+        plan.answer(self.matching_values(&plan))
+    }
+
+    fn aggregate(&self, aggregate: Aggregate, field: &str, context: &TSet<Prop>) -> Option<Ind> {
+        aggregate.apply(&self.matching_values(&QueryPlan::for_field(field, context).ok()?))
+    }
+}
+
+/// A database of entries read from a CSV or JSON file, one entry per row
+/// or object. Key columns select the entries matching the context: an entry
+/// matches when, for each key whose predicate is committed to, its column
+/// has the committed value. A value of "any", committed or in the column,
+/// matches every value. Questions are answered
+/// like by TravelDB, with the values of the column named after the predicate
+/// asked about (e.g., "price" for "?x.price(x)").
+pub struct FileDatabase {
+    entries: Vec<HashMap<String, String>>, // Entries, by column
+    keys: Vec<(String, String)>, // Predicates selecting the entries, with their columns
+}
+
+impl FileDatabase {
+    /// Creates a database of entries.
+    /// # Arguments
+    /// * `entries` - The entries, by column.
+    /// * `key_columns` - The key columns, either named after their predicate
+    ///   (e.g., "dest_city") or given as predicate=column (e.g., "dest_city=to").
+    pub fn from_entries(entries: Vec<HashMap<String, String>>, key_columns: &[&str]) -> Result<Self, String> {
+        let mut keys = Vec::new();
+        for key in key_columns {
+            let (pred, column) = key.split_once('=').unwrap_or((key, key));
+            let (pred, column) = (pred.trim(), column.trim());
+            if pred.is_empty() || column.is_empty() {
+                return Err(format!("Invalid key column: {}", key));
+            }
+            if !entries.is_empty() && !entries.iter().any(|entry| entry.contains_key(column)) {
+                return Err(format!("Unknown key column: {}", column));
+            }
+            keys.push((pred.to_string(), column.to_string()));
+        }
+        Ok(FileDatabase { entries, keys })
+    }
+
+    /// Loads a database from a CSV file whose first row names the columns.
+    /// Fields may be quoted, with quotes doubled inside them and line breaks
+    /// kept. Blank lines are skipped.
+    /// # Arguments
+    /// * `path` - The path of the file.
+    /// * `key_columns` - The key columns, as in from_entries.
+    pub fn from_csv(path: &str, key_columns: &[&str]) -> Result<Self, String> {
+        let contents = std::fs::read_to_string(path).map_err(|e| format!("Could not read {}: {}", path, e))?;
+        let mut rows = csv_rows(&contents).map_err(|e| format!("{}, {}", path, e))?.into_iter();
+        let header = rows.next().ok_or(format!("Missing header in {}", path))?;
+        let mut entries = Vec::new();
+        for (i, row) in rows.enumerate() {
+            if row.len() != header.len() {
+                return Err(format!("{}, row {}: expected {} fields, got {}", path, i + 2, header.len(), row.len()));
+            }
+            entries.push(header.iter().cloned().zip(row).collect());
+        }
+        Self::from_entries(entries, key_columns).map_err(|e| format!("{}: {}", path, e))
+    }
+
+    /// Loads a database from a JSON file holding a list of objects. Values
+    /// other than strings (e.g., numbers) are read as their JSON text.
+    /// # Arguments
+    /// * `path` - The path of the file.
+    /// * `key_columns` - The key columns, as in from_entries.
+    pub fn from_json(path: &str, key_columns: &[&str]) -> Result<Self, String> {
+        let contents = std::fs::read_to_string(path).map_err(|e| format!("Could not read {}: {}", path, e))?;
+        let json: serde_json::Value =
+            serde_json::from_str(&contents).map_err(|e| format!("Invalid database {}: {}", path, e))?;
+        let objects = json.as_array().ok_or(format!("{}: expected a list of entries", path))?;
+        let mut entries = Vec::new();
+        for object in objects {
+            let fields = object.as_object().ok_or(format!("{}: invalid entry {}", path, object))?;
+            let entry = fields.iter().map(|(column, value)| {
+                let value = value.as_str().map_or_else(|| value.to_string(), str::to_string);
+                (column.clone(), value)
+            });
+            entries.push(entry.collect());
+        }
+        Self::from_entries(entries, key_columns).map_err(|e| format!("{}: {}", path, e))
+    }
+
+    /// Returns the number of entries.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Tells whether the database has no entries.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Returns the output values of the entries selected by the filters of
+    /// a query on key columns that satisfy its constraints.
+    /// # Arguments
+    /// * `plan` - The query.
+    fn matching_values(&self, plan: &QueryPlan) -> Vec<Ind> {
+        let filters: Vec<(&str, &str)> = plan
+            .filters()
+            .iter()
+            .filter_map(|(pred, value)| {
+                let (_, column) = self.keys.iter().find(|(key, _)| key == pred)?;
+                Some((column.as_str(), value.as_str()))
+            })
+            .collect();
+        filter_entries(&self.entries, &filters)
+            .filter_map(|entry| entry.get(plan.output()))
+            .filter_map(|value| Ind::new(value).ok())
+            .filter(|value| plan.admits(value))
+            .collect()
+    }
+}
+
+/// Splits CSV text into rows of fields, unquoting quoted fields. Line
+/// breaks inside quoted fields belong to the field; blank lines are skipped.
+/// # Arguments
+/// * `text` - The text.
+fn csv_rows(text: &str) -> Result<Vec<Vec<String>>, String> {
+    let mut rows = Vec::new();
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut chars = text.chars().peekable();
+    let mut quoted = false;
+    // Whether the row has a quoted field, which keeps it from being blank
+    let mut has_quotes = false;
+    loop {
+        let c = chars.next();
+        match c {
+            Some('"') if quoted && chars.peek() == Some(&'"') => {
+                chars.next();
+                field.push('"');
+            }
+            Some('"') if quoted => quoted = false,
+            Some('"') if field.trim().is_empty() => {
+                field.clear();
+                quoted = true;
+                has_quotes = true;
+            }
+            Some(',') if !quoted => fields.push(std::mem::take(&mut field).trim().to_string()),
+            Some('\n') | None if !quoted => {
+                fields.push(std::mem::take(&mut field).trim().to_string());
+                let row = std::mem::take(&mut fields);
+                if has_quotes || row.len() > 1 || !row[0].is_empty() {
+                    rows.push(row);
+                }
+                has_quotes = false;
+                if c.is_none() {
+                    return Ok(rows);
+                }
+            }
+            Some(c) => field.push(c),
+            None => return Err(format!("row {}: Unterminated quote", rows.len() + 1)),
+        }
+    }
+}
+
+/// Implements the Database trait for FileDatabase.
+impl Database for FileDatabase {
+    fn try_consult_db(&self, question: &Question, context: &TSet<Prop>) -> Result<Prop, String> {
+        let plan = QueryPlan::new(question, context)?;
+        plan.answer(self.matching_values(&plan))
+    }
+
+    fn aggregate(&self, aggregate: Aggregate, field: &str, context: &TSet<Prop>) -> Option<Ind> {
+        aggregate.apply(&self.matching_values(&QueryPlan::for_field(field, context).ok()?))
+    }
+}
+
+/// Trait for databases answering asynchronously, e.g. over HTTP or SQL
+/// clients built on tokio. Controllers consult them through a BlockingDatabase.
+#[cfg(feature = "async")]
+pub trait AsyncDatabase {
+    /// Consults the database with a question and context, like
+    /// Database::try_consult_db.
+    /// # Arguments
+    /// * `question` - The question to consult.
+    /// * `context` - The context propositions.
+    fn consult_db(&self, question: &Question, context: &TSet<Prop>) -> impl Future<Output = Result<Prop, String>>;
+
+    /// Computes an aggregate over a field of the entries matching the
+    /// context. By default no aggregates are known.
+    /// # Arguments
+    /// * `aggregate` - The aggregate to compute.
+    /// * `field` - The field to aggregate over (e.g., "price").
+    /// * `context` - The context propositions.
+    fn aggregate(
+        &self,
+        _aggregate: Aggregate,
+        _field: &str,
+        _context: &TSet<Prop>,
+    ) -> impl Future<Output = Option<Ind>> {
+        std::future::ready(None)
+    }
+}
+
+/// A Database consulting an AsyncDatabase on a tokio runtime, blocking the
+/// thread of the dialogue until the answer arrives. Only that thread waits:
+/// dialogues running on threads of their own, e.g. in `spawn_blocking`, take
+/// their turns meanwhile. SessionManager::handle waits for the reply of the
+/// session, so its caller waits too. Consultations from within an async task,
+/// where blocking would stall the runtime, fail.
+#[cfg(feature = "async")]
+pub struct BlockingDatabase<D: AsyncDatabase> {
+    database: D, // The database consulted
+    runtime: tokio::runtime::Handle, // The runtime driving the consultations
+}
+
+#[cfg(feature = "async")]
+impl<D: AsyncDatabase> BlockingDatabase<D> {
+    /// Creates an adapter consulting a database on a runtime.
+    /// # Arguments
+    /// * `database` - The database.
+    /// * `runtime` - The runtime, e.g. `Handle::current()` taken in an async task.
+    pub fn new(database: D, runtime: tokio::runtime::Handle) -> Self {
+        BlockingDatabase { database, runtime }
+    }
+}
+
+/// Implements the Database trait for BlockingDatabase.
+#[cfg(feature = "async")]
+impl<D: AsyncDatabase> Database for BlockingDatabase<D> {
+    fn try_consult_db(&self, question: &Question, context: &TSet<Prop>) -> Result<Prop, String> {
+        if tokio::runtime::Handle::try_current().is_ok() {
+            return Err("Cannot block on a database from within an async task".to_string());
+        }
+        self.runtime.block_on(self.database.consult_db(question, context))
+    }
+
+    fn aggregate(&self, aggregate: Aggregate, field: &str, context: &TSet<Prop>) -> Option<Ind> {
+        if tokio::runtime::Handle::try_current().is_ok() {
+            return None;
+        }
+        self.runtime.block_on(self.database.aggregate(aggregate, field, context))
+    }
+}
+
+/// Answers a CachedDatabase holds by default.
+const DATABASE_CACHE_SIZE: usize = 1024;
+
+/// A cached answer of a CachedDatabase.
+struct CachedAnswer {
+    question: String, // The question answered
+    context: Vec<Prop>, // The relevant context it was answered in
+    answer: Prop, // The answer
+}
+
+/// A cache wrapping a database, e.g. a remote backend, so that a question
+/// consulted again in the same relevant context is answered without
+/// consulting the database. The relevant context is made of the commitments
+/// to the key predicates, if any were given, or else of all commitments, and
+/// of the constraints in the context. Sessions sharing the cache reuse the
+/// answers of each other, whatever their commitments. Answers stay cached
+/// until the host invalidates them, e.g. when the data behind them changed,
+/// or until the cache is full, when the least recently used answer is
+/// dropped. Failed consultations are not cached.
+pub struct CachedDatabase<D: Database> {
+    database: D, // The database consulted on misses
+    key_preds: Option<HashSet<String>>, // Predicates whose commitments are relevant, or None for all
+    capacity: usize, // Answers held at most
+    answers: Mutex<Vec<CachedAnswer>>, // Cached answers, the least recently used first
+    hits: Mutex<(usize, usize)>, // Consultations answered from the cache and from the database
+}
+
+impl<D: Database> CachedDatabase<D> {
+    /// Creates a cache of a database, taking all commitments as relevant.
+    /// # Arguments
+    /// * `database` - The database.
+    pub fn new(database: D) -> Self {
+        CachedDatabase {
+            database,
+            key_preds: None,
+            capacity: DATABASE_CACHE_SIZE,
+            answers: Mutex::new(Vec::new()),
+            hits: Mutex::new((0, 0)),
+        }
+    }
+
+    /// Sets the number of answers the cache holds at most (1024 by default).
+    /// # Arguments
+    /// * `capacity` - The number of answers, at least 1.
+    pub fn with_capacity(mut self, capacity: usize) -> Self {
+        self.capacity = capacity.max(1);
+        self
+    }
+
+    /// Sets the predicates whose commitments select the answers of the
+    /// database, so that other commitments do not keep answers from being reused.
+    /// # Arguments
+    /// * `preds` - The predicates (e.g., ["dest_city", "depart_city"]).
+    pub fn with_key_predicates(mut self, preds: &[&str]) -> Self {
+        self.key_preds = Some(preds.iter().map(|pred| pred.to_string()).collect());
+        self
+    }
+
+    /// Returns the number of consultations answered from the cache and from the database.
+    pub fn stats(&self) -> (usize, usize) {
+        *self.hits.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Drops all cached answers.
+    pub fn clear(&self) {
+        self.answers.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).clear();
+    }
+
+    /// Drops the answers given in a context with any of the propositions,
+    /// e.g. when the data about a city changed.
+    /// # Arguments
+    /// * `props` - The propositions (e.g., [dest_city(paris)]).
+    pub fn invalidate(&self, props: &[Prop]) {
+        let mut answers = self.answers.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        answers.retain(|cached| !cached.context.iter().any(|prop| props.contains(prop)));
+    }
+
+    /// Returns the propositions of the context relevant to the answers, sorted.
+    /// # Arguments
+    /// * `context` - The context propositions.
+    fn relevant(&self, context: &TSet<Prop>) -> Vec<Prop> {
+        let mut relevant: Vec<Prop> = context
+            .iter()
+            .filter(|prop| {
+                let key = self.key_preds.as_ref().is_none_or(|preds| preds.contains(&prop.pred.0.content));
+                key || prop.is_constraint()
+            })
+            .cloned()
+            .collect();
+        relevant.sort_by_key(|prop| prop.to_string());
+        relevant
+    }
+}
+
+/// Implements the Database trait for CachedDatabase.
+impl<D: Database> Database for CachedDatabase<D> {
+    fn try_consult_db(&self, question: &Question, context: &TSet<Prop>) -> Result<Prop, String> {
+        let key = (question.to_string(), self.relevant(context));
+        {
+            let mut answers = self.answers.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+            let index = answers.iter().position(|cached| (&cached.question, &cached.context) == (&key.0, &key.1));
+            if let Some(index) = index {
+                self.hits.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).0 += 1;
+                let cached = answers.remove(index);
+                let answer = cached.answer.clone();
+                answers.push(cached);
+                return Ok(answer);
+            }
+        }
+        self.hits.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).1 += 1;
+        let answer = self.database.try_consult_db(question, context)?;
+        let (question, context) = key;
+        let cached = CachedAnswer { question, context, answer: answer.clone() };
+        let mut answers = self.answers.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        if answers.len() >= self.capacity {
+            answers.remove(0);
+        }
+        answers.push(cached);
+        Ok(answer)
+    }
+
+    fn aggregate(&self, aggregate: Aggregate, field: &str, context: &TSet<Prop>) -> Option<Ind> {
+        self.database.aggregate(aggregate, field, context)
+    }
+}
+
+/// A consultation of a MockDatabase, recorded in the order it was made.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DatabaseCall {
+    pub question: String, // The question consulted (e.g., "?x.price(x)")
+    pub context: Vec<String>, // The context propositions, sorted
+}
+
+/// A programmed response of a MockDatabase.
+struct MockResponse {
+    question: String, // The question answered
+    context: Vec<Prop>, // Propositions the context must have for the response to apply
+    answer: Result<Prop, String>, // The answer, or the error the consultation fails with
+}
+
+/// A database answering with programmed responses, so that plans and update
+/// rules around ConsultDB can be tested deterministically. Each consultation
+/// is recorded, can be delayed and can be made to fail.
+///
+/// Of the responses to a question whose context propositions all hold, the
+/// one requiring the most propositions applies, so that a scenario may give
+/// a default answer along with answers for specific contexts. Questions
+/// without a response fail.
+pub struct MockDatabase {
+    responses: Vec<MockResponse>, // Programmed responses, in the order they were added
+    aggregates: HashMap<(Aggregate, String), Ind>, // Programmed aggregates, by aggregate and field
+    latency: Duration, // Time each consultation takes
+    failures: Mutex<usize>, // Number of the next consultations to fail
+    calls: Mutex<Vec<DatabaseCall>>, // Consultations made so far
+}
+
+impl MockDatabase {
+    /// Creates a new MockDatabase without responses.
+    pub fn new() -> Self {
+        MockDatabase {
+            responses: Vec::new(),
+            aggregates: HashMap::new(),
+            latency: Duration::ZERO,
+            failures: Mutex::new(0),
+            calls: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Loads a scenario from a JSON object with the fields "responses" (a
+    /// list of objects with a "question", an optional "context" list and
+    /// either an "answer" or an "error") and "aggregates" (a list of objects
+    /// with an "aggregate", a "field" and a "value"). Missing fields are taken as empty.
+    /// # Arguments
+    /// * `json` - The JSON object.
+    pub fn from_json(json: &serde_json::Value) -> Result<Self, String> {
+        let mut db = MockDatabase::new();
+        for response in json["responses"].as_array().into_iter().flatten() {
+            let question = response["question"].as_str().ok_or(format!("Invalid response: {}", response))?;
+            let context: Vec<&str> =
+                response["context"].as_array().into_iter().flatten().filter_map(|p| p.as_str()).collect();
+            match (response["answer"].as_str(), response["error"].as_str()) {
+                (Some(answer), None) => db.add_response(question, &context, answer)?,
+                (None, Some(error)) => db.add_failure(question, &context, error)?,
+                _ => return Err(format!("Invalid response: {}", response)),
+            }
+        }
+        for entry in json["aggregates"].as_array().into_iter().flatten() {
+            let fields = (entry["aggregate"].as_str(), entry["field"].as_str(), entry["value"].as_str());
+            let (Some(aggregate), Some(field), Some(value)) = fields else {
+                return Err(format!("Invalid aggregate: {}", entry));
+            };
+            let aggregate = Aggregate::new(aggregate).ok_or(format!("Unknown aggregate: {}", aggregate))?;
+            db.add_aggregate(aggregate, field, value)?;
+        }
+        Ok(db)
+    }
+
+    /// Loads a scenario from a JSON file in the format of from_json.
+    /// # Arguments
+    /// * `path` - The path of the file.
+    pub fn from_file(path: &str) -> Result<Self, String> {
+        let contents = std::fs::read_to_string(path).map_err(|e| format!("Could not read {}: {}", path, e))?;
+        let json = serde_json::from_str(&contents).map_err(|e| format!("Invalid scenario {}: {}", path, e))?;
+        Self::from_json(&json)
+    }
+
+    /// Sets the time each consultation takes, e.g. to test timeouts.
+    /// # Arguments
+    /// * `latency` - The time.
+    pub fn with_latency(mut self, latency: Duration) -> Self {
+        self.latency = latency;
+        self
+    }
+
+    /// Adds a response to a question.
+    /// # Arguments
+    /// * `question` - The question (e.g., "?x.price(x)").
+    /// * `context` - Propositions the context must have (e.g., ["dest_city(paris)"]).
+    /// * `answer` - The answer (e.g., "price(345)").
+    pub fn add_response(&mut self, question: &str, context: &[&str], answer: &str) -> Result<(), String> {
+        let answer = Prop::new(answer)?;
+        self.add(question, context, Ok(answer))
+    }
+
+    /// Adds a failure of the consultations of a question.
+    /// # Arguments
+    /// * `question` - The question (e.g., "?x.price(x)").
+    /// * `context` - Propositions the context must have (e.g., ["dest_city(paris)"]).
+    /// * `error` - The error the consultations fail with.
+    pub fn add_failure(&mut self, question: &str, context: &[&str], error: &str) -> Result<(), String> {
+        self.add(question, context, Err(error.to_string()))
+    }
+
+    /// Adds a programmed response.
+    /// # Arguments
+    /// * `question` - The question.
+    /// * `context` - Propositions the context must have.
+    /// * `answer` - The answer or error.
+    fn add(&mut self, question: &str, context: &[&str], answer: Result<Prop, String>) -> Result<(), String> {
+        let question = Question::new(question)?.to_string();
+        let context = context.iter().map(|p| Prop::new(p)).collect::<Result<_, _>>()?;
+        self.responses.push(MockResponse { question, context, answer });
+        Ok(())
+    }
+
+    /// Sets the value of an aggregate over a field.
+    /// # Arguments
+    /// * `aggregate` - The aggregate.
+    /// * `field` - The field (e.g., "price").
+    /// * `value` - The value (e.g., "180").
+    pub fn add_aggregate(&mut self, aggregate: Aggregate, field: &str, value: &str) -> Result<(), String> {
+        self.aggregates.insert((aggregate, field.to_string()), Ind::new(value)?);
+        Ok(())
+    }
+
+    /// Makes the next consultations fail, whatever their question.
+    /// # Arguments
+    /// * `count` - The number of consultations to fail.
+    pub fn fail_next(&self, count: usize) {
+        *self.failures.lock().unwrap_or_else(|poisoned| poisoned.into_inner()) = count;
+    }
+
+    /// Returns the consultations made so far.
+    pub fn calls(&self) -> Vec<DatabaseCall> {
+        self.calls.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).clone()
+    }
+}
+
+impl Default for MockDatabase {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Implements the Database trait for MockDatabase.
+impl Database for MockDatabase {
+    fn try_consult_db(&self, question: &Question, context: &TSet<Prop>) -> Result<Prop, String> {
+        let mut props: Vec<String> = context.iter().map(|p| p.to_string()).collect();
+        props.sort();
+        let call = DatabaseCall { question: question.to_string(), context: props };
+        self.calls.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).push(call);
+        if !self.latency.is_zero() {
+            thread::sleep(self.latency);
+        }
+        let mut failures = self.failures.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        if *failures > 0 {
+            *failures -= 1;
+            return Err(format!("Injected failure consulting {}", question));
+        }
+        drop(failures);
+        let question = question.to_string();
+        self.responses
+            .iter()
+            .filter(|r| r.question == question && r.context.iter().all(|p| context.contains(p)))
+            .fold(None, |best: Option<&MockResponse>, r| match best {
+                Some(best) if best.context.len() >= r.context.len() => Some(best),
+                _ => Some(r),
+            })
+            .map_or(Err(format!("No response programmed for {}", question)), |r| r.answer.clone())
+    }
+
+    fn aggregate(&self, aggregate: Aggregate, field: &str, _context: &TSet<Prop>) -> Option<Ind> {
+        self.aggregates.get(&(aggregate, field.to_string())).cloned()
+    }
+}
+
+// Knowledge base
+
+/// Trait for static world knowledge (e.g., visa rules per country) that holds
+/// in every dialogue, as opposed to the beliefs and commitments of a session.
+pub trait KnowledgeBase {
+    /// Tells whether a proposition holds, or None if it is not known.
+    /// # Arguments
+    /// * `prop` - The proposition to check.
+    /// * `context` - The commitments of the current session.
+    fn holds(&self, prop: &Prop, context: &TSet<Prop>) -> Option<bool>;
+}
+
+/// A knowledge base of static facts given as propositions (e.g., "visa_required(moscow)").
+/// Negative facts (e.g., "-visa_required(paris)") are known to be false.
+#[derive(Clone, Default)]
+pub struct FactBase {
+    facts: HashSet<String>, // Known facts
+}
+
+impl FactBase {
+    /// Creates a new, empty FactBase.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a fact.
+    /// # Arguments
+    /// * `fact` - The proposition (e.g., "visa_required(moscow)" or "-visa_required(paris)").
+    pub fn add_fact(&mut self, fact: &str) -> Result<(), String> {
+        let prop = Prop::new(fact)?;
+        self.facts.insert(prop.to_string());
+        Ok(())
+    }
+}
+
+/// Implements the KnowledgeBase trait for FactBase.
+impl KnowledgeBase for FactBase {
+    fn holds(&self, prop: &Prop, _context: &TSet<Prop>) -> Option<bool> {
+        let negated = Prop { yes: !prop.yes, ..prop.clone() };
+        if self.facts.contains(&prop.to_string()) {
+            Some(true)
+        } else if self.facts.contains(&negated.to_string()) {
+            Some(false)
+        } else {
+            None
+        }
+    }
+}
+
+// User profiles
+
+/// Trait for stores keeping the preferences of each user across sessions
+/// (e.g., "depart_city(london)"), offered as defaults in later dialogues.
+pub trait ProfileStore {
+    /// Returns the saved preferences of a user, or nothing for a new user.
+    /// # Arguments
+    /// * `user` - The user id.
+    fn load(&self, user: &str) -> Vec<Prop>;
+
+    /// Replaces the saved preferences of a user.
+    /// # Arguments
+    /// * `user` - The user id.
+    /// * `preferences` - The preferences to keep.
+    fn save(&mut self, user: &str, preferences: &[Prop]) -> Result<(), String>;
+}
+
+/// A profile store keeping preferences in memory, for tests and short-lived hosts.
+#[derive(Clone, Default)]
+pub struct MemoryProfileStore {
+    profiles: HashMap<String, Vec<String>>, // Preferences by user id
+}
+
+impl MemoryProfileStore {
+    /// Creates a new, empty MemoryProfileStore.
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+/// Implements the ProfileStore trait for MemoryProfileStore.
+impl ProfileStore for MemoryProfileStore {
+    fn load(&self, user: &str) -> Vec<Prop> {
+        let preferences = self.profiles.get(user).map(Vec::as_slice).unwrap_or_default();
+        preferences.iter().filter_map(|p| Prop::new(p).ok()).collect()
+    }
+
+    fn save(&mut self, user: &str, preferences: &[Prop]) -> Result<(), String> {
+        self.profiles.insert(user.to_string(), preferences.iter().map(|p| p.to_string()).collect());
+        Ok(())
+    }
+}
+
+/// A profile store keeping the preferences of each user in a file of its own,
+/// one proposition per line.
+pub struct FileProfileStore {
+    dir: std::path::PathBuf, // Directory holding a "<user>.txt" file per user
+}
+
+impl FileProfileStore {
+    /// Creates a new FileProfileStore.
+    /// # Arguments
+    /// * `dir` - The directory holding the profiles; it is created when saving.
+    pub fn new(dir: impl AsRef<Path>) -> Self {
+        FileProfileStore { dir: dir.as_ref().to_path_buf() }
+    }
+
+    /// Returns the file holding the preferences of a user.
+    /// # Arguments
+    /// * `user` - The user id.
+    fn path(&self, user: &str) -> std::path::PathBuf {
+        let name: String = user.chars().map(|c| if c.is_ascii_alphanumeric() || c == '-' { c } else { '_' }).collect();
+        self.dir.join(format!("{}.txt", name))
+    }
+}
+
+/// Implements the ProfileStore trait for FileProfileStore.
+impl ProfileStore for FileProfileStore {
+    fn load(&self, user: &str) -> Vec<Prop> {
+        let contents = std::fs::read_to_string(self.path(user)).unwrap_or_default();
+        contents.lines().filter_map(|line| Prop::new(line.trim()).ok()).collect()
+    }
+
+    fn save(&mut self, user: &str, preferences: &[Prop]) -> Result<(), String> {
+        std::fs::create_dir_all(&self.dir).map_err(|e| e.to_string())?;
+        let contents: String = preferences.iter().map(|p| format!("{}\n", p)).collect();
+        std::fs::write(self.path(user), contents).map_err(|e| e.to_string())
+    }
+}
+
+// Custom moves
+
+/// A change to the information state brought about by a custom move.
+#[derive(Clone)]
+pub enum MoveEffect {
+    Commit(Prop), // Adds a proposition to the commitments
+    Raise(Question), // Puts a question under discussion
+    Respond(Question), // Raises a question and starts its plan, as if the user had asked it
+    Reply(Move), // Puts a move on the agenda for the system to make
+}
+
+/// A kind of dialogue move registered by a domain, e.g. "BookTicket(params)",
+/// with its generation, interpretation and update rules. Move kinds are
+/// shared by the sessions of a domain, possibly on several threads.
+pub trait CustomMove: Send + Sync {
+    /// Returns the name of the move kind, capitalized (e.g., "BookTicket").
+    fn name(&self) -> &str;
+
+    /// Generates the utterance for the move.
+    /// # Arguments
+    /// * `args` - The arguments of the move.
+    fn generate(&self, args: &[String]) -> String;
+
+    /// Returns the arguments of the move if a user input expresses it.
+    /// By default the move is never recognized in user input.
+    /// # Arguments
+    /// * `input` - The user input.
+    fn interpret(&self, _input: &str) -> Option<Vec<String>> {
+        None
+    }
+
+    /// Returns the changes to the information state the move brings about.
+    /// An error rolls the update of the turn back.
+    /// # Arguments
+    /// * `args` - The arguments of the move.
+    /// * `speaker` - Who made the move.
+    fn integrate(&self, args: &[String], speaker: &Speaker) -> Result<Vec<MoveEffect>, String>;
+}
+
+// Rule preconditions
+
+/// The kinds of items a Pattern matches.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+#[allow(clippy::upper_case_acronyms)]
+enum PatternKind {
+    WhQ, // A wh-question about the predicate, e.g. "?x.dest_city(x)"
+    YNQ, // A yes/no-question about the predicate, e.g. "?return()"
+    Findout, // A Findout of a question about the predicate
+    Raise, // A Raise of a question about the predicate
+    ConsultDB, // A ConsultDB of a question about the predicate
+}
+
+/// A pattern over questions and plan constructs, matched by predicate.
+/// The pattern is parsed once, when it is created; matching an item of the
+/// information state only reads the head of its string form.
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+pub struct Pattern {
+    kind: PatternKind, // The kind of items matched
+    pred: String, // The predicate matched
+}
+
+/// Returns whether a question is a wh-question and the predicate it is
+/// about, from the head of its string form (e.g., (true, "price") for
+/// "?x.min:price(x)<300"). None if it is not a wh- or yes/no-question.
+/// # Arguments
+/// * `que` - The string form of the question.
+fn question_head(que: &str) -> Option<(bool, &str)> {
+    let body = que.trim().strip_prefix('?')?;
+    let (is_whq, head) = match body.split_once('.') {
+        Some((var, rest)) if is_variable(var) => (true, rest),
+        _ => (false, body.strip_prefix('-').unwrap_or(body)),
+    };
+    let (_, head) = split_aggregate(head);
+    Some((is_whq, head.find('(').map_or(head, |end| &head[..end])))
+}
+
+/// Implementation of methods for the Pattern struct.
+impl Pattern {
+    /// Creates a new Pattern from its string form.
+    /// # Arguments
+    /// * `s` - The string to parse (e.g., "is_whq(dest_city)" or "findout(depart_day)").
+    pub fn new(s: &str) -> Result<Self, String> {
+        let (name, pred) = s
+            .trim()
+            .strip_suffix(')')
+            .and_then(|s| s.split_once('('))
+            .ok_or(format!("Could not parse pattern: {}", s))?;
+        let kind = match name.trim() {
+            "is_whq" => PatternKind::WhQ,
+            "is_ynq" => PatternKind::YNQ,
+            "findout" => PatternKind::Findout,
+            "raise" => PatternKind::Raise,
+            "consult_db" => PatternKind::ConsultDB,
+            name => return Err(format!("Unknown pattern: {}", name)),
+        };
+        Ok(Pattern { kind, pred: Pred0::new(pred.trim())?.to_string() })
+    }
+
+    /// Returns the predicate the pattern matches.
+    pub fn pred(&self) -> &str {
+        &self.pred
+    }
+
+    /// Returns the same pattern over another predicate.
+    /// # Arguments
+    /// * `pred` - The predicate.
+    fn with_pred(&self, pred: &str) -> Pattern {
+        Pattern { kind: self.kind, pred: pred.to_string() }
+    }
+
+    /// Checks if a question or plan construct matches the pattern.
+    /// # Arguments
+    /// * `item` - The string form of the question or plan construct.
+    pub fn matches(&self, item: &str) -> bool {
+        let que = match self.kind {
+            PatternKind::WhQ | PatternKind::YNQ => Some(item),
+            PatternKind::Findout => construct_arg(item, "Findout"),
+            PatternKind::Raise => construct_arg(item, "Raise"),
+            PatternKind::ConsultDB => construct_arg(item, "ConsultDB"),
+        };
+        let Some((is_whq, pred)) = que.and_then(question_head) else {
+            return false;
+        };
+        let kind = match self.kind {
+            PatternKind::WhQ => is_whq,
+            PatternKind::YNQ => !is_whq,
+            _ => true,
+        };
+        kind && pred == self.pred
+    }
+}
+
+/// Formats the Pattern for display (e.g., "is_whq(dest_city)").
+impl fmt::Display for Pattern {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self.kind {
+            PatternKind::WhQ => "is_whq",
+            PatternKind::YNQ => "is_ynq",
+            PatternKind::Findout => "findout",
+            PatternKind::Raise => "raise",
+            PatternKind::ConsultDB => "consult_db",
+        };
+        write!(f, "{}({})", name, self.pred)
+    }
+}
+
+/// A declarative precondition of a rule, checked against the information state
+/// (e.g., "qud_top(is_whq(dest_city)) & in_plan(findout(depart_day))").
+/// Conditions are built with the functions of the conditions module (qud_top,
+/// in_plan, committed and so on), combined with `&`, `|` and `!`, or parsed
+/// from their string form.
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub enum Condition {
+    QudTop(Pattern), // The topmost question under discussion matches
+    InQud(Pattern), // A question under discussion matches
+    PlanTop(Pattern), // The next plan construct matches
+    InPlan(Pattern), // A plan construct matches
+    Committed(Prop), // The proposition is a shared commitment
+    Believed(Prop), // The proposition is a private belief
+    And(Box<Condition>, Box<Condition>), // Both conditions hold
+    Or(Box<Condition>, Box<Condition>), // Either condition holds
+    Not(Box<Condition>), // The condition does not hold
+}
+
+/// Implementation of methods for the Condition enum.
+impl Condition {
+    /// Creates a new Condition from its string form, where `&` binds tighter than `|`.
+    /// # Arguments
+    /// * `s` - The string to parse (e.g., "qud_top(is_whq(dest_city)) & !committed(return())").
+    pub fn new(s: &str) -> Result<Self, String> {
+        let s = s.trim();
+        for (op, combine) in [('|', Condition::or as fn(Self, Self) -> Self), ('&', Condition::and)] {
+            let parts = split_operator(s, op);
+            if parts.len() > 1 {
+                let mut parts = parts.into_iter().map(Condition::new);
+                let first = parts.next().unwrap()?;
+                return parts.try_fold(first, |cond, part| Ok(combine(cond, part?)));
+            }
+        }
+        if let Some(rest) = s.strip_prefix('!') {
+            return Ok(Condition::Not(Box::new(Condition::new(rest)?)));
+        }
+        if let Some(inner) = s.strip_prefix('(').and_then(|s| s.strip_suffix(')')) {
+            return Condition::new(inner);
+        }
+        let (name, arg) = s
+            .strip_suffix(')')
+            .and_then(|s| s.split_once('('))
+            .ok_or(format!("Could not parse condition: {}", s))?;
+        match name.trim() {
+            "qud_top" => Ok(Condition::QudTop(Pattern::new(arg)?)),
+            "in_qud" => Ok(Condition::InQud(Pattern::new(arg)?)),
+            "plan_top" => Ok(Condition::PlanTop(Pattern::new(arg)?)),
+            "in_plan" => Ok(Condition::InPlan(Pattern::new(arg)?)),
+            "committed" => Ok(Condition::Committed(Prop::new(arg.trim())?)),
+            "believed" => Ok(Condition::Believed(Prop::new(arg.trim())?)),
+            name => Err(format!("Unknown condition: {}", name)),
+        }
+    }
+
+    /// Returns the conjunction of two conditions.
+    fn and(self, other: Condition) -> Condition {
+        Condition::And(Box::new(self), Box::new(other))
+    }
+
+    /// Returns the disjunction of two conditions.
+    fn or(self, other: Condition) -> Condition {
+        Condition::Or(Box::new(self), Box::new(other))
+    }
+
+    /// Returns the condition with its predicates renamed.
+    /// # Arguments
+    /// * `names` - The new name of each predicate.
+    fn renamed(&self, names: &HashMap<String, String>) -> Condition {
+        let pattern = |pattern: &Pattern| names.get(pattern.pred()).map(|pred| pattern.with_pred(pred));
+        let prop = |prop: &Prop| Prop::new(&rename_preds(&prop.to_string(), names)).ok();
+        let renamed = match self {
+            Condition::QudTop(p) => pattern(p).map(Condition::QudTop),
+            Condition::InQud(p) => pattern(p).map(Condition::InQud),
+            Condition::PlanTop(p) => pattern(p).map(Condition::PlanTop),
+            Condition::InPlan(p) => pattern(p).map(Condition::InPlan),
+            Condition::Committed(p) => prop(p).map(Condition::Committed),
+            Condition::Believed(p) => prop(p).map(Condition::Believed),
+            Condition::And(a, b) => Some(a.renamed(names).and(b.renamed(names))),
+            Condition::Or(a, b) => Some(a.renamed(names).or(b.renamed(names))),
+            Condition::Not(a) => Some(!a.renamed(names)),
+        };
+        renamed.unwrap_or_else(|| self.clone())
+    }
+}
+
+/// Conjoins two conditions with `&`.
+impl std::ops::BitAnd for Condition {
+    type Output = Condition;
+
+    fn bitand(self, other: Condition) -> Condition {
+        self.and(other)
+    }
+}
+
+/// Disjoins two conditions with `|`.
+impl std::ops::BitOr for Condition {
+    type Output = Condition;
+
+    fn bitor(self, other: Condition) -> Condition {
+        self.or(other)
+    }
+}
+
+/// Negates a condition with `!`.
+impl std::ops::Not for Condition {
+    type Output = Condition;
+
+    fn not(self) -> Condition {
+        Condition::Not(Box::new(self))
+    }
+}
+
+/// Formats the Condition in the form parsed by Condition::new.
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Condition::QudTop(p) => write!(f, "qud_top({})", p),
+            Condition::InQud(p) => write!(f, "in_qud({})", p),
+            Condition::PlanTop(p) => write!(f, "plan_top({})", p),
+            Condition::InPlan(p) => write!(f, "in_plan({})", p),
+            Condition::Committed(p) => write!(f, "committed({})", p),
+            Condition::Believed(p) => write!(f, "believed({})", p),
+            Condition::And(a, b) => {
+                let operand = |c: &Condition| match c {
+                    Condition::Or(..) => format!("({})", c),
+                    _ => c.to_string(),
+                };
+                write!(f, "{} & {}", operand(a), operand(b))
+            }
+            Condition::Or(a, b) => write!(f, "{} | {}", a, b),
+            Condition::Not(a) => match **a {
+                Condition::And(..) | Condition::Or(..) => write!(f, "!({})", a),
+                _ => write!(f, "!{}", a),
+            },
+        }
+    }
+}
+
+// Inference rules
+
+create_enum!(
+    /// The phase of an update in which a rule is applied: before the latest
+    /// moves are integrated, after they are, after the plan is executed, or
+    /// before the next moves are selected.
+    #[allow(non_camel_case_types)]
+    RulePhase, GROUNDING, INTEGRATION, PLAN_MANAGEMENT, SELECTION
+);
+create_enum!(
+    /// How the applicable rules of a phase fire: only the one with the
+    /// highest priority, or all of them in order of priority.
+    #[allow(non_camel_case_types)]
+    ConflictStrategy, FIRST_MATCH, ALL_APPLICABLE
+);
+
+/// A forward-chaining rule deriving a proposition from propositions that all hold
+/// (e.g., "dest_city(london), citizenship(us) => need-visa()").
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub struct Rule {
+    premises: Vec<Prop>, // Propositions that must all hold
+    conclusion: Prop, // Proposition derived when they do
+    priority: i32, // Rules with a higher priority fire first
+    phase: RulePhase, // The phase of the update in which the rule applies
+    precondition: Option<Condition>, // Condition on the information state that must also hold
+}
+
+impl Rule {
+    /// Creates a new Rule.
+    /// # Arguments
+    /// * `premises` - The propositions that must all hold (e.g., ["dest_city(london)", "citizenship(us)"]).
+    /// * `conclusion` - The derived proposition (e.g., "need-visa()").
+    pub fn new(premises: &[&str], conclusion: &str) -> Result<Self, String> {
+        if premises.is_empty() {
+            return Err("A rule needs at least one premise".to_string());
+        }
+        Ok(Rule {
+            premises: premises.iter().map(|p| Prop::new(p)).collect::<Result<_, _>>()?,
+            conclusion: Prop::new(conclusion)?,
+            priority: 0,
+            phase: RulePhase::INTEGRATION,
+            precondition: None,
+        })
+    }
+
+    /// Creates a new Rule deriving a proposition whenever a condition on the
+    /// information state holds (e.g., "qud_top(is_whq(dest_city)) => asking-dest()").
+    /// # Arguments
+    /// * `precondition` - The condition.
+    /// * `conclusion` - The derived proposition.
+    pub fn when(precondition: Condition, conclusion: &str) -> Result<Self, String> {
+        Ok(Rule {
+            premises: Vec::new(),
+            conclusion: Prop::new(conclusion)?,
+            priority: 0,
+            phase: RulePhase::INTEGRATION,
+            precondition: Some(precondition),
+        })
+    }
+
+    /// Sets a condition on the information state that must hold, besides the
+    /// premises, for the rule to fire.
+    /// # Arguments
+    /// * `precondition` - The condition.
+    pub fn with_precondition(mut self, precondition: Condition) -> Self {
+        self.precondition = Some(precondition);
+        self
+    }
+
+    /// Returns the condition on the information state, if any.
+    pub fn precondition(&self) -> Option<&Condition> {
+        self.precondition.as_ref()
+    }
+
+    /// Sets the priority of the rule. Among the applicable rules of a phase,
+    /// those with a higher priority fire first, and a conclusion contradicting
+    /// one already derived is blocked. Rules default to priority 0.
+    /// # Arguments
+    /// * `priority` - The priority.
+    pub fn with_priority(mut self, priority: i32) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    /// Sets the phase of the update in which the rule applies.
+    /// Rules default to the integration phase.
+    /// # Arguments
+    /// * `phase` - The phase.
+    pub fn with_phase(mut self, phase: RulePhase) -> Self {
+        self.phase = phase;
+        self
+    }
+
+    /// Returns the priority of the rule.
+    pub fn priority(&self) -> i32 {
+        self.priority
+    }
+
+    /// Returns the phase in which the rule applies.
+    pub fn phase(&self) -> &RulePhase {
+        &self.phase
+    }
+
+    /// Checks if the rule fires on a set of facts.
+    /// # Arguments
+    /// * `facts` - The propositions that hold.
+    fn fires(&self, facts: &TSet<Prop>) -> bool {
+        self.premises.iter().all(|premise| facts.contains(premise))
+    }
+}
+
+/// Formats the Rule for display.
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let premises: Vec<String> = self.premises.iter().map(|p| p.to_string()).collect();
+        write!(f, "{}", premises.join(", "))?;
+        if let Some(precondition) = &self.precondition {
+            let separator = if premises.is_empty() { "" } else { " " };
+            write!(f, "{}when {}", separator, precondition)?;
+        }
+        write!(f, " => {}", self.conclusion)?;
+        if self.priority != 0 || self.phase != RulePhase::INTEGRATION {
+            write!(f, " [{}, {}]", self.phase, self.priority)?;
+        }
+        Ok(())
+    }
+}
+
+// Domain
+
+/// A problem found in a plan by Domain::lint_plans.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum PlanWarning {
+    IrrelevantQuestion { plan: String, question: String }, // No answer of the right sort exists
+    UnraisedCondition { plan: String, question: String }, // An If condition the plan never settles
+    UnreachableBranch { plan: String, construct: String }, // A branch ruled out by an enclosing If
+    NoAnswer { plan: String }, // The plan never responds or consults the database
+}
+
+/// Formats the PlanWarning for display.
+impl fmt::Display for PlanWarning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PlanWarning::IrrelevantQuestion { plan, question } => {
+                write!(f, "{}: no answer to {} can ever be relevant", plan, question)
+            }
+            PlanWarning::UnraisedCondition { plan, question } => {
+                write!(f, "{}: condition {} is never raised, found out or inferred", plan, question)
+            }
+            PlanWarning::UnreachableBranch { plan, construct } => {
+                write!(f, "{}: a branch of {} can never be taken", plan, construct)
+            }
+            PlanWarning::NoAnswer { plan } => write!(f, "{}: plan never responds or consults the database", plan),
+        }
+    }
+}
+
+/// A table of a domain shared with its copies, e.g. the individuals of a
+/// domain shared by the sessions of a server. Entries added to a copy while
+/// the table is shared go to an overlay of that copy instead of copying the
+/// table, so that each session only pays for its own additions.
+#[derive(Clone)]
+struct SharedTable<V> {
+    base: Arc<HashMap<String, V>>, // Entries shared with the copies of the domain
+    overlay: HashMap<String, V>, // Entries added to this copy while the base was shared
+}
+
+impl<V: Clone> SharedTable<V> {
+    /// Creates a table holding entries.
+    /// # Arguments
+    /// * `entries` - The entries.
+    fn new(entries: HashMap<String, V>) -> Self {
+        SharedTable { base: Arc::new(entries), overlay: HashMap::new() }
+    }
+
+    /// Returns the value of a key, looked up in the overlay first.
+    /// # Arguments
+    /// * `key` - The key.
+    fn get(&self, key: &str) -> Option<&V> {
+        self.overlay.get(key).or_else(|| self.base.get(key))
+    }
+
+    /// Checks if the table has a key.
+    /// # Arguments
+    /// * `key` - The key.
+    fn contains_key(&self, key: &str) -> bool {
+        self.overlay.contains_key(key) || self.base.contains_key(key)
+    }
+
+    /// Returns the entries, those of the overlay hiding the base entries they replace.
+    fn iter(&self) -> impl Iterator<Item = (&String, &V)> {
+        let base = self.base.iter().filter(|(key, _)| !self.overlay.contains_key(*key));
+        self.overlay.iter().chain(base)
+    }
+
+    /// Returns the keys.
+    fn keys(&self) -> impl Iterator<Item = &String> {
+        self.iter().map(|(key, _)| key)
+    }
+
+    /// Sets the value of a key, in the base unless it is shared or the key is overlaid.
+    /// # Arguments
+    /// * `key` - The key.
+    /// * `value` - The value.
+    fn insert(&mut self, key: String, value: V) {
+        match Arc::get_mut(&mut self.base) {
+            Some(base) if !self.overlay.contains_key(&key) => base.insert(key, value),
+            _ => self.overlay.insert(key, value),
+        };
+    }
+
+    /// Returns the value of a key for changes, copying a shared base value
+    /// into the overlay first.
+    /// # Arguments
+    /// * `key` - The key.
+    fn get_mut(&mut self, key: &str) -> Option<&mut V> {
+        if !self.overlay.contains_key(key) {
+            if Arc::get_mut(&mut self.base).is_some() {
+                return Arc::get_mut(&mut self.base)?.get_mut(key);
+            }
+            let value = self.base.get(key)?.clone();
+            self.overlay.insert(key.to_string(), value);
+        }
+        self.overlay.get_mut(key)
+    }
+
+    /// Returns the value of a key for changes, set to the default first if missing.
+    /// # Arguments
+    /// * `key` - The key.
+    fn entry_or_default(&mut self, key: &str) -> &mut V
+    where
+        V: Default,
+    {
+        if !self.contains_key(key) {
+            self.insert(key.to_string(), V::default());
+        }
+        self.get_mut(key).expect("the entry was just added")
+    }
+
+    /// Removes a key, copying a shared base without it. Domains only grow,
+    /// so this is used by tests taking individuals out of a domain.
+    /// # Arguments
+    /// * `key` - The key.
+    #[cfg(test)]
+    fn remove(&mut self, key: &str) -> Option<V> {
+        let overlaid = self.overlay.remove(key);
+        let based = self.base.contains_key(key).then(|| Arc::make_mut(&mut self.base).remove(key)).flatten();
+        overlaid.or(based)
+    }
+}
+
+impl<V> FromIterator<(String, V)> for SharedTable<V> {
+    fn from_iter<I: IntoIterator<Item = (String, V)>>(entries: I) -> Self {
+        SharedTable { base: Arc::new(entries.into_iter().collect()), overlay: HashMap::new() }
+    }
+}
+
+/// Represents the domain knowledge, including predicates, sorts, and plans.
+/// Cloning a domain is cheap: the copies share its tables until they are
+/// changed, individuals, sorts and aliases added to a copy being kept in an
+/// overlay of that copy (see IBISController::domain_mut).
+#[derive(Clone)]
+pub struct Domain {
+    name: Option<String>, // Name of the domain, e.g. "travel"
+    version: Option<String>, // Version of the domain, e.g. "1.2"
+    preds0: Arc<HashSet<String>>, // Zero-place predicates
+    preds1: Arc<HashMap<String, String>>, // One-place predicates with their sorts
+    sorts: SharedTable<HashSet<String>>, // Sorts and their individuals
+    inds: SharedTable<String>, // Individuals and their sorts
+    plans: Arc<HashMap<String, Arc<[String]>>>, // Question-triggered plans, shared with the dialogues carrying them out
+    plan_templates: Arc<HashMap<String, Arc<[String]>>>, // Plans with {parameter} placeholders, by trigger template
+    subplans: Arc<HashMap<String, Arc<[String]>>>, // Named plans called by CallPlan constructs
+    aliases: SharedTable<String>, // Predicate aliases used during interpretation
+    validators: Arc<HashMap<String, SortValidator>>, // Validators of sorts whose individuals are not enumerated
+    open_sorts: Arc<HashSet<String>>, // Sorts accepting new individuals at runtime
+    learned: Arc<HashSet<String>>, // Individuals of open sorts learned during dialogues
+    rules: Arc<Vec<Rule>>, // Inference rules over commitments and beliefs
+    conflict_strategy: ConflictStrategy, // How the applicable rules of a phase fire
+    functional: Arc<HashSet<String>>, // Predicates taking a single value per dialogue
+    query_preds: Arc<HashMap<String, Vec<String>>>, // Predicates relevant to database questions, by question
+    moves: Arc<HashMap<String, Arc<dyn CustomMove>>>, // Custom move kinds by name
+}
+
+/// Implementation of methods for the Domain struct.
+impl Domain {
+    /// Creates a new Domain.
+    /// # Arguments
+    /// * `preds0` - Zero-place predicates.
+    /// * `preds1` - One-place predicates with their sorts.
+    /// * `sorts` - Sorts and their individuals.
+    pub fn new(
+        preds0: HashSet<String>,
+        preds1: HashMap<String, String>,
+        sorts: HashMap<String, HashSet<String>>,
+    ) -> Self {
+        let inds = sorts
+            .iter()
+            .flat_map(|(sort, inds)| inds.iter().map(move |ind| (ind.clone(), sort.clone())))
+            .collect();
+        Domain {
+            name: None,
+            version: None,
+            preds0: Arc::new(preds0),
+            preds1: Arc::new(preds1),
+            sorts: SharedTable::new(sorts),
+            inds: SharedTable::new(inds),
+            plans: Arc::default(),
+            plan_templates: Arc::default(),
+            subplans: Arc::default(),
+            aliases: SharedTable::new(HashMap::new()),
+            validators: Arc::default(),
+            open_sorts: Arc::default(),
+            learned: Arc::default(),
+            rules: Arc::default(),
+            conflict_strategy: ConflictStrategy::ALL_APPLICABLE,
+            functional: Arc::default(),
+            query_preds: Arc::default(),
+            moves: Arc::default(),
+        }
+    }
+
+    /// Sets the name of the domain, reported in state dumps.
+    /// # Arguments
+    /// * `name` - The name (e.g., "travel").
+    pub fn set_name(&mut self, name: &str) {
+        self.name = Some(name.to_string());
+    }
+
+    /// Sets the version of the domain, reported in state dumps.
+    /// # Arguments
+    /// * `version` - The version (e.g., "1.2").
+    pub fn set_version(&mut self, version: &str) {
+        self.version = Some(version.to_string());
+    }
+
+    /// Returns the name of the domain, if set.
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    /// Returns the version of the domain, if set.
+    pub fn version(&self) -> Option<&str> {
+        self.version.as_deref()
+    }
+
+    /// Adds a plan for a question.
+    /// # Arguments
+    /// * `trigger` - The question that triggers the plan.
+    /// * `plan` - The plan constructors to execute.
+    pub fn add_plan(&mut self, trigger: Question, plan: Vec<String>) {
+        Arc::make_mut(&mut self.plans).insert(trigger.to_string(), plan.into());
+    }
+
+    /// Adds a named subplan, which plans call with a CallPlan construct (e.g.,
+    /// "CallPlan('collect_payment')") so that several tasks can share it.
+    /// # Arguments
+    /// * `name` - The name of the subplan.
+    /// * `plan` - The plan constructs to execute.
+    pub fn add_subplan(&mut self, name: &str, plan: Vec<String>) -> Result<(), String> {
+        CallPlan::new(name)?;
+        for construct in &plan {
+            PlanItem::new(construct)?;
+        }
+        let mut subplans = (*self.subplans).clone();
+        subplans.insert(name.to_string(), plan.into());
+        check_subplan_cycles(&subplans, [name])?;
+        self.subplans = Arc::new(subplans);
+        Ok(())
+    }
+
+    /// Adds a plan template: a plan for every question matching a trigger
+    /// with named parameters, e.g. "?book_trip({transport})" for
+    /// "?book_trip(train)". The values of the parameters, which are single
+    /// words, are substituted into the constructs of the plan (e.g.,
+    /// "Findout('?x.{transport}_class(x)')"). Plans added for a question
+    /// take precedence over templates.
+    /// # Arguments
+    /// * `trigger` - The question, with a placeholder per parameter.
+    /// * `plan` - The plan constructs, with placeholders among the parameters of the trigger.
+    pub fn add_plan_template(&mut self, trigger: &str, plan: Vec<String>) -> Result<(), String> {
+        let params = placeholders(trigger);
+        if params.is_empty() {
+            return Err(format!("Plan template {} has no parameters", trigger));
+        }
+        let sample: HashMap<String, String> = params.iter().map(|param| (param.clone(), "a".to_string())).collect();
+        Question::new(&fill_placeholders(trigger, &sample)).map_err(|e| format!("Invalid trigger {}: {}", trigger, e))?;
+        for construct in &plan {
+            if let Some(param) = placeholders(construct).into_iter().find(|param| !params.contains(param)) {
+                return Err(format!("Unknown parameter {} in {}", param, construct));
+            }
+            PlanItem::new(&fill_placeholders(construct, &sample))?;
+        }
+        Arc::make_mut(&mut self.plan_templates).insert(trigger.to_string(), plan.into());
+        Ok(())
+    }
+
+    /// Registers a custom move kind, so that moves such as "custom:BookTicket(paris)"
+    /// can be generated, interpreted and integrated.
+    /// # Arguments
+    /// * `kind` - The move kind.
+    pub fn add_move(&mut self, kind: impl CustomMove + 'static) -> Result<(), String> {
+        let name = Custom::new(kind.name(), &[])?.name;
+        if self.moves.contains_key(&name) {
+            return Err(format!("Move kind {} already exists", name));
+        }
+        Arc::make_mut(&mut self.moves).insert(name, Arc::new(kind));
+        Ok(())
+    }
+
+    /// Returns the custom move kind with a name, if registered.
+    /// # Arguments
+    /// * `name` - The name of the move kind.
+    fn custom_move(&self, name: &str) -> Option<&Arc<dyn CustomMove>> {
+        self.moves.get(name)
+    }
+
+    /// Registers a validator for a sort, so that any value it accepts is an
+    /// individual of the sort (e.g., three capital letters for "iata_code").
+    /// Enumerated individuals of the sort remain valid.
+    /// # Arguments
+    /// * `sort` - The sort.
+    /// * `validator` - The function accepting the individuals of the sort.
+    pub fn add_sort_validator<F>(&mut self, sort: &str, validator: F)
+    where
+        F: Fn(&str) -> bool + Send + Sync + 'static,
+    {
+        self.sorts.entry_or_default(sort);
+        Arc::make_mut(&mut self.validators).insert(sort.to_string(), Arc::new(validator));
+    }
+
+    /// Makes a sort open: values that are not individuals of another sort are
+    /// accepted as new individuals of it, subject to its validator if any, and
+    /// are recorded with add_individual once the user has used them.
+    /// # Arguments
+    /// * `sort` - The sort.
+    pub fn add_open_sort(&mut self, sort: &str) {
+        self.sorts.entry_or_default(sort);
+        Arc::make_mut(&mut self.open_sorts).insert(sort.to_string());
+    }
+
+    /// Checks if a sort accepts new individuals at runtime.
+    /// # Arguments
+    /// * `sort` - The sort.
+    pub fn is_open_sort(&self, sort: &str) -> bool {
+        self.open_sorts.contains(sort)
+    }
+
+    /// Records a new individual of a sort.
+    /// # Arguments
+    /// * `ind` - The individual.
+    /// * `sort` - The sort.
+    pub fn add_individual(&mut self, ind: &str, sort: &str) -> Result<(), String> {
+        Atomic::new(ind)?;
+        if let Some(known) = self.inds.get(ind) {
+            return if known == sort {
+                Ok(())
+            } else {
+                Err(format!("{} is already of sort {}", ind, known))
+            };
+        }
+        if self.validators.get(sort).is_some_and(|validator| !validator(ind)) {
+            return Err(format!("{} is not a valid {}", ind, sort));
+        }
+        self.sorts.entry_or_default(sort).insert(ind.to_string());
+        self.inds.insert(ind.to_string(), sort.to_string());
+        Ok(())
+    }
+
+    /// Records an individual of an open sort met during a dialogue. Unlike
+    /// the individuals added with add_individual, it is not part of the
+    /// content hash.
+    /// # Arguments
+    /// * `ind` - The individual.
+    /// * `sort` - The open sort.
+    fn learn_individual(&mut self, ind: &str, sort: &str) -> Result<(), String> {
+        self.add_individual(ind, sort)?;
+        Arc::make_mut(&mut self.learned).insert(ind.to_string());
+        Ok(())
+    }
+
+    /// Checks if a value is an individual of a sort: enumerated, accepted by
+    /// the validator of the sort, or new to an open sort without a validator.
+    /// # Arguments
+    /// * `ind` - The value.
+    /// * `sort` - The sort.
+    pub fn has_sort(&self, ind: &str, sort: &str) -> bool {
+        match self.inds.get(ind) {
+            Some(known) => known == sort,
+            None => match self.validators.get(sort) {
+                Some(validator) => validator(ind),
+                None => self.open_sorts.contains(sort),
+            },
+        }
+    }
+
+    /// Checks if a value is an individual of any sort.
+    /// # Arguments
+    /// * `ind` - The value.
+    pub fn is_individual(&self, ind: &str) -> bool {
+        self.inds.contains_key(ind)
+            || self.validators.values().any(|validator| validator(ind))
+            || self.open_sorts.iter().any(|sort| !self.validators.contains_key(sort))
+    }
+
+    /// Declares that a predicate takes a single value per dialogue, so that a new
+    /// value replaces the previous one instead of accumulating with it.
+    /// # Arguments
+    /// * `pred` - The one-place predicate (e.g., "dest_city").
+    pub fn declare_functional(&mut self, pred: &str) -> Result<(), String> {
+        if !self.preds1.contains_key(pred) {
+            return Err(format!("Unknown one-place predicate: {}", pred));
+        }
+        Arc::make_mut(&mut self.functional).insert(pred.to_string());
+        Ok(())
+    }
+
+    /// Checks if a predicate takes a single value per dialogue.
+    /// # Arguments
+    /// * `pred` - The predicate.
+    pub fn is_functional(&self, pred: &str) -> bool {
+        self.functional.contains(pred)
+    }
+
+    /// Declares the predicates whose commitments are relevant to a database
+    /// question, instead of those derived from the plans consulting it.
+    /// # Arguments
+    /// * `question` - The question consulted (e.g., "?x.price(x)").
+    /// * `preds` - The predicates (e.g., ["dest_city", "depart_day"]).
+    pub fn set_query_predicates(&mut self, question: &str, preds: &[&str]) -> Result<(), String> {
+        let question = Question::new(question)?;
+        let unknown = preds.iter().find(|pred| !self.preds0.contains(**pred) && !self.preds1.contains_key(**pred));
+        if let Some(pred) = unknown {
+            return Err(format!("Unknown predicate: {}", pred));
+        }
+        let preds = preds.iter().map(|pred| pred.to_string()).collect();
+        Arc::make_mut(&mut self.query_preds).insert(question.to_string(), preds);
+        Ok(())
+    }
+
+    /// Returns the predicates whose commitments are relevant to a database
+    /// question: those declared with set_query_predicates or else those found
+    /// out or raised by the plans consulting it, with their branches,
+    /// recoveries and subplans. None if no plan consults it.
+    /// # Arguments
+    /// * `question` - The question consulted.
+    pub fn query_predicates(&self, question: &Question) -> Option<HashSet<String>> {
+        let key = question.to_string();
+        if let Some(preds) = self.query_preds.get(&key) {
+            return Some(preds.iter().cloned().collect());
+        }
+        let mut relevant = None;
+        for plan in self.plans.values() {
+            let items = flatten_plan(plan.iter().cloned(), &self.subplans);
+            let consults = items
+                .iter()
+                .any(|item| matches!(item, PlanItem::ConsultDB(consult) if consult.content.to_string() == key));
+            if !consults {
+                continue;
+            }
+            let preds: &mut HashSet<String> = relevant.get_or_insert_default();
+            for item in &items {
+                let que = match item {
+                    PlanItem::Findout(findout) => &findout.content,
+                    PlanItem::Raise(raise) => &raise.content,
+                    _ => continue,
+                };
+                preds.extend(que.pred().map(str::to_string));
+            }
+        }
+        relevant
+    }
+
+    /// Adds an inference rule. Rules whose conclusion would feed back into
+    /// their own premises through other rules are rejected.
+    /// # Arguments
+    /// * `rule` - The rule.
+    pub fn add_rule(&mut self, rule: Rule) -> Result<(), String> {
+        // Follow the predicates derived from the conclusion back to the premises
+        let target: HashSet<&str> = rule.premises.iter().map(|p| p.pred()).collect();
+        let mut frontier = vec![vec![rule.conclusion.pred().to_string()]];
+        while let Some(chain) = frontier.pop() {
+            let last = chain.last().unwrap();
+            if target.contains(last.as_str()) {
+                let cycle: Vec<&str> = std::iter::once(last).chain(&chain).map(|p| p.as_str()).collect();
+                return Err(format!("Rule {} creates a cycle: {}", rule, cycle.join(" -> ")));
+            }
+            for next in self.rules.iter() {
+                let pred = next.conclusion.pred().to_string();
+                if next.premises.iter().any(|p| p.pred() == last) && !chain.contains(&pred) {
+                    let mut longer = chain.clone();
+                    longer.push(pred);
+                    frontier.push(longer);
+                }
+            }
+        }
+        Arc::make_mut(&mut self.rules).push(rule);
+        Ok(())
+    }
+
+    /// Sets how the applicable rules of a phase fire. By default all of them
+    /// fire, in order of priority.
+    /// # Arguments
+    /// * `strategy` - The conflict resolution strategy.
+    pub fn set_conflict_strategy(&mut self, strategy: ConflictStrategy) {
+        self.conflict_strategy = strategy;
+    }
+
+    /// Derives propositions from facts by applying the rules of every phase, in
+    /// phase order, returning each derived proposition with the rule deriving it.
+    /// # Arguments
+    /// * `facts` - The propositions that hold.
+    pub fn infer(&self, facts: &TSet<Prop>) -> Vec<(Rule, Prop)> {
+        let mut facts = facts.clone();
+        let mut derived = Vec::new();
+        for phase in RulePhase::iter() {
+            for (rule, prop) in self.infer_phase(&phase, &facts) {
+                facts.add(prop.clone()).ok();
+                derived.push((rule, prop));
+            }
+        }
+        derived
+    }
+
+    /// Derives propositions from facts by applying the rules of a phase until
+    /// nothing new follows. Applicable rules fire highest priority first, in
+    /// the order they were added otherwise, and a rule is blocked when the
+    /// negation of its conclusion holds. With the first-match strategy only
+    /// the first applicable rule fires. Rules with a precondition need an
+    /// information state and do not fire.
+    /// # Arguments
+    /// * `phase` - The phase.
+    /// * `facts` - The propositions that hold.
+    pub fn infer_phase(&self, phase: &RulePhase, facts: &TSet<Prop>) -> Vec<(Rule, Prop)> {
+        self.infer_phase_when(phase, facts, |_| false)
+    }
+
+    /// Derives propositions from facts by applying the rules of a phase whose
+    /// preconditions hold, as infer_phase does.
+    /// # Arguments
+    /// * `phase` - The phase.
+    /// * `facts` - The propositions that hold.
+    /// * `holds` - Checks a precondition.
+    fn infer_phase_when(
+        &self,
+        phase: &RulePhase,
+        facts: &TSet<Prop>,
+        holds: impl Fn(&Condition) -> bool,
+    ) -> Vec<(Rule, Prop)> {
+        let mut rules: Vec<&Rule> = self
+            .rules
+            .iter()
+            .filter(|rule| &rule.phase == phase && rule.precondition.as_ref().is_none_or(&holds))
+            .collect();
+        rules.sort_by_key(|rule| std::cmp::Reverse(rule.priority));
+        let mut facts = facts.clone();
+        let mut derived = Vec::new();
+        loop {
+            let Some(rule) = rules.iter().find(|rule| {
+                !facts.contains(&rule.conclusion) && !facts.contains(&rule.conclusion.negated()) && rule.fires(&facts)
+            }) else {
+                return derived;
+            };
+            facts.add(rule.conclusion.clone()).ok();
+            derived.push(((*rule).clone(), rule.conclusion.clone()));
+            if self.conflict_strategy == ConflictStrategy::FIRST_MATCH {
+                return derived;
+            }
+        }
+    }
+
+    /// Registers an alias for a predicate (e.g., "going to" for dest_city).
+    /// # Arguments
+    /// * `alias` - The word or phrase used by speakers.
+    /// * `pred` - The predicate it stands for.
+    pub fn add_alias(&mut self, alias: &str, pred: &str) -> Result<(), String> {
+        if !self.preds0.contains(pred) && !self.preds1.contains_key(pred) {
+            return Err(format!("Unknown predicate: {}", pred));
+        }
+        self.aliases.insert(alias.to_string(), pred.to_string());
+        Ok(())
+    }
+
+    /// Exports the domain as a JSON object for editors and visualizers, with
+    /// the fields "name", "version", "preds0", "preds1", "sorts" (with their
+    /// individuals), "open_sorts", "functional", "query_predicates", "aliases", "plans",
+    /// "plan_templates", "subplans", "rules", "conflict_strategy" and
+    /// "dependencies". Rules with a priority or phase
+    /// are written "premises => conclusion [PHASE, priority]". The
+    /// dependencies list, for each plan, the questions it finds out, raises or
+    /// branches on; they are derived from the plans and ignored by from_json.
+    /// Sort validators are functions and are not exported.
+    pub fn to_json(&self) -> serde_json::Value {
+        fn sorted(items: &HashSet<String>) -> Vec<&String> {
+            let mut items: Vec<&String> = items.iter().collect();
+            items.sort();
+            items
+        }
+        let sorts: BTreeMap<&String, Vec<&String>> =
+            self.sorts.iter().map(|(sort, inds)| (sort, sorted(inds))).collect();
+        let dependencies: BTreeMap<&String, Vec<String>> = self
+            .plans
+            .iter()
+            .map(|(trigger, plan)| {
+                let mut questions = Vec::new();
+                for item in flatten_plan(plan.iter().cloned(), &self.subplans) {
+                    let que = match item {
+                        PlanItem::Findout(findout) => findout.content.to_string(),
+                        PlanItem::Raise(raise) => raise.content.to_string(),
+                        PlanItem::If(cond) => cond.cond.to_string(),
+                        _ => continue,
+                    };
+                    if !questions.contains(&que) {
+                        questions.push(que);
+                    }
+                }
+                (trigger, questions)
+            })
+            .collect();
+        serde_json::json!({
+            "name": self.name,
+            "version": self.version,
+            "preds0": sorted(&self.preds0),
+            "preds1": self.preds1.iter().collect::<BTreeMap<_, _>>(),
+            "sorts": sorts,
+            "open_sorts": sorted(&self.open_sorts),
+            "functional": sorted(&self.functional),
+            "query_predicates": self.query_preds.iter().collect::<BTreeMap<_, _>>(),
+            "aliases": self.aliases.iter().collect::<BTreeMap<_, _>>(),
+            "plans": self.plans.iter().map(|(trigger, plan)| (trigger, &plan[..])).collect::<BTreeMap<_, _>>(),
+            "plan_templates": self
+                .plan_templates
+                .iter()
+                .map(|(trigger, plan)| (trigger, &plan[..]))
+                .collect::<BTreeMap<_, _>>(),
+            "subplans": self.subplans.iter().map(|(name, plan)| (name, &plan[..])).collect::<BTreeMap<_, _>>(),
+            "rules": self.rules.iter().map(|rule| rule.to_string()).collect::<Vec<_>>(),
+            "conflict_strategy": self.conflict_strategy,
+            "dependencies": dependencies,
+        })
+    }
+
+    /// Returns a hash of the content of the domain, as 16 hexadecimal digits.
+    /// It is the FNV-1a hash of the JSON export without the individuals
+    /// learned during dialogues, so it is stable across runs, builds and
+    /// sessions and changes whenever the domain as defined does.
+    pub fn content_hash(&self) -> String {
+        let mut json = self.to_json();
+        if let Some(sorts) = json["sorts"].as_object_mut() {
+            for inds in sorts.values_mut().filter_map(serde_json::Value::as_array_mut) {
+                inds.retain(|ind| ind.as_str().is_none_or(|ind| !self.learned.contains(ind)));
+            }
+        }
+        format!("{:016x}", fnv1a(&json.to_string()))
+    }
+
+    /// Checks if the domain admits a proposition: its predicate exists with
+    /// the right arity and its individual, if any, is of the sort of the
+    /// predicate. Values of sorts without individuals, validator or openness
+    /// (e.g., prices given by the database) are not checked.
+    /// # Arguments
+    /// * `prop` - The proposition.
+    pub fn admits(&self, prop: &Prop) -> bool {
+        match (prop.ind(), self.preds1.get(prop.pred())) {
+            (None, None) => self.preds0.contains(prop.pred()),
+            (Some(_), None) => false,
+            (None, Some(_)) => true,
+            (Some(ind), Some(sort)) => {
+                let unconstrained = self.sorts.get(sort).is_none_or(|inds| inds.is_empty())
+                    && !self.validators.contains_key(sort)
+                    && !self.open_sorts.contains(sort);
+                unconstrained || self.has_sort(&ind.to_string(), sort)
+            }
+        }
+    }
+
+    /// Loads a domain exported with to_json. Missing fields are taken as empty.
+    /// # Arguments
+    /// * `json` - The JSON object.
+    pub fn from_json(json: &serde_json::Value) -> Result<Self, String> {
+        fn field<T: serde::de::DeserializeOwned + Default>(json: &serde_json::Value, name: &str) -> Result<T, String> {
+            match json.get(name) {
+                None | Some(serde_json::Value::Null) => Ok(T::default()),
+                Some(value) => serde_json::from_value(value.clone()).map_err(|e| format!("Invalid {}: {}", name, e)),
+            }
+        }
+        let mut domain = Domain::new(field(json, "preds0")?, field(json, "preds1")?, field(json, "sorts")?);
+        domain.name = field(json, "name")?;
+        domain.version = field(json, "version")?;
+        for sort in field::<Vec<String>>(json, "open_sorts")? {
+            domain.add_open_sort(&sort);
+        }
+        for pred in field::<Vec<String>>(json, "functional")? {
+            domain.declare_functional(&pred)?;
+        }
+        for (que, preds) in field::<HashMap<String, Vec<String>>>(json, "query_predicates")? {
+            domain.set_query_predicates(&que, &preds.iter().map(String::as_str).collect::<Vec<_>>())?;
+        }
+        for (alias, pred) in field::<HashMap<String, String>>(json, "aliases")? {
+            domain.add_alias(&alias, &pred)?;
+        }
+        for (trigger, plan) in field::<HashMap<String, Vec<String>>>(json, "plans")? {
+            domain.add_plan(Question::new(&trigger)?, plan);
+        }
+        for (trigger, plan) in field::<HashMap<String, Vec<String>>>(json, "plan_templates")? {
+            domain.add_plan_template(&trigger, plan)?;
+        }
+        for (name, plan) in field::<HashMap<String, Vec<String>>>(json, "subplans")? {
+            domain.add_subplan(&name, plan)?;
+        }
+        for rule in field::<Vec<String>>(json, "rules")? {
+            let (premises, conclusion) = rule
+                .split_once("=>")
+                .ok_or(format!("Could not parse rule: {}", rule))?;
+            let (premises, precondition) = match premises.trim().strip_prefix("when ") {
+                Some(precondition) => ("", Some(precondition)),
+                None => match premises.split_once(" when ") {
+                    Some((premises, precondition)) => (premises, Some(precondition)),
+                    None => (premises, None),
+                },
+            };
+            let premises: Vec<&str> = split_top_level(premises).into_iter().map(str::trim).collect();
+            let (conclusion, options) = match conclusion.trim().strip_suffix(']').and_then(|c| c.rsplit_once('[')) {
+                Some((conclusion, options)) => (conclusion.trim(), Some(options)),
+                None => (conclusion.trim(), None),
+            };
+            let mut parsed = match precondition.map(Condition::new).transpose()? {
+                Some(precondition) if premises.is_empty() => Rule::when(precondition, conclusion)?,
+                Some(precondition) => Rule::new(&premises, conclusion)?.with_precondition(precondition),
+                None => Rule::new(&premises, conclusion)?,
+            };
+            if let Some((phase, priority)) = options.and_then(|options| options.split_once(',')) {
+                parsed = parsed
+                    .with_phase(phase.trim().parse()?)
+                    .with_priority(priority.trim().parse().map_err(|_| format!("Could not parse rule: {}", rule))?);
+            }
+            domain.add_rule(parsed)?;
+        }
+        if let Some(strategy) = field::<Option<ConflictStrategy>>(json, "conflict_strategy")? {
+            domain.set_conflict_strategy(strategy);
+        }
+        Ok(domain)
+    }
+
+    /// Checks the plans for questions whose answers can never be relevant
+    /// (e.g., because their sort has no individuals), If conditions the plan
+    /// never settles, branches an enclosing If rules out, and plans that never
+    /// respond or consult the database. Conditions settled only by a knowledge
+    /// base are reported too, since the domain cannot see it.
+    pub fn lint_plans(&self) -> Vec<PlanWarning> {
+        let mut triggers: Vec<&String> = self.plans.keys().collect();
+        triggers.sort();
+        let derived: HashSet<&str> = self.rules.iter().map(|rule| rule.conclusion.pred()).collect();
+        let mut warnings = Vec::new();
+        for trigger in triggers {
+            let plan = &self.plans[trigger];
+            let items = flatten_plan(plan.iter().cloned(), &self.subplans);
+            let settled: HashSet<String> = items
+                .iter()
+                .filter_map(|item| match item {
+                    PlanItem::Findout(findout) => Some(findout.content.to_string()),
+                    PlanItem::Raise(raise) => Some(raise.content.to_string()),
+                    PlanItem::ConsultDB(consult) => Some(consult.content.to_string()),
+                    _ => None,
+                })
+                .collect();
+            for item in &items {
+                let warning = match item {
+                    PlanItem::Findout(Findout { content: que }) | PlanItem::Raise(Raise { content: que })
+                        if !self.answerable(que) =>
+                    {
+                        PlanWarning::IrrelevantQuestion { plan: trigger.clone(), question: que.to_string() }
+                    }
+                    PlanItem::If(cond)
+                        if !settled.contains(&cond.cond.to_string())
+                            && !cond.cond.pred().is_some_and(|pred| derived.contains(pred)) =>
+                    {
+                        PlanWarning::UnraisedCondition { plan: trigger.clone(), question: cond.cond.to_string() }
+                    }
+                    _ => continue,
+                };
+                if !warnings.contains(&warning) {
+                    warnings.push(warning);
+                }
+            }
+            if !items.iter().any(|item| matches!(item, PlanItem::Respond(_) | PlanItem::ConsultDB(_))) {
+                warnings.push(PlanWarning::NoAnswer { plan: trigger.clone() });
+            }
+            self.lint_branches(trigger, plan, &mut Vec::new(), &mut warnings);
+        }
+        warnings
+    }
+
+    /// Reports the branches of If constructs whose condition an enclosing If
+    /// has already decided.
+    /// # Arguments
+    /// * `trigger` - The question triggering the plan.
+    /// * `plan` - The constructs to check.
+    /// * `decided` - The conditions decided by the enclosing branches, with their outcome.
+    /// * `warnings` - The warnings found so far.
+    fn lint_branches(
+        &self,
+        trigger: &str,
+        plan: &[String],
+        decided: &mut Vec<(String, bool)>,
+        warnings: &mut Vec<PlanWarning>,
+    ) {
+        for item in plan {
+            let Ok(PlanItem::If(cond)) = PlanItem::new(item) else {
+                continue;
+            };
+            let question = cond.cond.to_string();
+            let outcome = decided.iter().find(|(que, _)| *que == question).map(|(_, outcome)| *outcome);
+            let ruled_out = match outcome {
+                Some(true) => !cond.iffalse.is_empty(),
+                Some(false) => !cond.iftrue.is_empty(),
+                None => false,
+            };
+            if ruled_out {
+                warnings.push(PlanWarning::UnreachableBranch { plan: trigger.to_string(), construct: cond.to_string() });
+            }
+            for (branch, taken) in [(&cond.iftrue, true), (&cond.iffalse, false)] {
+                if outcome.is_some_and(|outcome| outcome != taken) {
+                    continue;
+                }
+                decided.push((question.clone(), taken));
+                self.lint_branches(trigger, branch, decided, warnings);
+                decided.pop();
+            }
+        }
+    }
+
+    /// Checks if some answer to a question could be relevant.
+    /// # Arguments
+    /// * `question` - The question.
+    fn answerable(&self, question: &Question) -> bool {
+        let inhabited = |sort: &String| {
+            self.sorts.get(sort).is_some_and(|inds| !inds.is_empty())
+                || self.validators.contains_key(sort)
+                || self.open_sorts.contains(sort)
+        };
+        let proposition = |prop: &Prop| match prop.ind() {
+            None => self.preds0.contains(prop.pred()),
+            Some(ind) => self.preds1.get(prop.pred()).is_some_and(|sort| self.has_sort(&ind.to_string(), sort)),
+        };
+        match question {
+            Question::WhQ(whq) => self.preds1.get(&whq.pred.to_string()).is_some_and(inhabited),
+            Question::YNQ(ynq) => proposition(&ynq.prop),
+            Question::AltQ(altq) => altq.ynqs.iter().all(|ynq| proposition(&ynq.prop)),
+        }
+    }
+
+    /// Returns the words of the domain: predicates, individuals and aliases, sorted.
+    pub fn vocabulary(&self) -> Vec<String> {
+        let mut words: Vec<String> = self
+            .preds0
+            .iter()
+            .chain(self.preds1.keys())
+            .chain(self.inds.keys())
+            .chain(self.aliases.keys())
+            .cloned()
+            .collect();
+        words.sort();
+        words.dedup();
+        words
+    }
+
+    /// Adds the predicates, individuals, plans and aliases of another domain.
+    /// Shared names must mean the same in both domains: a predicate must have
+    /// the same sort, an individual the same sort, an alias the same predicate
+    /// and a question the same plan. Nothing is added if any of them collide.
+    /// # Arguments
+    /// * `other` - The domain to add.
+    pub fn merge(&mut self, other: Domain) -> Result<(), String> {
+        let mut collisions = Vec::new();
+        for pred in other.preds0.iter() {
+            if self.preds1.contains_key(pred) {
+                collisions.push(format!("predicate {}", pred));
+            }
+        }
+        for (pred, sort) in other.preds1.iter() {
+            if self.preds0.contains(pred) || self.preds1.get(pred).is_some_and(|s| s != sort) {
+                collisions.push(format!("predicate {}", pred));
+            }
+        }
+        for (ind, sort) in other.inds.iter() {
+            if self.inds.get(ind).is_some_and(|s| s != sort) {
+                collisions.push(format!("individual {}", ind));
+            }
+        }
+        for (trigger, plan) in other.plans.iter() {
+            if self.plans.get(trigger).is_some_and(|p| p != plan) {
+                collisions.push(format!("plan for {}", trigger));
+            }
+        }
+        for (trigger, plan) in other.plan_templates.iter() {
+            if self.plan_templates.get(trigger).is_some_and(|p| p != plan) {
+                collisions.push(format!("plan template for {}", trigger));
+            }
+        }
+        for (name, plan) in other.subplans.iter() {
+            if self.subplans.get(name).is_some_and(|p| p != plan) {
+                collisions.push(format!("subplan {}", name));
+            }
+        }
+        for (alias, pred) in other.aliases.iter() {
+            if self.aliases.get(alias).is_some_and(|p| p != pred) {
+                collisions.push(format!("alias {}", alias));
+            }
+        }
+        for (sort, validator) in other.validators.iter() {
+            if self.validators.get(sort).is_some_and(|v| !Arc::ptr_eq(v, validator)) {
+                collisions.push(format!("validator for {}", sort));
+            }
+        }
+        for (name, kind) in other.moves.iter() {
+            if self.moves.get(name).is_some_and(|k| !Arc::ptr_eq(k, kind)) {
+                collisions.push(format!("move kind {}", name));
+            }
+        }
+        if !collisions.is_empty() {
+            collisions.sort();
+            return Err(format!("Conflicting definitions: {}", collisions.join(", ")));
+        }
+        let mut subplans = (*self.subplans).clone();
+        subplans.extend(other.subplans.iter().map(|(name, plan)| (name.clone(), plan.clone())));
+        check_subplan_cycles(&subplans, other.subplans.keys().map(String::as_str))?;
+        Arc::make_mut(&mut self.preds0).extend(Arc::unwrap_or_clone(other.preds0));
+        Arc::make_mut(&mut self.preds1).extend(Arc::unwrap_or_clone(other.preds1));
+        for (sort, inds) in other.sorts.iter() {
+            self.sorts.entry_or_default(sort).extend(inds.iter().cloned());
+        }
+        for (ind, sort) in other.inds.iter() {
+            self.inds.insert(ind.clone(), sort.clone());
+        }
+        for (alias, pred) in other.aliases.iter() {
+            self.aliases.insert(alias.clone(), pred.clone());
+        }
+        Arc::make_mut(&mut self.plans).extend(Arc::unwrap_or_clone(other.plans));
+        Arc::make_mut(&mut self.plan_templates).extend(Arc::unwrap_or_clone(other.plan_templates));
+        Arc::make_mut(&mut self.subplans).extend(Arc::unwrap_or_clone(other.subplans));
+        Arc::make_mut(&mut self.validators).extend(Arc::unwrap_or_clone(other.validators));
+        Arc::make_mut(&mut self.moves).extend(Arc::unwrap_or_clone(other.moves));
+        Arc::make_mut(&mut self.open_sorts).extend(Arc::unwrap_or_clone(other.open_sorts));
+        Arc::make_mut(&mut self.learned).extend(Arc::unwrap_or_clone(other.learned));
+        Arc::make_mut(&mut self.functional).extend(Arc::unwrap_or_clone(other.functional));
+        Arc::make_mut(&mut self.query_preds).extend(Arc::unwrap_or_clone(other.query_preds));
+        for rule in Arc::unwrap_or_clone(other.rules) {
+            if !self.rules.contains(&rule) {
+                Arc::make_mut(&mut self.rules).push(rule);
+            }
+        }
+        Ok(())
+    }
+
+    /// Adds another domain with its predicates put in a namespace
+    /// (e.g., "temperature" becomes "weather:temperature"), rewriting its plans
+    /// and aliases accordingly. Sorts and individuals are shared and merged.
+    /// # Arguments
+    /// * `namespace` - The namespace (e.g., "weather").
+    /// * `other` - The domain to add.
+    pub fn include(&mut self, namespace: &str, other: Domain) -> Result<(), String> {
+        if namespace.contains(':') || Aggregate::new(namespace).is_some() || Atomic::new(namespace).is_err() {
+            return Err(format!("Invalid namespace: {}", namespace));
+        }
+        let names: HashMap<String, String> = other
+            .preds0
+            .iter()
+            .chain(other.preds1.keys())
+            .map(|pred| (pred.clone(), format!("{}:{}", namespace, pred)))
+            .collect();
+        let domain = Domain {
+            name: other.name,
+            version: other.version,
+            preds0: Arc::new(other.preds0.iter().map(|pred| names[pred].clone()).collect()),
+            preds1: Arc::new(other.preds1.iter().map(|(pred, sort)| (names[pred].clone(), sort.clone())).collect()),
+            sorts: other.sorts,
+            inds: other.inds,
+            plans: Arc::new(
+                other
+                    .plans
+                    .iter()
+                    .map(|(trigger, plan)| {
+                        let plan = plan.iter().map(|construct| rename_preds(construct, &names)).collect();
+                        (rename_preds(trigger, &names), plan)
+                    })
+                    .collect(),
+            ),
+            plan_templates: Arc::new(
+                other
+                    .plan_templates
+                    .iter()
+                    .map(|(trigger, plan)| {
+                        let plan = plan.iter().map(|construct| rename_preds(construct, &names)).collect();
+                        (rename_preds(trigger, &names), plan)
+                    })
+                    .collect(),
+            ),
+            subplans: Arc::new(
+                other
+                    .subplans
+                    .iter()
+                    .map(|(name, plan)| {
+                        let plan = plan.iter().map(|construct| rename_preds(construct, &names)).collect();
+                        (name.clone(), plan)
+                    })
+                    .collect(),
+            ),
+            aliases: other.aliases.iter().map(|(alias, pred)| (alias.clone(), names[pred].clone())).collect(),
+            validators: other.validators,
+            moves: other.moves,
+            open_sorts: other.open_sorts,
+            learned: other.learned,
+            functional: Arc::new(other.functional.iter().map(|pred| names[pred].clone()).collect()),
+            query_preds: Arc::new(
+                other
+                    .query_preds
+                    .iter()
+                    .map(|(que, preds)| (rename_preds(que, &names), preds.iter().map(|p| names[p].clone()).collect()))
+                    .collect(),
+            ),
+            rules: Arc::new(
+                other
+                    .rules
+                    .iter()
+                    .filter_map(|rule| {
+                        let rename = |prop: &Prop| Prop::new(&rename_preds(&prop.to_string(), &names));
+                        let premises = rule.premises.iter().map(rename).collect::<Result<_, _>>().ok()?;
+                        let conclusion = rename(&rule.conclusion).ok()?;
+                        let precondition = rule.precondition.as_ref().map(|precondition| precondition.renamed(&names));
+                        Some(Rule { premises, conclusion, precondition, ..rule.clone() })
+                    })
+                    .collect(),
+            ),
+            conflict_strategy: other.conflict_strategy,
+        };
+        self.merge(domain)
+    }
+
+    /// Returns the predicate a word stands for, resolving aliases.
+    /// # Arguments
+    /// * `word` - A predicate name or alias.
+    pub fn resolve_pred(&self, word: &str) -> Option<&str> {
+        self.preds0
+            .get(word)
+            .or_else(|| self.preds1.get_key_value(word).map(|(pred, _)| pred))
+            .or_else(|| self.aliases.get(word))
+            .map(|pred| pred.as_str())
+    }
+
+    /// Interprets an input such as "I am going to paris" as a proposition
+    /// using the predicate aliases: an alias followed by an individual of the
+    /// predicate's sort, anywhere in the input on word boundaries. Longer
+    /// aliases are tried first. Individuals of open sorts that are not known
+    /// yet are only taken at the end of the input.
+    /// # Arguments
+    /// * `input` - The input string to interpret.
+    pub fn interpret_alias(&self, input: &str) -> Option<Prop> {
+        let words: Vec<&str> = input
+            .split_whitespace()
+            .map(|word| word.trim_matches(|c: char| !(c.is_alphanumeric() || c == '_')))
+            .filter(|word| !word.is_empty())
+            .collect();
+        let mut aliases: Vec<(&String, &String)> = self.aliases.iter().collect();
+        aliases.sort_by_key(|(alias, _)| std::cmp::Reverse(alias.len()));
+        aliases.into_iter().find_map(|(alias, pred)| {
+            if input == alias && self.preds0.contains(pred) {
+                return Prop::new(pred).ok();
+            }
+            let sort = self.preds1.get(pred)?;
+            let alias: Vec<&str> = alias.split_whitespace().collect();
+            let n = alias.len();
+            let (_, ind) = words.windows(n + 1).enumerate().find(|(i, run)| {
+                let ind = run[n];
+                let known = self.inds.get(ind) == Some(sort) || self.validators.get(sort).is_some_and(|v| v(ind));
+                run[..n] == alias[..] && (known || (i + n + 1 == words.len() && self.has_sort(ind, sort)))
+            })?;
+            Prop::new(&format!("{}({})", pred, ind[n])).ok()
+        })
+    }
+
+    /// Checks if an answer is relevant to a question.
+    /// # Arguments
+    /// * `answer` - The answer to check.
+    /// * `question` - The question to check against.
+    fn relevant(&self, answer: &Ans, question: &Question) -> bool {
+        match (answer, question) {
+            (Ans::Prop(prop), _) => question.unify(prop).is_some(),
+            (Ans::Conj(conj), _) => conj.conjuncts.iter().any(|prop| question.unify(prop).is_some()),
+            (Ans::ShortAns(short), Question::WhQ(whq)) => {
+                self.preds1
+                    .get(&whq.pred.0.content)
+                    .is_some_and(|sort| self.has_sort(&short.ind.0.content, sort))
+            }
+            (Ans::YesNo(_), Question::YNQ(_)) => true,
+            (Ans::YesNo(_), Question::AltQ(_)) => true,
+            (Ans::Any | Ans::Unknown, Question::WhQ(_)) => true,
+            _ => false,
+        }
+    }
+
+    /// Checks if an answer resolves a question.
+    /// # Arguments
+    /// * `answer` - The answer to check.
+    /// * `question` - The question to check against.
+    fn resolves(&self, answer: &Ans, question: &Question) -> bool {
+        if let Ans::Conj(conj) = answer {
+            return conj.conjuncts.iter().any(|prop| self.resolves(&Ans::Prop(prop.clone()), question));
+        }
+        if self.relevant(answer, question) {
+            match (answer, question) {
+                (Ans::YesNo(_), Question::YNQ(_)) => true,
+                (Ans::Prop(_), Question::YNQ(_)) => true,
+                (Ans::ShortAns(short), Question::WhQ(whq)) => short.yes && whq.admits(&short.ind),
+                (Ans::Prop(prop), Question::WhQ(whq)) => {
+                    prop.yes && question
+                        .unify(prop)
+                        .is_some_and(|bindings| !bindings.is_empty() && bindings.values().all(|ind| whq.admits(ind)))
+                }
+                _ => false,
+            }
+        } else {
+            false
+        }
+    }
+
+    /// Combines a question and answer into a proposition.
+    /// # Arguments
+    /// * `question` - The question.
+    /// * `answer` - The answer.
+    fn combine(&self, question: &Question, answer: &Ans) -> Result<Prop, Box<dyn std::error::Error>> {
+        assert!(self.relevant(answer, question));
+        match (question, answer) {
+            (Question::WhQ(whq), Ans::Any) => Ok(whq.pred.apply(&Ind::new(ANY)?)?),
+            (Question::WhQ(_), Ans::ShortAns(short)) => {
+                let variable = question.variable().unwrap_or_default().to_string();
+                let mut prop = question
+                    .instantiate(&Bindings::from([(variable, short.ind.clone())]))
+                    .ok_or("Could not instantiate question")?;
+                prop.yes = short.yes;
+                Ok(prop)
+            }
+            (Question::YNQ(ynq), Ans::YesNo(yesno)) => {
+                let mut prop = ynq.prop.clone();
+                if prop.yes != yesno.yes {
+                    prop.yes = !prop.yes;
+                }
+                Ok(prop)
+            }
+            _ => match answer {
+                Ans::Prop(p) => Ok(p.clone()),
+                Ans::Conj(c) => Ok(c.conjuncts.iter().find(|p| question.unify(p).is_some()).cloned().ok_or("No relevant conjunct")?),
+                _ => panic!("Invalid combination"),
+            },
+        }
+    }
+
+    /// Retrieves the plan for a question.
+    /// Constrained and aggregate wh-questions without a plan of their own use the
+    /// plan of the plain question, consulting the database with the question asked.
+    /// # Arguments
+    /// * `question` - The question to get the plan for.
+    fn get_plan(&self, question: &Question) -> Option<Plan> {
+        let plan = match (self.plan_constructs(&question.to_string()), question) {
+            (Some(plan), _) => plan,
+            (None, Question::WhQ(whq)) => {
+                let base = Question::WhQ(whq.base());
+                let consult = ConsultDB::new(base.clone()).to_string();
+                self.plan_constructs(&base.to_string())?
+                    .iter()
+                    .map(|construct| {
+                        if *construct == consult {
+                            ConsultDB::new(question.clone()).to_string()
+                        } else {
+                            construct.clone()
+                        }
+                    })
+                    .collect()
+            }
+            (None, _) => return None,
+        };
+        Some(Plan::new(plan))
+    }
+
+    /// Returns the plan constructs for a question: those of the plan added
+    /// for it, or else those of the first plan template, in alphabetical
+    /// order of the triggers, whose trigger it matches, with the parameters
+    /// substituted.
+    /// # Arguments
+    /// * `question` - The question, as a string.
+    fn plan_constructs(&self, question: &str) -> Option<Arc<[String]>> {
+        if let Some(plan) = self.plans.get(question) {
+            return Some(plan.clone());
+        }
+        let mut triggers: Vec<&String> = self.plan_templates.keys().collect();
+        triggers.sort();
+        triggers.into_iter().find_map(|trigger| {
+            let values = match_placeholders(trigger, question)?;
+            Some(self.plan_templates[trigger].iter().map(|construct| fill_placeholders(construct, &values)).collect())
+        })
+    }
+}
+
+/// Returns the names of the {name} placeholders of a template, in order.
+/// # Arguments
+/// * `template` - The template (e.g., "?book_trip({transport})").
+fn placeholders(template: &str) -> Vec<String> {
+    template
+        .split('{')
+        .skip(1)
+        .filter_map(|part| part.split_once('}').map(|(name, _)| name.to_string()))
+        .collect()
+}
+
+/// Substitutes values for the {name} placeholders of a template. Placeholders
+/// without a value are left as they are.
+/// # Arguments
+/// * `template` - The template.
+/// * `values` - The value of each placeholder by name.
+fn fill_placeholders(template: &str, values: &HashMap<String, String>) -> String {
+    values
+        .iter()
+        .fold(template.to_string(), |text, (name, value)| text.replace(&format!("{{{}}}", name), value))
+}
+
+/// Matches a string against a template, returning the value of each {name}
+/// placeholder. Values are single words of letters, digits and underscores,
+/// and a placeholder occurring twice must have the same value.
+/// # Arguments
+/// * `template` - The template (e.g., "?book_trip({transport})").
+/// * `s` - The string (e.g., "?book_trip(train)").
+fn match_placeholders(template: &str, s: &str) -> Option<HashMap<String, String>> {
+    let mut values: HashMap<String, String> = HashMap::new();
+    let (mut template, mut s) = (template, s);
+    while let Some(start) = template.find('{') {
+        let (literal, rest) = template.split_at(start);
+        s = s.strip_prefix(literal)?;
+        let (name, rest) = rest[1..].split_once('}')?;
+        let end = s.find(|c: char| !c.is_alphanumeric() && c != '_').unwrap_or(s.len());
+        if end == 0 || values.get(name).is_some_and(|value| *value != s[..end]) {
+            return None;
+        }
+        values.insert(name.to_string(), s[..end].to_string());
+        (template, s) = (rest, &s[end..]);
+    }
+    (template == s).then_some(values)
+}
+
+// Plan state
+
+/// The state of a plan being carried out in a dialogue. The constructs of the
+/// domain plan are shared rather than copied: executing them moves a cursor,
+/// and only constructs added during the dialogue (e.g., the branch of an If)
+/// are held by the plan itself. Like a Stack, the next construct is the top.
+#[derive(Clone, Default)]
+struct Plan {
+    shared: Arc<[String]>, // Constructs of the domain plan, next first
+    cursor: usize, // Shared constructs already executed
+    pushed: Vec<String>, // Constructs added during the dialogue, the last one next
+}
+
+/// Implementation of methods for the Plan struct.
+impl Plan {
+    /// Creates a new Plan carrying out shared constructs.
+    /// # Arguments
+    /// * `shared` - The constructs, next first.
+    fn new(shared: Arc<[String]>) -> Self {
+        Plan { shared, cursor: 0, pushed: Vec::new() }
+    }
+
+    /// Returns the shared constructs not executed yet, next first.
+    fn remaining(&self) -> &[String] {
+        &self.shared[self.cursor..]
+    }
+
+    /// Returns the next construct.
+    fn top(&self) -> Result<&String, String> {
+        self.pushed.last().or(self.remaining().first()).ok_or("Stack is empty".to_string())
+    }
+
+    /// Removes and returns the next construct.
+    fn pop(&mut self) -> Result<String, String> {
+        if let Some(construct) = self.pushed.pop() {
+            return Ok(construct);
+        }
+        let construct = self.remaining().first().cloned().ok_or("Stack is empty".to_string())?;
+        self.cursor += 1;
+        Ok(construct)
+    }
+
+    /// Adds a construct to execute next.
+    /// # Arguments
+    /// * `construct` - The construct.
+    fn push(&mut self, construct: String) -> Result<(), String> {
+        self.pushed.push(construct);
+        Ok(())
+    }
+
+    /// Returns the number of constructs left.
+    fn len(&self) -> usize {
+        self.remaining().len() + self.pushed.len()
+    }
+
+    /// Checks if no constructs are left.
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns an iterator over the constructs from the last to the next.
+    fn iter(&self) -> impl Iterator<Item = &String> {
+        self.remaining().iter().rev().chain(self.pushed.iter())
+    }
+
+    /// Returns an iterator over the constructs from the next to the last.
+    fn iter_rev(&self) -> impl Iterator<Item = &String> {
+        self.pushed.iter().rev().chain(self.remaining().iter())
+    }
+}
+
+/// Formats the Plan for display like a Stack, next construct first.
+impl fmt::Display for Plan {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let constructs: Vec<&str> = self.iter_rev().map(String::as_str).collect();
+        write!(f, "<[ {} <]", constructs.join(", "))
+    }
+}
+
+// IBIS Information State
+
+/// A fact waiting to be told to the user. Notifications are held back while
+/// the system is collecting answers for a plan and delivered by priority once
+/// it is not, unless they expire first.
+#[derive(Clone)]
+pub struct Notification {
+    pub prop: Prop, // The fact to tell, e.g. "cancelled(flight)"
+    pub priority: u32, // Higher priorities are told first
+    pub expires: Option<SystemTime>, // When the fact is no longer worth telling
+}
+
+/// Implementation of methods for the Notification struct.
+impl Notification {
+    /// Creates a new Notification with the lowest priority that does not expire.
+    /// # Arguments
+    /// * `prop` - The fact to tell.
+    pub fn new(prop: Prop) -> Self {
+        Notification { prop, priority: 0, expires: None }
+    }
+
+    /// Sets the priority of the notification.
+    /// # Arguments
+    /// * `priority` - The priority; higher priorities are told first.
+    pub fn with_priority(mut self, priority: u32) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    /// Makes the notification expire some time from now.
+    /// # Arguments
+    /// * `ttl` - The time the notification stays worth telling.
+    pub fn expiring_in(mut self, ttl: Duration) -> Self {
+        self.expires = Some(SystemTime::now() + ttl);
+        self
+    }
+
+    /// Returns whether the notification has expired at a given time.
+    /// # Arguments
+    /// * `now` - The time to check.
+    pub fn is_expired(&self, now: SystemTime) -> bool {
+        self.expires.is_some_and(|expires| now >= expires)
+    }
+}
+
+/// Formats the Notification for display (e.g., "cancelled(flight)!2").
+impl fmt::Display for Notification {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}!{}", self.prop, self.priority)
+    }
+}
+
+/// An application-defined field of the information state holding one of a
+/// fixed set of values, e.g. a mood or an authentication status.
+#[derive(Clone)]
+struct StateField {
+    values: Vec<String>, // Allowed values; the first one is the value at the start of a session
+    value: Value<String>, // Current value
+}
+
+/// Represents the Information-Based Inquiry System (IBIS) information state.
+/// The private part holds the agenda, plan and beliefs; the shared part holds
+/// the commitments and the questions under discussion (QUD).
+#[derive(Clone)]
+struct IBISInfostate {
+    agenda: Stack<String>, // Actions to perform in the near future
+    plan: Plan, // Plan constructors for the current issue
+    bel: TSet<String>, // Private beliefs of the session (e.g., database results); static facts live in the knowledge base
+    com: TSet<String>, // Shared commitments
+    qud: StackSet<String>, // Questions under discussion
+    issue: Value<String>, // Issue whose plan is being carried out
+    notifications: Vec<Notification>, // Facts waiting to be told, in arrival order
+    fields: BTreeMap<String, StateField>, // Application-defined fields, by name
+}
+
+/// Implementation of methods for the IBISInfostate struct.
+impl IBISInfostate {
+    /// Creates a new, empty information state.
+    fn new() -> Self {
+        IBISInfostate {
+            agenda: Stack::with_type(|item: &String| AgendaItem::new(item).is_ok()),
+            plan: Plan::default(),
+            bel: TSet::new(),
+            com: TSet::new(),
+            qud: StackSet::new(),
+            issue: Value::new_type(|_: &String| true),
+            notifications: Vec::new(),
+            fields: BTreeMap::new(),
+        }
+    }
+
+    /// Initializes the information state with default fields. Application-defined
+    /// fields are kept and set back to their first value.
+    fn init_is(&mut self) {
+        let mut fields = std::mem::take(&mut self.fields);
+        for field in fields.values_mut() {
+            field.value.set(field.values[0].clone()).ok();
+        }
+        *self = Self::new();
+        self.fields = fields;
+    }
+
+    /// Prints the information state with a prefix.
+    /// # Arguments
+    /// * `prefix` - The prefix for each line.
+    fn print_is(&self, prefix: &str) {
+        print!("{}", self.format_is(prefix));
+    }
+
+    /// Estimates the bytes held by the commitments, beliefs, QUD, agenda and plan.
+    fn memory_stats(&self) -> MemoryStats {
+        MemoryStats {
+            com: string_bytes(self.com.iter()),
+            bel: string_bytes(self.bel.iter()),
+            qud: string_bytes(self.qud.iter()),
+            agenda: string_bytes(self.agenda.iter()),
+            plan: std::mem::size_of::<Plan>() + string_bytes(self.plan.pushed.iter()),
+            ..MemoryStats::default()
+        }
+    }
+
+    /// Formats the information state, one field per line, with a prefix.
+    /// # Arguments
+    /// * `prefix` - The prefix for each line.
+    fn format_is(&self, prefix: &str) -> String {
+        let notifications: Vec<String> = self.notifications.iter().map(|n| n.to_string()).collect();
+        let mut state = format!(
+            "{p}AGENDA:         {}\n{p}PLAN:           {}\n{p}BEL:            {}\n{p}COM:            {}\n{p}QUD:            {}\n{p}ISSUE:          {}\n{p}NOTIFICATIONS:  [{}]\n",
+            self.agenda,
+            self.plan,
+            self.bel,
+            self.com,
+            self.qud,
+            self.issue,
+            notifications.join(", "),
+            p = prefix
+        );
+        for (name, field) in &self.fields {
+            state.push_str(&format!("{}{:<16}{}\n", prefix, format!("{}:", name.to_uppercase()), field.value));
+        }
+        state
+    }
+}
+
+// Unmatched inputs
+
+/// An input the grammar could not interpret, with the question the system
+/// had asked when it was given.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct UnmatchedInput {
+    pub input: String, // The user input, after the input filters
+    pub question: Option<String>, // The topmost question under discussion, if any
+}
+
+/// Unmatched inputs grouped by the known word or phrase they come closest to.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct UnmatchedCluster {
+    pub pattern: Option<String>, // The closest alias, individual or predicate, or None if nothing is close
+    pub inputs: Vec<String>, // The inputs of the cluster, in the order they were given
+}
+
+/// What users said that the system did not understand, for domain authors
+/// deciding which vocabulary and forms to add.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct UnmatchedReport {
+    pub clusters: Vec<UnmatchedCluster>, // Largest clusters first
+    pub suggested_aliases: Vec<(String, String)>, // Phrases and the predicates they seem to stand for
+}
+
+/// Formats the UnmatchedReport for display, one cluster or suggestion per line.
+impl fmt::Display for UnmatchedReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for cluster in &self.clusters {
+            let pattern = cluster.pattern.as_deref().unwrap_or("?");
+            writeln!(f, "{} ({}): {}", pattern, cluster.inputs.len(), cluster.inputs.join(" | "))?;
+        }
+        for (alias, pred) in &self.suggested_aliases {
+            writeln!(f, "add_alias(\"{}\", \"{}\")", alias, pred)?;
+        }
+        Ok(())
+    }
+}
+
+/// Returns the number of character insertions, deletions and substitutions
+/// turning one string into another.
+/// # Arguments
+/// * `a` - The first string.
+/// * `b` - The second string.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let above = row[j + 1];
+            row[j + 1] = (above + 1).min(row[j] + 1).min(diagonal + usize::from(ca != *cb));
+            diagonal = above;
+        }
+    }
+    row[b.len()]
+}
+
+/// Checks if an input contains the words of a phrase in a row, ignoring case
+/// and punctuation (e.g., "I don't know." contains "don't know", while
+/// "no idealism" does not contain "no idea").
+/// # Arguments
+/// * `input` - The input.
+/// * `phrase` - The phrase (e.g., "don't know").
+fn contains_words(input: &str, phrase: &str) -> bool {
+    let words = |text: &str| -> Vec<String> {
+        text.split(|c: char| !(c.is_alphanumeric() || c == '\''))
+            .filter(|word| !word.is_empty())
+            .map(str::to_lowercase)
+            .collect()
+    };
+    let (input, phrase) = (words(input), words(phrase));
+    !phrase.is_empty() && input.windows(phrase.len()).any(|run| run == phrase)
+}
+
+/// Returns the alias, individual or predicate of the domain closest to a run
+/// of words in an input, if one is within a third of its length.
+/// # Arguments
+/// * `domain` - The domain whose vocabulary is searched.
+/// * `input` - The unmatched input.
+fn closest_pattern(domain: &Domain, input: &str) -> Option<String> {
+    let words: Vec<&str> = input.split_whitespace().collect();
+    let patterns = domain
+        .aliases
+        .keys()
+        .chain(domain.inds.keys())
+        .chain(domain.preds0.iter())
+        .chain(domain.preds1.keys());
+    patterns
+        .filter_map(|pattern| {
+            let width = pattern.split_whitespace().count().max(1);
+            let distance = words.windows(width).map(|run| edit_distance(&run.join(" "), pattern)).min()?;
+            (distance <= (pattern.len() / 3).max(1)).then_some((distance, pattern))
+        })
+        .min_by(|(d1, p1), (d2, p2)| d1.cmp(d2).then_with(|| p1.cmp(p2)))
+        .map(|(_, pattern)| pattern.clone())
+}
+
+/// Returns a phrase the input uses for the predicate of a wh-question, when the
+/// input is the phrase followed by an individual of the predicate's sort
+/// (e.g., "heading to" for "?x.dest_city(x)" given "heading to paris").
+/// # Arguments
+/// * `domain` - The domain.
+/// * `unmatched` - The unmatched input and the question it answered.
+fn suggest_alias(domain: &Domain, unmatched: &UnmatchedInput) -> Option<(String, String)> {
+    let Ok(Question::WhQ(whq)) = Question::new(unmatched.question.as_deref()?) else {
+        return None;
+    };
+    let pred = &whq.pred.0.content;
+    let sort = domain.preds1.get(pred)?;
+    let (phrase, ind) = unmatched.input.trim().rsplit_once(' ')?;
+    let phrase = phrase.trim();
+    if !domain.has_sort(ind, sort) || domain.aliases.contains_key(phrase) {
+        return None;
+    }
+    Some((phrase.to_string(), pred.clone()))
+}
+
+// Coverage
+
+create_enum!(
+    /// The kind of an item tracked by dialogue coverage.
+    #[allow(non_camel_case_types)]
+    CoverageKind, PLAN_ITEM, RULE, FORM
+);
+
+/// A plan construct, rule or grammar form, with the times the dialogues exercised it.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct CoverageItem {
+    pub kind: CoverageKind, // What the item is
+    pub name: String, // The item (e.g., "?x.price(x) 4/then/0: Findout('?x.dest_city(x)')", a rule or a form's move)
+    pub hits: usize, // Times it was carried out, fired or generated
+}
+
+/// Which plan constructs, rules and grammar forms a test corpus exercised,
+/// the dialogue analogue of code coverage for domain authors. Plan constructs
+/// are named after the question of their plan and their position in it: the
+/// index of the construct, preceded for the constructs of a branch by the
+/// position of the If and "then" or "else", of a recovery by the position of
+/// the OnFailure and "recover", and of a subplan by the position of the
+/// CallPlan and "call" (e.g., "?x.price(x) 4/then/0"). Constructs count once
+/// carried out, rules once fired and forms once their move is generated.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct CoverageReport {
+    pub items: Vec<CoverageItem>, // Plan constructs, then rules, then forms
+}
+
+impl CoverageReport {
+    /// Returns the items the dialogues never exercised.
+    pub fn never_hit(&self) -> Vec<&CoverageItem> {
+        self.items.iter().filter(|item| item.hits == 0).collect()
+    }
+
+    /// Returns the share of the items of a kind that were exercised, 1.0 if there are none.
+    /// # Arguments
+    /// * `kind` - The kind of items.
+    pub fn ratio(&self, kind: &CoverageKind) -> f64 {
+        let items: Vec<&CoverageItem> = self.items.iter().filter(|item| item.kind == *kind).collect();
+        if items.is_empty() {
+            return 1.0;
+        }
+        items.iter().filter(|item| item.hits > 0).count() as f64 / items.len() as f64
+    }
+}
+
+/// Formats the CoverageReport for display: the share of each kind of items
+/// exercised, followed by the items never exercised.
+impl fmt::Display for CoverageReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for kind in CoverageKind::iter() {
+            let total = self.items.iter().filter(|item| item.kind == kind).count();
+            let hit = self.items.iter().filter(|item| item.kind == kind && item.hits > 0).count();
+            writeln!(f, "{}: {}/{} ({:.0}%)", kind, hit, total, self.ratio(&kind) * 100.0)?;
+            for item in self.never_hit().into_iter().filter(|item| item.kind == kind) {
+                writeln!(f, "  never hit: {}", item.name)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// IBIS Controller
+
+/// A handover of the dialogue to a human agent
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Escalation {
+    pub reason: String, // Why the system gave up, e.g. "repeated_ask ?x.how(x)"
+    pub state: serde_json::Value, // The dialogue state when the system gave up, as dumped by dump()
+}
+
+/// An error that interrupted the update of the information state in a turn,
+/// e.g. a malformed plan construct. The information state is rolled back to
+/// the start of the update, so the dialogue can go on.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct TurnError {
+    pub turn: usize, // The turn in which the error occurred
+    pub message: String, // What went wrong
+}
+
+/// Formats the TurnError for display (e.g., "turn 4: Could not parse plan construct: ...").
+impl fmt::Display for TurnError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "turn {}: {}", self.turn, self.message)
+    }
+}
+
+/// Safeguards against unbounded dialogues and misbehaving plans or rules.
+/// Limits left unset are not enforced, except the rounds of the update rules,
+/// which default to MAX_UPDATE_ITERATIONS.
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub struct Limits {
+    max_turns: Option<usize>, // User inputs per session
+    max_qud_depth: Option<usize>, // Questions under discussion
+    max_plan_depth: Option<usize>, // Constructs in the plan
+    max_agenda_size: Option<usize>, // Actions on the agenda
+    max_update_iterations: Option<usize>, // Rounds of the update rules per turn
+}
+
+/// The rounds of the update rules a turn may take when no limit is set.
+pub const MAX_UPDATE_ITERATIONS: usize = 100;
+
+impl Limits {
+    /// Creates a new Limits enforcing nothing.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the user inputs a session may have.
+    /// # Arguments
+    /// * `max_turns` - The number of inputs.
+    pub fn with_max_turns(mut self, max_turns: usize) -> Self {
+        self.max_turns = Some(max_turns);
+        self
+    }
+
+    /// Sets the questions that may be under discussion at once.
+    /// # Arguments
+    /// * `max_qud_depth` - The number of questions.
+    pub fn with_max_qud_depth(mut self, max_qud_depth: usize) -> Self {
+        self.max_qud_depth = Some(max_qud_depth);
+        self
+    }
+
+    /// Sets the constructs the plan may hold.
+    /// # Arguments
+    /// * `max_plan_depth` - The number of constructs.
+    pub fn with_max_plan_depth(mut self, max_plan_depth: usize) -> Self {
+        self.max_plan_depth = Some(max_plan_depth);
+        self
+    }
+
+    /// Sets the actions the agenda may hold.
+    /// # Arguments
+    /// * `max_agenda_size` - The number of actions.
+    pub fn with_max_agenda_size(mut self, max_agenda_size: usize) -> Self {
+        self.max_agenda_size = Some(max_agenda_size);
+        self
+    }
+
+    /// Sets the rounds of the update rules a turn may take before the update
+    /// stops short of a fixpoint.
+    /// # Arguments
+    /// * `max_update_iterations` - The number of rounds.
+    pub fn with_max_update_iterations(mut self, max_update_iterations: usize) -> Self {
+        self.max_update_iterations = Some(max_update_iterations);
+        self
+    }
+}
+
+/// The limit that ended a dialogue, with its configured maximum
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum LimitExceeded {
+    Turns(usize), // Too many user inputs
+    QudDepth(usize), // Too many questions under discussion
+    PlanDepth(usize), // Too many constructs in the plan
+    AgendaSize(usize), // Too many actions on the agenda
+}
+
+impl fmt::Display for LimitExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LimitExceeded::Turns(max) => write!(f, "max_turns {}", max),
+            LimitExceeded::QudDepth(max) => write!(f, "max_qud_depth {}", max),
+            LimitExceeded::PlanDepth(max) => write!(f, "max_plan_depth {}", max),
+            LimitExceeded::AgendaSize(max) => write!(f, "max_agenda_size {}", max),
+        }
+    }
+}
+
+/// A host hook called when the controller hands the dialogue over.
+type EscalationHandler = Box<dyn Fn(&Escalation)>;
+
+/// Callback notified of errors interrupting a turn
+type ErrorHandler = Box<dyn Fn(&TurnError)>;
+
+/// A host hook fulfilling an issue with the slot values collected by its plan.
+type FulfillmentHandler = Box<dyn Fn(&HashMap<String, Ind>)>;
+
+/// A hook producing the surface string of a move at generation time.
+type GenerationHook = Rc<dyn Fn(&Move, &IBISController) -> Option<String>>;
+
+/// An event from the host application interrupting a running dialogue. Events
+/// are handled at the next turn boundary.
+#[derive(Clone)]
+pub enum SystemEvent {
+    Inform(Prop), // A fact the system tells the user, e.g. "cancelled(flight)"
+    Notify(Notification), // A fact queued until a good moment to tell it
+    Pause, // A request to stop the dialogue until it is resumed
+    SetField(String, String), // A new value of an application-defined field, e.g. ("mood", "angry")
+}
+
+/// The progress of the plan for the issue being dealt with.
+#[derive(Clone, PartialEq, Debug)]
+pub struct TaskStatus {
+    pub issue: String, // The issue, e.g. "?x.price(x)"
+    pub resolved: Vec<String>, // Questions of the plan already answered, in plan order
+    pub remaining: Vec<String>, // Questions of the plan still to be answered, in plan order
+    pub consulted: Option<bool>, // Whether consulting the database answered the issue; None if not consulted (yet)
+}
+
+impl TaskStatus {
+    /// Returns the number of questions answered and the number of questions
+    /// known to be needed (e.g., "3 of 5 details collected").
+    pub fn progress(&self) -> (usize, usize) {
+        (self.resolved.len(), self.resolved.len() + self.remaining.len())
+    }
+
+    /// Checks if all questions have been answered and the issue is resolved.
+    pub fn is_complete(&self) -> bool {
+        self.remaining.is_empty() && self.consulted != Some(false)
+    }
+}
+
+/// A summary of a conversation: the issues the user raised that were
+/// resolved, the questions still open and the commitments made.
+#[derive(Clone, PartialEq, Debug, Default)]
+pub struct ConversationSummary {
+    pub resolved: Vec<(String, String)>, // Issues raised by the user with the proposition resolving each, e.g. ("?x.price(x)", "price(232)")
+    pub outstanding: Vec<String>, // Questions raised or planned but not answered, in the order they come up
+    pub commitments: Vec<String>, // Shared commitments, sorted
+}
+
+/// Formats the ConversationSummary for display, one part per line.
+impl fmt::Display for ConversationSummary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let resolved: Vec<String> = self.resolved.iter().map(|(que, prop)| format!("{} -> {}", que, prop)).collect();
+        writeln!(f, "RESOLVED:     [{}]", resolved.join(", "))?;
+        writeln!(f, "OUTSTANDING:  [{}]", self.outstanding.join(", "))?;
+        writeln!(f, "COMMITMENTS:  [{}]", self.commitments.join(", "))
+    }
+}
+
+/// An estimate of the bytes held by each part of a dialogue session, for
+/// monitoring long-running sessions and tuning history retention.
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub struct MemoryStats {
+    pub com: usize, // Shared commitments
+    pub bel: usize, // Private beliefs
+    pub qud: usize, // Questions under discussion
+    pub agenda: usize, // Actions on the agenda
+    pub plan: usize, // Plan state; the constructs shared with the domain are not counted
+    pub history: usize, // Moves made in the session
+    pub transcript: usize, // Utterances exchanged in the session
+}
+
+impl MemoryStats {
+    /// Returns the bytes held by all parts.
+    pub fn total(&self) -> usize {
+        self.com + self.bel + self.qud + self.agenda + self.plan + self.history + self.transcript
+    }
+}
+
+/// Formats the MemoryStats for display, one part per line.
+impl fmt::Display for MemoryStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "COM:          {} B", self.com)?;
+        writeln!(f, "BEL:          {} B", self.bel)?;
+        writeln!(f, "QUD:          {} B", self.qud)?;
+        writeln!(f, "AGENDA:       {} B", self.agenda)?;
+        writeln!(f, "PLAN:         {} B", self.plan)?;
+        writeln!(f, "HISTORY:      {} B", self.history)?;
+        writeln!(f, "TRANSCRIPT:   {} B", self.transcript)?;
+        writeln!(f, "TOTAL:        {} B", self.total())
+    }
+}
+
+/// How much of the history and transcript of a session is kept. Older turns
+/// are dropped, or compacted into a summary record first. At least the
+/// current turn is always kept.
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub enum HistoryRetention {
+    #[default]
+    KeepAll, // Keep every turn
+    KeepLast(usize), // Keep the last N turns and drop the older ones
+    Summarize(usize), // Keep the last N turns and compact the older ones into the summary record
+}
+
+/// The record of the turns compacted out of the history of a session.
+#[derive(Clone, PartialEq, Debug, Default)]
+pub struct HistorySummary {
+    pub turns: usize, // Turns compacted, from the first turn of the session
+    pub moves: usize, // Moves made in those turns
+    pub issues: Vec<String>, // Questions the user asked in those turns, in order
+}
+
+/// Estimates the bytes held by strings, counting each string and its buffer.
+/// # Arguments
+/// * `strings` - The strings.
+fn string_bytes<'a>(strings: impl Iterator<Item = &'a String>) -> usize {
+    strings.map(|s| std::mem::size_of::<String>() + s.capacity()).sum()
+}
+
+/// A measurement taken while a turn is run, exported when the `metrics`
+/// feature is enabled.
+#[cfg_attr(not(feature = "metrics"), allow(dead_code))]
+pub(crate) enum TurnMetric {
+    Interpretation { latency: Duration, understood: bool }, // A user input was interpreted
+    RulesFired(u64), // Inferences drawn by the domain rules
+    Database(Duration), // The database was consulted
+}
+
+/// The version of the format of state dumps. Dumps of older formats are
+/// migrated when restored.
+pub const STATE_FORMAT_VERSION: u64 = 2;
+
+/// Migrations of state dumps, each from its format to the next one, starting
+/// from format 1. A change to the format adds one and bumps
+/// STATE_FORMAT_VERSION.
+const STATE_MIGRATIONS: [fn(&mut serde_json::Map<String, serde_json::Value>); STATE_FORMAT_VERSION as usize - 1] =
+    [add_turn_holder];
+
+/// Migrates a state dump from format 1 to 2, which added the turn holder.
+/// The system holds the turn.
+/// # Arguments
+/// * `state` - The dump.
+fn add_turn_holder(state: &mut serde_json::Map<String, serde_json::Value>) {
+    if let Some(serde_json::Value::Object(mivs)) = state.get_mut("mivs") {
+        mivs.insert("turn_holder".to_string(), "SYS".into());
+    }
+}
+
+/// Migrates a state dump to the current format. Dumps without a format field
+/// predate it and are of format 1.
+/// # Arguments
+/// * `state` - The dump.
+pub fn migrate_state(state: serde_json::Value) -> Result<serde_json::Value, String> {
+    let serde_json::Value::Object(mut state) = state else {
+        return Err("A state dump must be a JSON object".to_string());
+    };
+    let format = match state.get("format") {
+        None => 1,
+        Some(format) => format
+            .as_u64()
+            .filter(|format| *format >= 1)
+            .ok_or(format!("Invalid state format: {}", format))?,
+    };
+    if format > STATE_FORMAT_VERSION {
+        return Err(format!(
+            "State format {} is newer than the supported format {}; upgrade the crate to restore it",
+            format, STATE_FORMAT_VERSION
+        ));
+    }
+    for migration in &STATE_MIGRATIONS[format as usize - 1..] {
+        migration(&mut state);
+    }
+    state.insert("format".to_string(), STATE_FORMAT_VERSION.into());
+    Ok(serde_json::Value::Object(state))
+}
+
+/// What restoring a state dump does when it was saved with a different domain
+/// than the controller has, as told by their content hashes.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum DomainMismatch {
+    #[default]
+    Migrate, // Restore the state, dropping commitments the new domain no longer admits
+    Refuse, // Refuse to restore the state
+}
+
+create_enum!(
+    /// How much of the working of the dialogue manager is printed.
+    Verbosity, QUIET, STATE, TRACE
+);
+
+create_enum!(
+    /// When the system confirms the answers it integrates by repeating them
+    /// ("Okay, paris."): never, when the interpretation is less confident
+    /// than a threshold, or always.
+    #[allow(non_camel_case_types)]
+    Confirmation, NEVER, LOW_CONFIDENCE, ALWAYS
+);
+
+/// Controls the IBIS dialogue system.
+pub struct IBISController {
+    is: IBISInfostate, // Information state
+    mivs: StandardMIVS, // Minimal information state
+    domain: Arc<Domain>, // Domain knowledge, possibly shared with other controllers
+    database: Box<dyn Database>, // Database consulted by the plans
+    knowledge: Box<dyn KnowledgeBase>, // Static world knowledge shared by all sessions
+    grammar: Box<dyn Grammar>, // Grammar for generation and interpretation
+    input_handler: Box<dyn InputHandler>, // Input handling abstraction
+    output_handler: Box<dyn OutputHandler>, // Output handling abstraction
+    silence_timeout: Option<Duration>, // Time the user may stay silent before being reminded
+    max_reminders: usize, // Reminders given before a silent session is closed
+    reminders: usize, // Reminders given since the user last spoke
+    acknowledgements: f64, // Chance of acknowledging an integrated user answer
+    confirmation: Confirmation, // When integrated user answers are confirmed
+    confirmation_threshold: f64, // Confidence below which answers are confirmed under LOW_CONFIDENCE
+    verbosity: Verbosity, // What the dialogue manager prints
+    max_reasks: Option<usize>, // Times a question may be asked again before escalating
+    max_misunderstandings: Option<usize>, // Inputs in a row not understood before escalating
+    asks: HashMap<String, usize>, // Times each question has been asked
+    misunderstandings: usize, // Inputs in a row not understood
+    escalation_handler: Option<EscalationHandler>, // Host hook receiving handovers
+    generation_hooks: Vec<(String, GenerationHook)>, // Hooks generating moves, with the pattern of their moves
+    session_forms: HashMap<String, String>, // Outputs of moves added for this session over the grammar
+    session_aliases: Vec<(String, String)>, // Phrases standing for words in this session, longest first
+    variants: Vec<(String, String)>, // Variants of the experiments the controller takes part in, by experiment
+    error_handler: Option<ErrorHandler>, // Host hook receiving turn errors
+    fulfillments: HashMap<String, FulfillmentHandler>, // Host hooks called when the plan of an issue completes
+    completed: Vec<(String, HashMap<String, Ind>)>, // Issues completed in the current update, with their slots
+    errors: Vec<TurnError>, // Errors that interrupted turns of this session
+    plan_failure: Option<String>, // Why a plan construct last failed in this session
+    input_filters: Vec<Box<dyn TextFilter>>, // Filters applied to user input, in order
+    tokenizer: Box<dyn Tokenizer>, // Tokenizer of user input before interpretation
+    locale: Option<Locale>, // How values are formatted in outputs, if localized
+    output_filters: Vec<Box<dyn TextFilter>>, // Filters applied to system output, in order
+    limits: Limits, // Safeguards against unbounded dialogues
+    turns: usize, // User inputs in this session
+    limit_exceeded: Option<LimitExceeded>, // The limit that ended the session, if any
+    rng: Rng, // Source of randomness, restarted from its seed for each session
+    history: Vec<TaggedMove>, // Moves made in this session, in order
+    retention: HistoryRetention, // How much of the history and transcript is kept
+    domain_mismatch: DomainMismatch, // What restoring a state saved with another domain does
+    compacted: HistorySummary, // Record of the turns compacted out of the history
+    transcript: Vec<(usize, Speaker, String)>, // Utterances of this session with their turn
+    handled_by: Vec<(usize, String)>, // Grammar that interpreted each user utterance, by turn
+    events: mpsc::Receiver<SystemEvent>, // Events sent by the host application
+    event_sender: mpsc::Sender<SystemEvent>, // Sender handed out to the host application
+    paused: bool, // Whether the dialogue stops at the next turn boundary
+    kernel: Kernel, // Modules of the dialogue system and the control algorithm running them
+    unmatched: Vec<UnmatchedInput>, // Inputs not understood, kept across sessions
+    coverage: Option<HashMap<(CoverageKind, String), usize>>, // Times each item was exercised, if tracked
+    deferred: HashSet<String>, // Questions the user could not answer yet
+    profiles: Option<Box<dyn ProfileStore>>, // Store of user preferences across sessions
+    preferences: Vec<Prop>, // The saved preferences of the user
+    offered: HashSet<String>, // Questions whose saved answer has been offered
+    #[cfg(feature = "metrics")]
+    metrics: Option<DialogueMetrics>, // Instruments recording per-turn metrics
+    streaming: bool, // Whether utterances are presented sentence by sentence as they are generated
+}
+
+/// The dialogue state an update may change, saved at its start so that a
+/// failing update is undone as a whole: the information states, the
+/// individuals the domain learned, the escalation counters and state, the
+/// deferred and offered questions and the history.
+struct UpdateSnapshot {
+    is: IBISInfostate, // Information state
+    mivs: StandardMIVS, // Minimal information state, with the program state
+    domain: Arc<Domain>, // Domain, with the individuals of open sorts learned
+    asks: HashMap<String, usize>, // Times each question has been asked
+    misunderstandings: usize, // Inputs in a row not understood
+    deferred: HashSet<String>, // Questions the user could not answer yet
+    offered: HashSet<String>, // Questions whose saved answer has been offered
+    plan_failure: Option<String>, // Why a plan construct last failed
+    limit_exceeded: Option<LimitExceeded>, // The limit that ended the session, if any
+    history: usize, // Moves in the history
+}
+
+/// Implementation of methods for the IBISController struct.
+impl IBISController {
+    /// Creates a new IBISController.
+    /// # Arguments
+    /// * `domain` - The domain knowledge, or an Arc sharing it with other controllers.
+    /// * `database` - The database consulted by the plans (e.g., a TravelDB).
+    /// * `grammar` - The grammar for dialogue.
+    pub fn new(
+        domain: impl Into<Arc<Domain>>,
+        database: impl Database + 'static,
+        grammar: impl Grammar + 'static,
+    ) -> Self {
+        Self::with_input_handler(domain, database, grammar, Box::new(StandardInputHandler::new()))
+    }
+    
+    pub fn with_input_handler(
+        domain: impl Into<Arc<Domain>>,
+        database: impl Database + 'static,
+        grammar: impl Grammar + 'static,
+        input_handler: Box<dyn InputHandler>,
+    ) -> Self {
+        Self::with_handlers(domain, database, grammar, input_handler, Box::new(StandardOutputHandler::new()))
+    }
+
+    /// Creates a new IBISController with custom input and output handling.
+    /// # Arguments
+    /// * `domain` - The domain knowledge, or an Arc sharing it with other controllers.
+    /// * `database` - The database consulted by the plans (e.g., a TravelDB).
+    /// * `grammar` - The grammar for dialogue.
+    /// * `input_handler` - The source of user input.
+    /// * `output_handler` - The presenter of system utterances.
+    pub fn with_handlers(
+        domain: impl Into<Arc<Domain>>,
+        database: impl Database + 'static,
+        grammar: impl Grammar + 'static,
+        input_handler: Box<dyn InputHandler>,
+        output_handler: Box<dyn OutputHandler>,
+    ) -> Self {
+        let (event_sender, events) = mpsc::channel();
+        IBISController {
+            is: IBISInfostate::new(),
+            mivs: StandardMIVS::new(),
+            domain: domain.into(),
+            database: Box::new(database),
+            knowledge: Box::new(FactBase::new()),
+            grammar: Box::new(grammar),
+            input_handler,
+            output_handler,
+            silence_timeout: None,
+            max_reminders: 0,
+            reminders: 0,
+            acknowledgements: 0.0,
+            confirmation: Confirmation::NEVER,
+            confirmation_threshold: 1.0,
+            verbosity: Verbosity::TRACE,
+            max_reasks: None,
+            max_misunderstandings: None,
+            asks: HashMap::new(),
+            misunderstandings: 0,
+            escalation_handler: None,
+            generation_hooks: Vec::new(),
+            session_forms: HashMap::new(),
+            session_aliases: Vec::new(),
+            variants: Vec::new(),
+            error_handler: None,
+            fulfillments: HashMap::new(),
+            completed: Vec::new(),
+            errors: Vec::new(),
+            plan_failure: None,
+            input_filters: Vec::new(),
+            tokenizer: Box::new(WhitespaceTokenizer),
+            locale: None,
+            output_filters: Vec::new(),
+            limits: Limits::new(),
+            turns: 0,
+            limit_exceeded: None,
+            rng: Rng::from_time(),
+            history: Vec::new(),
+            retention: HistoryRetention::KeepAll,
+            domain_mismatch: DomainMismatch::Migrate,
+            compacted: HistorySummary::default(),
+            transcript: Vec::new(),
+            handled_by: Vec::new(),
+            events,
+            event_sender,
+            paused: false,
+            kernel: Kernel::standard(),
+            unmatched: Vec::new(),
+            coverage: None,
+            deferred: HashSet::new(),
+            profiles: None,
+            preferences: Vec::new(),
+            offered: HashSet::new(),
+            #[cfg(feature = "metrics")]
+            metrics: None,
+            streaming: false,
+        }
+    }
+
+    /// Sets the presenter of system utterances.
+    /// # Arguments
+    /// * `output_handler` - The presenter (e.g., a ChannelOutputHandler).
+    pub fn with_output_handler(mut self, output_handler: Box<dyn OutputHandler>) -> Self {
+        self.output_handler = output_handler;
+        self
+    }
+
+    /// Makes the system hand the dialogue over to a human agent when a question
+    /// has to be asked again too often or the user is not understood too many
+    /// times in a row.
+    /// # Arguments
+    /// * `max_reasks` - The times a question may be asked again.
+    /// * `max_misunderstandings` - The inputs in a row that may not be understood.
+    pub fn with_escalation(mut self, max_reasks: usize, max_misunderstandings: usize) -> Self {
+        self.max_reasks = Some(max_reasks);
+        self.max_misunderstandings = Some(max_misunderstandings);
+        self
+    }
+
+    /// Registers a handler receiving the reason and the information state when
+    /// the dialogue is handed over to a human agent.
+    /// # Arguments
+    /// * `handler` - The function to call with each escalation.
+    pub fn on_escalate<F>(&mut self, handler: F)
+    where
+        F: Fn(&Escalation) + 'static,
+    {
+        self.escalation_handler = Some(Box::new(handler));
+    }
+
+    /// Registers a hook producing the surface string of the moves matching a
+    /// pattern at generation time, for outputs that need computation such as
+    /// the current date or counts from the database. The hook receives the
+    /// move and the controller, and may return None to leave the move to the
+    /// grammar. Its output is used as it is, punctuation included. Hooks are
+    /// tried in the order they were registered.
+    /// # Arguments
+    /// * `pattern` - The moves, with "*" standing for any text (e.g., "Answer(count(*))").
+    /// * `hook` - The function producing the surface string.
+    pub fn add_generation_hook<F>(&mut self, pattern: &str, hook: F)
+    where
+        F: Fn(&Move, &IBISController) -> Option<String> + 'static,
+    {
+        self.generation_hooks.push((pattern.to_string(), Rc::new(hook)));
+    }
+
+    /// Adds the output of a move for this session only, e.g. a greeting
+    /// naming the user. Session forms are layered over the grammar, which may
+    /// be shared with other sessions: they are used before generation hooks
+    /// and the grammar, as they are, punctuation included.
+    /// # Arguments
+    /// * `move_str` - The move (e.g., "Greet()").
+    /// * `output` - Its output.
+    pub fn add_session_form(&mut self, move_str: &str, output: &str) -> Result<(), String> {
+        let mv = Move::new(move_str)?;
+        self.session_forms.insert(mv.to_string(), output.to_string());
+        Ok(())
+    }
+
+    /// Adds a phrase standing for a word in the input of this session only,
+    /// e.g. "my hometown" for an individual the user introduced. Phrases are
+    /// replaced by their word before the input is interpreted by the grammar,
+    /// longer phrases first.
+    /// # Arguments
+    /// * `phrase` - The phrase used by the user.
+    /// * `word` - The word the grammar understands (e.g., "springfield").
+    pub fn add_session_alias(&mut self, phrase: &str, word: &str) -> Result<(), String> {
+        let phrase = self.tokenizer.tokenize(phrase).join(" ");
+        if phrase.is_empty() {
+            return Err("Empty alias".to_string());
+        }
+        self.session_aliases.retain(|(known, _)| *known != phrase);
+        self.session_aliases.push((phrase, word.to_string()));
+        self.session_aliases.sort_by_key(|(phrase, _)| std::cmp::Reverse(phrase.split(' ').count()));
+        Ok(())
+    }
+
+    /// Registers a handler receiving the errors that interrupt the update of
+    /// the information state. The state is rolled back before the handler is
+    /// called.
+    /// # Arguments
+    /// * `handler` - The function to call with each error.
+    pub fn on_error<F>(&mut self, handler: F)
+    where
+        F: Fn(&TurnError) + 'static,
+    {
+        self.error_handler = Some(Box::new(handler));
+    }
+
+    /// Registers a handler fulfilling an issue, e.g. booking the trip once the
+    /// plan for "?x.price(x)" is done. The handler is called each time the
+    /// plan completes, with the value of each slot resolved by the
+    /// commitments by its predicate (e.g., "dest_city" to paris). Plans
+    /// abandoned after a failure are not fulfilled.
+    /// # Arguments
+    /// * `issue` - The question of the plan (e.g., "?x.price(x)").
+    /// * `handler` - The function to call with the slot values.
+    pub fn on_complete<F>(&mut self, issue: &str, handler: F) -> Result<(), String>
+    where
+        F: Fn(&HashMap<String, Ind>) + 'static,
+    {
+        let issue = Question::new(issue)?;
+        self.fulfillments.insert(issue.to_string(), Box::new(handler));
+        Ok(())
+    }
+
+    /// Returns the domain of the controller.
+    pub fn domain(&self) -> &Domain {
+        &self.domain
+    }
+
+    /// Returns the domain of the controller for changes. A domain shared with
+    /// other controllers is copied first, so that the changes are only seen
+    /// by this one. The copy shares the tables of the domain: individuals,
+    /// sorts and aliases added to it are kept in an overlay of the session,
+    /// consulted before the shared tables.
+    pub fn domain_mut(&mut self) -> &mut Domain {
+        Arc::make_mut(&mut self.domain)
+    }
+
+    /// Returns why a plan construct last failed in the session (e.g., "No
+    /// entry answers ?x.price(x)"), if any did. Failures are recovered from
+    /// by the OnFailure constructs of the plan rather than interrupting turns.
+    pub fn plan_failure(&self) -> Option<&str> {
+        self.plan_failure.as_deref()
+    }
+
+    /// Returns the errors that interrupted turns of the session, in order.
+    pub fn errors(&self) -> &[TurnError] {
+        &self.errors
+    }
+
+    /// Sets the limits that end a session when exceeded, saying goodbye.
+    /// # Arguments
+    /// * `limits` - The limits.
+    pub fn with_limits(mut self, limits: Limits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Sets how much of the history and transcript a session keeps, so that
+    /// memory stays bounded in indefinitely long sessions.
+    /// # Arguments
+    /// * `retention` - The retention policy.
+    pub fn with_history_retention(mut self, retention: HistoryRetention) -> Self {
+        self.retention = retention;
+        self
+    }
+
+    /// Sets what restoring a state dump saved with a different domain does.
+    /// # Arguments
+    /// * `policy` - The policy.
+    pub fn with_domain_mismatch(mut self, policy: DomainMismatch) -> Self {
+        self.domain_mismatch = policy;
+        self
+    }
+
+    /// Seeds the random number generator so that sessions are reproducible.
+    /// Each session restarts the sequence from the seed.
+    /// # Arguments
+    /// * `seed` - The seed.
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.rng = Rng::new(seed);
+        self
+    }
+
+    /// Returns the seed of the random number generator, e.g. to log it so
+    /// that a session can be replayed.
+    pub fn seed(&self) -> u64 {
+        self.rng.seed()
+    }
+
+    /// Returns the random number generator shared by the parts of the system.
+    pub fn rng(&mut self) -> &mut Rng {
+        &mut self.rng
+    }
+
+    /// Returns the agenda: the moves and constructs the system is about to
+    /// deal with, top first.
+    pub fn agenda(&self) -> Vec<AgendaItem> {
+        self.is.agenda.iter_rev().filter_map(|item| AgendaItem::new(item).ok()).collect()
+    }
+
+    /// Puts a move or construct on top of the agenda. Moves on the agenda are
+    /// made on the next system turn, in the order they were pushed.
+    /// # Arguments
+    /// * `item` - The agenda item.
+    pub fn push_agenda(&mut self, item: AgendaItem) {
+        self.is.agenda.push(item.to_string()).ok();
+    }
+
+    /// Puts a construct on top of the plan, to be carried out before the rest
+    /// of the plan.
+    /// # Arguments
+    /// * `construct` - The plan construct.
+    pub fn push_plan(&mut self, construct: PlanItem) {
+        self.is.plan.push(construct.to_string()).ok();
+    }
+
+    /// Returns the propositions the user and the system are committed to,
+    /// e.g. the slots collected so far, in alphabetical order.
+    pub fn commitments(&self) -> Vec<Prop> {
+        let mut commitments: Vec<Prop> = self.com_props().into_iter().collect();
+        commitments.sort_by_key(|prop| prop.to_string());
+        commitments
+    }
+
+    /// Returns the individual a positive, unconstrained commitment gives a
+    /// one-place predicate, e.g. paris for "dest_city" once the user has
+    /// said where they go. Of several such commitments the first in
+    /// alphabetical order is taken.
+    /// # Arguments
+    /// * `pred` - The predicate (e.g., "dest_city").
+    pub fn resolved_value(&self, pred: &str) -> Option<Ind> {
+        self.commitments()
+            .into_iter()
+            .find(|prop| prop.yes && prop.constraint.is_none() && prop.pred() == pred)
+            .and_then(|prop| prop.ind)
+    }
+
+    /// Returns the questions under discussion not yet resolved, topmost first.
+    pub fn open_questions(&self) -> Vec<Question> {
+        self.is
+            .qud
+            .iter_rev()
+            .filter_map(|que| Question::new(que).ok())
+            .filter(|que| !self.resolved(que))
+            .collect()
+    }
+
+    /// Returns the moves made in the session so far, in order, tagged with
+    /// their speaker, turn, time and confidence.
+    pub fn history(&self) -> &[TaggedMove] {
+        &self.history
+    }
+
+    /// Returns the record of the turns compacted out of the history under the
+    /// summarizing retention policy.
+    pub fn compacted_history(&self) -> &HistorySummary {
+        &self.compacted
+    }
+
+    /// Returns the utterances of the session so far with their turn. User
+    /// input is recorded after the input filters.
+    pub fn transcript(&self) -> &[(usize, Speaker, String)] {
+        &self.transcript
+    }
+
+    /// Returns the name of the grammar that interpreted each user utterance
+    /// of the transcript, by turn, e.g. to analyze which grammar of an
+    /// ensemble handles which input.
+    pub fn handled_by(&self) -> &[(usize, String)] {
+        &self.handled_by
+    }
+
+    /// Returns a sender through which the host application can interrupt the
+    /// dialogue from another thread, e.g. with a push notification.
+    pub fn event_sender(&self) -> mpsc::Sender<SystemEvent> {
+        self.event_sender.clone()
+    }
+
+    /// Queues a notification, told to the user once the system is not
+    /// collecting answers for a plan.
+    /// # Arguments
+    /// * `notification` - The notification to queue.
+    pub fn notify(&mut self, notification: Notification) {
+        self.event_sender.send(SystemEvent::Notify(notification)).ok();
+    }
+
+    /// Returns the notifications waiting to be told, in arrival order.
+    pub fn notifications(&self) -> &[Notification] {
+        &self.is.notifications
+    }
+
+    /// Pauses the dialogue at the next turn boundary. The dialogue state is
+    /// kept until resume is called.
+    pub fn pause(&mut self) {
+        self.paused = true;
+    }
+
+    /// Continues a paused dialogue where it stopped, returning the error that
+    /// ended it, if any.
+    pub fn resume(&mut self) -> Result<(), TurnError> {
+        self.paused = false;
+        self.trace("resume");
+        self.converse()
+    }
+
+    /// Returns whether the dialogue is paused.
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    /// Handles the events sent by the host application: facts are put on the
+    /// agenda as Inform moves and pause requests pause the dialogue.
+    fn handle_system_events(&mut self) {
+        while let Ok(event) = self.events.try_recv() {
+            match event {
+                SystemEvent::Inform(prop) => {
+                    self.trace(&format!("system_event: inform {}", prop));
+                    self.push_agenda(AgendaItem::Move(Move::Inform(Inform::new(prop))));
+                }
+                SystemEvent::Notify(notification) => {
+                    self.trace(&format!("system_event: notify {}", notification));
+                    self.is.notifications.push(notification);
+                }
+                SystemEvent::Pause => self.paused = true,
+                SystemEvent::SetField(name, value) => {
+                    self.trace(&format!("system_event: set_field {}={}", name, value));
+                    if let Err(e) = self.set_field(&name, &value) {
+                        self.trace(&format!("set_field failed: {}", e));
+                    }
+                }
+            }
+        }
+    }
+
+    /// Returns the inputs not understood so far, across sessions.
+    pub fn unmatched(&self) -> &[UnmatchedInput] {
+        &self.unmatched
+    }
+
+    /// Groups the inputs not understood so far by the known alias, individual
+    /// or predicate they come closest to, and suggests aliases for phrases
+    /// used to answer a question (e.g., "heading to paris" when asked for the
+    /// destination suggests "heading to" for dest_city).
+    pub fn unmatched_report(&self) -> UnmatchedReport {
+        let mut clusters: Vec<UnmatchedCluster> = Vec::new();
+        let mut suggested_aliases: Vec<(String, String)> = Vec::new();
+        for unmatched in &self.unmatched {
+            let pattern = closest_pattern(&self.domain, &unmatched.input);
+            match clusters.iter_mut().find(|cluster| cluster.pattern == pattern) {
+                Some(cluster) => cluster.inputs.push(unmatched.input.clone()),
+                None => clusters.push(UnmatchedCluster { pattern, inputs: vec![unmatched.input.clone()] }),
+            }
+            if let Some(alias) = suggest_alias(&self.domain, unmatched)
+                && !suggested_aliases.contains(&alias)
+            {
+                suggested_aliases.push(alias);
+            }
+        }
+        clusters.sort_by_key(|cluster| std::cmp::Reverse(cluster.inputs.len()));
+        UnmatchedReport { clusters, suggested_aliases }
+    }
+
+    /// Tracks which plan constructs, rules and grammar forms the dialogues
+    /// exercise, across sessions, for coverage_report.
+    pub fn with_coverage(mut self) -> Self {
+        self.coverage = Some(HashMap::new());
+        self
+    }
+
+    /// Returns the plan constructs, rules and grammar forms of the controller
+    /// with the times the dialogues run since with_coverage exercised them,
+    /// or None if coverage is not tracked.
+    pub fn coverage_report(&self) -> Option<CoverageReport> {
+        let hits = self.coverage.as_ref()?;
+        let mut items = Vec::new();
+        let mut triggers: Vec<&String> = self.domain.plans.keys().collect();
+        triggers.sort();
+        for trigger in triggers {
+            let mut constructs = Vec::new();
+            plan_positions(&self.domain.plans[trigger], &self.domain.subplans, &[], &mut constructs);
+            items.extend(constructs.into_iter().map(|(position, construct)| {
+                (CoverageKind::PLAN_ITEM, format!("{} {}: {}", trigger, position.join("/"), construct))
+            }));
+        }
+        items.extend(self.domain.rules.iter().map(|rule| (CoverageKind::RULE, rule.to_string())));
+        items.extend(self.grammar.forms().into_iter().map(|form| (CoverageKind::FORM, form)));
+        let items = items
+            .into_iter()
+            .map(|key| {
+                let hits = hits.get(&key).copied().unwrap_or_default();
+                CoverageItem { kind: key.0, name: key.1, hits }
+            })
+            .collect();
+        Some(CoverageReport { items })
+    }
+
+    /// Returns the name under which coverage counts the topmost plan
+    /// construct, after the issue and the position of the construct in its
+    /// plan. Once the remaining constructs no longer follow the plan (e.g.,
+    /// after a question was deferred), the position is that of the first
+    /// construct of the plan equal to it.
+    /// # Arguments
+    /// * `item` - The topmost plan construct.
+    fn plan_item_name(&self, item: &PlanItem) -> Option<String> {
+        let issue = self.is.issue.get()?;
+        let plan: Vec<String> = self.domain.get_plan(&Question::new(issue).ok()?)?.iter_rev().cloned().collect();
+        let remaining: Vec<String> = self.is.plan.iter_rev().cloned().collect();
+        let position = plan_position(&plan, &remaining, &self.domain.subplans).or_else(|| {
+            let mut constructs = Vec::new();
+            plan_positions(&plan, &self.domain.subplans, &[], &mut constructs);
+            let construct = item.to_string();
+            constructs.into_iter().find(|(_, c)| *c == construct).map(|(position, _)| position)
+        })?;
+        Some(format!("{} {}: {}", issue, position.join("/"), item))
+    }
+
+    /// Counts an item as exercised, if coverage is tracked.
+    /// # Arguments
+    /// * `kind` - The kind of the item.
+    /// * `name` - The item.
+    fn record_coverage(&mut self, kind: CoverageKind, name: String) {
+        if let Some(coverage) = &mut self.coverage {
+            *coverage.entry((kind, name)).or_default() += 1;
+        }
+    }
+
+    /// Returns the limit that ended the last session, if any.
+    pub fn limit_exceeded(&self) -> Option<&LimitExceeded> {
+        self.limit_exceeded.as_ref()
+    }
+
+    /// Records per-turn metrics (interpretation latency, NLU failures, rules
+    /// fired and database latency) with OpenTelemetry instruments.
+    /// # Arguments
+    /// * `meter` - The meter creating the instruments.
+    #[cfg(feature = "metrics")]
+    pub fn with_metrics(mut self, meter: &opentelemetry::metrics::Meter) -> Self {
+        self.metrics = Some(DialogueMetrics::new(meter));
+        self
+    }
+
+    /// Presents each utterance sentence by sentence through the output
+    /// handler as the moves are generated, so that long responses can be
+    /// rendered before the whole turn is generated.
+    pub fn with_streaming_output(mut self) -> Self {
+        self.streaming = true;
+        self
+    }
+
+    /// Adds a filter applied to each user input before it is interpreted.
+    /// Filters run in the order they were added.
+    /// # Arguments
+    /// * `filter` - The filter (e.g., a PiiRedactor).
+    pub fn with_input_filter(mut self, filter: impl TextFilter + 'static) -> Self {
+        self.input_filters.push(Box::new(filter));
+        self
+    }
+
+    /// Replaces the grammar for generation and interpretation, e.g. with a
+    /// GrammarEnsemble.
+    /// # Arguments
+    /// * `grammar` - The grammar.
+    pub fn with_grammar(mut self, grammar: impl Grammar + 'static) -> Self {
+        self.grammar = Box::new(grammar);
+        self
+    }
+
+    /// Sets the locale formatting numbers, amounts and dates in the outputs of
+    /// the grammar (e.g., "232" as "€232" or "232 kr").
+    /// # Arguments
+    /// * `locale` - The locale.
+    pub fn with_locale(mut self, locale: Locale) -> Self {
+        self.locale = Some(locale);
+        self
+    }
+
+    /// Sets the kernel running the modules of the dialogue system, e.g. the
+    /// standard kernel with a module replaced or a different control algorithm.
+    /// # Arguments
+    /// * `kernel` - The kernel.
+    pub fn with_kernel(mut self, kernel: Kernel) -> Self {
+        self.kernel = kernel;
+        self
+    }
+
+    /// Sets the tokenizer splitting each user input, after the input filters,
+    /// into the tokens the grammar interprets.
+    /// # Arguments
+    /// * `tokenizer` - The tokenizer (e.g., a PunctuationTokenizer).
+    pub fn with_tokenizer(mut self, tokenizer: impl Tokenizer + 'static) -> Self {
+        self.tokenizer = Box::new(tokenizer);
+        self
+    }
+
+    /// Adds a filter applied to each system utterance before it is presented.
+    /// Filters run in the order they were added.
+    /// # Arguments
+    /// * `filter` - The filter.
+    pub fn with_output_filter(mut self, filter: impl TextFilter + 'static) -> Self {
+        self.output_filters.push(Box::new(filter));
+        self
+    }
+
+    /// Makes the system remind a silent user, asking its question again, and
+    /// close the session after too many reminders. Silence is detected by
+    /// input handlers able to time out (e.g., a ChannelInputHandler).
+    /// # Arguments
+    /// * `timeout` - The time the user may stay silent.
+    /// * `max_reminders` - The number of reminders before closing the session.
+    pub fn with_silence_timeout(mut self, timeout: Duration, max_reminders: usize) -> Self {
+        self.silence_timeout = Some(timeout);
+        self.max_reminders = max_reminders;
+        self
+    }
+
+    /// Makes the system acknowledge some of the answers it integrates
+    /// ("Okay."), before its next move. Which answers are acknowledged is
+    /// drawn from the random numbers of the session.
+    /// # Arguments
+    /// * `frequency` - The share of answers acknowledged, from 0 (never, the default) to 1 (always).
+    pub fn with_acknowledgements(mut self, frequency: f64) -> Self {
+        self.acknowledgements = frequency.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Sets when the system confirms the answers it integrates by repeating
+    /// them ("Okay, paris."), before its next move.
+    /// # Arguments
+    /// * `confirmation` - When answers are confirmed.
+    /// * `threshold` - The confidence below which answers are confirmed under LOW_CONFIDENCE, from 0 to 1.
+    pub fn with_confirmation(mut self, confirmation: Confirmation, threshold: f64) -> Self {
+        self.confirmation = confirmation;
+        self.confirmation_threshold = threshold.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Sets what the dialogue manager prints: nothing, the information state
+    /// at each turn, or the state and the traces of the update rules (the default).
+    /// # Arguments
+    /// * `verbosity` - The verbosity.
+    pub fn with_verbosity(mut self, verbosity: Verbosity) -> Self {
+        self.verbosity = verbosity;
+        self
+    }
+
+    /// Applies a configuration, e.g. loaded from a file and the environment
+    /// with IsuConfig::load. Settings the configuration leaves unset keep
+    /// their current value.
+    /// # Arguments
+    /// * `config` - The configuration.
+    pub fn with_config(mut self, config: &IsuConfig) -> Self {
+        if let Some(verbosity) = &config.verbosity {
+            self.verbosity = verbosity.clone();
+        }
+        if let Some(confirmation) = &config.confirmation {
+            self.confirmation = confirmation.clone();
+        }
+        if let Some(threshold) = config.confirmation_threshold {
+            self.confirmation_threshold = threshold.clamp(0.0, 1.0);
+        }
+        if let Some(locale) = &config.locale {
+            self.locale = Some(locale.clone());
+        }
+        if let Some(seed) = config.seed {
+            self.rng = Rng::new(seed);
+        }
+        if let Some(limits) = &config.limits {
+            self.limits = limits.clone();
+        }
+        if let Some(timeout) = config.silence_timeout {
+            self.silence_timeout = Some(timeout);
+        }
+        if let Some(max_reminders) = config.max_reminders {
+            self.max_reminders = max_reminders;
+        }
+        if let Some(max_reasks) = config.max_reasks {
+            self.max_reasks = Some(max_reasks);
+        }
+        if let Some(max_misunderstandings) = config.max_misunderstandings {
+            self.max_misunderstandings = Some(max_misunderstandings);
+        }
+        if let Some(frequency) = config.acknowledgements {
+            self.acknowledgements = frequency.clamp(0.0, 1.0);
+        }
+        if let Some(streaming) = config.streaming {
+            self.streaming = streaming;
+        }
+        self
+    }
+
+    /// Sets the knowledge base consulted for static facts, e.g. by the
+    /// conditions of If constructs not settled by the commitments.
+    /// # Arguments
+    /// * `knowledge` - The knowledge base.
+    pub fn with_knowledge_base(mut self, knowledge: impl KnowledgeBase + 'static) -> Self {
+        self.knowledge = Box::new(knowledge);
+        self
+    }
+
+    /// Sets the store in which the values of functional predicates are saved
+    /// per user at the end of each dialogue, to be offered as defaults later.
+    /// # Arguments
+    /// * `profiles` - The profile store.
+    pub fn with_profile_store(mut self, profiles: impl ProfileStore + 'static) -> Self {
+        self.profiles = Some(Box::new(profiles));
+        self
+    }
+
+    /// Identifies the user of the next sessions, e.g. after a multi-user
+    /// server authenticated them. The identity keys the preferences in the
+    /// profile store and the dialogue results, and holds as "user(<id>)" for
+    /// the inference rules.
+    /// # Arguments
+    /// * `user` - The user id.
+    pub fn set_user(&mut self, user: &str) -> Result<(), String> {
+        self.mivs.user.set(user.to_string())
+    }
+
+    /// Forgets the identity of the user, e.g. after logging out.
+    pub fn clear_user(&mut self) {
+        self.mivs.user.clear();
+    }
+
+    /// Takes part in an experiment with the variant a session is assigned to:
+    /// the rules of the variant are added to the domain of the controller and
+    /// its forms to the session forms. The variant is recorded with the
+    /// results, transcripts and metrics of the dialogues. Returns its name.
+    /// # Arguments
+    /// * `experiment` - The experiment.
+    /// * `session` - The id of the session (e.g., the id of a chat or user).
+    pub fn join_experiment(&mut self, experiment: &Experiment, session: &str) -> Result<String, String> {
+        let variant = experiment.assign(session).ok_or(format!("Experiment {} has no variants", experiment.name))?;
+        self.apply_variant(experiment, variant)?;
+        Ok(variant.name.clone())
+    }
+
+    /// Adds the rules and forms of a variant of an experiment.
+    /// # Arguments
+    /// * `experiment` - The experiment.
+    /// * `variant` - The variant.
+    fn apply_variant(&mut self, experiment: &Experiment, variant: &Variant) -> Result<(), String> {
+        if self.variants.iter().any(|(name, _)| *name == experiment.name) {
+            return Err(format!("Already in experiment {}", experiment.name));
+        }
+        for rule in &variant.rules {
+            self.domain_mut().add_rule(rule.clone())?;
+        }
+        for (move_str, output) in &variant.forms {
+            self.add_session_form(move_str, output)?;
+        }
+        self.variants.push((experiment.name.clone(), variant.name.clone()));
+        self.trace(&format!("variant: {}={}", experiment.name, variant.name));
+        Ok(())
+    }
+
+    /// Returns the variants of the experiments the controller takes part in, by experiment.
+    pub fn variants(&self) -> &[(String, String)] {
+        &self.variants
+    }
+
+    /// Returns the identity of the user, if the host application set one.
+    pub fn user(&self) -> Option<&str> {
+        self.mivs.user.get().map(String::as_str)
+    }
+
+    /// Returns the participant holding the turn, i.e. expected to speak
+    /// next, or None once the dialogue has ended.
+    pub fn turn_holder(&self) -> Option<&Speaker> {
+        self.mivs.turn_holder.get()
+    }
+
+    /// Returns true if the user holds the turn, e.g. to enable the input box
+    /// or open the microphone only while the system waits for the user.
+    pub fn is_user_turn(&self) -> bool {
+        self.turn_holder() == Some(&Speaker::USR)
+    }
+
+    /// Adds an enumerated field to the information state (e.g., "mood" with
+    /// the values "calm" and "angry"). The field starts each session with its
+    /// first value, is printed with the state, and holds as "<name>(<value>)"
+    /// for the inference rules.
+    /// # Arguments
+    /// * `name` - The name of the field, usable as a predicate.
+    /// * `values` - The allowed values, the initial one first.
+    pub fn add_field(&mut self, name: &str, values: &[&str]) -> Result<(), String> {
+        if self.is.fields.contains_key(name) {
+            return Err(format!("Field {} already exists", name));
+        }
+        let Some(first) = values.first() else {
+            return Err(format!("Field {} needs at least one value", name));
+        };
+        for value in values {
+            Prop::new(&format!("{}({})", name, value))?;
+        }
+        let allowed: HashSet<String> = values.iter().map(|v| v.to_string()).collect();
+        let mut value = Value::new_allowed(allowed);
+        value.set(first.to_string())?;
+        let values = values.iter().map(|v| v.to_string()).collect();
+        self.is.fields.insert(name.to_string(), StateField { values, value });
+        Ok(())
+    }
+
+    /// Sets the value of an application-defined field.
+    /// # Arguments
+    /// * `name` - The name of the field.
+    /// * `value` - One of its allowed values.
+    pub fn set_field(&mut self, name: &str, value: &str) -> Result<(), String> {
+        let field = self.is.fields.get_mut(name).ok_or(format!("Unknown field: {}", name))?;
+        field.value.set(value.to_string())
+    }
+
+    /// Returns the value of an application-defined field, if it exists.
+    /// # Arguments
+    /// * `name` - The name of the field.
+    pub fn field(&self, name: &str) -> Option<&str> {
+        self.is.fields.get(name)?.value.get().map(String::as_str)
+    }
+
+    /// Exports the application-defined fields as a JSON object from name to value.
+    pub fn fields_json(&self) -> serde_json::Value {
+        self.is
+            .fields
+            .iter()
+            .map(|(name, field)| (name.clone(), field.value.get().cloned().into()))
+            .collect::<serde_json::Map<String, serde_json::Value>>()
+            .into()
+    }
+
+    /// Dumps the whole dialogue state as a single JSON document, e.g. for a
+    /// debug endpoint, an inspector or a bug report. It has the fields
+    /// "format" (STATE_FORMAT_VERSION), "turn", "turns" (user inputs so far),
+    /// "domain" (its "name", "version" and content "hash"), "mivs", "is" and
+    /// "transcript", where user utterances name the "grammar" that interpreted
+    /// them.
+    /// Stacks are listed top first and sets sorted. The dump can be restored.
+    pub fn dump(&self) -> serde_json::Value {
+        fn sorted(set: &TSet<String>) -> Vec<&String> {
+            let mut items: Vec<&String> = set.iter().collect();
+            items.sort();
+            items
+        }
+        let mivs = &self.mivs;
+        let transcript: Vec<serde_json::Value> = self
+            .transcript
+            .iter()
+            .map(|(turn, speaker, utterance)| {
+                let mut entry = serde_json::json!({ "turn": turn, "speaker": speaker, "utterance": utterance });
+                if let Some((_, grammar)) = self.handled_by.iter().find(|(handled, _)| handled == turn) {
+                    entry["grammar"] = grammar.as_str().into();
+                }
+                entry
+            })
+            .collect();
+        serde_json::json!({
+            "format": STATE_FORMAT_VERSION,
+            "turn": mivs.turn.get(),
+            "turns": self.turns,
+            "domain": {
+                "name": self.domain.name(),
+                "version": self.domain.version(),
+                "hash": self.domain.content_hash(),
+            },
+            "mivs": {
+                "input": mivs.input.get(),
+                "latest_speaker": mivs.latest_speaker.get(),
+                "latest_moves": mivs.latest_move_strings(),
+                "next_moves": mivs.next_moves.iter_rev().collect::<Vec<_>>(),
+                "output": mivs.output.get(),
+                "program_state": mivs.program_state.get(),
+                "turn_holder": mivs.turn_holder.get(),
+                "user": mivs.user.get(),
+            },
+            "is": {
+                "agenda": self.is.agenda.iter_rev().collect::<Vec<_>>(),
+                "plan": self.is.plan.iter_rev().collect::<Vec<_>>(),
+                "bel": sorted(&self.is.bel),
+                "com": sorted(&self.is.com),
+                "qud": self.is.qud.iter_rev().collect::<Vec<_>>(),
+                "issue": self.is.issue.get(),
+                "notifications": self.is.notifications.iter().map(|n| n.to_string()).collect::<Vec<_>>(),
+                "fields": self.fields_json(),
+            },
+            "transcript": transcript,
+        })
+    }
+
+    /// Restores a dialogue state dumped by this or an older version of the
+    /// crate, migrating it to the current format first. The history of moves
+    /// is not part of a dump and starts empty; notifications no longer expire.
+    /// Application-defined fields must have been added before restoring.
+    /// A dump saved with a different domain is refused or migrated according
+    /// to the domain mismatch policy; migrating drops the commitments the
+    /// current domain does not admit. Dumps without a domain hash are migrated.
+    /// Returns the commitments dropped.
+    /// # Arguments
+    /// * `state` - The dump.
+    pub fn restore(&mut self, state: &serde_json::Value) -> Result<Vec<String>, String> {
+        fn field<T: serde::de::DeserializeOwned + Default>(json: &serde_json::Value, name: &str) -> Result<T, String> {
+            match json.get(name) {
+                None | Some(serde_json::Value::Null) => Ok(T::default()),
+                Some(value) => serde_json::from_value(value.clone()).map_err(|e| format!("Invalid {}: {}", name, e)),
+            }
+        }
+        let state = migrate_state(state.clone())?;
+        let (is_json, mivs_json) = (&state["is"], &state["mivs"]);
+        let saved = &state["domain"];
+        let hash = self.domain.content_hash();
+        let mismatch = saved["hash"].as_str() != Some(hash.as_str());
+        if mismatch && self.domain_mismatch == DomainMismatch::Refuse {
+            let describe = |name: Option<&str>, version: Option<&str>, hash: Option<&str>| {
+                let (name, version) = (name.unwrap_or("unnamed"), version.unwrap_or("unversioned"));
+                format!("{} {} (hash {})", name, version, hash.unwrap_or("unknown"))
+            };
+            return Err(format!(
+                "The state was saved with domain {}, which differs from the current domain {}",
+                describe(saved["name"].as_str(), saved["version"].as_str(), saved["hash"].as_str()),
+                describe(self.domain.name(), self.domain.version(), Some(&hash)),
+            ));
+        }
+
+        let mut is = self.is.clone();
+        is.init_is();
+        for item in field::<Vec<String>>(is_json, "agenda")?.into_iter().rev() {
+            is.agenda.push(item)?;
+        }
+        let plan: Vec<String> = field(is_json, "plan")?;
+        for construct in &plan {
+            PlanItem::new(construct)?;
+        }
+        is.plan = Plan::new(plan.into());
+        for prop in field::<Vec<String>>(is_json, "bel")? {
+            is.bel.add(prop)?;
+        }
+        let mut dropped = Vec::new();
+        for prop in field::<Vec<String>>(is_json, "com")? {
+            if mismatch && !self.domain.admits(&Prop::new(&prop)?) {
+                dropped.push(prop);
+            } else {
+                is.com.add(prop)?;
+            }
+        }
+        for que in field::<Vec<String>>(is_json, "qud")?.into_iter().rev() {
+            is.qud.push(que)?;
+        }
+        if let Some(issue) = field::<Option<String>>(is_json, "issue")? {
+            is.issue.set(issue)?;
+        }
+        for notification in field::<Vec<String>>(is_json, "notifications")? {
+            let (prop, priority) = notification
+                .rsplit_once('!')
+                .ok_or(format!("Could not parse notification: {}", notification))?;
+            let priority = priority.parse().map_err(|_| format!("Could not parse notification: {}", notification))?;
+            is.notifications.push(Notification::new(Prop::new(prop)?).with_priority(priority));
+        }
+        for (name, value) in field::<BTreeMap<String, String>>(is_json, "fields")? {
+            is.fields.get_mut(&name).ok_or(format!("Unknown field: {}", name))?.value.set(value)?;
+        }
+        let latest_moves = field::<Vec<String>>(mivs_json, "latest_moves")?
+            .iter()
+            .map(|mv| Move::new(mv))
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut next_moves = Stack::new();
+        for mv in field::<Vec<String>>(mivs_json, "next_moves")?.into_iter().rev() {
+            next_moves.push(mv)?;
+        }
+        let transcript = field::<Vec<serde_json::Value>>(&state, "transcript")?
+            .iter()
+            .map(|entry| {
+                let speaker = field::<Option<Speaker>>(entry, "speaker")?.ok_or("Missing speaker in transcript")?;
+                Ok((field(entry, "turn")?, speaker, field(entry, "utterance")?))
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+        let mut handled_by = Vec::new();
+        for entry in field::<Vec<serde_json::Value>>(&state, "transcript")? {
+            if let Some(grammar) = field::<Option<String>>(&entry, "grammar")? {
+                handled_by.push((field(&entry, "turn")?, grammar));
+            }
+        }
+
+        self.reset();
+        self.is = is;
+        let turn = field::<Option<usize>>(&state, "turn")?;
+        if let Some(turn) = turn {
+            self.mivs.turn.set(turn)?;
+        }
+        if let Some(input) = field::<Option<String>>(mivs_json, "input")? {
+            self.mivs.input.set(input)?;
+        }
+        if let Some(speaker) = field::<Option<Speaker>>(mivs_json, "latest_speaker")? {
+            self.mivs.latest_speaker.set(speaker.clone())?;
+            let turn = turn.unwrap_or_default();
+            self.mivs.latest_moves =
+                latest_moves.into_iter().map(|mv| TaggedMove::new(mv, speaker.clone(), turn)).collect();
+        }
+        self.mivs.next_moves = next_moves;
+        if let Some(output) = field::<Option<String>>(mivs_json, "output")? {
+            self.mivs.output.set(output)?;
+        }
+        if let Some(program_state) = field::<Option<ProgramState>>(mivs_json, "program_state")? {
+            self.mivs.program_state.set(program_state)?;
+        }
+        match field::<Option<Speaker>>(mivs_json, "turn_holder")? {
+            Some(holder) => self.mivs.turn_holder.set(holder)?,
+            None => self.mivs.turn_holder.clear(),
+        }
+        if let Some(user) = field::<Option<String>>(mivs_json, "user")? {
+            self.mivs.user.set(user)?;
+        }
+        self.turns = field(&state, "turns")?;
+        self.transcript = transcript;
+        self.handled_by = handled_by;
+        self.trace("restore");
+        for prop in &dropped {
+            self.trace(&format!("restore: dropped commitment {} not admitted by the domain", prop));
+        }
+        Ok(dropped)
+    }
+
+    /// Registers a handler notified when the program state, the latest
+    /// speaker, the turn holder or the output changes. It replaces any previous handler.
+    /// # Arguments
+    /// * `handler` - The function to call with each change.
+    pub fn on_mivs_event<F>(&mut self, handler: F)
+    where
+        F: Fn(&MivsEvent) + 'static,
+    {
+        let handler = Rc::new(handler);
+        let h = handler.clone();
+        self.mivs
+            .program_state
+            .on_change(move |_, new| h(&MivsEvent::ProgramState(new.cloned())));
+        let h = handler.clone();
+        self.mivs
+            .latest_speaker
+            .on_change(move |_, new| h(&MivsEvent::LatestSpeaker(new.cloned())));
+        let h = handler.clone();
+        self.mivs
+            .output
+            .on_change(move |_, new| h(&MivsEvent::Output(new.cloned())));
+        let h = handler.clone();
+        self.mivs
+            .turn
+            .on_change(move |_, new| h(&MivsEvent::Turn(new.cloned())));
+        let h = handler.clone();
+        self.mivs
+            .turn_holder
+            .on_change(move |_, new| h(&MivsEvent::TurnHolder(new.cloned())));
+        self.mivs
+            .user
+            .on_change(move |_, new| handler(&MivsEvent::User(new.cloned())));
+    }
+
+    /// Drops expired notifications and, unless a plan is still collecting
+    /// answers, puts the others on the agenda as Inform moves, highest
+    /// priority first.
+    fn select_notifications(&mut self) {
+        let now = SystemTime::now();
+        let (expired, mut pending): (Vec<Notification>, Vec<Notification>) =
+            self.is.notifications.drain(..).partition(|n| n.is_expired(now));
+        for notification in expired {
+            self.trace(&format!("notification_expired: {}", notification));
+        }
+        if !self.is.plan.is_empty() {
+            self.is.notifications = pending;
+            return;
+        }
+        pending.sort_by_key(|n| std::cmp::Reverse(n.priority));
+        for notification in pending {
+            self.push_agenda(AgendaItem::Move(Move::Inform(Inform::new(notification.prop))));
+            self.trace("select_notification");
+        }
+    }
+
+    /// Puts the topmost plan construct on the agenda.
+    fn select_from_plan(&mut self) -> bool {
+        let Ok(top) = self.is.plan.top() else {
+            return false;
+        };
+        self.is.agenda.push(top.clone()).ok();
+        self.trace("select_from_plan");
+        true
+    }
+
+    /// Decides to respond to the topmost question under discussion once
+    /// a belief resolving it has been found.
+    fn select_respond(&mut self) -> bool {
+        if !self.is.plan.is_empty() {
+            return false;
+        }
+        let Some(que) = self.qud_top() else {
+            return false;
+        };
+        if self.resolving_belief(&que).is_none() {
+            return false;
+        }
+        self.is.agenda.push(Respond::new(que).to_string()).ok();
+        self.trace("select_respond");
+        true
+    }
+
+    /// Asks the question of the Findout or Raise topmost on the agenda.
+    fn select_ask(&mut self) -> Result<bool, String> {
+        let item = self.agenda_construct();
+        let que = match item {
+            Some(PlanItem::Findout(findout)) => match self.saved_answer(&findout.content) {
+                Some(prop) => {
+                    // Saved values are only used once the user confirms them
+                    self.offered.insert(findout.content.to_string());
+                    self.push_agenda(AgendaItem::Move(Move::Ask(Ask::new(Question::YNQ(YNQ { prop })))));
+                    self.trace("offer_preference");
+                    return Ok(true);
+                }
+                None => findout.content,
+            },
+            Some(PlanItem::Raise(raise)) => {
+                if self.is.plan.top().ok() == Some(&raise.to_string()) {
+                    self.is.plan.pop().ok();
+                }
+                raise.content
+            }
+            _ => return Ok(false),
+        };
+        let asked = self.asks.entry(que.to_string()).or_default();
+        *asked += 1;
+        if self.max_reasks.is_some_and(|max| *asked > max + 1) {
+            self.escalate(&format!("repeated_ask {}", que))?;
+            return Ok(true);
+        }
+        self.push_agenda(AgendaItem::Move(Move::Ask(Ask::new(que))));
+        self.trace("select_ask");
+        Ok(true)
+    }
+
+    /// Returns the saved preference answering a wh-question, unless it has
+    /// already been offered in this session.
+    /// # Arguments
+    /// * `que` - The question about to be asked.
+    fn saved_answer(&self, que: &Question) -> Option<Prop> {
+        if self.offered.contains(&que.to_string()) {
+            return None;
+        }
+        self.preferences
+            .iter()
+            .find(|prop| self.domain.resolves(&Ans::Prop((*prop).clone()), que))
+            .cloned()
+    }
+
+    /// Saves the values of functional predicates committed to in this session
+    /// as the preferences of the user, keeping earlier ones for other predicates.
+    fn save_preferences(&mut self) {
+        let (Some(profiles), Some(user)) = (&mut self.profiles, self.mivs.user.get()) else {
+            return;
+        };
+        let committed: Vec<Prop> = self
+            .is
+            .com
+            .iter()
+            .filter_map(|p| Prop::new(p).ok())
+            .filter(|p| {
+                p.yes && p.constraint.is_none() && self.domain.is_functional(p.pred())
+                    && p.ind.as_ref().is_some_and(|ind| ind.0.content != ANY)
+            })
+            .collect();
+        let mut preferences: Vec<Prop> =
+            self.preferences.iter().filter(|p| !committed.iter().any(|c| c.pred == p.pred)).cloned().collect();
+        preferences.extend(committed);
+        if let Err(e) = profiles.save(user, &preferences) {
+            self.trace(&format!("save_preferences failed: {}", e));
+            return;
+        }
+        self.preferences = preferences;
+        self.trace("save_preferences");
+    }
+
+    /// Answers the question of the Respond topmost on the agenda.
+    fn select_answer(&mut self) -> bool {
+        let item = self.agenda_construct();
+        let Some(PlanItem::Respond(respond)) = item else {
+            return false;
+        };
+        let Some(prop) = self.resolving_belief(&respond.content) else {
+            return false;
+        };
+        let support = self.supporting_props(&prop);
+        self.push_agenda(AgendaItem::Move(Move::Answer(Answer::with_support(Ans::Prop(prop), support))));
+        self.trace("select_answer");
+        true
+    }
+
+    /// Takes the moves off the agenda as the next moves, in the order they
+    /// were pushed, leaving the plan constructs.
+    fn select_moves(&mut self) -> bool {
+        let items: Vec<String> = self.is.agenda.iter().cloned().collect();
+        let (moves, constructs): (Vec<String>, Vec<String>) =
+            items.into_iter().partition(|item| Move::new(item).is_ok());
+        self.is.agenda.clear();
+        for construct in constructs {
+            self.is.agenda.push(construct).ok();
+        }
+        for mv in &moves {
+            self.mivs.next_moves.push(mv.clone()).ok();
+        }
+        !moves.is_empty()
+    }
+
+    /// Gives negative semantic feedback when the user input was not understood.
+    fn select_icm_sem_neg(&mut self) -> Result<bool, String> {
+        if self.mivs.latest_speaker.get() != Some(&Speaker::USR)
+            || self.mivs.program_state.get() == Some(&ProgramState::QUIT)
+        {
+            return Ok(false);
+        }
+        if !self.mivs.latest_moves.is_empty() {
+            self.misunderstandings = 0;
+            return Ok(false);
+        }
+        self.misunderstandings += 1;
+        if self.max_misunderstandings.is_some_and(|max| self.misunderstandings > max) {
+            self.escalate("repeated_sem_neg")?;
+            return Ok(true);
+        }
+        self.push_agenda(AgendaItem::Move(Move::ICM(ICM::new("sem", "neg", None))));
+        self.trace("select_icm_sem_neg");
+        Ok(true)
+    }
+
+    /// Returns the first limit the session exceeds, if any.
+    fn exceeded_limit(&self) -> Option<LimitExceeded> {
+        let over = |max: Option<usize>, size: usize| max.filter(|max| size > *max);
+        let limits = &self.limits;
+        over(limits.max_turns, self.turns)
+            .map(LimitExceeded::Turns)
+            .or_else(|| over(limits.max_qud_depth, self.is.qud.len()).map(LimitExceeded::QudDepth))
+            .or_else(|| over(limits.max_plan_depth, self.is.plan.len()).map(LimitExceeded::PlanDepth))
+            .or_else(|| over(limits.max_agenda_size, self.is.agenda.len()).map(LimitExceeded::AgendaSize))
+    }
+
+    /// Ends the session with a goodbye when a limit is exceeded.
+    /// Returns true if the session was ended.
+    fn enforce_limits(&mut self) -> Result<bool, String> {
+        let Some(limit) = self.exceeded_limit() else {
+            return Ok(false);
+        };
+        self.trace(&format!("limit_exceeded: {}", limit));
+        self.limit_exceeded = Some(limit);
+        self.is.agenda.clear();
+        self.mivs.next_moves.clear();
+        self.push_agenda(AgendaItem::Move(Move::Quit(Quit)));
+        self.mivs.program_state.set(ProgramState::QUIT)?;
+        self.select_moves();
+        self.generate()?;
+        self.output()?;
+        Ok(true)
+    }
+
+    /// Hands the dialogue over to a human agent, ending it for the system.
+    /// # Arguments
+    /// * `reason` - Why the system gives up.
+    fn escalate(&mut self, reason: &str) -> Result<(), String> {
+        self.is.agenda.clear();
+        self.mivs.next_moves.clear();
+        self.push_agenda(AgendaItem::Move(Move::Escalate(Escalate)));
+        self.mivs.program_state.set(ProgramState::QUIT)?;
+        if let Some(handler) = &self.escalation_handler {
+            handler(&Escalation {
+                reason: reason.to_string(),
+                state: self.dump(),
+            });
+        }
+        self.trace(&format!("escalate: {}", reason));
+        Ok(())
+    }
+
+    /// Returns the topmost plan construct on the agenda, skipping the moves
+    /// waiting to be selected.
+    fn agenda_construct(&self) -> Option<PlanItem> {
+        self.is.agenda.iter_rev().find_map(|item| PlanItem::new(item).ok())
+    }
+
+    /// Returns the topmost question under discussion, if any.
+    fn qud_top(&self) -> Option<Question> {
+        self.is.qud.top().ok().and_then(|q| Question::new(q).ok())
+    }
+
+    /// Returns the questions of the Findout constructs in the plan, next first.
+    fn plan_findouts(&self) -> Vec<Question> {
+        self.is
+            .plan
+            .iter_rev()
+            .filter_map(|item| match PlanItem::new(item) {
+                Ok(PlanItem::Findout(findout)) => Some(findout.content),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Returns the progress of the plan for the issue being dealt with, if any.
+    /// Questions in branches of conditionals not yet settled count as neither
+    /// resolved nor remaining.
+    pub fn task_status(&self) -> Option<TaskStatus> {
+        let issue = Question::new(self.is.issue.get()?).ok()?;
+        let mut questions = Vec::new();
+        let mut consults = false;
+        let plan = self.domain.get_plan(&issue)?;
+        for item in flatten_plan(plan.iter_rev().cloned(), &self.domain.subplans) {
+            match item {
+                PlanItem::Findout(Findout { content: que }) | PlanItem::Raise(Raise { content: que }) => {
+                    questions.push(que)
+                }
+                PlanItem::ConsultDB(_) => consults = true,
+                _ => {}
+            }
+        }
+        let mut seen = HashSet::new();
+        questions.retain(|que| seen.insert(que.to_string()));
+        // Questions still in the plan or raised and awaiting an answer
+        let pending: Vec<String> = self
+            .is
+            .plan
+            .iter()
+            .filter_map(|item| match PlanItem::new(item) {
+                Ok(PlanItem::Findout(Findout { content: que })) | Ok(PlanItem::Raise(Raise { content: que })) => {
+                    Some(que.to_string())
+                }
+                _ => None,
+            })
+            .chain(self.is.qud.iter().cloned())
+            .collect();
+        let (resolved, remaining): (Vec<Question>, Vec<Question>) = questions
+            .into_iter()
+            .filter(|que| self.resolved(que) || self.believed(que).is_some() || pending.contains(&que.to_string()))
+            .partition(|que| self.resolved(que) || self.believed(que).is_some());
+        let consult_pending = self.is.plan.iter().any(|item| matches!(PlanItem::new(item), Ok(PlanItem::ConsultDB(_))));
+        Some(TaskStatus {
+            issue: issue.to_string(),
+            resolved: resolved.iter().map(|que| que.to_string()).collect(),
+            remaining: remaining.iter().map(|que| que.to_string()).collect(),
+            consulted: (consults && !consult_pending).then(|| self.believed(&issue).is_some()),
+        })
+    }
+
+    /// Summarizes the conversation so far: the issues the user raised and
+    /// what resolved them, the questions still open and the commitments made.
+    pub fn summarize(&self) -> ConversationSummary {
+        let mut issues: Vec<Question> = self.compacted.issues.iter().filter_map(|que| Question::new(que).ok()).collect();
+        for tagged in &self.history {
+            if let (Speaker::USR, Move::Ask(ask)) = (&tagged.speaker, &tagged.mv)
+                && !issues.iter().any(|que| que.to_string() == ask.content.to_string())
+            {
+                issues.push(ask.content.clone());
+            }
+        }
+        let answer = |que: &Question| {
+            self.com_props()
+                .into_iter()
+                .find(|prop| self.domain.resolves(&Ans::Prop(prop.clone()), que))
+                .or_else(|| self.believed(que))
+        };
+        let mut summary = ConversationSummary::default();
+        for que in issues
+            .iter()
+            .cloned()
+            .chain(self.is.qud.iter_rev().filter_map(|que| Question::new(que).ok()))
+            .chain(self.plan_findouts())
+        {
+            let key = que.to_string();
+            if summary.resolved.iter().any(|(resolved, _)| *resolved == key) || summary.outstanding.contains(&key) {
+                continue;
+            }
+            match answer(&que) {
+                Some(prop) if issues.iter().any(|issue| issue.to_string() == key) => {
+                    summary.resolved.push((key, prop.to_string()))
+                }
+                Some(_) => {}
+                None => summary.outstanding.push(key),
+            }
+        }
+        summary.commitments = self.is.com.iter().cloned().collect();
+        summary.commitments.sort();
+        summary
+    }
+
+    /// Renders the summary of the conversation in natural language with the
+    /// grammar: the answer to each resolved issue followed by the questions
+    /// still open.
+    pub fn summarize_text(&self) -> String {
+        let summary = self.summarize();
+        let moves: Vec<String> = summary
+            .resolved
+            .iter()
+            .filter_map(|(_, prop)| Prop::new(prop).ok())
+            .map(|prop| Answer::new(Ans::Prop(prop)).to_string())
+            .chain(summary.outstanding.iter().map(|que| format!("Ask('{}')", que)))
+            .collect();
+        self.realize(&moves)
+    }
+
+    /// Estimates the bytes held by the information state and the history of
+    /// the session. Moves in the history are counted by the length of their
+    /// string form.
+    pub fn memory_stats(&self) -> MemoryStats {
+        let moves: usize = self.history.iter().map(|tagged| tagged.mv.to_string().len()).sum();
+        let utterances: usize = self.transcript.iter().map(|(_, _, utterance)| utterance.capacity()).sum();
+        MemoryStats {
+            history: self.history.len() * std::mem::size_of::<TaggedMove>() + moves,
+            transcript: self.transcript.len() * std::mem::size_of::<(usize, Speaker, String)>() + utterances,
+            ..self.is.memory_stats()
+        }
+    }
+
+    /// Builds the dialogue context passed to the grammar.
+    fn interpretation_context(&self) -> InterpretationContext<'_> {
+        InterpretationContext {
+            domain: &self.domain,
+            qud: self.is.qud.iter_rev().filter_map(|q| Question::new(q).ok()).collect(),
+            findouts: self.plan_findouts(),
+            locale: self.locale.as_ref(),
+        }
+    }
+
+    /// Returns the commitments as propositions.
+    fn com_props(&self) -> TSet<Prop> {
+        self.is.com.iter().filter_map(|p| Prop::new(p).ok()).collect()
+    }
+
+    /// Returns the commitments given to the database with a question: those
+    /// to the predicate asked about and to the predicates relevant to it, or
+    /// all of them if the domain does not tell which are relevant.
+    /// # Arguments
+    /// * `que` - The question consulted.
+    fn query_context(&self, que: &Question) -> TSet<Prop> {
+        let com = self.com_props();
+        let mut context = match self.domain.query_predicates(que) {
+            Some(mut relevant) => {
+                relevant.extend(que.pred().map(str::to_string));
+                com.into_iter().filter(|prop| relevant.contains(prop.pred())).collect()
+            }
+            None => com,
+        };
+        if let Question::WhQ(whq) = que {
+            for prop in whq.constraint_props().into_iter().filter(|prop| prop.pred.0 != whq.pred.0) {
+                context.add(prop).ok();
+            }
+        }
+        context
+    }
+
+    /// Checks whether a question is resolved by the commitments.
+    /// # Arguments
+    /// * `que` - The question to check.
+    fn resolved(&self, que: &Question) -> bool {
+        self.com_props()
+            .into_iter()
+            .any(|prop| self.domain.resolves(&Ans::Prop(prop), que))
+    }
+
+    /// Returns the commitments supporting an answer: the positive, plain
+    /// propositions about other predicates, in a stable order.
+    /// # Arguments
+    /// * `answer` - The proposition being answered.
+    fn supporting_props(&self, answer: &Prop) -> Vec<Prop> {
+        let mut support: Vec<Prop> = self
+            .com_props()
+            .into_iter()
+            .filter(|prop| {
+                prop.yes
+                    && prop.ind.is_some()
+                    && prop.constraint.is_none()
+                    && prop.aggregate.is_none()
+                    && prop.pred != answer.pred
+            })
+            .collect();
+        support.sort_by_key(|prop| prop.to_string());
+        support
+    }
+
+    /// Returns a belief resolving a question that is not yet committed to.
+    /// # Arguments
+    /// * `que` - The question to resolve.
+    fn resolving_belief(&self, que: &Question) -> Option<Prop> {
+        self.is
+            .bel
+            .iter()
+            .filter(|prop| !self.is.com.contains(prop))
+            .filter_map(|prop| Prop::new(prop).ok())
+            .find(|prop| self.domain.resolves(&Ans::Prop(prop.clone()), que))
+    }
+
+    /// Returns a private belief resolving a question (e.g., one derived by an
+    /// inference rule), if any.
+    /// # Arguments
+    /// * `que` - The question to resolve.
+    fn believed(&self, que: &Question) -> Option<Prop> {
+        self.is
+            .bel
+            .iter()
+            .filter_map(|prop| Prop::new(prop).ok())
+            .find(|prop| self.domain.resolves(&Ans::Prop(prop.clone()), que))
+    }
+
+    /// Generates the surface string of moves. Moves a generation hook
+    /// produces are output as the hook says, and the runs of moves between
+    /// them are generated by the grammar.
+    /// # Arguments
+    /// * `moves` - The moves, first to utter first.
+    fn realize(&self, moves: &[String]) -> String {
+        let mut sentences = Vec::new();
+        let mut run: Vec<String> = Vec::new();
+        for move_str in moves {
+            let hooked = self.session_forms.get(move_str).cloned().or_else(|| {
+                self.generation_hooks
+                    .iter()
+                    .filter(|(pattern, _)| glob_match(pattern, move_str))
+                    .find_map(|(_, hook)| hook(&Move::new(move_str).ok()?, self))
+            });
+            let Some(sentence) = hooked else {
+                run.push(move_str.clone());
+                continue;
+            };
+            if !run.is_empty() {
+                sentences.push(self.grammar.generate(&run, &self.interpretation_context()));
+                run.clear();
+            }
+            sentences.push(sentence);
+        }
+        if sentences.is_empty() || !run.is_empty() {
+            sentences.push(self.grammar.generate(&run, &self.interpretation_context()));
+        }
+        sentences.join(" ")
+    }
+
+    /// Applies the output filters to a text, in order.
+    /// # Arguments
+    /// * `text` - The text to filter.
+    fn filter_output(&self, text: &str) -> String {
+        self.output_filters
+            .iter()
+            .fold(text.to_string(), |text, filter| filter.filter(&text))
+    }
+
+    /// Gives the turn to the user, whose input is read next, or takes it
+    /// from both participants once the dialogue has ended.
+    fn yield_turn(&mut self) -> Result<(), String> {
+        if self.is_running() {
+            self.mivs.turn_holder.set(Speaker::USR)
+        } else {
+            self.mivs.turn_holder.clear();
+            Ok(())
+        }
+    }
+
+    /// Makes the system take the turn once the user has spoken or stayed
+    /// silent, or takes it from both participants once the dialogue has ended.
+    fn take_turn(&mut self) -> Result<(), String> {
+        if self.is_running() {
+            self.mivs.turn_holder.set(Speaker::SYS)
+        } else {
+            self.mivs.turn_holder.clear();
+            Ok(())
+        }
+    }
+
+    /// Reminds a silent user, or says goodbye and closes the session once the
+    /// reminders are used up. The question under discussion is asked again on
+    /// the next turn.
+    fn handle_silence(&mut self) -> Result<(), String> {
+        self.mivs.input.clear();
+        if self.reminders < self.max_reminders {
+            self.reminders += 1;
+            self.push_agenda(AgendaItem::Move(Move::ICM(ICM::new("con", "neg", None))));
+            self.trace("remind_silent_user");
+        } else {
+            self.push_agenda(AgendaItem::Move(Move::Quit(Quit)));
+            self.mivs.program_state.set(ProgramState::QUIT)?;
+            self.trace("close_silent_session");
+        }
+        self.select_moves();
+        self.generate()?;
+        self.output()
+    }
+
+    /// Makes moves the latest moves, tagging them with the speaker and the
+    /// current turn, and records them in the history. Moves that cannot be parsed are
+    /// dropped.
+    /// # Arguments
+    /// * `speaker` - Who made the moves.
+    /// * `moves` - The moves, in order.
+    /// * `confidence` - How sure the interpretation of the moves is.
+    fn set_latest_moves(&mut self, speaker: Speaker, moves: &[String], confidence: f64) {
+        let turn_id = self.mivs.turn.get().copied().unwrap_or_default();
+        self.mivs.latest_moves = moves
+            .iter()
+            .filter_map(|m| Move::new(m).ok())
+            .map(|mv| TaggedMove::new(mv, speaker.clone(), turn_id).with_confidence(confidence))
+            .collect();
+        self.history.extend(self.mivs.latest_moves.iter().cloned());
+        self.prune_history(turn_id);
+    }
+
+    /// Drops the turns the retention policy does not keep from the history
+    /// and transcript, compacting their moves first if the policy says so.
+    /// # Arguments
+    /// * `turn` - The current turn.
+    fn prune_history(&mut self, turn: usize) {
+        let (HistoryRetention::KeepLast(keep) | HistoryRetention::Summarize(keep)) = self.retention else {
+            return;
+        };
+        let oldest = (turn + 1).saturating_sub(keep.max(1));
+        let pruned = self.history.iter().take_while(|tagged| tagged.turn_id < oldest).count();
+        if pruned == 0 {
+            return;
+        }
+        if let HistoryRetention::Summarize(_) = self.retention {
+            for tagged in &self.history[..pruned] {
+                if let (Speaker::USR, Move::Ask(ask)) = (&tagged.speaker, &tagged.mv)
+                    && !self.compacted.issues.contains(&ask.content.to_string())
+                {
+                    self.compacted.issues.push(ask.content.to_string());
+                }
+            }
+            self.compacted.moves += pruned;
+            self.compacted.turns = oldest - 1;
+        }
+        self.history.drain(..pruned);
+        self.transcript.retain(|(turn, _, _)| *turn >= oldest);
+        self.handled_by.retain(|(turn, _)| *turn >= oldest);
+        self.trace(&format!("prune_history: {} moves before turn {}", pruned, oldest));
+    }
+
+    /// Saves the dialogue state an update may change.
+    fn snapshot(&self) -> UpdateSnapshot {
+        UpdateSnapshot {
+            is: self.is.clone(),
+            mivs: self.mivs.clone(),
+            domain: self.domain.clone(),
+            asks: self.asks.clone(),
+            misunderstandings: self.misunderstandings,
+            deferred: self.deferred.clone(),
+            offered: self.offered.clone(),
+            plan_failure: self.plan_failure.clone(),
+            limit_exceeded: self.limit_exceeded.clone(),
+            history: self.history.len(),
+        }
+    }
+
+    /// Restores the dialogue state saved before an update, dropping the
+    /// issues it completed.
+    /// # Arguments
+    /// * `snapshot` - The saved state.
+    fn roll_back(&mut self, snapshot: UpdateSnapshot) {
+        self.is = snapshot.is;
+        self.mivs = snapshot.mivs;
+        self.domain = snapshot.domain;
+        self.asks = snapshot.asks;
+        self.misunderstandings = snapshot.misunderstandings;
+        self.deferred = snapshot.deferred;
+        self.offered = snapshot.offered;
+        self.plan_failure = snapshot.plan_failure;
+        self.limit_exceeded = snapshot.limit_exceeded;
+        self.history.truncate(snapshot.history);
+        self.completed.clear();
+    }
+
+    /// Integrates the latest moves, applies the rules and executes the plan.
+    /// The rules of each phase apply before integration, after it and after
+    /// the plan has been executed.
+    fn try_update(&mut self) -> Result<(), String> {
+        self.is.agenda.clear();
+        self.apply_rules(RulePhase::GROUNDING)?;
+        self.integrate()?;
+        self.fixpoint("update", Self::update_round)?;
+        self.apply_rules(RulePhase::PLAN_MANAGEMENT)?;
+        Ok(())
+    }
+
+    /// Applies the update rules once: the integration rules, downdating the
+    /// QUD, loading a plan and executing it.
+    fn update_round(&mut self) -> Result<(), String> {
+        self.apply_rules(RulePhase::INTEGRATION)?;
+        while self.downdate_qud() {}
+        self.load_plan()?;
+        while self.exceeded_limit().is_none() && self.exec_plan()? {}
+        Ok(())
+    }
+
+    /// Applies rules repeatedly until the information state stops changing.
+    /// A state seen before means the rules oscillate, and the iterations are
+    /// bounded by the limits; either ends the iteration and is traced.
+    /// # Arguments
+    /// * `name` - The name of the rules, for the trace.
+    /// * `round` - Applies the rules once.
+    fn fixpoint(&mut self, name: &str, round: fn(&mut Self) -> Result<(), String>) -> Result<(), String> {
+        let max = self.limits.max_update_iterations.unwrap_or(MAX_UPDATE_ITERATIONS);
+        let mut seen = vec![self.is.format_is("")];
+        for _ in 0..max {
+            round(self)?;
+            let state = self.is.format_is("");
+            if seen.last() == Some(&state) {
+                return Ok(());
+            }
+            if seen.contains(&state) {
+                self.trace(&format!("fixpoint_oscillation: {} after {} rounds", name, seen.len()));
+                return Ok(());
+            }
+            seen.push(state);
+        }
+        self.trace(&format!("fixpoint_limit: {} after {} rounds", name, max));
+        Ok(())
+    }
+
+    /// Integrates the latest moves into the information state.
+    fn integrate(&mut self) -> Result<(), String> {
+        let moves: Vec<TaggedMove> = self.mivs.latest_moves.clone();
+        for TaggedMove { mv, speaker, confidence, .. } in moves {
+            match (mv, speaker) {
+                (Move::Ask(ask), Speaker::USR) => self.integrate_usr_ask(ask.content),
+                (Move::Ask(ask), Speaker::SYS) => self.integrate_sys_ask(ask.content),
+                (Move::Answer(answer), Speaker::USR) => {
+                    if let Some(known) = self.known_answer(&answer.content) {
+                        // Answers the user gave before are acknowledged, not integrated again
+                        self.push_agenda(AgendaItem::Move(Move::ICM(ICM::new("acc", "pos", Some(known)))));
+                        self.trace("acknowledge_known_answer");
+                        continue;
+                    }
+                    let before = self.is.com.clone();
+                    self.integrate_answer(answer.content);
+                    let committed: Vec<String> = self.is.com.iter().filter(|p| !before.contains(p)).cloned().collect();
+                    if !committed.is_empty() && self.acknowledgements > 0.0 {
+                        self.acknowledge();
+                    }
+                    self.confirm(&committed, confidence);
+                }
+                (Move::Answer(answer), _) => self.integrate_answer(answer.content),
+                (Move::Quit(_), _) => self.integrate_quit()?,
+                (Move::Inform(inform), _) => self.commit(&inform.content),
+                (Move::Custom(custom), speaker) => self.integrate_custom(custom, speaker)?,
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the proposition a user answer commits to if it is already
+    /// committed to, e.g. when the user repeats an answer.
+    /// # Arguments
+    /// * `answer` - The answer given.
+    fn known_answer(&self, answer: &Ans) -> Option<String> {
+        let prop = match (self.qud_top(), answer) {
+            (_, Ans::Prop(prop)) if prop.is_constraint() => return None,
+            (_, Ans::Conj(_) | Ans::Any | Ans::Unknown) => return None,
+            (Some(que), _) if self.domain.relevant(answer, &que) => self.domain.combine(&que, answer).ok()?,
+            (_, Ans::Prop(prop)) => prop.clone(),
+            _ => return None,
+        };
+        Some(prop.to_string()).filter(|prop| self.is.com.contains(prop))
+    }
+
+    /// Puts an acknowledgement of the latest user answer on the agenda, as
+    /// often as the acknowledgement frequency says.
+    fn acknowledge(&mut self) {
+        if self.rng.chance(self.acknowledgements) {
+            self.push_agenda(AgendaItem::Move(Move::Acknowledge(Acknowledge)));
+            self.trace("acknowledge");
+        }
+    }
+
+    /// Puts positive feedback repeating the propositions committed by a user
+    /// answer on the agenda, if the confirmation strategy asks for it.
+    /// # Arguments
+    /// * `committed` - The propositions the answer committed to.
+    /// * `confidence` - How sure the interpretation of the answer is.
+    fn confirm(&mut self, committed: &[String], confidence: f64) {
+        let confirmed = match self.confirmation {
+            Confirmation::NEVER => false,
+            Confirmation::LOW_CONFIDENCE => confidence < self.confirmation_threshold,
+            Confirmation::ALWAYS => true,
+        };
+        if !confirmed {
+            return;
+        }
+        for prop in committed {
+            self.push_agenda(AgendaItem::Move(Move::ICM(ICM::new("und", "pos", Some(prop.clone())))));
+            self.trace("confirm");
+        }
+    }
+
+    /// Integrates a user question: raises it and puts a Respond on the agenda.
+    /// A constrained question also commits to its constraints.
+    /// # Arguments
+    /// * `que` - The question asked.
+    fn integrate_usr_ask(&mut self, que: Question) {
+        if let Question::WhQ(whq) = &que {
+            for prop in whq.constraint_props() {
+                self.is.com.add(prop.to_string()).ok();
+            }
+        }
+        self.is.qud.push(que.to_string()).ok();
+        self.is.agenda.push(Respond::new(que).to_string()).ok();
+        self.trace("integrate_usr_ask");
+    }
+
+    /// Integrates a custom move by applying the effects its move kind
+    /// returns. Moves of unknown kinds are ignored.
+    /// # Arguments
+    /// * `custom` - The move.
+    /// * `speaker` - Who made it.
+    fn integrate_custom(&mut self, custom: Custom, speaker: Speaker) -> Result<(), String> {
+        let Some(kind) = self.domain.custom_move(&custom.name).cloned() else {
+            return Ok(());
+        };
+        for effect in kind.integrate(&custom.args, &speaker)? {
+            match effect {
+                MoveEffect::Commit(prop) => self.commit(&prop),
+                MoveEffect::Raise(que) => self.is.qud.push(que.to_string())?,
+                MoveEffect::Respond(que) => self.integrate_usr_ask(que),
+                MoveEffect::Reply(mv) => self.push_agenda(AgendaItem::Move(mv)),
+            }
+        }
+        self.trace(&format!("integrate_custom: {}", custom.name));
+        Ok(())
+    }
+
+    /// Integrates a system question by raising it.
+    /// # Arguments
+    /// * `que` - The question asked.
+    fn integrate_sys_ask(&mut self, que: Question) {
+        self.is.qud.push(que.to_string()).ok();
+        self.trace("integrate_sys_ask");
+    }
+
+    /// Integrates an answer relevant to the topmost question under discussion.
+    /// Constraints (e.g., "price()<300") are committed to regardless of the QUD,
+    /// propositions resolving a later Findout of the plan are accommodated, and
+    /// new values of functional predicates already committed to revise them.
+    /// # Arguments
+    /// * `answer` - The answer given.
+    fn integrate_answer(&mut self, answer: Ans) {
+        if let Ans::Unknown = answer {
+            if let Some(que) = self.qud_top()
+                && self.domain.relevant(&answer, &que)
+            {
+                self.defer_findout(que);
+            }
+            return;
+        }
+        if let Ans::Conj(conj) = answer {
+            self.trace("decompose_conjunction");
+            for prop in conj.conjuncts {
+                self.integrate_answer(Ans::Prop(prop));
+            }
+            return;
+        }
+        if let Ans::Prop(prop) = &answer
+            && prop.is_constraint()
+        {
+            self.commit(prop);
+            self.trace("integrate_constraint");
+            return;
+        }
+        if let Some(que) = self.qud_top()
+            && self.domain.relevant(&answer, &que)
+        {
+            if let Ok(prop) = self.domain.combine(&que, &answer) {
+                self.commit(&prop);
+                self.trace("integrate_answer");
+                self.learn_individual(&prop);
+            }
+        } else if let Ans::Prop(prop) = &answer
+            && self.plan_findouts().iter().any(|que| self.domain.resolves(&answer, que))
+        {
+            self.commit(prop);
+            self.trace("accommodate_findout");
+        } else if let Ans::Prop(prop) = &answer
+            && self.domain.is_functional(prop.pred())
+            && !self.committed_values(prop).is_empty()
+        {
+            self.commit(prop);
+        }
+    }
+
+    /// Defers a question the user cannot answer yet: its Findout moves behind
+    /// the other questions of the plan and the question leaves the QUD. A
+    /// question that cannot be deferred any further, or is not known the
+    /// second time, stays open and is asked again, so that the user can answer
+    /// it, say any answer will do, or be handed over once it has been asked
+    /// too often.
+    /// # Arguments
+    /// * `que` - The question to defer.
+    fn defer_findout(&mut self, que: Question) {
+        let findout = Findout::new(que.clone()).to_string();
+        let mut plan: Vec<String> = self.is.plan.iter_rev().cloned().collect(); // Next first
+        let position = plan.iter().position(|item| *item == findout);
+        let later = plan
+            .iter()
+            .position(|item| !matches!(PlanItem::new(item), Ok(PlanItem::Findout(_) | PlanItem::Raise(_))))
+            .unwrap_or(plan.len());
+        let Some(position) = position.filter(|p| p + 1 < later && !self.deferred.contains(&que.to_string())) else {
+            self.trace("keep_findout_open");
+            return;
+        };
+        let item = plan.remove(position);
+        plan.insert(later - 1, item);
+        self.is.plan = Plan::new(plan.into());
+        self.is.qud.remove(&que.to_string());
+        self.deferred.insert(que.to_string());
+        self.trace("defer_findout");
+    }
+
+    /// Returns the other positive commitments to the predicate of a proposition.
+    /// # Arguments
+    /// * `prop` - The proposition.
+    fn committed_values(&self, prop: &Prop) -> Vec<String> {
+        self.com_props()
+            .into_iter()
+            .filter(|p| p.yes && p.ind.is_some() && p.pred == prop.pred && p != prop)
+            .map(|p| p.to_string())
+            .collect()
+    }
+
+    /// Adds a proposition to the commitments. A new value of a functional
+    /// predicate replaces the previous one and revises the beliefs resting
+    /// on it.
+    /// # Arguments
+    /// * `prop` - The proposition.
+    fn commit(&mut self, prop: &Prop) {
+        if prop.yes && prop.ind.is_some() && self.domain.is_functional(prop.pred()) {
+            let previous = self.committed_values(prop);
+            if !previous.is_empty() {
+                for value in &previous {
+                    self.is.com.remove(value);
+                }
+                self.is.com.add(prop.to_string()).ok();
+                self.trace("revise_commitment");
+                self.revise_beliefs(prop.pred());
+                return;
+            }
+        }
+        self.is.com.add(prop.to_string()).ok();
+    }
+
+    /// Drops the beliefs resting on the value of a predicate: the results of
+    /// the database queries whose context includes it and the conclusions of
+    /// the rules with a premise about it, and so on for what those rest on.
+    /// The ConsultDB, Findout and Raise constructs of the open issue that
+    /// produced or were settled by dropped beliefs go back on the plan, in
+    /// the order of the domain plan, to be carried out again.
+    /// # Arguments
+    /// * `pred` - The revised predicate.
+    fn revise_beliefs(&mut self, pred: &str) {
+        let issue = self.is.issue.get().and_then(|issue| Question::new(issue).ok());
+        let issue_plan = issue.as_ref().and_then(|issue| self.domain.get_plan(issue));
+        let consults: Vec<Question> = self
+            .domain
+            .plans
+            .values()
+            .map(|plan| plan.iter().cloned().collect::<Vec<_>>())
+            .chain(issue_plan.iter().map(|plan| plan.iter_rev().cloned().collect()))
+            .flat_map(|plan| flatten_plan(plan, &self.domain.subplans))
+            .filter_map(|item| match item {
+                PlanItem::ConsultDB(consult) => Some(consult.content),
+                _ => None,
+            })
+            .collect();
+        let mut stale = HashSet::from([pred.to_string()]);
+        loop {
+            let known = stale.len();
+            for que in &consults {
+                let context = self.domain.query_predicates(que);
+                if context.is_none_or(|preds| preds.iter().any(|p| stale.contains(p)))
+                    && let Some(pred) = que.pred()
+                {
+                    stale.insert(pred.to_string());
+                }
+            }
+            for rule in self.domain.rules.iter() {
+                if rule.premises.iter().any(|p| stale.contains(p.pred())) {
+                    stale.insert(rule.conclusion.pred().to_string());
+                }
+            }
+            if stale.len() == known {
+                break;
+            }
+        }
+        let dropped: Vec<String> = self
+            .is
+            .bel
+            .iter()
+            .filter(|belief| Prop::new(belief).is_ok_and(|prop| stale.contains(prop.pred())))
+            .cloned()
+            .collect();
+        for belief in &dropped {
+            self.is.bel.remove(belief);
+        }
+        let (Some(issue), Some(issue_plan)) = (issue, issue_plan) else {
+            return;
+        };
+        if !self.is.qud.contains(&issue.to_string()) {
+            return;
+        }
+        let pending: HashSet<String> = self.is.plan.iter().cloned().collect();
+        for construct in issue_plan.iter() {
+            let redo = match PlanItem::new(construct) {
+                Ok(PlanItem::ConsultDB(consult)) => consult.content.pred().is_some_and(|p| stale.contains(p)),
+                Ok(PlanItem::Findout(Findout { content: que }) | PlanItem::Raise(Raise { content: que })) => {
+                    let settled = self.resolved(&que) || self.believed(&que).is_some();
+                    que.pred().is_some_and(|p| stale.contains(p)) && !settled
+                }
+                _ => false,
+            };
+            if redo && !pending.contains(construct) {
+                self.is.plan.push(construct.clone()).ok();
+            }
+        }
+        self.trace("revise_beliefs");
+    }
+
+    /// Adds the propositions the inference rules of a phase derive from the
+    /// commitments and beliefs to the beliefs. The identity of the user holds
+    /// as "user(<id>)" and each application-defined field as "<name>(<value>)".
+    /// # Arguments
+    /// * `phase` - The phase of the update.
+    fn apply_rules(&mut self, phase: RulePhase) -> Result<(), String> {
+        let user = self.mivs.user.get().map(|user| format!("user({})", user));
+        let fields: Vec<String> = self
+            .is
+            .fields
+            .iter()
+            .filter_map(|(name, field)| field.value.get().map(|value| format!("{}({})", name, value)))
+            .collect();
+        let facts: TSet<Prop> = self
+            .is
+            .com
+            .iter()
+            .chain(self.is.bel.iter())
+            .chain(user.iter())
+            .chain(fields.iter())
+            .filter_map(|p| Prop::new(p).ok())
+            .collect();
+        let inferences = self.domain.infer_phase_when(&phase, &facts, |condition| self.holds(condition));
+        if !inferences.is_empty() {
+            self.record_metric(TurnMetric::RulesFired(inferences.len() as u64));
+        }
+        for (rule, prop) in inferences {
+            self.is.bel.add(prop.to_string())?;
+            self.trace(&format!("infer: {}", rule));
+            self.record_coverage(CoverageKind::RULE, rule.to_string());
+        }
+        Ok(())
+    }
+
+    /// Checks a rule precondition against the information state.
+    /// # Arguments
+    /// * `condition` - The precondition.
+    fn holds(&self, condition: &Condition) -> bool {
+        match condition {
+            Condition::QudTop(pattern) => self.is.qud.top().is_ok_and(|que| pattern.matches(que)),
+            Condition::InQud(pattern) => self.is.qud.iter().any(|que| pattern.matches(que)),
+            Condition::PlanTop(pattern) => self.is.plan.top().is_ok_and(|item| pattern.matches(item)),
+            Condition::InPlan(pattern) => self.is.plan.iter().any(|item| pattern.matches(item)),
+            Condition::Committed(prop) => self.is.com.contains(&prop.to_string()),
+            Condition::Believed(prop) => self.is.bel.contains(&prop.to_string()),
+            Condition::And(a, b) => self.holds(a) && self.holds(b),
+            Condition::Or(a, b) => self.holds(a) || self.holds(b),
+            Condition::Not(a) => !self.holds(a),
+        }
+    }
+
+    /// Records a measurement with the metrics instruments, if any.
+    /// # Arguments
+    /// * `metric` - The measurement.
+    fn record_metric(&self, metric: TurnMetric) {
+        #[cfg(feature = "metrics")]
+        if let Some(metrics) = &self.metrics {
+            metrics.record(&metric, &self.variants);
+        }
+        #[cfg(not(feature = "metrics"))]
+        let _ = metric;
+    }
+
+    /// Records the individual of a proposition as a new individual of an open sort.
+    /// # Arguments
+    /// * `prop` - The proposition integrated.
+    fn learn_individual(&mut self, prop: &Prop) {
+        let Some(ind) = &prop.ind else {
+            return;
+        };
+        let Some(sort) = self.domain.preds1.get(prop.pred()).cloned() else {
+            return;
+        };
+        if self.domain.is_open_sort(&sort)
+            && !self.domain.inds.contains_key(&ind.0.content)
+            && self.domain_mut().learn_individual(&ind.0.content, &sort).is_ok()
+        {
+            self.trace("learn_individual");
+        }
+    }
+
+    /// Integrates a quit move by ending the dialogue.
+    fn integrate_quit(&mut self) -> Result<(), String> {
+        self.mivs.program_state.set(ProgramState::QUIT)?;
+        self.trace("integrate_quit");
+        Ok(())
+    }
+
+    /// Removes the topmost question under discussion if it is resolved.
+    fn downdate_qud(&mut self) -> bool {
+        match self.qud_top() {
+            Some(que) if self.resolved(&que) => {
+                self.is.qud.pop().ok();
+                self.trace("downdate_qud");
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Loads the domain plan for the Respond topmost on the agenda.
+    fn load_plan(&mut self) -> Result<(), String> {
+        let item = self.agenda_construct();
+        if let Some(PlanItem::Respond(respond)) = item
+            && let Some(plan) = self.domain.get_plan(&respond.content)
+        {
+            for construct in plan.iter() {
+                PlanItem::new(construct)?;
+            }
+            self.is.plan = plan;
+            self.is.issue.set(respond.content.to_string()).ok();
+            self.is.agenda.remove(&PlanItem::Respond(respond).to_string());
+            self.trace("find_plan");
+        }
+        Ok(())
+    }
+
+    /// Executes the topmost plan construct, if possible, fulfilling the issue
+    /// once the last construct is done.
+    fn exec_plan(&mut self) -> Result<bool, String> {
+        let item = self.is.plan.top().ok().and_then(|top| PlanItem::new(top).ok());
+        let coverage_name = self.coverage.as_ref().and(item.as_ref()).and_then(|item| self.plan_item_name(item));
+        let executed = match item {
+            Some(PlanItem::Findout(Findout { content: que }))
+            | Some(PlanItem::Raise(Raise { content: que })) => {
+                if !self.resolved(&que) && self.believed(&que).is_none() {
+                    return Ok(false);
+                }
+                self.is.plan.pop().ok();
+                self.trace("remove_findout");
+                true
+            }
+            Some(PlanItem::ConsultDB(consult)) => {
+                let start = Instant::now();
+                let result = self.database.try_consult_db(&consult.content, &self.query_context(&consult.content));
+                self.record_metric(TurnMetric::Database(start.elapsed()));
+                let prop = match result {
+                    Ok(prop) => prop,
+                    Err(reason) => return self.fail_plan(reason),
+                };
+                self.is.bel.add(prop.to_string())?;
+                self.is.plan.pop().ok();
+                self.trace("exec_consultDB");
+                true
+            }
+            Some(PlanItem::If(cond)) => self.execute_if(cond)?,
+            Some(PlanItem::OnFailure(_)) => {
+                self.is.plan.pop().ok();
+                true
+            }
+            Some(PlanItem::CallPlan(call)) => {
+                let subplan = self.domain.subplans.get(&call.name).cloned();
+                let subplan = subplan.ok_or(format!("Unknown subplan {}", call.name))?;
+                self.is.plan.pop().ok();
+                for construct in subplan.iter().rev() {
+                    self.is.plan.push(construct.clone())?;
+                }
+                self.trace(&format!("call_plan: {}", call.name));
+                true
+            }
+            _ => false,
+        };
+        if executed && let Some(name) = coverage_name {
+            self.record_coverage(CoverageKind::PLAN_ITEM, name);
+        }
+        if executed && self.is.plan.is_empty() {
+            self.complete_issue();
+        }
+        Ok(executed)
+    }
+
+    /// Records that the plan of the issue has completed, with the slots
+    /// committed to, if the issue has a fulfillment handler. The handler is
+    /// called once the update succeeds (see fulfil_issues).
+    fn complete_issue(&mut self) {
+        let Some(issue) = self.is.issue.get().cloned() else {
+            return;
+        };
+        if !self.fulfillments.contains_key(&issue) {
+            return;
+        }
+        let slots: HashMap<String, Ind> = self
+            .commitments()
+            .into_iter()
+            .filter(|prop| prop.yes && prop.constraint.is_none())
+            .filter_map(|prop| Some((prop.pred().to_string(), prop.ind?)))
+            .collect();
+        self.completed.push((issue, slots));
+    }
+
+    /// Calls the fulfillment handlers of the issues whose plans completed
+    /// during the update, in the order they completed.
+    fn fulfil_issues(&mut self) {
+        for (issue, slots) in std::mem::take(&mut self.completed) {
+            if let Some(handler) = self.fulfillments.get(&issue) {
+                handler(&slots);
+                self.trace(&format!("fulfil: {}", issue));
+            }
+        }
+    }
+
+    /// Handles the failure of the topmost plan construct: the constructs up to
+    /// the next OnFailure are dropped and its recovery constructs carried
+    /// out instead. Without an OnFailure the plan is abandoned and the system
+    /// says it cannot help.
+    /// # Arguments
+    /// * `reason` - Why the construct failed.
+    fn fail_plan(&mut self, reason: String) -> Result<bool, String> {
+        self.trace(&format!("plan_failure: {}", reason));
+        self.plan_failure = Some(reason);
+        while let Ok(construct) = self.is.plan.pop() {
+            if let Ok(PlanItem::OnFailure(on_failure)) = PlanItem::new(&construct) {
+                for construct in on_failure.recovery.into_iter().rev() {
+                    PlanItem::new(&construct)?;
+                    self.is.plan.push(construct)?;
+                }
+                self.trace("recover");
+                return Ok(true);
+            }
+        }
+        self.push_agenda(AgendaItem::Move(Move::ICM(ICM::new("acc", "neg", None))));
+        self.trace("abandon_plan");
+        Ok(true)
+    }
+
+    /// Replaces a conditional with the branch selected by the commitments or,
+    /// failing them, by the static facts of the knowledge base.
+    /// # Arguments
+    /// * `cond` - The conditional plan construct.
+    fn execute_if(&mut self, cond: If) -> Result<bool, String> {
+        let Question::YNQ(ynq) = &cond.cond else {
+            return Ok(false);
+        };
+        let com = self.com_props();
+        let negated = Prop { yes: !ynq.prop.yes, ..ynq.prop.clone() };
+        let holds = if com.contains(&ynq.prop) || self.is.bel.contains(&ynq.prop.to_string()) {
+            true
+        } else if com.contains(&negated) || self.is.bel.contains(&negated.to_string()) {
+            false
+        } else if let Some(holds) = self.knowledge.holds(&ynq.prop, &com) {
+            holds
+        } else {
+            return Ok(false);
+        };
+        let branch = if holds { cond.iftrue } else { cond.iffalse };
+        self.is.plan.pop().ok();
+        for construct in branch.into_iter().rev() {
+            PlanItem::new(&construct)?;
+            self.is.plan.push(construct)?;
+        }
+        self.trace("execute_if");
+        Ok(true)
+    }
+}
+
+/// Implements the DialogueManager trait for IBISController.
+impl DialogueManager for IBISController {
+    fn trace(&self, message: &str) {
+        if self.verbosity == Verbosity::TRACE {
+            let turn = self.mivs.turn.get().copied().unwrap_or_default();
+            println!("{{[{}] {}}}", turn, message);
+        }
+    }
+
+    fn reset(&mut self) {
+        self.is.init_is();
+        self.mivs.init_mivs();
+        self.reminders = 0;
+        self.asks.clear();
+        self.misunderstandings = 0;
+        self.turns = 0;
+        self.limit_exceeded = None;
+        self.rng.reseed();
+        self.history.clear();
+        self.compacted = HistorySummary::default();
+        self.transcript.clear();
+        self.handled_by.clear();
+        self.deferred.clear();
+        self.offered.clear();
+        self.errors.clear();
+        self.plan_failure = None;
+        self.preferences = match (&self.profiles, self.mivs.user.get()) {
+            (Some(profiles), Some(user)) => profiles.load(user),
+            _ => Vec::new(),
+        };
+    }
+
+    fn is_running(&self) -> bool {
+        self.mivs.program_state.get() != Some(&ProgramState::QUIT)
+    }
+
+    /// Selects the next moves from the agenda and the plan, after applying
+    /// the rules of the selection phase.
+    fn select(&mut self) -> Result<(), String> {
+        self.apply_rules(RulePhase::SELECTION)?;
+        // Feedback on the user input comes before the system's own moves
+        self.select_icm_sem_neg()?;
+        if self.mivs.program_state.get() != Some(&ProgramState::QUIT) {
+            if self.agenda_construct().is_none() && !self.select_from_plan() {
+                self.select_respond();
+            }
+            if !self.select_ask()? {
+                self.select_answer();
+            }
+            self.select_notifications();
+        }
+        self.select_moves();
+        Ok(())
+    }
+
+    /// Generates output from the next moves.
+    fn generate(&mut self) -> Result<(), String> {
+        let moves: Vec<String> = self.mivs.next_moves.iter().cloned().collect();
+        if !self.streaming || moves.is_empty() {
+            let output = self.realize(&moves);
+            return self.mivs.output.set(output);
+        }
+        // Generating the moves one by one gives the same sentences as generating them together
+        let mut sentences = Vec::new();
+        for mv in moves {
+            let sentence = self.realize(&[mv]);
+            let filtered = self.filter_output(&sentence);
+            self.output_handler.write_chunk(&filtered);
+            sentences.push(sentence);
+        }
+        self.mivs.output.set(sentences.join(" "))
+    }
+
+    /// Outputs the generated response.
+    fn output(&mut self) -> Result<(), String> {
+        let output = self.filter_output(self.mivs.output.get().map_or("[---]", |o| o.as_str()));
+        if self.streaming && !self.mivs.next_moves.is_empty() {
+            self.output_handler.end_output();
+        } else {
+            self.output_handler.write_output(&output);
+        }
+        self.input_handler.observe_output(&output);
+        let turn = self.mivs.next_turn()?;
+        self.transcript.push((turn, Speaker::SYS, output));
+        self.mivs.latest_speaker.set(Speaker::SYS)?;
+        let moves: Vec<String> = self.mivs.next_moves.iter().cloned().collect();
+        for mv in &moves {
+            self.record_coverage(CoverageKind::FORM, mv.clone());
+        }
+        self.set_latest_moves(Speaker::SYS, &moves, 1.0);
+        self.mivs.next_moves.clear();
+        Ok(())
+    }
+
+    /// Reads user input.
+    fn input(&mut self) -> Result<(), String> {
+        let event = match self.silence_timeout {
+            Some(timeout) => self.input_handler.read_line_timeout(timeout),
+            None => match self.input_handler.read_line() {
+                Some(line) => InputEvent::Line(line),
+                None => InputEvent::Closed,
+            },
+        };
+        match event {
+            InputEvent::Line(input) => {
+                self.turns += 1;
+                let input = self
+                    .input_filters
+                    .iter()
+                    .fold(input, |text, filter| filter.filter(&text));
+                self.reminders = 0;
+                let turn = self.mivs.next_turn()?;
+                self.transcript.push((turn, Speaker::USR, input.clone()));
+                self.mivs.input.set(input)?;
+                self.mivs.latest_speaker.set(Speaker::USR)
+            }
+            InputEvent::Silence => self.handle_silence(),
+            InputEvent::Closed => {
+                self.mivs.input.clear();
+                self.mivs.program_state.set(ProgramState::QUIT)
+            }
+        }
+    }
+
+    /// Interprets the user input into moves.
+    fn interpret(&mut self) {
+        self.mivs.latest_moves.clear();
+        if let Some(input) = self.mivs.input.get()
+            && !input.is_empty()
+        {
+            let start = Instant::now();
+            let mut text = format!(" {} ", self.tokenizer.tokenize(input).join(" "));
+            for (phrase, word) in &self.session_aliases {
+                text = text.replace(&format!(" {} ", phrase), &format!(" {} ", word));
+            }
+            let parse = self.grammar.parse(text.trim(), &self.interpretation_context());
+            self.record_metric(TurnMetric::Interpretation { latency: start.elapsed(), understood: parse.is_some() });
+            if let Some(parse) = parse {
+                let moves: Vec<String> = parse.moves.iter().cloned().collect();
+                let turn = self.mivs.turn.get().copied().unwrap_or_default();
+                self.handled_by.push((turn, parse.grammar));
+                self.set_latest_moves(Speaker::USR, &moves, parse.confidence);
+            } else {
+                if self.verbosity != Verbosity::QUIET {
+                    println!("Did not understand: {}", input);
+                }
+                let question = self.is.qud.top().ok().cloned();
+                self.unmatched.push(UnmatchedInput { input: input.clone(), question });
+            }
+        }
+    }
+
+    /// Updates the information state with the latest moves.
+    /// If a rule or plan construct fails, the dialogue state an update
+    /// changes (see UpdateSnapshot) is rolled back to the start of the update
+    /// and the error is reported. Issues whose plans completed are only
+    /// fulfilled once the update succeeds. Trace output, metrics, coverage
+    /// and the calls made to the database, the knowledge base and the host
+    /// hooks are not undone.
+    fn update(&mut self) {
+        let snapshot = self.snapshot();
+        match self.try_update() {
+            Ok(()) => self.fulfil_issues(),
+            Err(message) => {
+                self.roll_back(snapshot);
+                self.trace("rollback");
+                self.report_error(message);
+            }
+        }
+    }
+
+    /// Reports an error interrupting the turn to the host and records it.
+    /// # Arguments
+    /// * `message` - What went wrong.
+    fn report_error(&mut self, message: String) -> TurnError {
+        let turn = self.mivs.turn.get().copied().unwrap_or_default();
+        let error = TurnError { turn, message };
+        self.trace(&format!("turn_error: {}", error.message));
+        if let Some(handler) = &self.error_handler {
+            handler(&error);
+        }
+        self.errors.push(error.clone());
+        error
+    }
+
+    /// Takes one round of turns by running the steps of the control
+    /// algorithm of the kernel once: those repeated by the algorithm, or the
+    /// whole algorithm if it repeats nothing. Returns whether the dialogue
+    /// goes on, which it does not once the algorithm stops or repeats nothing.
+    fn exchange(&mut self) -> Result<bool, String> {
+        self.kernel.validate()?;
+        let algorithm = self.kernel.algorithm.clone();
+        let (round, repeated) = match algorithm.as_slice() {
+            [ControlStep::Repeat(steps)] => (steps.as_slice(), true),
+            steps => (steps, false),
+        };
+        Ok(self.execute(round)? && repeated && self.is_running())
+    }
+
+    fn control(&mut self) -> Result<(), TurnError> {
+        self.push_agenda(AgendaItem::Move(Move::Greet(Greet)));
+        self.print_state();
+        self.converse()
+    }
+
+    fn print_state(&self) {
+        if self.verbosity == Verbosity::QUIET {
+            return;
+        }
+        println!("+------------------------ - -  -");
+        self.mivs.print_mivs("| ");
+        println!("|");
+        self.is.print_is("| ");
+        println!("+------------------------ - -  -");
+        println!();
+    }
+}
+
+/// Additional implementation to make IBISController usable
+impl IBISController {
+    /// Runs the dialogue manager (public interface). An error ending the
+    /// session is reported to the error handler and kept in errors().
+    pub fn run(&mut self) {
+        self.try_run().ok();
+    }
+
+    /// Runs the dialogue manager, returning the error that ended the session, if any.
+    pub fn try_run(&mut self) -> Result<(), TurnError> {
+        <Self as DialogueManager>::run(self)
+    }
+
+    /// Runs the control algorithm of the kernel until the dialogue ends, is
+    /// paused or fails.
+    fn converse(&mut self) -> Result<(), TurnError> {
+        let algorithm = self.kernel.algorithm.clone();
+        let result = self
+            .kernel
+            .validate()
+            .and_then(|_| self.execute(&algorithm))
+            .map(|_| ())
+            .map_err(|message| self.report_error(message));
+        if !self.paused {
+            self.save_preferences();
+        }
+        if !self.is_running() {
+            self.mivs.turn_holder.clear();
+        }
+        result
+    }
+
+    /// Runs steps of the control algorithm. Returns false once a Stop is
+    /// reached, which stops the algorithm.
+    /// # Arguments
+    /// * `steps` - The steps, run in order.
+    fn execute(&mut self, steps: &[ControlStep]) -> Result<bool, String> {
+        for step in steps {
+            let go_on = match step {
+                ControlStep::Module(name) => {
+                    let module = self.kernel.modules.get(name).cloned().ok_or(format!("Unknown module {}", name))?;
+                    module.run(self)?;
+                    true
+                }
+                ControlStep::If(condition, steps) => !self.control_holds(*condition) || self.execute(steps)?,
+                ControlStep::Repeat(steps) => {
+                    while self.execute(steps)? {}
+                    false
+                }
+                ControlStep::Stop => {
+                    if self.paused {
+                        self.trace("pause");
+                    }
+                    false
+                }
+            };
+            if !go_on {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    /// Checks a condition of the control algorithm.
+    /// # Arguments
+    /// * `condition` - The condition.
+    fn control_holds(&self, condition: ControlCondition) -> bool {
+        match condition {
+            ControlCondition::NextMoves => !self.mivs.next_moves.is_empty(),
+            ControlCondition::Ended => !self.is_running(),
+            ControlCondition::Paused => self.paused,
+            ControlCondition::LimitExceeded => self.limit_exceeded.is_some(),
+        }
+    }
+
+    /// Runs many scripted dialogues, each from a fresh dialogue state, and
+    /// returns their outcomes. Failed expectations are recorded rather than
+    /// raised, and the input handler is restored afterwards.
+    /// # Arguments
+    /// * `dialogues` - The scripts, e.g. recorded user logs.
+    pub fn run_corpus<I, D>(&mut self, dialogues: I) -> Vec<DialogueResult>
+    where
+        I: IntoIterator<Item = D>,
+        D: Into<ScriptedInputHandler>,
+    {
+        let mut results = Vec::new();
+        for dialogue in dialogues {
+            let script = Rc::new(RefCell::new(dialogue.into().with_strict(false)));
+            let handler = std::mem::replace(&mut self.input_handler, Box::new(SharedScript(script.clone())));
+            self.run();
+            self.input_handler = handler;
+
+            let script = script.borrow();
+            let mut commitments: Vec<String> = self.is.com.iter().cloned().collect();
+            commitments.sort();
+            results.push(DialogueResult {
+                transcript: self.transcript.clone(),
+                mismatches: script.mismatches().to_vec(),
+                commitments,
+                completed: !script.has_input(),
+                limit_exceeded: self.limit_exceeded.clone(),
+                user: self.user().map(str::to_string),
+                variants: self.variants.clone(),
+            });
+        }
+        results
+    }
+
+    /// Runs many scripted dialogues in parallel and returns their outcomes in
+    /// the order of the scripts, as run_corpus does. Controllers are not shared
+    /// between threads: each worker thread builds its own and reuses it for the
+    /// dialogues it runs.
+    /// # Arguments
+    /// * `build` - Builds a controller, e.g. the one of an example domain.
+    /// * `dialogues` - The scripts, e.g. recorded user logs.
+    #[cfg(feature = "parallel")]
+    pub fn run_corpus_parallel<F, D>(build: F, dialogues: Vec<D>) -> Vec<DialogueResult>
+    where
+        F: Fn() -> IBISController + Sync + Send,
+        D: Into<ScriptedInputHandler> + Send,
+    {
+        use rayon::prelude::*;
+        dialogues
+            .into_par_iter()
+            .map_init(&build, |controller, dialogue| controller.run_corpus([dialogue]).remove(0))
+            .collect()
+    }
+}
+
+// Experiments
+
+/// A variant of an experiment: the rules and the forms given to the
+/// sessions assigned to it, e.g. selection rules asking questions in another
+/// order or shorter prompts.
+#[derive(Clone)]
+pub struct Variant {
+    name: String, // Name of the variant (e.g., "short_prompts")
+    rules: Vec<Rule>, // Rules added to the domain of its sessions
+    forms: Vec<(String, String)>, // Outputs of moves in its sessions, with their move
+}
+
+impl Variant {
+    /// Creates a variant without rules or forms, e.g. the control of an experiment.
+    /// # Arguments
+    /// * `name` - The name of the variant.
+    pub fn new(name: &str) -> Self {
+        Variant { name: name.to_string(), rules: Vec::new(), forms: Vec::new() }
+    }
+
+    /// Adds a rule, typically of the selection phase.
+    /// # Arguments
+    /// * `rule` - The rule.
+    pub fn with_rule(mut self, rule: Rule) -> Self {
+        self.rules.push(rule);
+        self
+    }
+
+    /// Adds the output of a move.
+    /// # Arguments
+    /// * `move_str` - The move (e.g., "Ask('?x.dest_city(x)')").
+    /// * `output` - Its output (e.g., "Where to?").
+    pub fn with_form(mut self, move_str: &str, output: &str) -> Self {
+        self.forms.push((move_str.to_string(), output.to_string()));
+        self
+    }
+
+    /// Returns the name of the variant.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+/// An A/B test comparing variants of rules or phrasing. Sessions are
+/// assigned to a variant by a hash of their id, so that a session keeps its
+/// variant when it comes back, and variants get about as many sessions each.
+#[derive(Clone)]
+pub struct Experiment {
+    name: String, // Name of the experiment (e.g., "prompt_length")
+    variants: Vec<Variant>, // The variants compared
+}
+
+impl Experiment {
+    /// Creates an experiment without variants.
+    /// # Arguments
+    /// * `name` - The name of the experiment.
+    pub fn new(name: &str) -> Self {
+        Experiment { name: name.to_string(), variants: Vec::new() }
+    }
+
+    /// Adds a variant.
+    /// # Arguments
+    /// * `variant` - The variant.
+    pub fn with_variant(mut self, variant: Variant) -> Self {
+        self.variants.push(variant);
+        self
+    }
+
+    /// Returns the name of the experiment.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns the variant a session is assigned to, or None if there are no variants.
+    /// # Arguments
+    /// * `session` - The id of the session.
+    pub fn assign(&self, session: &str) -> Option<&Variant> {
+        let hash = fnv1a(&format!("{}:{}", self.name, session));
+        self.variants.get((hash % self.variants.len().max(1) as u64) as usize)
+    }
+}
+
+// Sessions
+
+/// Builds the controller of a new session reading user input from a handler.
+type SessionFactory = Arc<dyn Fn(Box<dyn InputHandler>) -> IBISController + Send + Sync>;
+
+/// What the dialogue of a managed session tells its manager.
+enum SessionEvent {
+    Utterance(String), // A system utterance
+    Awaiting, // The system waits for the next user message
+}
+
+/// Input handler of a managed session, telling the manager whenever the
+/// system waits for the next user message.
+struct SessionInputHandler {
+    receiver: mpsc::Receiver<String>, // User messages
+    events: mpsc::Sender<SessionEvent>, // Destination of the turn boundaries
+    closed: bool, // Whether the manager has closed the session
+}
+
+impl InputHandler for SessionInputHandler {
+    fn read_line(&mut self) -> Option<String> {
+        self.events.send(SessionEvent::Awaiting).ok();
+        let input = self.receiver.recv().ok();
+        self.closed = input.is_none();
+        input
+    }
+
+    fn has_input(&self) -> bool {
+        !self.closed
+    }
+}
+
+/// Output handler of a managed session, sending system utterances to the manager.
+struct SessionOutputHandler {
+    events: mpsc::Sender<SessionEvent>, // Destination of the utterances
+}
+
+impl OutputHandler for SessionOutputHandler {
+    fn write_output(&mut self, output: &str) {
+        self.events.send(SessionEvent::Utterance(output.to_string())).ok();
+    }
+}
+
+/// A dialogue run by a session manager.
+struct Session {
+    input: mpsc::Sender<String>, // User messages
+    events: mpsc::Receiver<SessionEvent>, // Utterances and turn boundaries of the dialogue
+    thread: thread::JoinHandle<()>, // Thread running the dialogue
+}
+
+/// Runs a dialogue per conversation, e.g. per chat of a messaging bot, for
+/// servers taking the turns of many users. Each dialogue runs on its own
+/// thread with a controller built by a factory, and each message is a user
+/// turn answered by the system utterances that follow it.
+pub struct SessionManager {
+    factory: SessionFactory, // Builder of the controller of each new session
+    sessions: HashMap<String, Session>, // Running dialogues by session id
+    experiments: Arc<Vec<Experiment>>, // Experiments every new session takes part in
+}
+
+impl SessionManager {
+    /// Creates a manager without sessions.
+    /// # Arguments
+    /// * `factory` - The function building the controller of a new session from its input handler.
+    pub fn new<F>(factory: F) -> Self
+    where
+        F: Fn(Box<dyn InputHandler>) -> IBISController + Send + Sync + 'static,
+    {
+        SessionManager { factory: Arc::new(factory), sessions: HashMap::new(), experiments: Arc::default() }
+    }
+
+    /// Makes every new session take part in an experiment, with the variant
+    /// its session id is assigned to. Fails if a variant does not fit the
+    /// controllers built by the factory, e.g. with a rule over an unknown predicate.
+    /// # Arguments
+    /// * `experiment` - The experiment.
+    pub fn with_experiment(mut self, experiment: Experiment) -> Result<Self, String> {
+        for variant in &experiment.variants {
+            let mut controller = (self.factory)(Box::<ScriptedInputHandler>::default());
+            for joined in self.experiments.iter() {
+                controller.join_experiment(joined, "")?;
+            }
+            controller.apply_variant(&experiment, variant)?;
+        }
+        Arc::make_mut(&mut self.experiments).push(experiment);
+        Ok(self)
+    }
+
+    /// Takes the turn of a user message in a session and returns the system
+    /// utterances answering it. An unknown session is started first, its
+    /// greeting coming before the answer. A session whose dialogue ends,
+    /// e.g. when the user says goodbye, is closed, and the next message
+    /// starts a new one.
+    /// # Arguments
+    /// * `session` - The session id (e.g., the id of a chat).
+    /// * `message` - The user message.
+    pub fn handle(&mut self, session: &str, message: &str) -> Vec<String> {
+        let mut replies = Vec::new();
+        if !self.sessions.contains_key(session) {
+            let started = self.start(session);
+            let (greeting, running) = Self::drain(&started.events);
+            replies.extend(greeting);
+            if !running {
+                started.thread.join().ok();
+                return replies;
+            }
+            self.sessions.insert(session.to_string(), started);
+        }
+        let Some(running) = self.sessions.get(session) else {
+            return replies;
+        };
+        running.input.send(message.to_string()).ok();
+        let (answer, running) = Self::drain(&running.events);
+        replies.extend(answer);
+        if !running {
+            self.end(session);
+        }
+        replies
+    }
+
+    /// Closes a session, ending its dialogue. Returns whether it was running.
+    /// # Arguments
+    /// * `session` - The session id.
+    pub fn end(&mut self, session: &str) -> bool {
+        let Some(Session { input, events, thread }) = self.sessions.remove(session) else {
+            return false;
+        };
+        drop(input);
+        while events.recv().is_ok() {}
+        thread.join().ok();
+        true
+    }
+
+    /// Returns the ids of the running sessions, sorted.
+    pub fn sessions(&self) -> Vec<&str> {
+        let mut sessions: Vec<&str> = self.sessions.keys().map(String::as_str).collect();
+        sessions.sort();
+        sessions
+    }
+
+    /// Starts the dialogue of a new session on its own thread.
+    /// # Arguments
+    /// * `session` - The session id.
+    fn start(&self, session: &str) -> Session {
+        let (input, receiver) = mpsc::channel();
+        let (events, events_receiver) = mpsc::channel();
+        let factory = self.factory.clone();
+        let experiments = self.experiments.clone();
+        let session = session.to_string();
+        let thread = thread::spawn(move || {
+            let input_handler = SessionInputHandler { receiver, events: events.clone(), closed: false };
+            let mut controller = factory(Box::new(input_handler))
+                .with_output_handler(Box::new(SessionOutputHandler { events }));
+            // The variants were checked against the factory by with_experiment
+            for experiment in experiments.iter() {
+                controller.join_experiment(experiment, &session).ok();
+            }
+            controller.run();
+        });
+        Session { input, events: events_receiver, thread }
+    }
+
+    /// Collects the system utterances of a session until it waits for the
+    /// next user message, returning them with whether the dialogue goes on.
+    /// # Arguments
+    /// * `events` - The events of the session.
+    fn drain(events: &mpsc::Receiver<SessionEvent>) -> (Vec<String>, bool) {
+        let mut utterances = Vec::new();
+        loop {
+            match events.recv() {
+                Ok(SessionEvent::Utterance(utterance)) => utterances.push(utterance),
+                Ok(SessionEvent::Awaiting) => return (utterances, true),
+                Err(_) => return (utterances, false),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Tests for core data structures
+    #[test]
+    fn test_value_creation_and_operations() {
+        let allowed_values = HashSet::from(["a".to_string(), "b".to_string(), "c".to_string()]);
+        let mut value = Value::new_allowed(allowed_values);
+        
+        // Test setting valid value
+        assert!(value.set("a".to_string()).is_ok());
+        assert_eq!(value.get(), Some(&"a".to_string()));
+        
+        // Test setting invalid value
+        assert!(value.set("d".to_string()).is_err());
+        
+        // Test clearing value
+        value.clear();
+        assert_eq!(value.get(), None);
+    }
+    
+    #[test]
+    fn test_value_with_type_constraint() {
+        let mut value = Value::new_type(|s: &String| s.len() > 2);
+        
+        assert!(value.set("hello".to_string()).is_ok());
+        assert!(value.set("hi".to_string()).is_err());
+        assert_eq!(value.get(), Some(&"hello".to_string()));
+    }
+
+    #[test]
+    fn test_value_change_observer() {
+        let changes = Rc::new(std::cell::RefCell::new(Vec::new()));
+        let mut value = Value::new_type(|s: &String| s.len() > 2);
+        let log = changes.clone();
+        value.on_change(move |old, new| log.borrow_mut().push((old.cloned(), new.cloned())));
+
+        value.set("hello".to_string()).unwrap();
+        value.set("hello".to_string()).unwrap(); // Unchanged, not reported
+        assert!(value.set("hi".to_string()).is_err()); // Rejected, not reported
+        value.clear();
+        assert_eq!(
+            *changes.borrow(),
+            [(None, Some("hello".to_string())), (Some("hello".to_string()), None)]
+        );
+    }
+    
+    #[test]
+    fn test_stack_operations() {
+        let mut stack = Stack::new();
+        
+        // Test empty stack
+        assert!(stack.top().is_err());
+        assert!(stack.pop().is_err());
+        assert_eq!(stack.len(), 0);
+        
+        // Test push and basic operations
+        assert!(stack.push("item1".to_string()).is_ok());
+        assert!(stack.push("item2".to_string()).is_ok());
+        assert_eq!(stack.len(), 2);
+        
+        // Test top and pop
+        assert_eq!(stack.top().unwrap(), &"item2".to_string());
+        assert_eq!(stack.pop().unwrap(), "item2".to_string());
+        assert_eq!(stack.len(), 1);
+        
+        // Test clear
+        stack.clear();
+        assert_eq!(stack.len(), 0);
+    }
+    
+    #[test]
+    fn test_stack_with_type_constraint() {
+        let mut stack = Stack::with_type(|s: &String| s.starts_with("valid_"));
+        
+        assert!(stack.push("valid_item".to_string()).is_ok());
+        assert!(stack.push("invalid_item".to_string()).is_err());
+        assert_eq!(stack.len(), 1);
+    }
+    
+    #[test]
+    fn test_stackset_operations() {
+        let mut stackset = StackSet::new();
+        
+        // Test push with uniqueness
+        assert!(stackset.push("item1".to_string()).is_ok());
+        assert!(stackset.push("item2".to_string()).is_ok());
+        assert!(stackset.push("item1".to_string()).is_ok()); // Should move to top
+        
+        assert!(stackset.contains(&"item1".to_string()));
+        assert!(stackset.contains(&"item2".to_string()));
+        assert!(!stackset.contains(&"item3".to_string()));
+    }
+
+    #[test]
+    fn test_stack_iteration_and_removal() {
+        let mut stack = Stack::from(vec!["a".to_string(), "b".to_string(), "a".to_string(), "c".to_string()]);
+        assert!(!stack.is_empty());
+        assert_eq!(stack.top().unwrap(), "c");
+        assert_eq!(stack.peek_nth(1), Some(&"a".to_string()));
+        assert_eq!(stack.peek_nth(4), None);
+        assert_eq!(stack.iter().cloned().collect::<Vec<_>>(), ["a", "b", "a", "c"]);
+        assert_eq!(stack.iter_rev().cloned().collect::<Vec<_>>(), ["c", "a", "b", "a"]);
+
+        // Only the topmost occurrence is removed
+        assert!(stack.remove(&"a".to_string()));
+        assert_eq!(stack.iter().cloned().collect::<Vec<_>>(), ["a", "b", "c"]);
+        assert!(!stack.remove(&"d".to_string()));
+
+        let mut stackset = StackSet::from(vec!["a".to_string(), "b".to_string(), "a".to_string()]);
+        assert_eq!(stackset.len(), 2);
+        assert_eq!(stackset.iter_rev().cloned().collect::<Vec<_>>(), ["a", "b"]);
+        assert!(stackset.remove(&"a".to_string()));
+        assert_eq!(stackset.top().unwrap(), "b");
+        stackset.clear();
+        assert!(stackset.is_empty());
+    }
+    
+    #[test]
+    fn test_tset_operations() {
+        let mut tset = TSet::new();
+        
+        assert!(tset.add("item1".to_string()).is_ok());
+        assert!(tset.add("item2".to_string()).is_ok());
+        assert!(tset.add("item1".to_string()).is_ok()); // Duplicate should be fine
+        
+        assert_eq!(tset.len(), 2); // Sets contain unique elements
+        assert!(tset.contains(&"item1".to_string()));
+        assert!(tset.contains(&"item2".to_string()));
+        
+        tset.clear();
+        assert_eq!(tset.len(), 0);
+    }
+    
+    #[test]
+    fn test_tset_with_type_constraint() {
+        let mut tset = TSet::with_type(|s: &String| s.len() <= 5);
+        
+        assert!(tset.add("short".to_string()).is_ok());
+        assert!(tset.add("toolongstring".to_string()).is_err());
+        assert_eq!(tset.len(), 1);
+    }
+
+    /// Element type implementing Debug but not Display.
+    #[derive(Clone, PartialEq, Eq, Hash, Debug)]
+    struct Slot {
+        name: &'static str,
+        filled: bool,
+    }
+
+    #[test]
+    fn test_containers_without_display() {
+        let slot = |name| Slot { name, filled: false };
+        let mut stack = Stack::with_type(|slot: &Slot| !slot.filled);
+        stack.push(slot("city")).unwrap();
+        let error = stack.push(Slot { name: "day", filled: true }).unwrap_err();
+        assert_eq!(error, "Value Slot { name: \"day\", filled: true } does not match type constraint");
+        assert_eq!(stack.top().unwrap().name, "city");
+        let mut stackset = StackSet::new();
+        stackset.push(slot("city")).unwrap();
+        stackset.push(slot("city")).unwrap();
+        assert_eq!(stackset.len(), 1);
+        let mut tset = TSet::new();
+        tset.extend([slot("city"), slot("day")]).unwrap();
+        assert!(tset.contains(&slot("day")));
+        let mut value = Value::new_allowed(HashSet::from([slot("city")]));
+        value.set(slot("city")).unwrap();
+        let error = value.set(slot("day")).unwrap_err();
+        assert_eq!(error, "Value Slot { name: \"day\", filled: false } is not among allowed values");
+    }
+
+    #[test]
+    fn test_container_traits() {
+        let mut stack: Stack<i32> = (1..=3).collect();
+        stack.extend([4]);
+        assert_eq!(stack, Stack::from(vec![1, 2, 3, 4]));
+        assert_eq!(format!("{:?}", stack), "[4, 3, 2, 1]");
+        assert_eq!((&stack).into_iter().sum::<i32>(), 10);
+        assert_eq!(stack.into_iter().collect::<Vec<_>>(), [1, 2, 3, 4]);
+
+        let mut even = Stack::with_type(|n: &i32| n % 2 == 0);
+        even.extend([2, 3, 4]);
+        assert_eq!(even, Stack::from(vec![2, 4]));
+
+        let mut stackset: StackSet<&str> = ["a", "b"].into_iter().collect();
+        stackset.extend(["a"]);
+        assert_eq!(stackset, StackSet::from(vec!["b", "a"]));
+        assert_eq!(format!("{:?}", stackset), "[\"a\", \"b\"]");
+        assert_eq!(stackset.into_iter().collect::<Vec<_>>(), ["b", "a"]);
+
+        let mut tset: TSet<i32> = [1, 2].into_iter().collect();
+        Extend::extend(&mut tset, [3]);
+        assert_eq!(tset, [3, 2, 1].into_iter().collect());
+        assert_eq!(format!("{:?}", TSet::from_iter([7])), "{7}");
+
+        let mut value = Value::default();
+        assert_eq!(value, Value::new_type(|_: &i32| true));
+        value.set(5).unwrap();
+        assert_eq!(format!("{:?}", value), "Value(Some(5))");
+        assert_eq!(value.into_iter().collect::<Vec<_>>(), [5]);
+    }
+
+    #[test]
+    fn test_record_helpers() {
+        let address = Record::new(HashMap::from([("city".to_string(), Box::new("paris".to_string()) as Box<dyn Any>)]));
+        let mut record = Record::new(HashMap::from([
+            ("name".to_string(), Box::new("ada".to_string()) as Box<dyn Any>),
+            ("age".to_string(), Box::new(36) as Box<dyn Any>),
+            ("address".to_string(), Box::new(address) as Box<dyn Any>),
+            ("tags".to_string(), Box::new(Stack::from(vec!["a".to_string()])) as Box<dyn Any>),
+            ("handler".to_string(), Box::new(|| ()) as Box<dyn Any>),
+        ]));
+        assert_eq!(record.keys(), ["address", "age", "handler", "name", "tags"]);
+        assert_eq!(record.iter().count(), 5);
+        assert_eq!(record.get_as::<String>("name").unwrap(), "ada");
+        assert_eq!(record.get_as::<i32>("name"), None);
+        record.set_checked("age", 37).unwrap();
+        assert_eq!(record.get_as::<i32>("age"), Some(&37));
+        assert!(record.set_checked("age", "old").is_err());
+        assert!(record.set_checked("height", 1.8).is_err());
+        assert_eq!(
+            record.pformat("> ", "  "),
+            "> address:\n>   city: paris\n> age: 37\n> handler: <opaque>\n> name: ada\n> tags: <[ a <]"
+        );
+        assert_eq!(record.get_as::<Record>("address").unwrap().to_string(), "{city = paris}");
+    }
+
+    #[test]
+    fn test_tset_algebra() {
+        let mut a = TSet::with_type(|s: &String| s.len() <= 5);
+        a.extend(["x", "y", "z"].map(String::from)).unwrap();
+        let b: TSet<String> = ["y", "z", "toolong"].map(String::from).into_iter().collect();
+
+        let mut inter: Vec<String> = a.intersection(&b).into_iter().collect();
+        inter.sort();
+        assert_eq!(inter, ["y", "z"]);
+        let diff = a.difference(&b);
+        assert_eq!(diff.len(), 1);
+        assert!(diff.contains(&"x".to_string()));
+        // Derived sets keep the type constraint
+        assert!(a.union(&b).is_err());
+        assert!(diff.clone().add("toolong".to_string()).is_err());
+
+        // A failing extend adds nothing
+        assert!(a.extend(["w".to_string(), "toolong".to_string()]).is_err());
+        assert_eq!(a.len(), 3);
+
+        assert!(a.remove(&"x".to_string()));
+        assert!(!a.remove(&"x".to_string()));
+        a.retain(|s| s != "y");
+        assert_eq!((&a).into_iter().collect::<Vec<_>>(), [&"z".to_string()]);
+        a.clear();
+        assert!(a.is_empty());
+    }
+    
+    // Tests for semantic types
+    #[test]
+    fn test_atomic_creation() {
+        // Valid atomic values
+        assert!(Atomic::new("hello").is_ok());
+        assert!(Atomic::new("test_atom").is_ok());
+        assert!(Atomic::new("atom-with-dash").is_ok());
+        assert!(Atomic::new("atom+with+plus").is_ok());
+        assert!(Atomic::new("atom:with:colon").is_ok());
+        
+        // Invalid atomic values
+        assert!(Atomic::new("").is_err()); // Empty
+        assert!(Atomic::new("yes").is_err()); // Reserved word
+        assert!(Atomic::new("no").is_err()); // Reserved word
+        assert!(Atomic::new("123invalid").is_err()); // Starts with number
+        assert!(Atomic::new("invalid@char").is_err()); // Invalid character
+    }
+    
+    #[test]
+    fn test_ind_creation_and_display() {
+        let ind = Ind::new("paris").unwrap();
+        assert_eq!(ind.to_string(), "paris");
+        
+        assert!(Ind::new("").is_err());
+        assert!(Ind::new("invalid@").is_err());
+    }
+    
+    #[test]
+    fn test_pred0_creation_and_display() {
+        let pred = Pred0::new("expensive").unwrap();
+        assert_eq!(pred.to_string(), "expensive");
+        
+        assert!(Pred0::new("").is_err());
+        assert!(Pred0::new("yes").is_err());
+    }
+    
+    #[test]
+    fn test_pred1_creation_and_apply() {
+        let pred1 = Pred1::new("city").unwrap();
+        let ind = Ind::new("paris").unwrap();
+        
+        let prop = pred1.apply(&ind).unwrap();
+        assert_eq!(prop.to_string(), "city(paris)");
+        assert!(prop.yes);
+        
+        assert!(Pred1::new("").is_err());
+    }
+    
+    #[test]
+    fn test_sort_creation() {
+        let sort = Sort::new("city").unwrap();
+        assert_eq!(sort.to_string(), "city");
+        
+        assert!(Sort::new("").is_err());
+    }
+    
+    #[test]
+    fn test_prop_creation_and_parsing() {
+        // Test basic proposition
+        let prop = Prop::new("expensive").unwrap();
+        assert_eq!(prop.to_string(), "expensive()");
+        assert!(prop.yes);
+        
+        // Test negated proposition
+        let prop = Prop::new("-expensive").unwrap();
+        assert_eq!(prop.to_string(), "-expensive()");
+        assert!(!prop.yes);
+        
+        // Test proposition with individual
+        let prop = Prop::new("city(paris)").unwrap();
+        assert_eq!(prop.to_string(), "city(paris)");
+        assert!(prop.yes);
+        assert_eq!(prop.ind.as_ref().unwrap().to_string(), "paris");
+    }
+    
+    #[test]
+    fn test_shortans_creation_and_parsing() {
+        // Positive short answer
+        let ans = ShortAns::new("paris").unwrap();
+        assert_eq!(ans.to_string(), "paris");
+        assert!(ans.yes);
+        
+        // Negative short answer  
+        let ans = ShortAns::new("-paris").unwrap();
+        assert_eq!(ans.to_string(), "-paris");
+        assert!(!ans.yes);
+        
+        assert!(ShortAns::new("").is_err());
+    }
+    
+    #[test]
+    fn test_yesno_creation() {
+        let yes_ans = YesNo::new("yes").unwrap();
+        assert_eq!(yes_ans.to_string(), "yes");
+        assert!(yes_ans.yes);
+        
+        let no_ans = YesNo::new("no").unwrap();
+        assert_eq!(no_ans.to_string(), "no");
+        assert!(!no_ans.yes);
+        
+        assert!(YesNo::new("maybe").is_err());
+    }
+    
+    #[test]
+    fn test_ans_enum_parsing() {
+        // Test yes/no parsing
+        let ans = Ans::new("yes").unwrap();
+        match ans {
+            Ans::YesNo(yesno) => assert!(yesno.yes),
+            _ => panic!("Expected YesNo variant"),
+        }
+        
+        // Test short answer parsing
+        let ans = Ans::new("paris").unwrap();
+        match ans {
+            Ans::ShortAns(short) => {
+                assert_eq!(short.to_string(), "paris");
+                assert!(short.yes);
+            },
+            _ => panic!("Expected ShortAns variant"),
+        }
+        
+        // Test proposition parsing
+        let ans = Ans::new("city(paris)").unwrap();
+        match ans {
+            Ans::Prop(prop) => {
+                assert_eq!(prop.to_string(), "city(paris)");
+                assert!(prop.yes);
+            },
+            _ => panic!("Expected Prop variant"),
+        }
+        
+        assert!(Ans::new("invalid(syntax").is_err());
+    }
+    
+    #[test] 
+    fn test_whq_creation_and_parsing() {
+        // Test standard wh-question format
+        let whq = WhQ::new("?x.city(x)").unwrap();
+        assert_eq!(whq.pred.to_string(), "city");
+        
+        // Test simplified format
+        let whq = WhQ::new("city").unwrap();
+        assert_eq!(whq.pred.to_string(), "city");
+        
+        assert!(WhQ::new("").is_err());
+    }
+    
+    #[test]
+    fn test_ynq_creation_and_parsing() {
+        let ynq = YNQ::new("?expensive").unwrap();
+        assert_eq!(ynq.prop.to_string(), "expensive()");
+        
+        let ynq = YNQ::new("expensive").unwrap();
+        assert_eq!(ynq.prop.to_string(), "expensive()");
+        
+        assert!(YNQ::new("").is_err());
+    }
+    
+    #[test]
+    fn test_question_enum_parsing() {
+        // Test wh-question parsing
+        let q = Question::new("?x.city(x)").unwrap();
+        match q {
+            Question::WhQ(whq) => assert_eq!(whq.pred.to_string(), "city"),
+            _ => panic!("Expected WhQ variant"),
+        }
+        
+        // Test yes/no question parsing
+        let q = Question::new("?expensive").unwrap();
+        match q {
+            Question::YNQ(ynq) => assert_eq!(ynq.prop.pred.to_string(), "expensive"),
+            _ => panic!("Expected YNQ variant"),
+        }
+        
+        assert!(Question::new("invalid").is_err());
+    }
+    
+    #[test]
+    fn test_constraint_parsing() {
+        // Numeric atoms are accepted, but not mixed ones
+        assert!(Atomic::new("232").is_ok());
+        assert_eq!(Atomic::new("12.5").unwrap().as_number(), Some(12.5));
+
+        let c = Constraint::new("<300").unwrap();
+        assert!(c.admits(&Ind::new("232").unwrap()));
+        assert!(!c.admits(&Ind::new("345").unwrap()));
+        assert!(!c.admits(&Ind::new("paris").unwrap()));
+        assert!(Constraint::new(">=50").unwrap().admits(&Ind::new("50").unwrap()));
+        assert!(Constraint::new("<paris").is_err());
+
+        let prop = Prop::new("price()<300").unwrap();
+        assert!(prop.is_constraint());
+        assert_eq!(prop.to_string(), "price()<300");
+        assert!(!Prop::new("price(232)<300").unwrap().is_constraint());
+
+        let q = Question::new("?x.price(x)<300").unwrap();
+        assert_eq!(q.to_string(), "?x.price(x)<300");
+        assert!(matches!(Ans::new("price()<300"), Ok(Ans::Prop(_))));
+    }
+
+    #[test]
+    fn test_constrained_resolution() {
+        let preds1 = HashMap::from([("price".to_string(), "int".to_string())]);
+        let domain = Domain::new(HashSet::new(), preds1, HashMap::new());
+        let q = Question::new("?x.price(x)<300").unwrap();
+
+        assert!(domain.resolves(&Ans::new("price(232)").unwrap(), &q));
+        assert!(!domain.resolves(&Ans::new("price(345)").unwrap(), &q));
+        // A constraint is relevant but does not resolve the question
+        assert!(domain.relevant(&Ans::new("price()<300").unwrap(), &q));
+        assert!(!domain.resolves(&Ans::new("price()<300").unwrap(), &q));
+    }
+
+    #[test]
+    fn test_unification() {
+        let prop = |s: &str| Prop::new(s).unwrap();
+        let q = Question::new("?x.dest_city(x)").unwrap();
+        assert_eq!(q.variable(), Some("x"));
+        let bindings = q.unify(&prop("dest_city(paris)")).unwrap();
+        assert_eq!(bindings.get("x").map(|ind| ind.to_string()), Some("paris".to_string()));
+        assert!(q.unify(&prop("-dest_city(paris)")).is_some());
+        assert!(q.unify(&prop("depart_city(paris)")).is_none());
+        assert!(q.instantiate(&bindings).unwrap() == prop("dest_city(paris)"));
+        assert!(q.instantiate(&Bindings::new()).is_none());
+
+        let q = Question::new("?return()").unwrap();
+        assert_eq!(q.variable(), None);
+        assert!(q.unify(&prop("-return()")).unwrap().is_empty());
+        assert!(q.instantiate(&Bindings::new()).unwrap() == prop("return()"));
+    }
+
+    #[test]
+    fn test_compound_questions() {
+        let q = Question::new("?y.flight(y) ∧ price(y)<300").unwrap();
+        assert_eq!(q.to_string(), "?y.flight(y) ∧ price(y)<300");
+        assert_eq!(q.variable(), Some("y"));
+        let bindings = q.unify(&Prop::new("flight(ba123)").unwrap()).unwrap();
+        assert_eq!(bindings.get("y").map(|ind| ind.to_string()), Some("ba123".to_string()));
+        let Question::WhQ(whq) = &q else { panic!("Expected a wh-question") };
+        let props: Vec<String> = whq.constraint_props().iter().map(|p| p.to_string()).collect();
+        assert_eq!(props, ["price()<300"]);
+        assert_eq!(Question::new("?x.flight(x) & price(x)<300").unwrap().to_string(), "?x.flight(x) ∧ price(x)<300");
+        assert!(Question::new("?x.flight(x) ∧ price(z)<300").is_err());
+        assert!(Question::new("?x.flight(x) ∧ direct(x)").is_err());
+        assert!(Question::new("?x.flight(x) ∧ -price(x)<300").is_err());
+
+        // Conditions on the predicate asked about restrict its answers
+        let preds1 = HashMap::from([("price".to_string(), "int".to_string())]);
+        let mut domain = Domain::new(HashSet::new(), preds1, HashMap::new());
+        let q = Question::new("?p.price(p) ∧ price(p)>100").unwrap();
+        assert!(domain.resolves(&Ans::new("price(150)").unwrap(), &q));
+        assert!(!domain.resolves(&Ans::new("price(50)").unwrap(), &q));
+
+        // Questions differing only in their variable share a plan
+        domain.add_plan(Question::new("?x.price(x)").unwrap(), vec!["ConsultDB('?x.price(x)')".to_string()]);
+        let plan = domain.get_plan(&Question::new("?y.price(y)").unwrap()).unwrap();
+        assert_eq!(plan.iter().collect::<Vec<_>>(), ["ConsultDB('?y.price(y)')"]);
+
+        // Conditions on other predicates restrict the lookups answering the question
+        let controller = travel_controller(&[]);
+        let q = Question::new("?x.dest_city(x) ∧ price(x)<300").unwrap();
+        let context: Vec<String> = controller.query_context(&q).iter().map(|p| p.to_string()).collect();
+        assert_eq!(context, ["price()<300"]);
+    }
+
+    #[test]
+    fn test_conjunctive_answers() {
+        let answer = Ans::new("dest_city(paris) ∧ depart_city(berlin)").unwrap();
+        assert!(matches!(&answer, Ans::Conj(conj) if conj.conjuncts().len() == 2));
+        assert_eq!(answer.to_string(), "dest_city(paris) ∧ depart_city(berlin)");
+        assert!(Conj::new("dest_city(paris)").is_err());
+
+        // Both slots are filled in one turn
+        let mut controller = travel_controller(&["?x.price(x)", "dest_city(paris) & depart_city(berlin)"]);
+        controller.run();
+        assert!(controller.is.com.contains(&"dest_city(paris)".to_string()));
+        assert!(controller.is.com.contains(&"depart_city(berlin)".to_string()));
+        let asked = controller.history().iter().rev().find(|tagged| tagged.speaker == Speaker::SYS).unwrap();
+        assert_eq!(asked.to_string(), "Ask('?x.depart_day(x)')");
+    }
+
+    #[test]
+    fn test_any_and_unknown_answers() {
+        assert!(matches!(Ans::new("any"), Ok(Ans::Any)));
+        assert!(matches!(Ans::new("unknown"), Ok(Ans::Unknown)));
+
+        // The destination is deferred behind the other questions, then left open
+        let mut controller =
+            travel_controller(&["?x.price(x)", "I don't know", "berlin", "today", "no", "doesn't matter"]);
+        controller.run();
+        let asked: Vec<String> = controller
+            .history()
+            .iter()
+            .filter(|tagged| tagged.speaker == Speaker::SYS)
+            .map(|tagged| tagged.to_string())
+            .collect();
+        assert_eq!(asked[1..3], ["Ask('?x.dest_city(x)')", "Ask('?x.depart_city(x)')"]);
+        assert_eq!(asked[asked.len() - 2], "Ask('?x.dest_city(x)')");
+        assert!(controller.is.com.contains(&"dest_city(any)".to_string()));
+        assert!(controller.is.bel.contains(&"price(345)".to_string())); // Any destination matches
+
+        // A question not known the second time stays open
+        let inputs = ["?x.price(x)", "paris", "berlin", "no idea", "no", "no idea", "today"];
+        let mut controller = travel_controller(&inputs);
+        controller.run();
+        assert!(!controller.is.com.contains(&"depart_day(any)".to_string()));
+        assert!(controller.is.com.contains(&"depart_day(today)".to_string()));
+        assert!(controller.is.bel.contains(&"price(345)".to_string()));
+
+        // Indifference phrases match whole words only
+        let grammar = SimpleGenGrammar::new();
+        let domain = travel_domain();
+        let ctx = InterpretationContext::new(&domain);
+        assert!(grammar.interpret("I don't know.", &ctx).unwrap().contains(&"Answer(unknown)".to_string()));
+        assert!(grammar.interpret("Anything!", &ctx).unwrap().contains(&"Answer(any)".to_string()));
+        assert!(grammar.interpret("no idealism", &ctx).is_none());
+        assert!(!contains_words("there is nothing", "anything") && !contains_words("dont knowledge", "don't know"));
+    }
+
+    #[test]
+    fn test_travel_db_constraints() {
+        let mut db = TravelDB::new();
+        for price in ["345", "232", "180"] {
+            db.add_entry(HashMap::from([
+                ("from".to_string(), "berlin".to_string()),
+                ("to".to_string(), "paris".to_string()),
+                ("day".to_string(), "today".to_string()),
+                ("price".to_string(), price.to_string()),
+            ]));
+        }
+        let mut context = TSet::new();
+        for prop in ["depart_city(berlin)", "dest_city(paris)", "depart_day(today)", "price()>200"] {
+            context.add(Prop::new(prop).unwrap()).unwrap();
+        }
+
+        let q = Question::new("?x.price(x)<300").unwrap();
+        assert_eq!(db.try_consult_db(&q, &context).unwrap().to_string(), "price(232)<300");
+    }
+
+    #[test]
+    fn test_aggregate_parsing() {
+        let q = Question::new("?x.min:price(x)").unwrap();
+        assert_eq!(q.to_string(), "?x.min:price(x)");
+        match &q {
+            Question::WhQ(whq) => assert_eq!(whq.aggregate, Some(Aggregate::Min)),
+            _ => panic!("Expected WhQ variant"),
+        }
+        let prop = Prop::new("count:price(2)").unwrap();
+        assert_eq!(prop.aggregate, Some(Aggregate::Count));
+        assert_eq!(prop.to_string(), "count:price(2)");
+
+        let values: Vec<Ind> = ["345", "232", "paris"].iter().map(|v| Ind::new(v).unwrap()).collect();
+        assert_eq!(Aggregate::Min.apply(&values).unwrap().to_string(), "232");
+        assert_eq!(Aggregate::Max.apply(&values).unwrap().to_string(), "345");
+        assert_eq!(Aggregate::Count.apply(&values).unwrap().to_string(), "3");
+        assert!(Aggregate::Min.apply(&[]).is_none());
+
+        // Only answers with the same aggregate resolve an aggregate question
+        let preds1 = HashMap::from([("price".to_string(), "int".to_string())]);
+        let domain = Domain::new(HashSet::new(), preds1, HashMap::new());
+        assert!(domain.resolves(&Ans::new("min:price(232)").unwrap(), &q));
+        assert!(!domain.resolves(&Ans::new("price(232)").unwrap(), &q));
+    }
+
+    #[test]
+    fn test_travel_db_aggregates() {
+        let mut db = TravelDB::new();
+        for price in ["345", "232", "180"] {
+            db.add_entry(HashMap::from([
+                ("from".to_string(), "berlin".to_string()),
+                ("to".to_string(), "paris".to_string()),
+                ("day".to_string(), "today".to_string()),
+                ("price".to_string(), price.to_string()),
+            ]));
+        }
+        let mut context = TSet::new();
+        for prop in ["depart_city(berlin)", "dest_city(paris)", "depart_day(today)"] {
+            context.add(Prop::new(prop).unwrap()).unwrap();
+        }
+
+        assert_eq!(db.aggregate(Aggregate::Max, "price", &context).unwrap().to_string(), "345");
+        assert_eq!(db.aggregate(Aggregate::Count, "price", &context).unwrap().to_string(), "3");
+        let q = Question::new("?x.min:price(x)").unwrap();
+        assert_eq!(db.try_consult_db(&q, &context).unwrap().to_string(), "min:price(180)");
+
+        context.add(Prop::new("price()>200").unwrap()).unwrap();
+        assert_eq!(db.aggregate(Aggregate::Count, "price", &context).unwrap().to_string(), "2");
+    }
+
+    #[test]
+    fn test_answer_with_support() {
+        let mv = Move::new("Answer(price(232), [depart_city(berlin), dest_city(paris)])").unwrap();
+        assert_eq!(mv.to_string(), "Answer(price(232), [depart_city(berlin), dest_city(paris)])");
+        assert!(Move::new("Answer(price(232), [(paris)])").is_err());
+
+        let mut grammar = SimpleGenGrammar::new();
+        grammar.add_answer_form("price", "The price from {depart_city} to {dest_city} is {}");
+        let domain = Domain::new(HashSet::new(), HashMap::new(), HashMap::new());
+        let moves = ["Answer(price(232), [depart_city(berlin), dest_city(paris)])".to_string()];
+        assert_eq!(grammar.generate(&moves, &InterpretationContext::new(&domain)), "The price from berlin to paris is 232.");
+
+        // Answers found under a constraint take the answer form too
+        grammar.add_answer_form("price", "The price from {depart_city} under {bound} is {}");
+        let moves = ["Answer(price(232)<300, [depart_city(berlin)])".to_string()];
+        let ctx = InterpretationContext::new(&domain);
+        assert_eq!(grammar.generate(&moves, &ctx), "The price from berlin under 300 is 232.");
+
+        grammar.add_answer_form("-pets()", "Sorry, pets are not allowed");
+        let moves = ["Answer(-pets(), [price(232)])".to_string()];
+        assert_eq!(grammar.generate(&moves, &InterpretationContext::new(&domain)), "Sorry, pets are not allowed.");
+        assert_eq!(Question::new("?pets()").unwrap().pred(), Some("pets"));
+        assert_eq!(Prop::new("-pets()").unwrap().pred(), "pets");
+        assert!(!Prop::new("-pets()").unwrap().is_positive());
+    }
+
+    #[test]
+    fn test_constraint_interpretation_and_generation() {
+        let mut grammar = SimpleGenGrammar::new();
+        grammar.add_unit("euros", "price");
+        let domain = Domain::new(HashSet::new(), HashMap::new(), HashMap::new());
+        let ctx = InterpretationContext::new(&domain);
+
+        let moves = grammar.interpret("under 300 euros", &ctx).unwrap();
+        assert!(moves.contains(&"Answer(price()<300)".to_string()));
+        assert!(grammar.interpret("under 300 dollars", &ctx).is_none());
+
+        let moves = ["Answer(price(232)<300)".to_string()];
+        assert_eq!(grammar.generate(&moves, &ctx), "The cheapest is 232.");
+        let moves = ["Answer(price(232)>200)".to_string()];
+        assert_eq!(grammar.generate(&moves, &ctx), "The lowest above 200 is 232.");
+        grammar.set_constrained_form(CmpOp::Gt, "Prices over {bound} start at {}");
+        assert_eq!(grammar.generate(&moves, &ctx), "Prices over 200 start at 232.");
+    }
+
+    #[test]
+    fn test_interpretation_context() {
+        let preds1 = HashMap::from([
+            ("dest_city".to_string(), "city".to_string()),
+            ("depart_day".to_string(), "day".to_string()),
+            ("return_day".to_string(), "day".to_string()),
+        ]);
+        let sorts = HashMap::from([
+            ("city".to_string(), HashSet::from(["paris".to_string()])),
+            ("day".to_string(), HashSet::from(["today".to_string()])),
+        ]);
+        let domain = Domain::new(HashSet::new(), preds1, sorts);
+        let grammar = SimpleGenGrammar::new();
+        let mut ctx = InterpretationContext::new(&domain);
+        ctx.qud = vec![Question::new("?x.return_day(x)").unwrap()];
+        ctx.findouts = ["?x.dest_city(x)", "?x.depart_day(x)"].iter().map(|q| Question::new(q).unwrap()).collect();
+
+        // The topmost question wins over the plan
+        let moves = grammar.interpret("today", &ctx).unwrap();
+        assert!(moves.contains(&"Answer(return_day(today))".to_string()));
+        // Otherwise the only relevant findout is used
+        let moves = grammar.interpret("paris", &ctx).unwrap();
+        assert!(moves.contains(&"Answer(dest_city(paris))".to_string()));
+        // Ambiguous findouts leave the answer bare
+        ctx.qud.clear();
+        ctx.findouts.push(Question::new("?x.return_day(x)").unwrap());
+        let moves = grammar.interpret("today", &ctx).unwrap();
+        assert!(moves.contains(&"Answer(today)".to_string()));
+
+        let moves = ["Ask('?x.dest_city(x)')".to_string()];
+        assert_eq!(grammar.generate(&moves, &ctx), "Which city?");
+    }
+
+    #[test]
+    fn test_move_and_plan_item_parsing() {
+        assert!(matches!(Move::new("Ask('?x.how(x)')"), Ok(Move::Ask(_))));
+        assert!(matches!(Move::new("Answer(paris)"), Ok(Move::Answer(_))));
+        assert_eq!(Move::new("icm:sem*neg").unwrap().to_string(), "icm:sem*neg");
+        assert!(Move::new("Dance()").is_err());
+
+        let item = PlanItem::new("If('?return()', ['Findout(?x.return_day(x))'], [])").unwrap();
+        match item {
+            PlanItem::If(cond) => {
+                assert_eq!(cond.cond.to_string(), "?return()");
+                assert_eq!(cond.iftrue, vec!["Findout(?x.return_day(x))".to_string()]);
+                assert!(cond.iffalse.is_empty());
+            }
+            _ => panic!("Expected If variant"),
+        }
+        assert!(matches!(PlanItem::new("ConsultDB('?x.price(x)')"), Ok(PlanItem::ConsultDB(_))));
+    }
+
+    // Tests for dialogue components
+    #[test]
+    fn test_dialogue_moves() {
+        // Test Greet
+        let greet = Greet;
+        assert_eq!(greet.to_string(), "Greet()");
+        
+        // Test Quit
+        let quit = Quit;
+        assert_eq!(quit.to_string(), "Quit()");
+        
+        // Test Ask
+        let question = Question::new("?expensive").unwrap();
+        let ask = Ask::new(question);
+        assert!(ask.to_string().contains("Ask"));
+        assert!(ask.to_string().contains("expensive"));
+        
+        // Test Answer
+        let answer_content = Ans::new("yes").unwrap();
+        let answer = Answer::new(answer_content);
+        assert!(answer.to_string().contains("Answer"));
+        assert!(answer.to_string().contains("yes"));
+    }
+    
+    #[test]
+    fn test_icm_creation() {
+        let icm = ICM::new("per", "pos", Some("understood".to_string()));
+        assert_eq!(icm.to_string(), "icm:per*pos:'understood'");
+        
+        let icm_no_content = ICM::new("sem", "neg", None);
+        assert_eq!(icm_no_content.to_string(), "icm:sem*neg");
+    }
+    
+    #[test]
+    fn test_plan_constructors() {
+        let question = Question::new("?expensive").unwrap();
+        
+        // Test Respond
+        let respond = Respond::new(question.clone());
+        assert!(respond.to_string().contains("Respond"));
+        assert!(respond.to_string().contains("expensive"));
+        
+        // Test ConsultDB
+        let consult = ConsultDB::new(question.clone());
+        assert!(consult.to_string().contains("ConsultDB"));
+        assert!(consult.to_string().contains("expensive"));
+        
+        // Test Findout
+        let findout = Findout::new(question.clone());
+        assert!(findout.to_string().contains("Findout"));
+        assert!(findout.to_string().contains("expensive"));
+        
+        // Test Raise
+        let raise = Raise::new(question.clone());
+        assert!(raise.to_string().contains("Raise"));
+        assert!(raise.to_string().contains("expensive"));
+        
+        // Test If
+        let if_plan = If::new(
+            question, 
+            vec!["ConsultDB".to_string()], 
+            vec!["Greet".to_string()]
+        );
+        assert!(if_plan.to_string().contains("If"));
+    }
+    
+    // Tests for grammar functionality
+    #[test]
+    fn test_simple_gen_grammar() {
+        let mut grammar = SimpleGenGrammar::new();
+        let domain = Domain::new(HashSet::new(), HashMap::new(), HashMap::new());
+        let ctx = InterpretationContext::new(&domain);
+        
+        // Test adding custom forms
+        grammar.add_form("Ask('?price')", "What is the price?");
+        grammar.add_form("Answer(paris)", "The answer is Paris.");
+        
+        // Test generation
+        let moves = ["Greet()".to_string()];
+        let output = grammar.generate(&moves, &ctx);
+        assert_eq!(output, "Hello.");
+        
+        // Test interpretation - "quit" is handled as special case in the grammar
+        let interpreted = grammar.interpret("quit", &ctx);
+        assert!(interpreted.is_some());
+        let moves = interpreted.unwrap();
+        assert!(moves.elements.iter().any(|m| m.contains("Quit")));
+        
+        // Test question interpretation  
+        let interpreted = grammar.interpret("?expensive", &ctx);
+        assert!(interpreted.is_some());
+        let moves = interpreted.unwrap();
+        assert!(moves.elements.iter().any(|m| m.contains("Ask") && m.contains("expensive")));
+        
+        // Test answer interpretation
+        let interpreted = grammar.interpret("yes", &ctx);
+        assert!(interpreted.is_some());
+        let moves = interpreted.unwrap();
+        assert!(moves.elements.iter().any(|m| m.contains("Answer") && m.contains("yes")));
+        
+        // Test unrecognized input
+        let interpreted = grammar.interpret("random gibberish", &ctx);
+        assert!(interpreted.is_none());
+    }
+    
+    #[test]
+    fn test_generation_cache() {
+        let mut grammar = SimpleGenGrammar::new();
+        let preds1 = HashMap::from([("dest_city".to_string(), "city".to_string())]);
+        let mut domain = Domain::new(HashSet::new(), preds1, HashMap::new());
+        grammar.add_answer_form("dest_city", "You are going to {}");
+        let moves = ["Greet()".to_string(), "Answer(dest_city(paris))".to_string()];
+        let output = grammar.generate(&moves, &InterpretationContext::new(&domain));
+        assert_eq!(output, "Hello. You are going to paris.");
+        assert_eq!(grammar.generate(&moves, &InterpretationContext::new(&domain)), output);
+        assert_eq!(grammar.cache().outputs.len(), 1);
+
+        // Changing a form empties the cache
+        grammar.add_form("Greet()", "Hi");
+        assert_eq!(grammar.generate(&moves, &InterpretationContext::new(&domain)), "Hi. You are going to paris.");
+
+        // Questions phrased after their sort follow the domain
+        let ask = ["Ask('?x.dest_city(x)')".to_string()];
+        assert_eq!(grammar.generate(&ask, &InterpretationContext::new(&domain)), "Ask('?x.dest_city(x)').");
+        domain.add_open_sort("city");
+        assert_eq!(grammar.generate(&ask, &InterpretationContext::new(&domain)), "Which city?");
+    }
+
+    #[test]
+    fn test_interpretation_ranking() {
+        let mut domain = Domain::new(HashSet::from(["outdoor".to_string()]), HashMap::new(), HashMap::new());
+        domain.add_alias("outside", "outdoor").unwrap();
+        let grammar = SimpleGenGrammar::new();
+        let mut ctx = InterpretationContext::new(&domain);
+
+        // Without an open question the bare reading comes first
+        let readings = grammar.interpretations("outside", &ctx);
+        let moves: Vec<Vec<&String>> = readings.iter().map(|r| r.moves.iter().collect()).collect();
+        assert_eq!(moves, [vec!["Answer(outside)"], vec!["Answer(outdoor())"]]);
+        assert_eq!(readings[0].score, readings[1].score);
+
+        // The alias reading answers the open question
+        ctx.qud = vec![Question::new("?outdoor()").unwrap()];
+        let readings = grammar.interpretations("outside", &ctx);
+        assert_eq!(readings[0].score, 3);
+        assert!(grammar.interpret("outside", &ctx).unwrap().contains(&"Answer(outdoor())".to_string()));
+        assert!(grammar.interpretations("gibberish words", &ctx).is_empty());
+    }
+
+    // Tests for database functionality
+    #[test]
+    fn test_travel_db() {
+        let mut db = TravelDB::new();
+        
+        // Add sample entries
+        let mut entry1 = HashMap::new();
+        entry1.insert("from".to_string(), "paris".to_string());
+        entry1.insert("to".to_string(), "london".to_string());
+        entry1.insert("day".to_string(), "monday".to_string());
+        entry1.insert("price".to_string(), "200".to_string());
+        db.add_entry(entry1);
+        
+        let mut entry2 = HashMap::new();
+        entry2.insert("from".to_string(), "london".to_string());
+        entry2.insert("to".to_string(), "paris".to_string());
+        entry2.insert("day".to_string(), "tuesday".to_string());
+        entry2.insert("price".to_string(), "180".to_string());
+        db.add_entry(entry2);
+        
+        // Test lookup
+        let result = db.lookup_entry("paris", "london", "monday");
+        assert!(result.is_some());
+        assert_eq!(result.unwrap().get("price"), Some(&"200".to_string()));
+        
+        let no_result = db.lookup_entry("invalid", "route", "never");
+        assert!(no_result.is_none());
+        
+        // The commitments in the context select the entries
+        let mut context = TSet::new();
+        let prop1 = Prop {
+            pred: Pred0::new("depart_city").unwrap(),
+            ind: Some(Ind::new("paris").unwrap()),
+            yes: true,
+            constraint: None,
+            aggregate: None,
+        };
+        context.add(prop1).unwrap();
+        
+        let price = Question::new("?x.price(x)").unwrap();
+        assert_eq!(db.try_consult_db(&price, &context).unwrap().to_string(), "price(200)");
+
+        context.add(Prop::new("dest_city(rome)").unwrap()).unwrap();
+        assert!(db.try_consult_db(&price, &context).is_err());
+    }
+    
+    // Tests for domain functionality
+    #[test]
+    fn test_domain_creation_and_operations() {
+        let preds0 = HashSet::from(["expensive".to_string(), "available".to_string()]);
+        let mut preds1 = HashMap::new();
+        preds1.insert("city".to_string(), "location".to_string());
+        preds1.insert("transport".to_string(), "vehicle".to_string());
+        
+        let mut sorts = HashMap::new();
+        sorts.insert("location".to_string(), HashSet::from(["paris".to_string(), "london".to_string()]));
+        sorts.insert("vehicle".to_string(), HashSet::from(["plane".to_string(), "train".to_string()]));
+        
+        let mut domain = Domain::new(preds0, preds1, sorts);
+        
+        // Test individuals are correctly inferred
+        assert_eq!(domain.inds.get("paris"), Some(&"location".to_string()));
+        assert_eq!(domain.inds.get("plane"), Some(&"vehicle".to_string()));
+        
+        // Test adding plans
+        let question = Question::new("?expensive").unwrap();
+        let plan = vec!["ConsultDB".to_string(), "Respond".to_string()];
+        domain.add_plan(question.clone(), plan);
+        
+        let retrieved_plan = domain.get_plan(&question);
+        assert!(retrieved_plan.is_some());
+        let plan_stack = retrieved_plan.unwrap();
+        assert_eq!(plan_stack.len(), 2);
+        
+        // Test relevance checking
+        let ans_yes = Ans::new("yes").unwrap();
+        let ynq = Question::new("?expensive").unwrap();
+        assert!(domain.relevant(&ans_yes, &ynq));
+        
+        let ans_paris = Ans::new("paris").unwrap();
+        let whq = Question::new("?x.city(x)").unwrap();
+        assert!(domain.relevant(&ans_paris, &whq));
+        
+        // Test resolution checking
+        let ans_yes = Ans::new("yes").unwrap();
+        let ynq = Question::new("?expensive").unwrap();
+        assert!(domain.resolves(&ans_yes, &ynq));
+        
+        let ans_no = Ans::new("no").unwrap();
+        assert!(domain.resolves(&ans_no, &ynq));
+        
+        // Test combination
+        let combined = domain.combine(&whq, &ans_paris);
+        assert!(combined.is_ok());
+        let prop = combined.unwrap();
+        assert_eq!(prop.pred.to_string(), "city");
+        assert_eq!(prop.ind.as_ref().unwrap().to_string(), "paris");
+    }
+
+    #[test]
+    fn test_domain_aliases() {
+        let preds0 = HashSet::from(["return".to_string()]);
+        let preds1 = HashMap::from([("dest_city".to_string(), "city".to_string())]);
+        let sorts = HashMap::from([("city".to_string(), HashSet::from(["paris".to_string()]))]);
+        let mut domain = Domain::new(preds0, preds1, sorts);
+        domain.add_alias("to", "dest_city").unwrap();
+        domain.add_alias("going to", "dest_city").unwrap();
+        domain.add_alias("return ticket", "return").unwrap();
+        assert!(domain.add_alias("from", "depart_city").is_err());
+
+        assert_eq!(domain.resolve_pred("going to"), Some("dest_city"));
+        assert_eq!(domain.resolve_pred("dest_city"), Some("dest_city"));
+        assert_eq!(domain.resolve_pred("from"), None);
+
+        assert_eq!(domain.interpret_alias("going to paris").unwrap().to_string(), "dest_city(paris)");
+        assert_eq!(domain.interpret_alias("to paris").unwrap().to_string(), "dest_city(paris)");
+        assert_eq!(domain.interpret_alias("return ticket").unwrap().to_string(), "return()");
+        assert!(domain.interpret_alias("to london").is_none());
+        let sentence = domain.interpret_alias("I want to go to paris, please").unwrap();
+        assert_eq!(sentence.to_string(), "dest_city(paris)");
+        assert!(domain.interpret_alias("tokyo paris").is_none());
+
+        assert_eq!(
+            domain.vocabulary(),
+            ["dest_city", "going to", "paris", "return", "return ticket", "to"]
+        );
+    }
+    
+    #[test]
+    fn test_domain_composition() {
+        let preds1 = HashMap::from([("dest_city".to_string(), "city".to_string())]);
+        let sorts = HashMap::from([("city".to_string(), HashSet::from(["paris".to_string()]))]);
+        let mut domain = Domain::new(HashSet::new(), preds1, sorts);
+
+        let preds1 = HashMap::from([
+            ("temperature".to_string(), "degrees".to_string()),
+            ("city".to_string(), "city".to_string()),
+        ]);
+        let sorts = HashMap::from([
+            ("city".to_string(), HashSet::from(["berlin".to_string()])),
+            ("degrees".to_string(), HashSet::from(["warm".to_string(), "cold".to_string()])),
+        ]);
+        let mut weather = Domain::new(HashSet::from(["rain".to_string()]), preds1, sorts);
+        weather.add_plan(
+            Question::new("?x.temperature(x)").unwrap(),
+            vec!["Findout('?x.city(x)')".to_string(), "If('?rain()', ['Findout(?x.city(x))'], [])".to_string()],
+        );
+        weather.add_alias("in", "city").unwrap();
+
+        domain.include("weather", weather.clone()).unwrap();
+        assert!(domain.preds1.contains_key("weather:temperature"));
+        assert!(domain.preds0.contains("weather:rain"));
+        assert_eq!(domain.sorts.get("city").unwrap().len(), 2);
+        assert_eq!(domain.resolve_pred("in"), Some("weather:city"));
+        let plan = domain.get_plan(&Question::new("?x.weather:temperature(x)").unwrap()).unwrap();
+        assert_eq!(
+            plan.iter_rev().cloned().collect::<Vec<_>>(),
+            ["Findout('?x.weather:city(x)')", "If('?weather:rain()', ['Findout(?x.weather:city(x))'], [])"]
+        );
+        assert!(domain.include("min", weather.clone()).is_err());
+
+        // Merging without a namespace detects conflicting definitions
+        let preds1 = HashMap::from([("dest_city".to_string(), "airport".to_string())]);
+        let sorts = HashMap::from([("airport".to_string(), HashSet::from(["paris".to_string()]))]);
+        let err = domain.merge(Domain::new(HashSet::new(), preds1, sorts)).unwrap_err();
+        assert_eq!(err, "Conflicting definitions: individual paris, predicate dest_city");
+        assert!(!domain.sorts.contains_key("airport"));
+        let mut plain = Domain::new(HashSet::new(), HashMap::new(), HashMap::new());
+        plain.merge(weather).unwrap();
+        assert!(plain.preds1.contains_key("temperature"));
+    }
+
+    #[test]
+    fn test_sort_validators() {
+        let preds1 = HashMap::from([
+            ("dest_airport".to_string(), "iata_code".to_string()),
+            ("phone".to_string(), "phone_number".to_string()),
+        ]);
+        let sorts = HashMap::from([("iata_code".to_string(), HashSet::from(["TXL".to_string()]))]);
+        let mut domain = Domain::new(HashSet::new(), preds1, sorts);
+        domain.add_sort_validator("iata_code", |ind| ind.len() == 3 && ind.chars().all(|c| c.is_ascii_uppercase()));
+        domain.add_sort_validator("phone_number", |ind| ind.len() >= 6 && ind.chars().all(|c| c.is_ascii_digit()));
+        domain.add_alias("to", "dest_airport").unwrap();
+
+        assert!(domain.has_sort("CDG", "iata_code"));
+        assert!(domain.has_sort("TXL", "iata_code"));
+        assert!(!domain.has_sort("cdg", "iata_code"));
+        assert!(!domain.has_sort("CDG", "phone_number"));
+        assert!(domain.is_individual("0301234"));
+        assert!(!domain.is_individual("berlin"));
+        assert_eq!(domain.interpret_alias("to CDG").unwrap().to_string(), "dest_airport(CDG)");
+
+        let mut ctx = InterpretationContext::new(&domain);
+        ctx.qud = vec![Question::new("?x.phone(x)").unwrap()];
+        let grammar = SimpleGenGrammar::new();
+        let moves = grammar.interpret("0301234", &ctx).unwrap();
+        assert!(moves.contains(&"Answer(phone(0301234))".to_string()));
+        assert!(grammar.interpret("CDG", &ctx).unwrap().contains(&"Answer(CDG)".to_string())); // Not a phone number
+    }
+
+    // Test for enums
+    #[test]
+    fn test_speaker_enum() {
+        let usr = Speaker::new("USR").unwrap();
+        assert_eq!(usr.to_string(), "USR");
+        
+        let sys = Speaker::new("SYS").unwrap();  
+        assert_eq!(sys.to_string(), "SYS");
+        
+        assert!(Speaker::new("INVALID").is_none());
+    }
+    
+    #[test]
+    fn test_program_state_enum() {
+        let run = ProgramState::new("RUN").unwrap();
+        assert_eq!(run.to_string(), "RUN");
+        
+        let quit = ProgramState::new("QUIT").unwrap();
+        assert_eq!(quit.to_string(), "QUIT");
+        
+        assert!(ProgramState::new("INVALID").is_none());
+    }
+
+    #[test]
+    fn test_enum_parsing_and_serialization() {
+        assert_eq!("SYS".parse::<Speaker>(), Ok(Speaker::SYS));
+        assert_eq!("BOT".parse::<Speaker>(), Err("Unknown Speaker: BOT".to_string()));
+        assert_eq!(ProgramState::iter().collect::<Vec<_>>(), [ProgramState::RUN, ProgramState::QUIT]);
+        assert_eq!(Speaker::VARIANTS.len(), 2);
+
+        let json = serde_json::to_string(&Speaker::USR).unwrap();
+        assert_eq!(json, "\"USR\"");
+        assert_eq!(serde_json::from_str::<Speaker>(&json).unwrap(), Speaker::USR);
+        assert!(serde_json::from_str::<ProgramState>("\"PAUSE\"").is_err());
+    }
+    
+    // Tests for input handlers
+    #[test]
+    fn test_demo_input_handler() {
+        let inputs = vec!["hello".to_string(), "?expensive".to_string(), "quit".to_string()];
+        let mut handler = DemoInputHandler::new(inputs);
+        
+        assert!(handler.has_input());
+        assert_eq!(handler.read_line(), Some("hello".to_string()));
+        
+        assert!(handler.has_input());
+        assert_eq!(handler.read_line(), Some("?expensive".to_string()));
+        
+        assert!(handler.has_input());
+        assert_eq!(handler.read_line(), Some("quit".to_string()));
+        
+        assert!(!handler.has_input());
+        assert_eq!(handler.read_line(), None);
+    }
+    
+    #[test]
+    fn test_scripted_input_handler() {
+        let mut handler = ScriptedInputHandler::new()
+            .with_echo(false)
+            .with_strict(false)
+            .expect("Hello.")
+            .say("?x.price(x)")
+            .delay(Duration::from_millis(1))
+            .branch(vec![
+                ("travel", vec![ScriptStep::Say("train".to_string())]),
+                ("", vec![ScriptStep::Say("paris".to_string())]),
+            ])
+            .expect("Where do you want to go?")
+            .say("quit");
+        assert!(handler.has_input());
+
+        handler.observe_output("Hello.");
+        assert_eq!(handler.read_line(), Some("?x.price(x)".to_string()));
+        handler.observe_output("How do you want to travel?");
+        assert_eq!(handler.read_line(), Some("train".to_string()));
+        handler.observe_output("When do you want to leave?");
+        assert_eq!(handler.read_line(), Some("quit".to_string()));
+        assert_eq!(
+            handler.mismatches(),
+            ["expected system output \"Where do you want to go?\", got \"When do you want to leave?\""]
+        );
+        assert!(!handler.has_input());
+        assert_eq!(handler.read_line(), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "expected system output")]
+    fn test_scripted_input_handler_asserts_expectations() {
+        let mut controller = travel_controller(&[]);
+        controller.input_handler = Box::new(
+            ScriptedInputHandler::new().expect("Goodbye.").say("quit"),
+        );
+        controller.run();
+    }
+
+    // Integration test for IBISController
+    #[test]
+    fn test_ibis_controller_creation() {
+        // Create domain
+        let preds0 = HashSet::from(["expensive".to_string()]);
+        let preds1 = HashMap::from([("city".to_string(), "location".to_string())]);
+        let sorts = HashMap::from([("location".to_string(), HashSet::from(["paris".to_string()]))]);
+        let domain = Domain::new(preds0, preds1, sorts);
+        
+        // Create database
+        let database = TravelDB::new();
+        
+        // Create grammar
+        let grammar = SimpleGenGrammar::new();
+        
+        // Create demo input handler
+        let inputs = vec!["hello".to_string(), "quit".to_string()];
+        let input_handler = Box::new(DemoInputHandler::new(inputs));
+        
+        // Create controller
+        let controller = IBISController::with_input_handler(domain, database, grammar, input_handler);
+        
+        // Basic assertion that controller was created successfully
+        assert!(controller.mivs.program_state.get().is_none()); // Initially unset
+    }
+
+    /// Builds a small travel controller driven by the given inputs.
+    pub(crate) fn travel_controller(inputs: &[&str]) -> IBISController {
+        let inputs = inputs.iter().map(|s| s.to_string()).collect();
+        travel_session(Box::new(DemoInputHandler::new(inputs)))
+    }
+
+    /// Builds the travel controller reading user input from a handler.
+    pub(crate) fn travel_session(input_handler: Box<dyn InputHandler>) -> IBISController {
+        IBISController::with_input_handler(travel_domain(), travel_database(), travel_grammar(), input_handler)
+    }
+
+    /// Builds the domain of the travel controller.
+    pub(crate) fn travel_domain() -> Domain {
+        let preds1 = HashMap::from([
+            ("price".to_string(), "int".to_string()),
+            ("dest_city".to_string(), "city".to_string()),
+            ("depart_city".to_string(), "city".to_string()),
+            ("depart_day".to_string(), "day".to_string()),
+        ]);
+        let sorts = HashMap::from([
+            ("city".to_string(), HashSet::from(["paris".to_string(), "berlin".to_string()])),
+            ("day".to_string(), HashSet::from(["today".to_string()])),
+        ]);
+        let mut domain = Domain::new(HashSet::from(["return".to_string()]), preds1, sorts);
+        domain.add_plan(
+            Question::new("?x.price(x)").unwrap(),
+            vec![
+                "Findout('?x.dest_city(x)')".to_string(),
+                "Findout('?x.depart_city(x)')".to_string(),
+                "Findout('?x.depart_day(x)')".to_string(),
+                "Findout('?return()')".to_string(),
+                "If('?return()', ['Findout(?x.dest_city(x))'], [])".to_string(),
+                "ConsultDB('?x.price(x)')".to_string(),
+            ],
+        );
+        domain
+    }
+
+    /// Builds the database of the travel controller.
+    pub(crate) fn travel_database() -> TravelDB {
+        let mut database = TravelDB::new();
+        for price in ["345", "232"] {
+            database.add_entry(HashMap::from([
+                ("from".to_string(), "berlin".to_string()),
+                ("to".to_string(), "paris".to_string()),
+                ("day".to_string(), "today".to_string()),
+                ("price".to_string(), price.to_string()),
+            ]));
+        }
+        database
+    }
+
+    /// Builds the grammar of the travel controller.
+    pub(crate) fn travel_grammar() -> SimpleGenGrammar {
+        let mut grammar = SimpleGenGrammar::new();
+        grammar.add_unit("euros", "price");
+        grammar
+    }
+
+    #[test]
+    fn test_open_sorts() {
+        let mut controller = travel_controller(&["?x.price(x)", "springfield", "quit"]);
+        controller.domain_mut().add_open_sort("city");
+        controller.domain_mut().add_sort_validator("day", |ind| ind.ends_with("day"));
+        controller.domain_mut().add_open_sort("day");
+        assert!(controller.domain.has_sort("springfield", "city"));
+        assert!(!controller.domain.has_sort("paris", "day"));
+        assert!(controller.domain_mut().add_individual("tomorrow", "day").is_err());
+        assert!(controller.domain_mut().add_individual("paris", "day").is_err());
+
+        controller.run();
+        assert!(controller.is.com.contains(&"dest_city(springfield)".to_string()));
+        assert_eq!(controller.domain.inds.get("springfield"), Some(&"city".to_string()));
+        assert!(controller.domain.vocabulary().contains(&"springfield".to_string()));
+    }
+
+    #[test]
+    fn test_knowledge_base() {
+        let mut kb = FactBase::new();
+        kb.add_fact("visa_required(moscow)").unwrap();
+        kb.add_fact("-visa_required(paris)").unwrap();
+        assert!(kb.add_fact("(paris)").is_err());
+        let context = TSet::new();
+        assert_eq!(kb.holds(&Prop::new("visa_required(moscow)").unwrap(), &context), Some(true));
+        assert_eq!(kb.holds(&Prop::new("visa_required(paris)").unwrap(), &context), Some(false));
+        assert_eq!(kb.holds(&Prop::new("visa_required(berlin)").unwrap(), &context), None);
+
+        // An If over a static fact is settled by the knowledge base
+        let mut kb = FactBase::new();
+        kb.add_fact("daily_flights()").unwrap();
+        let mut controller = travel_controller(&["?x.price(x)"]).with_knowledge_base(kb);
+        controller.domain_mut().add_plan(
+            Question::new("?x.price(x)").unwrap(),
+            vec!["If('?daily_flights()', ['Findout(?x.depart_day(x))'], ['Findout(?x.dest_city(x))'])".to_string()],
+        );
+        controller.run();
+        assert_eq!(controller.mivs.output.get(), Some(&"Which day?".to_string()));
+        assert!(controller.is.com.is_empty() && controller.is.bel.is_empty());
+    }
+
+    #[test]
+    fn test_inference_rules() {
+        let rule = Rule::new(&["dest_city(london)", "citizenship(us)"], "need-visa()").unwrap();
+        assert_eq!(rule.to_string(), "dest_city(london), citizenship(us) => need-visa()");
+        assert!(Rule::new(&[], "need-visa()").is_err());
+
+        let mut domain = Domain::new(HashSet::new(), HashMap::new(), HashMap::new());
+        domain.add_rule(rule).unwrap();
+        domain.add_rule(Rule::new(&["need-visa()"], "-return()").unwrap()).unwrap();
+        let err = domain.add_rule(Rule::new(&["return()"], "citizenship(uk)").unwrap()).unwrap_err();
+        assert_eq!(err, "Rule return() => citizenship(uk) creates a cycle: return -> citizenship -> need-visa -> return");
+        assert!(domain.add_rule(Rule::new(&["-return()"], "return()").unwrap()).is_err());
+
+        let facts: TSet<Prop> = ["dest_city(london)", "citizenship(us)"].iter().map(|p| Prop::new(p).unwrap()).collect();
+        let derived: Vec<String> = domain.infer(&facts).iter().map(|(_, prop)| prop.to_string()).collect();
+        assert_eq!(derived, ["need-visa()", "-return()"]);
+    }
+
+    #[test]
+    fn test_rule_priorities() {
+        let mut domain = Domain::new(HashSet::new(), HashMap::new(), HashMap::new());
+        domain.add_rule(Rule::new(&["vip()"], "upgrade()").unwrap()).unwrap();
+        domain.add_rule(Rule::new(&["overbooked()"], "-upgrade()").unwrap().with_priority(5)).unwrap();
+        domain.add_rule(Rule::new(&["vip()"], "lounge()").unwrap().with_priority(1)).unwrap();
+        domain.add_rule(Rule::new(&["upgrade()"], "greet()").unwrap().with_phase(RulePhase::SELECTION)).unwrap();
+        let facts: TSet<Prop> = ["vip()", "overbooked()"].iter().map(|p| Prop::new(p).unwrap()).collect();
+
+        // The higher-priority rule blocks the contradicting conclusion
+        let derived = |domain: &Domain, phase| -> Vec<String> {
+            domain.infer_phase(&phase, &facts).iter().map(|(_, prop)| prop.to_string()).collect()
+        };
+        assert_eq!(derived(&domain, RulePhase::INTEGRATION), ["-upgrade()", "lounge()"]);
+        assert!(derived(&domain, RulePhase::SELECTION).is_empty());
+        domain.set_conflict_strategy(ConflictStrategy::FIRST_MATCH);
+        assert_eq!(derived(&domain, RulePhase::INTEGRATION), ["-upgrade()"]);
+
+        let json = domain.to_json();
+        assert_eq!(json["rules"][1], "overbooked() => -upgrade() [INTEGRATION, 5]");
+        assert_eq!(Domain::from_json(&json).unwrap().to_json(), json);
+
+        // Selection rules apply to what the update derived
+        let mut controller = travel_controller(&["vip", "quit"]);
+        controller.domain_mut().add_rule(Rule::new(&["dest_city(paris)"], "vip()").unwrap()).unwrap();
+        controller
+            .domain_mut()
+            .add_rule(Rule::new(&["vip()"], "priority()").unwrap().with_phase(RulePhase::SELECTION))
+            .unwrap();
+        controller.reset();
+        controller.is.com.add("dest_city(paris)".to_string()).unwrap();
+        controller.update();
+        assert!(controller.is.bel.contains(&"vip()".to_string()));
+        assert!(!controller.is.bel.contains(&"priority()".to_string()));
+        controller.select().unwrap();
+        assert!(controller.is.bel.contains(&"priority()".to_string()));
+    }
+
+    #[test]
+    fn test_domain_json() {
+        let mut domain = travel_controller(&[]).domain().clone();
+        domain.add_alias("going to", "dest_city").unwrap();
+        domain.add_open_sort("city");
+        domain.declare_functional("dest_city").unwrap();
+        domain.add_rule(Rule::new(&["dest_city(paris)", "depart_city(berlin)"], "-return()").unwrap()).unwrap();
+        let json = domain.to_json();
+
+        assert_eq!(json["preds1"]["dest_city"], "city");
+        assert_eq!(json["sorts"]["city"], serde_json::json!(["berlin", "paris"]));
+        assert_eq!(json["aliases"]["going to"], "dest_city");
+        assert_eq!(json["rules"][0], "dest_city(paris), depart_city(berlin) => -return()");
+        assert_eq!(json["dependencies"]["?x.price(x)"][0], "?x.dest_city(x)");
+        assert!(json["dependencies"]["?x.price(x)"].as_array().unwrap().contains(&"?return()".into()));
+
+        let loaded = Domain::from_json(&json).unwrap();
+        assert_eq!(loaded.to_json(), json);
+        assert!(loaded.is_functional("dest_city"));
+        assert!(Domain::from_json(&serde_json::json!({})).unwrap().vocabulary().is_empty());
+        assert!(Domain::from_json(&serde_json::json!({ "preds0": "return" })).is_err());
+        assert!(Domain::from_json(&serde_json::json!({ "aliases": { "to": "nowhere" } })).is_err());
+    }
+
+    #[test]
+    fn test_lint_plans() {
+        let mut domain = travel_controller(&[]).domain().clone();
+        assert!(domain.lint_plans().is_empty());
+
+        Arc::make_mut(&mut domain.preds1).insert("seat".to_string(), "seat".to_string());
+        Arc::make_mut(&mut domain.preds0).insert("meal".to_string());
+        domain.add_plan(
+            Question::new("?x.seat(x)").unwrap(),
+            vec![
+                "Findout('?x.seat(x)')".to_string(),
+                "If('?meal()', ['If(?meal(), [], [Findout(?x.dest_city(x))])'], [])".to_string(),
+            ],
+        );
+        let warnings = domain.lint_plans();
+        assert_eq!(
+            warnings,
+            [
+                PlanWarning::IrrelevantQuestion { plan: "?x.seat(x)".to_string(), question: "?x.seat(x)".to_string() },
+                PlanWarning::UnraisedCondition { plan: "?x.seat(x)".to_string(), question: "?meal()".to_string() },
+                PlanWarning::NoAnswer { plan: "?x.seat(x)".to_string() },
+                PlanWarning::UnreachableBranch {
+                    plan: "?x.seat(x)".to_string(),
+                    construct: "If('?meal()', [], [Findout(?x.dest_city(x))])".to_string(),
+                },
+            ]
+        );
+        assert_eq!(warnings[2].to_string(), "?x.seat(x): plan never responds or consults the database");
+
+        // Open sorts can be answered and conditions can be inferred
+        domain.add_open_sort("seat");
+        domain.add_rule(Rule::new(&["dest_city(paris)"], "meal()").unwrap()).unwrap();
+        assert_eq!(domain.lint_plans().len(), 2);
+    }
+
+    #[test]
+    fn test_ibis_dialogue_with_inference() {
+        let mut controller = travel_controller(&["?x.price(x)", "paris", "berlin", "today"]);
+        let rule = Rule::new(&["dest_city(paris)", "depart_city(berlin)"], "-return()").unwrap();
+        controller.domain_mut().add_rule(rule).unwrap();
+        controller.run();
+
+        // The derived belief skips the return question and selects the one-way branch
+        assert!(controller.is.bel.contains(&"-return()".to_string()));
+        assert!(!controller.is.com.contains(&"-return()".to_string()));
+        assert!(controller.is.com.contains(&"price(345)".to_string()));
+    }
+
+    #[test]
+    fn test_functional_predicates() {
+        let mut controller =
+            travel_controller(&["?x.price(x)", "berlin", "dest_city(paris)", "berlin", "today", "no"]);
+        controller.domain_mut().declare_functional("dest_city").unwrap();
+        assert!(controller.domain_mut().declare_functional("return").is_err());
+        assert!(controller.domain.is_functional("dest_city"));
+        controller.run();
+
+        assert!(controller.is.com.contains(&"dest_city(paris)".to_string()));
+        assert!(!controller.is.com.contains(&"dest_city(berlin)".to_string()));
+        assert!(controller.is.com.contains(&"depart_city(berlin)".to_string()));
+        assert!(controller.is.com.contains(&"price(345)".to_string()));
+
+        // Only the beliefs resting on the old value are dropped, and the
+        // query made with it is made again
+        let mut database = travel_database();
+        database.add_entry(HashMap::from([
+            ("from".to_string(), "berlin".to_string()),
+            ("to".to_string(), "berlin".to_string()),
+            ("price".to_string(), "99".to_string()),
+        ]));
+        let mut domain = travel_domain();
+        domain.declare_functional("dest_city").unwrap();
+        domain.add_plan(
+            Question::new("?x.price(x)").unwrap(),
+            vec![
+                "Findout('?x.dest_city(x)')".to_string(),
+                "Findout('?x.depart_city(x)')".to_string(),
+                "ConsultDB('?x.price(x)')".to_string(),
+                "Findout('?x.depart_day(x)')".to_string(),
+            ],
+        );
+        domain.add_rule(Rule::new(&["dest_city(paris)"], "popular()").unwrap()).unwrap();
+        domain.add_rule(Rule::new(&["depart_city(berlin)"], "hub()").unwrap()).unwrap();
+        let inputs = ["?x.price(x)", "paris", "berlin", "dest_city(berlin)"].map(String::from).to_vec();
+        let handler = Box::new(DemoInputHandler::new(inputs));
+        let mut controller = IBISController::with_input_handler(domain, Arc::new(database), travel_grammar(), handler);
+        controller.run();
+        assert!(!controller.is.bel.contains(&"price(345)".to_string()));
+        assert!(controller.is.bel.contains(&"price(99)".to_string()));
+        assert!(!controller.is.bel.contains(&"popular()".to_string()));
+        assert!(controller.is.bel.contains(&"hub()".to_string()));
+        assert_eq!(controller.is.plan.top().unwrap(), "Findout('?x.depart_day(x)')");
+    }
+
+    #[test]
+    fn test_task_status() {
+        let mut controller = travel_controller(&["paris"]);
+        controller.run();
+        assert!(controller.task_status().is_none()); // No issue yet
+
+        let mut controller = travel_controller(&["?x.price(x)", "paris", "berlin"]);
+        controller.run();
+        let status = controller.task_status().unwrap();
+        assert_eq!(status.issue, "?x.price(x)");
+        assert_eq!(status.resolved, ["?x.dest_city(x)", "?x.depart_city(x)"]);
+        assert_eq!(status.remaining, ["?x.depart_day(x)", "?return()"]);
+        assert_eq!(status.consulted, None);
+        assert_eq!(status.progress(), (2, 4));
+        assert!(!status.is_complete());
+
+        let mut controller = travel_controller(&["?x.price(x)", "paris", "berlin", "today", "no"]);
+        controller.run();
+        let status = controller.task_status().unwrap();
+        assert_eq!(status.progress(), (4, 4));
+        assert_eq!(status.consulted, Some(true));
+        assert!(status.is_complete());
+    }
+
+    #[test]
+    fn test_turn_errors() {
+        let mut controller = travel_controller(&["?x.price(x)", "paris"]);
+        controller.mivs.output = Value::new_type(|output: &String| !output.contains("city"));
+        let error = controller.try_run().unwrap_err();
+
+        // The session ends with the error instead of aborting the process
+        assert_eq!(error.turn, 2);
+        assert!(error.message.contains("does not match type constraint"));
+        assert_eq!(controller.errors(), [error]);
+        assert_eq!(controller.transcript().len(), 2);
+
+        controller.mivs.output = Value::new_type(|_: &String| true);
+        assert!(controller.try_run().is_ok());
+        assert!(controller.errors().is_empty());
+    }
+
+    #[test]
+    fn test_update_rollback() {
+        let mut controller = travel_controller(&["?x.price(x)", "paris", "berlin", "today", "yes"]);
+        controller.domain_mut().add_plan(
+            Question::new("?x.price(x)").unwrap(),
+            vec![
+                "Findout('?x.dest_city(x)')".to_string(),
+                "Findout('?x.depart_city(x)')".to_string(),
+                "Findout('?x.depart_day(x)')".to_string(),
+                "Findout('?return()')".to_string(),
+                "If('?return()', ['Lookup(?x.dest_city(x))'], [])".to_string(),
+                "ConsultDB('?x.price(x)')".to_string(),
+            ],
+        );
+        let reported = Rc::new(RefCell::new(Vec::new()));
+        let sink = reported.clone();
+        controller.on_error(move |error| sink.borrow_mut().push(error.clone()));
+        controller.run();
+
+        // The answer that selected the malformed branch is undone, not half applied
+        assert_eq!(controller.errors().len(), 1);
+        assert!(controller.errors()[0].message.contains("Could not parse plan construct"));
+        assert_eq!(*reported.borrow(), controller.errors());
+        assert!(!controller.is.com.contains(&"return()".to_string()));
+        assert_eq!(controller.is.plan.top().unwrap(), "Findout('?return()')");
+        assert_eq!(controller.is.qud.top().unwrap(), "?return()");
+
+        // So is what the domain learned from it
+        let mut controller = travel_controller(&["?x.price(x)", "springfield"]);
+        controller.domain_mut().add_open_sort("city");
+        controller.domain_mut().add_plan(
+            Question::new("?x.price(x)").unwrap(),
+            vec![
+                "Findout('?x.dest_city(x)')".to_string(),
+                "If('?dest_city(springfield)', ['Lookup(?x.dest_city(x))'], [])".to_string(),
+            ],
+        );
+        controller.run();
+        assert_eq!(controller.errors().len(), 1);
+        assert!(!controller.is.com.contains(&"dest_city(springfield)".to_string()));
+        assert_eq!(controller.domain.inds.get("springfield"), None);
+    }
+
+    #[test]
+    fn test_summarize() {
+        let mut controller = travel_controller(&["?x.price(x)", "paris", "berlin"]);
+        controller.run();
+        let summary = controller.summarize();
+        assert!(summary.resolved.is_empty());
+        assert_eq!(summary.outstanding, ["?x.price(x)", "?x.depart_day(x)", "?return()"]);
+        assert_eq!(summary.commitments, ["depart_city(berlin)", "dest_city(paris)"]);
+
+        let mut grammar = travel_grammar();
+        grammar.add_answer_form("price", "The trip costs {} euros");
+        let inputs = ["?x.price(x)", "paris", "berlin", "today", "no"];
+        let mut controller = travel_controller(&inputs).with_grammar(grammar);
+        controller.run();
+        let summary = controller.summarize();
+        assert_eq!(summary.resolved, [("?x.price(x)".to_string(), "price(345)".to_string())]);
+        assert!(summary.outstanding.is_empty());
+        assert!(summary.to_string().starts_with("RESOLVED:     [?x.price(x) -> price(345)]\n"));
+        assert_eq!(controller.summarize_text(), "The trip costs 345 euros.");
+    }
+
+    #[test]
+    fn test_history_retention() {
+        let inputs = ["?x.price(x)", "paris", "berlin", "today", "no"];
+        let mut controller = travel_controller(&inputs).with_history_retention(HistoryRetention::Summarize(2));
+        controller.run();
+        let last = controller.history().last().unwrap().turn_id;
+        assert!(controller.history().iter().all(|tagged| tagged.turn_id + 2 > last));
+        assert!(controller.transcript().iter().all(|(turn, _, _)| turn + 2 > last));
+        let compacted = controller.compacted_history();
+        assert_eq!(compacted.turns, last - 2);
+        assert_eq!(compacted.issues, ["?x.price(x)"]);
+        let mut full = travel_controller(&inputs);
+        full.run();
+        assert_eq!(compacted.moves + controller.history().len(), full.history().len());
+        // The issue raised in a compacted turn is still summarized
+        assert_eq!(controller.summarize().resolved[0].0, "?x.price(x)");
+
+        let mut controller = travel_controller(&inputs).with_history_retention(HistoryRetention::KeepLast(1));
+        controller.run();
+        assert!(controller.history().iter().all(|tagged| tagged.turn_id == last));
+        assert_eq!(controller.compacted_history(), &HistorySummary::default());
+    }
+
+    #[test]
+    fn test_memory_stats() {
+        let mut controller = travel_controller(&["?x.price(x)", "paris"]);
+        controller.run();
+        let stats = controller.memory_stats();
+        assert!(stats.com >= "dest_city(paris)".len() + std::mem::size_of::<String>());
+        assert!(stats.history > 0 && stats.transcript > 0);
+        assert_eq!(stats.plan, std::mem::size_of::<Plan>()); // The domain plan is shared
+        assert_eq!(stats.total(), stats.com + stats.bel + stats.qud + stats.agenda + stats.plan + stats.history + stats.transcript);
+        assert!(stats.to_string().ends_with(&format!("TOTAL:        {} B\n", stats.total())));
+
+        controller.reset();
+        assert_eq!(controller.memory_stats().history, 0);
+    }
+
+    #[test]
+    fn test_silence_reminders() {
+        let mut controller = travel_controller(&[]).with_silence_timeout(Duration::from_secs(30), 2);
+        let results = controller.run_corpus([ScriptedInputHandler::new()
+            .with_echo(false)
+            .say("?x.price(x)")
+            .expect("Which city?")
+            .silence()
+            .expect("Which city?")
+            .say("paris")
+            .silence()
+            .silence()
+            .silence()]);
+        let outputs: Vec<&str> = results[0]
+            .transcript
+            .iter()
+            .filter(|(_, speaker, _)| *speaker == Speaker::SYS)
+            .map(|(_, _, output)| output.as_str())
+            .collect();
+        assert_eq!(
+            outputs,
+            [
+                "Hello.", "Which city?", "Are you still there?", "Which city?", "Which city?",
+                "Are you still there?", "Which city?", "Are you still there?", "Which city?", "Goodbye.",
+            ]
+        );
+        assert!(results[0].passed());
+        assert_eq!(controller.mivs.program_state.get(), Some(&ProgramState::QUIT));
+        assert_eq!(controller.turn_holder(), None);
+
+        // Channel input times out while no one sends anything
+        let (input, mut handler) = ChannelInputHandler::channel();
+        assert_eq!(handler.read_line_timeout(Duration::from_millis(1)), InputEvent::Silence);
+        input.send("paris".to_string()).unwrap();
+        assert_eq!(handler.read_line_timeout(Duration::from_millis(1)), InputEvent::Line("paris".to_string()));
+        drop(input);
+        assert_eq!(handler.read_line_timeout(Duration::from_millis(1)), InputEvent::Closed);
+    }
+
+    #[test]
+    fn test_escalation() {
+        let escalations = Rc::new(std::cell::RefCell::new(Vec::new()));
+        let mut controller = travel_controller(&["?x.price(x)", "hello there", "hello there", "hello there", "paris"])
+            .with_escalation(5, 2);
+        let log = escalations.clone();
+        controller.on_escalate(move |escalation| log.borrow_mut().push(escalation.clone()));
+        controller.run();
+        assert_eq!(controller.mivs.output.get(), Some(&"Let me put you through to a colleague.".to_string()));
+        assert_eq!(escalations.borrow().len(), 1);
+        assert_eq!(escalations.borrow()[0].reason, "repeated_sem_neg");
+        assert_eq!(escalations.borrow()[0].state["is"]["qud"], serde_json::json!(["?x.dest_city(x)", "?x.price(x)"]));
+        assert_eq!(escalations.borrow()[0].state["is"]["issue"], "?x.price(x)");
+        assert!(!controller.is.com.contains(&"dest_city(paris)".to_string())); // Handed over before the answer
+
+        let mut controller = travel_controller(&["?x.price(x)", "train", "train", "paris"]).with_escalation(1, 5);
+        let log = escalations.clone();
+        controller.on_escalate(move |escalation| log.borrow_mut().push(escalation.clone()));
+        controller.run();
+        assert_eq!(escalations.borrow()[1].reason, "repeated_ask ?x.dest_city(x)");
+        assert!(Move::new("Escalate()").is_ok());
+    }
+
+    #[test]
+    fn test_machine_readable_forms() {
+        let prop = Prop::new("price(232)<300").unwrap();
+        assert_eq!(
+            serde_json::to_value(&prop).unwrap(),
+            serde_json::json!({
+                "pred": "price",
+                "ind": "232",
+                "positive": true,
+                "constraint": { "op": "<", "bound": "300" },
+                "aggregate": null,
+            })
+        );
+        assert_eq!(serde_json::to_value(Prop::new("-return()").unwrap()).unwrap()["positive"], false);
+
+        let question = serde_json::to_value(Question::new("?x.min:price(x)").unwrap()).unwrap();
+        assert_eq!(question["type"], "wh");
+        assert_eq!(question["variable"], "x");
+        assert_eq!(question["aggregate"], "min");
+        let question = serde_json::to_value(Question::new("?return()").unwrap()).unwrap();
+        assert_eq!(question["prop"]["pred"], "return");
+        let question = Question::new("?y.flight(y) ∧ price(y)<300 ∧ duration(y)<5").unwrap();
+        assert_eq!(question.to_canonical_string(), "?y.flight(y) ∧ duration(y)<5 ∧ price(y)<300");
+        let reparsed = Question::new(&question.to_canonical_string()).unwrap();
+        assert_eq!(reparsed.to_canonical_string(), question.to_canonical_string());
+
+        let answer = Move::new("Answer(price(232), [dest_city(paris), depart_city(london)])").unwrap();
+        assert_eq!(answer.to_canonical_string(), "Answer(price(232), [depart_city(london), dest_city(paris)])");
+        let conj = Move::new("Answer(dest_city(paris) ∧ depart_city(london))").unwrap();
+        assert_eq!(conj.to_canonical_string(), "Answer(depart_city(london) ∧ dest_city(paris))");
+        for s in ["Ask('?x.price(x)')", "icm:sem*neg", "Escalate()"] {
+            assert_eq!(Move::new(s).unwrap().to_canonical_string(), s);
+        }
+        let answer = serde_json::to_value(&answer).unwrap();
+        assert_eq!(answer["move"], "answer");
+        assert_eq!(answer["answer"]["type"], "prop");
+        assert_eq!(answer["answer"]["ind"], "232");
+        assert_eq!(answer["support"][1]["pred"], "depart_city");
+        assert_eq!(serde_json::to_value(Move::new("Answer(-paris)").unwrap()).unwrap()["answer"]["positive"], false);
+        let icm = serde_json::to_value(Move::new("icm:per*neg").unwrap()).unwrap();
+        assert_eq!(icm, serde_json::json!({ "move": "icm", "level": "per", "polarity": "neg", "content": null }));
+        let greet = serde_json::to_value(Move::new("Greet()").unwrap()).unwrap();
+        assert_eq!(greet, serde_json::json!({ "move": "greet" }));
+    }
+
+    #[test]
+    fn test_rng() {
+        let mut rng = Rng::new(42);
+        let sequence: Vec<u64> = (0..5).map(|_| rng.next_u64()).collect();
+        rng.reseed();
+        assert_eq!((0..5).map(|_| rng.next_u64()).collect::<Vec<_>>(), sequence);
+        assert_ne!(Rng::new(43).next_u64(), sequence[0]);
+
+        assert!((0..100).all(|_| rng.below(3) < 3));
+        assert_eq!(rng.below(0), 0);
+        assert!(rng.chance(1.0));
+        assert!(!rng.chance(0.0));
+        assert_eq!(rng.choose::<u8>(&[]), None);
+        assert!(["a", "b"].contains(rng.choose(&["a", "b"]).unwrap()));
+
+        let mut controller = travel_controller(&["quit"]).with_seed(7);
+        assert_eq!(controller.seed(), 7);
+        let first = controller.rng().next_u64();
+        controller.run(); // Each session restarts from the seed
+        assert_eq!(controller.rng().next_u64(), first);
+    }
+
+    #[test]
+    fn test_move_history() {
+        let mut controller = travel_controller(&["?x.price(x)", "paris"]);
+        let start = SystemTime::now();
+        controller.run();
+        let history: Vec<(String, Speaker, usize)> = controller
+            .history()
+            .iter()
+            .map(|tagged| (tagged.to_string(), tagged.speaker.clone(), tagged.turn_id))
+            .collect();
+        assert_eq!(history[0], ("Greet()".to_string(), Speaker::SYS, 1));
+        assert_eq!(history[1], ("Ask('?x.price(x)')".to_string(), Speaker::USR, 2));
+        assert_eq!(history[2], ("Ask('?x.dest_city(x)')".to_string(), Speaker::SYS, 3));
+        assert_eq!(history[3], ("Answer(dest_city(paris))".to_string(), Speaker::USR, 4));
+        assert!(controller.history().iter().all(|tagged| tagged.timestamp >= start && tagged.confidence == 1.0));
+        assert_eq!(controller.transcript()[3], (4, Speaker::USR, "paris".to_string())); // Same turn as its moves
+
+        let tagged = TaggedMove::new(Move::new("Answer(paris)").unwrap(), Speaker::USR, 1).with_confidence(0.4);
+        assert_eq!(tagged.confidence, 0.4);
+    }
+
+    #[test]
+    fn test_system_events() {
+        assert!(matches!(Move::new("Inform(cancelled(flight))"), Ok(Move::Inform(_))));
+
+        let mut grammar = travel_grammar();
+        grammar.add_answer_form("cancelled", "Your {} was cancelled");
+        let mut controller = travel_controller(&["?x.price(x)"]).with_grammar(grammar);
+        let events = controller.event_sender();
+        events.send(SystemEvent::Inform(Prop::new("cancelled(flight)").unwrap())).unwrap();
+        events.send(SystemEvent::Pause).unwrap();
+        controller.run();
+        assert!(controller.is_paused());
+        assert!(controller.transcript().is_empty()); // Paused before the first turn
+
+        controller.resume().unwrap();
+        assert!(!controller.is_paused());
+        assert_eq!(controller.transcript()[0], (1, Speaker::SYS, "Hello. Your flight was cancelled.".to_string()));
+        assert_eq!(controller.transcript()[1], (2, Speaker::USR, "?x.price(x)".to_string()));
+        assert!(controller.is.com.contains(&"cancelled(flight)".to_string()));
+    }
+
+    #[test]
+    fn test_notifications() {
+        let prop = |s: &str| Prop::new(s).unwrap();
+        let mut controller = travel_controller(&[]);
+        controller.reset();
+        controller.notify(Notification::new(prop("delayed(train)")));
+        controller.notify(Notification::new(prop("cancelled(flight)")).with_priority(2));
+        controller.notify(Notification::new(prop("open(lounge)")).expiring_in(Duration::ZERO));
+        controller.handle_system_events();
+        assert_eq!(controller.notifications().len(), 3);
+
+        // Held back while the plan collects answers, but expired ones are dropped
+        controller.is.plan.push("Findout('?x.dest_city(x)')".to_string()).unwrap();
+        controller.select().unwrap();
+        assert_eq!(controller.mivs.next_moves.iter().collect::<Vec<_>>(), ["Ask('?x.dest_city(x)')"]);
+        assert_eq!(controller.notifications().len(), 2);
+
+        controller.is.plan = Plan::default();
+        controller.is.agenda.clear();
+        controller.mivs.next_moves.clear();
+        controller.select().unwrap();
+        let next: Vec<&String> = controller.mivs.next_moves.iter().collect();
+        assert_eq!(next, ["Inform(cancelled(flight))", "Inform(delayed(train))"]);
+        assert!(controller.notifications().is_empty());
+    }
+
+    #[test]
+    fn test_unmatched_report() {
+        assert_eq!(edit_distance("paris", "parris"), 1);
+        assert_eq!(edit_distance("", "abc"), 3);
+
+        let mut controller = travel_controller(&["the prise", "?x.price(x)", "heading to paris", "parris please", "blah blah"]);
+        controller.run();
+        let inputs: Vec<&str> = controller.unmatched().iter().map(|u| u.input.as_str()).collect();
+        assert_eq!(inputs, ["the prise", "heading to paris", "parris please", "blah blah"]);
+        assert_eq!(controller.unmatched()[0].question, None);
+        assert_eq!(controller.unmatched()[1].question, Some("?x.dest_city(x)".to_string()));
+
+        let report = controller.unmatched_report();
+        let cluster = |pattern: Option<&str>| {
+            report.clusters.iter().find(|c| c.pattern.as_deref() == pattern).map(|c| c.inputs.clone())
+        };
+        assert_eq!(report.clusters[0].pattern, Some("paris".to_string())); // Largest cluster first
+        assert_eq!(cluster(Some("paris")).unwrap(), ["heading to paris", "parris please"]);
+        assert_eq!(cluster(Some("price")).unwrap(), ["the prise"]);
+        assert_eq!(cluster(None).unwrap(), ["blah blah"]);
+        assert_eq!(report.suggested_aliases, [("heading to".to_string(), "dest_city".to_string())]);
+        assert!(report.to_string().contains("add_alias(\"heading to\", \"dest_city\")"));
+    }
+
+    #[test]
+    fn test_agenda() {
+        assert!(matches!(AgendaItem::new("icm:sem*neg"), Ok(AgendaItem::Move(Move::ICM(_)))));
+        assert!(matches!(AgendaItem::new("Findout('?x.how(x)')"), Ok(AgendaItem::Construct(PlanItem::Findout(_)))));
+        assert!(AgendaItem::new("Wander()").is_err());
+
+        let mut controller = travel_controller(&[]);
+        controller.reset();
+        assert!(controller.is.agenda.push("Wander()".to_string()).is_err()); // Typed agenda
+        controller.push_plan(PlanItem::new("Findout('?x.dest_city(x)')").unwrap());
+        controller.push_agenda(AgendaItem::new("icm:acc*pos").unwrap());
+        controller.select().unwrap();
+
+        // Moves on the agenda do not block the plan from refilling it
+        let next: Vec<&String> = controller.mivs.next_moves.iter().collect();
+        assert_eq!(next, ["icm:acc*pos", "Ask('?x.dest_city(x)')"]);
+        let agenda: Vec<String> = controller.agenda().iter().map(|item| item.to_string()).collect();
+        assert_eq!(agenda, ["Findout('?x.dest_city(x)')"]);
+    }
+
+    #[test]
+    fn test_limits() {
+        let mut controller = travel_controller(&["?x.price(x)", "paris", "berlin", "today"])
+            .with_limits(Limits::new().with_max_turns(2));
+        controller.run();
+        assert_eq!(controller.limit_exceeded(), Some(&LimitExceeded::Turns(2)));
+        assert_eq!(controller.mivs.output.get(), Some(&"Goodbye.".to_string()));
+        assert!(controller.is.com.contains(&"dest_city(paris)".to_string()));
+        assert!(!controller.is.com.contains(&"depart_city(berlin)".to_string())); // Not interpreted
+
+        let mut controller = travel_controller(&["?x.price(x)"]).with_limits(Limits::new().with_max_qud_depth(1));
+        controller.run();
+        assert_eq!(controller.limit_exceeded(), Some(&LimitExceeded::QudDepth(1)));
+
+        let mut controller = travel_controller(&[]).with_limits(Limits::new().with_max_plan_depth(3));
+        let results = controller.run_corpus(vec![ScriptedInputHandler::new().say("?x.price(x)").say("paris")]);
+        assert_eq!(results[0].limit_exceeded, Some(LimitExceeded::PlanDepth(3)));
+        assert!(!results[0].completed);
+        assert_eq!(LimitExceeded::PlanDepth(3).to_string(), "max_plan_depth 3");
+
+        let results = controller.run_corpus(vec![ScriptedInputHandler::new().say("quit")]);
+        assert_eq!(results[0].limit_exceeded, None); // Cleared for each session
+    }
+
+    #[test]
+    fn test_lazy_plans() {
+        let domain = travel_controller(&[]).domain;
+        let que = Question::new("?x.price(x)").unwrap();
+        let mut plan = domain.get_plan(&que).unwrap();
+        assert!(Arc::ptr_eq(&plan.shared, &domain.plans["?x.price(x)"])); // Not copied
+        assert_eq!(plan.len(), 6);
+        assert_eq!(plan.pop().unwrap(), "Findout('?x.dest_city(x)')");
+        plan.push("Raise('?return()')".to_string()).unwrap();
+        assert_eq!(plan.top().unwrap(), "Raise('?return()')");
+        assert_eq!(plan.iter_rev().nth(1).unwrap(), "Findout('?x.depart_city(x)')");
+        assert_eq!(plan.iter().next().unwrap(), "ConsultDB('?x.price(x)')");
+        assert!(plan.to_string().starts_with("<[ Raise('?return()'), Findout('?x.depart_city(x)'),"));
+        assert_eq!(domain.get_plan(&que).unwrap().len(), 6); // The domain plan is untouched
+
+        // Constrained questions consult the database with the question asked
+        let plan = domain.get_plan(&Question::new("?x.price(x)<300").unwrap()).unwrap();
+        assert_eq!(plan.iter().next().unwrap(), "ConsultDB('?x.price(x)<300')");
+    }
+
+    #[test]
+    fn test_update_fixpoint() {
+        let build = |limits: Limits| {
+            let mut controller = travel_controller(&[]).with_limits(limits);
+            controller.domain_mut().add_rule(Rule::new(&["dest_city(paris)"], "-return()").unwrap()).unwrap();
+            controller.domain_mut().add_rule(Rule::new(&["price(345)"], "expensive()").unwrap()).unwrap();
+            controller.reset();
+            for prop in ["dest_city(paris)", "depart_city(berlin)", "depart_day(today)"] {
+                controller.is.com.add(prop.to_string()).unwrap();
             }
-            self.input();
-            self.interpret();
-            self.update();
-            self.print_state();
+            controller.is.plan = controller.domain.get_plan(&Question::new("?x.price(x)").unwrap()).unwrap();
+            controller.update();
+            controller
+        };
+
+        // The price found by the plan feeds the rules again in the same turn
+        let controller = build(Limits::new());
+        assert!(controller.is.plan.is_empty());
+        assert!(controller.is.bel.contains(&"expensive()".to_string()));
+
+        let controller = build(Limits::new().with_max_update_iterations(1));
+        assert!(controller.is.bel.contains(&"price(345)".to_string()));
+        assert!(!controller.is.bel.contains(&"expensive()".to_string()));
+    }
+
+    #[test]
+    fn test_run_corpus() {
+        let mut controller = travel_controller(&[]);
+        let log: Vec<String> = ["?x.price(x)", "paris", "berlin", "today", "no"]
+            .iter()
+            .map(|s| s.to_string())
+            .collect();
+        let failing = ScriptedInputHandler::new().expect("Goodbye.").say("quit");
+        let results = controller.run_corpus(vec![ScriptedInputHandler::from(log), failing]);
+
+        assert_eq!(results.len(), 2);
+        assert!(results[0].passed());
+        assert!(results[0].commitments.contains(&"price(345)".to_string()));
+        assert_eq!(results[0].transcript[0], (1, Speaker::SYS, "Hello.".to_string()));
+        assert_eq!(results[0].transcript[1], (2, Speaker::USR, "?x.price(x)".to_string()));
+        assert!(!results[1].passed());
+        assert_eq!(results[1].mismatches.len(), 1);
+        assert!(results[1].commitments.is_empty()); // Fresh state for each dialogue
+    }
+
+    #[test]
+    #[cfg(feature = "parallel")]
+    fn test_run_corpus_parallel() {
+        let scripts = |count: usize| -> Vec<ScriptedInputHandler> {
+            (0..count)
+                .map(|i| {
+                    let city = if i % 2 == 0 { "paris" } else { "berlin" };
+                    ScriptedInputHandler::from(vec!["?x.price(x)".to_string(), city.to_string(), "quit".to_string()])
+                })
+                .collect()
+        };
+        let results = IBISController::run_corpus_parallel(|| travel_controller(&[]), scripts(16));
+        let expected = travel_controller(&[]).run_corpus(scripts(16));
+        assert_eq!(results.len(), 16);
+        for (result, expected) in results.iter().zip(&expected) {
+            assert_eq!(result.transcript, expected.transcript); // In script order
+            assert_eq!(result.commitments, expected.commitments);
         }
+        assert!(results[1].commitments.contains(&"dest_city(berlin)".to_string()));
     }
 
-    fn print_state(&self) {
-        println!("+------------------------ - -  -");
-        self.mivs.print_mivs("| ");
-        println!("|");
-        self.is.print_is("| ");
-        println!("+------------------------ - -  -");
-        println!();
+    /// Grammar reading a few paraphrases with limited confidence.
+    struct ParaphraseGrammar;
+
+    impl Grammar for ParaphraseGrammar {
+        fn generate(&self, moves: &[String], _ctx: &InterpretationContext) -> String {
+            moves.join(" ")
+        }
+
+        fn interpret(&self, input: &str, _ctx: &InterpretationContext) -> Option<TSet<String>> {
+            let answer = match input {
+                "the french capital" | "paris" => "Answer(paris)",
+                _ => return None,
+            };
+            Some(TSet::from_iter([answer.to_string()]))
+        }
+
+        fn name(&self) -> &str {
+            "paraphrases"
+        }
+
+        fn parse(&self, input: &str, ctx: &InterpretationContext) -> Option<Parse> {
+            self.interpret(input, ctx).map(|moves| Parse { moves, confidence: 0.6, grammar: self.name().to_string() })
+        }
     }
-}
 
-/// Additional implementation to make IBISController usable
-impl IBISController {
-    /// Runs the dialogue manager (public interface)
-    pub fn run(&mut self) {
-        <Self as DialogueManager>::run(self);
+    #[test]
+    fn test_grammar_ensemble() {
+        let ensemble = GrammarEnsemble::new().with_grammar(travel_grammar()).with_grammar(ParaphraseGrammar);
+        let mut controller = travel_controller(&["?x.price(x)", "the french capital"]).with_grammar(ensemble);
+        controller.run();
+        assert!(controller.is.com.contains(&"dest_city(paris)".to_string()));
+        let grammars: Vec<&str> = controller.handled_by().iter().map(|(_, grammar)| grammar.as_str()).collect();
+        assert_eq!(grammars, ["SimpleGenGrammar", "paraphrases"]);
+        let answer = controller.history().iter().find(|tagged| tagged.mv.to_string() == "Answer(paris)").unwrap();
+        assert_eq!(answer.confidence, 0.6);
+        let (turn, _) = &controller.handled_by()[1];
+        let transcript = controller.dump()["transcript"].clone();
+        let entry = transcript.as_array().unwrap().iter().find(|entry| entry["turn"] == *turn).unwrap();
+        assert_eq!(entry["grammar"], "paraphrases");
+
+        // The most confident parse wins, unless an earlier one is confident enough
+        let domain = travel_controller(&[]).domain;
+        let ctx = InterpretationContext::new(&domain);
+        let ensemble = GrammarEnsemble::new().with_grammar(ParaphraseGrammar).with_grammar(travel_grammar());
+        assert_eq!(ensemble.parse("paris", &ctx).unwrap().grammar, "SimpleGenGrammar");
+        let ensemble = ensemble.with_policy(EnsemblePolicy::FirstConfident(0.5));
+        assert_eq!(ensemble.parse("paris", &ctx).unwrap().grammar, "paraphrases");
+        assert_eq!(ensemble.generate(&["Answer(paris)".to_string()], &ctx), "Answer(paris)");
+        assert!(ensemble.parse("gibberish words", &ctx).is_none());
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    #[test]
+    fn test_generation_hooks() {
+        assert!(glob_match("Answer(price(*))", "Answer(price(232))"));
+        assert!(glob_match("*(paris)*", "Answer(dest_city(paris))"));
+        assert!(glob_match("Greet()", "Greet()"));
+        assert!(!glob_match("Answer(price(*))", "Answer(dest_city(paris))"));
+        assert!(!glob_match("Ask(*)*x", "Ask(x)"));
+
+        let mut controller = travel_controller(&["?x.price(x)", "paris", "berlin", "today", "no"]);
+        controller.add_generation_hook("Greet()", |_, _| Some("Good morning!".to_string()));
+        controller.add_generation_hook("Answer(price(*)*", |mv, ibis| {
+            let Move::Answer(answer) = mv else {
+                return None;
+            };
+            Some(format!("{} for {} turns of talk.", answer.content, ibis.transcript().len()))
+        });
+        controller.add_generation_hook("Ask(*)", |_, ibis| ibis.field("missing").map(str::to_string));
+        controller.run();
+        let outputs: Vec<&str> = controller
+            .transcript()
+            .iter()
+            .filter(|(_, speaker, _)| *speaker == Speaker::SYS)
+            .map(|(_, _, output)| output.as_str())
+            .collect();
+        assert_eq!(outputs[0], "Good morning!");
+        assert_eq!(outputs[1], "Which city?");
+        assert_eq!(outputs.last().unwrap(), &"price(345) for 10 turns of talk.");
+    }
 
-    // Tests for core data structures
     #[test]
-    fn test_value_creation_and_operations() {
-        let allowed_values = HashSet::from(["a".to_string(), "b".to_string(), "c".to_string()]);
-        let mut value = Value::new_allowed(allowed_values);
-        
-        // Test setting valid value
-        assert!(value.set("a".to_string()).is_ok());
-        assert_eq!(value.get(), Some(&"a".to_string()));
-        
-        // Test setting invalid value
-        assert!(value.set("d".to_string()).is_err());
-        
-        // Test clearing value
-        value.clear();
-        assert_eq!(value.get(), None);
+    fn test_locale() {
+        let locale = Locale::new()
+            .with_format("price", ValueFormat::CURRENCY)
+            .with_format("distance", ValueFormat::NUMBER)
+            .with_format("depart_day", ValueFormat::DATE);
+        assert_eq!(locale.format("price", "232"), "$232");
+        assert_eq!(locale.format("distance", "1234567.5"), "1,234,567.5");
+        assert_eq!(locale.format("depart_day", "2024-05-17"), "05/17/2024");
+        assert_eq!(locale.format("depart_day", "today"), "today");
+        assert_eq!(locale.format("dest_city", "232"), "232");
+        let german = Locale::german().with_format("price", ValueFormat::CURRENCY).with_format("day", ValueFormat::DATE);
+        assert_eq!(german.format("price", "1232.5"), "1.232,5 €");
+        assert_eq!(german.format("day", "2024-05-17"), "17.05.2024");
+        let swedish = Locale::swedish().with_format("price", ValueFormat::CURRENCY);
+        assert_eq!(swedish.format("price", "232"), "232 kr");
+        let euro = Locale::new().with_currency("€", true).with_format("price", ValueFormat::CURRENCY);
+        assert_eq!(euro.format("price", "232"), "€232");
+        assert!(Prop::new("depart_day(2024-05-17)").is_ok());
+
+        let mut grammar = travel_grammar();
+        grammar.add_answer_form("price", "The trip costs {}");
+        let inputs = ["?x.price(x)", "paris", "berlin", "today", "no"];
+        let mut controller = travel_controller(&inputs).with_grammar(grammar).with_locale(swedish);
+        controller.run();
+        let (_, _, output) = controller.transcript().last().unwrap();
+        assert_eq!(output, "The trip costs 345 kr.");
+        assert!(!Atomic::is_date("2024-13-01") && !Atomic::is_date("2023-02-29") && !Atomic::is_date("2024-04-31"));
+        assert!(Atomic::is_date("2024-02-29"));
+
+        // Phrases generated without a locale are not reused with one, nor the other way round
+        let moves = ["Answer(price(232))".to_string()];
+        let domain = travel_domain();
+        let german = Locale::german().with_format("price", ValueFormat::CURRENCY);
+        let mut localized = InterpretationContext::new(&domain);
+        localized.locale = Some(&german);
+        let mut grammar = travel_grammar();
+        grammar.add_answer_form("price", "It costs {}");
+        assert_eq!(grammar.generate(&moves, &InterpretationContext::new(&domain)), "It costs 232.");
+        assert_eq!(grammar.generate(&moves, &localized), "It costs 232 €.");
+        assert_eq!(grammar.generate(&moves, &InterpretationContext::new(&domain)), "It costs 232.");
     }
-    
+
     #[test]
-    fn test_value_with_type_constraint() {
-        let mut value = Value::new_type(|s: &String| s.len() > 2);
-        
-        assert!(value.set("hello".to_string()).is_ok());
-        assert!(value.set("hi".to_string()).is_err());
-        assert_eq!(value.get(), Some(&"hello".to_string()));
+    fn test_tokenizers() {
+        assert_eq!(WhitespaceTokenizer.tokenize("  Paris,  please! "), ["Paris,", "please!"]);
+        assert_eq!(PunctuationTokenizer.tokenize("Paris, please!"), ["Paris", "please"]);
+        assert_eq!(PunctuationTokenizer.tokenize("\"paris?\" ... ?x.price(x)"), ["paris", "?x.price(x)"]);
+        let lowercase = |text: &str| text.split_whitespace().map(str::to_lowercase).collect::<Vec<_>>();
+        assert_eq!(lowercase.tokenize("PARIS Today"), ["paris", "today"]);
+
+        // Every grammar sees the tokenized input
+        let mut controller = travel_controller(&["?x.price(x)", "paris!"]).with_tokenizer(PunctuationTokenizer);
+        controller.run();
+        assert!(controller.is.com.contains(&"dest_city(paris)".to_string()));
+        let mut controller = travel_controller(&["?x.price(x)", "paris!"]);
+        controller.run();
+        assert!(!controller.is.com.contains(&"dest_city(paris)".to_string()));
     }
-    
+
     #[test]
-    fn test_stack_operations() {
-        let mut stack = Stack::new();
-        
-        // Test empty stack
-        assert!(stack.top().is_err());
-        assert!(stack.pop().is_err());
-        assert_eq!(stack.len(), 0);
-        
-        // Test push and basic operations
-        assert!(stack.push("item1".to_string()).is_ok());
-        assert!(stack.push("item2".to_string()).is_ok());
-        assert_eq!(stack.len(), 2);
-        
-        // Test top and pop
-        assert_eq!(stack.top().unwrap(), &"item2".to_string());
-        assert_eq!(stack.pop().unwrap(), "item2".to_string());
-        assert_eq!(stack.len(), 1);
-        
-        // Test clear
-        stack.clear();
-        assert_eq!(stack.len(), 0);
+    #[cfg(feature = "unicode")]
+    fn test_unicode_tokenizer() {
+        assert_eq!(UnicodeTokenizer.tokenize("«Paris», s'il vous plaît!"), ["Paris", "s'il", "vous", "plaît"]);
     }
-    
+
     #[test]
-    fn test_stack_with_type_constraint() {
-        let mut stack = Stack::with_type(|s: &String| s.starts_with("valid_"));
-        
-        assert!(stack.push("valid_item".to_string()).is_ok());
-        assert!(stack.push("invalid_item".to_string()).is_err());
-        assert_eq!(stack.len(), 1);
+    fn test_pii_redactor() {
+        let redactor = PiiRedactor::new();
+        assert_eq!(redactor.filter("mail me at jane.doe@example.com, thanks"), "mail me at [email], thanks");
+        assert_eq!(redactor.filter("call +46 70-123 45 67 today"), "call [number] today");
+        assert_eq!(redactor.filter("card 4111111111111111."), "card [number].");
+        assert_eq!(redactor.filter("price(232) at 10:30 @home"), "price(232) at 10:30 @home");
+        assert_eq!(PiiRedactor::new().with_min_digits(3).filter("room 101"), "room [number]");
     }
-    
+
     #[test]
-    fn test_stackset_operations() {
-        let mut stackset = StackSet::new();
-        
-        // Test push with uniqueness
-        assert!(stackset.push("item1".to_string()).is_ok());
-        assert!(stackset.push("item2".to_string()).is_ok());
-        assert!(stackset.push("item1".to_string()).is_ok()); // Should move to top
-        
-        assert!(stackset.contains(&"item1".to_string()));
-        assert!(stackset.contains(&"item2".to_string()));
-        assert!(!stackset.contains(&"item3".to_string()));
+    fn test_text_filters() {
+        let inputs = Rc::new(std::cell::RefCell::new(Vec::new()));
+        let log = inputs.clone();
+        let mut controller = travel_controller(&[])
+            .with_input_filter(|text: &str| text.to_lowercase())
+            .with_input_filter(PiiRedactor::new())
+            .with_input_filter(move |text: &str| {
+                log.borrow_mut().push(text.to_string());
+                text.to_string()
+            })
+            .with_output_filter(|text: &str| text.replace("Hello", "Welcome"));
+        let script = ScriptedInputHandler::new()
+            .expect("Welcome.")
+            .say("?X.PRICE(X)")
+            .say("PARIS")
+            .say("call me on 0701234567");
+        let results = controller.run_corpus(vec![script]);
+
+        assert!(results[0].passed(), "{:?}", results[0].mismatches);
+        assert!(results[0].commitments.contains(&"dest_city(paris)".to_string()));
+        assert_eq!(inputs.borrow().last(), Some(&"call me on [number]".to_string()));
     }
-    
+
     #[test]
-    fn test_tset_operations() {
-        let mut tset = TSet::new();
-        
-        assert!(tset.add("item1".to_string()).is_ok());
-        assert!(tset.add("item2".to_string()).is_ok());
-        assert!(tset.add("item1".to_string()).is_ok()); // Duplicate should be fine
-        
-        assert_eq!(tset.len(), 2); // Sets contain unique elements
-        assert!(tset.contains(&"item1".to_string()));
-        assert!(tset.contains(&"item2".to_string()));
-        
-        tset.clear();
-        assert_eq!(tset.len(), 0);
+    fn test_user_preferences() {
+        let dir = std::env::temp_dir().join(format!("isu-profiles-{}", std::process::id()));
+        let session = |inputs: &[&str], user: &str| {
+            let mut grammar = travel_grammar();
+            grammar.add_yn_form("depart_city", "From {} as usual?");
+            let mut controller =
+                travel_controller(inputs).with_profile_store(FileProfileStore::new(&dir)).with_grammar(grammar);
+            controller.domain_mut().declare_functional("depart_city").unwrap();
+            controller.set_user(user).unwrap();
+            controller.run();
+            controller
+        };
+        let said = |controller: &IBISController, text: &str| {
+            controller.transcript().iter().any(|(_, speaker, line)| *speaker == Speaker::SYS && line.contains(text))
+        };
+
+        session(&["?x.price(x)", "paris", "berlin", "today", "no"], "ann");
+        let saved: Vec<String> = FileProfileStore::new(&dir).load("ann").iter().map(|p| p.to_string()).collect();
+        assert_eq!(saved, ["depart_city(berlin)"]);
+
+        // The saved value is offered and only used once confirmed
+        let controller = session(&["?x.price(x)", "paris", "yes", "today", "no"], "ann");
+        assert!(said(&controller, "From berlin as usual?"));
+        assert!(controller.is.bel.contains(&"price(345)".to_string()));
+        let controller = session(&["?x.price(x)", "paris", "no", "berlin"], "ann");
+        assert!(said(&controller, "From berlin as usual?") && said(&controller, "Which city?"));
+        assert!(controller.is.com.contains(&"-depart_city(berlin)".to_string()));
+
+        let controller = session(&["?x.price(x)", "paris"], "bob");
+        assert!(!said(&controller, "as usual"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_check_golden() {
+        let path = std::env::temp_dir().join(format!("isu-golden-{}.txt", std::process::id()));
+        assert!(check_golden(&path, "S> Hello.\n").is_err()); // Missing golden file
+
+        std::fs::write(&path, "S> Hello.\nCOM: \n").unwrap();
+        assert!(check_golden(&path, "S> Hello.\nCOM: \n").is_ok());
+        let err = check_golden(&path, "S> Hi.\nCOM: \n").unwrap_err();
+        assert!(err.ends_with("- S> Hello.\n+ S> Hi."));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_ibis_dialogue_answers_price() {
+        let mut controller = travel_controller(&["?x.price(x)", "paris", "berlin", "today", "no"]);
+        controller.run();
+
+        assert!(controller.is.com.contains(&"dest_city(paris)".to_string()));
+        assert!(controller.is.com.contains(&"-return()".to_string()));
+        assert!(controller.is.com.contains(&"price(345)".to_string()));
+        assert_eq!(
+            controller.mivs.output.get(),
+            Some(&"Answer(price(345), [depart_city(berlin), depart_day(today), dest_city(paris)]).".to_string())
+        );
+        assert_eq!(controller.is.plan.len(), 0);
+        assert_eq!(controller.is.qud.len(), 0);
+    }
+
+    #[test]
+    fn test_ibis_dialogue_with_constraint() {
+        let mut controller =
+            travel_controller(&["?x.price(x)", "under 300 euros", "paris", "berlin", "today", "no"]);
+        controller.run();
+
+        assert!(controller.is.com.contains(&"price()<300".to_string()));
+        assert!(controller.is.com.contains(&"price(232)<300".to_string()));
+        assert_eq!(controller.mivs.output.get(), Some(&"The cheapest is 232.".to_string()));
+    }
+
+    #[test]
+    fn test_ibis_dialogue_with_aggregate() {
+        let mut grammar = travel_grammar();
+        grammar.add_aggregate_phrase("cheapest", Aggregate::Min, "price");
+        grammar.set_aggregate_form(Aggregate::Min, "The cheapest is {}");
+        let inputs = ["what is the cheapest flight", "paris", "berlin", "today", "no"];
+        let mut controller = travel_controller(&inputs).with_grammar(grammar);
+        controller.run();
+
+        assert!(controller.is.com.contains(&"min:price(232)".to_string()));
+        assert_eq!(controller.mivs.output.get(), Some(&"The cheapest is 232.".to_string()));
+        assert_eq!(controller.is.qud.len(), 0);
+    }
+
+    #[test]
+    fn test_ibis_dialogue_with_alias() {
+        let mut controller = travel_controller(&["?x.price(x)", "going to paris", "berlin", "today", "no"]);
+        controller.domain_mut().add_alias("going to", "dest_city").unwrap();
+        controller.run();
+
+        assert!(controller.is.com.contains(&"dest_city(paris)".to_string()));
+        assert!(controller.is.com.contains(&"price(345)".to_string()));
+    }
+
+    #[test]
+    fn test_short_answers_bound_to_qud() {
+        let mut controller = travel_controller(&[]);
+        controller.mivs.input.set("today".to_string()).unwrap();
+        controller.interpret();
+        assert!(controller.mivs.latest_move_strings().contains(&"Answer(today)".to_string()));
+
+        controller.is.qud.push("?x.depart_day(x)".to_string()).unwrap();
+        controller.interpret();
+        assert!(controller.mivs.latest_move_strings().contains(&"Answer(depart_day(today))".to_string()));
+
+        // Answers of the wrong sort are left for the update rules to reject
+        controller.mivs.input.set("paris".to_string()).unwrap();
+        controller.interpret();
+        assert!(controller.mivs.latest_move_strings().contains(&"Answer(paris)".to_string()));
+
+        controller.is.qud.push("?return()".to_string()).unwrap();
+        controller.mivs.input.set("no".to_string()).unwrap();
+        controller.interpret();
+        assert!(controller.mivs.latest_move_strings().contains(&"Answer(-return())".to_string()));
+    }
+
+    #[test]
+    fn test_ibis_dialogue_accommodates_findout() {
+        // The day is given while the destination is asked for
+        let mut controller = travel_controller(&["?x.price(x)", "today", "paris", "berlin", "no"]);
+        controller.run();
+
+        assert!(controller.is.com.contains(&"depart_day(today)".to_string()));
+        assert!(controller.is.com.contains(&"price(345)".to_string()));
+        assert_eq!(controller.is.plan.len(), 0);
+    }
+
+    #[test]
+    fn test_mivs_events() {
+        let events = Rc::new(std::cell::RefCell::new(Vec::new()));
+        let mut controller = travel_controller(&["quit"]);
+        let log = events.clone();
+        controller.on_mivs_event(move |event| log.borrow_mut().push(event.clone()));
+        controller.run();
+
+        assert_eq!(
+            *events.borrow(),
+            [
+                MivsEvent::ProgramState(Some(ProgramState::RUN)),
+                MivsEvent::TurnHolder(Some(Speaker::SYS)),
+                MivsEvent::Output(Some("Hello.".to_string())),
+                MivsEvent::Turn(Some(1)),
+                MivsEvent::LatestSpeaker(Some(Speaker::SYS)),
+                MivsEvent::TurnHolder(Some(Speaker::USR)),
+                MivsEvent::Turn(Some(2)),
+                MivsEvent::LatestSpeaker(Some(Speaker::USR)),
+                MivsEvent::TurnHolder(Some(Speaker::SYS)),
+                MivsEvent::ProgramState(Some(ProgramState::QUIT)),
+                MivsEvent::TurnHolder(None),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_turn_taking() {
+        let holders = Rc::new(std::cell::RefCell::new(Vec::new()));
+        let mut controller = travel_controller(&["?x.price(x)", "paris"]);
+        assert_eq!(controller.turn_holder(), None);
+        let log = holders.clone();
+        controller.on_mivs_event(move |event| {
+            if let MivsEvent::TurnHolder(holder) = event {
+                log.borrow_mut().push(holder.clone());
+            }
+        });
+        controller.event_sender().send(SystemEvent::Pause).unwrap();
+        controller.run();
+        assert_eq!(controller.turn_holder(), Some(&Speaker::SYS)); // The system opens the dialogue
+        assert!(!controller.is_user_turn());
+
+        // The turn alternates until the input is closed
+        controller.resume().unwrap();
+        assert_eq!(controller.turn_holder(), None);
+        assert!(!controller.is_user_turn());
+        let (sys, usr) = (Some(Speaker::SYS), Some(Speaker::USR));
+        let expected = [sys.clone(), usr.clone(), sys.clone(), usr.clone(), sys, usr, None];
+        assert_eq!(*holders.borrow(), expected);
+
+        // The holder is kept in dumps
+        let mut controller = travel_controller(&[]);
+        controller.mivs.init_mivs();
+        controller.mivs.turn_holder.set(Speaker::USR).unwrap();
+        let dump = controller.dump();
+        assert_eq!(dump["mivs"]["turn_holder"], "USR");
+        let mut restored = travel_controller(&[]);
+        restored.restore(&dump).unwrap();
+        assert!(restored.is_user_turn());
+    }
+
+    #[test]
+    fn test_user_identity() {
+        let events = Rc::new(std::cell::RefCell::new(Vec::new()));
+        let mut controller = travel_controller(&[]);
+        controller.domain_mut().add_rule(Rule::new(&["user(ann)"], "vip()").unwrap()).unwrap();
+        let log = events.clone();
+        controller.on_mivs_event(move |event| log.borrow_mut().push(event.clone()));
+        assert!(controller.set_user("").is_err());
+        controller.set_user("ann").unwrap();
+        assert_eq!(events.borrow()[0], MivsEvent::User(Some("ann".to_string())));
+
+        // The identity outlives the session and is known to the rules
+        let results = controller.run_corpus(vec![ScriptedInputHandler::new().say("?x.price(x)")]);
+        assert_eq!(controller.user(), Some("ann"));
+        assert_eq!(results[0].user, Some("ann".to_string()));
+        assert!(controller.is.bel.contains(&"vip()".to_string()));
+
+        controller.clear_user();
+        assert_eq!(controller.user(), None);
+    }
+
+    /// A move kind booking a ticket to a city.
+    struct BookTicket;
+
+    impl CustomMove for BookTicket {
+        fn name(&self) -> &str {
+            "BookTicket"
+        }
+
+        fn generate(&self, args: &[String]) -> String {
+            format!("Your ticket to {} is booked", args[0])
+        }
+
+        fn interpret(&self, input: &str) -> Option<Vec<String>> {
+            input.strip_prefix("book ").map(|rest| rest.split_whitespace().map(String::from).collect())
+        }
+
+        fn integrate(&self, args: &[String], speaker: &Speaker) -> Result<Vec<MoveEffect>, String> {
+            let [city] = args else {
+                return Err(format!("BookTicket takes one city, got {}", args.len()));
+            };
+            if *speaker == Speaker::SYS {
+                return Ok(Vec::new());
+            }
+            Ok(vec![
+                MoveEffect::Commit(Prop::new(&format!("dest_city({})", city))?),
+                MoveEffect::Reply(Move::Custom(Custom::new("BookTicket", &[city])?)),
+            ])
+        }
     }
-    
+
     #[test]
-    fn test_tset_with_type_constraint() {
-        let mut tset = TSet::with_type(|s: &String| s.len() <= 5);
-        
-        assert!(tset.add("short".to_string()).is_ok());
-        assert!(tset.add("toolongstring".to_string()).is_err());
-        assert_eq!(tset.len(), 1);
+    fn test_custom_moves() {
+        assert!(matches!(Move::new("custom:BookTicket(paris)"), Ok(Move::Custom(_))));
+        assert!(Move::new("BookTicket(paris)").is_err());
+        assert!(Custom::new("book", &[]).is_err());
+        let mv = Move::new("custom:BookTicket('paris', today)").unwrap();
+        assert_eq!(mv.to_string(), "custom:BookTicket(paris, today)");
+        let json = serde_json::json!({ "move": "custom", "name": "BookTicket", "args": ["paris", "today"] });
+        assert_eq!(serde_json::to_value(&mv).unwrap(), json);
+
+        let mut controller = travel_controller(&["book paris", "book berlin today"]);
+        assert!(mv.typecheck(&controller.domain).is_err());
+        controller.domain_mut().add_move(BookTicket).unwrap();
+        assert!(controller.domain_mut().add_move(BookTicket).is_err());
+        assert!(mv.typecheck(&controller.domain).is_ok());
+        controller.run();
+
+        // The move kind interprets, integrates and generates its moves; a
+        // failing update rule is rolled back
+        assert_eq!(controller.transcript()[2].2, "Your ticket to paris is booked.");
+        assert!(controller.is.com.contains(&"dest_city(paris)".to_string()));
+        assert_eq!(controller.errors().len(), 1);
+        assert!(controller.errors()[0].message.contains("BookTicket takes one city"));
     }
-    
-    // Tests for semantic types
+
     #[test]
-    fn test_atomic_creation() {
-        // Valid atomic values
-        assert!(Atomic::new("hello").is_ok());
-        assert!(Atomic::new("test_atom").is_ok());
-        assert!(Atomic::new("atom-with-dash").is_ok());
-        assert!(Atomic::new("atom+with+plus").is_ok());
-        assert!(Atomic::new("atom:with:colon").is_ok());
-        
-        // Invalid atomic values
-        assert!(Atomic::new("").is_err()); // Empty
-        assert!(Atomic::new("yes").is_err()); // Reserved word
-        assert!(Atomic::new("no").is_err()); // Reserved word
-        assert!(Atomic::new("123invalid").is_err()); // Starts with number
-        assert!(Atomic::new("invalid@char").is_err()); // Invalid character
+    fn test_state_fields() {
+        let mut controller = travel_controller(&[]);
+        controller.add_field("mood", &["calm", "angry"]).unwrap();
+        assert!(controller.add_field("mood", &["calm"]).is_err());
+        assert!(controller.add_field("auth", &[]).is_err());
+        assert!(controller.set_field("mood", "sad").is_err());
+        assert!(controller.set_field("weather", "sunny").is_err());
+        controller.domain_mut().add_rule(Rule::new(&["mood(angry)"], "apologize()").unwrap()).unwrap();
+
+        // Hosts can change a field in the middle of a session
+        controller.event_sender().send(SystemEvent::SetField("mood".to_string(), "angry".to_string())).unwrap();
+        controller.run_corpus(vec![ScriptedInputHandler::new().say("?x.price(x)")]);
+        assert_eq!(controller.field("mood"), Some("angry"));
+        assert!(controller.is.bel.contains(&"apologize()".to_string()));
+        assert!(controller.is.format_is("").contains("MOOD:           <angry>\n"));
+        assert_eq!(controller.fields_json(), serde_json::json!({ "mood": "angry" }));
+
+        // Each session starts from the first value
+        controller.run_corpus(vec![ScriptedInputHandler::new()]);
+        assert_eq!(controller.field("mood"), Some("calm"));
     }
-    
+
     #[test]
-    fn test_ind_creation_and_display() {
-        let ind = Ind::new("paris").unwrap();
-        assert_eq!(ind.to_string(), "paris");
-        
-        assert!(Ind::new("").is_err());
-        assert!(Ind::new("invalid@").is_err());
+    fn test_dump() {
+        let mut controller = travel_controller(&["?x.price(x)", "paris"]);
+        controller.domain_mut().set_name("travel");
+        controller.domain_mut().set_version("1.2");
+        controller.add_field("mood", &["calm", "angry"]).unwrap();
+        controller.run();
+        let dump = controller.dump();
+        assert_eq!(dump["domain"]["name"], "travel");
+        assert_eq!(dump["domain"]["version"], "1.2");
+        assert_eq!(dump["turns"], 2);
+        assert_eq!(dump["turn"], controller.mivs.turn.get().copied().unwrap());
+        assert_eq!(dump["mivs"]["output"], "Which city?");
+        assert_eq!(dump["mivs"]["program_state"], "QUIT");
+        assert_eq!(dump["mivs"]["latest_speaker"], "SYS");
+        assert_eq!(dump["is"]["com"], serde_json::json!(["dest_city(paris)"]));
+        assert_eq!(dump["is"]["qud"][0], "?x.depart_city(x)");
+        assert_eq!(dump["is"]["plan"][0], "Findout('?x.depart_city(x)')");
+        assert_eq!(dump["is"]["issue"], "?x.price(x)");
+        assert_eq!(dump["is"]["fields"], controller.fields_json());
+        assert_eq!(Domain::from_json(&controller.domain.to_json()).unwrap().version(), Some("1.2"));
     }
-    
+
     #[test]
-    fn test_pred0_creation_and_display() {
-        let pred = Pred0::new("expensive").unwrap();
-        assert_eq!(pred.to_string(), "expensive");
-        
-        assert!(Pred0::new("").is_err());
-        assert!(Pred0::new("yes").is_err());
+    fn test_domain_compatibility() {
+        let mut controller = travel_controller(&["?x.price(x)", "paris"]);
+        controller.domain_mut().set_name("travel");
+        controller.domain_mut().set_version("1.2");
+        controller.run();
+        let dump = controller.dump();
+        assert_eq!(dump["domain"]["hash"], controller.domain.content_hash());
+        assert_eq!(travel_controller(&[]).domain.content_hash().len(), 16);
+
+        // The same domain restores everything
+        let mut restored = travel_controller(&[]).with_domain_mismatch(DomainMismatch::Refuse);
+        restored.domain_mut().set_name("travel");
+        restored.domain_mut().set_version("1.2");
+        assert!(restored.restore(&dump).unwrap().is_empty());
+        assert!(restored.is.com.contains(&"dest_city(paris)".to_string()));
+
+        // Individuals learned during a dialogue do not change the hash
+        let hash = restored.domain.content_hash();
+        restored.domain_mut().add_open_sort("city");
+        let opened = restored.domain.content_hash();
+        assert_ne!(opened, hash);
+        restored.learn_individual(&Prop::new("dest_city(springfield)").unwrap());
+        assert_eq!(restored.domain.inds.get("springfield"), Some(&"city".to_string()));
+        assert_eq!(restored.domain.content_hash(), opened);
+
+        // A domain without paris refuses the state, or migrates it without the commitment
+        let mut restored = travel_controller(&[]).with_domain_mismatch(DomainMismatch::Refuse);
+        restored.domain_mut().set_name("travel");
+        restored.domain_mut().set_version("1.3");
+        restored.domain_mut().sorts.get_mut("city").unwrap().remove("paris");
+        restored.domain_mut().inds.remove("paris");
+        let error = restored.restore(&dump).unwrap_err();
+        assert!(error.contains("saved with domain travel 1.2"), "{}", error);
+        assert!(error.contains("current domain travel 1.3"), "{}", error);
+        let mut restored = restored.with_domain_mismatch(DomainMismatch::Migrate);
+        assert_eq!(restored.restore(&dump).unwrap(), ["dest_city(paris)"]);
+        assert!(!restored.is.com.contains(&"dest_city(paris)".to_string()));
+        assert_eq!(restored.is.issue.get().unwrap(), "?x.price(x)");
+        assert!(restored.domain.admits(&Prop::new("price(345)").unwrap()));
+        assert!(!restored.domain.admits(&Prop::new("colour(red)").unwrap()));
     }
-    
+
     #[test]
-    fn test_pred1_creation_and_apply() {
-        let pred1 = Pred1::new("city").unwrap();
-        let ind = Ind::new("paris").unwrap();
-        
-        let prop = pred1.apply(&ind).unwrap();
-        assert_eq!(prop.to_string(), "city(paris)");
-        assert!(prop.yes);
-        
-        assert!(Pred1::new("").is_err());
+    fn test_state_migration() {
+        let mut controller = travel_controller(&["?x.price(x)", "paris"]);
+        controller.add_field("mood", &["calm", "angry"]).unwrap();
+        controller.run();
+        let dump = controller.dump();
+        assert_eq!(dump["format"], STATE_FORMAT_VERSION);
+
+        // A restored session dumps the same state and carries on where it stopped
+        let mut restored = travel_controller(&["berlin"]);
+        restored.add_field("mood", &["calm", "angry"]).unwrap();
+        restored.restore(&dump).unwrap();
+        assert_eq!(restored.dump(), dump);
+        restored.mivs.program_state.set(ProgramState::RUN).unwrap();
+        restored.control().unwrap();
+        assert_eq!(restored.mivs.output.get().unwrap(), "Which day?");
+
+        // Dumps without a format field are of format 1, which has no turn holder
+        let mut old = dump.clone();
+        old.as_object_mut().unwrap().remove("format");
+        old["mivs"].as_object_mut().unwrap().remove("turn_holder");
+        let mut migrated = dump.clone();
+        migrated["mivs"]["turn_holder"] = "SYS".into();
+        assert_eq!(migrate_state(old.clone()).unwrap(), migrated);
+        restored.restore(&old).unwrap();
+        assert_eq!(restored.mivs.output.get().unwrap(), "Which city?");
+        assert_eq!(restored.turn_holder(), Some(&Speaker::SYS));
+
+        // Newer or invalid dumps are refused and leave the state alone
+        let mut newer = dump.clone();
+        newer["format"] = (STATE_FORMAT_VERSION + 1).into();
+        assert!(restored.restore(&newer).unwrap_err().contains("newer than the supported format"));
+        assert!(migrate_state(serde_json::json!([])).is_err());
+        let mut invalid = dump.clone();
+        invalid["is"]["plan"] = serde_json::json!(["Wander()"]);
+        assert!(restored.restore(&invalid).is_err());
+        assert_eq!(restored.mivs.output.get().unwrap(), "Which city?");
+    }
+
+    /// Output handler recording the system utterances.
+    struct RecordingOutputHandler(Rc<std::cell::RefCell<Vec<String>>>);
+
+    impl OutputHandler for RecordingOutputHandler {
+        fn write_output(&mut self, output: &str) {
+            self.0.borrow_mut().push(output.to_string());
+        }
     }
-    
+
     #[test]
-    fn test_sort_creation() {
-        let sort = Sort::new("city").unwrap();
-        assert_eq!(sort.to_string(), "city");
-        
-        assert!(Sort::new("").is_err());
+    fn test_custom_handlers() {
+        let utterances = Rc::new(std::cell::RefCell::new(Vec::new()));
+        let domain = Domain::new(HashSet::new(), HashMap::new(), HashMap::new());
+        let input_handler = DemoInputHandler::new(vec!["hello there".to_string()])
+            .with_prompt("You: ")
+            .with_echo(false)
+            .with_eof_message("Bye");
+        let mut controller = IBISController::with_handlers(
+            domain,
+            TravelDB::new(),
+            SimpleGenGrammar::new(),
+            Box::new(input_handler),
+            Box::new(RecordingOutputHandler(utterances.clone())),
+        );
+        controller.run();
+
+        assert_eq!(*utterances.borrow(), ["Hello.", "I don't understand."]);
     }
-    
+
     #[test]
-    fn test_prop_creation_and_parsing() {
-        // Test basic proposition
-        let prop = Prop::new("expensive").unwrap();
-        assert_eq!(prop.to_string(), "expensive()");
-        assert!(prop.yes);
-        
-        // Test negated proposition
-        let prop = Prop::new("-expensive").unwrap();
-        assert_eq!(prop.to_string(), "-expensive()");
-        assert!(!prop.yes);
-        
-        // Test proposition with individual
-        let prop = Prop::new("city(paris)").unwrap();
-        assert_eq!(prop.to_string(), "city(paris)");
-        assert!(prop.yes);
-        assert_eq!(prop.ind.as_ref().unwrap().to_string(), "paris");
+    fn test_channel_handlers() {
+        let (input, input_handler) = ChannelInputHandler::channel();
+        let (output_handler, output) = ChannelOutputHandler::channel();
+        let dialogue = std::thread::spawn(move || {
+            let domain = Domain::new(HashSet::new(), HashMap::new(), HashMap::new());
+            let mut controller = IBISController::with_handlers(
+                domain,
+                TravelDB::new(),
+                SimpleGenGrammar::new(),
+                Box::new(input_handler),
+                Box::new(output_handler),
+            );
+            controller.run();
+        });
+
+        assert_eq!(output.recv().unwrap(), "Hello.");
+        input.send("hello there".to_string()).unwrap();
+        assert_eq!(output.recv().unwrap(), "I don't understand.");
+        input.send("quit".to_string()).unwrap();
+        dialogue.join().unwrap();
+        assert!(output.recv().is_err());
     }
-    
+
     #[test]
-    fn test_shortans_creation_and_parsing() {
-        // Positive short answer
-        let ans = ShortAns::new("paris").unwrap();
-        assert_eq!(ans.to_string(), "paris");
-        assert!(ans.yes);
-        
-        // Negative short answer  
-        let ans = ShortAns::new("-paris").unwrap();
-        assert_eq!(ans.to_string(), "-paris");
-        assert!(!ans.yes);
-        
-        assert!(ShortAns::new("").is_err());
+    fn test_streaming_output() {
+        let (output_handler, output) = StreamingOutputHandler::channel(1);
+        let dialogue = std::thread::spawn(move || {
+            let mut controller = travel_controller(&["?x.price(x)", "I want to go to paris"]).with_streaming_output();
+            controller.output_handler = Box::new(output_handler);
+            controller.run();
+            controller
+                .transcript()
+                .iter()
+                .filter(|(_, speaker, _)| *speaker == Speaker::SYS)
+                .map(|(_, _, text)| text.clone())
+                .collect::<Vec<String>>()
+        });
+
+        // The host reads one sentence at a time while the dialogue waits for it
+        let mut utterances = vec![Vec::new()];
+        for chunk in output.iter() {
+            match chunk {
+                OutputChunk::Sentence(sentence) => utterances.last_mut().unwrap().push(sentence),
+                OutputChunk::End => utterances.push(Vec::new()),
+            }
+        }
+        let system = dialogue.join().unwrap();
+        utterances.pop();
+        assert_eq!(utterances.last().unwrap(), &["I don't understand.", "Which city?"]);
+        let joined: Vec<String> = utterances.iter().map(|sentences| sentences.join(" ")).collect();
+        assert_eq!(joined, system);
     }
-    
+
     #[test]
-    fn test_yesno_creation() {
-        let yes_ans = YesNo::new("yes").unwrap();
-        assert_eq!(yes_ans.to_string(), "yes");
-        assert!(yes_ans.yes);
-        
-        let no_ans = YesNo::new("no").unwrap();
-        assert_eq!(no_ans.to_string(), "no");
-        assert!(!no_ans.yes);
-        
-        assert!(YesNo::new("maybe").is_err());
+    fn test_ibis_dialogue_not_understood() {
+        let mut controller = travel_controller(&["I want to go to paris"]);
+        controller.run();
+
+        assert_eq!(controller.mivs.output.get(), Some(&"I don't understand.".to_string()));
+
+        // The feedback comes before the question asked again
+        let mut controller = travel_controller(&["?x.price(x)", "I want to go to paris"]);
+        controller.run();
+        assert_eq!(
+            controller.mivs.output.get(),
+            Some(&SimpleGenGrammar::new().generate(
+                &["icm:sem*neg".to_string(), "Ask('?x.dest_city(x)')".to_string()],
+                &InterpretationContext::new(&controller.domain)
+            ))
+        );
+        assert!(controller.mivs.output.get().unwrap().starts_with("I don't understand. "));
     }
-    
+
     #[test]
-    fn test_ans_enum_parsing() {
-        // Test yes/no parsing
-        let ans = Ans::new("yes").unwrap();
-        match ans {
-            Ans::YesNo(yesno) => assert!(yesno.yes),
-            _ => panic!("Expected YesNo variant"),
+    fn test_custom_dialogue_manager() {
+        /// A manager echoing each input until it runs out of inputs.
+        struct Echo {
+            inputs: Vec<&'static str>, // Remaining user inputs, last first
+            input: Option<String>, // The latest user input
+            next: Option<String>, // The move selected next
+            output: Vec<String>, // Everything said so far
+            running: bool, // Whether inputs are left
         }
-        
-        // Test short answer parsing
-        let ans = Ans::new("paris").unwrap();
-        match ans {
-            Ans::ShortAns(short) => {
-                assert_eq!(short.to_string(), "paris");
-                assert!(short.yes);
-            },
-            _ => panic!("Expected ShortAns variant"),
+
+        impl DialogueManager for Echo {
+            fn trace(&self, _message: &str) {}
+            fn reset(&mut self) {
+                self.next = Some("Greet()".to_string());
+                self.running = true;
+            }
+            fn is_running(&self) -> bool {
+                self.running
+            }
+            fn select(&mut self) -> Result<(), String> {
+                if let Some(input) = self.input.take() {
+                    self.next = Some(format!("Inform({})", input));
+                }
+                Ok(())
+            }
+            fn generate(&mut self) -> Result<(), String> {
+                Move::new(self.next.as_deref().unwrap_or_default()).map(|_| ())
+            }
+            fn output(&mut self) -> Result<(), String> {
+                self.output.extend(self.next.take());
+                Ok(())
+            }
+            fn input(&mut self) -> Result<(), String> {
+                self.input = self.inputs.pop().map(String::from);
+                self.running = self.input.is_some();
+                Ok(())
+            }
+            fn interpret(&mut self) {}
+            fn update(&mut self) {}
+            fn print_state(&self) {}
         }
-        
-        // Test proposition parsing
-        let ans = Ans::new("city(paris)").unwrap();
-        match ans {
-            Ans::Prop(prop) => {
-                assert_eq!(prop.to_string(), "city(paris)");
-                assert!(prop.yes);
-            },
-            _ => panic!("Expected Prop variant"),
+
+        let mut echo = Echo { inputs: vec!["bye", "hi"], input: None, next: None, output: Vec::new(), running: false };
+        echo.run().unwrap();
+        assert_eq!(echo.output, ["Greet()", "Inform(hi)", "Inform(bye)"]);
+
+        // A failing phase ends the dialogue with its error
+        echo.inputs = vec!["(", "hi"];
+        echo.output.clear();
+        let error = echo.run().unwrap_err();
+        assert_eq!(error, TurnError { turn: 0, message: Move::new("Inform(()").err().unwrap() });
+        assert_eq!(echo.output, ["Greet()", "Inform(hi)"]);
+    }
+
+    #[test]
+    fn test_kernel_modules() {
+        // The interpretation module replaced by one ignoring case
+        let kernel = Kernel::standard().with_module("interpret", |c: &mut IBISController| {
+            if let Some(input) = c.mivs.input.get().cloned() {
+                c.mivs.input.set(input.to_lowercase())?;
+            }
+            c.interpret();
+            Ok(())
+        });
+        let mut controller = travel_controller(&["?x.price(x)", "PARIS", "Berlin"]).with_kernel(kernel.clone());
+        controller.run();
+        assert!(controller.is.com.contains(&"dest_city(paris)".to_string()));
+        assert!(controller.is.com.contains(&"depart_city(berlin)".to_string()));
+
+        // Single rounds of turns run through the kernel too
+        let mut controller = travel_controller(&["?x.price(x)", "PARIS"]).with_kernel(kernel);
+        controller.reset();
+        assert!(controller.exchange().unwrap());
+        assert!(controller.exchange().unwrap());
+        assert!(controller.is.com.contains(&"dest_city(paris)".to_string()));
+        assert!(!controller.exchange().unwrap());
+
+        // A control algorithm in which the system only greets
+        use ControlStep::Module as M;
+        let greet = vec![M("select".to_string()), M("generate".to_string()), M("output".to_string())];
+        let mut controller = travel_controller(&["?x.price(x)"]).with_kernel(Kernel::standard().with_algorithm(greet));
+        controller.try_run().unwrap();
+        assert_eq!(controller.transcript.len(), 1);
+        assert!(controller.mivs.input.get().is_none());
+
+        let kernel = Kernel::standard().with_algorithm(vec![M("parse".to_string())]);
+        assert_eq!(kernel.validate().unwrap_err(), "Unknown module parse");
+        let mut controller = travel_controller(&[]).with_kernel(kernel);
+        assert_eq!(controller.try_run().unwrap_err().message, "Unknown module parse");
+        let kernel = Kernel::standard().with_algorithm(vec![ControlStep::Repeat(vec![M("input".to_string())])]);
+        assert_eq!(kernel.validate().unwrap_err(), "Repeat without a stop");
+        assert!(Kernel::standard().validate().is_ok());
+        assert_eq!(Kernel::standard().modules().len(), 10);
+    }
+
+    #[test]
+    fn test_control_language() {
+        let algorithm = Kernel::parse_algorithm(
+            "repeat [ select, if next_moves nonempty then [generate, output, update], input, interpret, update ] \
+             until program_state == quit",
+        )
+        .unwrap();
+        assert_eq!(
+            algorithm.iter().map(|step| step.to_string()).collect::<Vec<_>>(),
+            ["repeat [select, if next_moves nonempty then [generate, output, update], input, interpret, update, \
+              if program_state == quit then [stop]]"]
+        );
+        let kernel = Kernel::standard().with_algorithm(algorithm);
+        let mut controller = travel_controller(&["?x.price(x)", "paris", "berlin", "today"]).with_kernel(kernel);
+        controller.try_run().unwrap();
+        assert!(controller.is.com.contains(&"depart_day(today)".to_string()));
+
+        // The standard algorithm reads back as it is written
+        let standard = Kernel::parse_algorithm(STANDARD_CONTROL_ALGORITHM).unwrap();
+        assert_eq!(Kernel::parse_algorithm(&standard[0].to_string()).unwrap(), standard);
+
+        let path = std::env::temp_dir().join(format!("isu-control-{}.txt", std::process::id()));
+        std::fs::write(&path, "select, generate, output").unwrap();
+        let kernel = Kernel::standard().with_algorithm_file(path.to_str().unwrap()).unwrap();
+        assert_eq!(kernel.algorithm().len(), 3);
+        std::fs::remove_file(&path).ok();
+
+        for (text, error) in [
+            ("repeat [select", "Expected ] in control algorithm, found the end"),
+            ("if raining then [stop]", "Unknown condition raining in control algorithm"),
+            ("select; output", "Unexpected ; in control algorithm"),
+            ("select, then", "Expected a step in control algorithm, found then"),
+            ("select output", "Unexpected output in control algorithm"),
+            ("select, parse", "Unknown module parse"),
+        ] {
+            assert_eq!(Kernel::standard().with_algorithm_text(text).err().unwrap(), error);
         }
-        
-        assert!(Ans::new("invalid(syntax").is_err());
     }
-    
-    #[test] 
-    fn test_whq_creation_and_parsing() {
-        // Test standard wh-question format
-        let whq = WhQ::new("?x.city(x)").unwrap();
-        assert_eq!(whq.pred.to_string(), "city");
-        
-        // Test simplified format
-        let whq = WhQ::new("city").unwrap();
-        assert_eq!(whq.pred.to_string(), "city");
-        
-        assert!(WhQ::new("").is_err());
+
+    #[test]
+    fn test_plan_templates() {
+        let preds1 = HashMap::from([
+            ("book_trip".to_string(), "means".to_string()),
+            ("plane_class".to_string(), "class".to_string()),
+            ("train_class".to_string(), "class".to_string()),
+            ("dest_city".to_string(), "city".to_string()),
+        ]);
+        let sorts = HashMap::from([
+            ("means".to_string(), HashSet::from(["plane".to_string(), "train".to_string()])),
+            ("class".to_string(), HashSet::from(["first".to_string(), "economy".to_string()])),
+            ("city".to_string(), HashSet::from(["paris".to_string()])),
+        ]);
+        let mut domain = Domain::new(HashSet::new(), preds1, sorts);
+        let template = vec![
+            "Findout('?x.{transport}_class(x)')".to_string(),
+            "Findout('?x.dest_city(x)')".to_string(),
+        ];
+        domain.add_plan_template("?book_trip({transport})", template.clone()).unwrap();
+        let constructs = |domain: &Domain, question: &str| {
+            let plan = domain.get_plan(&Question::new(question).unwrap())?;
+            Some(plan.iter_rev().cloned().collect::<Vec<_>>())
+        };
+        assert_eq!(
+            constructs(&domain, "?book_trip(train)").unwrap(),
+            ["Findout('?x.train_class(x)')", "Findout('?x.dest_city(x)')"]
+        );
+        assert_eq!(constructs(&domain, "?book_trip(plane)").unwrap()[0], "Findout('?x.plane_class(x)')");
+        assert!(constructs(&domain, "?x.book_trip(x)").is_none());
+
+        // A plan of its own takes precedence
+        let mut special = domain.clone();
+        special.add_plan(Question::new("?book_trip(plane)").unwrap(), vec!["Findout('?x.dest_city(x)')".to_string()]);
+        assert_eq!(constructs(&special, "?book_trip(plane)").unwrap(), ["Findout('?x.dest_city(x)')"]);
+        let restored = Domain::from_json(&domain.to_json()).unwrap();
+        assert_eq!(constructs(&restored, "?book_trip(train)"), constructs(&domain, "?book_trip(train)"));
+
+        assert_eq!(
+            domain.add_plan_template("?book_trip(train)", template).unwrap_err(),
+            "Plan template ?book_trip(train) has no parameters"
+        );
+        assert_eq!(
+            domain.add_plan_template("?book_trip({means})", vec!["Findout('?x.{class}(x)')".to_string()]).unwrap_err(),
+            "Unknown parameter class in Findout('?x.{class}(x)')"
+        );
+
+        let handler = DemoInputHandler::new(["?book_trip(train)", "first", "paris"].map(String::from).to_vec());
+        let mut controller =
+            IBISController::with_input_handler(domain, TravelDB::new(), SimpleGenGrammar::new(), Box::new(handler));
+        controller.run();
+        assert!(controller.is.com.contains(&"train_class(first)".to_string()));
+        assert!(controller.is.com.contains(&"dest_city(paris)".to_string()));
     }
-    
+
     #[test]
-    fn test_ynq_creation_and_parsing() {
-        let ynq = YNQ::new("?expensive").unwrap();
-        assert_eq!(ynq.prop.to_string(), "expensive()");
-        
-        let ynq = YNQ::new("expensive").unwrap();
-        assert_eq!(ynq.prop.to_string(), "expensive()");
-        
-        assert!(YNQ::new("").is_err());
+    fn test_subplans() {
+        let preds1 = HashMap::from([
+            ("price".to_string(), "int".to_string()),
+            ("dest_city".to_string(), "city".to_string()),
+            ("card".to_string(), "card_type".to_string()),
+        ]);
+        let sorts = HashMap::from([
+            ("city".to_string(), HashSet::from(["paris".to_string()])),
+            ("card_type".to_string(), HashSet::from(["visa".to_string(), "amex".to_string()])),
+        ]);
+        let mut domain = Domain::new(HashSet::new(), preds1, sorts);
+        domain.add_subplan("collect_payment", vec!["Findout('?x.card(x)')".to_string()]).unwrap();
+        domain.add_plan(
+            Question::new("?x.price(x)").unwrap(),
+            vec!["CallPlan('collect_payment')".to_string(), "Findout('?x.dest_city(x)')".to_string()],
+        );
+        assert_eq!(domain.add_subplan("pay now", Vec::new()).unwrap_err(), "Invalid subplan name: pay now");
+        assert!(domain.add_subplan("pay", vec!["CallPlan()".to_string()]).is_err());
+        // Subplans calling themselves would be expanded forever
+        let retry = vec!["If('?declined()', ['CallPlan(pay)'], [])".to_string()];
+        assert_eq!(domain.add_subplan("pay", retry).unwrap_err(), "Subplan pay calls itself");
+        domain.add_subplan("refund", vec!["CallPlan('pay')".to_string()]).unwrap();
+        let cycle = vec!["CallPlan('refund')".to_string()];
+        assert_eq!(domain.add_subplan("pay", cycle.clone()).unwrap_err(), "Subplan pay calls itself");
+        let mut other = Domain::new(HashSet::new(), HashMap::new(), HashMap::new());
+        other.add_subplan("pay", cycle).unwrap();
+        assert_eq!(domain.clone().merge(other).unwrap_err(), "Subplan pay calls itself");
+        assert!(!domain.subplans.contains_key("pay"));
+        Arc::make_mut(&mut domain.subplans).remove("refund");
+        assert!(CallPlan::new("collect_payment").unwrap().typecheck(&domain).is_ok());
+        assert!(CallPlan::new("refund").unwrap().typecheck(&domain).is_err());
+        assert_eq!(Domain::from_json(&domain.to_json()).unwrap().content_hash(), domain.content_hash());
+        let dependencies = domain.to_json()["dependencies"]["?x.price(x)"].clone();
+        assert_eq!(dependencies, serde_json::json!(["?x.dest_city(x)", "?x.card(x)"]));
+
+        // The subplan is carried out first, then the calling plan goes on
+        let handler = DemoInputHandler::new(["?x.price(x)", "visa", "paris"].map(String::from).to_vec());
+        let mut controller = IBISController::with_input_handler(
+            domain.clone(),
+            TravelDB::new(),
+            SimpleGenGrammar::new(),
+            Box::new(handler),
+        );
+        controller.run();
+        let asked: Vec<String> = controller
+            .history()
+            .iter()
+            .filter_map(|tagged| match &tagged.mv {
+                Move::Ask(ask) if tagged.speaker == Speaker::SYS => Some(ask.content.to_string()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(asked, ["?x.card(x)", "?x.dest_city(x)"]);
+        assert!(controller.is.com.contains(&"card(visa)".to_string()));
+        assert!(controller.is.com.contains(&"dest_city(paris)".to_string()));
+
+        // Calling a missing subplan is an error of the turn
+        domain.add_plan(Question::new("?x.price(x)").unwrap(), vec!["CallPlan('refund')".to_string()]);
+        let handler = DemoInputHandler::new(vec!["?x.price(x)".to_string()]);
+        let mut controller =
+            IBISController::with_input_handler(domain, TravelDB::new(), SimpleGenGrammar::new(), Box::new(handler));
+        controller.run();
+        assert_eq!(controller.errors()[0].message, "Unknown subplan refund");
     }
-    
+
     #[test]
-    fn test_question_enum_parsing() {
-        // Test wh-question parsing
-        let q = Question::new("?x.city(x)").unwrap();
-        match q {
-            Question::WhQ(whq) => assert_eq!(whq.pred.to_string(), "city"),
-            _ => panic!("Expected WhQ variant"),
-        }
-        
-        // Test yes/no question parsing
-        let q = Question::new("?expensive").unwrap();
-        match q {
-            Question::YNQ(ynq) => assert_eq!(ynq.prop.pred.to_string(), "expensive"),
-            _ => panic!("Expected YNQ variant"),
+    fn test_plan_failure() {
+        /// A database that only knows prices from berlin.
+        struct BerlinDB;
+
+        impl Database for BerlinDB {
+            fn try_consult_db(&self, _question: &Question, context: &TSet<Prop>) -> Result<Prop, String> {
+                match context.contains(&Prop::new("depart_city(berlin)").unwrap()) {
+                    true => Prop::new("price(100)"),
+                    false => Err("No price without a departure city".to_string()),
+                }
+            }
+
+            fn aggregate(&self, _aggregate: Aggregate, _field: &str, _context: &TSet<Prop>) -> Option<Ind> {
+                None
+            }
         }
-        
-        assert!(Question::new("invalid").is_err());
+
+        let domain = |plan: &[&str]| {
+            let preds1 = HashMap::from([
+                ("price".to_string(), "int".to_string()),
+                ("dest_city".to_string(), "city".to_string()),
+                ("depart_city".to_string(), "city".to_string()),
+            ]);
+            let cities = HashSet::from(["paris".to_string(), "berlin".to_string()]);
+            let sorts = HashMap::from([("city".to_string(), cities)]);
+            let mut domain = Domain::new(HashSet::new(), preds1, sorts);
+            domain.add_plan(Question::new("?x.price(x)").unwrap(), plan.iter().map(|c| c.to_string()).collect());
+            domain
+        };
+        let controller = |domain, inputs: &[&str]| {
+            let handler = DemoInputHandler::new(inputs.iter().map(|s| s.to_string()).collect());
+            IBISController::with_input_handler(domain, BerlinDB, SimpleGenGrammar::new(), Box::new(handler))
+        };
+
+        // The recovery constructs find out what the database is missing and consult it again
+        let plan = [
+            "Findout('?x.dest_city(x)')",
+            "ConsultDB('?x.price(x)')",
+            "OnFailure(['Findout(?x.depart_city(x))', 'ConsultDB(?x.price(x))'])",
+        ];
+        let on_failure = PlanItem::new(plan[2]).unwrap().to_string();
+        assert_eq!(on_failure, "OnFailure([Findout(?x.depart_city(x)), ConsultDB(?x.price(x))])");
+        assert_eq!(PlanItem::new(&on_failure).unwrap().to_string(), on_failure);
+        let mut ibis = controller(domain(&plan), &["?x.price(x)", "paris", "berlin"]);
+        ibis.run();
+        assert!(ibis.is.bel.contains(&"price(100)".to_string()));
+        assert_eq!(ibis.plan_failure(), Some("No price without a departure city"));
+        assert!(ibis.errors().is_empty());
+
+        // Without recovery constructs the plan is abandoned
+        let mut ibis = controller(domain(&plan[..2]), &["?x.price(x)", "paris"]);
+        ibis.run();
+        assert!(ibis.is.plan.is_empty());
+        assert!(ibis.transcript.iter().any(|(_, _, utterance)| utterance == "Sorry, I cannot help with that."));
     }
-    
-    // Tests for dialogue components
+
     #[test]
-    fn test_dialogue_moves() {
-        // Test Greet
-        let greet = Greet;
-        assert_eq!(greet.to_string(), "Greet()");
-        
-        // Test Quit
-        let quit = Quit;
-        assert_eq!(quit.to_string(), "Quit()");
-        
-        // Test Ask
-        let question = Question::new("?expensive").unwrap();
-        let ask = Ask::new(question);
-        assert!(ask.to_string().contains("Ask"));
-        assert!(ask.to_string().contains("expensive"));
-        
-        // Test Answer
-        let answer_content = Ans::new("yes").unwrap();
-        let answer = Answer::new(answer_content);
-        assert!(answer.to_string().contains("Answer"));
-        assert!(answer.to_string().contains("yes"));
+    fn test_commitment_queries() {
+        let mut controller = travel_controller(&["?x.price(x)", "paris", "berlin"]);
+        controller.run();
+        let commitments: Vec<String> = controller.commitments().iter().map(|prop| prop.to_string()).collect();
+        assert_eq!(commitments, ["depart_city(berlin)", "dest_city(paris)"]);
+        assert_eq!(controller.resolved_value("dest_city").unwrap().to_string(), "paris");
+        assert!(controller.resolved_value("depart_day").is_none());
+        let open: Vec<String> = controller.open_questions().iter().map(|que| que.to_string()).collect();
+        assert_eq!(open, ["?x.depart_day(x)", "?x.price(x)"]);
+    }
+
+    #[test]
+    fn test_fulfillment() {
+        let fulfilled = Rc::new(RefCell::new(Vec::new()));
+        let mut controller = travel_controller(&["?x.price(x)", "paris", "berlin", "today", "no"]);
+        let log = fulfilled.clone();
+        controller
+            .on_complete("?x.price(x)", move |slots| {
+                let mut slots: Vec<String> = slots.iter().map(|(pred, ind)| format!("{}={}", pred, ind)).collect();
+                slots.sort();
+                log.borrow_mut().push(slots.join(" "));
+            })
+            .unwrap();
+        assert!(controller.on_complete("price", |_| {}).is_err());
+        controller.run();
+        assert_eq!(*fulfilled.borrow(), ["depart_city=berlin depart_day=today dest_city=paris"]);
+
+        // An abandoned plan is not fulfilled
+        fulfilled.borrow_mut().clear();
+        let mut controller = travel_controller(&["?x.price(x)", "berlin", "paris", "today", "no"]);
+        let log = fulfilled.clone();
+        controller.on_complete("?x.price(x)", move |_| log.borrow_mut().push(String::new())).unwrap();
+        controller.run();
+        assert!(controller.plan_failure().is_some());
+        assert!(fulfilled.borrow().is_empty());
+
+        // A plan completed in an update that is rolled back is not fulfilled
+        let mut controller = travel_controller(&[]);
+        let log = fulfilled.clone();
+        controller.on_complete("?x.price(x)", move |_| log.borrow_mut().push(String::new())).unwrap();
+        let price = Question::new("?x.price(x)").unwrap();
+        controller.domain_mut().add_plan(price, vec!["Findout('?x.dest_city(x)')".to_string()]);
+        controller.domain_mut().add_plan(
+            Question::new("?x.hotel(x)").unwrap(),
+            vec!["If('?return()', ['Lookup(?x.dest_city(x))'], [])".to_string()],
+        );
+        controller.reset();
+        controller.is.com.add("dest_city(paris)".to_string()).unwrap();
+        controller.is.com.add("return()".to_string()).unwrap();
+        controller.mivs.latest_moves = ["Ask('?x.hotel(x)')", "Ask('?x.price(x)')"]
+            .iter()
+            .map(|mv| TaggedMove::new(Move::new(mv).unwrap(), Speaker::USR, 1))
+            .collect();
+        controller.update();
+        assert_eq!(controller.errors().len(), 1);
+        assert!(fulfilled.borrow().is_empty());
     }
-    
+
     #[test]
-    fn test_icm_creation() {
-        let icm = ICM::new("per", "pos", Some("understood".to_string()));
-        assert_eq!(icm.to_string(), "icm:per*pos:'understood'");
-        
-        let icm_no_content = ICM::new("sem", "neg", None);
-        assert_eq!(icm_no_content.to_string(), "icm:sem*neg");
+    fn test_session_manager() {
+        let mut sessions = SessionManager::new(travel_session);
+        assert_eq!(sessions.handle("alice", "?x.price(x)"), ["Hello.", "Which city?"]);
+        assert_eq!(sessions.handle("bob", "?x.price(x)"), ["Hello.", "Which city?"]);
+        assert_eq!(sessions.handle("alice", "paris"), ["Which city?"]);
+        assert_eq!(sessions.sessions(), ["alice", "bob"]);
+
+        // A session whose dialogue ends is closed
+        assert!(sessions.handle("alice", "quit").is_empty());
+        assert_eq!(sessions.sessions(), ["bob"]);
+        assert!(sessions.end("bob"));
+        assert!(!sessions.end("bob"));
+        assert!(sessions.sessions().is_empty());
     }
-    
+
     #[test]
-    fn test_plan_constructors() {
-        let question = Question::new("?expensive").unwrap();
-        
-        // Test Respond
-        let respond = Respond::new(question.clone());
-        assert!(respond.to_string().contains("Respond"));
-        assert!(respond.to_string().contains("expensive"));
-        
-        // Test ConsultDB
-        let consult = ConsultDB::new(question.clone());
-        assert!(consult.to_string().contains("ConsultDB"));
-        assert!(consult.to_string().contains("expensive"));
-        
-        // Test Findout
-        let findout = Findout::new(question.clone());
-        assert!(findout.to_string().contains("Findout"));
-        assert!(findout.to_string().contains("expensive"));
-        
-        // Test Raise
-        let raise = Raise::new(question.clone());
-        assert!(raise.to_string().contains("Raise"));
-        assert!(raise.to_string().contains("expensive"));
-        
-        // Test If
-        let if_plan = If::new(
-            question, 
-            vec!["ConsultDB".to_string()], 
-            vec!["Greet".to_string()]
-        );
-        assert!(if_plan.to_string().contains("If"));
+    fn test_shared_domain() {
+        fn assert_shareable<T: Send + Sync>() {}
+        assert_shareable::<Domain>();
+        assert_shareable::<SimpleGenGrammar>();
+        assert_shareable::<TravelDB>();
+
+        // Many dialogues on several threads share one domain, grammar and database
+        let domain = Arc::new(travel_domain());
+        let grammar = Arc::new(travel_grammar());
+        let database = Arc::new(travel_database());
+        let threads: Vec<_> = (0..8)
+            .map(|_| {
+                let (domain, grammar, database) = (domain.clone(), grammar.clone(), database.clone());
+                thread::spawn(move || {
+                    let completed = Rc::new(std::cell::Cell::new(0));
+                    for _ in 0..50 {
+                        let inputs = ["?x.price(x)", "paris", "berlin", "today", "no"].map(String::from).to_vec();
+                        let mut controller = IBISController::with_handlers(
+                            domain.clone(),
+                            database.clone(),
+                            grammar.clone(),
+                            Box::new(DemoInputHandler::new(inputs)),
+                            Box::new(ChannelOutputHandler::channel().0),
+                        );
+                        let counter = completed.clone();
+                        controller.on_complete("?x.price(x)", move |_| counter.set(counter.get() + 1)).unwrap();
+                        controller.run();
+                        assert!(std::ptr::eq(controller.domain(), &*domain));
+                    }
+                    completed.get()
+                })
+            })
+            .collect();
+        let completed: usize = threads.into_iter().map(|thread| thread.join().unwrap()).sum();
+        assert_eq!(completed, 400);
+        assert_eq!(Arc::strong_count(&domain), 1);
+        assert!(!grammar.cache().outputs.is_empty());
+
+        // A controller changing a shared domain gets its own copy
+        let mut controller = IBISController::new(domain.clone(), TravelDB::new(), SimpleGenGrammar::new());
+        controller.domain_mut().add_individual("london", "city").unwrap();
+        assert!(controller.domain().has_sort("london", "city"));
+        assert!(!domain.has_sort("london", "city"));
     }
-    
-    // Tests for grammar functionality
+
     #[test]
-    fn test_simple_gen_grammar() {
-        let mut grammar = SimpleGenGrammar::new();
-        
-        // Test adding custom forms
-        grammar.add_form("Ask('?price')", "What is the price?");
-        grammar.add_form("Answer(paris)", "The answer is Paris.");
-        
-        // Test generation
-        let mut moves = TSet::new();
-        moves.add("Greet()".to_string()).unwrap();
-        let output = grammar.generate(&moves);
-        assert_eq!(output, "Hello.");
-        
-        // Test interpretation - "quit" is handled as special case in the grammar
-        let interpreted = grammar.interpret("quit");
-        assert!(interpreted.is_some());
-        let moves = interpreted.unwrap();
-        assert!(moves.elements.iter().any(|m| m.contains("Quit")));
-        
-        // Test question interpretation  
-        let interpreted = grammar.interpret("?expensive");
-        assert!(interpreted.is_some());
-        let moves = interpreted.unwrap();
-        assert!(moves.elements.iter().any(|m| m.contains("Ask") && m.contains("expensive")));
-        
-        // Test answer interpretation
-        let interpreted = grammar.interpret("yes");
-        assert!(interpreted.is_some());
-        let moves = interpreted.unwrap();
-        assert!(moves.elements.iter().any(|m| m.contains("Answer") && m.contains("yes")));
-        
-        // Test unrecognized input
-        let interpreted = grammar.interpret("random gibberish");
-        assert!(interpreted.is_none());
+    fn test_domain_overlay() {
+        let domain = Arc::new(travel_domain());
+        let session = |inputs: &[&str]| {
+            let inputs = inputs.iter().map(|s| s.to_string()).collect();
+            IBISController::with_handlers(
+                domain.clone(),
+                travel_database(),
+                travel_grammar(),
+                Box::new(DemoInputHandler::new(inputs)),
+                Box::new(ChannelOutputHandler::channel().0),
+            )
+        };
+
+        // A session adds an individual and an alias of its own
+        let mut controller = session(&["?x.price(x)", "going to london", "berlin", "today", "no"]);
+        controller.domain_mut().add_individual("london", "city").unwrap();
+        controller.domain_mut().add_alias("going to", "dest_city").unwrap();
+        controller.run();
+        assert!(controller.is.com.contains(&"dest_city(london)".to_string()));
+        assert!(controller.domain().has_sort("london", "city"));
+        assert!(controller.domain().sorts.get("city").unwrap().contains("paris"));
+
+        // The additions are kept in an overlay: the shared tables are neither changed nor copied
+        assert!(!domain.has_sort("london", "city"));
+        assert_eq!(domain.resolve_pred("going to"), None);
+        assert!(Arc::ptr_eq(&controller.domain().inds.base, &domain.inds.base));
+        assert!(Arc::ptr_eq(&controller.domain().sorts.base, &domain.sorts.base));
+        assert!(Arc::ptr_eq(&controller.domain().plans, &domain.plans));
+
+        // Other sessions do not see them
+        let mut other = session(&["?x.price(x)", "going to london"]);
+        other.run();
+        assert!(!other.is.com.iter().any(|prop| prop.contains("london")));
     }
-    
-    // Tests for database functionality
+
     #[test]
-    fn test_travel_db() {
-        let mut db = TravelDB::new();
-        
-        // Add sample entries
-        let mut entry1 = HashMap::new();
-        entry1.insert("from".to_string(), "paris".to_string());
-        entry1.insert("to".to_string(), "london".to_string());
-        entry1.insert("day".to_string(), "monday".to_string());
-        entry1.insert("price".to_string(), "200".to_string());
-        db.add_entry(entry1);
-        
-        let mut entry2 = HashMap::new();
-        entry2.insert("from".to_string(), "london".to_string());
-        entry2.insert("to".to_string(), "paris".to_string());
-        entry2.insert("day".to_string(), "tuesday".to_string());
-        entry2.insert("price".to_string(), "180".to_string());
-        db.add_entry(entry2);
-        
-        // Test lookup
-        let result = db.lookup_entry("paris", "london", "monday");
-        assert!(result.is_some());
-        assert_eq!(result.unwrap().get("price"), Some(&"200".to_string()));
-        
-        let no_result = db.lookup_entry("invalid", "route", "never");
-        assert!(no_result.is_none());
-        
-        // Test context retrieval (using mock context)
-        let mut context = TSet::new();
-        let prop1 = Prop {
-            pred: Pred0::new("depart_city").unwrap(),
-            ind: Some(Ind::new("paris").unwrap()),
-            yes: true,
+    fn test_session_grammar() {
+        let grammar = Arc::new(travel_grammar());
+        let session = |inputs: &[&str]| {
+            let inputs = inputs.iter().map(|s| s.to_string()).collect();
+            IBISController::with_handlers(
+                travel_domain(),
+                travel_database(),
+                grammar.clone(),
+                Box::new(DemoInputHandler::new(inputs)),
+                Box::new(ChannelOutputHandler::channel().0),
+            )
         };
-        context.add(prop1).unwrap();
-        
-        let context_value = db.get_context(&context, "depart_city");
-        assert_eq!(context_value, Some("paris".to_string()));
-        
-        let no_context = db.get_context(&context, "nonexistent");
-        assert_eq!(no_context, None);
+
+        // A session names the user and a city they introduced
+        let mut controller = session(&["?x.price(x)", "my home town", "berlin", "today", "no"]);
+        controller.domain_mut().add_individual("springfield", "city").unwrap();
+        controller.add_session_form("Greet()", "Welcome back, Ann!").unwrap();
+        controller.add_session_alias("my home town", "springfield").unwrap();
+        controller.add_session_alias("home", "paris").unwrap();
+        assert!(controller.add_session_form("Greet(", "Hi").is_err());
+        assert!(controller.add_session_alias(" ", "paris").is_err());
+        controller.run();
+        assert_eq!(controller.transcript[0].2, "Welcome back, Ann!");
+        assert!(controller.is.com.contains(&"dest_city(springfield)".to_string()));
+
+        // The shared grammar is left as it was for the other sessions
+        let mut other = session(&["?x.price(x)", "my home town"]);
+        other.run();
+        assert_eq!(other.transcript[0].2, "Hello.");
+        assert!(!other.is.com.iter().any(|prop| prop.contains("springfield")));
     }
-    
-    // Tests for domain functionality
+
     #[test]
-    fn test_domain_creation_and_operations() {
-        let preds0 = HashSet::from(["expensive".to_string(), "available".to_string()]);
-        let mut preds1 = HashMap::new();
-        preds1.insert("city".to_string(), "location".to_string());
-        preds1.insert("transport".to_string(), "vehicle".to_string());
-        
-        let mut sorts = HashMap::new();
-        sorts.insert("location".to_string(), HashSet::from(["paris".to_string(), "london".to_string()]));
-        sorts.insert("vehicle".to_string(), HashSet::from(["plane".to_string(), "train".to_string()]));
-        
-        let mut domain = Domain::new(preds0, preds1, sorts);
-        
-        // Test individuals are correctly inferred
-        assert_eq!(domain.inds.get("paris"), Some(&"location".to_string()));
-        assert_eq!(domain.inds.get("plane"), Some(&"vehicle".to_string()));
-        
-        // Test adding plans
-        let question = Question::new("?expensive").unwrap();
-        let plan = vec!["ConsultDB".to_string(), "Respond".to_string()];
-        domain.add_plan(question.clone(), plan);
-        
-        let retrieved_plan = domain.get_plan(&question);
-        assert!(retrieved_plan.is_some());
-        let plan_stack = retrieved_plan.unwrap();
-        assert_eq!(plan_stack.len(), 2);
-        
-        // Test relevance checking
-        let ans_yes = Ans::new("yes").unwrap();
-        let ynq = Question::new("?expensive").unwrap();
-        assert!(domain.relevant(&ans_yes, &ynq));
-        
-        let ans_paris = Ans::new("paris").unwrap();
-        let whq = Question::new("?x.city(x)").unwrap();
-        assert!(domain.relevant(&ans_paris, &whq));
-        
-        // Test resolution checking
-        let ans_yes = Ans::new("yes").unwrap();
-        let ynq = Question::new("?expensive").unwrap();
-        assert!(domain.resolves(&ans_yes, &ynq));
-        
-        let ans_no = Ans::new("no").unwrap();
-        assert!(domain.resolves(&ans_no, &ynq));
-        
-        // Test combination
-        let combined = domain.combine(&whq, &ans_paris);
-        assert!(combined.is_ok());
-        let prop = combined.unwrap();
-        assert_eq!(prop.pred.to_string(), "city");
-        assert_eq!(prop.ind.as_ref().unwrap().to_string(), "paris");
+    fn test_backchannels() {
+        let mut controller = travel_controller(&["?x.price(x)", "paris", "mhm", "berlin", "today", "no"])
+            .with_acknowledgements(1.0);
+        controller.run();
+        assert_eq!(controller.transcript[4].2, "Okay. Which city?");
+
+        // The backchannel is heard without disturbing the question under discussion
+        assert_eq!(controller.transcript[6].2, "Which city?");
+        assert!(controller.is.com.contains(&"depart_city(berlin)".to_string()));
+        assert!(controller.is.com.contains(&"price(345)".to_string()));
+        assert!(controller.unmatched.is_empty());
+        let acknowledged = controller.history().iter().filter(|m| matches!(m.mv, Move::Acknowledge(_))).count();
+        assert_eq!(acknowledged, 5); // Four answers and the backchannel
+
+        // Answers are not acknowledged by default
+        let mut controller = travel_controller(&["?x.price(x)", "paris"]);
+        controller.run();
+        assert_eq!(controller.transcript[4].2, "Which city?");
+        assert_eq!(serde_json::to_value(Move::new("Acknowledge()").unwrap()).unwrap()["move"], "acknowledge");
+
+        // Backchannels to a yes/no question answer it
+        let mut controller = travel_controller(&["?x.price(x)", "paris", "berlin", "today", "okay"]);
+        controller.run();
+        assert!(controller.is.com.contains(&"return()".to_string()));
     }
-    
-    // Test for enums
+
     #[test]
-    fn test_speaker_enum() {
-        let usr = Speaker::new("USR").unwrap();
-        assert_eq!(usr.to_string(), "USR");
-        
-        let sys = Speaker::new("SYS").unwrap();  
-        assert_eq!(sys.to_string(), "SYS");
-        
-        assert!(Speaker::new("INVALID").is_none());
+    fn test_experiments() {
+        let experiment = Experiment::new("prompts")
+            .with_variant(Variant::new("control"))
+            .with_variant(Variant::new("short").with_form("Ask('?x.dest_city(x)')", "Where to?"));
+        assert_eq!(experiment.assign("alice").unwrap().name(), experiment.assign("alice").unwrap().name());
+        let assigned = |name: &str| {
+            (0..100).map(|i| format!("user{}", i)).find(|s| experiment.assign(s).unwrap().name() == name)
+        };
+        let (control, short) = (assigned("control").unwrap(), assigned("short").unwrap());
+        assert!(Experiment::new("empty").assign("alice").is_none());
+
+        // Sessions get the forms of their variant, recorded with their results
+        let mut controller = travel_controller(&[]);
+        assert_eq!(controller.join_experiment(&experiment, &short).unwrap(), "short");
+        assert!(controller.join_experiment(&experiment, &control).is_err());
+        let results = controller.run_corpus(vec![ScriptedInputHandler::new().say("?x.price(x)")]);
+        assert_eq!(results[0].transcript[2].2, "Where to?");
+        assert_eq!(results[0].variants, [("prompts".to_string(), "short".to_string())]);
+        assert!(results[0].to_transcript().starts_with("VARIANTS: prompts=short\n[1] S> Hello."));
+
+        // Session managers assign each new session by its id
+        let mut sessions = SessionManager::new(travel_session).with_experiment(experiment).unwrap();
+        assert_eq!(sessions.handle(&control, "?x.price(x)"), ["Hello.", "Which city?"]);
+        assert_eq!(sessions.handle(&short, "?x.price(x)"), ["Hello.", "Where to?"]);
+        let broken = Experiment::new("broken").with_variant(Variant::new("typo").with_form("Ask(", "Where to?"));
+        assert!(SessionManager::new(travel_session).with_experiment(broken).is_err());
     }
-    
+
     #[test]
-    fn test_program_state_enum() {
-        let run = ProgramState::new("RUN").unwrap();
-        assert_eq!(run.to_string(), "RUN");
-        
-        let quit = ProgramState::new("QUIT").unwrap();
-        assert_eq!(quit.to_string(), "QUIT");
-        
-        assert!(ProgramState::new("INVALID").is_none());
+    fn test_mock_database() {
+        let json = serde_json::json!({
+            "responses": [
+                {"question": "?x.price(x)", "answer": "price(100)"},
+                {
+                    "question": "?x.price(x)",
+                    "context": ["dest_city(paris)", "depart_city(berlin)"],
+                    "answer": "price(345)"
+                },
+                {"question": "?x.price(x)", "context": ["dest_city(rome)"], "error": "No flights to rome"}
+            ],
+            "aggregates": [{"aggregate": "min", "field": "price", "value": "80"}]
+        });
+        let db = MockDatabase::from_json(&json).unwrap();
+        let context = |props: &[&str]| props.iter().map(|p| Prop::new(p).unwrap()).collect::<TSet<Prop>>();
+        let price = Question::new("?x.price(x)").unwrap();
+        assert_eq!(db.try_consult_db(&price, &context(&["dest_city(paris)"])).unwrap().to_string(), "price(100)");
+        assert_eq!(db.try_consult_db(&price, &context(&["dest_city(rome)"])).err().unwrap(), "No flights to rome");
+        assert!(db.try_consult_db(&Question::new("?x.dest_city(x)").unwrap(), &context(&[])).is_err());
+        assert_eq!(db.aggregate(Aggregate::Min, "price", &context(&[])).unwrap().to_string(), "80");
+        assert_eq!(db.calls().len(), 3);
+        assert!(MockDatabase::from_json(&serde_json::json!({"responses": [{"question": "?x.price(x)"}]})).is_err());
+
+        // Controllers consult it with their commitments
+        let db = Arc::new(db.with_latency(Duration::from_millis(5)));
+        let inputs = ["?x.price(x)", "paris", "berlin", "today", "no"].map(String::from).to_vec();
+        let handler = Box::new(DemoInputHandler::new(inputs.clone()));
+        let mut controller = IBISController::with_input_handler(travel_domain(), db.clone(), travel_grammar(), handler);
+        controller.run();
+        assert!(controller.is.bel.contains(&"price(345)".to_string()));
+        let call = db.calls().pop().unwrap();
+        assert_eq!(call.question, "?x.price(x)");
+        assert_eq!(call.context, ["-return()", "depart_city(berlin)", "depart_day(today)", "dest_city(paris)"]);
+
+        // Injected failures abandon the plan
+        db.fail_next(1);
+        let handler = Box::new(DemoInputHandler::new(inputs));
+        let mut controller = IBISController::with_input_handler(travel_domain(), db.clone(), travel_grammar(), handler);
+        controller.run();
+        assert!(!controller.is.bel.contains(&"price(345)".to_string()));
+        assert!(controller.transcript.iter().any(|(_, _, utterance)| utterance == "Sorry, I cannot help with that."));
     }
-    
-    // Tests for input handlers
+
     #[test]
-    fn test_demo_input_handler() {
-        let inputs = vec!["hello".to_string(), "?expensive".to_string(), "quit".to_string()];
-        let mut handler = DemoInputHandler::new(inputs);
-        
-        assert!(handler.has_input());
-        assert_eq!(handler.read_line(), Some("hello".to_string()));
-        
-        assert!(handler.has_input());
-        assert_eq!(handler.read_line(), Some("?expensive".to_string()));
-        
-        assert!(handler.has_input());
-        assert_eq!(handler.read_line(), Some("quit".to_string()));
-        
-        assert!(!handler.has_input());
-        assert_eq!(handler.read_line(), None);
+    fn test_file_database() {
+        let dir = std::env::temp_dir().join(format!("isu-files-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = |name: &str| dir.join(name).to_str().unwrap().to_string();
+        let csv = "price,to,day,note\n232,paris,today,\"direct, \"\"fast\"\"\"\n180,paris,any,\n400,rome,today,\n";
+        std::fs::write(path("flights.csv"), csv).unwrap();
+        let json = r#"[{"price": 232, "to": "paris"}, {"price": 180, "to": "paris"}]"#;
+        std::fs::write(path("flights.json"), json).unwrap();
+        std::fs::write(path("ragged.csv"), "price,to\n232\n").unwrap();
+
+        let csv = FileDatabase::from_csv(&path("flights.csv"), &["dest_city=to", "depart_day=day"]).unwrap();
+        let json = FileDatabase::from_json(&path("flights.json"), &["dest_city=to"]).unwrap();
+        assert_eq!((csv.len(), json.len()), (3, 2));
+        let context: TSet<Prop> =
+            ["dest_city(paris)", "depart_day(today)"].iter().map(|p| Prop::new(p).unwrap()).collect();
+        let ask = |db: &FileDatabase, question: &str| db.try_consult_db(&Question::new(question).unwrap(), &context);
+        assert_eq!(ask(&csv, "?x.price(x)").unwrap().to_string(), "price(232)");
+        assert_eq!(ask(&csv, "?x.min:price(x)").unwrap().to_string(), "min:price(180)");
+        assert_eq!(ask(&json, "?x.price(x)<200").unwrap().to_string(), "price(180)<200");
+        assert!(ask(&csv, "?x.price(x)>500").is_err());
+        assert_eq!(csv.aggregate(Aggregate::Count, "price", &context).unwrap().to_string(), "2");
+        let rows = csv_rows("232, \"direct, \"\"fast\"\"\"\r\n\n180,\"two\nlines\"\n").unwrap();
+        assert_eq!(rows, [["232", "direct, \"fast\""], ["180", "two\nlines"]]);
+        assert_eq!(csv_rows("price,note\n232,\"open").unwrap_err(), "row 2: Unterminated quote");
+
+        // Broken files and unknown key columns are errors
+        assert!(FileDatabase::from_csv(&path("ragged.csv"), &[]).err().unwrap().contains("row 2"));
+        assert!(FileDatabase::from_csv(&path("flights.csv"), &["dest_city"]).is_err());
+        assert!(FileDatabase::from_json(&path("missing.json"), &[]).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
     }
-    
-    // Integration test for IBISController
+
     #[test]
-    fn test_ibis_controller_creation() {
-        // Create domain
-        let preds0 = HashSet::from(["expensive".to_string()]);
-        let preds1 = HashMap::from([("city".to_string(), "location".to_string())]);
-        let sorts = HashMap::from([("location".to_string(), HashSet::from(["paris".to_string()]))]);
-        let domain = Domain::new(preds0, preds1, sorts);
-        
-        // Create database
-        let database = TravelDB::new();
-        
-        // Create grammar
-        let grammar = SimpleGenGrammar::new();
-        
-        // Create demo input handler
-        let inputs = vec!["hello".to_string(), "quit".to_string()];
-        let input_handler = Box::new(DemoInputHandler::new(inputs));
-        
-        // Create controller
-        let controller = IBISController::with_input_handler(domain, database, grammar, input_handler);
-        
-        // Basic assertion that controller was created successfully
-        assert!(matches!(controller.mivs.program_state.get(), None)); // Initially unset
+    fn test_cached_database() {
+        let mut mock = MockDatabase::new();
+        mock.add_response("?x.price(x)", &["dest_city(paris)"], "price(232)").unwrap();
+        mock.add_response("?x.price(x)", &["dest_city(berlin)"], "price(99)").unwrap();
+        let mock = Arc::new(mock);
+        let db = CachedDatabase::new(mock.clone()).with_key_predicates(&["dest_city"]);
+        let context = |props: &[&str]| props.iter().map(|p| Prop::new(p).unwrap()).collect::<TSet<Prop>>();
+        let price = Question::new("?x.price(x)").unwrap();
+
+        // Commitments to other predicates do not keep answers from being reused
+        assert_eq!(db.try_consult_db(&price, &context(&["dest_city(paris)"])).unwrap().to_string(), "price(232)");
+        let answer = db.try_consult_db(&price, &context(&["dest_city(paris)", "return()"])).unwrap();
+        assert_eq!(answer.to_string(), "price(232)");
+        assert_eq!((db.stats(), mock.calls().len()), ((1, 1), 1));
+
+        // Sessions with other commitments keep the answers of each other
+        assert_eq!(db.try_consult_db(&price, &context(&["dest_city(berlin)"])).unwrap().to_string(), "price(99)");
+        assert_eq!(db.try_consult_db(&price, &context(&["dest_city(paris)"])).unwrap().to_string(), "price(232)");
+        assert_eq!(mock.calls().len(), 2);
+
+        // Constraints are relevant, and failures are not cached
+        assert!(db.try_consult_db(&price, &context(&["dest_city(paris)", "price()<100"])).is_ok());
+        mock.fail_next(1);
+        assert!(db.try_consult_db(&price, &context(&["dest_city(rome)"])).is_err());
+        assert!(db.try_consult_db(&price, &context(&["dest_city(rome)"])).is_err());
+        assert_eq!(mock.calls().len(), 5);
+
+        // Invalidated answers are consulted again
+        db.invalidate(&[Prop::new("dest_city(paris)").unwrap()]);
+        db.try_consult_db(&price, &context(&["dest_city(berlin)"])).unwrap();
+        db.try_consult_db(&price, &context(&["dest_city(paris)"])).unwrap();
+        assert_eq!(mock.calls().len(), 6);
+        db.clear();
+        db.try_consult_db(&price, &context(&["dest_city(paris)"])).unwrap();
+        assert_eq!(db.stats(), (3, 7));
+
+        // A full cache drops the least recently used answer
+        let db = CachedDatabase::new(mock.clone()).with_key_predicates(&["dest_city"]).with_capacity(2);
+        let (paris, berlin) = (context(&["dest_city(paris)"]), context(&["dest_city(berlin)"]));
+        for context in [&paris, &berlin, &paris, &context(&["dest_city(paris)", "price()<300"]), &paris, &berlin] {
+            db.try_consult_db(&price, context).unwrap();
+        }
+        assert_eq!(db.stats(), (2, 4));
+    }
+
+    #[cfg(feature = "async")]
+    #[test]
+    fn test_async_database() {
+        /// A database answering after giving way to other tasks, like a remote backend.
+        struct RemoteDB;
+
+        impl AsyncDatabase for RemoteDB {
+            async fn consult_db(&self, _question: &Question, context: &TSet<Prop>) -> Result<Prop, String> {
+                tokio::task::yield_now().await;
+                match context.contains(&Prop::new("dest_city(paris)").unwrap()) {
+                    true => Prop::new("price(345)"),
+                    false => Err("No flights".to_string()),
+                }
+            }
+        }
+
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        let db = BlockingDatabase::new(RemoteDB, runtime.handle().clone());
+        let price = Question::new("?x.price(x)").unwrap();
+        assert!(db.try_consult_db(&price, &TSet::new()).is_err());
+        assert!(db.aggregate(Aggregate::Min, "price", &TSet::new()).is_none());
+
+        // The dialogue blocks on the runtime from a thread of its own
+        let handle = runtime.handle().clone();
+        let session = thread::spawn(move || {
+            let inputs = ["?x.price(x)", "paris", "berlin", "today", "no"].map(String::from).to_vec();
+            let db = BlockingDatabase::new(RemoteDB, handle);
+            let handler = Box::new(DemoInputHandler::new(inputs));
+            let mut controller = IBISController::with_input_handler(travel_domain(), db, travel_grammar(), handler);
+            controller.run();
+            controller.is.bel.contains(&"price(345)".to_string())
+        });
+        assert!(session.join().unwrap());
+
+        // Blocking from within an async task fails instead of panicking
+        let error = runtime.block_on(async { db.try_consult_db(&price, &TSet::new()) }).unwrap_err();
+        assert_eq!(error, "Cannot block on a database from within an async task");
+        assert!(runtime.block_on(async { db.aggregate(Aggregate::Min, "price", &TSet::new()) }).is_none());
+    }
+
+    #[test]
+    fn test_query_planning() {
+        let props = ["dest_city(paris)", "depart_city(berlin)", "-return()", "price()>200", "class(first)"];
+        let context: TSet<Prop> = props
+            .iter()
+            .map(|p| Prop::new(p).unwrap())
+            .collect();
+        let plan = QueryPlan::new(&Question::new("?x.price(x)<300").unwrap(), &context).unwrap();
+        assert_eq!(plan.output(), "price");
+        assert!(QueryPlan::new(&Question::new("?return()").unwrap(), &context).is_err());
+        assert!(QueryPlan::for_field("ticket price", &context).is_err());
+        let filters: Vec<String> = plan.filters().iter().map(|(pred, value)| format!("{}={}", pred, value)).collect();
+        assert_eq!(filters, ["class=first", "depart_city=berlin", "dest_city=paris"]);
+        assert_eq!(plan.constraints().len(), 2);
+
+        // Filters apply to the columns of the entries, other commitments are left out
+        let mut db = TravelDB::new().with_column("class", "cabin");
+        for (price, cabin) in [("345", "first"), ("180", "second")] {
+            let entry = [("from", "berlin"), ("to", "paris"), ("cabin", cabin), ("price", price)];
+            db.add_entry(entry.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect());
+        }
+        let price = Question::new("?x.price(x)").unwrap();
+        assert_eq!(db.try_consult_db(&price, &context).unwrap().to_string(), "price(345)>200");
+        let context: TSet<Prop> = ["dest_city(paris)", "how(train)"].iter().map(|p| Prop::new(p).unwrap()).collect();
+        assert_eq!(db.aggregate(Aggregate::Count, "price", &context).unwrap().to_string(), "2");
+
+        // The relevant predicates are derived from the plans, or declared
+        let mut domain = travel_domain();
+        let relevant = domain.query_predicates(&price).unwrap();
+        assert_eq!(relevant, HashSet::from(["dest_city", "depart_city", "depart_day", "return"].map(String::from)));
+        assert!(domain.query_predicates(&Question::new("?x.dest_city(x)").unwrap()).is_none());
+        assert!(domain.set_query_predicates("?x.price(x)", &["budget"]).is_err());
+        domain.set_query_predicates("?x.price(x)", &["dest_city"]).unwrap();
+        assert_eq!(Domain::from_json(&domain.to_json()).unwrap().query_predicates(&price).unwrap().len(), 1);
+
+        // The database is given the relevant commitments only
+        let mut mock = MockDatabase::new();
+        mock.add_response("?x.price(x)", &[], "price(232)").unwrap();
+        let mock = Arc::new(mock);
+        let inputs = ["?x.price(x)", "paris", "berlin", "today", "no"].map(String::from).to_vec();
+        let handler = Box::new(DemoInputHandler::new(inputs));
+        let mut controller = IBISController::with_input_handler(domain, mock.clone(), travel_grammar(), handler);
+        controller.run();
+        assert_eq!(mock.calls()[0].context, ["dest_city(paris)"]);
+    }
+
+    #[test]
+    fn test_known_answers() {
+        // Findouts resolved by over-answering are skipped, repeated answers are acknowledged
+        let mut controller = travel_controller(&[]);
+        let script = ScriptedInputHandler::new()
+            .say("?x.price(x)")
+            .say("depart_city(berlin)")
+            .say("paris")
+            .say("dest_city(paris)")
+            .say("today");
+        let result = controller.run_corpus(vec![script]);
+        let transcript: Vec<&str> = result[0].transcript.iter().map(|(_, _, text)| text.as_str()).collect();
+        assert_eq!(transcript[4], "Which city?");
+        assert_eq!(transcript[6], "Which day?");
+        assert_eq!(transcript[8], "I know, you already told me paris. Which day?");
+        assert_eq!(result[0].commitments, ["depart_city(berlin)", "depart_day(today)", "dest_city(paris)"]);
+    }
+
+    #[test]
+    fn test_coverage() {
+        let controller = travel_controller(&[]);
+        assert!(controller.coverage_report().is_none());
+        let mut controller = controller.with_coverage();
+        controller.domain_mut().add_rule(Rule::new(&["dest_city(paris)"], "popular()").unwrap()).unwrap();
+        controller.domain_mut().add_rule(Rule::new(&["dest_city(berlin)"], "rare()").unwrap()).unwrap();
+        let scripts = [vec!["?x.price(x)", "today", "paris", "berlin", "no"], vec!["hello"]];
+        controller.run_corpus(scripts.iter().map(|inputs| {
+            inputs.iter().fold(ScriptedInputHandler::new(), |script, input| script.say(input))
+        }));
+        let report = controller.coverage_report().unwrap();
+        let hits = |kind: CoverageKind, name: &str| {
+            report.items.iter().find(|item| item.kind == kind && item.name == name).map(|item| item.hits)
+        };
+        assert_eq!(hits(CoverageKind::PLAN_ITEM, "?x.price(x) 5: ConsultDB('?x.price(x)')"), Some(1));
+        let construct = "?x.price(x) 4: If('?return()', [Findout(?x.dest_city(x))], [])";
+        assert_eq!(hits(CoverageKind::PLAN_ITEM, construct), Some(1));
+        assert_eq!(hits(CoverageKind::PLAN_ITEM, "?x.price(x) 0: Findout('?x.dest_city(x)')"), Some(1));
+        // The Findout of the branch not taken is told apart from the identical one asked first
+        assert_eq!(hits(CoverageKind::PLAN_ITEM, "?x.price(x) 4/then/0: Findout('?x.dest_city(x)')"), Some(0));
+        assert_eq!(hits(CoverageKind::RULE, "dest_city(paris) => popular()"), Some(1));
+        assert_eq!(hits(CoverageKind::FORM, "Greet()"), Some(2)); // Once per session
+        let never_hit: Vec<&str> = report.never_hit().iter().map(|item| item.name.as_str()).collect();
+        assert!(never_hit.contains(&"dest_city(berlin) => rare()"));
+        assert!(never_hit.contains(&"?x.price(x) 4/then/0: Findout('?x.dest_city(x)')"));
+        assert!(never_hit.contains(&"Quit()"));
+        assert!(!never_hit.contains(&"Greet()"));
+        assert_eq!(report.ratio(&CoverageKind::RULE), 0.5);
+        let summary = "PLAN_ITEM: 6/7 (86%)\n  never hit: ?x.price(x) 4/then/0: Findout('?x.dest_city(x)')\n";
+        assert!(report.to_string().starts_with(summary));
+
+        // The branch taken counts at its own position
+        let mut controller = travel_controller(&[]).with_coverage();
+        let script = ["?x.price(x)", "paris", "berlin", "today", "yes", "london"];
+        controller.run_corpus([script.iter().fold(ScriptedInputHandler::new(), |script, input| script.say(input))]);
+        let report = controller.coverage_report().unwrap();
+        let then = report.items.iter().find(|item| item.name.starts_with("?x.price(x) 4/then/0")).unwrap();
+        assert_eq!(then.hits, 1);
+        assert_eq!(report.ratio(&CoverageKind::PLAN_ITEM), 1.0);
     }
 }
\ No newline at end of file
@@ -0,0 +1,119 @@
+//! Per-turn dialogue metrics exported through OpenTelemetry.
+
+use crate::TurnMetric;
+use opentelemetry::KeyValue;
+use opentelemetry::metrics::{Counter, Histogram, Meter};
+
+/// OpenTelemetry instruments recording the health of the dialogues of a
+/// controller. The NLU failure rate is the ratio of `isu.nlu.failures` to
+/// `isu.turns`. Measurements carry the variant of each experiment the
+/// controller takes part in as an attribute `isu.variant.<experiment>`.
+pub struct DialogueMetrics {
+    turns: Counter<u64>, // User inputs interpreted
+    nlu_failures: Counter<u64>, // User inputs the grammar did not understand
+    interpretation_latency: Histogram<f64>, // Seconds spent interpreting an input
+    rules_fired: Counter<u64>, // Inferences drawn by the domain rules
+    db_latency: Histogram<f64>, // Seconds spent consulting the database
+}
+
+impl DialogueMetrics {
+    /// Creates the instruments with a meter.
+    /// # Arguments
+    /// * `meter` - The meter of the application (e.g., `global::meter("isu")`).
+    pub fn new(meter: &Meter) -> Self {
+        DialogueMetrics {
+            turns: meter
+                .u64_counter("isu.turns")
+                .with_description("User inputs interpreted")
+                .build(),
+            nlu_failures: meter
+                .u64_counter("isu.nlu.failures")
+                .with_description("User inputs not understood")
+                .build(),
+            interpretation_latency: meter
+                .f64_histogram("isu.interpretation.latency")
+                .with_unit("s")
+                .with_description("Time spent interpreting a user input")
+                .build(),
+            rules_fired: meter
+                .u64_counter("isu.rules.fired")
+                .with_description("Inferences drawn by the domain rules")
+                .build(),
+            db_latency: meter
+                .f64_histogram("isu.db.latency")
+                .with_unit("s")
+                .with_description("Time spent consulting the database")
+                .build(),
+        }
+    }
+
+    /// Records a measurement taken during a turn.
+    /// # Arguments
+    /// * `metric` - The measurement.
+    /// * `variants` - The variants of the experiments of the controller, by experiment.
+    pub(crate) fn record(&self, metric: &TurnMetric, variants: &[(String, String)]) {
+        let attributes: Vec<KeyValue> = variants
+            .iter()
+            .map(|(experiment, variant)| KeyValue::new(format!("isu.variant.{}", experiment), variant.clone()))
+            .collect();
+        match metric {
+            TurnMetric::Interpretation { latency, understood } => {
+                self.turns.add(1, &attributes);
+                if !understood {
+                    self.nlu_failures.add(1, &attributes);
+                }
+                self.interpretation_latency.record(latency.as_secs_f64(), &attributes);
+            }
+            TurnMetric::RulesFired(count) => self.rules_fired.add(*count, &attributes),
+            TurnMetric::Database(latency) => self.db_latency.record(latency.as_secs_f64(), &attributes),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tests::travel_controller;
+    use crate::{Experiment, Variant};
+
+    #[test]
+    fn test_metrics() {
+        use opentelemetry::metrics::MeterProvider;
+        use opentelemetry_sdk::metrics::data::{AggregatedMetrics, MetricData};
+        use opentelemetry_sdk::metrics::{InMemoryMetricExporter, PeriodicReader, SdkMeterProvider};
+
+        let exporter = InMemoryMetricExporter::default();
+        let reader = PeriodicReader::builder(exporter.clone()).build();
+        let provider = SdkMeterProvider::builder().with_reader(reader).build();
+        let mut controller = travel_controller(&["?x.price(x)", "paris", "hmm what", "berlin", "today", "no"])
+            .with_metrics(&provider.meter("isu"));
+        controller.join_experiment(&Experiment::new("prompts").with_variant(Variant::new("short")), "alice").unwrap();
+        controller.run();
+        assert_eq!(controller.summarize().resolved.len(), 1);
+        provider.force_flush().unwrap();
+
+        // Each measurement is counted once, with the variant of the controller
+        let exported = exporter.get_finished_metrics().unwrap();
+        let metrics: Vec<_> = exported.iter().flat_map(|rm| rm.scope_metrics()).flat_map(|sm| sm.metrics()).collect();
+        let variant = opentelemetry::KeyValue::new("isu.variant.prompts", "short");
+        let count = |name: &str| -> u64 {
+            let metric = metrics.iter().find(|metric| metric.name() == name).unwrap();
+            match metric.data() {
+                AggregatedMetrics::U64(MetricData::Sum(sum)) => sum
+                    .data_points()
+                    .inspect(|point| assert!(point.attributes().any(|attribute| *attribute == variant)))
+                    .map(|point| point.value())
+                    .sum(),
+                AggregatedMetrics::F64(MetricData::Histogram(histogram)) => histogram
+                    .data_points()
+                    .inspect(|point| assert!(point.attributes().any(|attribute| *attribute == variant)))
+                    .map(|point| point.count())
+                    .sum(),
+                _ => panic!("Unexpected data for {}", name),
+            }
+        };
+        assert_eq!(count("isu.turns"), 6);
+        assert_eq!(count("isu.nlu.failures"), 1);
+        assert_eq!(count("isu.interpretation.latency"), 6);
+        assert_eq!(count("isu.db.latency"), 1);
+    }
+}
@@ -0,0 +1,140 @@
+//! Readline-style console input with history and completion of domain words.
+
+use crate::{Domain, InputHandler};
+use rustyline::completion::Completer;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::history::DefaultHistory;
+use rustyline::validate::Validator;
+use rustyline::{Context, Editor, Helper};
+
+/// Completes the word under the cursor with predicates, individuals and aliases.
+struct DomainCompleter {
+    words: Vec<String>, // Sorted vocabulary of the domain
+}
+
+impl Completer for DomainCompleter {
+    type Candidate = String;
+
+    fn complete(&self, line: &str, pos: usize, _ctx: &Context<'_>) -> rustyline::Result<(usize, Vec<String>)> {
+        let start = line[..pos]
+            .char_indices()
+            .rfind(|(_, c)| c.is_whitespace() || "(.?,'".contains(*c))
+            .map_or(0, |(i, c)| i + c.len_utf8());
+        let prefix = &line[start..pos];
+        let candidates = self.words.iter().filter(|w| w.starts_with(prefix)).cloned().collect();
+        Ok((start, candidates))
+    }
+}
+
+impl Hinter for DomainCompleter {
+    type Hint = String;
+}
+
+impl Highlighter for DomainCompleter {}
+
+impl Validator for DomainCompleter {}
+
+impl Helper for DomainCompleter {}
+
+/// Console input handler with line editing, input history and tab-completion
+/// of the words of a domain
+pub struct RustylineInputHandler {
+    editor: Editor<DomainCompleter, DefaultHistory>, // Line editor
+    prompt: String, // Prompt printed before reading a line
+    eof_message: String, // Message printed at the end of input
+}
+
+impl RustylineInputHandler {
+    /// Creates a new RustylineInputHandler completing the words of a domain.
+    /// # Arguments
+    /// * `domain` - The domain whose predicates, individuals and aliases are completed.
+    pub fn new(domain: &Domain) -> Result<Self, String> {
+        let mut editor = Editor::new().map_err(|e| e.to_string())?;
+        editor.set_helper(Some(DomainCompleter { words: domain.vocabulary() }));
+        Ok(Self {
+            editor,
+            prompt: "U> ".to_string(),
+            eof_message: "EOF".to_string(),
+        })
+    }
+
+    /// Sets the prompt printed before reading a line.
+    /// # Arguments
+    /// * `prompt` - The prompt.
+    pub fn with_prompt(mut self, prompt: &str) -> Self {
+        self.prompt = prompt.to_string();
+        self
+    }
+
+    /// Sets the message printed at the end of input.
+    /// # Arguments
+    /// * `message` - The message.
+    pub fn with_eof_message(mut self, message: &str) -> Self {
+        self.eof_message = message.to_string();
+        self
+    }
+
+    /// Loads the input history from a file.
+    /// # Arguments
+    /// * `path` - The history file.
+    pub fn load_history(&mut self, path: &str) -> Result<(), String> {
+        self.editor.load_history(path).map_err(|e| e.to_string())
+    }
+
+    /// Saves the input history to a file.
+    /// # Arguments
+    /// * `path` - The history file.
+    pub fn save_history(&mut self, path: &str) -> Result<(), String> {
+        self.editor.save_history(path).map_err(|e| e.to_string())
+    }
+}
+
+impl InputHandler for RustylineInputHandler {
+    fn read_line(&mut self) -> Option<String> {
+        match self.editor.readline(&self.prompt) {
+            Ok(line) => {
+                let line = line.trim().to_string();
+                if !line.is_empty() {
+                    self.editor.add_history_entry(line.as_str()).ok();
+                }
+                Some(line)
+            }
+            Err(_) => {
+                println!("{}", self.eof_message);
+                None
+            }
+        }
+    }
+
+    fn has_input(&self) -> bool {
+        true // Always assume input is available for blocking input
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rustyline::history::DefaultHistory;
+    use std::collections::{HashMap, HashSet};
+
+    #[test]
+    fn test_domain_completion() {
+        let preds1 = HashMap::from([("dest_city".to_string(), "city".to_string())]);
+        let sorts = HashMap::from([(
+            "city".to_string(),
+            HashSet::from(["paris".to_string(), "london".to_string()]),
+        )]);
+        let domain = Domain::new(HashSet::new(), preds1, sorts);
+        let completer = DomainCompleter { words: domain.vocabulary() };
+        let history = DefaultHistory::new();
+        let ctx = Context::new(&history);
+
+        let (start, candidates) = completer.complete("going to pa", 11, &ctx).unwrap();
+        assert_eq!(start, 9);
+        assert_eq!(candidates, ["paris"]);
+        let (start, candidates) = completer.complete("?x.de", 5, &ctx).unwrap();
+        assert_eq!(start, 3);
+        assert_eq!(candidates, ["dest_city"]);
+    }
+}
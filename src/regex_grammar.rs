@@ -0,0 +1,174 @@
+//! Grammars interpreting input with regular expressions.
+
+use crate::{Grammar, InterpretationContext, Move, SimpleGenGrammar, TSet};
+use regex::Regex;
+
+/// An interpretation rule: input matching the pattern stands for the move
+/// made by filling the template with the named captures of the match.
+struct RegexRule {
+    pattern: Regex, // The pattern (e.g., "to (?P<city>\w+)")
+    template: String, // The move with placeholders (e.g., "Answer({city})")
+}
+
+/// A grammar interpreting input with rules pairing a regular expression with
+/// a move template, e.g. "(?i)\bto (?P<city>\w+)" with "Answer({city})". Every
+/// rule matching the input contributes its move, so "hello, to paris" may be
+/// both a greeting and an answer. Output is generated by a SimpleGenGrammar.
+pub struct RegexGrammar {
+    rules: Vec<RegexRule>, // Interpretation rules, in the order they were added
+    generator: SimpleGenGrammar, // Grammar generating the output
+}
+
+impl RegexGrammar {
+    /// Creates a grammar without rules, generating with a default SimpleGenGrammar.
+    pub fn new() -> Self {
+        RegexGrammar {
+            rules: Vec::new(),
+            generator: SimpleGenGrammar::new(),
+        }
+    }
+
+    /// Sets the grammar generating the output.
+    /// # Arguments
+    /// * `generator` - The grammar.
+    pub fn with_generator(mut self, generator: SimpleGenGrammar) -> Self {
+        self.generator = generator;
+        self
+    }
+
+    /// Returns the grammar generating the output, e.g. to add forms to it.
+    pub fn generator_mut(&mut self) -> &mut SimpleGenGrammar {
+        &mut self.generator
+    }
+
+    /// Adds an interpretation rule. The template must be a move once its
+    /// placeholders are filled, and every placeholder must be a named capture
+    /// of the pattern.
+    /// # Arguments
+    /// * `pattern` - The regular expression (e.g., "(?i)\bto (?P<city>\w+)").
+    /// * `template` - The move, with a placeholder per capture (e.g., "Answer({city})").
+    pub fn add_rule(&mut self, pattern: &str, template: &str) -> Result<(), String> {
+        let pattern = Regex::new(pattern).map_err(|e| format!("Invalid pattern {}: {}", pattern, e))?;
+        let mut sample = template.to_string();
+        for name in placeholders(template) {
+            if !pattern.capture_names().any(|capture| capture == Some(name)) {
+                return Err(format!("Unknown capture {} in template {}", name, template));
+            }
+            sample = sample.replace(&format!("{{{}}}", name), "x");
+        }
+        Move::new(&sample).map_err(|e| format!("Invalid template {}: {}", template, e))?;
+        self.rules.push(RegexRule { pattern, template: template.to_string() });
+        Ok(())
+    }
+
+    /// Loads a grammar from a JSON object with the fields "rules" (a list of
+    /// objects with a "pattern" and a "move"), "forms" (outputs by move) and
+    /// "answer_forms" (answer templates by predicate). Missing fields are
+    /// taken as empty.
+    /// # Arguments
+    /// * `json` - The JSON object.
+    pub fn from_json(json: &serde_json::Value) -> Result<Self, String> {
+        let mut grammar = RegexGrammar::new();
+        for rule in json["rules"].as_array().into_iter().flatten() {
+            let (Some(pattern), Some(template)) = (rule["pattern"].as_str(), rule["move"].as_str()) else {
+                return Err(format!("Invalid rule: {}", rule));
+            };
+            grammar.add_rule(pattern, template)?;
+        }
+        for (move_str, output) in json["forms"].as_object().into_iter().flatten() {
+            grammar.generator.add_form(move_str, output.as_str().ok_or(format!("Invalid form: {}", output))?);
+        }
+        for (pred, form) in json["answer_forms"].as_object().into_iter().flatten() {
+            grammar.generator.add_answer_form(pred, form.as_str().ok_or(format!("Invalid form: {}", form))?);
+        }
+        Ok(grammar)
+    }
+
+    /// Loads a grammar from a JSON file in the format of from_json.
+    /// # Arguments
+    /// * `path` - The path of the file.
+    pub fn from_file(path: &str) -> Result<Self, String> {
+        let contents = std::fs::read_to_string(path).map_err(|e| format!("Could not read {}: {}", path, e))?;
+        let json = serde_json::from_str(&contents).map_err(|e| format!("Invalid grammar {}: {}", path, e))?;
+        Self::from_json(&json)
+    }
+}
+
+/// Returns the names of the placeholders of a template, e.g. "city" in "Answer({city})".
+/// # Arguments
+/// * `template` - The template.
+fn placeholders(template: &str) -> Vec<&str> {
+    template
+        .split('{')
+        .skip(1)
+        .filter_map(|part| part.split_once('}').map(|(name, _)| name))
+        .collect()
+}
+
+impl Grammar for RegexGrammar {
+    fn generate(&self, moves: &[String], ctx: &InterpretationContext) -> String {
+        self.generator.generate(moves, ctx)
+    }
+
+    fn interpret(&self, input: &str, _ctx: &InterpretationContext) -> Option<TSet<String>> {
+        let mut moves = TSet::new();
+        for rule in &self.rules {
+            let Some(captures) = rule.pattern.captures(input) else {
+                continue;
+            };
+            let mut move_str = rule.template.clone();
+            for name in placeholders(&rule.template) {
+                let value = captures.name(name).map_or("", |value| value.as_str());
+                move_str = move_str.replace(&format!("{{{}}}", name), value);
+            }
+            if let Ok(mv) = Move::new(&move_str) {
+                moves.add(mv.to_string()).ok()?;
+            }
+        }
+        (!moves.is_empty()).then_some(moves)
+    }
+
+    fn forms(&self) -> Vec<String> {
+        self.generator.forms()
+    }
+}
+
+impl Default for RegexGrammar {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::travel_controller;
+
+    #[test]
+    fn test_regex_grammar() {
+        let json = serde_json::json!({
+            "rules": [
+                { "pattern": r"(?i)^(hello|hi)\b", "move": "Greet()" },
+                { "pattern": r"(?i)\bto (?P<city>paris|berlin)\b", "move": "Answer(dest_city({city}))" },
+                { "pattern": r"(?i)\bhow much\b", "move": "Ask('?x.price(x)')" },
+            ],
+            "forms": { "Ask('?x.dest_city(x)')": "Where to?" },
+        });
+        let grammar = RegexGrammar::from_json(&json).unwrap();
+        let domain = travel_controller(&[]).domain;
+        let ctx = InterpretationContext::new(&domain);
+        let moves = grammar.interpret("Hi, I want to go to paris", &ctx).unwrap();
+        assert_eq!(moves.len(), 2);
+        assert!(moves.contains(&"Greet()".to_string()));
+        assert!(moves.contains(&"Answer(dest_city(paris))".to_string()));
+        assert!(grammar.interpret("How much is it?", &ctx).unwrap().contains(&"Ask('?x.price(x)')".to_string()));
+        assert!(grammar.interpret("to london", &ctx).is_none());
+        assert_eq!(grammar.generate(&["Ask('?x.dest_city(x)')".to_string()], &ctx), "Where to?");
+
+        let mut grammar = RegexGrammar::new();
+        assert!(grammar.add_rule("to (", "Greet()").is_err());
+        assert!(grammar.add_rule("to (?P<city>\\w+)", "Answer({town})").unwrap_err().contains("Unknown capture"));
+        assert!(grammar.add_rule("to (?P<city>\\w+)", "Travel({city}").unwrap_err().contains("Invalid template"));
+        assert!(RegexGrammar::from_json(&serde_json::json!({ "rules": [{ "pattern": "x" }] })).is_err());
+    }
+}
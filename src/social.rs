@@ -0,0 +1,154 @@
+//! Social moves: thanks, apologies, insults and greetings in the middle of a dialogue.
+
+use crate::{Custom, CustomMove, Move, MoveEffect, Speaker};
+use std::collections::HashMap;
+
+/// Words an utterance may have at most to be taken as a social move, so that
+/// answers wrapped in politeness ("sorry, I meant paris") are left to the grammar.
+const MAX_SOCIAL_WORDS: usize = 6;
+
+/// Words answering a question, which make an utterance task content (e.g., "no thanks").
+const ANSWER_WORDS: [&str; 2] = ["yes", "no"];
+
+/// Phrases expressing each social act of the user, tried in order.
+const SOCIAL_PHRASES: [(&str, &[&str]); 4] = [
+    ("insult", &["stupid", "idiot", "dumb", "useless", "shut up", "hate you", "you suck"]),
+    ("apology", &["sorry", "apologize", "apologise", "my bad", "excuse me", "pardon me"]),
+    ("thanks", &["thanks", "thank you", "thx", "cheers", "appreciate it", "much appreciated"]),
+    ("greeting", &["hello", "hi", "hey", "good morning", "good afternoon", "good evening"]),
+];
+
+/// A move kind for social acts, registered on a domain with `Domain::add_move`.
+///
+/// User utterances thanking, apologizing, insulting or greeting are
+/// recognized as "custom:Social(thanks)" and so on, instead of being
+/// misunderstood as task content, and the system replies with the move of
+/// the matching response ("custom:Social(welcome)" for thanks, "reassure"
+/// for apologies, "deflect" for insults and "greet" for greetings). The
+/// question under discussion is left as it was, so the system asks it again
+/// after replying.
+pub struct SocialMoves {
+    forms: HashMap<String, String>, // Utterances of the acts, by act
+}
+
+impl SocialMoves {
+    /// Creates the move kind with English responses.
+    pub fn new() -> Self {
+        let forms = [
+            ("welcome", "You're welcome"),
+            ("reassure", "No problem"),
+            ("deflect", "I'm sorry you feel that way. Let's try to sort this out"),
+            ("greet", "Hello again"),
+        ];
+        SocialMoves { forms: forms.iter().map(|(act, form)| (act.to_string(), form.to_string())).collect() }
+    }
+
+    /// Sets the utterance of an act, e.g. to localize the responses.
+    /// # Arguments
+    /// * `act` - The act (e.g., "welcome").
+    /// * `form` - Its utterance (e.g., "De rien").
+    pub fn with_form(mut self, act: &str, form: &str) -> Self {
+        self.forms.insert(act.to_string(), form.to_string());
+        self
+    }
+
+    /// Returns the social act a user input expresses, if any.
+    /// # Arguments
+    /// * `input` - The user input.
+    pub fn classify(input: &str) -> Option<&'static str> {
+        let words: Vec<String> = input
+            .split(|c: char| !c.is_alphanumeric() && c != '\'')
+            .filter(|word| !word.is_empty())
+            .map(str::to_lowercase)
+            .collect();
+        let answers = words.iter().any(|word| ANSWER_WORDS.contains(&word.as_str()));
+        if words.is_empty() || words.len() > MAX_SOCIAL_WORDS || answers {
+            return None;
+        }
+        let text = format!(" {} ", words.join(" "));
+        SOCIAL_PHRASES
+            .iter()
+            .find(|(_, phrases)| phrases.iter().any(|phrase| text.contains(&format!(" {} ", phrase))))
+            .map(|(act, _)| *act)
+    }
+
+    /// Returns the response of the system to a social act of the user.
+    /// # Arguments
+    /// * `act` - The act of the user (e.g., "thanks").
+    fn response(act: &str) -> Option<&'static str> {
+        match act {
+            "thanks" => Some("welcome"),
+            "apology" => Some("reassure"),
+            "insult" => Some("deflect"),
+            "greeting" => Some("greet"),
+            _ => None,
+        }
+    }
+}
+
+impl Default for SocialMoves {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CustomMove for SocialMoves {
+    fn name(&self) -> &str {
+        "Social"
+    }
+
+    fn generate(&self, args: &[String]) -> String {
+        let act = args.first().map_or("", String::as_str);
+        self.forms.get(act).cloned().unwrap_or_default()
+    }
+
+    fn interpret(&self, input: &str) -> Option<Vec<String>> {
+        Self::classify(input).map(|act| vec![act.to_string()])
+    }
+
+    fn integrate(&self, args: &[String], speaker: &Speaker) -> Result<Vec<MoveEffect>, String> {
+        let [act] = args else {
+            return Err(format!("Social takes one act, got {}", args.len()));
+        };
+        if *speaker == Speaker::SYS {
+            return Ok(Vec::new());
+        }
+        let response = Self::response(act).ok_or(format!("Unknown social act: {}", act))?;
+        Ok(vec![MoveEffect::Reply(Move::Custom(Custom::new("Social", &[response])?))])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::travel_controller;
+
+    #[test]
+    fn test_social_moves() {
+        assert_eq!(SocialMoves::classify("Thank you so much!"), Some("thanks"));
+        assert_eq!(SocialMoves::classify("sorry"), Some("apology"));
+        assert_eq!(SocialMoves::classify("you are useless"), Some("insult"));
+        assert_eq!(SocialMoves::classify("hi there"), Some("greeting"));
+        assert_eq!(SocialMoves::classify("this is paris"), None);
+        assert_eq!(SocialMoves::classify("no thanks"), None);
+        assert_eq!(SocialMoves::classify("sorry, I meant to say that I am going to paris"), None);
+
+        let inputs = ["?x.price(x)", "paris", "thanks", "berlin", "you're useless", "sorry", "today", "no"];
+        let mut controller = travel_controller(&inputs);
+        controller.domain_mut().add_move(SocialMoves::new().with_form("reassure", "Never mind")).unwrap();
+        controller.run();
+        let outputs: Vec<&str> =
+            controller.transcript.iter().skip(6).step_by(2).take(4).map(|(_, _, text)| text.as_str()).collect();
+        assert_eq!(
+            outputs,
+            [
+                "You're welcome. Which city?",
+                "Which day?",
+                "I'm sorry you feel that way. Let's try to sort this out. Which day?",
+                "Never mind. Which day?",
+            ]
+        );
+        assert!(controller.unmatched.is_empty());
+        assert!(controller.is.com.contains(&"price(345)".to_string()));
+    }
+}
@@ -0,0 +1,111 @@
+use isu::*;
+use std::collections::{HashMap, HashSet};
+
+#[path = "../examples/travel.rs"]
+#[allow(dead_code)]
+mod travel;
+
+#[path = "../examples/home.rs"]
+#[allow(dead_code)]
+mod home;
+
+#[path = "../examples/restaurant.rs"]
+#[allow(dead_code)]
+mod restaurant;
+
+#[path = "../examples/faq.rs"]
+#[allow(dead_code)]
+mod faq;
+
+/// Builds the travel domain used by the examples.
+fn travel_domain() -> Domain {
+    let preds0 = HashSet::from(["return".to_string()]);
+    let preds1 = HashMap::from([
+        ("price".to_string(), "int".to_string()),
+        ("how".to_string(), "means".to_string()),
+        ("dest_city".to_string(), "city".to_string()),
+        ("depart_city".to_string(), "city".to_string()),
+        ("depart_day".to_string(), "day".to_string()),
+        ("return_day".to_string(), "day".to_string()),
+    ]);
+    let sorts = HashMap::from([
+        ("means".to_string(), HashSet::from(["plane".to_string(), "train".to_string()])),
+        ("city".to_string(), HashSet::from(["paris".to_string(), "berlin".to_string()])),
+        ("day".to_string(), HashSet::from(["today".to_string(), "tomorrow".to_string()])),
+    ]);
+    let mut domain = Domain::new(preds0, preds1, sorts);
+    domain.add_plan(
+        Question::new("?x.price(x)").unwrap(),
+        vec![
+            "Findout('?x.how(x)')".to_string(),
+            "Findout('?x.dest_city(x)')".to_string(),
+            "Findout('?x.depart_city(x)')".to_string(),
+            "Findout('?x.depart_day(x)')".to_string(),
+            "Findout('?return()')".to_string(),
+            "If('?return()', ['Findout(?x.return_day(x))'], [])".to_string(),
+            "ConsultDB('?x.price(x)')".to_string(),
+        ],
+    );
+    domain
+}
+
+/// Builds the travel database used by the examples.
+fn travel_database() -> TravelDB {
+    let mut database = TravelDB::new();
+    database.add_entry(HashMap::from([
+        ("price".to_string(), "232".to_string()),
+        ("from".to_string(), "berlin".to_string()),
+        ("to".to_string(), "paris".to_string()),
+        ("day".to_string(), "today".to_string()),
+    ]));
+    database
+}
+
+#[test]
+fn travel_dialogue_runs_to_completion() {
+    let inputs = ["?x.price(x)", "train", "paris", "berlin", "today", "yes", "tomorrow", "quit"];
+    let handler = DemoInputHandler::new(inputs.iter().map(|s| s.to_string()).collect());
+    let mut ibis = IBISController::with_input_handler(
+        travel_domain(),
+        travel_database(),
+        SimpleGenGrammar::new(),
+        Box::new(handler),
+    );
+    ibis.run();
+}
+
+/// Runs an example script and compares its transcript with the golden file of the example.
+fn check_example(mut ibis: IBISController, script: ScriptedInputHandler, name: &str) {
+    let results = ibis.run_corpus([script]);
+    assert!(results[0].passed(), "{:?}", results[0].mismatches);
+    let golden = format!("{}/tests/golden/{}.txt", env!("CARGO_MANIFEST_DIR"), name);
+    check_golden(golden, &results[0].to_transcript()).unwrap();
+}
+
+#[test]
+fn travel_example_matches_golden_transcript() {
+    let ibis = travel::travel_controller(Box::new(ScriptedInputHandler::new()));
+    check_example(ibis, travel::travel_script(), "travel");
+}
+
+#[test]
+fn example_domains_match_golden_transcripts() {
+    let ibis = home::home_controller(Box::new(ScriptedInputHandler::new()));
+    check_example(ibis, home::home_script(), "home");
+    let ibis = restaurant::restaurant_controller(Box::new(ScriptedInputHandler::new()));
+    check_example(ibis, restaurant::restaurant_script(), "restaurant");
+    let ibis = faq::faq_controller(Box::new(ScriptedInputHandler::new()));
+    check_example(ibis, faq::faq_script(), "faq");
+}
+
+isu::create_enum!(
+    /// The mood of the user, as an application-defined enumeration.
+    Mood, HAPPY, ANGRY
+);
+
+#[test]
+fn applications_can_define_enums() {
+    assert_eq!(Mood::iter().map(|mood| mood.to_string()).collect::<Vec<_>>(), ["HAPPY", "ANGRY"]);
+    assert_eq!("ANGRY".parse::<Mood>(), Ok(Mood::ANGRY));
+    assert_eq!(serde_json::to_string(&Mood::HAPPY).unwrap(), "\"HAPPY\"");
+}